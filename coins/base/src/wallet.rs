@@ -1,7 +1,10 @@
 use anyhow::Result;
 use bip39::Mnemonic;
 use ethers::prelude::*;
+use ethers::types::transaction::eip712::TypedData;
+use ethers::utils::hash_message;
 use std::str::FromStr;
+use walletd_ethereum::signing;
 
 pub struct BaseWallet {
     wallet: LocalWallet,
@@ -59,6 +62,44 @@ impl BaseWallet {
         format!("0x{}", hex::encode(self.wallet.signer().to_bytes()))
     }
 
+    /// Sign `message` as `personal_sign` does (EIP-191), returning the signature as 0x-prefixed hex
+    pub fn sign_message(&self, message: &[u8]) -> Result<String> {
+        let signature = self.wallet.sign_hash(hash_message(message))?;
+        Ok(format!("0x{signature}"))
+    }
+
+    /// Sign typed data as `eth_signTypedData_v4` does (EIP-712). Domains bound to another chain
+    /// are refused.
+    pub fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String> {
+        Ok(signing::sign_typed_data(
+            &self.wallet,
+            typed_data,
+            self.chain_id,
+        )?)
+    }
+
+    /// Whether `address` produced `signature` over `message` with `personal_sign`
+    pub fn verify_message(address: &str, message: &[u8], signature: &str) -> Result<bool> {
+        Ok(signing::verify_message(
+            Address::from_str(address)?,
+            message,
+            signature,
+        )?)
+    }
+
+    /// Whether `address` produced `signature` over `typed_data` with `eth_signTypedData_v4`
+    pub fn verify_typed_data(
+        address: &str,
+        typed_data: &TypedData,
+        signature: &str,
+    ) -> Result<bool> {
+        Ok(signing::verify_typed_data(
+            Address::from_str(address)?,
+            typed_data,
+            signature,
+        )?)
+    }
+
     pub async fn get_balance(&self) -> Result<U256> {
         if let Some(provider) = &self.provider {
             let balance = provider.get_balance(self.wallet.address(), None).await?;
@@ -85,3 +126,33 @@ impl BaseWallet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    #[test]
+    fn signs_and_verifies_messages() {
+        let wallet = BaseWallet::from_private_key(KEY, 8453).unwrap();
+        let signature = wallet.sign_message(b"Some data").unwrap();
+        assert!(BaseWallet::verify_message(&wallet.address(), b"Some data", &signature).unwrap());
+
+        let typed_data: TypedData = serde_json::from_value(serde_json::json!({
+            "types": {
+                "EIP712Domain": [{"name": "chainId", "type": "uint256"}],
+                "Login": [{"name": "nonce", "type": "uint256"}]
+            },
+            "primaryType": "Login",
+            "domain": {"chainId": 8453},
+            "message": {"nonce": 1}
+        }))
+        .unwrap();
+        let signature = wallet.sign_typed_data(&typed_data).unwrap();
+        assert!(BaseWallet::verify_typed_data(&wallet.address(), &typed_data, &signature).unwrap());
+
+        let sepolia = BaseWallet::from_private_key(KEY, 84532).unwrap();
+        assert!(sepolia.sign_typed_data(&typed_data).is_err());
+    }
+}
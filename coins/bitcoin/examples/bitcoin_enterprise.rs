@@ -1,24 +1,45 @@
+use bitcoin::bip32::Xpriv;
+use bitcoin::secp256k1::Secp256k1;
+use walletd_bitcoin::multi_wallet::{
+    cosigner_key, EnterpriseConfig, EnterpriseWalletManager, MultisigKind,
+};
 use walletd_bitcoin::Network;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Enterprise Bitcoin wallet example");
 
-    // Note: EnterpriseWalletManager needs proper implementation
-    // This is a simplified example
-
     let network = Network::Testnet;
     println!("Using network: {network:?}");
 
-    // The actual enterprise features would include:
-    // - Multi-signature wallets
-    // - Hardware security module integration
-    // - Rate limiting
-    // - KYC/AML compliance
-    // - Batch transactions
-    // - Lightning network integration
+    let manager = EnterpriseWalletManager::new(EnterpriseConfig {
+        max_concurrent_ops: 4,
+        network,
+        rpc_endpoints: vec![],
+    })?;
+
+    // Each cosigner shares a BIP48 account xpub; their master keys stay on their devices
+    let secp = Secp256k1::new();
+    let cosigners: Vec<Xpriv> = (1u8..=3)
+        .map(|i| Xpriv::new_master(network, &[i; 32]))
+        .collect::<Result<_, _>>()?;
+
+    for kind in [MultisigKind::SortedMultiP2wsh, MultisigKind::MuSig2P2tr] {
+        let keys = cosigners
+            .iter()
+            .map(|m| cosigner_key(m, kind, 0, &secp))
+            .collect::<Result<Vec<_>, _>>()?;
+        let wallet_id = format!("treasury-{kind:?}");
+        let address = manager.create_wallet(&wallet_id, kind, 2, &keys).await?;
+        println!("\n2-of-3 {kind:?}");
+        println!("   Receive: {address}");
+        println!(
+            "   Change:  {}",
+            manager.get_change_address(&wallet_id).await?
+        );
+    }
 
-    println!("Enterprise features not yet implemented");
+    println!("\nFund a receive address, then create_proposal → sign → broadcast_proposal");
 
     Ok(())
 }
@@ -6,6 +6,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = BitcoinConfig {
         network: Network::Testnet,
         rpc_endpoints: vec![], // Empty for now, would need proper RpcEndpoint structs
+        storage: None,
+        electrum_url: None,
+        security: Default::default(),
     };
 
     // Create wallet manager
@@ -1,6 +1,7 @@
+use std::sync::Arc;
 use walletd_bitcoin::{
     lightning::LightningManager,
-    swaps::{Chain, SwapCoordinator},
+    swaps::{htlc, Chain, SwapCoordinator},
     BitcoinConfig, BitcoinWalletManager, Network,
 };
 
@@ -13,8 +14,11 @@ async fn main() -> anyhow::Result<()> {
     let btc_config = BitcoinConfig {
         network: Network::Bitcoin,
         rpc_endpoints: vec![],
+        storage: None,
+        electrum_url: None,
+        security: Default::default(),
     };
-    let btc_manager = BitcoinWalletManager::new(btc_config).await?;
+    let btc_manager = Arc::new(BitcoinWalletManager::new(btc_config).await?);
 
     // Create a wallet
     let wallet = btc_manager.create_wallet("alice", None).await?;
@@ -48,7 +52,7 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize swap coordinator
     println!("\n💱 Cross-Chain Swaps:");
-    let swap_coordinator = SwapCoordinator::new();
+    let swap_coordinator = SwapCoordinator::new(btc_manager.clone())?;
 
     // Show available swap routes
     let routes = vec![
@@ -66,27 +70,29 @@ async fn main() -> anyhow::Result<()> {
         println!("   {from:?} → {to:?}: {route:?}");
     }
 
-    // Simulate a swap
-    println!("\n🔄 Initiating BTC → ICP swap:");
-    let swap_id = swap_coordinator
-        .initiate_btc_to_icp_swap(
-            100_000,   // 0.001 BTC
-            1_000_000, // 0.01 ICP
-            &wallet.first_address,
-            "test-icp-principal",
-        )
-        .await?;
-    println!("   Swap ID: {swap_id}");
-    println!("   Status: Initiated");
-    println!("   From: {} sats (BTC)", 100_000);
-    println!("   To: {} e8s (ICP)", 1_000_000);
+    // Build the HTLC a BTC → ICP swap locks its bitcoin in
+    println!("\n🔄 BTC → ICP swap HTLC:");
+    let (_secret, payment_hash) = htlc::new_secret();
+    let counterparty = btc_manager.vault().xpub("alice", &"m/84'/0'/1'".parse()?)?;
+    let ours = btc_manager.vault().xpub("alice", &"m/84'/0'/0'".parse()?)?;
+    let contract = htlc::Htlc {
+        payment_hash,
+        recipient: counterparty.to_pub(),
+        refund: ours.to_pub(),
+        timelock: walletd_bitcoin::swaps::INITIATOR_TIMELOCK,
+    };
+    println!("   Payment hash: {payment_hash}");
+    println!("   HTLC address: {}", contract.address(Network::Bitcoin));
+    println!("   Script: {}", contract.witness_script().to_asm_string());
+    println!("   Funding it needs a node: configure rpc_endpoints or electrum_url and call");
+    println!("   initiate_btc_to_icp_swap, then advance the swap as blocks arrive");
 
     println!("\n✅ Demo completed!");
     println!("\nFeatures demonstrated:");
     println!("• Bitcoin HD wallet creation");
     println!("• Lightning Network node (when enabled)");
     println!("• Cross-chain swap route discovery");
-    println!("• BTC ↔ ICP atomic swap initiation");
+    println!("• BTC ↔ ICP atomic swap HTLCs");
 
     Ok(())
 }
@@ -9,6 +9,9 @@ async fn main() -> anyhow::Result<()> {
     let config = BitcoinConfig {
         network: Network::Bitcoin,
         rpc_endpoints: vec![], // No RPC for this test
+        storage: None,
+        electrum_url: None,
+        security: Default::default(),
     };
 
     let manager = BitcoinWalletManager::new(config).await?;
@@ -39,6 +42,11 @@ async fn main() -> anyhow::Result<()> {
         .await?;
     println!("   Native SegWit: {native_segwit}");
 
+    let taproot = manager
+        .get_receive_address("test-user", AddressType::Taproot)
+        .await?;
+    println!("   Taproot: {taproot}");
+
     // Check balance
     println!("\n3️⃣ Checking balance...");
     let balance = manager.get_balance("test-user").await?;
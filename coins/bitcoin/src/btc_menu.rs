@@ -10,6 +10,9 @@ pub async fn handle_btc_menu(_wallet: &mut WalletDIcpApi, address: &str, balance
     let config = BitcoinConfig {
         network: Network::Bitcoin,
         rpc_endpoints: vec![], // Add your Bitcoin node endpoints
+        storage: None,
+        electrum_url: None,
+        security: Default::default(),
     };
     
     let btc_manager = BitcoinWalletManager::new(config).await
@@ -69,6 +72,7 @@ async fn handle_generate_address(manager: &BitcoinWalletManager) -> Result<(), S
     println!("1. Legacy (P2PKH) - starts with '1'");
     println!("2. SegWit (P2SH) - starts with '3'");
     println!("3. Native SegWit (Bech32) - starts with 'bc1'");
+    println!("4. Taproot (Bech32m) - starts with 'bc1p'");
     
     print!("Choice: ");
     io::stdout().flush().unwrap();
@@ -79,6 +83,7 @@ async fn handle_generate_address(manager: &BitcoinWalletManager) -> Result<(), S
         "1" => AddressType::Legacy,
         "2" => AddressType::SegwitP2SH,
         "3" => AddressType::NativeSegwit,
+        "4" => AddressType::Taproot,
         _ => {
             println!("Invalid choice");
             return Ok(());
@@ -0,0 +1,285 @@
+// BIP44 chains and gap-limit address discovery
+use crate::AddressType;
+use anyhow::Result;
+use bitcoin::bip32::{ChildNumber, DerivationPath};
+use bitcoin::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Consecutive unused addresses after which a chain is assumed to hold no more funds
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Whether a chain hands out receive or change addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeychainKind {
+    /// Receive addresses, `.../0/index`
+    External,
+    /// Change addresses, `.../1/index`
+    Internal,
+}
+
+/// One address chain, `m/purpose'/0'/account'/chain`, with the purpose given by the
+/// address type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Keychain {
+    pub address_type: AddressType,
+    pub account: u32,
+    pub kind: KeychainKind,
+}
+
+impl Keychain {
+    pub fn new(address_type: AddressType, account: u32, kind: KeychainKind) -> Self {
+        Self {
+            address_type,
+            account,
+            kind,
+        }
+    }
+
+    /// Receive chain of the first account
+    pub fn receive(address_type: AddressType) -> Self {
+        Self::new(address_type, 0, KeychainKind::External)
+    }
+
+    /// Change chain of the first account
+    pub fn change(address_type: AddressType) -> Self {
+        Self::new(address_type, 0, KeychainKind::Internal)
+    }
+
+    /// Every chain of an account, receive and change for each address type
+    pub fn account(account: u32) -> Vec<Self> {
+        AddressType::ALL
+            .iter()
+            .flat_map(|t| {
+                [KeychainKind::External, KeychainKind::Internal]
+                    .map(|kind| Self::new(*t, account, kind))
+            })
+            .collect()
+    }
+
+    /// Derivation path of the account this chain belongs to, `m/purpose'/0'/account'`
+    pub fn account_path(&self) -> Result<DerivationPath> {
+        Ok(DerivationPath::from(vec![
+            ChildNumber::from_hardened_idx(self.address_type.purpose())?,
+            ChildNumber::from_hardened_idx(0)?, // Bitcoin
+            ChildNumber::from_hardened_idx(self.account)?,
+        ]))
+    }
+
+    /// Derivation path of the key at `index` on this chain
+    pub fn path(&self, index: u32) -> Result<DerivationPath> {
+        let chain = match self.kind {
+            KeychainKind::External => 0,
+            KeychainKind::Internal => 1,
+        };
+        Ok(self.account_path()?.extend([
+            ChildNumber::from_normal_idx(chain)?,
+            ChildNumber::from_normal_idx(index)?,
+        ]))
+    }
+}
+
+/// Scan chains together, `gap_limit` addresses past the last used one at a time, until every
+/// chain ends in `gap_limit` unused addresses. `find_used` is called once per round with the
+/// addresses derived in it. Returns one past the last used index of every chain.
+pub fn scan_chains<K, D, U>(
+    chains: &[K],
+    gap_limit: u32,
+    mut derive: D,
+    mut find_used: U,
+) -> Result<HashMap<K, u32>>
+where
+    K: Copy + Eq + Hash,
+    D: FnMut(K, u32) -> Result<Address>,
+    U: FnMut(&[Address]) -> Result<HashSet<Address>>,
+{
+    let gap_limit = gap_limit.max(1);
+    let mut next: HashMap<K, u32> = chains.iter().map(|c| (*c, 0)).collect();
+    let mut scanned: HashMap<K, u32> = next.clone();
+
+    loop {
+        let mut round = vec![];
+        for chain in chains {
+            let end = next[chain] + gap_limit;
+            for index in scanned[chain]..end {
+                round.push((*chain, index, derive(*chain, index)?));
+            }
+            scanned.insert(*chain, end);
+        }
+        if round.is_empty() {
+            return Ok(next);
+        }
+
+        let addresses: Vec<Address> = round.iter().map(|(_, _, a)| a.clone()).collect();
+        let used = find_used(&addresses)?;
+        for (chain, index, address) in round {
+            if used.contains(&address) {
+                let next = next.get_mut(&chain).unwrap();
+                *next = (*next).max(index + 1);
+            }
+        }
+    }
+}
+
+/// Scan accounts 0, 1, ... in turn and stop at the first one without any used address, as
+/// in BIP44 account discovery. Returns the chains that have been used.
+pub fn scan_accounts<D, U>(
+    gap_limit: u32,
+    mut derive: D,
+    mut find_used: U,
+) -> Result<HashMap<Keychain, u32>>
+where
+    D: FnMut(Keychain, u32) -> Result<Address>,
+    U: FnMut(&[Address]) -> Result<HashSet<Address>>,
+{
+    let mut discovered = HashMap::new();
+    for account in 0.. {
+        let used: HashMap<Keychain, u32> = scan_chains(
+            &Keychain::account(account),
+            gap_limit,
+            &mut derive,
+            &mut find_used,
+        )?
+        .into_iter()
+        .filter(|(_, next)| *next > 0)
+        .collect();
+        if used.is_empty() {
+            break;
+        }
+        discovered.extend(used);
+    }
+    Ok(discovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::{Network, PublicKey};
+    use std::str::FromStr;
+
+    /// Distinct regtest addresses standing in for derived ones
+    fn address(chain: u32, index: u32) -> Address {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let mut bytes = [0u8; 32];
+        bytes[0] = 1;
+        bytes[28..].copy_from_slice(&(chain * 100_000 + index).to_be_bytes());
+        let key = bitcoin::secp256k1::SecretKey::from_slice(&bytes).unwrap();
+        let key = PublicKey::new(key.public_key(&secp));
+        Address::p2wpkh(&key, Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn scans_past_gap() {
+        let used: HashSet<Address> = [address(0, 0), address(0, 19), address(0, 38)]
+            .into_iter()
+            .chain([address(1, 3)])
+            .collect();
+        let mut rounds = 0;
+        let mut derived = 0;
+        let next = scan_chains(
+            &[0, 1, 2],
+            20,
+            |chain, index| {
+                derived += 1;
+                Ok(address(chain, index))
+            },
+            |addresses| {
+                rounds += 1;
+                Ok(addresses
+                    .iter()
+                    .filter(|a| used.contains(a))
+                    .cloned()
+                    .collect())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(next[&0], 39);
+        assert_eq!(next[&1], 4);
+        assert_eq!(next[&2], 0);
+        // Index 38 is only reached once 19 is seen used
+        assert_eq!(rounds, 3);
+        assert_eq!(derived, 59 + 24 + 20);
+    }
+
+    #[test]
+    fn stops_at_first_unused_account() {
+        let chain = Keychain::new(AddressType::Taproot, 1, KeychainKind::Internal);
+        let target = Keychain::account(1)
+            .iter()
+            .position(|c| *c == chain)
+            .unwrap() as u32;
+        let mut accounts = HashSet::new();
+        let discovered = scan_accounts(
+            5,
+            |keychain, index| {
+                accounts.insert(keychain.account);
+                let position = Keychain::account(keychain.account)
+                    .iter()
+                    .position(|c| *c == keychain)
+                    .unwrap() as u32;
+                Ok(address(keychain.account * 10 + position, index))
+            },
+            |addresses| {
+                let wanted = [address(0, 2), address(10 + target, 0)];
+                Ok(addresses
+                    .iter()
+                    .filter(|a| wanted.contains(a))
+                    .cloned()
+                    .collect())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(discovered.len(), 2);
+        assert_eq!(discovered[&Keychain::receive(AddressType::Legacy)], 3);
+        assert_eq!(discovered[&chain], 1);
+        assert_eq!(accounts, HashSet::from([0, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn derives_change_and_account_addresses() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                        abandon abandon abandon about";
+        manager
+            .create_wallet("alice", Some(mnemonic.to_string()))
+            .await
+            .unwrap();
+
+        let change = manager
+            .get_change_address("alice", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let account = manager
+            .get_address(
+                "alice",
+                Keychain::new(AddressType::Taproot, 2, KeychainKind::External),
+            )
+            .await
+            .unwrap();
+
+        let wallets = manager.wallets.read().await;
+        let wallet = &wallets["alice"];
+        let path = |address: &str| {
+            let address = Address::from_str(address).unwrap().assume_checked();
+            wallet.derivation_path(&address.script_pubkey()).unwrap()
+        };
+        assert_eq!(path(&change).to_string(), "m/84'/0'/0'/1/0");
+        assert_eq!(path(&account).to_string(), "m/86'/0'/2'/0/0");
+        assert_eq!(
+            wallet.next_index(Keychain::receive(AddressType::NativeSegwit)),
+            1
+        );
+    }
+}
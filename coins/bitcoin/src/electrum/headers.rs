@@ -0,0 +1,355 @@
+// Block header chain verified by proof of work, for checking what an Electrum server reports
+use anyhow::Result;
+use bitcoin::block::Header;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::Params;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::pow::Work;
+use bitcoin::{BlockHash, CompactTarget, Network, Target, TxMerkleNode, Txid};
+
+/// Blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u32 = 2016;
+
+/// Headers from a trusted starting point to the best tip seen, each checked to link to the
+/// previous one and to carry enough proof of work for its target
+#[derive(Clone)]
+pub struct HeaderChain {
+    params: Params,
+    /// Height of `headers[0]`, which is trusted rather than verified
+    base_height: u32,
+    headers: Vec<Header>,
+}
+
+impl HeaderChain {
+    /// Chain starting at the network's genesis block
+    pub fn new(network: Network) -> Self {
+        Self::with_checkpoint(network, 0, genesis_block(network).header)
+    }
+
+    /// Chain starting at a header trusted out of band, to avoid fetching every header. On
+    /// networks that retarget, the checkpoint must be at a retarget boundary so the first
+    /// header of every later period is verified and the next target can be computed.
+    pub fn with_checkpoint(network: Network, height: u32, header: Header) -> Self {
+        Self {
+            params: Params::new(network),
+            base_height: height,
+            headers: vec![header],
+        }
+    }
+
+    pub fn base_height(&self) -> u32 {
+        self.base_height
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        self.base_height + self.headers.len() as u32 - 1
+    }
+
+    pub fn tip(&self) -> &Header {
+        self.headers
+            .last()
+            .expect("chain always holds its base header")
+    }
+
+    /// Verified header at a height
+    pub fn get(&self, height: u32) -> Option<&Header> {
+        let index = height.checked_sub(self.base_height)?;
+        self.headers.get(index as usize)
+    }
+
+    /// Verify headers following the tip and append them
+    pub fn extend(&mut self, headers: &[Header]) -> Result<()> {
+        for header in headers {
+            let height = self.tip_height() + 1;
+            self.verify(self.tip(), height, header)?;
+            self.headers.push(*header);
+        }
+        Ok(())
+    }
+
+    /// Total proof of work of the headers above `height`
+    pub fn work_above(&self, height: u32) -> Work {
+        let skip = (height + 1).saturating_sub(self.base_height) as usize;
+        self.headers
+            .iter()
+            .skip(skip)
+            .fold(Work::from_be_bytes([0; 32]), |work, header| {
+                work + header.work()
+            })
+    }
+
+    /// Drop every header above `height` after a reorg. The base header is always kept.
+    pub fn truncate(&mut self, height: u32) {
+        let keep = height.saturating_sub(self.base_height) as usize + 1;
+        self.headers.truncate(keep.max(1));
+    }
+
+    /// Check a transaction's merkle branch, as returned by `blockchain.transaction.get_merkle`,
+    /// against the verified header at `height`
+    pub fn verify_merkle_proof(
+        &self,
+        txid: &Txid,
+        height: u32,
+        branch: &[TxMerkleNode],
+        position: usize,
+    ) -> Result<()> {
+        let header = self
+            .get(height)
+            .ok_or_else(|| anyhow::anyhow!("No verified header at height {height}"))?;
+        if merkle_root(txid, branch, position) != header.merkle_root {
+            return Err(anyhow::anyhow!(
+                "Merkle proof for {txid} does not match block {height}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn verify(&self, prev: &Header, height: u32, header: &Header) -> Result<BlockHash> {
+        if header.prev_blockhash != prev.block_hash() {
+            return Err(anyhow::anyhow!(
+                "Header {height} does not connect to the previous header"
+            ));
+        }
+        if header.target() > self.params.pow_limit {
+            return Err(anyhow::anyhow!(
+                "Header {height} target is above the network limit"
+            ));
+        }
+        if !self.params.no_pow_retargeting && height.is_multiple_of(RETARGET_INTERVAL) {
+            // The target follows from how long the previous period took
+            let start = height - RETARGET_INTERVAL;
+            let first = self.get(start).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Header {height} retargets from header {start}, which isn't verified"
+                )
+            })?;
+            let timespan = (prev.time as i64 - first.time as i64).max(0) as u64;
+            let expected = next_work_required(prev.bits, timespan, &self.params);
+            if header.bits != expected {
+                return Err(anyhow::anyhow!(
+                    "Header {height} has target {:#x}, not the retarget {:#x}",
+                    header.bits.to_consensus(),
+                    expected.to_consensus()
+                ));
+            }
+        } else if !self.params.no_pow_retargeting
+            && !self.params.allow_min_difficulty_blocks
+            && header.bits != prev.bits
+        {
+            // The target may only change at retarget boundaries, except on test networks
+            // that allow minimum-difficulty blocks
+            return Err(anyhow::anyhow!(
+                "Header {height} changes difficulty outside a retarget boundary"
+            ));
+        }
+        header
+            .validate_pow(header.target())
+            .map_err(|e| anyhow::anyhow!("Header {height} has invalid proof of work: {e}"))
+    }
+}
+
+/// Target of the first header of a period, from the bits of the last header of the previous
+/// period and the seconds between that period's first and last headers. This is Bitcoin
+/// Core's `CalculateNextWorkRequired`: the old target scaled by the timespan, which is clamped
+/// to a quarter to four times the expected one, and capped at the network's limit.
+pub fn next_work_required(
+    last_bits: CompactTarget,
+    timespan: u64,
+    params: &Params,
+) -> CompactTarget {
+    let expected = params.pow_target_timespan;
+    let timespan = timespan.clamp(expected / 4, expected * 4);
+    // 256-bit target * timespan / expected, in 64-bit limbs with one to spare for the product
+    let mut limbs = [0u64; 5];
+    for (limb, chunk) in limbs[1..]
+        .iter_mut()
+        .zip(Target::from_compact(last_bits).to_be_bytes().chunks(8))
+    {
+        *limb = u64::from_be_bytes(chunk.try_into().expect("8-byte chunks"));
+    }
+    let mut carry = 0u128;
+    for limb in limbs.iter_mut().rev() {
+        let product = *limb as u128 * timespan as u128 + carry;
+        *limb = product as u64;
+        carry = product >> 64;
+    }
+    let mut remainder = 0u128;
+    for limb in limbs.iter_mut() {
+        let dividend = (remainder << 64) | *limb as u128;
+        *limb = (dividend / expected as u128) as u64;
+        remainder = dividend % expected as u128;
+    }
+    if limbs[0] != 0 {
+        return params.pow_limit.to_compact_lossy();
+    }
+    let mut bytes = [0u8; 32];
+    for (chunk, limb) in bytes.chunks_mut(8).zip(&limbs[1..]) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    Target::from_be_bytes(bytes)
+        .min(params.pow_limit)
+        .to_compact_lossy()
+}
+
+/// Merkle root implied by a transaction's branch and its position in the block
+pub fn merkle_root(txid: &Txid, branch: &[TxMerkleNode], position: usize) -> TxMerkleNode {
+    let mut node = txid.to_byte_array();
+    for (depth, sibling) in branch.iter().enumerate() {
+        let mut pair = [0u8; 64];
+        if (position >> depth) & 1 == 1 {
+            pair[..32].copy_from_slice(sibling.as_byte_array());
+            pair[32..].copy_from_slice(&node);
+        } else {
+            pair[..32].copy_from_slice(&node);
+            pair[32..].copy_from_slice(sibling.as_byte_array());
+        }
+        node = sha256d::Hash::hash(&pair).to_byte_array();
+    }
+    TxMerkleNode::from_byte_array(node)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bitcoin::block::Version;
+
+    /// Mine a regtest header on top of `prev`; the regtest target is met by about half of
+    /// all hashes
+    pub(crate) fn mine(prev: &Header, merkle_root: TxMerkleNode) -> Header {
+        let mut header = Header {
+            version: Version::TWO,
+            prev_blockhash: prev.block_hash(),
+            merkle_root,
+            time: prev.time + 600,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        while header.validate_pow(header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn verifies_links_and_work() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let first = mine(chain.tip(), TxMerkleNode::all_zeros());
+        let second = mine(&first, TxMerkleNode::all_zeros());
+        chain.extend(&[first, second]).unwrap();
+        assert_eq!(chain.tip_height(), 2);
+        assert_eq!(chain.get(1), Some(&first));
+
+        // Doesn't build on the tip
+        let stale = mine(&first, TxMerkleNode::from_byte_array([1; 32]));
+        assert!(chain.extend(&[stale]).is_err());
+
+        // Proof of work that doesn't meet the target
+        let mut weak = mine(&second, TxMerkleNode::all_zeros());
+        while weak.validate_pow(weak.target()).is_ok() {
+            weak.nonce += 1;
+        }
+        assert!(chain.extend(&[weak]).is_err());
+
+        chain.truncate(1);
+        chain.extend(&[stale]).unwrap();
+        assert_eq!(chain.tip(), &stale);
+    }
+
+    /// Bitcoin Core's `pow_tests` for mainnet retargets
+    #[test]
+    fn computes_retargets() {
+        let params = Params::new(Network::Bitcoin);
+        let next = |bits: u32, first: u64, last: u64| {
+            next_work_required(CompactTarget::from_consensus(bits), last - first, &params)
+                .to_consensus()
+        };
+        // Blocks 30240 to 32255
+        assert_eq!(next(0x1d00ffff, 1261130161, 1262152739), 0x1d00d86a);
+        // Blocks 0 to 2015, capped at the network limit
+        assert_eq!(next(0x1d00ffff, 1231006505, 1233061996), 0x1d00ffff);
+        // Blocks 66528 to 68543, clamped to a quarter of the expected timespan
+        assert_eq!(next(0x1c05a3f4, 1279008237, 1279297671), 0x1c0168fd);
+        // Blocks 46368 to 48383, clamped to four times the expected timespan
+        assert_eq!(next(0x1c387f6f, 1263163443, 1269211443), 0x1d00e1fd);
+    }
+
+    #[test]
+    fn checks_targets_at_retarget_boundaries() {
+        // Regtest with retargeting turned on, so headers are cheap to mine
+        let mut chain = HeaderChain::new(Network::Regtest);
+        chain.params.no_pow_retargeting = false;
+        let mut prev = *chain.tip();
+        let mut period = vec![];
+        for _ in 1..RETARGET_INTERVAL {
+            // Blocks a quarter as far apart as expected raise the difficulty
+            let mut header = mine(&prev, TxMerkleNode::all_zeros());
+            header.time = prev.time + 150;
+            while header.validate_pow(header.target()).is_err() {
+                header.nonce += 1;
+            }
+            period.push(header);
+            prev = header;
+        }
+        chain.extend(&period).unwrap();
+
+        // Keeping the network's easiest target at the boundary is refused
+        let easy = mine(&prev, TxMerkleNode::all_zeros());
+        assert!(chain.extend(&[easy]).is_err());
+
+        let mut retargeted = easy;
+        retargeted.bits = next_work_required(
+            prev.bits,
+            (prev.time - chain.get(0).unwrap().time) as u64,
+            &chain.params,
+        );
+        assert!(retargeted.target() < easy.target());
+        while retargeted.validate_pow(retargeted.target()).is_err() {
+            retargeted.nonce += 1;
+        }
+        chain.extend(&[retargeted]).unwrap();
+        assert_eq!(chain.tip_height(), RETARGET_INTERVAL);
+    }
+
+    #[test]
+    fn sums_work_above_a_height() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let first = mine(chain.tip(), TxMerkleNode::all_zeros());
+        let second = mine(&first, TxMerkleNode::all_zeros());
+        chain.extend(&[first, second]).unwrap();
+        assert_eq!(chain.work_above(0), first.work() + second.work());
+        assert_eq!(chain.work_above(1), second.work());
+        assert_eq!(chain.work_above(2), Work::from_be_bytes([0; 32]));
+    }
+
+    #[test]
+    fn checks_merkle_proofs() {
+        // Three transactions; the last is paired with itself
+        let txids = [txid(1), txid(2), txid(3)];
+        let hash = |a: &[u8; 32], b: &[u8; 32]| {
+            let mut pair = [0u8; 64];
+            pair[..32].copy_from_slice(a);
+            pair[32..].copy_from_slice(b);
+            sha256d::Hash::hash(&pair).to_byte_array()
+        };
+        let left = hash(&txids[0].to_byte_array(), &txids[1].to_byte_array());
+        let right = hash(&txids[2].to_byte_array(), &txids[2].to_byte_array());
+        let root = TxMerkleNode::from_byte_array(hash(&left, &right));
+
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let block = mine(chain.tip(), root);
+        chain.extend(&[block]).unwrap();
+
+        let branch = [
+            TxMerkleNode::from_byte_array(txids[2].to_byte_array()),
+            TxMerkleNode::from_byte_array(left),
+        ];
+        chain.verify_merkle_proof(&txids[2], 1, &branch, 2).unwrap();
+        assert!(chain.verify_merkle_proof(&txids[2], 1, &branch, 1).is_err());
+        assert!(chain.verify_merkle_proof(&txids[0], 1, &branch, 2).is_err());
+        assert!(chain.verify_merkle_proof(&txids[2], 5, &branch, 2).is_err());
+    }
+}
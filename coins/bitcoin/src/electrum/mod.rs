@@ -0,0 +1,1081 @@
+// Electrum protocol client over TCP or TLS, a chain backend that needs no full node
+pub mod headers;
+
+pub use headers::HeaderChain;
+
+use crate::utxo_manager::TrackedUtxo;
+use anyhow::Result;
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Address, Amount, FeeRate, Network, OutPoint, Script, Transaction, TxMerkleNode};
+use bitcoin::{TxOut, Txid};
+use futures::future::try_join_all;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot};
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+
+/// How long to wait for the server to answer a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Deepest reorg followed when the server's chain stops matching the verified headers
+pub const MAX_REORG_DEPTH: u32 = 100;
+
+/// Most headers `blockchain.block.headers` returns per call
+const MAX_HEADERS_PER_REQUEST: u32 = 2016;
+
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Event pushed by the server for a subscription
+#[derive(Debug, Clone)]
+pub enum ElectrumNotification {
+    /// New best block
+    Header { height: u32, header: Header },
+    /// A script's history changed; `status` hashes its new history
+    ScriptHash {
+        script_hash: String,
+        status: Option<String>,
+    },
+}
+
+/// Transaction touching a script, as reported by `blockchain.scripthash.get_history`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(rename = "tx_hash")]
+    pub txid: Txid,
+    /// Block height, or 0 in the mempool (-1 when spending unconfirmed outputs)
+    pub height: i64,
+}
+
+impl HistoryEntry {
+    pub fn is_confirmed(&self) -> bool {
+        self.height > 0
+    }
+}
+
+/// Balance of a script; the unconfirmed part is negative when mempool transactions spend it
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ScriptBalance {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
+#[derive(Deserialize)]
+struct UnspentEntry {
+    tx_hash: Txid,
+    tx_pos: u32,
+    height: i64,
+    value: u64,
+}
+
+#[derive(Deserialize)]
+struct HeaderEntry {
+    height: u32,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct HeadersChunk {
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct MerkleProof {
+    merkle: Vec<TxMerkleNode>,
+    pos: usize,
+}
+
+/// Requests waiting for an answer, by id
+#[derive(Default)]
+struct Inflight {
+    closed: bool,
+    waiting: HashMap<u64, oneshot::Sender<Result<Value>>>,
+}
+
+/// Electrum server connection. Requests are pipelined over one socket; subscription
+/// notifications are delivered through `notifications()`. Confirmed history returned by
+/// `verified_history` is checked against a proof-of-work verified header chain.
+pub struct ElectrumClient {
+    writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    inflight: Arc<Mutex<Inflight>>,
+    next_id: AtomicU64,
+    notifications: broadcast::Sender<ElectrumNotification>,
+    headers: tokio::sync::Mutex<HeaderChain>,
+    network: Network,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl ElectrumClient {
+    /// Connect to `ssl://host:port`, `tls://host:port` or `tcp://host:port`; a bare
+    /// `host:port` uses TLS. TLS certificates must chain to a public root CA.
+    pub async fn connect(url: &str, network: Network) -> Result<Self> {
+        Self::connect_with(url, network, None).await
+    }
+
+    /// Connect like Electrum does, for the many servers with self-signed certificates: a
+    /// certificate chaining to a public root CA is accepted, any other is pinned in `pins` the
+    /// first time it is seen and must stay the same afterwards (trust on first use).
+    pub async fn connect_pinned(
+        url: &str,
+        network: Network,
+        pins: &CertificatePins,
+    ) -> Result<Self> {
+        Self::connect_with(url, network, Some(pins)).await
+    }
+
+    async fn connect_with(
+        url: &str,
+        network: Network,
+        pins: Option<&CertificatePins>,
+    ) -> Result<Self> {
+        let (tls, host, port) = parse_url(url)?;
+        let stream = TcpStream::connect((host.as_str(), port)).await?;
+        if !tls {
+            return Self::handshake(Self::start(stream, network)).await;
+        }
+
+        let server = format!("{host}:{port}");
+        let server_name = ServerName::try_from(host)?;
+        let roots = Arc::new(root_certificates());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let (config, verifier) = match pins {
+            Some(pins) => {
+                let verifier = Arc::new(PinningVerifier {
+                    webpki: WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+                        .build()?,
+                    pinned: pins.get(&server)?,
+                    seen: Mutex::new(None),
+                    provider,
+                });
+                let config = builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier.clone())
+                    .with_no_client_auth();
+                (config, Some(verifier))
+            }
+            None => (
+                builder.with_root_certificates(roots).with_no_client_auth(),
+                None,
+            ),
+        };
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let client = Self::handshake(Self::start(
+            connector.connect(server_name, stream).await?,
+            network,
+        ))
+        .await?;
+        // Pin a new self-signed certificate only once the server proved it holds its key
+        let seen = verifier.and_then(|v| *v.seen.lock().unwrap());
+        if let (Some(pins), Some(fingerprint)) = (pins, seen) {
+            pins.pin(&server, fingerprint)?;
+        }
+        Ok(client)
+    }
+
+    async fn handshake(client: Self) -> Result<Self> {
+        let _: Value = client
+            .request("server.version", json!(["walletd", PROTOCOL_VERSION]))
+            .await?;
+        Ok(client)
+    }
+
+    fn start<S>(stream: S, network: Network) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+        let inflight = Arc::new(Mutex::new(Inflight::default()));
+        let (notifications, _) = broadcast::channel(256);
+        let reader = tokio::spawn(read_messages(
+            BufReader::new(read),
+            inflight.clone(),
+            notifications.clone(),
+        ));
+
+        Self {
+            writer: tokio::sync::Mutex::new(Box::new(write)),
+            inflight,
+            next_id: AtomicU64::new(0),
+            notifications,
+            headers: tokio::sync::Mutex::new(HeaderChain::new(network)),
+            network,
+            reader,
+        }
+    }
+
+    /// Verify headers from a trusted checkpoint instead of from the genesis block
+    pub async fn set_checkpoint(&self, height: u32, header: Header) {
+        *self.headers.lock().await = HeaderChain::with_checkpoint(self.network, height, header);
+    }
+
+    /// Events for subscribed scripts and new blocks
+    pub fn notifications(&self) -> broadcast::Receiver<ElectrumNotification> {
+        self.notifications.subscribe()
+    }
+
+    /// Call a method and wait for its result
+    pub async fn request<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut inflight = self.inflight.lock().unwrap();
+            if inflight.closed {
+                return Err(anyhow::anyhow!("Electrum connection closed"));
+            }
+            inflight.waiting.insert(id, sender);
+        }
+
+        let mut line = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        line.push(b'\n');
+        {
+            let mut writer = self.writer.lock().await;
+            writer.write_all(&line).await?;
+            writer.flush().await?;
+        }
+
+        let result = match tokio::time::timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result?,
+            Ok(Err(_)) => return Err(anyhow::anyhow!("Electrum connection closed")),
+            Err(_) => {
+                self.inflight.lock().unwrap().waiting.remove(&id);
+                return Err(anyhow::anyhow!("Electrum request {method} timed out"));
+            }
+        };
+        Ok(serde_json::from_value(result)?)
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let _: Value = self.request("server.ping", json!([])).await?;
+        Ok(())
+    }
+
+    /// Height and header of the server's best block. Also subscribes to new blocks.
+    pub async fn tip(&self) -> Result<(u32, Header)> {
+        let tip: HeaderEntry = self
+            .request("blockchain.headers.subscribe", json!([]))
+            .await?;
+        Ok((tip.height, decode_header(&tip.hex)?))
+    }
+
+    /// Up to `count` consecutive headers starting at `start`
+    pub async fn block_headers(&self, start: u32, count: u32) -> Result<Vec<Header>> {
+        let chunk: HeadersChunk = self
+            .request("blockchain.block.headers", json!([start, count]))
+            .await?;
+        hex::decode(&chunk.hex)?
+            .chunks(80)
+            .map(|bytes| Ok(deserialize(bytes)?))
+            .collect()
+    }
+
+    /// Fetch and verify headers up to the server's tip, following reorgs up to
+    /// `MAX_REORG_DEPTH` deep. The server's chain replaces the verified one only if it has
+    /// more proof of work. Returns the verified tip height.
+    pub async fn sync_headers(&self) -> Result<u32> {
+        let (server_tip, _) = self.tip().await?;
+        let mut chain = self.headers.lock().await;
+        // Build the server's chain apart, so a weaker one never replaces ours
+        let mut candidate = chain.clone();
+        let mut fork = chain.tip_height();
+        let mut depth = 0;
+        loop {
+            // Start from a header we hold so we can tell whether the server agrees with it
+            let from = candidate.tip_height().min(server_tip);
+            let count = (server_tip - from + 1).min(MAX_HEADERS_PER_REQUEST);
+            let headers = self.block_headers(from, count).await?;
+            let first = headers
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("Server returned no headers from {from}"))?;
+
+            if Some(first) != candidate.get(from) {
+                if depth == MAX_REORG_DEPTH || from <= candidate.base_height() {
+                    return Err(anyhow::anyhow!(
+                        "Server chain does not connect to the verified headers"
+                    ));
+                }
+                candidate.truncate(from - 1);
+                fork = fork.min(from - 1);
+                depth += 1;
+                continue;
+            }
+
+            candidate.truncate(from);
+            fork = fork.min(from);
+            candidate.extend(&headers[1..])?;
+            if candidate.tip_height() >= server_tip {
+                break;
+            }
+        }
+
+        if fork == chain.tip_height() || candidate.work_above(fork) > chain.work_above(fork) {
+            *chain = candidate;
+        } else if candidate.get(fork + 1).is_some() {
+            return Err(anyhow::anyhow!(
+                "Server follows a chain with less work than the verified headers from {fork}"
+            ));
+        }
+        // Otherwise the server is only behind our tip
+        Ok(chain.tip_height())
+    }
+
+    pub async fn get_balance(&self, script: &Script) -> Result<ScriptBalance> {
+        self.request(
+            "blockchain.scripthash.get_balance",
+            json!([script_hash(script)]),
+        )
+        .await
+    }
+
+    pub async fn get_history(&self, script: &Script) -> Result<Vec<HistoryEntry>> {
+        self.request(
+            "blockchain.scripthash.get_history",
+            json!([script_hash(script)]),
+        )
+        .await
+    }
+
+    /// Subscribe to changes in a script's history, returning its current status hash
+    /// (`None` while it has no history)
+    pub async fn subscribe_script(&self, script: &Script) -> Result<Option<String>> {
+        self.request(
+            "blockchain.scripthash.subscribe",
+            json!([script_hash(script)]),
+        )
+        .await
+    }
+
+    pub async fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        let hex: String = self
+            .request("blockchain.transaction.get", json!([txid.to_string()]))
+            .await?;
+        Ok(deserialize(&hex::decode(hex)?)?)
+    }
+
+    pub async fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.request(
+            "blockchain.transaction.broadcast",
+            json!([serialize_hex(tx)]),
+        )
+        .await
+    }
+
+    /// Fee rate for confirmation within `target` blocks
+    pub async fn estimate_fee(&self, target: u16) -> Result<FeeRate> {
+        let btc_per_kvb: f64 = self
+            .request("blockchain.estimatefee", json!([target]))
+            .await?;
+        btc_per_kvb_to_fee_rate(btc_per_kvb)
+            .ok_or_else(|| anyhow::anyhow!("Electrum server has no fee estimate for {target}"))
+    }
+
+    /// Lowest fee rate the server's node relays
+    pub async fn relay_fee(&self) -> Result<FeeRate> {
+        let btc_per_kvb: f64 = self.request("blockchain.relayfee", json!([])).await?;
+        btc_per_kvb_to_fee_rate(btc_per_kvb)
+            .ok_or_else(|| anyhow::anyhow!("Electrum server returned no relay fee"))
+    }
+
+    /// Unspent outputs paying to the addresses, confirmed and unconfirmed
+    pub async fn utxos(&self, addresses: &[Address]) -> Result<Vec<TrackedUtxo>> {
+        let (tip, _) = self.tip().await?;
+        let lists = try_join_all(addresses.iter().map(|address| async move {
+            let script = address.script_pubkey();
+            let unspent: Vec<UnspentEntry> = self
+                .request(
+                    "blockchain.scripthash.listunspent",
+                    json!([script_hash(&script)]),
+                )
+                .await?;
+            Ok::<_, anyhow::Error>((script, unspent))
+        }))
+        .await?;
+
+        Ok(lists
+            .into_iter()
+            .flat_map(|(script, unspent)| {
+                unspent.into_iter().map(move |u| TrackedUtxo {
+                    outpoint: OutPoint::new(u.tx_hash, u.tx_pos),
+                    txout: TxOut {
+                        value: Amount::from_sat(u.value),
+                        script_pubkey: script.clone(),
+                    },
+                    confirmations: if u.height > 0 {
+                        (tip as i64 - u.height + 1).max(0) as u32
+                    } else {
+                        0
+                    },
+                })
+            })
+            .collect())
+    }
+
+    /// Which of the addresses have any history
+    pub async fn used_addresses(&self, addresses: &[Address]) -> Result<HashSet<Address>> {
+        let histories = try_join_all(addresses.iter().map(|address| async move {
+            let history = self.get_history(&address.script_pubkey()).await?;
+            Ok::<_, anyhow::Error>((address, history))
+        }))
+        .await?;
+        Ok(histories
+            .into_iter()
+            .filter(|(_, history)| !history.is_empty())
+            .map(|(address, _)| address.clone())
+            .collect())
+    }
+
+    /// History of the addresses, oldest first with unconfirmed transactions last. Every
+    /// confirmed transaction's merkle proof is checked against the verified header chain.
+    pub async fn verified_history(&self, addresses: &[Address]) -> Result<Vec<HistoryEntry>> {
+        let histories = try_join_all(
+            addresses
+                .iter()
+                .map(|address| async move { self.get_history(&address.script_pubkey()).await }),
+        )
+        .await?;
+        let mut entries: Vec<HistoryEntry> = histories.into_iter().flatten().collect();
+        entries.sort_by_key(|e| (!e.is_confirmed(), e.height, e.txid));
+        entries.dedup_by_key(|e| e.txid);
+
+        self.sync_headers().await?;
+        for entry in entries.iter().filter(|e| e.is_confirmed()) {
+            let height = entry.height as u32;
+            let proof: MerkleProof = self
+                .request(
+                    "blockchain.transaction.get_merkle",
+                    json!([entry.txid.to_string(), height]),
+                )
+                .await?;
+            let headers = self.headers.lock().await;
+            if height > headers.base_height() {
+                headers.verify_merkle_proof(&entry.txid, height, &proof.merkle, proof.pos)?;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Drop for ElectrumClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Electrum's script hash: the SHA256 of the script, hex encoded in reverse byte order
+pub fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).to_byte_array();
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Split `scheme://host:port` into whether to use TLS, host and port
+fn parse_url(url: &str) -> Result<(bool, String, u16)> {
+    let (tls, address) = match url.split_once("://") {
+        Some(("ssl" | "tls", address)) => (true, address),
+        Some(("tcp", address)) => (false, address),
+        Some((scheme, _)) => return Err(anyhow::anyhow!("Unsupported Electrum scheme {scheme}")),
+        None => (true, url),
+    };
+    let (host, port) = address
+        .trim_end_matches('/')
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Electrum URL {url} has no port"))?;
+    Ok((
+        tls,
+        host.trim_matches(['[', ']']).to_string(),
+        port.parse()?,
+    ))
+}
+
+fn root_certificates() -> rustls::RootCertStore {
+    rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// SHA256 fingerprint of a DER certificate
+fn fingerprint(certificate: &CertificateDer<'_>) -> [u8; 32] {
+    sha256::Hash::hash(certificate.as_ref()).to_byte_array()
+}
+
+/// Electrum server certificates pinned on first use, by `host:port`, in a JSON file mapping
+/// each server to the hex SHA256 fingerprint of its certificate
+#[derive(Debug, Clone)]
+pub struct CertificatePins {
+    path: PathBuf,
+}
+
+impl CertificatePins {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// `walletd/electrum_certs.json` in the user's data directory
+    pub fn default_location() -> Self {
+        let dir = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+        Self::new(dir.join("walletd").join("electrum_certs.json"))
+    }
+
+    /// Fingerprint pinned for `host:port`
+    pub fn get(&self, server: &str) -> Result<Option<[u8; 32]>> {
+        self.load()?
+            .get(server)
+            .map(|hex| {
+                hex::decode(hex)?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Malformed pin for {server}"))
+            })
+            .transpose()
+    }
+
+    /// Pin a certificate fingerprint for `host:port`, e.g. one checked out of band
+    pub fn pin(&self, server: &str, fingerprint: [u8; 32]) -> Result<()> {
+        let mut pins = self.load()?;
+        pins.insert(server.to_string(), hex::encode(fingerprint));
+        self.save(&pins)
+    }
+
+    /// Forget the pin for `host:port`, after the server operator replaced its certificate
+    pub fn remove(&self, server: &str) -> Result<()> {
+        let mut pins = self.load()?;
+        if pins.remove(server).is_some() {
+            self.save(&pins)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, String>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, pins: &BTreeMap<String, String>) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(pins)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Accepts certificates that chain to a public root CA, and otherwise only the pinned one, or
+/// any certificate when none is pinned yet, remembering it in `seen`
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pinned: Option<[u8; 32]>,
+    seen: Mutex<Option<[u8; 32]>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let ca_signed = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if ca_signed.is_ok() {
+            return ca_signed;
+        }
+        let fingerprint = fingerprint(end_entity);
+        match self.pinned {
+            Some(pinned) if pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(pinned) => Err(rustls::Error::General(format!(
+                "Certificate of {server_name:?} changed from the pinned {} to {}",
+                hex::encode(pinned),
+                hex::encode(fingerprint)
+            ))),
+            None => {
+                *self.seen.lock().unwrap() = Some(fingerprint);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Route responses to their requests and notifications to subscribers until the
+/// connection closes, then fail whatever is still waiting
+async fn read_messages<R: AsyncRead + Unpin>(
+    reader: BufReader<R>,
+    inflight: Arc<Mutex<Inflight>>,
+    notifications: broadcast::Sender<ElectrumNotification>,
+) {
+    let mut lines = reader.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let messages = match serde_json::from_str(&line) {
+            Ok(Value::Array(batch)) => batch,
+            Ok(message) => vec![message],
+            Err(e) => {
+                log::warn!("Ignoring malformed Electrum message: {e}");
+                continue;
+            }
+        };
+        for message in messages {
+            if let Some(id) = message.get("id").and_then(Value::as_u64) {
+                let sender = inflight.lock().unwrap().waiting.remove(&id);
+                if let Some(sender) = sender {
+                    let _ = sender.send(response_result(message));
+                }
+                continue;
+            }
+            match parse_notification(&message) {
+                Ok(Some(notification)) => {
+                    let _ = notifications.send(notification);
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Ignoring malformed Electrum notification: {e}"),
+            }
+        }
+    }
+
+    let mut inflight = inflight.lock().unwrap();
+    inflight.closed = true;
+    inflight.waiting.clear();
+}
+
+fn response_result(mut message: Value) -> Result<Value> {
+    match message.get("error") {
+        Some(error) if !error.is_null() => Err(anyhow::anyhow!(
+            "Electrum error: {}",
+            error["message"].as_str().unwrap_or(&error.to_string())
+        )),
+        _ => Ok(message["result"].take()),
+    }
+}
+
+fn parse_notification(message: &Value) -> Result<Option<ElectrumNotification>> {
+    let params = message["params"].clone();
+    match message["method"].as_str() {
+        Some("blockchain.headers.subscribe") => {
+            let [tip]: [HeaderEntry; 1] = serde_json::from_value(params)?;
+            Ok(Some(ElectrumNotification::Header {
+                height: tip.height,
+                header: decode_header(&tip.hex)?,
+            }))
+        }
+        Some("blockchain.scripthash.subscribe") => {
+            let (script_hash, status) = serde_json::from_value(params)?;
+            Ok(Some(ElectrumNotification::ScriptHash {
+                script_hash,
+                status,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn decode_header(hex: &str) -> Result<Header> {
+    Ok(deserialize(&hex::decode(hex)?)?)
+}
+
+/// Convert a BTC/kvB rate as returned by Electrum, where -1 means no estimate
+fn btc_per_kvb_to_fee_rate(btc_per_kvb: f64) -> Option<FeeRate> {
+    (btc_per_kvb > 0.0)
+        .then(|| FeeRate::from_sat_per_kwu((btc_per_kvb * 100_000_000.0 / 4.0).ceil() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::headers::tests::mine;
+    use super::*;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+
+    /// Electrum server on localhost answering with `handler`. Every scripthash subscription
+    /// is followed by a status change notification.
+    async fn serve<H>(handler: H) -> String
+    where
+        H: Fn(&str, &Value) -> Value + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let method = request["method"].as_str().unwrap();
+                let mut reply = json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": handler(method, &request["params"]),
+                })
+                .to_string();
+                if method == "blockchain.scripthash.subscribe" {
+                    reply.push('\n');
+                    reply.push_str(
+                        &json!({
+                            "jsonrpc": "2.0",
+                            "method": method,
+                            "params": [request["params"][0], "changed"],
+                        })
+                        .to_string(),
+                    );
+                }
+                reply.push('\n');
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    /// TLS Electrum server on localhost presenting the i-th certificate and its PKCS#8 key on
+    /// its i-th connection
+    async fn serve_tls(certificates: Vec<(CertificateDer<'static>, Vec<u8>)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        tokio::spawn(async move {
+            for (certificate, key) in certificates {
+                let (socket, _) = listener.accept().await.unwrap();
+                let key = rustls::pki_types::PrivatePkcs8KeyDer::from(key);
+                let config = rustls::ServerConfig::builder_with_provider(provider.clone())
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .with_no_client_auth()
+                    .with_single_cert(vec![certificate], key.into())
+                    .unwrap();
+                let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(socket).await else {
+                        return;
+                    };
+                    let (read, mut write) = tokio::io::split(stream);
+                    let mut lines = BufReader::new(read).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let request: Value = serde_json::from_str(&line).unwrap();
+                        let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": []});
+                        let reply = format!("{reply}\n");
+                        write.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn pins_self_signed_certificates_on_first_use() {
+        let self_signed = || {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            (
+                certified.cert.der().clone(),
+                certified.key_pair.serialize_der(),
+            )
+        };
+        let (first, second) = (self_signed(), self_signed());
+        let pinned = fingerprint(&first.0);
+        let port = serve_tls(vec![
+            self_signed(),
+            self_signed(),
+            first.clone(),
+            first,
+            second,
+        ])
+        .await;
+        let url = format!("ssl://localhost:{port}");
+        let server = format!("localhost:{port}");
+        let dir = std::env::temp_dir().join(format!("walletd-electrum-{}", uuid::Uuid::new_v4()));
+        let pins = CertificatePins::new(dir.join("certs.json"));
+
+        // Not signed by a public CA
+        assert!(ElectrumClient::connect(&url, Network::Regtest)
+            .await
+            .is_err());
+        // A pin that doesn't match is refused, and stays in place
+        pins.pin(&server, [0; 32]).unwrap();
+        assert!(
+            ElectrumClient::connect_pinned(&url, Network::Regtest, &pins)
+                .await
+                .is_err()
+        );
+        assert_eq!(pins.get(&server).unwrap(), Some([0; 32]));
+
+        // Trusted on first use
+        pins.remove(&server).unwrap();
+        ElectrumClient::connect_pinned(&url, Network::Regtest, &pins)
+            .await
+            .unwrap();
+        assert_eq!(pins.get(&server).unwrap(), Some(pinned));
+        ElectrumClient::connect_pinned(&url, Network::Regtest, &pins)
+            .await
+            .unwrap();
+
+        // A different certificate afterwards is refused
+        let error = ElectrumClient::connect_pinned(&url, Network::Regtest, &pins)
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{error:?}").contains("changed"), "{error:?}");
+        assert_eq!(pins.get(&server).unwrap(), Some(pinned));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn address(n: u8) -> Address {
+        let script =
+            bitcoin::ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([n; 20]));
+        Address::from_script(&script, Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn parses_urls_and_script_hashes() {
+        assert_eq!(
+            parse_url("ssl://electrum.blockstream.info:60002").unwrap(),
+            (true, "electrum.blockstream.info".to_string(), 60002)
+        );
+        assert_eq!(
+            parse_url("tcp://127.0.0.1:50001").unwrap(),
+            (false, "127.0.0.1".to_string(), 50001)
+        );
+        assert!(parse_url("testnet.aranguren.org:51002").unwrap().0);
+        assert!(parse_url("http://example.com:80").is_err());
+
+        // Example from the Electrum protocol docs
+        let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")
+            .unwrap()
+            .assume_checked();
+        assert_eq!(
+            script_hash(&address.script_pubkey()),
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161"
+        );
+    }
+
+    #[tokio::test]
+    async fn syncs_utxos_and_notifications() {
+        let funded = address(1);
+        let funded_hash = script_hash(&funded.script_pubkey());
+        let served_hash = json!(funded_hash);
+        let url = serve(move |method, params| match method {
+            "server.version" => json!(["electrs", "1.4"]),
+            "blockchain.headers.subscribe" => json!({
+                "height": 110,
+                "hex": serialize_hex(&bitcoin::blockdata::constants::genesis_block(
+                    Network::Regtest
+                ).header),
+            }),
+            "blockchain.scripthash.listunspent" if params[0] == served_hash => json!([
+                {"tx_hash": "aa".repeat(32), "tx_pos": 1, "height": 101, "value": 5000},
+                {"tx_hash": "bb".repeat(32), "tx_pos": 0, "height": 0, "value": 700},
+            ]),
+            "blockchain.scripthash.listunspent" => json!([]),
+            "blockchain.scripthash.get_history" if params[0] == served_hash => {
+                json!([{"tx_hash": "aa".repeat(32), "height": 101}])
+            }
+            "blockchain.scripthash.get_history" => json!([]),
+            "blockchain.scripthash.subscribe" => json!(null),
+            "blockchain.estimatefee" => json!(0.0002),
+            _ => json!(null),
+        })
+        .await;
+
+        let client = ElectrumClient::connect(&url, Network::Regtest)
+            .await
+            .unwrap();
+        let utxos = client.utxos(&[funded.clone(), address(2)]).await.unwrap();
+        assert_eq!(utxos.len(), 2);
+        let confirmed = utxos.iter().find(|u| u.outpoint.vout == 1).unwrap();
+        assert_eq!(confirmed.confirmations, 10);
+        assert_eq!(confirmed.txout.script_pubkey, funded.script_pubkey());
+        assert!(utxos
+            .iter()
+            .any(|u| u.confirmations == 0 && u.txout.value.to_sat() == 700));
+
+        let used = client
+            .used_addresses(&[funded.clone(), address(2)])
+            .await
+            .unwrap();
+        assert_eq!(used, HashSet::from([funded.clone()]));
+
+        // 0.0002 BTC/kvB is 20 sat/vB
+        let fee_rate = client.estimate_fee(6).await.unwrap();
+        assert_eq!(fee_rate.to_sat_per_vb_ceil(), 20);
+
+        let mut notifications = client.notifications();
+        assert_eq!(
+            client
+                .subscribe_script(&funded.script_pubkey())
+                .await
+                .unwrap(),
+            None
+        );
+        match notifications.recv().await.unwrap() {
+            ElectrumNotification::ScriptHash {
+                script_hash,
+                status,
+            } => {
+                assert_eq!(script_hash, funded_hash);
+                assert_eq!(status.as_deref(), Some("changed"));
+            }
+            other => panic!("unexpected notification {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_history_against_headers() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Regtest).header;
+        let txid = Txid::from_byte_array([7; 32]);
+        // A single-transaction block's merkle root is the txid itself
+        let first = mine(&genesis, TxMerkleNode::from_byte_array([7; 32]));
+        let second = mine(&first, TxMerkleNode::all_zeros());
+        let chain = [genesis, first, second];
+        let funded = address(3);
+
+        let url = serve(move |method, params| match method {
+            "blockchain.headers.subscribe" => json!({"height": 2, "hex": serialize_hex(&second)}),
+            "blockchain.block.headers" => {
+                let start = params[0].as_u64().unwrap() as usize;
+                let count = params[1].as_u64().unwrap() as usize;
+                let hex: String = chain[start..(start + count).min(3)]
+                    .iter()
+                    .map(serialize_hex)
+                    .collect();
+                json!({"count": count, "hex": hex, "max": 2016})
+            }
+            "blockchain.scripthash.get_history" => json!([
+                {"tx_hash": txid.to_string(), "height": 1},
+                {"tx_hash": "cc".repeat(32), "height": 0},
+            ]),
+            "blockchain.transaction.get_merkle" => json!({
+                "block_height": 1, "merkle": [], "pos": 0,
+            }),
+            _ => json!(null),
+        })
+        .await;
+
+        let client = ElectrumClient::connect(&url, Network::Regtest)
+            .await
+            .unwrap();
+        let history = client.verified_history(&[funded]).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].txid, txid);
+        assert!(!history[1].is_confirmed());
+        assert_eq!(client.headers.lock().await.tip(), &second);
+    }
+
+    #[tokio::test]
+    async fn follows_only_reorgs_to_more_work() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Regtest).header;
+        let branch = |tag: u8, len: usize| {
+            let mut headers = vec![genesis];
+            for _ in 0..len {
+                let prev = *headers.last().unwrap();
+                headers.push(mine(&prev, TxMerkleNode::from_byte_array([tag; 32])));
+            }
+            headers
+        };
+        let (ours, shorter, longer) = (branch(1, 3), branch(2, 1), branch(3, 4));
+        let served = Arc::new(Mutex::new(ours.clone()));
+        let url = serve({
+            let served = served.clone();
+            move |method, params| {
+                let chain = served.lock().unwrap();
+                match method {
+                    "blockchain.headers.subscribe" => json!({
+                        "height": chain.len() - 1,
+                        "hex": serialize_hex(chain.last().unwrap()),
+                    }),
+                    "blockchain.block.headers" => {
+                        let start = params[0].as_u64().unwrap() as usize;
+                        let count = params[1].as_u64().unwrap() as usize;
+                        let hex: String = chain[start..(start + count).min(chain.len())]
+                            .iter()
+                            .map(serialize_hex)
+                            .collect();
+                        json!({"count": count, "hex": hex, "max": 2016})
+                    }
+                    _ => json!(null),
+                }
+            }
+        })
+        .await;
+        let client = ElectrumClient::connect(&url, Network::Regtest)
+            .await
+            .unwrap();
+        assert_eq!(client.sync_headers().await.unwrap(), 3);
+
+        // A fork with less work is refused, and a server that is only behind is ignored
+        *served.lock().unwrap() = shorter;
+        assert!(client.sync_headers().await.is_err());
+        assert_eq!(client.headers.lock().await.tip(), &ours[3]);
+        *served.lock().unwrap() = ours[..3].to_vec();
+        assert_eq!(client.sync_headers().await.unwrap(), 3);
+
+        // A fork with more work replaces the verified headers
+        *served.lock().unwrap() = longer.clone();
+        assert_eq!(client.sync_headers().await.unwrap(), 4);
+        assert_eq!(client.headers.lock().await.tip(), &longer[4]);
+    }
+}
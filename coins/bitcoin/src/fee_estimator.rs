@@ -0,0 +1,310 @@
+// Fee estimation from bitcoind and Esplora with caching and a fixed fallback
+use crate::electrum::ElectrumClient;
+use anyhow::Result;
+use bitcoin::{Amount, FeeRate};
+use bitcoincore_rpc::json::EstimateMode;
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// How long an estimate is reused before the sources are queried again
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Fee rate to fall back to when no source can estimate
+pub const DEFAULT_FALLBACK_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(10);
+
+/// Confirmation speed a user can pick instead of a block target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeePriority {
+    /// Within about a day
+    Economy,
+    /// Within about an hour
+    Normal,
+    /// In the next block or two
+    Priority,
+}
+
+impl FeePriority {
+    /// Confirmation target in blocks
+    pub fn target(self) -> u16 {
+        match self {
+            FeePriority::Economy => 144,
+            FeePriority::Normal => 6,
+            FeePriority::Priority => 2,
+        }
+    }
+}
+
+impl std::str::FromStr for FeePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "economy" | "low" => Ok(FeePriority::Economy),
+            "normal" | "medium" => Ok(FeePriority::Normal),
+            "priority" | "high" => Ok(FeePriority::Priority),
+            _ => Err(anyhow::anyhow!("Unknown fee priority: {s}")),
+        }
+    }
+}
+
+/// Where fee estimates come from, queried in order until one answers
+#[derive(Clone)]
+pub enum FeeSource {
+    /// `estimatesmartfee` on a bitcoind node
+    Rpc(Arc<Client>),
+    /// An Esplora server's `/fee-estimates`, e.g. `https://blockstream.info/api`
+    Esplora(String),
+    /// `blockchain.estimatefee` on an Electrum server
+    Electrum(Arc<ElectrumClient>),
+}
+
+/// Fee rate estimator over several sources. Estimates are cached per target and never
+/// fall below the node's mempool minimum (or 1 sat/vB without a node).
+pub struct FeeEstimator {
+    sources: Vec<FeeSource>,
+    /// Used when every source fails, e.g. on a fresh regtest chain
+    fallback: Option<FeeRate>,
+    cache_ttl: Duration,
+    cache: RwLock<HashMap<u16, (FeeRate, Instant)>>,
+    http: reqwest::Client,
+}
+
+impl FeeEstimator {
+    pub fn new(sources: Vec<FeeSource>) -> Self {
+        Self {
+            sources,
+            fallback: None,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            cache: RwLock::new(HashMap::new()),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_source(mut self, source: FeeSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Fee rate returned when no source can estimate
+    pub fn with_fallback(mut self, fee_rate: FeeRate) -> Self {
+        self.fallback = Some(fee_rate);
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Fee rate for a transaction to confirm within `target` blocks
+    pub async fn estimate(&self, target: u16) -> Result<FeeRate> {
+        let target = target.max(1);
+        if let Some((fee_rate, at)) = self.cache.read().await.get(&target) {
+            if at.elapsed() < self.cache_ttl {
+                return Ok(*fee_rate);
+            }
+        }
+
+        let mut estimate = None;
+        let mut errors = vec![];
+        for source in &self.sources {
+            match self.query(source, target).await {
+                Ok(fee_rate) => {
+                    estimate = Some(fee_rate);
+                    break;
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        let fee_rate = match (estimate, self.fallback) {
+            (Some(fee_rate), _) | (None, Some(fee_rate)) => fee_rate,
+            (None, None) => {
+                return Err(anyhow::anyhow!(
+                    "No fee estimate available: {}",
+                    errors.join("; ")
+                ))
+            }
+        };
+        let fee_rate = fee_rate.max(self.min_relay_fee().await);
+
+        self.cache
+            .write()
+            .await
+            .insert(target, (fee_rate, Instant::now()));
+        Ok(fee_rate)
+    }
+
+    /// Fee rate for the given priority level
+    pub async fn estimate_priority(&self, priority: FeePriority) -> Result<FeeRate> {
+        self.estimate(priority.target()).await
+    }
+
+    /// Lowest fee rate the first reachable node will relay, or 1 sat/vB
+    pub async fn min_relay_fee(&self) -> FeeRate {
+        for source in &self.sources {
+            match source {
+                FeeSource::Rpc(client) => {
+                    let client = client.clone();
+                    let info = tokio::task::spawn_blocking(move || client.get_mempool_info()).await;
+                    if let Ok(Ok(info)) = info {
+                        return per_kvb(info.mempool_min_fee).max(FeeRate::BROADCAST_MIN);
+                    }
+                }
+                FeeSource::Electrum(client) => {
+                    if let Ok(fee_rate) = client.relay_fee().await {
+                        return fee_rate.max(FeeRate::BROADCAST_MIN);
+                    }
+                }
+                FeeSource::Esplora(_) => {}
+            }
+        }
+        FeeRate::BROADCAST_MIN
+    }
+
+    /// Drop cached estimates so the next call queries the sources
+    pub async fn clear_cache(&self) {
+        self.cache.write().await.clear();
+    }
+
+    async fn query(&self, source: &FeeSource, target: u16) -> Result<FeeRate> {
+        match source {
+            FeeSource::Rpc(client) => {
+                let client = client.clone();
+                // Short targets need the conservative estimate so the tx isn't stuck
+                let mode = if target <= 2 {
+                    EstimateMode::Conservative
+                } else {
+                    EstimateMode::Economical
+                };
+                let result = tokio::task::spawn_blocking(move || {
+                    client.estimate_smart_fee(target, Some(mode))
+                })
+                .await??;
+                result.fee_rate.map(per_kvb).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "estimatesmartfee: {}",
+                        result.errors.unwrap_or_default().join(", ")
+                    )
+                })
+            }
+            FeeSource::Esplora(url) => {
+                let url = format!("{}/fee-estimates", url.trim_end_matches('/'));
+                let estimates: HashMap<String, f64> = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                esplora_estimate(&estimates, target)
+                    .ok_or_else(|| anyhow::anyhow!("{url} returned no estimates"))
+            }
+            FeeSource::Electrum(client) => client.estimate_fee(target).await,
+        }
+    }
+}
+
+/// Convert a BTC/kvB rate as returned by bitcoind, rounding up so the rate is never underpaid
+fn per_kvb(amount: Amount) -> FeeRate {
+    FeeRate::from_sat_per_kwu(amount.to_sat().div_ceil(4))
+}
+
+/// Pick from Esplora's `{"<target>": <sat/vB>}` map the estimate for the largest target not
+/// above `target`, or the shortest target when all are longer
+pub(crate) fn esplora_estimate(estimates: &HashMap<String, f64>, target: u16) -> Option<FeeRate> {
+    let mut parsed: Vec<(u16, f64)> = estimates
+        .iter()
+        .filter_map(|(k, v)| Some((k.parse().ok()?, *v)))
+        .filter(|(_, v)| v.is_finite() && *v >= 0.0)
+        .collect();
+    parsed.sort_by_key(|(t, _)| *t);
+
+    let (_, sat_per_vb) = parsed
+        .iter()
+        .rev()
+        .find(|(t, _)| *t <= target)
+        .or_else(|| parsed.first())?;
+    // sat/vB to sat/kwu, rounded up so we never underpay
+    Some(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_esplora_target() {
+        let estimates: HashMap<String, f64> = [("1", 30.5), ("3", 20.0), ("6", 12.0), ("144", 1.2)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+
+        let rate = |target| esplora_estimate(&estimates, target).unwrap();
+        assert_eq!(rate(6).to_sat_per_vb_ceil(), 12);
+        assert_eq!(rate(10).to_sat_per_vb_ceil(), 12);
+        assert_eq!(rate(2).to_sat_per_vb_ceil(), 31);
+        assert_eq!(rate(1008), FeeRate::from_sat_per_kwu(300));
+        assert!(esplora_estimate(&HashMap::new(), 6).is_none());
+    }
+
+    #[test]
+    fn rounds_bitcoind_rates_up() {
+        // 0.00001001 BTC/kvB is 250.25 sat/kwu
+        assert_eq!(
+            per_kvb(Amount::from_sat(1_001)),
+            FeeRate::from_sat_per_kwu(251)
+        );
+        assert_eq!(
+            per_kvb(Amount::from_sat(1_000)),
+            FeeRate::from_sat_per_kwu(250)
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_and_caches() {
+        let unreachable = FeeSource::Esplora("http://127.0.0.1:9".to_string());
+        let estimator = FeeEstimator::new(vec![unreachable.clone()]);
+        assert!(estimator.estimate(6).await.is_err());
+
+        let estimator = FeeEstimator::new(vec![unreachable])
+            .with_fallback(FeeRate::from_sat_per_kwu(100))
+            .with_cache_ttl(Duration::from_secs(600));
+        // Clamped to the 1 sat/vB relay minimum
+        let rate = estimator
+            .estimate_priority(FeePriority::Normal)
+            .await
+            .unwrap();
+        assert_eq!(rate, FeeRate::BROADCAST_MIN);
+        assert!(estimator.cache.read().await.contains_key(&6));
+    }
+
+    /// Run against a regtest node with
+    /// `BITCOIND_RPC_URL=http://127.0.0.1:18443 BITCOIND_RPC_USER=.. BITCOIND_RPC_PASS=..`
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_estimatesmartfee() {
+        let url = std::env::var("BITCOIND_RPC_URL").unwrap();
+        let auth = bitcoincore_rpc::Auth::UserPass(
+            std::env::var("BITCOIND_RPC_USER").unwrap(),
+            std::env::var("BITCOIND_RPC_PASS").unwrap(),
+        );
+        let client = Arc::new(Client::new(&url, auth).unwrap());
+        let estimator = FeeEstimator::new(vec![FeeSource::Rpc(client)])
+            .with_fallback(FeeRate::from_sat_per_vb_unchecked(2));
+
+        // A fresh regtest chain has no fee data, so this exercises the fallback and clamp
+        let min_relay = estimator.min_relay_fee().await;
+        for priority in [
+            FeePriority::Economy,
+            FeePriority::Normal,
+            FeePriority::Priority,
+        ] {
+            assert!(estimator.estimate_priority(priority).await.unwrap() >= min_relay);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+// Labels module: BIP329 wallet labels for transactions, addresses, keys, inputs and outputs
+//
+// Labels are exported and imported as JSON lines, one record per line, which Sparrow and other
+// BIP329 wallets read and write. Records of types we don't know are skipped on import.
+use anyhow::Result;
+use bitcoin::bip32::Xpub;
+use bitcoin::{OutPoint, Txid};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What a label's reference points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    /// A transaction, by txid
+    Tx,
+    /// An address
+    Addr,
+    /// A public key, in hex
+    Pubkey,
+    /// An input, by the outpoint it spends
+    Input,
+    /// An output, by outpoint
+    Output,
+    /// An extended public key
+    Xpub,
+}
+
+impl LabelType {
+    const ALL: [LabelType; 6] = [
+        LabelType::Tx,
+        LabelType::Addr,
+        LabelType::Pubkey,
+        LabelType::Input,
+        LabelType::Output,
+        LabelType::Xpub,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LabelType::Tx => "tx",
+            LabelType::Addr => "addr",
+            LabelType::Pubkey => "pubkey",
+            LabelType::Input => "input",
+            LabelType::Output => "output",
+            LabelType::Xpub => "xpub",
+        }
+    }
+
+    /// The type of a reference stored before labels had one: a txid, an outpoint (taken to
+    /// be an output), an extended public key or else an address
+    pub(crate) fn guess(reference: &str) -> Self {
+        if Txid::from_str(reference).is_ok() {
+            LabelType::Tx
+        } else if OutPoint::from_str(reference).is_ok() {
+            LabelType::Output
+        } else if Xpub::from_str(reference).is_ok() {
+            LabelType::Xpub
+        } else {
+            LabelType::Addr
+        }
+    }
+}
+
+impl fmt::Display for LabelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LabelType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown label type: {s}"))
+    }
+}
+
+/// A BIP329 label record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+    #[serde(rename = "type")]
+    pub kind: LabelType,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Key origin of the wallet the reference belongs to, e.g. `wpkh([d34db33f/84'/0'/0'])`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// For outputs: `false` when the output is frozen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+impl Label {
+    pub fn new(kind: LabelType, reference: impl Into<String>, label: impl Into<String>) -> Self {
+        Self {
+            kind,
+            reference: reference.into(),
+            label: Some(label.into()).filter(|l: &String| !l.is_empty()),
+            origin: None,
+            spendable: None,
+        }
+    }
+
+    /// Check the reference has the form its type calls for
+    pub fn validate(&self) -> Result<()> {
+        let valid = match self.kind {
+            LabelType::Tx => Txid::from_str(&self.reference).is_ok(),
+            LabelType::Input | LabelType::Output => OutPoint::from_str(&self.reference).is_ok(),
+            LabelType::Pubkey => bitcoin::PublicKey::from_str(&self.reference).is_ok(),
+            LabelType::Xpub => Xpub::from_str(&self.reference).is_ok(),
+            LabelType::Addr => !self.reference.is_empty(),
+        };
+        if !valid {
+            return Err(anyhow::anyhow!(
+                "Invalid {} reference: {}",
+                self.kind,
+                self.reference
+            ));
+        }
+        if self.spendable.is_some() && self.kind != LabelType::Output {
+            return Err(anyhow::anyhow!("Only outputs can be marked spendable"));
+        }
+        Ok(())
+    }
+
+    /// Nothing worth keeping: no label, origin or spendable flag
+    pub fn is_empty(&self) -> bool {
+        self.label.is_none() && self.origin.is_none() && self.spendable.is_none()
+    }
+}
+
+/// A wallet's labels, one per type and reference
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    labels: BTreeMap<(LabelType, String), Label>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, kind: LabelType, reference: &str) -> Option<&Label> {
+        self.labels.get(&(kind, reference.to_string()))
+    }
+
+    /// The label text for a reference, if it has one
+    pub fn label(&self, kind: LabelType, reference: &str) -> Option<&str> {
+        self.get(kind, reference)?.label.as_deref()
+    }
+
+    /// Add or replace a record; one with nothing in it removes the reference
+    pub fn insert(&mut self, label: Label) {
+        let key = (label.kind, label.reference.clone());
+        if label.is_empty() {
+            self.labels.remove(&key);
+        } else {
+            self.labels.insert(key, label);
+        }
+    }
+
+    pub fn remove(&mut self, kind: LabelType, reference: &str) -> Option<Label> {
+        self.labels.remove(&(kind, reference.to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Label> {
+        self.labels.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// BIP329 export, one JSON record per line
+    pub fn to_jsonl(&self) -> Result<String> {
+        export_jsonl(self.iter())
+    }
+}
+
+impl FromIterator<Label> for Labels {
+    fn from_iter<I: IntoIterator<Item = Label>>(iter: I) -> Self {
+        let mut labels = Labels::new();
+        for label in iter {
+            labels.insert(label);
+        }
+        labels
+    }
+}
+
+/// Encode records as BIP329 JSON lines
+pub fn export_jsonl<'a>(labels: impl IntoIterator<Item = &'a Label>) -> Result<String> {
+    let mut out = String::new();
+    for label in labels {
+        out.push_str(&serde_json::to_string(label)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Parse BIP329 JSON lines. Blank lines and records of unknown types are skipped; malformed
+/// lines and invalid references are errors naming the line.
+pub fn import_jsonl(jsonl: &str) -> Result<Vec<Label>> {
+    let mut labels = vec![];
+    for (number, line) in jsonl.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record: serde_json::Value =
+            serde_json::from_str(line).map_err(|e| anyhow::anyhow!("Line {}: {e}", number + 1))?;
+        let known = record["type"]
+            .as_str()
+            .is_some_and(|kind| LabelType::from_str(kind).is_ok());
+        if !known {
+            continue;
+        }
+        let mut label: Label = serde_json::from_value(record)
+            .map_err(|e| anyhow::anyhow!("Line {}: {e}", number + 1))?;
+        label.label = label.label.filter(|l| !l.is_empty());
+        label
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Line {}: {e}", number + 1))?;
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utxo_manager::{CoinControl, SelectionStrategy, TrackedUtxo};
+    use crate::{AddressType, BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Address, Amount, FeeRate, Network, TxOut};
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // Records from the BIP329 examples
+    const EXAMPLE: &str = r#"{ "type": "tx", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd", "label": "Transaction", "origin": "wpkh([d34db33f/84'/0'/0'])" }
+{ "type": "addr", "ref": "bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c", "label": "Address" }
+{ "type": "pubkey", "ref": "0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448", "label": "Public Key" }
+{ "type": "input", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0", "label": "Input" }
+{ "type": "output", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1", "label": "Output", "spendable": false }
+{ "type": "xpub", "ref": "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8", "label": "Extended Public Key" }
+"#;
+
+    #[test]
+    fn round_trips_bip329_records() {
+        let labels: Labels = import_jsonl(EXAMPLE).unwrap().into_iter().collect();
+        assert_eq!(labels.len(), 6);
+        let output = labels
+            .get(
+                LabelType::Output,
+                "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1",
+            )
+            .unwrap();
+        assert_eq!(output.spendable, Some(false));
+        assert_eq!(
+            labels
+                .get(LabelType::Tx, &output.reference[..64])
+                .unwrap()
+                .origin
+                .as_deref(),
+            Some("wpkh([d34db33f/84'/0'/0'])")
+        );
+
+        let exported = labels.to_jsonl().unwrap();
+        assert_eq!(exported.lines().count(), 6);
+        assert!(exported.contains(r#""type":"input""#));
+        let again: Labels = import_jsonl(&exported).unwrap().into_iter().collect();
+        assert_eq!(
+            again.iter().collect::<Vec<_>>(),
+            labels.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_records_and_skips_unknown_types() {
+        let unknown = r#"{"type":"psbt","ref":"cHNidP8","label":"Later"}"#;
+        assert!(import_jsonl(unknown).unwrap().is_empty());
+
+        let error = import_jsonl("\n{\"type\":\"tx\",\"ref\":\"abc\"}").unwrap_err();
+        assert!(error.to_string().starts_with("Line 2"));
+        assert!(import_jsonl(r#"{"type":"addr","ref":"x","spendable":true}"#).is_err());
+        assert!(import_jsonl("not json").is_err());
+
+        assert_eq!(
+            LabelType::guess("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1"),
+            LabelType::Output
+        );
+        assert_eq!(
+            LabelType::guess("bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c"),
+            LabelType::Addr
+        );
+    }
+
+    #[tokio::test]
+    async fn coin_control_and_freezes_follow_imported_labels() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        manager
+            .create_wallet("alice", Some(MNEMONIC.to_string()))
+            .await
+            .unwrap();
+        let address = manager
+            .get_receive_address("alice", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let script = address
+            .parse::<Address<_>>()
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+        let utxos: Vec<TrackedUtxo> = (1..=3u8)
+            .map(|i| TrackedUtxo {
+                outpoint: OutPoint::new(Txid::from_byte_array([i; 32]), 0),
+                txout: TxOut {
+                    value: Amount::from_sat(i as u64 * 20_000),
+                    script_pubkey: script.clone(),
+                },
+                confirmations: 6,
+            })
+            .collect();
+        let [small, medium, large] = [0, 1, 2].map(|i| utxos[i].outpoint);
+        manager
+            .utxo_manager()
+            .write()
+            .await
+            .update_utxos("alice", utxos);
+
+        // A Sparrow export freezing the largest coin
+        let sparrow = format!(
+            "{{\"type\":\"addr\",\"ref\":\"{address}\",\"label\":\"Deposits\"}}\n\
+             {{\"type\":\"output\",\"ref\":\"{large}\",\"label\":\"Cold\",\"spendable\":false}}\n"
+        );
+        assert_eq!(manager.import_labels("alice", &sparrow).await.unwrap(), 2);
+        assert_eq!(
+            manager
+                .label("alice", LabelType::Addr, &address)
+                .await
+                .as_deref(),
+            Some("Deposits")
+        );
+        assert!(manager.utxo_manager().read().await.is_frozen(&large));
+
+        let recipient = Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .unwrap()
+            .assume_checked();
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+        let inputs = |psbt: &bitcoin::psbt::Psbt| -> Vec<OutPoint> {
+            psbt.unsigned_tx
+                .input
+                .iter()
+                .map(|i| i.previous_output)
+                .collect()
+        };
+
+        // Naming the frozen coin spends it; excluding the medium one forces the small one in
+        let control = CoinControl::new().include(large).exclude(medium);
+        let psbt = manager
+            .create_psbt_with_coin_control(
+                "alice",
+                &[(recipient.clone(), 70_000)],
+                fee_rate,
+                SelectionStrategy::LargestFirst,
+                &control,
+            )
+            .await
+            .unwrap();
+        assert_eq!(inputs(&psbt), vec![large, small]);
+        manager
+            .utxo_manager()
+            .write()
+            .await
+            .release(&[large, small]);
+
+        // Spend all from these: no change and no other coins
+        let psbt = manager
+            .create_sweep_psbt("alice", &[small, medium], &recipient, fee_rate)
+            .await
+            .unwrap();
+        assert_eq!(inputs(&psbt), vec![small, medium]);
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert!(psbt.unsigned_tx.output[0].value.to_sat() < 60_000);
+
+        manager
+            .set_label("alice", LabelType::Tx, &small.txid.to_string(), "Salary")
+            .await
+            .unwrap();
+        let exported: Labels = import_jsonl(&manager.export_labels("alice").await.unwrap())
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(exported.len(), 3);
+        assert_eq!(
+            exported
+                .get(LabelType::Output, &large.to_string())
+                .unwrap()
+                .spendable,
+            Some(false)
+        );
+
+        let thaw = format!("{{\"type\":\"output\",\"ref\":\"{large}\",\"spendable\":true}}");
+        manager.import_labels("alice", &thaw).await.unwrap();
+        assert!(!manager.utxo_manager().read().await.is_frozen(&large));
+        // The label text went with the record it was replaced by
+        assert!(manager
+            .labels("alice")
+            .await
+            .label(LabelType::Output, &large.to_string())
+            .is_none());
+    }
+}
@@ -3,24 +3,60 @@ pub use bitcoin::Network;
 
 use anyhow::Result;
 use bip39::Mnemonic;
-use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{All, Secp256k1, XOnlyPublicKey};
+use bitcoin::taproot::TaprootSpendInfo;
 use bitcoin::{
-    bip32::{DerivationPath, Xpriv, Xpub},
-    Address,
+    bip32::{DerivationPath, Fingerprint, KeySource, Xpriv, Xpub},
+    Address, Block, BlockHash, FeeRate, OutPoint, Script, ScriptBuf, Transaction, Txid,
 };
-use bitcoincore_rpc::{Auth, Client};
+use bitcoincore_rpc::RpcApi;
+use discovery::DEFAULT_GAP_LIMIT;
+use electrum::{CertificatePins, ElectrumClient, ElectrumNotification, HistoryEntry};
+use fee_estimator::{FeeEstimator, FeeSource, DEFAULT_FALLBACK_FEE_RATE};
+use labels::{Label, LabelType, Labels};
+use message::SignatureFormat;
+use ordinals::{InscriptionId, OrdClient, ProtectedSats};
+use rpc_pool::{EndpointHealth, RpcPool};
+use security::key_manager;
+use security::{BitcoinSecurityVault, SecurityConfig, Signer};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use storage::{StorageConfig, StorageManager};
 use tokio::sync::RwLock;
+use transaction_builder::fee_bump::{self, FeeBump, ReplacementTracker, SentTransaction};
+use transaction_builder::script_builder::Descriptor;
+use transaction_builder::{psbt_handler, TransactionBuilder};
+use utxo_manager::{
+    coin_selection, CoinControl, CoinSelectionParams, FreezeReason, SelectionResult,
+    SelectionStrategy, TrackedUtxo, UtxoManager,
+};
+use watch_only::WatchOnlySource;
+
+pub use discovery::{Keychain, KeychainKind};
+pub use fee_estimator::FeePriority;
 
+pub mod discovery;
+pub mod electrum;
+pub mod fee_estimator;
+pub mod labels;
 pub mod lightning; // Always expose lightning module
+pub mod message;
 pub mod multi_wallet;
+pub mod ordinals;
+pub mod rpc_pool;
 pub mod security;
 pub mod storage;
 pub mod swaps;
 pub mod transaction_builder;
 pub mod utxo_manager;
+pub mod watch_only;
+
+/// Depth of BIP44 account keys, `m/purpose'/coin'/account'`
+const ACCOUNT_DEPTH: usize = 3;
 
 /// Multi-user Bitcoin wallet manager
 pub struct BitcoinWalletManager {
@@ -28,9 +64,26 @@ pub struct BitcoinWalletManager {
     wallets: Arc<RwLock<HashMap<String, UserBitcoinWallet>>>,
     /// Shared secp256k1 context
     secp: Arc<Secp256k1<All>>,
-    /// Bitcoin RPC clients
-    #[allow(dead_code)]
-    rpc_clients: Vec<Arc<Client>>,
+    /// Bitcoin Core endpoints with failover
+    rpc: Arc<RpcPool>,
+    /// Electrum server used for chain data instead of the RPC endpoints when set
+    electrum: Option<Arc<ElectrumClient>>,
+    /// Local `ord` index consulted for inscriptions and rare sats in new outputs
+    ord: Option<Arc<OrdClient>>,
+    /// Tracked UTXOs per user
+    utxo_manager: Arc<RwLock<UtxoManager>>,
+    /// Fee rate estimates from the RPC endpoints
+    fee_estimator: Arc<FeeEstimator>,
+    /// Broadcast transactions and their fee-bump replacements per user
+    tx_history: Arc<RwLock<HashMap<String, ReplacementTracker>>>,
+    /// Labels of addresses, transactions and outputs per user
+    labels: Arc<RwLock<HashMap<String, Labels>>>,
+    /// Durable store the state above is written through to
+    storage: Option<Arc<StorageManager>>,
+    /// Master keys of every wallet and the signers for them
+    vault: Arc<BitcoinSecurityVault>,
+    /// Unused addresses scanned past the last used one during discovery
+    gap_limit: u32,
     /// Network (mainnet, testnet, regtest)
     network: Network,
 }
@@ -41,15 +94,31 @@ pub struct UserBitcoinWallet {
     /// User identifier
     #[allow(dead_code)]
     user_id: String,
-    /// Extended private key
-    xprv: Xpriv,
-    /// Extended public key
-    #[allow(dead_code)]
-    xpub: Xpub,
-    /// Derived addresses
-    addresses: HashMap<u32, Address>,
-    /// Current address index
+    /// Master fingerprint and path of the wallet's key, which is held by the vault
+    key_origin: KeySource,
+    /// Extended public key of every account addresses are derived from, by account path
+    account_xpubs: HashMap<DerivationPath, Xpub>,
+    /// Every address handed out or discovered
+    addresses: HashMap<ScriptBuf, Address>,
+    /// Next descriptor index, for descriptor wallets
     current_index: u32,
+    /// Next index on every chain that has been used, for HD wallets
+    next_indexes: HashMap<Keychain, u32>,
+    /// Derivation path of every script handed out, for PSBT key origins
+    script_paths: HashMap<ScriptBuf, DerivationPath>,
+    /// Public output descriptor the wallet derives its scripts from, if created from one
+    descriptor: Option<Descriptor>,
+    /// Descriptor index of every script handed out
+    descriptor_indexes: HashMap<ScriptBuf, u32>,
+    /// BIP86 derivation path of every taproot key handed out, internal or leaf
+    taproot_keys: HashMap<XOnlyPublicKey, DerivationPath>,
+    /// Script trees of taproot outputs with script-path leaves
+    taproot_trees: HashMap<ScriptBuf, TaprootSpendInfo>,
+    /// Leaves each script tree was built from, in order, so it can be rebuilt
+    taproot_leaves: HashMap<ScriptBuf, Vec<ScriptBuf>>,
+    /// No key is held: scripts come from the account xpub or public descriptor it was
+    /// created from, and spending goes through PSBTs signed elsewhere
+    watch_only: bool,
 }
 
 impl BitcoinWalletManager {
@@ -58,19 +127,92 @@ impl BitcoinWalletManager {
         let secp = Arc::new(Secp256k1::new());
 
         // Create RPC client pool
-        let mut rpc_clients = Vec::new();
-        for endpoint in &config.rpc_endpoints {
-            let client = Client::new(
-                &endpoint.url,
-                Auth::UserPass(endpoint.user.clone(), endpoint.pass.clone()),
-            )?;
-            rpc_clients.push(Arc::new(client));
-        }
+        let rpc = Arc::new(RpcPool::new(&config.rpc_endpoints)?);
+
+        // An unreachable Electrum server leaves the RPC endpoints in charge rather than
+        // keeping the wallet from starting
+        let electrum = match &config.electrum_url {
+            Some(url) => {
+                let pins = CertificatePins::default_location();
+                match ElectrumClient::connect_pinned(url, config.network, &pins).await {
+                    Ok(client) => Some(Arc::new(client)),
+                    Err(e) => {
+                        log::error!("Electrum server {url} unavailable, using RPC: {e}");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut fee_sources: Vec<FeeSource> =
+            rpc.clients().into_iter().map(FeeSource::Rpc).collect();
+        fee_sources.extend(electrum.clone().map(FeeSource::Electrum));
+        let fee_estimator = FeeEstimator::new(fee_sources).with_fallback(DEFAULT_FALLBACK_FEE_RATE);
+
+        let vault = Arc::new(BitcoinSecurityVault::new(config.security.clone())?);
+
+        // Reload every user's state from disk
+        let mut wallets = HashMap::new();
+        let mut utxo_manager = UtxoManager::new();
+        let mut labels = HashMap::new();
+        let mut tx_history = HashMap::new();
+        let storage = match &config.storage {
+            Some(storage_config) => {
+                let storage = StorageManager::open(storage_config)?;
+                for (mut wallet, key) in storage.load_wallets(config.network, &secp)? {
+                    let Some(key) = key else {
+                        wallets.insert(wallet.user_id.clone(), wallet);
+                        continue;
+                    };
+                    vault.restore_key(&wallet.user_id, key, wallet.key_origin.clone());
+                    // Accounts are derived again when the key is available and their xpubs
+                    // weren't stored
+                    if !vault.is_locked(&wallet.user_id) {
+                        for path in wallet.used_accounts() {
+                            if !wallet.account_xpubs.contains_key(&path) {
+                                let xpub = vault.xpub(&wallet.user_id, &path)?;
+                                wallet.account_xpubs.insert(path, xpub);
+                            }
+                        }
+                    }
+                    wallets.insert(wallet.user_id.clone(), wallet);
+                }
+                for (user_id, utxos) in storage.load_utxos()? {
+                    utxo_manager.update_utxos(&user_id, utxos);
+                }
+                for (outpoint, reason) in storage.load_frozen()?.into_values().flatten() {
+                    utxo_manager.freeze(outpoint, reason);
+                }
+                labels = storage.load_labels()?;
+                // Outputs imported as unspendable before they were seen
+                for label in labels.values().flat_map(|l| l.iter()) {
+                    if label.kind == LabelType::Output && label.spendable == Some(false) {
+                        let outpoint = OutPoint::from_str(&label.reference)?;
+                        if !utxo_manager.is_frozen(&outpoint) {
+                            utxo_manager.freeze(outpoint, FreezeReason::Manual);
+                        }
+                    }
+                }
+                tx_history = storage.load_history()?;
+                Some(Arc::new(storage))
+            }
+            None => None,
+        };
 
         Ok(Self {
-            wallets: Arc::new(RwLock::new(HashMap::new())),
+            wallets: Arc::new(RwLock::new(wallets)),
             secp,
-            rpc_clients,
+            rpc,
+            electrum,
+            ord: None,
+            fee_estimator: Arc::new(fee_estimator),
+            utxo_manager: Arc::new(RwLock::new(utxo_manager)),
+            tx_history: Arc::new(RwLock::new(tx_history)),
+            labels: Arc::new(RwLock::new(labels)),
+            storage,
+            vault,
+            gap_limit: DEFAULT_GAP_LIMIT,
             network: config.network,
         })
     }
@@ -97,19 +239,36 @@ impl BitcoinWalletManager {
         let seed = mnemonic.to_seed("");
         let xprv = Xpriv::new_master(self.network, &seed)?;
         let xpub = Xpub::from_priv(&self.secp, &xprv);
+        self.vault.add_key(user_id, &xprv, None);
 
         let mut wallet = UserBitcoinWallet {
             user_id: user_id.to_string(),
-            xprv,
-            xpub,
+            key_origin: key_manager::master_origin(&xprv, &self.secp),
+            account_xpubs: HashMap::new(),
             addresses: HashMap::new(),
             current_index: 0,
+            next_indexes: HashMap::new(),
+            script_paths: HashMap::new(),
+            descriptor: None,
+            descriptor_indexes: HashMap::new(),
+            taproot_keys: HashMap::new(),
+            taproot_trees: HashMap::new(),
+            taproot_leaves: HashMap::new(),
+            watch_only: false,
         };
 
+        // Cache the first account's xpubs so addresses can be derived while locked
+        self.ensure_accounts(&mut wallet, &Keychain::account(0))?;
+
         // Generate first address
-        let first_address = wallet.derive_address(0, &self.secp, self.network)?;
+        let first_address = wallet.next_address(
+            Keychain::receive(AddressType::NativeSegwit),
+            &self.secp,
+            self.network,
+        )?;
 
         // Store wallet
+        self.persist_wallet(&wallet)?;
         let mut wallets = self.wallets.write().await;
         wallets.insert(user_id.to_string(), wallet);
 
@@ -119,22 +278,146 @@ impl BitcoinWalletManager {
             xpub: xpub.to_string(),
             first_address: first_address.to_string(),
             network: self.network,
+            descriptor: None,
+        })
+    }
+
+    /// Create a wallet for a user from an output descriptor such as
+    /// `wpkh([d34db33f/84'/0'/0']xprv.../0/*)` or a compiled `wsh()` policy.
+    /// The descriptor must contain an extended private key for the wallet to sign with.
+    pub async fn create_wallet_from_descriptor(
+        &self,
+        user_id: &str,
+        descriptor: &str,
+    ) -> Result<WalletInfo> {
+        let descriptor = Descriptor::from_str(descriptor)?;
+        let (xprv, key_origin) = descriptor
+            .secret_key()
+            .ok_or_else(|| anyhow::anyhow!("Descriptor has no private key to sign with"))?;
+        // xprv/tprv only distinguish mainnet from the test networks
+        if (xprv.network == Network::Bitcoin) != (self.network == Network::Bitcoin) {
+            return Err(anyhow::anyhow!(
+                "Descriptor key is for {}, wallet manager is on {}",
+                xprv.network,
+                self.network
+            ));
+        }
+        let xpub = Xpub::from_priv(&self.secp, &xprv);
+        let key_origin =
+            key_origin.unwrap_or_else(|| key_manager::master_origin(&xprv, &self.secp));
+        self.vault.add_key(user_id, &xprv, Some(key_origin.clone()));
+        let public_descriptor = descriptor.to_public(&self.secp);
+
+        let mut wallet = UserBitcoinWallet {
+            user_id: user_id.to_string(),
+            key_origin,
+            account_xpubs: HashMap::new(),
+            addresses: HashMap::new(),
+            current_index: 0,
+            next_indexes: HashMap::new(),
+            script_paths: HashMap::new(),
+            descriptor: Some(public_descriptor.clone()),
+            descriptor_indexes: HashMap::new(),
+            taproot_keys: HashMap::new(),
+            taproot_trees: HashMap::new(),
+            taproot_leaves: HashMap::new(),
+            watch_only: false,
+        };
+
+        let first_address = wallet.derive_descriptor_address(0, &self.secp, self.network)?;
+        wallet.current_index = 1;
+
+        self.persist_wallet(&wallet)?;
+        let mut wallets = self.wallets.write().await;
+        wallets.insert(user_id.to_string(), wallet);
+
+        Ok(WalletInfo {
+            user_id: user_id.to_string(),
+            mnemonic: String::new(),
+            xpub: xpub.to_string(),
+            first_address: first_address.to_string(),
+            network: self.network,
+            descriptor: Some(public_descriptor.to_string()),
+        })
+    }
+
+    /// Create a watch-only wallet from an account's extended public key, such as `zpub...`
+    /// or `[d34db33f/84'/0'/0']xpub...`, or from a public descriptor. Balances, history and
+    /// unsigned PSBTs work as for any wallet; anything that needs the private key fails.
+    /// Give the key origin so hardware signers recognise the keys in exported PSBTs.
+    pub async fn create_watch_only_wallet(
+        &self,
+        user_id: &str,
+        source: &str,
+    ) -> Result<WalletInfo> {
+        let source = WatchOnlySource::parse(source, self.network)?;
+        let mut wallet = UserBitcoinWallet {
+            user_id: user_id.to_string(),
+            key_origin: source.key_origin(),
+            account_xpubs: HashMap::new(),
+            addresses: HashMap::new(),
+            current_index: 0,
+            next_indexes: HashMap::new(),
+            script_paths: HashMap::new(),
+            descriptor: None,
+            descriptor_indexes: HashMap::new(),
+            taproot_keys: HashMap::new(),
+            taproot_trees: HashMap::new(),
+            taproot_leaves: HashMap::new(),
+            watch_only: true,
+        };
+
+        let first_address = match &source {
+            WatchOnlySource::Account {
+                xpub,
+                address_type,
+                account,
+                ..
+            } => {
+                let keychain = Keychain::new(*address_type, *account, KeychainKind::External);
+                wallet.account_xpubs.insert(keychain.account_path()?, *xpub);
+                wallet.next_address(keychain, &self.secp, self.network)?
+            }
+            WatchOnlySource::Descriptor(descriptor) => {
+                wallet.descriptor = Some(descriptor.clone());
+                wallet.current_index = 1;
+                wallet.derive_descriptor_address(0, &self.secp, self.network)?
+            }
+        };
+
+        self.persist_wallet(&wallet)?;
+        let mut wallets = self.wallets.write().await;
+        wallets.insert(user_id.to_string(), wallet);
+
+        Ok(WalletInfo {
+            user_id: user_id.to_string(),
+            mnemonic: String::new(),
+            xpub: source.xpub().map(|x| x.to_string()).unwrap_or_default(),
+            first_address: first_address.to_string(),
+            network: self.network,
+            descriptor: match &source {
+                WatchOnlySource::Descriptor(descriptor) => Some(descriptor.to_string()),
+                WatchOnlySource::Account { .. } => None,
+            },
         })
     }
 
-    /// Get balance for a user
+    /// Get balance for a user, refreshed from the Electrum server or RPC endpoints when
+    /// either is configured
     pub async fn get_balance(&self, user_id: &str) -> Result<Balance> {
-        let wallets = self.wallets.read().await;
-        let _wallet = wallets
-            .get(user_id)
-            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        if self.rpc.is_empty() && self.electrum.is_none() {
+            if !self.wallets.read().await.contains_key(user_id) {
+                return Err(anyhow::anyhow!("Wallet not found"));
+            }
+        } else {
+            self.sync_utxos(user_id).await?;
+        }
 
-        // In production, this would query the blockchain
-        // For now, return mock data
+        let (confirmed, unconfirmed) = self.utxo_manager.read().await.balance(user_id);
         Ok(Balance {
-            confirmed: 0,
-            unconfirmed: 0,
-            total: 0,
+            confirmed,
+            unconfirmed,
+            total: confirmed + unconfirmed,
         })
     }
 
@@ -144,122 +427,2083 @@ impl BitcoinWalletManager {
         user_id: &str,
         address_type: AddressType,
     ) -> Result<String> {
+        self.get_address(user_id, Keychain::receive(address_type))
+            .await
+    }
+
+    /// Get a fresh address for change, from the internal chain
+    pub async fn get_change_address(
+        &self,
+        user_id: &str,
+        address_type: AddressType,
+    ) -> Result<String> {
+        self.get_address(user_id, Keychain::change(address_type))
+            .await
+    }
+
+    /// Get the next unused address on any account's receive or change chain.
+    /// Descriptor wallets have a single chain and always derive from their descriptor.
+    pub async fn get_address(&self, user_id: &str, keychain: Keychain) -> Result<String> {
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+
+        let address = if wallet.descriptor.is_some() {
+            let index = wallet.current_index;
+            wallet.current_index += 1;
+            wallet.derive_descriptor_address(index, &self.secp, self.network)?
+        } else {
+            self.ensure_accounts(wallet, &[keychain])?;
+            wallet.next_address(keychain, &self.secp, self.network)?
+        };
+
+        self.persist_wallet(wallet)?;
+        Ok(address.to_string())
+    }
+
+    /// Get a fresh public key on a chain with its full derivation path, for scripts such as
+    /// HTLCs that are signed through the vault's signer
+    pub async fn get_public_key(
+        &self,
+        user_id: &str,
+        keychain: Keychain,
+    ) -> Result<(bitcoin::PublicKey, DerivationPath)> {
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        if wallet.descriptor.is_some() || keychain.address_type == AddressType::Taproot {
+            return Err(anyhow::anyhow!(
+                "Only non-taproot chains of HD wallets hand out keys"
+            ));
+        }
+
+        self.ensure_accounts(wallet, &[keychain])?;
+        // Revealed as an address too, so the key is provisioned on an HSM like any other
+        let address = wallet.next_address(keychain, &self.secp, self.network)?;
+        let path = wallet
+            .derivation_path(&address.script_pubkey())
+            .expect("revealed addresses have a path");
+        let pubkey = wallet.derive_public_key(&path, &self.secp)?;
+        self.persist_wallet(wallet)?;
+        Ok((pubkey, path))
+    }
+
+    /// Get a taproot address whose BIP86 internal key can spend by key path and whose
+    /// tapscript `leaves` can spend by script path. Leaves are placed in a balanced tree.
+    pub async fn get_taproot_script_address(
+        &self,
+        user_id: &str,
+        leaves: &[ScriptBuf],
+    ) -> Result<String> {
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        if wallet.descriptor.is_some() {
+            return Err(anyhow::anyhow!(
+                "Descriptor wallets only derive their descriptor's scripts"
+            ));
+        }
+
+        self.ensure_accounts(wallet, &[Keychain::receive(AddressType::Taproot)])?;
+        let index = wallet.take_index(Keychain::receive(AddressType::Taproot));
+        let address =
+            wallet.derive_taproot_script_address(index, leaves, &self.secp, self.network)?;
+        self.persist_wallet(wallet)?;
+        Ok(address.to_string())
+    }
+
+    /// Get a fresh BIP86 x-only key to use inside tapscript leaves, e.g. `<key> OP_CHECKSIG`.
+    /// The wallet signs script-path spends for leaves containing keys handed out here.
+    pub async fn get_taproot_key(&self, user_id: &str) -> Result<XOnlyPublicKey> {
         let mut wallets = self.wallets.write().await;
         let wallet = wallets
             .get_mut(user_id)
             .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
 
-        let index = wallet.current_index;
-        wallet.current_index += 1;
+        self.ensure_accounts(wallet, &[Keychain::receive(AddressType::Taproot)])?;
+        let index = wallet.take_index(Keychain::receive(AddressType::Taproot));
+        let key = wallet.derive_taproot_key(index, &self.secp)?;
+        self.persist_wallet(wallet)?;
+        Ok(key)
+    }
+
+    /// Scan this many unused addresses past the last used one on every chain during discovery
+    pub fn with_gap_limit(mut self, gap_limit: u32) -> Self {
+        self.gap_limit = gap_limit;
+        self
+    }
+
+    /// Find the addresses a restored wallet has used on the receive and change chains of
+    /// every address type and account, via a rescan of the chain on the RPC node. Each
+    /// chain's next address moves past its last used one. Returns the next index of every
+    /// chain found in use.
+    pub async fn discover_addresses(&self, user_id: &str) -> Result<HashMap<Keychain, u32>> {
+        let wallet = self
+            .wallets
+            .read()
+            .await
+            .get(user_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        let secp = self.secp.clone();
+        let network = self.network;
+        let gap_limit = self.gap_limit;
+        let is_descriptor = wallet.descriptor.is_some();
+        let vault = self.vault.clone();
+        let scan = move |find_used: &mut dyn FnMut(&[Address]) -> Result<HashSet<Address>>| {
+            match &wallet.descriptor {
+                // Descriptor wallets have their descriptor as the only chain
+                Some(descriptor) => discovery::scan_chains(
+                    &[Keychain::receive(AddressType::NativeSegwit)],
+                    gap_limit,
+                    |_, index| descriptor.address(index, network, &secp),
+                    find_used,
+                ),
+                // Watch-only wallets have just the account they were created from
+                None if wallet.watch_only => {
+                    let (account, xpub) = wallet
+                        .watched_account()
+                        .ok_or_else(|| anyhow::anyhow!("Watch-only wallet has no account"))?;
+                    discovery::scan_chains(
+                        &[
+                            account,
+                            Keychain {
+                                kind: KeychainKind::Internal,
+                                ..account
+                            },
+                        ],
+                        gap_limit,
+                        |keychain, index| keychain_address(&xpub, keychain, index, &secp, network),
+                        find_used,
+                    )
+                }
+                // Accounts past the cached ones need the key unlocked
+                None => discovery::scan_accounts(
+                    gap_limit,
+                    |keychain, index| {
+                        let account = match wallet.account_xpubs.get(&keychain.account_path()?) {
+                            Some(xpub) => *xpub,
+                            None => vault.xpub(&wallet.user_id, &keychain.account_path()?)?,
+                        };
+                        keychain_address(&account, keychain, index, &secp, network)
+                    },
+                    find_used,
+                ),
+            }
+        };
 
-        let address = match address_type {
-            AddressType::Legacy => wallet.derive_address_p2pkh(index, &self.secp, self.network)?,
-            AddressType::SegwitP2SH => {
-                wallet.derive_address_p2sh_wpkh(index, &self.secp, self.network)?
+        let discovered = match self.electrum.clone() {
+            Some(electrum) => {
+                // The scan is synchronous, so run it off the runtime and wait on each lookup
+                let runtime = tokio::runtime::Handle::current();
+                tokio::task::spawn_blocking(move || {
+                    scan(&mut |addresses: &[Address]| {
+                        runtime.block_on(electrum.used_addresses(addresses))
+                    })
+                })
+                .await??
+            }
+            None => {
+                let wallet_name = format!("walletd-{user_id}");
+                self.rpc
+                    .call(move |node| {
+                        let wallet_client = node.wallet_client(&wallet_name)?;
+                        scan(&mut |addresses: &[Address]| {
+                            UtxoManager::used_addresses(
+                                &node.client,
+                                &wallet_client,
+                                &wallet_name,
+                                addresses,
+                            )
+                        })
+                    })
+                    .await?
             }
-            AddressType::NativeSegwit => wallet.derive_address(index, &self.secp, self.network)?,
         };
 
-        wallet.addresses.insert(index, address.clone());
-        Ok(address.to_string())
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        if !is_descriptor {
+            self.ensure_accounts(wallet, discovered.keys())?;
+        }
+        for (keychain, next) in &discovered {
+            if is_descriptor {
+                for index in 0..*next {
+                    wallet.derive_descriptor_address(index, &self.secp, self.network)?;
+                }
+                wallet.current_index = wallet.current_index.max(*next);
+            } else {
+                for index in 0..*next {
+                    wallet.reveal(*keychain, index, &self.secp, self.network)?;
+                }
+                let current = wallet.next_indexes.entry(*keychain).or_default();
+                *current = (*current).max(*next);
+            }
+        }
+        self.persist_wallet(wallet)?;
+        Ok(discovered)
     }
-}
 
-impl UserBitcoinWallet {
-    fn derive_address(
-        &mut self,
-        index: u32,
-        secp: &Secp256k1<All>,
-        network: Network,
-    ) -> Result<Address> {
-        let path = DerivationPath::from(vec![
-            bitcoin::bip32::ChildNumber::from_hardened_idx(84)?, // BIP84
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,  // Bitcoin
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,  // Account
-            bitcoin::bip32::ChildNumber::from_normal_idx(0)?,    // External
-            bitcoin::bip32::ChildNumber::from_normal_idx(index)?,
-        ]);
+    /// Rescan a wallet restored from an existing mnemonic or descriptor: discover its used
+    /// addresses, then sync their UTXOs
+    pub async fn rescan_wallet(&self, user_id: &str) -> Result<Balance> {
+        self.discover_addresses(user_id).await?;
+        self.get_balance(user_id).await
+    }
 
-        let child_xprv = self.xprv.derive_priv(secp, &path)?;
-        let child_xpub = Xpub::from_priv(secp, &child_xprv);
+    /// Sync and broadcast through this Electrum server, e.g. one connected with
+    /// `ElectrumClient::connect_pinned` to a pin file of your choice. Fee estimates keep their
+    /// configured sources.
+    pub fn with_electrum(mut self, electrum: Arc<ElectrumClient>) -> Self {
+        self.electrum = Some(electrum);
+        self
+    }
 
-        Ok(Address::p2wpkh(&child_xpub.to_pub(), network)?)
+    pub fn electrum(&self) -> Option<Arc<ElectrumClient>> {
+        self.electrum.clone()
     }
 
-    fn derive_address_p2pkh(
-        &mut self,
-        index: u32,
-        secp: &Secp256k1<All>,
-        network: Network,
-    ) -> Result<Address> {
-        let path = DerivationPath::from(vec![
-            bitcoin::bip32::ChildNumber::from_hardened_idx(44)?, // BIP44
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_normal_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_normal_idx(index)?,
-        ]);
+    /// Also ask a local `ord server` about new outputs, which finds inscriptions and rare
+    /// sats that arrived through ordinary transfers
+    pub fn with_ord_client(mut self, ord: OrdClient) -> Self {
+        self.ord = Some(Arc::new(ord));
+        self
+    }
 
-        let child_xprv = self.xprv.derive_priv(secp, &path)?;
-        let child_xpub = Xpub::from_priv(secp, &child_xprv);
+    /// Every transaction touching the user's addresses, from the Electrum server. Confirmed
+    /// transactions are checked to be in blocks of the proof-of-work verified header chain.
+    pub async fn get_history(&self, user_id: &str) -> Result<Vec<HistoryEntry>> {
+        let electrum = self
+            .electrum
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No Electrum server configured"))?;
+        let addresses = self.addresses(user_id).await?;
+        electrum.verified_history(&addresses).await
+    }
 
-        Ok(Address::p2pkh(&child_xpub.to_pub(), network))
+    /// Subscribe to changes on every address of the user, e.g. to sync again when one is
+    /// paid. Addresses handed out later need another call.
+    pub async fn subscribe(
+        &self,
+        user_id: &str,
+    ) -> Result<tokio::sync::broadcast::Receiver<ElectrumNotification>> {
+        let electrum = self
+            .electrum
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No Electrum server configured"))?;
+        let notifications = electrum.notifications();
+        for address in self.addresses(user_id).await? {
+            electrum.subscribe_script(&address.script_pubkey()).await?;
+        }
+        electrum.tip().await?;
+        Ok(notifications)
     }
 
-    fn derive_address_p2sh_wpkh(
-        &mut self,
-        index: u32,
-        secp: &Secp256k1<All>,
-        network: Network,
-    ) -> Result<Address> {
-        let path = DerivationPath::from(vec![
-            bitcoin::bip32::ChildNumber::from_hardened_idx(49)?, // BIP49
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_hardened_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_normal_idx(0)?,
-            bitcoin::bip32::ChildNumber::from_normal_idx(index)?,
-        ]);
+    /// Use a different fee estimator, e.g. one with an Esplora source
+    pub fn with_fee_estimator(mut self, fee_estimator: FeeEstimator) -> Self {
+        self.fee_estimator = Arc::new(fee_estimator);
+        self
+    }
 
-        let child_xprv = self.xprv.derive_priv(secp, &path)?;
-        let child_xpub = Xpub::from_priv(secp, &child_xprv);
+    pub fn fee_estimator(&self) -> Arc<FeeEstimator> {
+        self.fee_estimator.clone()
+    }
 
-        Ok(Address::p2shwpkh(&child_xpub.to_pub(), network)?)
+    /// Estimated fee rate for the given priority level
+    pub async fn estimate_fee_rate(&self, priority: FeePriority) -> Result<FeeRate> {
+        self.fee_estimator.estimate_priority(priority).await
     }
-}
 
-// Supporting structures
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WalletInfo {
-    pub user_id: String,
-    pub mnemonic: String,
-    pub xpub: String,
-    pub first_address: String,
-    pub network: Network,
-}
+    /// Shared UTXO manager
+    pub fn utxo_manager(&self) -> Arc<RwLock<UtxoManager>> {
+        self.utxo_manager.clone()
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Balance {
-    pub confirmed: u64,
-    pub unconfirmed: u64,
-    pub total: u64,
-}
+    /// Refresh a user's UTXOs from the first configured RPC endpoint
+    pub async fn sync_utxos(&self, user_id: &str) -> Result<usize> {
+        let addresses = self.addresses(user_id).await?;
 
-#[derive(Debug, Clone)]
-pub enum AddressType {
-    Legacy,
-    SegwitP2SH,
-    NativeSegwit,
-}
+        let (confirmed, unconfirmed): (Vec<TrackedUtxo>, Vec<TrackedUtxo>) = match &self.electrum {
+            Some(electrum) => electrum
+                .utxos(&addresses)
+                .await?
+                .into_iter()
+                .partition(|u| u.confirmations > 0),
+            None => {
+                let wallet_name = format!("walletd-{user_id}");
+                self.rpc
+                    .call(move |node| {
+                        let confirmed = UtxoManager::scan_utxos(&node.client, &addresses)?;
+                        // Nodes without wallet support still report confirmed coins
+                        let unconfirmed = node
+                            .wallet_client(&wallet_name)
+                            .and_then(|wallet| {
+                                UtxoManager::mempool_utxos(
+                                    &node.client,
+                                    &wallet,
+                                    &wallet_name,
+                                    &addresses,
+                                )
+                            })
+                            .unwrap_or_else(|e| {
+                                log::warn!("Unconfirmed outputs unavailable: {e}");
+                                vec![]
+                            });
+                        Ok((confirmed, unconfirmed))
+                    })
+                    .await?
+            }
+        };
 
-#[derive(Debug, Clone)]
-pub struct BitcoinConfig {
-    pub network: Network,
-    pub rpc_endpoints: Vec<RpcEndpoint>,
-}
+        // Neither source sees our own unconfirmed spends, so apply them from the history
+        let mut history = self.tx_history.write().await;
+        let mut spent = HashSet::new();
+        let mut change = vec![];
+        if let Some(tracker) = history.get_mut(user_id) {
+            let confirmed_outpoints: HashSet<OutPoint> =
+                confirmed.iter().map(|u| u.outpoint).collect();
+            for tx in tracker.pending().into_iter().cloned().collect::<Vec<_>>() {
+                let txid = tx.txid();
+                let Some(index) = tx.change_index else {
+                    spent.extend(tx.tx.input.iter().map(|i| i.previous_output));
+                    continue;
+                };
+                let outpoint = OutPoint::new(txid, index as u32);
+                if confirmed_outpoints.contains(&outpoint) {
+                    tracker.mark_confirmed(&txid)?;
+                    continue;
+                }
+                spent.extend(tx.tx.input.iter().map(|i| i.previous_output));
+                change.push(TrackedUtxo {
+                    outpoint,
+                    txout: tx.tx.output[index].clone(),
+                    confirmations: 0,
+                });
+            }
+            self.persist_history(user_id, tracker)?;
+        }
+        drop(history);
 
-#[derive(Debug, Clone)]
-pub struct RpcEndpoint {
-    pub url: String,
-    pub user: String,
-    pub pass: String,
+        // Later entries win, so confirmed outputs replace stale unconfirmed ones
+        let utxos: Vec<TrackedUtxo> = change
+            .into_iter()
+            .chain(unconfirmed)
+            .chain(confirmed)
+            .filter(|u| !spent.contains(&u.outpoint))
+            .collect();
+        let count = utxos.len();
+        let mut utxo_manager = self.utxo_manager.write().await;
+        let known: HashSet<OutPoint> = utxo_manager
+            .get_utxos(user_id)
+            .iter()
+            .map(|u| u.outpoint)
+            .collect();
+        let new: Vec<TrackedUtxo> = utxos
+            .iter()
+            .filter(|u| !known.contains(&u.outpoint))
+            .cloned()
+            .collect();
+        utxo_manager.update_utxos(user_id, utxos);
+        drop(utxo_manager);
+        self.persist_utxos(user_id).await?;
+        self.protect_sats(user_id, &new).await?;
+        Ok(count)
+    }
+
+    /// Probe every RPC endpoint so failed ones are skipped until they recover
+    pub async fn check_rpc_health(&self) -> Vec<EndpointHealth> {
+        self.rpc.health_check().await
+    }
+
+    /// Probe the RPC endpoints every `interval` in the background
+    pub fn spawn_health_checks(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let rpc = self.rpc.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for endpoint in rpc.health_check().await {
+                    if !endpoint.healthy {
+                        log::warn!("RPC endpoint {} is unreachable", endpoint.url);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Select coins for a payment of `amount` sats and reserve them for ten minutes
+    pub async fn select_coins(
+        &self,
+        user_id: &str,
+        amount: u64,
+        params: &CoinSelectionParams,
+        strategy: SelectionStrategy,
+    ) -> Result<SelectionResult> {
+        self.utxo_manager.write().await.select_coins(
+            user_id,
+            amount,
+            params,
+            strategy,
+            Duration::from_secs(600),
+        )
+    }
+
+    /// Build an unsigned PSBT paying `recipients`, funded by coin selection from the user's
+    /// UTXOs, with change to a fresh native segwit address and key origins attached
+    pub async fn create_psbt(
+        &self,
+        user_id: &str,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        strategy: SelectionStrategy,
+    ) -> Result<Psbt> {
+        self.create_psbt_with_coin_control(
+            user_id,
+            recipients,
+            fee_rate,
+            strategy,
+            &CoinControl::default(),
+        )
+        .await
+    }
+
+    /// Like `create_psbt`, spending the coins `control` includes, frozen or not, and none of
+    /// those it excludes
+    pub async fn create_psbt_with_coin_control(
+        &self,
+        user_id: &str,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        strategy: SelectionStrategy,
+        control: &CoinControl,
+    ) -> Result<Psbt> {
+        let scripts: Vec<ScriptBuf> = recipients.iter().map(|(a, _)| a.script_pubkey()).collect();
+        let params = self.selection_params(user_id, fee_rate, &scripts).await?;
+        self.create_psbt_with_params(user_id, recipients, &params, strategy, control)
+            .await
+    }
+
+    /// Build an unsigned PSBT spending all of `outpoints`, and nothing else, to `recipient`
+    /// less the fee, with no change
+    pub async fn create_sweep_psbt(
+        &self,
+        user_id: &str,
+        outpoints: &[OutPoint],
+        recipient: &Address,
+        fee_rate: FeeRate,
+    ) -> Result<Psbt> {
+        if outpoints.is_empty() {
+            return Err(anyhow::anyhow!("No coins to spend"));
+        }
+        let params = self
+            .selection_params(user_id, fee_rate, &[recipient.script_pubkey()])
+            .await?;
+        let mut utxo_manager = self.utxo_manager.write().await;
+        let utxos = utxo_manager.named_utxos(user_id, outpoints)?;
+        let weight = utxos
+            .iter()
+            .fold(params.base_weight, |w, u| w + params.spend_weight(u));
+        let fee = coin_selection::fee_for_weight(fee_rate, weight);
+        let total: u64 = utxos.iter().map(|u| u.txout.value.to_sat()).sum();
+        let value = total
+            .checked_sub(fee)
+            .filter(|value| *value >= params.dust_limit)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Insufficient funds. Have: {total} sats, fee: {fee} sats")
+            })?;
+        utxo_manager.reserve(outpoints, Duration::from_secs(600));
+        drop(utxo_manager);
+
+        let psbt = async {
+            let builder = utxos
+                .into_iter()
+                .fold(TransactionBuilder::new(), |b, utxo| b.add_input(utxo))
+                .add_recipient(recipient, value);
+            self.prepare_psbt(user_id, builder.build_psbt()?).await
+        }
+        .await;
+        // No transaction will spend the coins, so other sends may have them back
+        if psbt.is_err() {
+            self.utxo_manager.write().await.release(outpoints);
+        }
+        psbt
+    }
+
+    /// Like `create_psbt`, paying the estimated fee rate for `priority`. The economy estimate
+    /// is used as the long-term fee rate, so coin selection consolidates when fees are low.
+    pub async fn create_psbt_with_priority(
+        &self,
+        user_id: &str,
+        recipients: &[(Address, u64)],
+        priority: FeePriority,
+        strategy: SelectionStrategy,
+    ) -> Result<Psbt> {
+        let fee_rate = self.fee_estimator.estimate_priority(priority).await?;
+        let long_term = self
+            .fee_estimator
+            .estimate_priority(FeePriority::Economy)
+            .await?;
+        let scripts: Vec<ScriptBuf> = recipients.iter().map(|(a, _)| a.script_pubkey()).collect();
+        let params = self
+            .selection_params(user_id, fee_rate, &scripts)
+            .await?
+            .with_long_term_fee_rate(long_term);
+        self.create_psbt_with_params(
+            user_id,
+            recipients,
+            &params,
+            strategy,
+            &CoinControl::default(),
+        )
+        .await
+    }
+
+    /// Coin selection parameters for the user's wallet. Descriptor wallets' inputs and change
+    /// are weighed by their descriptor's worst-case satisfaction instead of as P2WPKH.
+    async fn selection_params(
+        &self,
+        user_id: &str,
+        fee_rate: FeeRate,
+        recipients: &[ScriptBuf],
+    ) -> Result<CoinSelectionParams> {
+        let params = CoinSelectionParams::new(fee_rate, recipients);
+        let wallets = self.wallets.read().await;
+        Ok(
+            match wallets.get(user_id).and_then(|w| w.descriptor.as_ref()) {
+                Some(descriptor) => params
+                    .with_change_script(&descriptor.script_pubkey(0, &self.secp)?)
+                    .with_input_weight(descriptor.max_satisfaction_weight(&self.secp)?),
+                None => params,
+            },
+        )
+    }
+
+    async fn create_psbt_with_params(
+        &self,
+        user_id: &str,
+        recipients: &[(Address, u64)],
+        params: &CoinSelectionParams,
+        strategy: SelectionStrategy,
+        control: &CoinControl,
+    ) -> Result<Psbt> {
+        let amount = recipients.iter().map(|(_, amount)| amount).sum();
+        let selection = self.utxo_manager.write().await.select_coins_with(
+            user_id,
+            amount,
+            params,
+            strategy,
+            control,
+            Duration::from_secs(600),
+        )?;
+
+        let psbt = async {
+            let change_script = self.change_script(user_id).await?;
+            let builder = recipients
+                .iter()
+                .fold(TransactionBuilder::new(), |b, (address, amount)| {
+                    b.add_recipient(address, *amount)
+                })
+                .add_selection(&selection, change_script);
+            self.prepare_psbt(user_id, builder.build_psbt()?).await
+        }
+        .await;
+        // No transaction will spend the coins, so other sends may have them back
+        if psbt.is_err() {
+            let reserved: Vec<OutPoint> = selection.selected.iter().map(|u| u.outpoint).collect();
+            self.utxo_manager.write().await.release(&reserved);
+        }
+        psbt
+    }
+
+    /// Build an unsigned PSBT as `create_psbt` does and encode it in base64, for signing on
+    /// another device such as the hardware wallet behind a watch-only wallet
+    pub async fn export_unsigned_psbt(
+        &self,
+        user_id: &str,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        strategy: SelectionStrategy,
+    ) -> Result<String> {
+        let psbt = self
+            .create_psbt(user_id, recipients, fee_rate, strategy)
+            .await?;
+        Ok(psbt_handler::to_base64(&psbt))
+    }
+
+    /// Finalize and broadcast a base64 PSBT signed on another device
+    pub async fn broadcast_signed_psbt(&self, user_id: &str, psbt: &str) -> Result<Txid> {
+        self.broadcast_psbt(user_id, psbt_handler::from_base64(psbt)?)
+            .await
+    }
+
+    /// Attach the user's BIP32 key origins to a PSBT built elsewhere
+    pub async fn prepare_psbt(&self, user_id: &str, mut psbt: Psbt) -> Result<Psbt> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        psbt_handler::add_wallet_derivations(&mut psbt, wallet, &self.secp)?;
+        Ok(psbt)
+    }
+
+    /// Sign every input of the PSBT the user holds keys for
+    pub async fn sign_psbt(&self, user_id: &str, psbt: &mut Psbt) -> Result<usize> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        wallet.ensure_can_sign()?;
+        if wallet.descriptor.is_none() {
+            self.vault.provision(user_id, wallet.ecdsa_key_paths())?;
+        }
+        drop(wallets);
+        let signer = self.vault.signer(user_id)?;
+        psbt_handler::sign_psbt(psbt, signer.as_ref())
+    }
+
+    /// Sign a message with one of the user's addresses, in `format` or the one wallets expect
+    /// for the address type (see `SignatureFormat::for_script`)
+    pub async fn sign_message(
+        &self,
+        user_id: &str,
+        address: &str,
+        message: &str,
+        format: Option<SignatureFormat>,
+    ) -> Result<String> {
+        let address = Address::from_str(address)?.require_network(self.network)?;
+        let wallet = self
+            .wallets
+            .read()
+            .await
+            .get(user_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        wallet.ensure_can_sign()?;
+        if wallet.descriptor.is_none() {
+            self.vault.provision(user_id, wallet.ecdsa_key_paths())?;
+        }
+        let signer = self.vault.signer(user_id)?;
+        wallet.sign_message(
+            signer.as_ref(),
+            &address,
+            message.as_bytes(),
+            format,
+            &self.secp,
+        )
+    }
+
+    /// Check a BIP322 or `signmessage` signature by any address
+    pub fn verify_message(&self, address: &str, message: &str, signature: &str) -> Result<bool> {
+        let address = Address::from_str(address)?.require_network(self.network)?;
+        UserBitcoinWallet::verify_message(&address, message.as_bytes(), signature, &self.secp)
+    }
+
+    /// Finalize a signed PSBT, satisfying descriptor wallets' scripts from their miniscript
+    pub async fn finalize_psbt(&self, user_id: &str, psbt: &mut Psbt) -> Result<()> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        match &wallet.descriptor {
+            Some(descriptor) => {
+                psbt_handler::finalize_with_descriptor(psbt, wallet, descriptor, &self.secp)
+            }
+            None => psbt_handler::finalize_psbt(psbt),
+        }
+    }
+
+    /// Finalize and broadcast a signed PSBT, tracking it so its fee can be bumped later.
+    /// Spent coins are forgotten and the output the builder marked as change is tracked as an
+    /// unconfirmed UTXO.
+    pub async fn broadcast_psbt(&self, user_id: &str, psbt: Psbt) -> Result<Txid> {
+        self.broadcast(user_id, psbt, None).await
+    }
+
+    /// Replace an unconfirmed transaction with one paying `fee_rate` (BIP125). The extra fee
+    /// comes out of change first, then from confirmed coins. Returns the replacement's txid.
+    pub async fn bump_fee(&self, user_id: &str, txid: &Txid, fee_rate: FeeRate) -> Result<Txid> {
+        let original = self.sent_transaction(user_id, txid).await?;
+        let extra_utxos = self.utxo_manager.read().await.spendable_utxos(user_id, 1);
+        let change_script = match original.change_index {
+            Some(index) => original.tx.output[index].script_pubkey.clone(),
+            None => self.change_script(user_id).await?,
+        };
+
+        let descendant_fees = self.descendant_fees(user_id, txid).await?;
+
+        let bump = fee_bump::bump_fee_rbf(
+            &original,
+            fee_rate,
+            &extra_utxos,
+            &change_script,
+            descendant_fees,
+        )?;
+        let psbt = self.sign_fee_bump(user_id, &bump).await?;
+        self.broadcast(user_id, psbt, Some(*txid)).await
+    }
+
+    /// Fees of `txid`'s in-mempool descendants. The node's `getmempoolentry` also counts
+    /// descendants others made, such as a recipient spending their output; with Electrum, or
+    /// once the node no longer has `txid`, only the user's own tracked spends are known.
+    async fn descendant_fees(&self, user_id: &str, txid: &Txid) -> Result<u64> {
+        let tracked = self
+            .tx_history
+            .read()
+            .await
+            .get(user_id)
+            .map(|history| history.descendant_fees(txid))
+            .unwrap_or(0);
+        if self.electrum.is_some() || self.rpc.is_empty() {
+            return Ok(tracked);
+        }
+        let txid = *txid;
+        let mempool = self
+            .rpc
+            .call(move |node| {
+                let fees = node.client.get_mempool_entry(&txid)?.fees;
+                Ok(fees
+                    .descendant
+                    .to_sat()
+                    .saturating_sub(fees.modified.to_sat()))
+            })
+            .await;
+        Ok(mempool.map_or(tracked, |fees| fees.max(tracked)))
+    }
+
+    /// Spend our change from an unconfirmed transaction so parent and child together pay
+    /// `package_fee_rate`. Returns the child's txid.
+    pub async fn cpfp(
+        &self,
+        user_id: &str,
+        txid: &Txid,
+        package_fee_rate: FeeRate,
+    ) -> Result<Txid> {
+        let parent = self.sent_transaction(user_id, txid).await?;
+        let vout = parent
+            .change_index
+            .ok_or_else(|| anyhow::anyhow!("Transaction has no change output to spend"))?;
+        let destination = self.change_script(user_id).await?;
+
+        let child = fee_bump::build_cpfp(&parent, vout as u32, package_fee_rate, &destination)?;
+        let psbt = self.sign_fee_bump(user_id, &child).await?;
+        self.broadcast(user_id, psbt, None).await
+    }
+
+    /// Every transaction the user broadcast, including replaced ones
+    pub async fn transaction_history(&self, user_id: &str) -> Vec<SentTransaction> {
+        self.tx_history
+            .read()
+            .await
+            .get(user_id)
+            .map(|t| t.history().into_iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Record that a transaction confirmed, settling its replacement chain
+    pub async fn mark_confirmed(&self, user_id: &str, txid: &Txid) -> Result<()> {
+        let mut history = self.tx_history.write().await;
+        let tracker = history
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("No transactions for user"))?;
+        tracker.mark_confirmed(txid)?;
+        self.persist_history(user_id, tracker)
+    }
+
+    /// The transaction that finally confirmed in place of `txid` or any of its replacements
+    pub async fn confirmed_txid(&self, user_id: &str, txid: &Txid) -> Option<Txid> {
+        self.tx_history
+            .read()
+            .await
+            .get(user_id)
+            .and_then(|t| t.confirmed_txid(txid))
+    }
+
+    async fn sent_transaction(&self, user_id: &str, txid: &Txid) -> Result<SentTransaction> {
+        self.tx_history
+            .read()
+            .await
+            .get(user_id)
+            .and_then(|t| t.get(txid))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Transaction {txid} not found"))
+    }
+
+    /// Script of a fresh change address: native segwit, or on the watched account's change
+    /// chain for watch-only wallets
+    async fn change_script(&self, user_id: &str) -> Result<ScriptBuf> {
+        let watched = self
+            .wallets
+            .read()
+            .await
+            .get(user_id)
+            .and_then(|wallet| wallet.watched_account());
+        let keychain = match watched {
+            Some((account, _)) => Keychain {
+                kind: KeychainKind::Internal,
+                ..account
+            },
+            None => Keychain::change(AddressType::NativeSegwit),
+        };
+        let address = self.get_address(user_id, keychain).await?;
+        Ok(address
+            .parse::<Address<_>>()?
+            .require_network(self.network)?
+            .script_pubkey())
+    }
+
+    async fn sign_fee_bump(&self, user_id: &str, bump: &FeeBump) -> Result<Psbt> {
+        let mut psbt = self.prepare_psbt(user_id, bump.build_psbt()?).await?;
+        let signed = self.sign_psbt(user_id, &mut psbt).await?;
+        if signed < psbt.inputs.len() {
+            return Err(anyhow::anyhow!(
+                "Signed {signed} of {} inputs",
+                psbt.inputs.len()
+            ));
+        }
+        Ok(psbt)
+    }
+
+    async fn broadcast(
+        &self,
+        user_id: &str,
+        mut psbt: Psbt,
+        replaces: Option<Txid>,
+    ) -> Result<Txid> {
+        self.finalize_psbt(user_id, &mut psbt).await?;
+        let prevouts = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::anyhow!("PSBT inputs are missing their spent outputs"))?;
+        let change_index = psbt_handler::change_index(&psbt);
+        let tx = psbt_handler::extract_transaction(psbt)?;
+        let sent = SentTransaction::new(tx.clone(), prevouts, change_index)?;
+
+        let txid = self.broadcast_transaction(&tx).await?;
+
+        let mut utxo_manager = self.utxo_manager.write().await;
+        let spent: Vec<OutPoint> = sent.tx.input.iter().map(|i| i.previous_output).collect();
+        utxo_manager.mark_spent(user_id, &spent);
+        if let Some(original) = replaces {
+            // The replaced transaction's outputs no longer exist
+            let outputs = self
+                .sent_transaction(user_id, &original)
+                .await?
+                .tx
+                .output
+                .len();
+            let gone: Vec<OutPoint> = (0..outputs as u32)
+                .map(|vout| OutPoint::new(original, vout))
+                .collect();
+            utxo_manager.mark_spent(user_id, &gone);
+        }
+        if let Some(index) = change_index {
+            utxo_manager.add_utxo(
+                user_id,
+                TrackedUtxo {
+                    outpoint: OutPoint::new(txid, index as u32),
+                    txout: sent.tx.output[index].clone(),
+                    confirmations: 0,
+                },
+            );
+        }
+        drop(utxo_manager);
+
+        let mut history = self.tx_history.write().await;
+        let tracker = history.entry(user_id.to_string()).or_default();
+        match replaces {
+            Some(original) => tracker.record_replacement(original, sent),
+            None => tracker.record(sent),
+        };
+        self.persist_history(user_id, tracker)?;
+        drop(history);
+        self.persist_utxos(user_id).await?;
+        Ok(txid)
+    }
+
+    /// Broadcast a finished transaction without tracking it in any user's history
+    pub async fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid> {
+        match &self.electrum {
+            Some(electrum) => electrum.broadcast(tx).await,
+            None => {
+                let tx = tx.clone();
+                self.rpc
+                    .call(move |node| Ok(node.client.send_raw_transaction(&tx)?))
+                    .await
+            }
+        }
+    }
+
+    /// Height of the best block
+    pub async fn block_height(&self) -> Result<u32> {
+        match &self.electrum {
+            Some(electrum) => Ok(electrum.tip().await?.0),
+            None => {
+                self.rpc
+                    .call(|node| Ok(node.client.get_block_count()? as u32))
+                    .await
+            }
+        }
+    }
+
+    /// Hash of the block at `height` on the best chain. Needs the RPC endpoints.
+    pub async fn block_hash(&self, height: u32) -> Result<BlockHash> {
+        self.rpc
+            .call(move |node| Ok(node.client.get_block_hash(height as u64)?))
+            .await
+    }
+
+    /// A block by hash, on the best chain or not. Needs the RPC endpoints.
+    pub async fn block(&self, hash: &BlockHash) -> Result<Block> {
+        let hash = *hash;
+        self.rpc
+            .call(move |node| Ok(node.client.get_block(&hash)?))
+            .await
+    }
+
+    /// Height of a block by hash, on the best chain or not. Needs the RPC endpoints.
+    pub async fn block_height_of(&self, hash: &BlockHash) -> Result<u32> {
+        let hash = *hash;
+        self.rpc
+            .call(move |node| Ok(node.client.get_block_header_info(&hash)?.height as u32))
+            .await
+    }
+
+    /// Unspent outputs paying to an address that isn't necessarily the wallet's. Without
+    /// Electrum only confirmed outputs are found.
+    pub async fn address_utxos(&self, address: &Address) -> Result<Vec<TrackedUtxo>> {
+        let addresses = vec![address.clone()];
+        match &self.electrum {
+            Some(electrum) => electrum.utxos(&addresses).await,
+            None => {
+                self.rpc
+                    .call(move |node| UtxoManager::scan_utxos(&node.client, &addresses))
+                    .await
+            }
+        }
+    }
+
+    /// The transaction spending `outpoint`, a `script_pubkey` output, if there is one in the
+    /// mempool or in a block from `from_height` on
+    pub async fn find_spending_tx(
+        &self,
+        outpoint: OutPoint,
+        script_pubkey: &Script,
+        from_height: u32,
+    ) -> Result<Option<Transaction>> {
+        let spends = move |tx: &Transaction| tx.input.iter().any(|i| i.previous_output == outpoint);
+        if let Some(electrum) = &self.electrum {
+            for entry in electrum.get_history(script_pubkey).await? {
+                if entry.txid == outpoint.txid {
+                    continue;
+                }
+                let tx = electrum.get_transaction(&entry.txid).await?;
+                if spends(&tx) {
+                    return Ok(Some(tx));
+                }
+            }
+            return Ok(None);
+        }
+
+        self.rpc
+            .call(move |node| {
+                let client = &node.client;
+                // Bitcoin Core 24+ indexes mempool spends; blocks are searched one by one
+                let mempool: Vec<serde_json::Value> = client.call(
+                    "gettxspendingprevout",
+                    &[serde_json::json!([{ "txid": outpoint.txid, "vout": outpoint.vout }])],
+                )?;
+                let spending = mempool
+                    .first()
+                    .and_then(|entry| entry["spendingtxid"].as_str())
+                    .map(Txid::from_str)
+                    .transpose()?;
+                if let Some(txid) = spending {
+                    return Ok(Some(client.get_raw_transaction(&txid, None)?));
+                }
+
+                let tip = client.get_block_count()?;
+                for height in from_height as u64..=tip {
+                    let block = client.get_block(&client.get_block_hash(height)?)?;
+                    if let Some(tx) = block.txdata.into_iter().find(spends) {
+                        return Ok(Some(tx));
+                    }
+                }
+                Ok(None)
+            })
+            .await
+    }
+
+    /// Label a transaction, address, key, input or output; an empty label removes it
+    pub async fn set_label(
+        &self,
+        user_id: &str,
+        kind: LabelType,
+        reference: &str,
+        label: &str,
+    ) -> Result<()> {
+        let mut labels = self.labels.write().await;
+        let user_labels = labels.entry(user_id.to_string()).or_default();
+        let mut record = user_labels
+            .get(kind, reference)
+            .cloned()
+            .unwrap_or_else(|| Label::new(kind, reference, ""));
+        record.label = (!label.is_empty()).then(|| label.to_string());
+        record.validate()?;
+        if let Some(storage) = &self.storage {
+            if record.is_empty() {
+                storage.remove_label(user_id, kind, reference)?;
+            } else {
+                storage.save_labels(user_id, std::slice::from_ref(&record))?;
+            }
+        }
+        user_labels.insert(record);
+        Ok(())
+    }
+
+    /// The label text of a reference
+    pub async fn label(&self, user_id: &str, kind: LabelType, reference: &str) -> Option<String> {
+        let labels = self.labels.read().await;
+        labels
+            .get(user_id)?
+            .label(kind, reference)
+            .map(String::from)
+    }
+
+    /// All of the user's labels
+    pub async fn labels(&self, user_id: &str) -> Labels {
+        self.labels
+            .read()
+            .await
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Export the user's labels as BIP329 JSON lines. Tracked outputs carry their current
+    /// frozen state as `spendable`, so freezes carry over to other wallets.
+    pub async fn export_labels(&self, user_id: &str) -> Result<String> {
+        let mut labels = self.labels(user_id).await;
+        let utxo_manager = self.utxo_manager.read().await;
+        for utxo in utxo_manager.get_utxos(user_id) {
+            let reference = utxo.outpoint.to_string();
+            let frozen = utxo_manager.is_frozen(&utxo.outpoint);
+            let mut record = match labels.get(LabelType::Output, &reference) {
+                Some(record) => record.clone(),
+                None if frozen => Label::new(LabelType::Output, reference, ""),
+                None => continue,
+            };
+            record.spendable = Some(!frozen);
+            labels.insert(record);
+        }
+        labels.to_jsonl()
+    }
+
+    /// Import BIP329 JSON lines, e.g. exported by Sparrow, replacing labels of the same
+    /// references. Outputs marked unspendable are frozen and those marked spendable are
+    /// unfrozen unless they hold inscriptions or rare sats. Returns how many were imported.
+    pub async fn import_labels(&self, user_id: &str, jsonl: &str) -> Result<usize> {
+        let imported = labels::import_jsonl(jsonl)?;
+        if let Some(storage) = &self.storage {
+            storage.save_labels(user_id, &imported)?;
+        }
+
+        let mut utxo_manager = self.utxo_manager.write().await;
+        for label in imported.iter().filter(|l| l.kind == LabelType::Output) {
+            let outpoint = OutPoint::from_str(&label.reference)?;
+            match label.spendable {
+                Some(false) if !utxo_manager.is_frozen(&outpoint) => {
+                    utxo_manager.freeze(outpoint, FreezeReason::Manual)
+                }
+                Some(true) => {
+                    if let Some(reason) = utxo_manager.unfreeze(&outpoint) {
+                        if reason != FreezeReason::Manual {
+                            utxo_manager.freeze(outpoint, reason);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        drop(utxo_manager);
+        self.persist_frozen(user_id).await?;
+
+        let count = imported.len();
+        let mut labels = self.labels.write().await;
+        let user_labels = labels.entry(user_id.to_string()).or_default();
+        for label in imported {
+            user_labels.insert(label);
+        }
+        Ok(count)
+    }
+
+    async fn addresses(&self, user_id: &str) -> Result<Vec<Address>> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        Ok(wallet.addresses.values().cloned().collect())
+    }
+
+    /// Check every tracked UTXO for inscriptions and rare sats, freezing the ones that hold
+    /// some. `sync_utxos` does this for new outputs.
+    pub async fn scan_protected_sats(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(OutPoint, ProtectedSats)>> {
+        let utxos = self.utxo_manager.read().await.get_utxos(user_id);
+        self.protect_sats(user_id, &utxos).await
+    }
+
+    /// Freeze outputs holding inscriptions or rare sats. Outputs whose transactions can't be
+    /// fetched are logged and left as they are.
+    async fn protect_sats(
+        &self,
+        user_id: &str,
+        utxos: &[TrackedUtxo],
+    ) -> Result<Vec<(OutPoint, ProtectedSats)>> {
+        let mut found = vec![];
+        for utxo in utxos {
+            match self.protected_sats(user_id, &utxo.outpoint).await {
+                Ok(protected) if !protected.is_empty() => found.push((utxo.outpoint, protected)),
+                Ok(_) => {}
+                Err(e) => log::warn!("Can't check {} for inscriptions: {e}", utxo.outpoint),
+            }
+        }
+        if found.is_empty() {
+            return Ok(found);
+        }
+        let mut utxo_manager = self.utxo_manager.write().await;
+        for (outpoint, protected) in &found {
+            utxo_manager.freeze(*outpoint, FreezeReason::ProtectedSats(protected.clone()));
+        }
+        drop(utxo_manager);
+        self.persist_frozen(user_id).await?;
+        Ok(found)
+    }
+
+    async fn protected_sats(&self, user_id: &str, outpoint: &OutPoint) -> Result<ProtectedSats> {
+        let tx = self.fetch_transaction(user_id, &outpoint.txid).await?;
+        let mut input_values = vec![];
+        for input in &tx.input[..ordinals::input_values_needed(&tx)] {
+            let previous = input.previous_output;
+            let prev_tx = self.fetch_transaction(user_id, &previous.txid).await?;
+            let spent = prev_tx
+                .output
+                .get(previous.vout as usize)
+                .ok_or_else(|| anyhow::anyhow!("{previous} does not exist"))?;
+            input_values.push(spent.value.to_sat());
+        }
+        let mut protected = ordinals::scan_output(&tx, outpoint.vout, &input_values)?;
+        if let Some(ord) = &self.ord {
+            protected.merge(ord.protected_sats(outpoint).await?);
+        }
+        Ok(protected)
+    }
+
+    /// A transaction by ID, from Electrum or from the user's watch-only wallet on the node,
+    /// falling back to `getrawtransaction` (which needs `-txindex` for other transactions)
+    async fn fetch_transaction(&self, user_id: &str, txid: &Txid) -> Result<Transaction> {
+        if let Some(electrum) = &self.electrum {
+            return electrum.get_transaction(txid).await;
+        }
+        let txid = *txid;
+        let wallet_name = format!("walletd-{user_id}");
+        self.rpc
+            .call(move |node| {
+                let from_wallet = node
+                    .wallet_client(&wallet_name)
+                    .and_then(|wallet| Ok(wallet.get_transaction(&txid, Some(true))?));
+                match from_wallet {
+                    Ok(result) => Ok(result.transaction()?),
+                    Err(_) => Ok(node.client.get_raw_transaction(&txid, None)?),
+                }
+            })
+            .await
+    }
+
+    /// Keep coin selection away from an output until `unfreeze_utxo`
+    pub async fn freeze_utxo(&self, user_id: &str, outpoint: OutPoint) -> Result<()> {
+        self.utxo_manager
+            .write()
+            .await
+            .freeze(outpoint, FreezeReason::Manual);
+        self.persist_frozen(user_id).await
+    }
+
+    /// Let coin selection spend a frozen output again, inscribed or not
+    pub async fn unfreeze_utxo(&self, user_id: &str, outpoint: &OutPoint) -> Result<()> {
+        self.utxo_manager.write().await.unfreeze(outpoint);
+        self.persist_frozen(user_id).await
+    }
+
+    pub async fn frozen_utxos(&self, user_id: &str) -> Vec<(TrackedUtxo, FreezeReason)> {
+        self.utxo_manager.read().await.frozen_utxos(user_id)
+    }
+
+    /// Build an unsigned PSBT sending the frozen output holding `inscription` to `recipient`.
+    /// The whole output is the first input and pays the first output, so the inscribed sat
+    /// keeps its offset; fees come from other coins, with change back to the wallet.
+    pub async fn create_inscription_psbt(
+        &self,
+        user_id: &str,
+        inscription: &InscriptionId,
+        recipient: &Address,
+        fee_rate: FeeRate,
+    ) -> Result<Psbt> {
+        let mut params = self
+            .selection_params(user_id, fee_rate, &[recipient.script_pubkey()])
+            .await?;
+        let mut utxo_manager = self.utxo_manager.write().await;
+        let (utxo, protected) = utxo_manager
+            .frozen_utxos(user_id)
+            .into_iter()
+            .find_map(|(utxo, reason)| match reason {
+                FreezeReason::ProtectedSats(protected)
+                    if protected
+                        .inscriptions
+                        .iter()
+                        .any(|(id, _)| id == inscription) =>
+                {
+                    Some((utxo, protected))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("No frozen output holds inscription {inscription}"))?;
+        if protected.inscriptions.len() + protected.rare_sats.len() > 1 {
+            return Err(anyhow::anyhow!(
+                "{} also holds other inscriptions or rare sats, which would be sent too",
+                utxo.outpoint
+            ));
+        }
+
+        params.base_weight += params.spend_weight(&utxo);
+        let selection = utxo_manager.select_coins(
+            user_id,
+            0,
+            &params,
+            SelectionStrategy::default(),
+            Duration::from_secs(600),
+        )?;
+        utxo_manager.reserve(&[utxo.outpoint], Duration::from_secs(600));
+        drop(utxo_manager);
+
+        let mut reserved: Vec<OutPoint> = selection.selected.iter().map(|u| u.outpoint).collect();
+        reserved.push(utxo.outpoint);
+        let psbt = async {
+            let change_script = self.change_script(user_id).await?;
+            let value = utxo.txout.value.to_sat();
+            let builder = TransactionBuilder::new()
+                .add_input(utxo)
+                .add_recipient(recipient, value)
+                .add_selection(&selection, change_script);
+            self.prepare_psbt(user_id, builder.build_psbt()?).await
+        }
+        .await;
+        // No transaction will spend the coins, so other sends may have them back
+        if psbt.is_err() {
+            self.utxo_manager.write().await.release(&reserved);
+        }
+        psbt
+    }
+
+    /// Encrypt the user's key with a passphrase. The wallet is locked afterwards and signs
+    /// only while unlocked.
+    pub async fn encrypt_wallet(&self, user_id: &str, passphrase: &str) -> Result<()> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        wallet.ensure_can_sign()?;
+        self.vault.encrypt(user_id, passphrase)?;
+        self.persist_wallet(wallet)
+    }
+
+    /// Unlock an encrypted wallet until the configured timeout passes
+    pub fn unlock_wallet(&self, user_id: &str, passphrase: &str) -> Result<()> {
+        self.vault.unlock(user_id, passphrase)
+    }
+
+    pub fn lock_wallet(&self, user_id: &str) -> Result<()> {
+        self.vault.lock(user_id)
+    }
+
+    pub fn is_wallet_locked(&self, user_id: &str) -> bool {
+        self.vault.is_locked(user_id)
+    }
+
+    pub async fn change_wallet_passphrase(
+        &self,
+        user_id: &str,
+        old: &str,
+        new: &str,
+    ) -> Result<()> {
+        let wallets = self.wallets.read().await;
+        let wallet = wallets
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        wallet.ensure_can_sign()?;
+        self.vault.change_passphrase(user_id, old, new)?;
+        self.persist_wallet(wallet)
+    }
+
+    pub fn vault(&self) -> Arc<BitcoinSecurityVault> {
+        self.vault.clone()
+    }
+
+    /// Cache the xpubs of the accounts of these chains, deriving any missing ones from the
+    /// user's key
+    fn ensure_accounts<'a>(
+        &self,
+        wallet: &mut UserBitcoinWallet,
+        keychains: impl IntoIterator<Item = &'a Keychain>,
+    ) -> Result<()> {
+        for keychain in keychains {
+            let path = keychain.account_path()?;
+            if !wallet.account_xpubs.contains_key(&path) {
+                if wallet.watch_only {
+                    let accounts: Vec<String> =
+                        wallet.account_xpubs.keys().map(|p| p.to_string()).collect();
+                    return Err(anyhow::anyhow!(
+                        "Watch-only wallet only has account {}; it can't derive {path}",
+                        accounts.join(", ")
+                    ));
+                }
+                let xpub = self.vault.xpub(&wallet.user_id, &path)?;
+                wallet.account_xpubs.insert(path, xpub);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the wallet through to storage. With an HSM, keys of new addresses are copied
+    /// onto it while the wallet is unlocked; any left are copied when signing.
+    fn persist_wallet(&self, wallet: &UserBitcoinWallet) -> Result<()> {
+        if wallet.watch_only {
+            return match &self.storage {
+                Some(storage) => storage.save_wallet(wallet, None),
+                None => Ok(()),
+            };
+        }
+        if wallet.descriptor.is_none() && !self.vault.is_locked(&wallet.user_id) {
+            self.vault
+                .provision(&wallet.user_id, wallet.ecdsa_key_paths())?;
+        }
+        match &self.storage {
+            Some(storage) => {
+                storage.save_wallet(wallet, Some(&self.vault.stored_key(&wallet.user_id)?))
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn persist_utxos(&self, user_id: &str) -> Result<()> {
+        match &self.storage {
+            Some(storage) => {
+                let utxos = self.utxo_manager.read().await.get_utxos(user_id);
+                storage.save_utxos(user_id, &utxos)
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn persist_frozen(&self, user_id: &str) -> Result<()> {
+        match &self.storage {
+            Some(storage) => {
+                let frozen: Vec<(OutPoint, FreezeReason)> = self
+                    .utxo_manager
+                    .read()
+                    .await
+                    .frozen_utxos(user_id)
+                    .into_iter()
+                    .map(|(utxo, reason)| (utxo.outpoint, reason))
+                    .collect();
+                storage.save_frozen(user_id, &frozen)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn persist_history(&self, user_id: &str, history: &ReplacementTracker) -> Result<()> {
+        match &self.storage {
+            Some(storage) => storage.save_history(user_id, history),
+            None => Ok(()),
+        }
+    }
+}
+
+impl UserBitcoinWallet {
+    /// Fingerprint of the master key, used as the BIP32 key origin
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.key_origin.0
+    }
+
+    /// Whether the wallet only watches, without a key to sign with
+    pub fn is_watch_only(&self) -> bool {
+        self.watch_only
+    }
+
+    fn ensure_can_sign(&self) -> Result<()> {
+        if self.watch_only {
+            return Err(anyhow::anyhow!(
+                "Wallet {} is watch-only; export an unsigned PSBT and sign it where the key is",
+                self.user_id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receive chain and xpub of the account a watch-only wallet was created from
+    fn watched_account(&self) -> Option<(Keychain, Xpub)> {
+        if !self.watch_only || self.descriptor.is_some() {
+            return None;
+        }
+        let (path, xpub) = self.account_xpubs.iter().next()?;
+        let [purpose, _, account] = path.as_ref() else {
+            return None;
+        };
+        let address_type = AddressType::ALL
+            .into_iter()
+            .find(|t| u32::from(*purpose) == t.purpose() + (1 << 31))?;
+        let account = u32::from(*account) - (1 << 31);
+        Some((
+            Keychain::new(address_type, account, KeychainKind::External),
+            *xpub,
+        ))
+    }
+
+    /// BIP32 origin of the key at a path the wallet handed out. Watch-only wallets derive
+    /// below the standard account path, which is swapped for the origin they were given.
+    pub fn key_source(&self, path: &DerivationPath) -> KeySource {
+        let (fingerprint, origin) = &self.key_origin;
+        if self.watch_only {
+            let rest = &path.as_ref()[ACCOUNT_DEPTH.min(path.len())..];
+            return (*fingerprint, origin.extend(rest));
+        }
+        (*fingerprint, path.clone())
+    }
+
+    /// Sign `message` to prove control of one of the wallet's addresses. BIP322 signatures are
+    /// made through the PSBT signer; multisig scripts are signed by each cosigner on the PSBT
+    /// from `message::to_sign_psbt` instead.
+    pub fn sign_message(
+        &self,
+        signer: &dyn Signer,
+        address: &Address,
+        message: &[u8],
+        format: Option<SignatureFormat>,
+        secp: &Secp256k1<All>,
+    ) -> Result<String> {
+        self.ensure_can_sign()?;
+        let script_pubkey = address.script_pubkey();
+        if !self.owns(&script_pubkey) {
+            return Err(anyhow::anyhow!(
+                "{address} is not an address of this wallet"
+            ));
+        }
+        let format = format.unwrap_or_else(|| SignatureFormat::for_script(&script_pubkey));
+        if format == SignatureFormat::Legacy {
+            let path = self
+                .derivation_path(&script_pubkey)
+                .ok_or_else(|| anyhow::anyhow!("Legacy signatures need a single-key address"))?;
+            return message::sign_legacy(signer, &path, &script_pubkey, message, secp);
+        }
+
+        let mut psbt = message::to_sign_psbt(&script_pubkey, message)?;
+        psbt_handler::add_wallet_derivations(&mut psbt, self, secp)?;
+        if psbt_handler::sign_psbt(&mut psbt, signer)? == 0 {
+            return Err(anyhow::anyhow!("No key to sign for {address}"));
+        }
+        match &self.descriptor {
+            Some(descriptor) => {
+                psbt_handler::finalize_with_descriptor(&mut psbt, self, descriptor, secp)?
+            }
+            None => psbt_handler::finalize_psbt(&mut psbt)?,
+        }
+        message::encode_signature(&psbt, format)
+    }
+
+    /// Check a signature of `message` by `address` in any format; see
+    /// `message::verify_message`
+    pub fn verify_message(
+        address: &Address,
+        message: &[u8],
+        signature: &str,
+        secp: &Secp256k1<All>,
+    ) -> Result<bool> {
+        message::verify_message(address, message, signature, secp)
+    }
+
+    /// Output descriptor the wallet was created from
+    pub fn descriptor(&self) -> Option<&Descriptor> {
+        self.descriptor.as_ref()
+    }
+
+    /// Descriptor index of a script this wallet has handed out
+    pub fn descriptor_index(&self, script_pubkey: &Script) -> Option<u32> {
+        self.descriptor_indexes.get(script_pubkey).copied()
+    }
+
+    /// Whether the script is one this wallet has handed out
+    pub fn owns(&self, script_pubkey: &Script) -> bool {
+        self.script_paths.contains_key(script_pubkey)
+            || self.descriptor_indexes.contains_key(script_pubkey)
+    }
+
+    /// Full derivation path of a script this wallet has handed out
+    pub fn derivation_path(&self, script_pubkey: &Script) -> Option<DerivationPath> {
+        self.script_paths.get(script_pubkey).cloned()
+    }
+
+    /// Public key at a path from the master key, below one of the cached accounts
+    pub fn derive_public_key(
+        &self,
+        path: &DerivationPath,
+        secp: &Secp256k1<All>,
+    ) -> Result<bitcoin::PublicKey> {
+        let (account, rest) = path.as_ref().split_at(ACCOUNT_DEPTH.min(path.len()));
+        let account = DerivationPath::from(account);
+        let xpub = self.account_xpubs.get(&account).ok_or_else(|| {
+            anyhow::anyhow!("Account {account} hasn't been derived; unlock the wallet first")
+        })?;
+        Ok(xpub.derive_pub(secp, &DerivationPath::from(rest))?.to_pub())
+    }
+
+    /// Accounts the wallet has handed out addresses or keys from
+    fn used_accounts(&self) -> HashSet<DerivationPath> {
+        self.script_paths
+            .values()
+            .chain(self.taproot_keys.values())
+            .map(|path| DerivationPath::from(&path.as_ref()[..ACCOUNT_DEPTH.min(path.len())]))
+            .collect()
+    }
+
+    /// Paths of the keys behind every non-taproot address, which an HSM can sign for
+    fn ecdsa_key_paths(&self) -> impl Iterator<Item = &DerivationPath> {
+        self.script_paths
+            .iter()
+            .filter(|(script, _)| !script.is_p2tr())
+            .map(|(_, path)| path)
+    }
+
+    /// Derivation path of a taproot key this wallet has handed out
+    pub fn taproot_key_path(&self, key: &XOnlyPublicKey) -> Option<DerivationPath> {
+        self.taproot_keys.get(key).cloned()
+    }
+
+    /// Script tree of a taproot output with script-path leaves
+    pub fn taproot_tree(&self, script_pubkey: &Script) -> Option<&TaprootSpendInfo> {
+        self.taproot_trees.get(script_pubkey)
+    }
+
+    /// Next index on a chain
+    pub fn next_index(&self, keychain: Keychain) -> u32 {
+        self.next_indexes.get(&keychain).copied().unwrap_or(0)
+    }
+
+    /// Claim the next index on a chain
+    fn take_index(&mut self, keychain: Keychain) -> u32 {
+        let next = self.next_indexes.entry(keychain).or_default();
+        *next += 1;
+        *next - 1
+    }
+
+    /// Derive the public key at `index` on a chain
+    fn derive_child(
+        &self,
+        keychain: Keychain,
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<(DerivationPath, bitcoin::PublicKey)> {
+        let path = keychain.path(index)?;
+        let pubkey = self.derive_public_key(&path, secp)?;
+        Ok((path, pubkey))
+    }
+
+    /// Hand out the next address on a chain
+    fn next_address(
+        &mut self,
+        keychain: Keychain,
+        secp: &Secp256k1<All>,
+        network: Network,
+    ) -> Result<Address> {
+        let index = self.take_index(keychain);
+        self.reveal(keychain, index, secp, network)
+    }
+
+    /// Derive the address at `index` on a chain and remember it as ours
+    fn reveal(
+        &mut self,
+        keychain: Keychain,
+        index: u32,
+        secp: &Secp256k1<All>,
+        network: Network,
+    ) -> Result<Address> {
+        let (path, pubkey) = self.derive_child(keychain, index, secp)?;
+        let address = script_address(keychain.address_type, &pubkey, secp, network)?;
+        if keychain.address_type == AddressType::Taproot {
+            self.taproot_keys
+                .insert(XOnlyPublicKey::from(pubkey.inner), path.clone());
+        }
+        self.remember(&address, path);
+        Ok(address)
+    }
+
+    fn remember(&mut self, address: &Address, path: DerivationPath) {
+        self.script_paths.insert(address.script_pubkey(), path);
+        self.addresses
+            .insert(address.script_pubkey(), address.clone());
+    }
+
+    fn derive_descriptor_address(
+        &mut self,
+        index: u32,
+        secp: &Secp256k1<All>,
+        network: Network,
+    ) -> Result<Address> {
+        let descriptor = self
+            .descriptor
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Wallet has no descriptor"))?;
+        let address = descriptor.address(index, network, secp)?;
+        self.descriptor_indexes
+            .insert(address.script_pubkey(), index);
+        self.addresses
+            .insert(address.script_pubkey(), address.clone());
+        Ok(address)
+    }
+
+    /// BIP86 internal key at `index` on the first account's taproot receive chain
+    fn derive_taproot_key(&mut self, index: u32, secp: &Secp256k1<All>) -> Result<XOnlyPublicKey> {
+        let (path, pubkey) =
+            self.derive_child(Keychain::receive(AddressType::Taproot), index, secp)?;
+        let key = XOnlyPublicKey::from(pubkey.inner);
+        self.taproot_keys.insert(key, path);
+        Ok(key)
+    }
+
+    fn derive_taproot_script_address(
+        &mut self,
+        index: u32,
+        leaves: &[ScriptBuf],
+        secp: &Secp256k1<All>,
+        network: Network,
+    ) -> Result<Address> {
+        if leaves.is_empty() {
+            return Err(anyhow::anyhow!("At least one tapscript leaf is required"));
+        }
+        let internal_key = self.derive_taproot_key(index, secp)?;
+        let spend_info = TaprootSpendInfo::with_huffman_tree(
+            secp,
+            internal_key,
+            leaves.iter().map(|leaf| (1, leaf.clone())),
+        )?;
+        let address = Address::p2tr_tweaked(spend_info.output_key(), network);
+
+        let path = self.taproot_keys[&internal_key].clone();
+        self.remember(&address, path);
+        self.taproot_trees
+            .insert(address.script_pubkey(), spend_info);
+        self.taproot_leaves
+            .insert(address.script_pubkey(), leaves.to_vec());
+        Ok(address)
+    }
+}
+
+/// Address at `index` on a chain of the account with this xpub
+fn keychain_address(
+    account: &Xpub,
+    keychain: Keychain,
+    index: u32,
+    secp: &Secp256k1<All>,
+    network: Network,
+) -> Result<Address> {
+    let path = keychain.path(index)?;
+    let pubkey = account
+        .derive_pub(secp, &DerivationPath::from(&path.as_ref()[ACCOUNT_DEPTH..]))?
+        .to_pub();
+    script_address(keychain.address_type, &pubkey, secp, network)
+}
+
+/// Single-key address of the given type for a derived public key
+fn script_address(
+    address_type: AddressType,
+    pubkey: &bitcoin::PublicKey,
+    secp: &Secp256k1<All>,
+    network: Network,
+) -> Result<Address> {
+    Ok(match address_type {
+        AddressType::Legacy => Address::p2pkh(pubkey, network),
+        AddressType::SegwitP2SH => Address::p2shwpkh(pubkey, network)?,
+        AddressType::NativeSegwit => Address::p2wpkh(pubkey, network)?,
+        AddressType::Taproot => {
+            Address::p2tr(secp, XOnlyPublicKey::from(pubkey.inner), None, network)
+        }
+    })
+}
+
+// Supporting structures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletInfo {
+    pub user_id: String,
+    pub mnemonic: String,
+    pub xpub: String,
+    pub first_address: String,
+    pub network: Network,
+    /// Public output descriptor, for descriptor wallets
+    #[serde(default)]
+    pub descriptor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AddressType {
+    Legacy,
+    SegwitP2SH,
+    NativeSegwit,
+    /// BIP86 single-key P2TR
+    Taproot,
+}
+
+impl AddressType {
+    pub const ALL: [AddressType; 4] = [
+        AddressType::Legacy,
+        AddressType::SegwitP2SH,
+        AddressType::NativeSegwit,
+        AddressType::Taproot,
+    ];
+
+    /// BIP43 purpose of the derivation paths for this address type
+    pub fn purpose(self) -> u32 {
+        match self {
+            AddressType::Legacy => 44,
+            AddressType::SegwitP2SH => 49,
+            AddressType::NativeSegwit => 84,
+            AddressType::Taproot => 86,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BitcoinConfig {
+    pub network: Network,
+    pub rpc_endpoints: Vec<RpcEndpoint>,
+    /// Persist wallets to disk; state is kept in memory only when `None`
+    pub storage: Option<StorageConfig>,
+    /// Electrum server to sync and broadcast through instead of the RPC endpoints,
+    /// e.g. `ssl://electrum.blockstream.info:60002`. Self-signed certificates are pinned on
+    /// first use in `CertificatePins::default_location()`; when the server can't be reached
+    /// the RPC endpoints are used instead.
+    pub electrum_url: Option<String>,
+    /// Key unlock timeout and HSM signing
+    pub security: SecurityConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcEndpoint {
+    pub url: String,
+    pub user: String,
+    pub pass: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, TxOut, WPubkeyHash};
+    use bitcoincore_rpc::{Auth, Client};
+
+    #[tokio::test]
+    async fn unreachable_electrum_server_falls_back_to_rpc() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: Some("tcp://127.0.0.1:9".to_string()),
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        assert!(manager.electrum().is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_inscription_psbt_releases_its_coins() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        let inscription = InscriptionId {
+            txid: Txid::all_zeros(),
+            index: 0,
+        };
+        let utxo = |vout, value| TrackedUtxo {
+            outpoint: OutPoint::new(Txid::all_zeros(), vout),
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            },
+            confirmations: 6,
+        };
+        let (inscribed, funding) = (utxo(0, 10_000), utxo(1, 100_000));
+        {
+            let mut utxo_manager = manager.utxo_manager.write().await;
+            utxo_manager.update_utxos("nobody", [inscribed.clone(), funding.clone()]);
+            utxo_manager.freeze(
+                inscribed.outpoint,
+                FreezeReason::ProtectedSats(ProtectedSats {
+                    inscriptions: vec![(inscription, 0)],
+                    rare_sats: vec![],
+                }),
+            );
+        }
+
+        // Coins are selected, but there is no wallet to send the change to
+        let recipient = Address::p2wpkh(
+            &bitcoin::PublicKey::from_slice(&[2; 33]).unwrap(),
+            Network::Regtest,
+        )
+        .unwrap();
+        assert!(manager
+            .create_inscription_psbt(
+                "nobody",
+                &inscription,
+                &recipient,
+                FeeRate::from_sat_per_vb(2).unwrap()
+            )
+            .await
+            .is_err());
+
+        let mut utxo_manager = manager.utxo_manager.write().await;
+        utxo_manager.unfreeze(&inscribed.outpoint);
+        assert_eq!(utxo_manager.spendable_utxos("nobody", 0).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_psbts_release_their_coins() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        let utxo = TrackedUtxo {
+            outpoint: OutPoint::new(Txid::all_zeros(), 0),
+            txout: TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            },
+            confirmations: 6,
+        };
+        manager
+            .utxo_manager
+            .write()
+            .await
+            .update_utxos("nobody", [utxo.clone()]);
+        let recipient = Address::p2wpkh(
+            &bitcoin::PublicKey::from_slice(&[2; 33]).unwrap(),
+            Network::Regtest,
+        )
+        .unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+
+        // Coins are selected, but there is no wallet to send the change to or prepare for
+        assert!(manager
+            .create_psbt(
+                "nobody",
+                &[(recipient.clone(), 50_000)],
+                fee_rate,
+                SelectionStrategy::default()
+            )
+            .await
+            .is_err());
+        assert_eq!(
+            manager
+                .utxo_manager
+                .read()
+                .await
+                .spendable_utxos("nobody", 0)
+                .len(),
+            1
+        );
+
+        assert!(manager
+            .create_sweep_psbt("nobody", &[utxo.outpoint], &recipient, fee_rate)
+            .await
+            .is_err());
+        assert_eq!(
+            manager
+                .utxo_manager
+                .read()
+                .await
+                .spendable_utxos("nobody", 0)
+                .len(),
+            1
+        );
+    }
+
+    /// Run against a regtest node with
+    /// `BITCOIND_RPC_URL=http://127.0.0.1:18443 BITCOIND_RPC_USER=.. BITCOIND_RPC_PASS=..`
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_balance_with_failover() {
+        let node = RpcEndpoint {
+            url: std::env::var("BITCOIND_RPC_URL").unwrap(),
+            user: std::env::var("BITCOIND_RPC_USER").unwrap(),
+            pass: std::env::var("BITCOIND_RPC_PASS").unwrap(),
+        };
+        // The first endpoint is down, so every call has to fail over
+        let dead = RpcEndpoint {
+            url: "http://127.0.0.1:9".to_string(),
+            ..node.clone()
+        };
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![dead, node.clone()],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        let user = format!("regtest-{}", std::process::id());
+        manager.create_wallet(&user, None).await.unwrap();
+        let address = manager
+            .get_receive_address(&user, AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let address = Address::from_str(&address)
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap();
+
+        let client = Client::new(&node.url, Auth::UserPass(node.user, node.pass)).unwrap();
+        let funder = format!("{user}-funder");
+        client
+            .create_wallet(&funder, None, None, None, None)
+            .unwrap();
+        let funder = Client::new(
+            &format!("{}/wallet/{funder}", node.url.trim_end_matches('/')),
+            Auth::UserPass(
+                std::env::var("BITCOIND_RPC_USER").unwrap(),
+                std::env::var("BITCOIND_RPC_PASS").unwrap(),
+            ),
+        )
+        .unwrap();
+
+        // Coinbase outputs to our address count as confirmed
+        client.generate_to_address(1, &address).unwrap();
+        let balance = manager.get_balance(&user).await.unwrap();
+        assert_eq!(balance.confirmed, 50 * 100_000_000);
+        assert_eq!(balance.unconfirmed, 0);
+
+        // A mempool payment to us shows up as unconfirmed
+        let funder_address = funder
+            .get_new_address(None, None)
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap();
+        client.generate_to_address(101, &funder_address).unwrap();
+        funder
+            .send_to_address(
+                &address,
+                bitcoin::Amount::from_sat(100_000),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let balance = manager.get_balance(&user).await.unwrap();
+        assert_eq!(balance.confirmed, 50 * 100_000_000);
+        assert_eq!(balance.unconfirmed, 100_000);
+        assert_eq!(balance.total, balance.confirmed + balance.unconfirmed);
+
+        let health = manager.check_rpc_health().await;
+        assert!(!health[0].healthy);
+        assert!(health[1].healthy);
+    }
+
+    /// Run against a regtest node, see `regtest_balance_with_failover`
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_rescan_restored_wallet() {
+        let node = RpcEndpoint {
+            url: std::env::var("BITCOIND_RPC_URL").unwrap(),
+            user: std::env::var("BITCOIND_RPC_USER").unwrap(),
+            pass: std::env::var("BITCOIND_RPC_PASS").unwrap(),
+        };
+        let config = BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![node.clone()],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        };
+        let client = Client::new(&node.url, Auth::UserPass(node.user, node.pass)).unwrap();
+
+        let original = BitcoinWalletManager::new(config.clone()).await.unwrap();
+        let user = format!("rescan-{}", std::process::id());
+        let mnemonic = original.create_wallet(&user, None).await.unwrap().mnemonic;
+        let mut funded = vec![];
+        for _ in 0..9 {
+            funded.push(
+                original
+                    .get_receive_address(&user, AddressType::NativeSegwit)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let change = original
+            .get_change_address(&user, AddressType::Taproot)
+            .await
+            .unwrap();
+        // Index 8 is past a gap of 5 from index 0 and only found once index 4 is
+        for address in [&funded[4], &funded[8], &change] {
+            let address = Address::from_str(address)
+                .unwrap()
+                .require_network(Network::Regtest)
+                .unwrap();
+            client.generate_to_address(1, &address).unwrap();
+        }
+
+        let restored = BitcoinWalletManager::new(config)
+            .await
+            .unwrap()
+            .with_gap_limit(5);
+        let user = format!("{user}-restored");
+        restored.create_wallet(&user, Some(mnemonic)).await.unwrap();
+        let balance = restored.rescan_wallet(&user).await.unwrap();
+        assert_eq!(balance.confirmed, 3 * 50 * 100_000_000);
+
+        let wallets = restored.wallets.read().await;
+        let wallet = &wallets[&user];
+        assert_eq!(
+            wallet.next_index(Keychain::receive(AddressType::NativeSegwit)),
+            9
+        );
+        assert_eq!(wallet.next_index(Keychain::change(AddressType::Taproot)), 1);
+    }
 }
@@ -1 +1,329 @@
-// Channel manager module
+// Channel manager module: balances and in-flight HTLCs of payment channels, and the gossip
+// announcing them
+use super::routing::{ChannelPolicy, GossipMessage};
+use anyhow::Result;
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::PublicKey;
+use std::collections::HashMap;
+
+/// Most HTLCs either side may have in flight, from BOLT2
+pub const MAX_ACCEPTED_HTLCS: usize = 483;
+
+/// Short channel IDs pack the funding transaction's block height, index in the block and
+/// output index
+pub fn short_channel_id(block: u32, tx_index: u32, output: u16) -> u64 {
+    ((block as u64 & 0xff_ffff) << 40) | ((tx_index as u64 & 0xff_ffff) << 16) | output as u64
+}
+
+/// Short channel ID in the usual `blockxtxxoutput` form
+pub fn format_short_channel_id(scid: u64) -> String {
+    format!(
+        "{}x{}x{}",
+        scid >> 40,
+        (scid >> 16) & 0xff_ffff,
+        scid & 0xffff
+    )
+}
+
+pub fn parse_short_channel_id(s: &str) -> Result<u64> {
+    let parts: Vec<&str> = s.split('x').collect();
+    let [block, tx_index, output] = parts[..] else {
+        return Err(anyhow::anyhow!("Invalid short channel ID: {s}"));
+    };
+    Ok(short_channel_id(
+        block.parse()?,
+        tx_index.parse()?,
+        output.parse()?,
+    ))
+}
+
+/// An HTLC offered over a channel and not yet settled or failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Htlc {
+    pub id: u64,
+    /// Node that offered it, whose balance it was taken from
+    pub offered_by: PublicKey,
+    pub amount_msat: u64,
+    pub payment_hash: sha256::Hash,
+}
+
+/// A payment channel between two nodes
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub short_channel_id: u64,
+    pub nodes: [PublicKey; 2],
+    pub capacity_sats: u64,
+    /// Each node's forwarding policy, in the same order as `nodes`
+    pub policies: [ChannelPolicy; 2],
+    balances_msat: [u64; 2],
+    htlcs: Vec<Htlc>,
+    next_htlc_id: u64,
+}
+
+impl Channel {
+    /// Channel funded by `funder`, which pushes `push_msat` to `peer` on opening
+    pub fn new(
+        short_channel_id: u64,
+        funder: PublicKey,
+        peer: PublicKey,
+        capacity_sats: u64,
+        push_msat: u64,
+    ) -> Result<Self> {
+        if funder == peer {
+            return Err(anyhow::anyhow!("Can't open a channel to ourselves"));
+        }
+        let capacity_msat = capacity_sats * 1_000;
+        if push_msat > capacity_msat {
+            return Err(anyhow::anyhow!(
+                "Can't push more than the channel's capacity"
+            ));
+        }
+        Ok(Self {
+            short_channel_id,
+            nodes: [funder, peer],
+            capacity_sats,
+            policies: [ChannelPolicy::default(); 2],
+            balances_msat: [capacity_msat - push_msat, push_msat],
+            htlcs: vec![],
+            next_htlc_id: 0,
+        })
+    }
+
+    fn side(&self, node: &PublicKey) -> Result<usize> {
+        self.nodes.iter().position(|n| n == node).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{node} is not in channel {}",
+                format_short_channel_id(self.short_channel_id)
+            )
+        })
+    }
+
+    pub fn counterparty(&self, node: &PublicKey) -> Option<PublicKey> {
+        let side = self.side(node).ok()?;
+        Some(self.nodes[1 - side])
+    }
+
+    /// Balance of `node`, less what it has in flight
+    pub fn balance_msat(&self, node: &PublicKey) -> u64 {
+        self.side(node)
+            .map(|side| self.balances_msat[side])
+            .unwrap_or_default()
+    }
+
+    /// What `node` can still send, keeping the 1% channel reserve
+    pub fn outbound_msat(&self, node: &PublicKey) -> u64 {
+        let reserve_msat = self.capacity_sats * 10;
+        self.balance_msat(node).saturating_sub(reserve_msat)
+    }
+
+    pub fn pending_htlcs(&self) -> &[Htlc] {
+        &self.htlcs
+    }
+
+    pub fn set_policy(&mut self, node: &PublicKey, policy: ChannelPolicy) -> Result<()> {
+        let side = self.side(node)?;
+        self.policies[side] = policy;
+        Ok(())
+    }
+
+    /// Offer an HTLC from `from` to its counterparty, taking the amount out of its balance
+    /// until the HTLC is settled or failed
+    pub fn add_htlc(
+        &mut self,
+        from: &PublicKey,
+        amount_msat: u64,
+        payment_hash: sha256::Hash,
+    ) -> Result<u64> {
+        let side = self.side(from)?;
+        let policy = self.policies[side];
+        if policy.disabled {
+            return Err(anyhow::anyhow!("Channel is disabled"));
+        }
+        if amount_msat < policy.htlc_minimum_msat || amount_msat > policy.htlc_maximum_msat {
+            return Err(anyhow::anyhow!(
+                "HTLC of {amount_msat} msat is outside the channel's limits"
+            ));
+        }
+        if self.htlcs.iter().filter(|h| h.offered_by == *from).count() >= MAX_ACCEPTED_HTLCS {
+            return Err(anyhow::anyhow!("Too many HTLCs in flight"));
+        }
+        if amount_msat > self.outbound_msat(from) {
+            return Err(anyhow::anyhow!(
+                "Insufficient liquidity: {amount_msat} msat needed, {} available",
+                self.outbound_msat(from)
+            ));
+        }
+        self.balances_msat[side] -= amount_msat;
+        let id = self.next_htlc_id;
+        self.next_htlc_id += 1;
+        self.htlcs.push(Htlc {
+            id,
+            offered_by: *from,
+            amount_msat,
+            payment_hash,
+        });
+        Ok(id)
+    }
+
+    /// The recipient revealed the preimage: the amount moves to its side
+    pub fn settle_htlc(&mut self, id: u64) -> Result<()> {
+        let htlc = self.remove_htlc(id)?;
+        let side = self.side(&htlc.offered_by)?;
+        self.balances_msat[1 - side] += htlc.amount_msat;
+        Ok(())
+    }
+
+    /// The HTLC failed: the amount goes back to the node that offered it
+    pub fn fail_htlc(&mut self, id: u64) -> Result<()> {
+        let htlc = self.remove_htlc(id)?;
+        let side = self.side(&htlc.offered_by)?;
+        self.balances_msat[side] += htlc.amount_msat;
+        Ok(())
+    }
+
+    fn remove_htlc(&mut self, id: u64) -> Result<Htlc> {
+        let index = self
+            .htlcs
+            .iter()
+            .position(|h| h.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown HTLC {id}"))?;
+        Ok(self.htlcs.remove(index))
+    }
+
+    /// Announcement and both directions' updates, for feeding a channel graph
+    pub fn gossip(&self, timestamp: u32) -> Vec<GossipMessage> {
+        let mut messages = vec![GossipMessage::ChannelAnnouncement {
+            short_channel_id: self.short_channel_id,
+            node_1: self.nodes[0],
+            node_2: self.nodes[1],
+            capacity_sats: self.capacity_sats,
+        }];
+        for (source, policy) in self.nodes.iter().zip(self.policies) {
+            messages.push(GossipMessage::ChannelUpdate {
+                short_channel_id: self.short_channel_id,
+                source: *source,
+                timestamp,
+                policy,
+            });
+        }
+        messages
+    }
+}
+
+/// Channels by short channel ID, with IDs handed out as if each funding transaction confirmed
+/// in its own block
+#[derive(Debug, Clone)]
+pub struct ChannelManager {
+    channels: HashMap<u64, Channel>,
+    next_block: u32,
+}
+
+impl Default for ChannelManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelManager {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            next_block: 100,
+        }
+    }
+
+    pub fn open_channel(
+        &mut self,
+        funder: PublicKey,
+        peer: PublicKey,
+        capacity_sats: u64,
+        push_msat: u64,
+    ) -> Result<&Channel> {
+        let scid = short_channel_id(self.next_block, 1, 0);
+        let channel = Channel::new(scid, funder, peer, capacity_sats, push_msat)?;
+        self.next_block += 1;
+        Ok(self.channels.entry(scid).or_insert(channel))
+    }
+
+    /// Remove a channel; it can't close with HTLCs in flight
+    pub fn close_channel(&mut self, scid: u64) -> Result<Channel> {
+        let channel = self.channel(scid)?;
+        if !channel.htlcs.is_empty() {
+            return Err(anyhow::anyhow!("Channel has HTLCs in flight"));
+        }
+        Ok(self.channels.remove(&scid).expect("channel exists"))
+    }
+
+    pub fn channel(&self, scid: u64) -> Result<&Channel> {
+        self.channels
+            .get(&scid)
+            .ok_or_else(|| anyhow::anyhow!("Channel {} not found", format_short_channel_id(scid)))
+    }
+
+    pub fn channel_mut(&mut self, scid: u64) -> Result<&mut Channel> {
+        self.channels
+            .get_mut(&scid)
+            .ok_or_else(|| anyhow::anyhow!("Channel {} not found", format_short_channel_id(scid)))
+    }
+
+    /// Channels `node` is in, oldest first
+    pub fn channels_of(&self, node: &PublicKey) -> Vec<&Channel> {
+        let mut channels: Vec<&Channel> = self
+            .channels
+            .values()
+            .filter(|c| c.nodes.contains(node))
+            .collect();
+        channels.sort_by_key(|c| c.short_channel_id);
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+    fn key(i: u8) -> PublicKey {
+        SecretKey::from_slice(&[i; 32])
+            .unwrap()
+            .public_key(&Secp256k1::new())
+    }
+
+    #[test]
+    fn htlcs_move_balances() {
+        let (alice, bob) = (key(1), key(2));
+        let mut manager = ChannelManager::new();
+        let scid = manager
+            .open_channel(alice, bob, 100_000, 10_000_000)
+            .unwrap()
+            .short_channel_id;
+        assert_eq!(format_short_channel_id(scid), "100x1x0");
+        assert_eq!(parse_short_channel_id("100x1x0").unwrap(), scid);
+
+        let channel = manager.channel_mut(scid).unwrap();
+        assert_eq!(channel.balance_msat(&alice), 90_000_000);
+        // 1% of the capacity is held in reserve
+        assert_eq!(channel.outbound_msat(&bob), 9_000_000);
+        assert!(channel
+            .add_htlc(&bob, 9_000_001, sha256::Hash::hash(b"x"))
+            .is_err());
+
+        let settled = channel
+            .add_htlc(&alice, 5_000_000, sha256::Hash::hash(b"a"))
+            .unwrap();
+        let failed = channel
+            .add_htlc(&alice, 1_000_000, sha256::Hash::hash(b"b"))
+            .unwrap();
+        assert_eq!(channel.balance_msat(&alice), 84_000_000);
+        channel.settle_htlc(settled).unwrap();
+        channel.fail_htlc(failed).unwrap();
+        assert!(channel.settle_htlc(failed).is_err());
+        assert_eq!(channel.balance_msat(&alice), 85_000_000);
+        assert_eq!(channel.balance_msat(&bob), 15_000_000);
+
+        assert_eq!(manager.channels_of(&bob).len(), 1);
+        manager.close_channel(scid).unwrap();
+        assert!(manager.channels_of(&alice).is_empty());
+    }
+}
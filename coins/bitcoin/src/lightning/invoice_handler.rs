@@ -1 +1,932 @@
-// Invoice handler module
+// Invoice handler module: BOLT11 payment requests, decoded, verified and signed
+use anyhow::Result;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::script::PushBytesBuf;
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use bitcoin::{
+    Address, Network, PubkeyHash, ScriptBuf, ScriptHash, WitnessProgram, WitnessVersion,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Expiry when an invoice has no `x` field
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// Final hop CLTV delta when an invoice has no `c` field
+pub const DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA: u64 = 18;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const CHECKSUM_LEN: usize = 6;
+/// 35-bit timestamp
+const TIMESTAMP_LEN: usize = 7;
+/// 64-byte compact signature and its recovery id
+const SIGNATURE_LEN: usize = 104;
+/// Tagged field lengths are two 5-bit groups
+const MAX_FIELD_LEN: usize = 1023;
+/// Millisatoshis per bitcoin
+const MSAT_PER_BTC: u64 = 100_000_000_000;
+
+// Tagged field types, as the value of their bech32 character
+const TAG_PAYMENT_HASH: u8 = 1; // p
+const TAG_ROUTE_HINT: u8 = 3; // r
+const TAG_FEATURES: u8 = 5; // 9
+const TAG_EXPIRY: u8 = 6; // x
+const TAG_FALLBACK: u8 = 9; // f
+const TAG_DESCRIPTION: u8 = 13; // d
+const TAG_PAYMENT_SECRET: u8 = 16; // s
+const TAG_PAYEE: u8 = 19; // n
+const TAG_DESCRIPTION_HASH: u8 = 23; // h
+const TAG_MIN_FINAL_CLTV: u8 = 24; // c
+const TAG_METADATA: u8 = 27; // m
+
+/// Feature bits, as the even (required) bit of each pair
+pub const FEATURE_VAR_ONION: u16 = 8;
+pub const FEATURE_PAYMENT_SECRET: u16 = 14;
+pub const FEATURE_BASIC_MPP: u16 = 16;
+pub const FEATURE_PAYMENT_METADATA: u16 = 48;
+const KNOWN_FEATURES: [u16; 4] = [
+    FEATURE_VAR_ONION,
+    FEATURE_PAYMENT_SECRET,
+    FEATURE_BASIC_MPP,
+    FEATURE_PAYMENT_METADATA,
+];
+
+/// Feature bits set in an invoice's `9` field
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features(BTreeSet<u16>);
+
+impl Features {
+    /// Variable-length onions and payment secrets required, multi-part payments supported
+    pub fn standard() -> Self {
+        Self::default()
+            .with_bit(FEATURE_VAR_ONION)
+            .with_bit(FEATURE_PAYMENT_SECRET)
+            .with_bit(FEATURE_BASIC_MPP + 1)
+    }
+
+    pub fn with_bit(mut self, bit: u16) -> Self {
+        self.0.insert(bit);
+        self
+    }
+
+    pub fn is_set(&self, bit: u16) -> bool {
+        self.0.contains(&bit)
+    }
+
+    /// Whether the feature whose required bit is `feature` is set as required or optional
+    pub fn supports(&self, feature: u16) -> bool {
+        self.is_set(feature & !1) || self.is_set(feature | 1)
+    }
+
+    /// Required bits of features this wallet doesn't know, which make an invoice unpayable
+    pub fn unknown_required(&self) -> Vec<u16> {
+        self.0
+            .iter()
+            .copied()
+            .filter(|bit| bit % 2 == 0 && !KNOWN_FEATURES.contains(bit))
+            .collect()
+    }
+
+    fn from_groups(groups: &[u8]) -> Self {
+        let mut bits = BTreeSet::new();
+        for (position, group) in groups.iter().rev().enumerate() {
+            for bit in 0..5 {
+                if group >> bit & 1 == 1 {
+                    bits.insert((position * 5 + bit) as u16);
+                }
+            }
+        }
+        Self(bits)
+    }
+
+    fn to_groups(&self) -> Vec<u8> {
+        let Some(highest) = self.0.last() else {
+            return vec![];
+        };
+        let mut groups = vec![0u8; *highest as usize / 5 + 1];
+        let len = groups.len();
+        for bit in &self.0 {
+            groups[len - 1 - *bit as usize / 5] |= 1 << (bit % 5);
+        }
+        groups
+    }
+}
+
+/// One hop of a private route to the payee
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteHintHop {
+    pub src_node_id: PublicKey,
+    pub short_channel_id: u64,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub cltv_expiry_delta: u16,
+}
+
+/// A private route ending at the payee, from an `r` field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteHint(pub Vec<RouteHintHop>);
+
+/// Encoded size of a route hint hop
+const ROUTE_HINT_HOP_LEN: usize = 51;
+
+impl RouteHint {
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(ROUTE_HINT_HOP_LEN) {
+            return Err(anyhow::anyhow!("Malformed route hint"));
+        }
+        let hops = bytes
+            .chunks(ROUTE_HINT_HOP_LEN)
+            .map(|hop| {
+                Ok(RouteHintHop {
+                    src_node_id: PublicKey::from_slice(&hop[..33])?,
+                    short_channel_id: u64::from_be_bytes(hop[33..41].try_into()?),
+                    fee_base_msat: u32::from_be_bytes(hop[41..45].try_into()?),
+                    fee_proportional_millionths: u32::from_be_bytes(hop[45..49].try_into()?),
+                    cltv_expiry_delta: u16::from_be_bytes(hop[49..51].try_into()?),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(hops))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|hop| {
+                [
+                    &hop.src_node_id.serialize()[..],
+                    &hop.short_channel_id.to_be_bytes(),
+                    &hop.fee_base_msat.to_be_bytes(),
+                    &hop.fee_proportional_millionths.to_be_bytes(),
+                    &hop.cltv_expiry_delta.to_be_bytes(),
+                ]
+                .concat()
+            })
+            .collect()
+    }
+}
+
+/// A decoded BOLT11 invoice whose signature has been checked against its payee
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bolt11Invoice {
+    pub network: Network,
+    pub amount_msat: Option<u64>,
+    /// Creation time, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub payment_hash: sha256::Hash,
+    pub payment_secret: Option<[u8; 32]>,
+    pub description: Option<String>,
+    pub description_hash: Option<sha256::Hash>,
+    /// Node to pay, from the `n` field or recovered from the signature
+    pub payee: PublicKey,
+    pub expiry: Duration,
+    pub min_final_cltv_expiry_delta: u64,
+    /// On-chain addresses to pay instead
+    pub fallbacks: Vec<Address>,
+    pub route_hints: Vec<RouteHint>,
+    pub features: Features,
+    pub metadata: Option<Vec<u8>>,
+    encoded: String,
+}
+
+impl Bolt11Invoice {
+    /// Seconds since the Unix epoch at which the invoice expires
+    pub fn expires_at(&self) -> u64 {
+        self.timestamp.saturating_add(self.expiry.as_secs())
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now >= self.expires_at()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(unix_time())
+    }
+
+    /// Check that the invoice can be paid on `network` now
+    pub fn validate(&self, network: Network) -> Result<()> {
+        if self.network != network {
+            return Err(anyhow::anyhow!(
+                "Invoice is for {}, not {network}",
+                self.network
+            ));
+        }
+        if self.is_expired() {
+            return Err(anyhow::anyhow!("Invoice expired at {}", self.expires_at()));
+        }
+        if self.payment_secret.is_none() {
+            return Err(anyhow::anyhow!("Invoice has no payment secret"));
+        }
+        let unknown = self.features.unknown_required();
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invoice requires unknown features {unknown:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = match s.get(..10) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("lightning:") => &s[10..],
+            _ => s,
+        };
+        let (hrp, data) = bech32_decode(s)?;
+        let (network, amount_msat) = parse_hrp(&hrp)?;
+        if data.len() < TIMESTAMP_LEN + SIGNATURE_LEN {
+            return Err(anyhow::anyhow!("Invoice is too short"));
+        }
+        let (signed, signature) = data.split_at(data.len() - SIGNATURE_LEN);
+        let timestamp = read_int(&signed[..TIMESTAMP_LEN]);
+
+        let mut payment_hash = None;
+        let mut payment_secret = None;
+        let mut description = None;
+        let mut description_hash = None;
+        let mut payee = None;
+        let mut expiry = DEFAULT_EXPIRY;
+        let mut min_final_cltv_expiry_delta = DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA;
+        let mut fallbacks = vec![];
+        let mut route_hints = vec![];
+        let mut features = Features::default();
+        let mut metadata = None;
+
+        let mut fields = &signed[TIMESTAMP_LEN..];
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(anyhow::anyhow!("Truncated tagged field"));
+            }
+            let len = (fields[1] as usize) << 5 | fields[2] as usize;
+            let value = fields
+                .get(3..3 + len)
+                .ok_or_else(|| anyhow::anyhow!("Truncated tagged field"))?;
+            // Fields of the wrong length are skipped, as readers must
+            match (fields[0], len) {
+                (TAG_PAYMENT_HASH, 52) if payment_hash.is_none() => {
+                    payment_hash = Some(sha256::Hash::from_slice(&from_groups(value)?)?);
+                }
+                (TAG_PAYMENT_SECRET, 52) if payment_secret.is_none() => {
+                    payment_secret = Some(from_groups(value)?.as_slice().try_into()?);
+                }
+                (TAG_DESCRIPTION, _) => description = Some(String::from_utf8(from_groups(value)?)?),
+                (TAG_DESCRIPTION_HASH, 52) => {
+                    description_hash = Some(sha256::Hash::from_slice(&from_groups(value)?)?);
+                }
+                (TAG_PAYEE, 53) => payee = Some(PublicKey::from_slice(&from_groups(value)?)?),
+                (TAG_EXPIRY, _) => expiry = Duration::from_secs(read_int(value)),
+                (TAG_MIN_FINAL_CLTV, _) => min_final_cltv_expiry_delta = read_int(value),
+                (TAG_FALLBACK, 1..) => {
+                    if let Some(address) = fallback_address(value, network)? {
+                        fallbacks.push(address);
+                    }
+                }
+                (TAG_ROUTE_HINT, _) => {
+                    route_hints.push(RouteHint::from_bytes(&from_groups(value)?)?)
+                }
+                (TAG_FEATURES, _) => features = Features::from_groups(value),
+                (TAG_METADATA, _) => metadata = Some(from_groups(value)?),
+                _ => {}
+            }
+            fields = &fields[3 + len..];
+        }
+
+        let payment_hash =
+            payment_hash.ok_or_else(|| anyhow::anyhow!("Invoice has no payment hash"))?;
+        let payee = verify_signature(&hrp, signed, signature, payee)?;
+        Ok(Self {
+            network,
+            amount_msat,
+            timestamp,
+            payment_hash,
+            payment_secret,
+            description,
+            description_hash,
+            payee,
+            expiry,
+            min_final_cltv_expiry_delta,
+            fallbacks,
+            route_hints,
+            features,
+            metadata,
+            encoded: s.to_lowercase(),
+        })
+    }
+}
+
+impl FromStr for Bolt11Invoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::decode(s)
+    }
+}
+
+impl fmt::Display for Bolt11Invoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encoded)
+    }
+}
+
+/// Builds and signs BOLT11 invoices with a node key
+#[derive(Debug, Clone)]
+pub struct InvoiceBuilder {
+    network: Network,
+    amount_msat: Option<u64>,
+    timestamp: Option<u64>,
+    payment_hash: sha256::Hash,
+    payment_secret: [u8; 32],
+    description: Option<String>,
+    description_hash: Option<sha256::Hash>,
+    include_payee: bool,
+    expiry: Duration,
+    min_final_cltv_expiry_delta: u64,
+    fallbacks: Vec<Address>,
+    route_hints: Vec<RouteHint>,
+    features: Features,
+    metadata: Option<Vec<u8>>,
+}
+
+impl InvoiceBuilder {
+    pub fn new(network: Network, payment_hash: sha256::Hash, payment_secret: [u8; 32]) -> Self {
+        Self {
+            network,
+            amount_msat: None,
+            timestamp: None,
+            payment_hash,
+            payment_secret,
+            description: None,
+            description_hash: None,
+            include_payee: false,
+            expiry: DEFAULT_EXPIRY,
+            min_final_cltv_expiry_delta: DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA,
+            fallbacks: vec![],
+            route_hints: vec![],
+            features: Features::standard(),
+            metadata: None,
+        }
+    }
+
+    pub fn with_amount_msat(mut self, amount_msat: u64) -> Self {
+        self.amount_msat = Some(amount_msat);
+        self
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Commit to a description too long for the invoice by its hash
+    pub fn with_description_hash(mut self, hash: sha256::Hash) -> Self {
+        self.description_hash = Some(hash);
+        self
+    }
+
+    /// Creation time in seconds since the Unix epoch; now by default
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn with_min_final_cltv_expiry_delta(mut self, delta: u64) -> Self {
+        self.min_final_cltv_expiry_delta = delta;
+        self
+    }
+
+    pub fn with_fallback(mut self, address: Address) -> Self {
+        self.fallbacks.push(address);
+        self
+    }
+
+    pub fn with_route_hint(mut self, hint: RouteHint) -> Self {
+        self.route_hints.push(hint);
+        self
+    }
+
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Write the payee's node id in an `n` field instead of leaving it to key recovery
+    pub fn with_payee_field(mut self) -> Self {
+        self.include_payee = true;
+        self
+    }
+
+    /// Encode the invoice and sign it with the payee's node key
+    pub fn build_signed(self, node_key: &SecretKey) -> Result<Bolt11Invoice> {
+        let secp = Secp256k1::new();
+        if self.description.is_some() == self.description_hash.is_some() {
+            return Err(anyhow::anyhow!(
+                "An invoice needs either a description or a description hash"
+            ));
+        }
+        let hrp = format!(
+            "ln{}{}",
+            currency_prefix(self.network)?,
+            self.amount_msat
+                .map(encode_amount)
+                .transpose()?
+                .unwrap_or_default()
+        );
+
+        let timestamp = self.timestamp.unwrap_or_else(unix_time);
+        if timestamp >= 1 << 35 {
+            return Err(anyhow::anyhow!("Timestamp out of range"));
+        }
+        let mut data = int_groups(timestamp, TIMESTAMP_LEN);
+        push_field(
+            &mut data,
+            TAG_PAYMENT_HASH,
+            to_groups(self.payment_hash.as_byte_array()),
+        )?;
+        push_field(
+            &mut data,
+            TAG_PAYMENT_SECRET,
+            to_groups(&self.payment_secret),
+        )?;
+        if let Some(description) = &self.description {
+            push_field(
+                &mut data,
+                TAG_DESCRIPTION,
+                to_groups(description.as_bytes()),
+            )?;
+        }
+        if let Some(hash) = &self.description_hash {
+            push_field(
+                &mut data,
+                TAG_DESCRIPTION_HASH,
+                to_groups(hash.as_byte_array()),
+            )?;
+        }
+        if self.include_payee {
+            let payee = node_key.public_key(&secp).serialize();
+            push_field(&mut data, TAG_PAYEE, to_groups(&payee))?;
+        }
+        if self.expiry != DEFAULT_EXPIRY {
+            push_field(&mut data, TAG_EXPIRY, min_int_groups(self.expiry.as_secs()))?;
+        }
+        if self.min_final_cltv_expiry_delta != DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA {
+            push_field(
+                &mut data,
+                TAG_MIN_FINAL_CLTV,
+                min_int_groups(self.min_final_cltv_expiry_delta),
+            )?;
+        }
+        for address in &self.fallbacks {
+            push_field(&mut data, TAG_FALLBACK, fallback_groups(address)?)?;
+        }
+        for hint in &self.route_hints {
+            push_field(&mut data, TAG_ROUTE_HINT, to_groups(&hint.to_bytes()))?;
+        }
+        if self.features != Features::default() {
+            push_field(&mut data, TAG_FEATURES, self.features.to_groups())?;
+        }
+        if let Some(metadata) = &self.metadata {
+            push_field(&mut data, TAG_METADATA, to_groups(metadata))?;
+        }
+
+        let msg = signing_message(&hrp, &data);
+        let (recovery_id, compact) = secp
+            .sign_ecdsa_recoverable(&msg, node_key)
+            .serialize_compact();
+        let mut signature = compact.to_vec();
+        signature.push(recovery_id.to_i32() as u8);
+        data.extend(to_groups(&signature));
+
+        Bolt11Invoice::decode(&bech32_encode(&hrp, &data))
+    }
+}
+
+/// Check the invoice signature, returning the payee: the `n` field's key if present,
+/// otherwise the key recovered from the signature
+fn verify_signature(
+    hrp: &str,
+    signed: &[u8],
+    signature: &[u8],
+    payee: Option<PublicKey>,
+) -> Result<PublicKey> {
+    let secp = Secp256k1::verification_only();
+    let bytes = from_groups(signature)?;
+    let recovery_id = RecoveryId::from_i32(bytes[64] as i32)?;
+    let signature = RecoverableSignature::from_compact(&bytes[..64], recovery_id)?;
+    let msg = signing_message(hrp, signed);
+    match payee {
+        Some(payee) => {
+            secp.verify_ecdsa(&msg, &signature.to_standard(), &payee)
+                .map_err(|_| anyhow::anyhow!("Invoice signature doesn't match its payee"))?;
+            Ok(payee)
+        }
+        None => {
+            let payee = secp.recover_ecdsa(&msg, &signature)?;
+            // Recovery accepts high-S signatures, which BOLT11 readers must reject
+            secp.verify_ecdsa(&msg, &signature.to_standard(), &payee)
+                .map_err(|_| anyhow::anyhow!("Invoice signature is not normalized"))?;
+            Ok(payee)
+        }
+    }
+}
+
+/// SHA256 of the human-readable part and the data before the signature, as bytes
+fn signing_message(hrp: &str, data: &[u8]) -> Message {
+    let preimage = [hrp.as_bytes(), &to_bytes_padded(data)].concat();
+    Message::from_digest(sha256::Hash::hash(&preimage).to_byte_array())
+}
+
+fn currency_prefix(network: Network) -> Result<&'static str> {
+    match network {
+        Network::Bitcoin => Ok("bc"),
+        Network::Testnet => Ok("tb"),
+        Network::Signet => Ok("tbs"),
+        Network::Regtest => Ok("bcrt"),
+        // Every network is matched above; this covers any a later bitcoin release adds
+        #[allow(unreachable_patterns)]
+        _ => Err(anyhow::anyhow!("No invoice prefix for {network}")),
+    }
+}
+
+/// Network and amount from a human-readable part such as `lnbc2500u`
+fn parse_hrp(hrp: &str) -> Result<(Network, Option<u64>)> {
+    let rest = hrp
+        .strip_prefix("ln")
+        .ok_or_else(|| anyhow::anyhow!("Not a lightning invoice"))?;
+    // Longest prefixes first, since `bc` also starts `bcrt`
+    let (network, amount) = [
+        ("bcrt", Network::Regtest),
+        ("bc", Network::Bitcoin),
+        ("tbs", Network::Signet),
+        ("tb", Network::Testnet),
+    ]
+    .into_iter()
+    .find_map(|(prefix, network)| rest.strip_prefix(prefix).map(|amount| (network, amount)))
+    .ok_or_else(|| anyhow::anyhow!("Unknown invoice currency in {hrp}"))?;
+    if amount.is_empty() {
+        return Ok((network, None));
+    }
+
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    if digits.is_empty() || digits.starts_with('0') || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow::anyhow!("Malformed invoice amount {amount}"));
+    }
+    let value: u64 = digits.parse()?;
+    let amount_msat = match multiplier {
+        None => value.checked_mul(MSAT_PER_BTC),
+        Some('m') => value.checked_mul(MSAT_PER_BTC / 1_000),
+        Some('u') => value.checked_mul(MSAT_PER_BTC / 1_000_000),
+        Some('n') => value.checked_mul(MSAT_PER_BTC / 1_000_000_000),
+        // A pico-bitcoin is a tenth of a millisatoshi
+        Some('p') if value.is_multiple_of(10) => Some(value / 10),
+        Some('p') => return Err(anyhow::anyhow!("Amount {amount} is below a millisatoshi")),
+        Some(c) => return Err(anyhow::anyhow!("Unknown amount multiplier {c}")),
+    }
+    .ok_or_else(|| anyhow::anyhow!("Amount {amount} is too large"))?;
+    Ok((network, Some(amount_msat)))
+}
+
+/// Shortest amount in the human-readable part for a millisatoshi value
+fn encode_amount(amount_msat: u64) -> Result<String> {
+    if amount_msat == 0 {
+        return Err(anyhow::anyhow!("Invoice amounts must be positive"));
+    }
+    for (multiplier, msat) in [
+        ("", MSAT_PER_BTC),
+        ("m", MSAT_PER_BTC / 1_000),
+        ("u", MSAT_PER_BTC / 1_000_000),
+        ("n", MSAT_PER_BTC / 1_000_000_000),
+    ] {
+        if amount_msat.is_multiple_of(msat) {
+            return Ok(format!("{}{multiplier}", amount_msat / msat));
+        }
+    }
+    Ok(format!("{}p", amount_msat * 10))
+}
+
+/// On-chain fallback from an `f` field: a witness version or 17 (P2PKH) or 18 (P2SH), then
+/// the hash or witness program. Unknown versions are skipped.
+fn fallback_address(value: &[u8], network: Network) -> Result<Option<Address>> {
+    let program = from_groups(&value[1..])?;
+    let script = match value[0] {
+        17 => ScriptBuf::new_p2pkh(&PubkeyHash::from_slice(&program)?),
+        18 => ScriptBuf::new_p2sh(&ScriptHash::from_slice(&program)?),
+        version @ 0..=16 => {
+            let version = WitnessVersion::try_from(version)?;
+            let program = PushBytesBuf::try_from(program)?;
+            ScriptBuf::new_witness_program(&WitnessProgram::new(version, program)?)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(Address::from_script(&script, network)?))
+}
+
+fn fallback_groups(address: &Address) -> Result<Vec<u8>> {
+    let script = address.script_pubkey();
+    let (version, program) = if script.is_p2pkh() {
+        (17, script.as_bytes()[3..23].to_vec())
+    } else if script.is_p2sh() {
+        (18, script.as_bytes()[2..22].to_vec())
+    } else if let Some(version) = script.witness_version() {
+        (version.to_num(), script.as_bytes()[2..].to_vec())
+    } else {
+        return Err(anyhow::anyhow!("{address} can't be an invoice fallback"));
+    };
+    Ok([vec![version], to_groups(&program)].concat())
+}
+
+fn push_field(data: &mut Vec<u8>, tag: u8, value: Vec<u8>) -> Result<()> {
+    if value.len() > MAX_FIELD_LEN {
+        return Err(anyhow::anyhow!("Invoice field is too long"));
+    }
+    data.extend([tag, (value.len() >> 5) as u8, (value.len() & 31) as u8]);
+    data.extend(value);
+    Ok(())
+}
+
+/// Big-endian integer from 5-bit groups
+fn read_int(groups: &[u8]) -> u64 {
+    groups.iter().fold(0, |n, g| n << 5 | *g as u64)
+}
+
+/// Big-endian integer in exactly `len` 5-bit groups
+fn int_groups(n: u64, len: usize) -> Vec<u8> {
+    (0..len).rev().map(|i| (n >> (5 * i) & 31) as u8).collect()
+}
+
+/// Big-endian integer in as few 5-bit groups as it needs
+fn min_int_groups(n: u64) -> Vec<u8> {
+    let len = (64 - n.leading_zeros() as usize).div_ceil(5);
+    int_groups(n, len)
+}
+
+/// Bytes as 5-bit groups, zero-padded
+fn to_groups(bytes: &[u8]) -> Vec<u8> {
+    convert_bits(bytes, 8, 5, true).expect("padding never fails")
+}
+
+/// 5-bit groups as bytes; leftover bits must be zero padding
+fn from_groups(groups: &[u8]) -> Result<Vec<u8>> {
+    convert_bits(groups, 5, 8, false)
+}
+
+/// 5-bit groups as bytes with the last byte zero-padded, as signed
+fn to_bytes_padded(groups: &[u8]) -> Vec<u8> {
+    convert_bits(groups, 5, 8, true).expect("padding never fails")
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut out = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let max = (1 << to) - 1;
+    for value in data {
+        acc = acc << from | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push((acc >> bits & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            out.push((acc << (to - bits) & max) as u8);
+        }
+    } else if bits >= from || acc << (to - bits) & max != 0 {
+        return Err(anyhow::anyhow!("Invalid padding"));
+    }
+    Ok(out)
+}
+
+fn polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    values.into_iter().fold(1, |chk, value| {
+        let top = chk >> 25;
+        let chk = (chk & 0x1ffffff) << 5 ^ value as u32;
+        GENERATOR
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| top >> i & 1 == 1)
+            .fold(chk, |chk, (_, g)| chk ^ g)
+    })
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    hrp.bytes()
+        .map(|b| b >> 5)
+        .chain([0])
+        .chain(hrp.bytes().map(|b| b & 31))
+        .collect()
+}
+
+/// Bech32 without the 90 character limit, which invoices routinely exceed
+fn bech32_encode(hrp: &str, data: &[u8]) -> String {
+    let values = [hrp_expand(hrp), data.to_vec(), vec![0; CHECKSUM_LEN]].concat();
+    let checksum = polymod(values) ^ 1;
+    let checksum = (0..CHECKSUM_LEN).map(|i| (checksum >> (5 * (5 - i)) & 31) as u8);
+    let chars: String = data
+        .iter()
+        .copied()
+        .chain(checksum)
+        .map(|g| CHARSET[g as usize] as char)
+        .collect();
+    format!("{hrp}1{chars}")
+}
+
+fn bech32_decode(s: &str) -> Result<(String, Vec<u8>)> {
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(anyhow::anyhow!("Invoice mixes upper and lower case"));
+    }
+    let s = s.to_lowercase();
+    let (hrp, data) = s
+        .rsplit_once('1')
+        .ok_or_else(|| anyhow::anyhow!("Invoice has no separator"))?;
+    if hrp.is_empty() || data.len() < CHECKSUM_LEN {
+        return Err(anyhow::anyhow!("Invoice is too short"));
+    }
+    let data = data
+        .bytes()
+        .map(|c| {
+            CHARSET
+                .iter()
+                .position(|x| *x == c)
+                .map(|g| g as u8)
+                .ok_or_else(|| anyhow::anyhow!("Invalid character {}", c as char))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    if polymod(hrp_expand(hrp).into_iter().chain(data.iter().copied())) != 1 {
+        return Err(anyhow::anyhow!("Invalid invoice checksum"));
+    }
+    Ok((hrp.to_string(), data[..data.len() - CHECKSUM_LEN].to_vec()))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_key() -> SecretKey {
+        SecretKey::from_slice(&[0x11; 32]).unwrap()
+    }
+
+    /// The donation example from BOLT11, with its payee recovered from the signature
+    #[test]
+    fn decodes_spec_vector() {
+        let invoice: Bolt11Invoice = "lightning:lnbc1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq9qrsgq357wnc5r2ueh7ck6q93dj32dlqnls087fxdwk8qakdyafkq3yap9us6v52vjjsrvywa6rt52cm9r9zqt8r2t7mlcwspyetp5h2tztugp9lfyql"
+            .parse()
+            .unwrap();
+        assert_eq!(invoice.network, Network::Bitcoin);
+        assert_eq!(invoice.amount_msat, None);
+        assert_eq!(invoice.timestamp, 1_496_314_658);
+        assert_eq!(
+            invoice.payment_hash.to_byte_array()[..4],
+            [0x00, 0x01, 0x02, 0x03]
+        );
+        assert_eq!(invoice.payment_secret, Some([0x11; 32]));
+        assert_eq!(
+            invoice.description.as_deref(),
+            Some("Please consider supporting this project")
+        );
+        assert_eq!(
+            invoice.payee.to_string(),
+            "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad"
+        );
+        assert!(invoice.features.supports(FEATURE_PAYMENT_SECRET));
+        assert!(invoice.is_expired());
+    }
+
+    #[test]
+    fn round_trips_every_field() {
+        let secp = Secp256k1::new();
+        let payee = node_key().public_key(&secp);
+        let payment_hash = sha256::Hash::hash(b"preimage");
+        let hint = RouteHint(vec![RouteHintHop {
+            src_node_id: payee,
+            short_channel_id: 0x0102_0304_0506_0708,
+            fee_base_msat: 1_000,
+            fee_proportional_millionths: 20,
+            cltv_expiry_delta: 144,
+        }]);
+        let fallback = Address::p2pkh(&bitcoin::PublicKey::new(payee), Network::Testnet);
+        let segwit_fallback =
+            Address::p2wpkh(&bitcoin::PublicKey::new(payee), Network::Testnet).unwrap();
+        let invoice = InvoiceBuilder::new(Network::Testnet, payment_hash, [7; 32])
+            .with_amount_msat(2_500_000_000)
+            .with_description("coffee")
+            .with_timestamp(1_700_000_000)
+            .with_expiry(Duration::from_secs(600))
+            .with_min_final_cltv_expiry_delta(40)
+            .with_fallback(fallback.clone())
+            .with_fallback(segwit_fallback.clone())
+            .with_route_hint(hint.clone())
+            .with_metadata(vec![1, 2, 3])
+            .with_payee_field()
+            .build_signed(&node_key())
+            .unwrap();
+        assert!(invoice.to_string().starts_with("lntb25m1"));
+
+        let decoded: Bolt11Invoice = invoice.to_string().to_uppercase().parse().unwrap();
+        assert_eq!(decoded, invoice);
+        assert_eq!(decoded.amount_msat, Some(2_500_000_000));
+        assert_eq!(decoded.payment_hash, payment_hash);
+        assert_eq!(decoded.payment_secret, Some([7; 32]));
+        assert_eq!(decoded.description.as_deref(), Some("coffee"));
+        assert_eq!(decoded.payee, payee);
+        assert_eq!(decoded.expires_at(), 1_700_000_600);
+        assert_eq!(decoded.min_final_cltv_expiry_delta, 40);
+        assert_eq!(decoded.fallbacks, vec![fallback, segwit_fallback]);
+        assert_eq!(decoded.route_hints, vec![hint]);
+        assert_eq!(decoded.metadata, Some(vec![1, 2, 3]));
+        assert!(decoded.features.supports(FEATURE_PAYMENT_SECRET));
+        assert!(decoded.features.supports(FEATURE_BASIC_MPP));
+        assert!(decoded.features.unknown_required().is_empty());
+
+        // Expired long ago, and for testnet
+        assert!(decoded.validate(Network::Bitcoin).is_err());
+        assert!(decoded.validate(Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn recovers_payee_and_rejects_tampering() {
+        let secp = Secp256k1::new();
+        let invoice = InvoiceBuilder::new(Network::Bitcoin, sha256::Hash::hash(b"x"), [1; 32])
+            .with_amount_msat(1)
+            .with_description("tip")
+            .build_signed(&node_key())
+            .unwrap();
+        let encoded = invoice.to_string();
+        assert!(encoded.starts_with("lnbc10p1"));
+        assert_eq!(invoice.payee, node_key().public_key(&secp));
+        assert_eq!(invoice.expiry, DEFAULT_EXPIRY);
+        invoice.validate(Network::Bitcoin).unwrap();
+        assert!(invoice.validate(Network::Regtest).is_err());
+
+        // A different amount breaks the signature's payee, or the checksum
+        let tampered = encoded.replacen("lnbc10p", "lnbc20p", 1);
+        assert!(
+            tampered
+                .parse::<Bolt11Invoice>()
+                .is_ok_and(|i| i.payee != invoice.payee)
+                || tampered.parse::<Bolt11Invoice>().is_err()
+        );
+        let mut flipped = encoded.into_bytes();
+        let last = flipped.len() - 10;
+        flipped[last] = if flipped[last] == b'q' { b'p' } else { b'q' };
+        assert!(String::from_utf8(flipped)
+            .unwrap()
+            .parse::<Bolt11Invoice>()
+            .is_err());
+    }
+
+    #[test]
+    fn parses_amounts() {
+        assert_eq!(parse_hrp("lnbc").unwrap(), (Network::Bitcoin, None));
+        assert_eq!(
+            parse_hrp("lnbcrt2500u").unwrap(),
+            (Network::Regtest, Some(250_000_000))
+        );
+        assert_eq!(
+            parse_hrp("lntbs1").unwrap(),
+            (Network::Signet, Some(MSAT_PER_BTC))
+        );
+        assert!(parse_hrp("lnbc1p").is_err());
+        assert!(parse_hrp("lnbc010n").is_err());
+        assert!(parse_hrp("lnxy1").is_err());
+        for msat in [1, 1_000, 250_000_000, MSAT_PER_BTC, 123_456_789] {
+            let hrp = format!("lnbc{}", encode_amount(msat).unwrap());
+            assert_eq!(parse_hrp(&hrp).unwrap().1, Some(msat));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_required_features() {
+        let invoice = InvoiceBuilder::new(Network::Bitcoin, sha256::Hash::hash(b"y"), [2; 32])
+            .with_description("future")
+            .with_features(Features::standard().with_bit(100))
+            .build_signed(&node_key())
+            .unwrap();
+        assert_eq!(invoice.features.unknown_required(), vec![100]);
+        assert!(invoice.validate(Network::Bitcoin).is_err());
+    }
+}
@@ -1,49 +1,1234 @@
-use ldk_node::{Builder, Node};
-use ldk_node::lightning_invoice::Bolt11Invoice;
-use bitcoin::Network;
+// LDK module: an in-process Lightning node whose channels are funded and swept through the
+// Bitcoin wallet, with its channel state persisted under a storage directory
+use super::{Balance, Bolt11Invoice, ChannelInfo, Invoice, NodeInfo, Payment, PaymentStatus};
+use crate::transaction_builder::psbt_handler;
+use crate::{AddressType, BitcoinWalletManager, FeePriority};
+use ::lightning::chain::chaininterface::{
+    BroadcasterInterface, ConfirmationTarget, FeeEstimator, FEERATE_FLOOR_SATS_PER_KW,
+};
+use ::lightning::chain::chainmonitor;
+use ::lightning::chain::transaction::TransactionData;
+use ::lightning::chain::{BestBlock, Filter, Listen, Watch};
+use ::lightning::events::{Event, PaymentPurpose};
+use ::lightning::ln::channelmanager::{
+    self, ChainParameters, ChannelManagerReadArgs, PaymentId, RecipientOnionFields, Retry,
+};
+use ::lightning::ln::peer_handler::{self, IgnoringMessageHandler, MessageHandler};
+use ::lightning::ln::{ChannelId, PaymentHash};
+use ::lightning::routing::gossip::{NetworkGraph, P2PGossipSync};
+use ::lightning::routing::router::{DefaultRouter, RouteParameters};
+use ::lightning::routing::scoring::{
+    ProbabilisticScorer, ProbabilisticScoringDecayParameters, ProbabilisticScoringFeeParameters,
+};
+use ::lightning::routing::utxo::UtxoLookup;
+use ::lightning::sign::{
+    EntropySource, InMemorySigner, KeysManager, NodeSigner, Recipient, SpendableOutputDescriptor,
+};
+use ::lightning::util::config::UserConfig;
+use ::lightning::util::logger::{Level, Logger, Record};
+use ::lightning::util::persist::{self, KVStore};
+use ::lightning::util::ser::{Readable, ReadableArgs, Writeable};
+use anyhow::Result;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Address, Network, ScriptBuf};
+use ldk_bitcoin::secp256k1::PublicKey as NodeId;
+use lightning_invoice::Currency;
+use lightning_net_tokio::SocketDescriptor;
+use lightning_persister::fs_store::FilesystemStore;
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
+/// How long a payment keeps being retried over new routes
+const PAYMENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for a peer to accept a channel and its funding to be broadcast
+const CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for the noise handshake with a new peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Invoices created by the node expire after an hour
+const INVOICE_EXPIRY_SECS: u32 = 3600;
+/// Store key of the node id the storage directory belongs to
+const NODE_ID_KEY: &str = "node_id";
+/// Store namespace channel outputs wait in until they have been swept to the wallet
+const SPENDABLE_OUTPUTS_NAMESPACE: &str = "spendable_outputs";
+
+type ChainMonitor = chainmonitor::ChainMonitor<
+    InMemorySigner,
+    Arc<dyn Filter + Send + Sync>,
+    Arc<Broadcaster>,
+    Arc<FeeRates>,
+    Arc<LdkLogger>,
+    Arc<FilesystemStore>,
+>;
+type Graph = NetworkGraph<Arc<LdkLogger>>;
+type Scorer = ProbabilisticScorer<Arc<Graph>, Arc<LdkLogger>>;
+type Router = DefaultRouter<
+    Arc<Graph>,
+    Arc<LdkLogger>,
+    Arc<RwLock<Scorer>>,
+    ProbabilisticScoringFeeParameters,
+    Scorer,
+>;
+type ChannelManager = channelmanager::ChannelManager<
+    Arc<ChainMonitor>,
+    Arc<Broadcaster>,
+    Arc<KeysManager>,
+    Arc<KeysManager>,
+    Arc<KeysManager>,
+    Arc<FeeRates>,
+    Arc<Router>,
+    Arc<LdkLogger>,
+>;
+type GossipSync = P2PGossipSync<Arc<Graph>, Arc<dyn UtxoLookup + Send + Sync>, Arc<LdkLogger>>;
+type PeerManager = peer_handler::PeerManager<
+    SocketDescriptor,
+    Arc<ChannelManager>,
+    Arc<GossipSync>,
+    Arc<IgnoringMessageHandler>,
+    Arc<LdkLogger>,
+    Arc<IgnoringMessageHandler>,
+    Arc<KeysManager>,
+>;
+
+/// A Lightning node run by LDK inside the wallet process. Channel funding comes from the
+/// owner's Bitcoin wallet and outputs of closed channels are swept back to it. Blocks are
+/// read from the wallet's bitcoind RPC endpoints.
 pub struct LdkRealNode {
-    node: Node,
+    user_id: String,
+    wallet: Arc<BitcoinWalletManager>,
+    network: Network,
+    keys: Arc<KeysManager>,
+    logger: Arc<LdkLogger>,
+    fees: Arc<FeeRates>,
+    store: Arc<FilesystemStore>,
+    chain_monitor: Arc<ChainMonitor>,
+    channel_manager: Arc<ChannelManager>,
+    network_graph: Arc<Graph>,
+    peer_manager: Arc<PeerManager>,
+    listening_port: u16,
+    /// Block the channel manager and monitors have been synced to
+    best_block: tokio::sync::Mutex<(ldk_bitcoin::BlockHash, u32)>,
+    /// Payments sent and received since the node started, by payment hash
+    payments: Mutex<HashMap<PaymentHash, Payment>>,
+    /// Channel opens waiting for their funding to be broadcast, by user channel id
+    pending_opens: Mutex<HashMap<u128, oneshot::Sender<Result<ChannelId>>>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl LdkRealNode {
-    pub async fn new(storage_dir: String, network: Network) -> Result<Self> {
-        // This creates a REAL Lightning node
-        let builder = Builder::new()
-            .set_network(network)
-            .set_storage_dir_path(storage_dir)
-            .set_listening_addresses(vec!["0.0.0.0:9735".parse()?])?;
-        
-        let node = builder.build()?;
-        node.start()?;
-        
-        Ok(Self { node })
-    }
-    
+    /// Start the node `seed` derives for `user_id`, restoring its channels from `storage_dir`
+    /// if it has run there before. A `listening_port` of 0 takes any free port.
+    pub async fn start(
+        wallet: Arc<BitcoinWalletManager>,
+        user_id: &str,
+        seed: [u8; 32],
+        storage_dir: &Path,
+        listening_port: u16,
+    ) -> Result<Arc<Self>> {
+        let network = wallet.network;
+        std::fs::create_dir_all(storage_dir)?;
+        let store = Arc::new(FilesystemStore::new(storage_dir.to_path_buf()));
+        let logger = Arc::new(LdkLogger);
+        let fees = Arc::new(FeeRates::default());
+        fees.refresh(&wallet).await;
+        let broadcaster = Arc::new(Broadcaster {
+            wallet: wallet.clone(),
+            runtime: tokio::runtime::Handle::current(),
+        });
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let keys = Arc::new(KeysManager::new(&seed, now.as_secs(), now.subsec_nanos()));
+        let node_id = keys
+            .get_node_id(Recipient::Node)
+            .map_err(|_| anyhow::anyhow!("Can't derive the node id"))?;
+        match read_key(&store, "", NODE_ID_KEY)? {
+            Some(stored) if stored != node_id.serialize() => {
+                return Err(anyhow::anyhow!(
+                    "{} holds the channels of node {}, not {node_id}",
+                    storage_dir.display(),
+                    hex::encode(stored)
+                ));
+            }
+            Some(_) => {}
+            None => store.write("", "", NODE_ID_KEY, &node_id.serialize())?,
+        }
+
+        let chain_monitor = Arc::new(ChainMonitor::new(
+            None,
+            broadcaster.clone(),
+            logger.clone(),
+            fees.clone(),
+            store.clone(),
+        ));
+        let network_graph = Arc::new(
+            match read_key(
+                &store,
+                persist::NETWORK_GRAPH_PERSISTENCE_PRIMARY_NAMESPACE,
+                persist::NETWORK_GRAPH_PERSISTENCE_KEY,
+            )? {
+                Some(bytes) => Graph::read(&mut Cursor::new(bytes), logger.clone())
+                    .map_err(|e| anyhow::anyhow!("Can't read the network graph: {e:?}"))?,
+                None => Graph::new(ldk_network(network)?, logger.clone()),
+            },
+        );
+        let scorer = Arc::new(RwLock::new(ProbabilisticScorer::new(
+            ProbabilisticScoringDecayParameters::default(),
+            network_graph.clone(),
+            logger.clone(),
+        )));
+        let router = Arc::new(DefaultRouter::new(
+            network_graph.clone(),
+            logger.clone(),
+            keys.get_secure_random_bytes(),
+            scorer,
+            ProbabilisticScoringFeeParameters::default(),
+        ));
+
+        let mut config = UserConfig::default();
+        // A wallet's channels are private; invoices carry route hints to them instead
+        config.channel_handshake_config.announced_channel = false;
+        config
+            .channel_handshake_limits
+            .force_announced_channel_preference = false;
+
+        let mut monitors =
+            persist::read_channel_monitors(store.clone(), keys.clone(), keys.clone())?;
+        let (manager_block, channel_manager) = match read_key(
+            &store,
+            persist::CHANNEL_MANAGER_PERSISTENCE_PRIMARY_NAMESPACE,
+            persist::CHANNEL_MANAGER_PERSISTENCE_KEY,
+        )? {
+            Some(bytes) => {
+                let args = ChannelManagerReadArgs::new(
+                    keys.clone(),
+                    keys.clone(),
+                    keys.clone(),
+                    fees.clone(),
+                    chain_monitor.clone(),
+                    broadcaster.clone(),
+                    router.clone(),
+                    logger.clone(),
+                    config,
+                    monitors.iter_mut().map(|(_, monitor)| monitor).collect(),
+                );
+                <(ldk_bitcoin::BlockHash, ChannelManager)>::read(&mut Cursor::new(bytes), args)
+                    .map_err(|e| anyhow::anyhow!("Can't read the channel manager: {e:?}"))?
+            }
+            None => {
+                let height = wallet.block_height().await?;
+                let hash = to_ldk(&wallet.block_hash(height).await?)?;
+                let channel_manager = ChannelManager::new(
+                    fees.clone(),
+                    chain_monitor.clone(),
+                    broadcaster.clone(),
+                    router,
+                    logger.clone(),
+                    keys.clone(),
+                    keys.clone(),
+                    keys.clone(),
+                    config,
+                    ChainParameters {
+                        network: ldk_network(network)?,
+                        best_block: BestBlock::new(hash, height),
+                    },
+                    now.as_secs() as u32,
+                );
+                (hash, channel_manager)
+            }
+        };
+
+        // Replay the blocks every channel monitor and the manager missed while stopped
+        for (monitor_block, monitor) in monitors {
+            let listener = (monitor, broadcaster.clone(), fees.clone(), logger.clone());
+            sync_listener(&wallet, &listener, monitor_block).await?;
+            let monitor = listener.0;
+            let funding = monitor.get_funding_txo().0;
+            chain_monitor
+                .watch_channel(funding, monitor)
+                .map_err(|_| anyhow::anyhow!("Can't watch the channel funded by {funding:?}"))?;
+        }
+        let best_block = sync_listener(&wallet, &channel_manager, manager_block).await?;
+        let channel_manager = Arc::new(channel_manager);
+
+        let gossip_sync = Arc::new(GossipSync::new(network_graph.clone(), None, logger.clone()));
+        let peer_manager = Arc::new(PeerManager::new(
+            MessageHandler {
+                chan_handler: channel_manager.clone(),
+                route_handler: gossip_sync,
+                onion_message_handler: Arc::new(IgnoringMessageHandler {}),
+                custom_message_handler: Arc::new(IgnoringMessageHandler {}),
+            },
+            now.as_secs() as u32,
+            &keys.get_secure_random_bytes(),
+            logger.clone(),
+            keys.clone(),
+        ));
+
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", listening_port)).await?;
+        let node = Arc::new(Self {
+            user_id: user_id.to_string(),
+            wallet,
+            network,
+            keys,
+            logger,
+            fees,
+            store,
+            chain_monitor,
+            channel_manager,
+            network_graph,
+            peer_manager,
+            listening_port: listener.local_addr()?.port(),
+            best_block: tokio::sync::Mutex::new(best_block),
+            payments: Mutex::new(HashMap::new()),
+            pending_opens: Mutex::new(HashMap::new()),
+            tasks: Mutex::new(vec![]),
+        });
+        node.persist()?;
+
+        let accept = {
+            let peer_manager = node.peer_manager.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => match stream.into_std() {
+                            Ok(stream) => {
+                                tokio::spawn(lightning_net_tokio::setup_inbound(
+                                    peer_manager.clone(),
+                                    stream,
+                                ));
+                            }
+                            Err(e) => log::warn!("Inbound Lightning connection failed: {e}"),
+                        },
+                        Err(e) => log::warn!("Inbound Lightning connection failed: {e}"),
+                    }
+                }
+            })
+        };
+        let background = tokio::spawn(node.clone().run());
+        node.tasks.lock().unwrap().extend([accept, background]);
+        Ok(node)
+    }
+
+    /// Stop the node's background tasks, disconnect from peers and persist its state
+    pub fn stop(&self) -> Result<()> {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.peer_manager.disconnect_all_peers();
+        self.persist()
+    }
+
     pub fn node_id(&self) -> String {
-        self.node.node_id().to_string()
-    }
-    
-    pub async fn open_channel(&self, node_id: &str, amount_sats: u64) -> Result<()> {
-        // This opens a REAL channel with REAL Bitcoin
-        let pubkey = PublicKey::from_str(node_id)?;
-        let address = "1.2.3.4:9735".parse()?;
-        
-        self.node.connect_open_channel(pubkey, address, amount_sats, None, None, false)?;
+        self.channel_manager.get_our_node_id().to_string()
+    }
+
+    pub fn node_info(&self) -> NodeInfo {
+        NodeInfo {
+            _____user_id: self.user_id.clone(),
+            node_id: self.node_id(),
+            alias: format!("{}'s Lightning Node", self.user_id),
+            num_peers: self.peer_manager.get_peer_node_ids().len() as u32,
+            num_channels: self.channel_manager.list_channels().len() as u32,
+            listening_port: self.listening_port,
+        }
+    }
+
+    /// Connect to a peer at `address`, a `host:port`
+    pub async fn connect_peer(&self, node_id: &str, address: &str) -> Result<()> {
+        let node_id = NodeId::from_str(node_id)?;
+        if self.is_connected(&node_id) {
+            return Ok(());
+        }
+        let addr: SocketAddr = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{address} doesn't resolve"))?;
+        let closed =
+            lightning_net_tokio::connect_outbound(self.peer_manager.clone(), node_id, addr)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Couldn't connect to {address}"))?;
+        let mut closed = Box::pin(closed);
+        let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            if self.is_connected(&node_id) {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = &mut closed => break,
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+            }
+        }
+        Err(anyhow::anyhow!(
+            "{node_id} at {address} didn't complete the handshake"
+        ))
+    }
+
+    /// Open a channel to a connected peer, funded from the owner's wallet. Returns the
+    /// channel id once the funding transaction has been broadcast.
+    pub async fn open_channel(&self, node_id: &str, amount_sats: u64) -> Result<String> {
+        let node_id = NodeId::from_str(node_id)?;
+        if !self.is_connected(&node_id) {
+            return Err(anyhow::anyhow!(
+                "Connect to {node_id} before opening a channel"
+            ));
+        }
+        let mut random = [0u8; 16];
+        random.copy_from_slice(&self.keys.get_secure_random_bytes()[..16]);
+        let user_channel_id = u128::from_be_bytes(random);
+        let (sender, receiver) = oneshot::channel();
+        self.pending_opens
+            .lock()
+            .unwrap()
+            .insert(user_channel_id, sender);
+
+        if let Err(e) = self.channel_manager.create_channel(
+            node_id,
+            amount_sats,
+            0,
+            user_channel_id,
+            None,
+            None,
+        ) {
+            self.pending_opens.lock().unwrap().remove(&user_channel_id);
+            return Err(anyhow::anyhow!("Can't open a channel: {e:?}"));
+        }
+        let opened = tokio::time::timeout(CHANNEL_OPEN_TIMEOUT, receiver).await;
+        self.pending_opens.lock().unwrap().remove(&user_channel_id);
+        match opened {
+            Ok(Ok(channel_id)) => Ok(hex::encode(channel_id?.0)),
+            _ => Err(anyhow::anyhow!(
+                "{node_id} didn't accept the channel in time"
+            )),
+        }
+    }
+
+    /// Close a channel cooperatively, or unilaterally with `force`. Our balance is swept to
+    /// the wallet once it can be spent.
+    pub fn close_channel(&self, channel_id: &str, force: bool) -> Result<()> {
+        let channel_id = ChannelId(
+            hex::decode(channel_id)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Channel ids are 32 bytes"))?,
+        );
+        let channel = self
+            .channel_manager
+            .list_channels()
+            .into_iter()
+            .find(|c| c.channel_id == channel_id)
+            .ok_or_else(|| anyhow::anyhow!("No channel {}", hex::encode(channel_id.0)))?;
+        let counterparty = channel.counterparty.node_id;
+        if force {
+            self.channel_manager
+                .force_close_broadcasting_latest_txn(&channel_id, &counterparty)
+        } else {
+            self.channel_manager
+                .close_channel(&channel_id, &counterparty)
+        }
+        .map_err(|e| anyhow::anyhow!("Can't close the channel: {e:?}"))
+    }
+
+    pub fn list_channels(&self) -> Vec<ChannelInfo> {
+        self.channel_manager
+            .list_channels()
+            .into_iter()
+            .map(|channel| ChannelInfo {
+                channel_id: hex::encode(channel.channel_id.0),
+                peer_node_id: channel.counterparty.node_id.to_string(),
+                capacity_sats: channel.channel_value_satoshis,
+                local_balance_sats: channel.outbound_capacity_msat / 1_000,
+                remote_balance_sats: channel.inbound_capacity_msat / 1_000,
+                active: channel.is_usable,
+            })
+            .collect()
+    }
+
+    /// The owner's on-chain balance and what they can spend over channels
+    pub async fn balance(&self) -> Result<Balance> {
+        let onchain = self.wallet.get_balance(&self.user_id).await?;
+        let channel_balance_sats = self
+            .channel_manager
+            .list_channels()
+            .iter()
+            .map(|c| c.outbound_capacity_msat / 1_000)
+            .sum::<u64>();
+        Ok(Balance {
+            total_balance_sats: onchain.total + channel_balance_sats,
+            confirmed_balance_sats: onchain.confirmed,
+            channel_balance_sats,
+        })
+    }
+
+    pub fn create_invoice(&self, amount_msat: Option<u64>, description: String) -> Result<Invoice> {
+        let invoice = lightning_invoice::utils::create_invoice_from_channelmanager(
+            &self.channel_manager,
+            self.keys.clone(),
+            self.logger.clone(),
+            Currency::from(ldk_network(self.network)?),
+            amount_msat,
+            description,
+            INVOICE_EXPIRY_SECS,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("Can't create an invoice: {e:?}"))?;
+        let bolt11 = invoice.to_string();
+        let decoded: Bolt11Invoice = bolt11.parse()?;
+        Ok(Invoice {
+            bolt11,
+            payment_hash: decoded.payment_hash.to_string(),
+            amount_msat,
+        })
+    }
+
+    /// Pay a decoded invoice, retrying over other routes until it succeeds or times out
+    pub async fn pay_invoice(&self, invoice: &Bolt11Invoice) -> Result<Payment> {
+        let amount_msat = invoice
+            .amount_msat
+            .ok_or_else(|| anyhow::anyhow!("Invoice has no amount"))?;
+        let (payment_hash, recipient_onion, route_params) = payment_parameters(invoice)?;
+        self.payments.lock().unwrap().insert(
+            payment_hash,
+            Payment {
+                payment_hash: invoice.payment_hash.to_string(),
+                payment_preimage: None,
+                amount_msat,
+                fee_msat: 0,
+                status: PaymentStatus::Pending,
+            },
+        );
+        self.channel_manager
+            .send_payment(
+                payment_hash,
+                recipient_onion,
+                PaymentId(payment_hash.0),
+                route_params,
+                Retry::Timeout(PAYMENT_TIMEOUT),
+            )
+            .map_err(|e| anyhow::anyhow!("Payment failed: {e:?}"))?;
+
+        let deadline = tokio::time::Instant::now() + PAYMENT_TIMEOUT + Duration::from_secs(5);
+        loop {
+            let payment = self.payment(&payment_hash);
+            match payment {
+                Some(payment) if !matches!(payment.status, PaymentStatus::Pending) => {
+                    return Ok(payment)
+                }
+                Some(payment) if tokio::time::Instant::now() >= deadline => return Ok(payment),
+                Some(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                None => return Err(anyhow::anyhow!("Payment was forgotten")),
+            }
+        }
+    }
+
+    fn payment(&self, payment_hash: &PaymentHash) -> Option<Payment> {
+        self.payments.lock().unwrap().get(payment_hash).cloned()
+    }
+
+    fn is_connected(&self, node_id: &NodeId) -> bool {
+        self.peer_manager
+            .get_peer_node_ids()
+            .iter()
+            .any(|(peer, _)| peer == node_id)
+    }
+
+    /// Drive the node: peer messages and events every second, chain sync every ten, and
+    /// timers, fee estimates, gossip persistence and sweeps every minute
+    async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        for tick in 0u64.. {
+            ticker.tick().await;
+            self.peer_manager.process_events();
+            let node = self.clone();
+            self.channel_manager
+                .process_pending_events_async(|event| {
+                    let node = node.clone();
+                    async move { node.handle_event(event).await }
+                })
+                .await;
+            self.chain_monitor
+                .process_pending_events_async(|event| {
+                    let node = node.clone();
+                    async move { node.handle_event(event).await }
+                })
+                .await;
+            if self.channel_manager.get_and_clear_needs_persistence() {
+                if let Err(e) = self.persist() {
+                    log::error!("Couldn't persist the channel manager: {e}");
+                }
+            }
+
+            if tick % 10 == 0 {
+                self.peer_manager.timer_tick_occurred();
+                if let Err(e) = self.sync_chain().await {
+                    log::warn!("Lightning chain sync failed: {e}");
+                }
+            }
+            if tick % 60 == 0 {
+                self.channel_manager.timer_tick_occurred();
+                self.chain_monitor.rebroadcast_pending_claims();
+                self.fees.refresh(&self.wallet).await;
+                if let Err(e) = self.store.write(
+                    persist::NETWORK_GRAPH_PERSISTENCE_PRIMARY_NAMESPACE,
+                    persist::NETWORK_GRAPH_PERSISTENCE_SECONDARY_NAMESPACE,
+                    persist::NETWORK_GRAPH_PERSISTENCE_KEY,
+                    &self.network_graph.encode(),
+                ) {
+                    log::warn!("Couldn't persist the network graph: {e}");
+                }
+                self.sweep().await;
+            }
+        }
+    }
+
+    /// Feed new blocks to the channel monitors and the manager
+    async fn sync_chain(&self) -> Result<()> {
+        let mut best_block = self.best_block.lock().await;
+        let listener = ChainListener {
+            chain_monitor: &self.chain_monitor,
+            channel_manager: &self.channel_manager,
+        };
+        *best_block = sync_listener(&self.wallet, &listener, best_block.0).await?;
         Ok(())
     }
-    
-    pub async fn receive_payment(&self, amount_sats: u64, description: String) -> Result<String> {
-        // Creates a REAL invoice that can receive REAL sats
-        let invoice = self.node.receive_payment(amount_sats, &description, 3600)?;
-        Ok(invoice.to_string())
-    }
-    
-    pub async fn send_payment(&self, invoice: String) -> Result<String> {
-        // Sends REAL Bitcoin over Lightning
-        let invoice = Bolt11Invoice::from_str(&invoice)?;
-        let hash = self.node.send_payment(&invoice)?;
-        Ok(hash.to_string())
+
+    fn persist(&self) -> Result<()> {
+        self.store.write(
+            persist::CHANNEL_MANAGER_PERSISTENCE_PRIMARY_NAMESPACE,
+            persist::CHANNEL_MANAGER_PERSISTENCE_SECONDARY_NAMESPACE,
+            persist::CHANNEL_MANAGER_PERSISTENCE_KEY,
+            &self.channel_manager.encode(),
+        )?;
+        Ok(())
+    }
+
+    async fn handle_event(&self, event: Event) {
+        match event {
+            Event::FundingGenerationReady {
+                temporary_channel_id,
+                counterparty_node_id,
+                channel_value_satoshis,
+                output_script,
+                user_channel_id,
+                ..
+            } => {
+                let funded = self
+                    .fund_channel(
+                        &temporary_channel_id,
+                        &counterparty_node_id,
+                        channel_value_satoshis,
+                        &output_script,
+                    )
+                    .await;
+                if let Err(e) = funded {
+                    log::warn!("Couldn't fund a channel with {counterparty_node_id}: {e}");
+                    let _ = self.channel_manager.force_close_without_broadcasting_txn(
+                        &temporary_channel_id,
+                        &counterparty_node_id,
+                    );
+                    if let Some(open) = self.pending_opens.lock().unwrap().remove(&user_channel_id)
+                    {
+                        let _ = open.send(Err(e));
+                    }
+                }
+            }
+            Event::ChannelPending {
+                channel_id,
+                user_channel_id,
+                counterparty_node_id,
+                ..
+            } => {
+                log::info!(
+                    "Channel {} with {counterparty_node_id} is waiting for confirmations",
+                    hex::encode(channel_id.0)
+                );
+                if let Some(open) = self.pending_opens.lock().unwrap().remove(&user_channel_id) {
+                    let _ = open.send(Ok(channel_id));
+                }
+            }
+            Event::ChannelReady { channel_id, .. } => {
+                log::info!("Channel {} is ready", hex::encode(channel_id.0));
+            }
+            Event::ChannelClosed {
+                channel_id,
+                user_channel_id,
+                reason,
+                ..
+            } => {
+                log::info!("Channel {} closed: {reason}", hex::encode(channel_id.0));
+                if let Some(open) = self.pending_opens.lock().unwrap().remove(&user_channel_id) {
+                    let _ = open.send(Err(anyhow::anyhow!("Channel closed: {reason}")));
+                }
+            }
+            Event::PaymentClaimable {
+                payment_hash,
+                purpose,
+                ..
+            } => {
+                let preimage = match purpose {
+                    PaymentPurpose::InvoicePayment {
+                        payment_preimage, ..
+                    } => payment_preimage,
+                    PaymentPurpose::SpontaneousPayment(preimage) => Some(preimage),
+                };
+                match preimage {
+                    Some(preimage) => self.channel_manager.claim_funds(preimage),
+                    None => self.channel_manager.fail_htlc_backwards(&payment_hash),
+                }
+            }
+            Event::PaymentClaimed {
+                payment_hash,
+                amount_msat,
+                purpose,
+                ..
+            } => {
+                let preimage = match purpose {
+                    PaymentPurpose::InvoicePayment {
+                        payment_preimage, ..
+                    } => payment_preimage,
+                    PaymentPurpose::SpontaneousPayment(preimage) => Some(preimage),
+                };
+                self.payments.lock().unwrap().insert(
+                    payment_hash,
+                    Payment {
+                        payment_hash: hex::encode(payment_hash.0),
+                        payment_preimage: preimage.map(|p| hex::encode(p.0)),
+                        amount_msat,
+                        fee_msat: 0,
+                        status: PaymentStatus::Succeeded,
+                    },
+                );
+            }
+            Event::PaymentSent {
+                payment_hash,
+                payment_preimage,
+                fee_paid_msat,
+                ..
+            } => {
+                if let Some(payment) = self.payments.lock().unwrap().get_mut(&payment_hash) {
+                    payment.payment_preimage = Some(hex::encode(payment_preimage.0));
+                    payment.fee_msat = fee_paid_msat.unwrap_or_default();
+                    payment.status = PaymentStatus::Succeeded;
+                }
+            }
+            Event::PaymentFailed {
+                payment_hash,
+                reason,
+                ..
+            } => {
+                log::warn!("Payment {} failed: {reason:?}", hex::encode(payment_hash.0));
+                if let Some(payment) = self.payments.lock().unwrap().get_mut(&payment_hash) {
+                    payment.status = PaymentStatus::Failed;
+                }
+            }
+            Event::PendingHTLCsForwardable { time_forwardable } => {
+                let channel_manager = self.channel_manager.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(time_forwardable).await;
+                    channel_manager.process_pending_htlc_forwards();
+                });
+            }
+            Event::SpendableOutputs { outputs, .. } => {
+                for output in outputs {
+                    let bytes = output.encode();
+                    let key = sha256::Hash::hash(&bytes).to_string();
+                    if let Err(e) = self
+                        .store
+                        .write(SPENDABLE_OUTPUTS_NAMESPACE, "", &key, &bytes)
+                    {
+                        log::error!("Couldn't store a spendable channel output: {e}");
+                    }
+                }
+                self.sweep().await;
+            }
+            Event::DiscardFunding { transaction, .. } => {
+                log::warn!(
+                    "Funding transaction {} was never broadcast",
+                    transaction.txid()
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Pay the channel's funding output from the owner's wallet and hand the signed
+    /// transaction to LDK, which broadcasts it once the peer has signed the first commitment
+    async fn fund_channel(
+        &self,
+        temporary_channel_id: &ChannelId,
+        counterparty: &NodeId,
+        value: u64,
+        output_script: &ldk_bitcoin::ScriptBuf,
+    ) -> Result<()> {
+        let script = ScriptBuf::from_bytes(output_script.to_bytes());
+        let address = Address::from_script(&script, self.network)?;
+        let mut psbt = self
+            .wallet
+            .create_psbt_with_priority(
+                &self.user_id,
+                &[(address, value)],
+                FeePriority::Normal,
+                Default::default(),
+            )
+            .await?;
+        let signed = self.wallet.sign_psbt(&self.user_id, &mut psbt).await?;
+        if signed < psbt.inputs.len() {
+            return Err(anyhow::anyhow!(
+                "Signed {signed} of {} inputs",
+                psbt.inputs.len()
+            ));
+        }
+        self.wallet.finalize_psbt(&self.user_id, &mut psbt).await?;
+        let tx = to_ldk(&psbt_handler::extract_transaction(psbt)?)?;
+        self.channel_manager
+            .funding_transaction_generated(temporary_channel_id, counterparty, tx)
+            .map_err(|e| anyhow::anyhow!("LDK refused the funding transaction: {e:?}"))
+    }
+
+    /// Sweep stored channel outputs to a fresh wallet address, one transaction each so an
+    /// output still under its CSV delay doesn't hold up the others
+    async fn sweep(&self) {
+        let keys = match self.store.list(SPENDABLE_OUTPUTS_NAMESPACE, "") {
+            Ok(keys) => keys,
+            Err(e) => {
+                log::error!("Couldn't list spendable channel outputs: {e}");
+                return;
+            }
+        };
+        for key in keys {
+            if let Err(e) = self.sweep_output(&key).await {
+                log::debug!("Channel output {key} not swept yet: {e}");
+            }
+        }
+    }
+
+    async fn sweep_output(&self, key: &str) -> Result<()> {
+        let bytes = self.store.read(SPENDABLE_OUTPUTS_NAMESPACE, "", key)?;
+        let descriptor = SpendableOutputDescriptor::read(&mut Cursor::new(bytes))
+            .map_err(|e| anyhow::anyhow!("Unreadable channel output: {e:?}"))?;
+        let destination = self
+            .wallet
+            .get_receive_address(&self.user_id, AddressType::NativeSegwit)
+            .await?;
+        let destination = Address::from_str(&destination)?.require_network(self.network)?;
+        let tx = self
+            .keys
+            .spend_spendable_outputs(
+                &[&descriptor],
+                vec![],
+                ldk_bitcoin::ScriptBuf::from_bytes(destination.script_pubkey().to_bytes()),
+                self.fees
+                    .get_est_sat_per_1000_weight(ConfirmationTarget::OnChainSweep),
+                None,
+                &ldk_bitcoin::secp256k1::Secp256k1::new(),
+            )
+            .map_err(|_| anyhow::anyhow!("Can't build a sweep"))?;
+        let txid = self.wallet.broadcast_transaction(&from_ldk(&tx)?).await?;
+        log::info!("Swept a channel output to {destination} in {txid}");
+        self.store
+            .remove(SPENDABLE_OUTPUTS_NAMESPACE, "", key, false)?;
+        Ok(())
+    }
+}
+
+/// Move a chain listener from its best block to the wallet's chain tip, disconnecting
+/// blocks that were reorganized out first. Returns the block the listener is now at.
+async fn sync_listener<L: Listen + Sync + ?Sized>(
+    wallet: &BitcoinWalletManager,
+    listener: &L,
+    best: ldk_bitcoin::BlockHash,
+) -> Result<(ldk_bitcoin::BlockHash, u32)> {
+    let mut hash = best;
+    let mut height = wallet.block_height_of(&from_ldk(&hash)?).await?;
+    let tip = wallet.block_height().await?;
+    while height > tip
+        || to_ldk::<_, ldk_bitcoin::BlockHash>(&wallet.block_hash(height).await?)? != hash
+    {
+        let block: ldk_bitcoin::Block = to_ldk(&wallet.block(&from_ldk(&hash)?).await?)?;
+        listener.block_disconnected(&block.header, height);
+        hash = block.header.prev_blockhash;
+        height -= 1;
+    }
+    for next in height + 1..=tip {
+        let block: ldk_bitcoin::Block =
+            to_ldk(&wallet.block(&wallet.block_hash(next).await?).await?)?;
+        if block.header.prev_blockhash != hash {
+            // Reorganized while syncing; the next sync disconnects back to the fork
+            break;
+        }
+        listener.block_connected(&block, next);
+        hash = block.block_hash();
+        height = next;
+    }
+    Ok((hash, height))
+}
+
+/// Connects blocks to the channel monitors before the channel manager, as LDK requires
+struct ChainListener<'a> {
+    chain_monitor: &'a ChainMonitor,
+    channel_manager: &'a ChannelManager,
+}
+
+impl Listen for ChainListener<'_> {
+    fn filtered_block_connected(
+        &self,
+        header: &ldk_bitcoin::block::Header,
+        txdata: &TransactionData,
+        height: u32,
+    ) {
+        self.chain_monitor
+            .filtered_block_connected(header, txdata, height);
+        self.channel_manager
+            .filtered_block_connected(header, txdata, height);
+    }
+
+    fn block_disconnected(&self, header: &ldk_bitcoin::block::Header, height: u32) {
+        self.chain_monitor.block_disconnected(header, height);
+        self.channel_manager.block_disconnected(header, height);
+    }
+}
+
+/// Fee rates from the wallet's estimator in sat per 1000 weight units, cached because LDK
+/// asks for them synchronously
+struct FeeRates {
+    economy: AtomicU32,
+    normal: AtomicU32,
+    priority: AtomicU32,
+}
+
+impl Default for FeeRates {
+    fn default() -> Self {
+        Self {
+            economy: AtomicU32::new(FEERATE_FLOOR_SATS_PER_KW),
+            normal: AtomicU32::new(FEERATE_FLOOR_SATS_PER_KW),
+            priority: AtomicU32::new(FEERATE_FLOOR_SATS_PER_KW),
+        }
+    }
+}
+
+impl FeeRates {
+    async fn refresh(&self, wallet: &BitcoinWalletManager) {
+        for (priority, rate) in [
+            (FeePriority::Economy, &self.economy),
+            (FeePriority::Normal, &self.normal),
+            (FeePriority::Priority, &self.priority),
+        ] {
+            match wallet.estimate_fee_rate(priority).await {
+                Ok(fee_rate) => rate.store(
+                    (fee_rate.to_sat_per_kwu() as u32).max(FEERATE_FLOOR_SATS_PER_KW),
+                    Ordering::Relaxed,
+                ),
+                Err(e) => log::warn!("Lightning fee estimate unavailable: {e}"),
+            }
+        }
+    }
+}
+
+impl FeeEstimator for FeeRates {
+    fn get_est_sat_per_1000_weight(&self, target: ConfirmationTarget) -> u32 {
+        let rate = match target {
+            ConfirmationTarget::OnChainSweep => &self.priority,
+            ConfirmationTarget::AnchorChannelFee
+            | ConfirmationTarget::MinAllowedAnchorChannelRemoteFee
+            | ConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee
+            | ConfirmationTarget::ChannelCloseMinimum => &self.economy,
+            _ => &self.normal,
+        };
+        rate.load(Ordering::Relaxed)
+    }
+}
+
+/// Broadcasts LDK's commitment, closing and claim transactions through the wallet
+struct Broadcaster {
+    wallet: Arc<BitcoinWalletManager>,
+    runtime: tokio::runtime::Handle,
+}
+
+impl BroadcasterInterface for Broadcaster {
+    fn broadcast_transactions(&self, txs: &[&ldk_bitcoin::Transaction]) {
+        for tx in txs {
+            let tx: bitcoin::Transaction = match from_ldk(*tx) {
+                Ok(tx) => tx,
+                Err(e) => {
+                    log::error!("Can't convert an LDK transaction: {e}");
+                    continue;
+                }
+            };
+            let wallet = self.wallet.clone();
+            self.runtime.spawn(async move {
+                if let Err(e) = wallet.broadcast_transaction(&tx).await {
+                    log::warn!(
+                        "Couldn't broadcast Lightning transaction {}: {e}",
+                        tx.txid()
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Forwards LDK's log records to the `log` crate
+struct LdkLogger;
+
+impl Logger for LdkLogger {
+    fn log(&self, record: Record) {
+        let level = match record.level {
+            Level::Gossip | Level::Trace => log::Level::Trace,
+            Level::Debug => log::Level::Debug,
+            Level::Info => log::Level::Info,
+            Level::Warn => log::Level::Warn,
+            Level::Error => log::Level::Error,
+        };
+        log::log!(target: "walletd::ldk", level, "{}: {}", record.module_path, record.args);
+    }
+}
+
+/// What `ChannelManager::send_payment` needs to pay an invoice: its hash, the secret to put
+/// in the onion, and where and how much to route
+fn payment_parameters(
+    invoice: &Bolt11Invoice,
+) -> Result<(PaymentHash, RecipientOnionFields, RouteParameters)> {
+    let ldk_invoice = lightning_invoice::Bolt11Invoice::from_str(&invoice.to_string())
+        .map_err(|e| anyhow::anyhow!("LDK can't read the invoice: {e:?}"))?;
+    lightning_invoice::payment::payment_parameters_from_invoice(&ldk_invoice)
+        .map_err(|_| anyhow::anyhow!("Invoice has no amount"))
+}
+
+/// A key from the store, or `None` if it has never been written
+fn read_key(store: &FilesystemStore, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    match store.read(namespace, "", key) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// LDK builds on an older rust-bitcoin, so values cross over in their consensus encoding
+fn to_ldk<T, U>(value: &T) -> Result<U>
+where
+    T: bitcoin::consensus::Encodable,
+    U: ldk_bitcoin::consensus::Decodable,
+{
+    Ok(ldk_bitcoin::consensus::deserialize(
+        &bitcoin::consensus::serialize(value),
+    )?)
+}
+
+fn from_ldk<T, U>(value: &T) -> Result<U>
+where
+    T: ldk_bitcoin::consensus::Encodable,
+    U: bitcoin::consensus::Decodable,
+{
+    Ok(bitcoin::consensus::deserialize(
+        &ldk_bitcoin::consensus::serialize(value),
+    )?)
+}
+
+fn ldk_network(network: Network) -> Result<ldk_bitcoin::Network> {
+    match network {
+        Network::Bitcoin => Ok(ldk_bitcoin::Network::Bitcoin),
+        Network::Testnet => Ok(ldk_bitcoin::Network::Testnet),
+        Network::Signet => Ok(ldk_bitcoin::Network::Signet),
+        Network::Regtest => Ok(ldk_bitcoin::Network::Regtest),
+        // Every network is matched above; this covers any a later bitcoin release adds
+        #[allow(unreachable_patterns)]
+        _ => Err(anyhow::anyhow!("LDK doesn't support {network}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::invoice_handler::InvoiceBuilder;
+    use crate::{BitcoinConfig, RpcEndpoint};
+    use ::lightning::routing::router::Payee;
+    use bitcoincore_rpc::{Auth, Client, RpcApi};
+
+    /// The wallet's own invoices are what LDK is asked to pay; it has to read them the same way
+    #[test]
+    fn invoices_convert_to_ldk_payment_parameters() {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let node_key = bitcoin::secp256k1::SecretKey::from_slice(&[0x22; 32]).unwrap();
+        let builder = InvoiceBuilder::new(
+            Network::Regtest,
+            sha256::Hash::hash(b"preimage"),
+            [0x33; 32],
+        )
+        .with_description("coffee");
+        let invoice = builder
+            .clone()
+            .with_amount_msat(150_000)
+            .build_signed(&node_key)
+            .unwrap();
+
+        let (payment_hash, onion, route) = payment_parameters(&invoice).unwrap();
+        assert_eq!(payment_hash.0, invoice.payment_hash.to_byte_array());
+        assert_eq!(
+            onion.payment_secret.map(|secret| secret.0),
+            Some([0x33; 32])
+        );
+        assert_eq!(route.final_value_msat, 150_000);
+        match route.payment_params.payee {
+            Payee::Clear { node_id, .. } => {
+                assert_eq!(node_id.serialize(), node_key.public_key(&secp).serialize())
+            }
+            Payee::Blinded { .. } => panic!("a BOLT11 payee is never blinded"),
+        }
+
+        let amountless = builder.build_signed(&node_key).unwrap();
+        assert!(payment_parameters(&amountless).is_err());
+    }
+
+    #[test]
+    fn transactions_round_trip_through_ldk_types() {
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![bitcoin::TxOut {
+                value: bitcoin::Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new_op_return([1, 2, 3]),
+            }],
+        };
+        let ldk_tx: ldk_bitcoin::Transaction = to_ldk(&tx).unwrap();
+        assert_eq!(ldk_tx.txid().to_string(), tx.txid().to_string());
+        assert_eq!(from_ldk::<_, bitcoin::Transaction>(&ldk_tx).unwrap(), tx);
+        assert_eq!(
+            ldk_network(Network::Regtest).unwrap(),
+            ldk_bitcoin::Network::Regtest
+        );
+    }
+
+    /// Run against a regtest bitcoind with
+    /// `BITCOIND_RPC_URL=http://127.0.0.1:18443 BITCOIND_RPC_USER=.. BITCOIND_RPC_PASS=..`.
+    /// Alice opens a channel to a second local node, pays Bob's invoice over it, closes it
+    /// cooperatively, and both restart from their storage directories.
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_two_nodes_pay_and_close() {
+        let endpoint = RpcEndpoint {
+            url: std::env::var("BITCOIND_RPC_URL").unwrap(),
+            user: std::env::var("BITCOIND_RPC_USER").unwrap(),
+            pass: std::env::var("BITCOIND_RPC_PASS").unwrap(),
+        };
+        let client = Client::new(
+            &endpoint.url,
+            Auth::UserPass(endpoint.user.clone(), endpoint.pass.clone()),
+        )
+        .unwrap();
+        let wallet = Arc::new(
+            BitcoinWalletManager::new(BitcoinConfig {
+                network: Network::Regtest,
+                rpc_endpoints: vec![endpoint],
+                storage: None,
+                electrum_url: None,
+                security: Default::default(),
+            })
+            .await
+            .unwrap(),
+        );
+        let alice = format!("ldk-alice-{}", std::process::id());
+        let bob = format!("ldk-bob-{}", std::process::id());
+        wallet.create_wallet(&alice, None).await.unwrap();
+        wallet.create_wallet(&bob, None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("walletd-ldk-{}", uuid::Uuid::new_v4()));
+
+        let burn = Address::from_str("bcrt1qs758ursh4q9z627kt3pp5yysm78ddny6txaqgw")
+            .unwrap()
+            .assume_checked();
+        let mine = |blocks: u64| {
+            client.generate_to_address(blocks, &burn).unwrap();
+        };
+        let funding = wallet
+            .get_receive_address(&alice, AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let funding = Address::from_str(&funding).unwrap().assume_checked();
+        client.generate_to_address(1, &funding).unwrap();
+        mine(100);
+        wallet.sync_utxos(&alice).await.unwrap();
+
+        let alice_node = LdkRealNode::start(wallet.clone(), &alice, [1; 32], &dir.join("alice"), 0)
+            .await
+            .unwrap();
+        let bob_node = LdkRealNode::start(wallet.clone(), &bob, [2; 32], &dir.join("bob"), 0)
+            .await
+            .unwrap();
+        alice_node
+            .connect_peer(
+                &bob_node.node_id(),
+                &format!("127.0.0.1:{}", bob_node.listening_port),
+            )
+            .await
+            .unwrap();
+        let channel_id = alice_node
+            .open_channel(&bob_node.node_id(), 1_000_000)
+            .await
+            .unwrap();
+        mine(6);
+        let wait_until = |node: Arc<LdkRealNode>, usable: bool| async move {
+            for _ in 0..60 {
+                if node.list_channels().iter().any(|c| c.active) == usable {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            panic!(
+                "channel never became {}",
+                if usable { "usable" } else { "unusable" }
+            );
+        };
+        wait_until(alice_node.clone(), true).await;
+        wait_until(bob_node.clone(), true).await;
+
+        let invoice = bob_node
+            .create_invoice(Some(50_000_000), "Regtest coffee".to_string())
+            .unwrap();
+        let decoded: Bolt11Invoice = invoice.bolt11.parse().unwrap();
+        assert_eq!(decoded.payee.to_string(), bob_node.node_id());
+        let payment = alice_node.pay_invoice(&decoded).await.unwrap();
+        assert!(matches!(payment.status, PaymentStatus::Succeeded));
+        let preimage = hex::decode(payment.payment_preimage.unwrap()).unwrap();
+        assert_eq!(sha256::Hash::hash(&preimage), decoded.payment_hash);
+        assert!(bob_node.list_channels()[0].local_balance_sats > 0);
+
+        // A restart restores the channel from the stored monitors and manager
+        bob_node.stop().unwrap();
+        let bob_node = LdkRealNode::start(wallet.clone(), &bob, [2; 32], &dir.join("bob"), 0)
+            .await
+            .unwrap();
+        assert_eq!(bob_node.list_channels()[0].channel_id, channel_id);
+        assert!(
+            LdkRealNode::start(wallet.clone(), &bob, [3; 32], &dir.join("bob"), 0)
+                .await
+                .is_err()
+        );
+        alice_node
+            .connect_peer(
+                &bob_node.node_id(),
+                &format!("127.0.0.1:{}", bob_node.listening_port),
+            )
+            .await
+            .unwrap();
+        wait_until(alice_node.clone(), true).await;
+
+        // Bob's side of a cooperative close is swept into his wallet
+        alice_node.close_channel(&channel_id, false).unwrap();
+        for _ in 0..60 {
+            mine(1);
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            wallet.sync_utxos(&bob).await.unwrap();
+            if wallet.get_balance(&bob).await.unwrap().total > 0 {
+                break;
+            }
+        }
+        assert!(wallet.get_balance(&bob).await.unwrap().total > 0);
+        alice_node.stop().unwrap();
+        bob_node.stop().unwrap();
     }
 }
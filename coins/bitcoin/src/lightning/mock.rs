@@ -1,7 +1,16 @@
+use super::channel_manager::{
+    format_short_channel_id, parse_short_channel_id, Channel, ChannelManager,
+};
+use super::routing::{
+    ChannelGraph, ChannelPolicy, FirstHop, GossipMessage, Pathfinder, ProbabilisticScorer,
+    RouteParams, RoutePath,
+};
 use super::*;
 use bitcoin::hashes::{sha256, Hash};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
 
 // Define the types that will be used across all backends
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,13 +66,291 @@ pub struct Balance {
 // Internal node representation
 struct LightningNode {
     _____user_id: String,
-    node_id: String,
-    channels: Vec<ChannelInfo>,
+    node_id: PublicKey,
+    node_key: SecretKey,
     peers: Vec<String>,
 }
 
+/// How a payment over a `SimulatedNetwork` went
+#[derive(Debug, Clone)]
+pub struct SimulatedPayment {
+    pub status: PaymentStatus,
+    pub preimage: Option<[u8; 32]>,
+    pub amount_msat: u64,
+    pub fee_msat: u64,
+    /// Routes tried before the payment completed or was given up on
+    pub attempts: usize,
+    /// Paths the delivered amount was split over
+    pub parts: usize,
+    /// Why the payment failed
+    pub failure: Option<String>,
+}
+
+/// Nodes and channels with real balances, paid over with the pathfinder. The graph only
+/// learns capacities from gossip, as on the real network, so the scorer finds out how the
+/// liquidity is split from failed HTLCs and payments retry around them.
+pub struct SimulatedNetwork {
+    channels: ChannelManager,
+    graph: ChannelGraph,
+    scorer: ProbabilisticScorer,
+    /// Preimages payees will release, by payment hash
+    preimages: HashMap<sha256::Hash, (PublicKey, [u8; 32])>,
+    /// Nodes that fail every HTLC they are sent
+    offline: HashSet<PublicKey>,
+    max_attempts: usize,
+    timestamp: u32,
+}
+
+impl Default for SimulatedNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedNetwork {
+    pub fn new() -> Self {
+        Self {
+            channels: ChannelManager::new(),
+            graph: ChannelGraph::new(),
+            scorer: ProbabilisticScorer::default(),
+            preimages: HashMap::new(),
+            offline: HashSet::new(),
+            max_attempts: 10,
+            timestamp: 0,
+        }
+    }
+
+    /// Give up on a payment after this many routes
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn graph(&self) -> &ChannelGraph {
+        &self.graph
+    }
+
+    pub fn scorer(&self) -> &ProbabilisticScorer {
+        &self.scorer
+    }
+
+    /// Open and announce a channel, returning its short channel ID
+    pub fn open_channel(
+        &mut self,
+        funder: PublicKey,
+        peer: PublicKey,
+        capacity_sats: u64,
+        push_msat: u64,
+    ) -> Result<u64> {
+        self.timestamp += 1;
+        let channel = self
+            .channels
+            .open_channel(funder, peer, capacity_sats, push_msat)?;
+        let scid = channel.short_channel_id;
+        for message in channel.gossip(self.timestamp) {
+            self.graph.apply(message)?;
+        }
+        Ok(scid)
+    }
+
+    /// Change and announce the policy `node` forwards over a channel with
+    pub fn set_policy(&mut self, scid: u64, node: &PublicKey, policy: ChannelPolicy) -> Result<()> {
+        self.timestamp += 1;
+        self.channels.channel_mut(scid)?.set_policy(node, policy)?;
+        self.graph.apply(GossipMessage::ChannelUpdate {
+            short_channel_id: scid,
+            source: *node,
+            timestamp: self.timestamp,
+            policy,
+        })
+    }
+
+    pub fn close_channel(&mut self, scid: u64) -> Result<Channel> {
+        let channel = self.channels.close_channel(scid)?;
+        self.graph.apply(GossipMessage::ChannelClosed {
+            short_channel_id: scid,
+        })?;
+        Ok(channel)
+    }
+
+    pub fn channel(&self, scid: u64) -> Result<&Channel> {
+        self.channels.channel(scid)
+    }
+
+    pub fn channels_of(&self, node: &PublicKey) -> Vec<&Channel> {
+        self.channels.channels_of(node)
+    }
+
+    pub fn set_offline(&mut self, node: PublicKey, offline: bool) {
+        if offline {
+            self.offline.insert(node);
+        } else {
+            self.offline.remove(&node);
+        }
+    }
+
+    /// Have `payee` accept payments to the hash of `preimage`
+    pub fn add_preimage(&mut self, payee: PublicKey, preimage: [u8; 32]) -> sha256::Hash {
+        let payment_hash = sha256::Hash::hash(&preimage);
+        self.preimages.insert(payment_hash, (payee, preimage));
+        payment_hash
+    }
+
+    /// Pay `amount_msat` to `payee`. Parts that fail are rerouted, with the scorer having
+    /// learned from the failure, while the parts that arrived are held. The payee settles
+    /// once the whole amount is in; if that doesn't happen every held part is failed back.
+    pub fn pay(
+        &mut self,
+        payer: &PublicKey,
+        payee: &PublicKey,
+        amount_msat: u64,
+        payment_hash: sha256::Hash,
+        params: &RouteParams,
+    ) -> SimulatedPayment {
+        let mut held: Vec<(RoutePath, Vec<u64>)> = vec![];
+        let mut remaining = amount_msat;
+        let mut attempts = 0;
+        let mut failure = None;
+
+        while remaining > 0 {
+            if attempts == self.max_attempts {
+                failure = Some(format!("Gave up after {attempts} attempts"));
+                break;
+            }
+            let fee_msat: u64 = held.iter().map(|(path, _)| path.fee_msat()).sum();
+            let attempt_params = RouteParams {
+                max_parts: params.max_parts.saturating_sub(held.len()).max(1),
+                max_fee_msat: params.max_fee_msat.map(|max| max.saturating_sub(fee_msat)),
+                ..params.clone()
+            };
+            let first_hops = self.first_hops(payer);
+            let route = Pathfinder::new(&self.graph, &self.scorer).find_route(
+                payer,
+                payee,
+                remaining,
+                Some(&first_hops),
+                &attempt_params,
+            );
+            let route = match route {
+                Ok(route) => route,
+                Err(e) => {
+                    failure = Some(e.to_string());
+                    break;
+                }
+            };
+            attempts += 1;
+            for path in route.paths {
+                match self.forward(&path, payment_hash) {
+                    Ok(htlcs) => {
+                        remaining -= path.amount_msat();
+                        held.push((path, htlcs));
+                    }
+                    Err(failed_channel) => self.scorer.payment_path_failed(&path, failed_channel),
+                }
+            }
+        }
+
+        let preimage = self
+            .preimages
+            .get(&payment_hash)
+            .filter(|(node, _)| node == payee && remaining == 0)
+            .map(|(_, preimage)| *preimage);
+        if remaining == 0 && preimage.is_none() {
+            failure = Some("Payee doesn't know the payment hash".to_string());
+        }
+        for (path, htlcs) in &held {
+            for (hop, id) in path.hops.iter().zip(htlcs) {
+                let channel = self
+                    .channels
+                    .channel_mut(hop.short_channel_id)
+                    .expect("channels with HTLCs in flight stay open");
+                let resolved = match preimage {
+                    Some(_) => channel.settle_htlc(*id),
+                    None => channel.fail_htlc(*id),
+                };
+                resolved.expect("held HTLCs are in flight");
+            }
+            if preimage.is_some() {
+                self.scorer.payment_path_successful(path);
+            }
+        }
+
+        SimulatedPayment {
+            status: if preimage.is_some() {
+                PaymentStatus::Succeeded
+            } else {
+                PaymentStatus::Failed
+            },
+            preimage,
+            amount_msat,
+            fee_msat: preimage
+                .map(|_| held.iter().map(|(path, _)| path.fee_msat()).sum())
+                .unwrap_or_default(),
+            attempts,
+            parts: if preimage.is_some() { held.len() } else { 0 },
+            failure,
+        }
+    }
+
+    fn first_hops(&self, payer: &PublicKey) -> Vec<FirstHop> {
+        self.channels
+            .channels_of(payer)
+            .into_iter()
+            .filter_map(|channel| {
+                // We know when our own peers are disconnected
+                let counterparty = channel.counterparty(payer)?;
+                if self.offline.contains(&counterparty) {
+                    return None;
+                }
+                Some(FirstHop {
+                    short_channel_id: channel.short_channel_id,
+                    counterparty,
+                    outbound_msat: channel.outbound_msat(payer),
+                })
+            })
+            .collect()
+    }
+
+    /// Add the path's HTLCs hop by hop. On failure those already added are failed back and
+    /// the channel that failed is returned.
+    fn forward(&mut self, path: &RoutePath, payment_hash: sha256::Hash) -> Result<Vec<u64>, u64> {
+        let mut added = vec![];
+        for hop in &path.hops {
+            let result = if self.offline.contains(&hop.node_id) {
+                Err(anyhow::anyhow!("Next node is offline"))
+            } else {
+                self.channels
+                    .channel_mut(hop.short_channel_id)
+                    .and_then(|channel| {
+                        channel.add_htlc(&hop.source, hop.amount_msat, payment_hash)
+                    })
+            };
+            match result {
+                Ok(id) => added.push(id),
+                Err(e) => {
+                    log::debug!(
+                        "HTLC failed at {}: {e}",
+                        format_short_channel_id(hop.short_channel_id)
+                    );
+                    for (hop, id) in path.hops.iter().zip(&added) {
+                        if let Ok(channel) = self.channels.channel_mut(hop.short_channel_id) {
+                            let _ = channel.fail_htlc(*id);
+                        }
+                    }
+                    return Err(hop.short_channel_id);
+                }
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Mock nodes sharing one simulated network, so payments between them are routed over the
+/// channels they open
 pub struct MockLightning {
+    network: Network,
     nodes: Mutex<HashMap<String, LightningNode>>,
+    simulated: Mutex<SimulatedNetwork>,
 }
 
 impl Default for MockLightning {
@@ -74,19 +361,40 @@ impl Default for MockLightning {
 
 impl MockLightning {
     pub fn new() -> Self {
+        Self::with_network(Network::Bitcoin)
+    }
+
+    /// Mock nodes that sign invoices for `network`
+    pub fn with_network(network: Network) -> Self {
         Self {
+            network,
             nodes: Mutex::new(HashMap::new()),
+            simulated: Mutex::new(SimulatedNetwork::new()),
         }
     }
 
+    /// The network the mock nodes' channels are in, e.g. to add channels between other nodes
+    pub fn simulated_network(&self) -> MutexGuard<'_, SimulatedNetwork> {
+        self.simulated.lock().unwrap()
+    }
+
+    fn node_id(&self, user_id: &str) -> Result<PublicKey> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map(|node| node.node_id)
+            .ok_or_else(|| anyhow::anyhow!("Node not found"))
+    }
+
     pub async fn create_node(&self, user_id: &str, seed: [u8; 32]) -> Result<NodeInfo> {
-        let hash = sha256::Hash::hash(&seed);
-        let node_id = format!("02{}", hex::encode(&hash[..]));
+        let node_key = SecretKey::from_slice(&sha256::Hash::hash(&seed).to_byte_array())?;
+        let node_id = node_key.public_key(&Secp256k1::new());
 
         let node = LightningNode {
             _____user_id: user_id.to_string(),
-            node_id: node_id.clone(),
-            channels: Vec::new(),
+            node_id,
+            node_key,
             peers: Vec::new(),
         };
 
@@ -94,7 +402,7 @@ impl MockLightning {
 
         Ok(NodeInfo {
             _____user_id: user_id.to_string(),
-            node_id,
+            node_id: node_id.to_string(),
             alias: format!("{user_id}'s Lightning Node"),
             num_peers: 0,
             num_channels: 0,
@@ -110,52 +418,72 @@ impl MockLightning {
 
         Ok(NodeInfo {
             _____user_id: user_id.to_string(),
-            node_id: node.node_id.clone(),
+            node_id: node.node_id.to_string(),
             alias: format!("{user_id}'s Lightning Node"),
             num_peers: node.peers.len() as u32,
-            num_channels: node.channels.len() as u32,
+            num_channels: self.simulated_network().channels_of(&node.node_id).len() as u32,
             listening_port: 9735,
         })
     }
 
+    /// Channels are funded entirely by the opener and usable at once
     pub async fn open_channel(
         &self,
         user_id: &str,
         peer_node_id: &str,
         amount_sats: u64,
     ) -> Result<String> {
+        let peer: PublicKey = peer_node_id
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid node ID: {peer_node_id}"))?;
         let mut nodes = self.nodes.lock().unwrap();
         let node = nodes
             .get_mut(user_id)
             .ok_or_else(|| anyhow::anyhow!("Node not found"))?;
 
-        let channel_id = format!("channel_{}_{}", &node.node_id[..8], &peer_node_id[..8]);
-
-        let channel = ChannelInfo {
-            channel_id: channel_id.clone(),
-            peer_node_id: peer_node_id.to_string(),
-            capacity_sats: amount_sats,
-            local_balance_sats: amount_sats,
-            remote_balance_sats: 0,
-            active: false,
-        };
-
-        node.channels.push(channel);
+        let scid = self
+            .simulated_network()
+            .open_channel(node.node_id, peer, amount_sats, 0)?;
 
         if !node.peers.contains(&peer_node_id.to_string()) {
             node.peers.push(peer_node_id.to_string());
         }
 
-        Ok(channel_id)
+        Ok(format_short_channel_id(scid))
     }
 
-    pub async fn list_channels(&self, user_id: &str) -> Result<Vec<ChannelInfo>> {
-        let nodes = self.nodes.lock().unwrap();
-        let node = nodes
-            .get(user_id)
-            .ok_or_else(|| anyhow::anyhow!("Node not found"))?;
+    /// Channels close at once whether forced or not
+    pub async fn close_channel(&self, user_id: &str, channel_id: &str, _force: bool) -> Result<()> {
+        let node_id = self.node_id(user_id)?;
+        let scid = parse_short_channel_id(channel_id)?;
+        let mut simulated = self.simulated_network();
+        if !simulated.channel(scid)?.nodes.contains(&node_id) {
+            return Err(anyhow::anyhow!("Channel not found"));
+        }
+        simulated.close_channel(scid)?;
+        Ok(())
+    }
 
-        Ok(node.channels.clone())
+    pub async fn list_channels(&self, user_id: &str) -> Result<Vec<ChannelInfo>> {
+        let node_id = self.node_id(user_id)?;
+        let simulated = self.simulated_network();
+        Ok(simulated
+            .channels_of(&node_id)
+            .into_iter()
+            .map(|channel| {
+                let peer = channel
+                    .counterparty(&node_id)
+                    .expect("node is in its channels");
+                ChannelInfo {
+                    channel_id: format_short_channel_id(channel.short_channel_id),
+                    peer_node_id: peer.to_string(),
+                    capacity_sats: channel.capacity_sats,
+                    local_balance_sats: channel.balance_msat(&node_id) / 1_000,
+                    remote_balance_sats: channel.balance_msat(&peer) / 1_000,
+                    active: true,
+                }
+            })
+            .collect())
     }
 
     pub async fn create_invoice(
@@ -169,28 +497,72 @@ impl MockLightning {
             .get(user_id)
             .ok_or_else(|| anyhow::anyhow!("Node not found"))?;
 
-        let payment_hash = hex::encode(sha256::Hash::hash(uuid::Uuid::new_v4().as_bytes()));
+        let mut preimage = [0u8; 32];
+        let mut payment_secret = [0u8; 32];
+        thread_rng().fill_bytes(&mut preimage);
+        thread_rng().fill_bytes(&mut payment_secret);
+        let payment_hash = self
+            .simulated_network()
+            .add_preimage(node.node_id, preimage);
+
+        let mut builder = InvoiceBuilder::new(self.network, payment_hash, payment_secret)
+            .with_description(&description);
+        if let Some(amount_msat) = amount_msat {
+            builder = builder.with_amount_msat(amount_msat);
+        }
+        let invoice = builder.build_signed(&node.node_key)?;
 
         Ok(Invoice {
-            bolt11: format!(
-                "lnbc{}m1p{}desc{}node{}",
-                amount_msat.unwrap_or(0) / 1_000_000,
-                &payment_hash[..8],
-                &description[..description.len().min(20)].replace(" ", ""),
-                &node.node_id[..16]
-            ),
-            payment_hash,
+            bolt11: invoice.to_string(),
+            payment_hash: payment_hash.to_string(),
             amount_msat,
         })
     }
 
-    pub async fn pay_invoice(&self, _user_id: &str, _bolt11: &str) -> Result<Payment> {
+    /// Route payments to mock nodes over the simulated network; payments to anyone else
+    /// stay pending
+    pub async fn pay_invoice(&self, user_id: &str, invoice: &Bolt11Invoice) -> Result<Payment> {
+        let payer = self.node_id(user_id)?;
+        let amount_msat = invoice
+            .amount_msat
+            .ok_or_else(|| anyhow::anyhow!("Invoice has no amount"))?;
+        let payee_is_mock = self
+            .nodes
+            .lock()
+            .unwrap()
+            .values()
+            .any(|node| node.node_id == invoice.payee);
+        if !payee_is_mock {
+            return Ok(Payment {
+                payment_hash: invoice.payment_hash.to_string(),
+                payment_preimage: None,
+                amount_msat,
+                fee_msat: 0,
+                status: PaymentStatus::Pending,
+            });
+        }
+
+        let params = RouteParams {
+            final_cltv_expiry_delta: invoice.min_final_cltv_expiry_delta as u32,
+            route_hints: invoice.route_hints.clone(),
+            ..Default::default()
+        };
+        let payment = self.simulated_network().pay(
+            &payer,
+            &invoice.payee,
+            amount_msat,
+            invoice.payment_hash,
+            &params,
+        );
+        if let Some(failure) = &payment.failure {
+            log::warn!("Payment {} failed: {failure}", invoice.payment_hash);
+        }
         Ok(Payment {
-            payment_hash: hex::encode(sha256::Hash::hash(b"mock_payment")),
-            payment_preimage: Some(hex::encode([0u8; 32])),
-            amount_msat: 10_000_000,
-            fee_msat: 10_000,
-            status: PaymentStatus::Succeeded,
+            payment_hash: invoice.payment_hash.to_string(),
+            payment_preimage: payment.preimage.map(hex::encode),
+            amount_msat,
+            fee_msat: payment.fee_msat,
+            status: payment.status,
         })
     }
 
@@ -213,12 +585,13 @@ impl MockLightning {
     }
 
     pub async fn get_balance(&self, user_id: &str) -> Result<Balance> {
-        let nodes = self.nodes.lock().unwrap();
-        let node = nodes
-            .get(user_id)
-            .ok_or_else(|| anyhow::anyhow!("Node not found"))?;
-
-        let channel_balance: u64 = node.channels.iter().map(|c| c.local_balance_sats).sum();
+        let node_id = self.node_id(user_id)?;
+        let channel_balance: u64 = self
+            .simulated_network()
+            .channels_of(&node_id)
+            .iter()
+            .map(|c| c.balance_msat(&node_id) / 1_000)
+            .sum();
 
         Ok(Balance {
             total_balance_sats: 1_000_000 + channel_balance,
@@ -227,3 +600,164 @@ impl MockLightning {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pays_signed_mock_invoices() {
+        let manager = LightningManager::new(Network::Regtest).await.unwrap();
+        let alice = manager.create_node("alice", [1; 32]).await.unwrap();
+        manager.create_node("bob", [2; 32]).await.unwrap();
+
+        let invoice = manager
+            .create_invoice("alice", Some(21_000), "Coffee".to_string())
+            .await
+            .unwrap();
+        let decoded = manager.decode_invoice(&invoice.bolt11).unwrap();
+        assert_eq!(decoded.payee.to_string(), alice.node_id);
+        assert_eq!(decoded.description.as_deref(), Some("Coffee"));
+        assert_eq!(decoded.payment_hash.to_string(), invoice.payment_hash);
+
+        // Nothing to route over until bob opens a channel to alice
+        let payment = manager.send_payment("bob", &invoice.bolt11).await.unwrap();
+        assert!(matches!(payment.status, PaymentStatus::Failed));
+        let channel_id = manager
+            .open_channel("bob", &alice.node_id, 100_000)
+            .await
+            .unwrap();
+
+        let payment = manager.send_payment("bob", &invoice.bolt11).await.unwrap();
+        assert!(matches!(payment.status, PaymentStatus::Succeeded));
+        assert_eq!(payment.amount_msat, 21_000);
+        let preimage = hex::decode(payment.payment_preimage.unwrap()).unwrap();
+        assert_eq!(sha256::Hash::hash(&preimage), decoded.payment_hash);
+        let channels = manager.list_channels("alice").await.unwrap();
+        assert_eq!(channels[0].channel_id, channel_id);
+        assert_eq!(channels[0].local_balance_sats, 21);
+        assert_eq!(
+            manager
+                .get_balance("bob")
+                .await
+                .unwrap()
+                .channel_balance_sats,
+            99_979
+        );
+
+        // Amountless invoices and other networks' invoices are refused
+        let open = manager
+            .create_invoice("alice", None, "Tip".to_string())
+            .await
+            .unwrap();
+        assert!(manager.send_payment("bob", &open.bolt11).await.is_err());
+        let mainnet = LightningManager::new(Network::Bitcoin).await.unwrap();
+        assert!(mainnet.decode_invoice(&invoice.bolt11).is_err());
+    }
+
+    fn key(i: u8) -> PublicKey {
+        SecretKey::from_slice(&[i; 32])
+            .unwrap()
+            .public_key(&Secp256k1::new())
+    }
+
+    #[test]
+    fn retries_around_channels_without_liquidity() {
+        let (a, b, c, d) = (key(1), key(2), key(3), key(4));
+        let mut network = SimulatedNetwork::new();
+        network.open_channel(a, b, 1_000_000, 0).unwrap();
+        network.open_channel(a, c, 1_000_000, 0).unwrap();
+        // d funded the channel from b, so b can't pay d over it, but the graph can't tell
+        network.open_channel(d, b, 1_000_000, 0).unwrap();
+        let c_to_d = network.open_channel(c, d, 1_000_000, 0).unwrap();
+        let expensive = ChannelPolicy {
+            fee_proportional_millionths: 1_000,
+            ..Default::default()
+        };
+        network.set_policy(c_to_d, &c, expensive).unwrap();
+
+        let hash = network.add_preimage(d, [7; 32]);
+        let payment = network.pay(&a, &d, 100_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Succeeded));
+        assert_eq!(payment.preimage, Some([7; 32]));
+        assert_eq!(payment.attempts, 2);
+        assert_eq!(payment.parts, 1);
+        assert_eq!(payment.fee_msat, 101_000);
+        assert_eq!(
+            network.channel(c_to_d).unwrap().balance_msat(&d),
+            100_000_000
+        );
+        let paid: u64 = network
+            .channels_of(&a)
+            .iter()
+            .map(|channel| channel.balance_msat(&a))
+            .sum();
+        assert_eq!(paid, 2_000_000_000 - 100_101_000);
+        assert!(network
+            .channels_of(&b)
+            .iter()
+            .all(|channel| channel.pending_htlcs().is_empty()));
+    }
+
+    #[test]
+    fn splits_payments_until_liquidity_runs_out() {
+        let (a, d) = (key(1), key(4));
+        let mut network = SimulatedNetwork::new();
+        let first = network.open_channel(a, d, 60_000, 0).unwrap();
+        let second = network.open_channel(a, d, 60_000, 0).unwrap();
+
+        let hash = network.add_preimage(d, [1; 32]);
+        let payment = network.pay(&a, &d, 100_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Succeeded));
+        assert_eq!(payment.parts, 2);
+        let received = network.channel(first).unwrap().balance_msat(&d)
+            + network.channel(second).unwrap().balance_msat(&d);
+        assert_eq!(received, 100_000_000);
+
+        // What's left, less the channel reserves, can't carry another payment this size
+        let hash = network.add_preimage(d, [2; 32]);
+        let payment = network.pay(&a, &d, 50_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Failed));
+        assert!(payment.failure.unwrap().contains("No route"));
+
+        // Parts that arrive for a hash the payee doesn't know are failed back
+        let unknown = sha256::Hash::hash(b"unknown");
+        let payment = network.pay(&a, &d, 15_000_000, unknown, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Failed));
+        assert_eq!(payment.fee_msat, 0);
+        let balance = network.channel(first).unwrap().balance_msat(&a)
+            + network.channel(second).unwrap().balance_msat(&a);
+        assert_eq!(balance, 20_000_000);
+        assert!(network.channel(first).unwrap().pending_htlcs().is_empty());
+        assert!(network.channel(second).unwrap().pending_htlcs().is_empty());
+    }
+
+    #[test]
+    fn fails_over_offline_nodes_and_gives_up() {
+        let (a, b, c, d) = (key(1), key(2), key(3), key(4));
+        let mut network = SimulatedNetwork::new().with_max_attempts(3);
+        network.open_channel(a, b, 1_000_000, 0).unwrap();
+        network.open_channel(b, c, 1_000_000, 0).unwrap();
+        network.open_channel(c, d, 1_000_000, 0).unwrap();
+        let hash = network.add_preimage(d, [3; 32]);
+
+        network.set_offline(b, true);
+        let payment = network.pay(&a, &d, 1_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Failed));
+        assert_eq!(payment.attempts, 0);
+
+        // c drops every HTLC b forwards, and there's no other way round
+        network.set_offline(b, false);
+        network.set_offline(c, true);
+        let payment = network.pay(&a, &d, 1_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Failed));
+        assert!(payment.attempts >= 1);
+        assert_eq!(network.channels_of(&a)[0].balance_msat(&a), 1_000_000_000);
+
+        // The scorer remembers b -> c failing, so a new channel round c gets the payment through
+        let b_to_d = network.open_channel(b, d, 1_000_000, 0).unwrap();
+        let payment = network.pay(&a, &d, 1_000_000, hash, &RouteParams::default());
+        assert!(matches!(payment.status, PaymentStatus::Succeeded));
+        assert_eq!(network.channel(b_to_d).unwrap().balance_msat(&d), 1_000_000);
+    }
+}
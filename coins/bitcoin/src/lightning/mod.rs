@@ -1,13 +1,24 @@
 use anyhow::Result;
 use bitcoin::Network;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "lightning-ldk")]
+use std::{collections::HashMap, path::Path, sync::Arc};
 
+pub mod channel_manager;
+pub mod invoice_handler;
+#[cfg(feature = "lightning-ldk")]
+pub mod ldk_real;
 pub mod mock;
+pub mod routing;
 #[cfg(feature = "lightning-voltage")]
 pub mod voltage;
 
 // Re-export the common types from mock
-pub use mock::{Balance, ChannelInfo, Invoice, NodeInfo, Payment, PaymentStatus};
+pub use invoice_handler::{Bolt11Invoice, InvoiceBuilder, RouteHint, RouteHintHop};
+pub use mock::{
+    Balance, ChannelInfo, Invoice, NodeInfo, Payment, PaymentStatus, SimulatedNetwork,
+    SimulatedPayment,
+};
 
 /// Lightning Network configuration
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -19,14 +30,29 @@ pub enum LightningConfig {
     /// Voltage Lightning Node Service
     #[cfg(feature = "lightning-voltage")]
     Voltage { api_key: String, node_url: String },
+
+    /// LDK node embedded in the process, funded from the user's Bitcoin wallet
+    #[cfg(feature = "lightning-ldk")]
+    Ldk {
+        /// Each user's node keeps its channel state in a subdirectory named after them
+        storage_dir: String,
+        /// Port peers connect on; 0 gives every node a free port
+        listening_port: u16,
+    },
 }
 
 /// Lightning Network manager
 pub struct LightningManager {
     config: LightningConfig,
+    network: Network,
     #[cfg(feature = "lightning-voltage")]
     voltage_client: Option<voltage::VoltageClient>,
     mock_backend: mock::MockLightning,
+    /// Wallet LDK nodes fund channels from and sweep closed channels to
+    #[cfg(feature = "lightning-ldk")]
+    wallet: Option<Arc<crate::BitcoinWalletManager>>,
+    #[cfg(feature = "lightning-ldk")]
+    ldk_nodes: tokio::sync::RwLock<HashMap<String, Arc<ldk_real::LdkRealNode>>>,
 }
 
 impl LightningManager {
@@ -34,7 +60,7 @@ impl LightningManager {
         Self::with_config(LightningConfig::default(), network).await
     }
 
-    pub async fn with_config(config: LightningConfig, _network: Network) -> Result<Self> {
+    pub async fn with_config(config: LightningConfig, network: Network) -> Result<Self> {
         #[cfg(feature = "lightning-voltage")]
         let voltage_client = match &config {
             LightningConfig::Voltage { api_key, node_url } => Some(voltage::VoltageClient::new(
@@ -46,12 +72,62 @@ impl LightningManager {
 
         Ok(Self {
             config,
+            network,
             #[cfg(feature = "lightning-voltage")]
             voltage_client,
-            mock_backend: mock::MockLightning::new(),
+            mock_backend: mock::MockLightning::with_network(network),
+            #[cfg(feature = "lightning-ldk")]
+            wallet: None,
+            #[cfg(feature = "lightning-ldk")]
+            ldk_nodes: Default::default(),
         })
     }
 
+    /// Manager whose LDK nodes fund and sweep channels through the users' wallets in `wallet`
+    #[cfg(feature = "lightning-ldk")]
+    pub async fn with_wallet(
+        config: LightningConfig,
+        wallet: Arc<crate::BitcoinWalletManager>,
+    ) -> Result<Self> {
+        let mut manager = Self::with_config(config, wallet.network).await?;
+        manager.wallet = Some(wallet);
+        Ok(manager)
+    }
+
+    /// Start the user's LDK node, or return the one already running
+    #[cfg(feature = "lightning-ldk")]
+    async fn start_ldk_node(
+        &self,
+        user_id: &str,
+        seed: [u8; 32],
+        storage_dir: &str,
+        listening_port: u16,
+    ) -> Result<NodeInfo> {
+        let wallet = self.wallet.clone().ok_or_else(|| {
+            anyhow::anyhow!("LDK nodes need a wallet; create the manager with with_wallet")
+        })?;
+        let mut nodes = self.ldk_nodes.write().await;
+        if let Some(node) = nodes.get(user_id) {
+            return Ok(node.node_info());
+        }
+        let dir = Path::new(storage_dir).join(user_id);
+        let node =
+            ldk_real::LdkRealNode::start(wallet, user_id, seed, &dir, listening_port).await?;
+        let info = node.node_info();
+        nodes.insert(user_id.to_string(), node);
+        Ok(info)
+    }
+
+    #[cfg(feature = "lightning-ldk")]
+    async fn ldk_node(&self, user_id: &str) -> Result<Arc<ldk_real::LdkRealNode>> {
+        self.ldk_nodes
+            .read()
+            .await
+            .get(user_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Node not found"))
+    }
+
     pub async fn create_node(&self, user_id: &str, seed: [u8; 32]) -> Result<NodeInfo> {
         match &self.config {
             #[cfg(feature = "lightning-voltage")]
@@ -64,6 +140,15 @@ impl LightningManager {
                 }
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk {
+                storage_dir,
+                listening_port,
+            } => {
+                self.start_ldk_node(user_id, seed, storage_dir, *listening_port)
+                    .await
+            }
+
             LightningConfig::Mock => self.mock_backend.create_node(user_id, seed).await,
         }
     }
@@ -79,6 +164,9 @@ impl LightningManager {
                 }
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => Ok(self.ldk_node(user_id).await?.node_info()),
+
             LightningConfig::Mock => self.mock_backend.get_node_info(user_id).await,
         }
     }
@@ -100,6 +188,12 @@ impl LightningManager {
                 }
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => self
+                .ldk_node(user_id)
+                .await?
+                .create_invoice(amount_msat, description),
+
             LightningConfig::Mock => {
                 self.mock_backend
                     .create_invoice(user_id, amount_msat, description)
@@ -108,18 +202,37 @@ impl LightningManager {
         }
     }
 
+    /// Parse a BOLT11 invoice and check that it can be paid on this network now
+    pub fn decode_invoice(&self, bolt11: &str) -> Result<Bolt11Invoice> {
+        let invoice: Bolt11Invoice = bolt11.parse()?;
+        invoice.validate(self.network)?;
+        Ok(invoice)
+    }
+
+    /// Pay a BOLT11 invoice, once it has been decoded and validated
     pub async fn send_payment(&self, user_id: &str, bolt11: &str) -> Result<Payment> {
+        let invoice = self.decode_invoice(bolt11)?;
+        if invoice.amount_msat.is_none() {
+            return Err(anyhow::anyhow!(
+                "Invoice has no amount; ask the payee for one with an amount"
+            ));
+        }
         match &self.config {
             #[cfg(feature = "lightning-voltage")]
             LightningConfig::Voltage { .. } => {
                 if let Some(client) = &self.voltage_client {
-                    client.pay_invoice(bolt11.to_string()).await
+                    client.pay_invoice(invoice.to_string()).await
                 } else {
                     Err(anyhow::anyhow!("Voltage client not initialized"))
                 }
             }
 
-            LightningConfig::Mock => self.mock_backend.pay_invoice(user_id, bolt11).await,
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => {
+                self.ldk_node(user_id).await?.pay_invoice(&invoice).await
+            }
+
+            LightningConfig::Mock => self.mock_backend.pay_invoice(user_id, &invoice).await,
         }
     }
 
@@ -134,6 +247,9 @@ impl LightningManager {
                 }
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => Ok(self.ldk_node(user_id).await?.list_channels()),
+
             LightningConfig::Mock => self.mock_backend.list_channels(user_id).await,
         }
     }
@@ -151,6 +267,14 @@ impl LightningManager {
                 Ok(())
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => {
+                self.ldk_node(user_id)
+                    .await?
+                    .connect_peer(peer_node_id, address)
+                    .await
+            }
+
             LightningConfig::Mock => {
                 self.mock_backend
                     .connect_peer(user_id, peer_node_id, address)
@@ -168,6 +292,9 @@ impl LightningManager {
                 self.mock_backend.get_balance(user_id).await
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => self.ldk_node(user_id).await?.balance().await,
+
             LightningConfig::Mock => self.mock_backend.get_balance(user_id).await,
         }
     }
@@ -189,6 +316,14 @@ impl LightningManager {
                 ))
             }
 
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => {
+                self.ldk_node(user_id)
+                    .await?
+                    .open_channel(peer_node_id, amount_sats)
+                    .await
+            }
+
             LightningConfig::Mock => {
                 self.mock_backend
                     .open_channel(user_id, peer_node_id, amount_sats)
@@ -196,5 +331,27 @@ impl LightningManager {
             }
         }
     }
+
+    /// Close a channel cooperatively, or unilaterally with `force`
+    pub async fn close_channel(&self, user_id: &str, channel_id: &str, force: bool) -> Result<()> {
+        match &self.config {
+            #[cfg(feature = "lightning-voltage")]
+            LightningConfig::Voltage { .. } => Err(anyhow::anyhow!(
+                "Channel closing with Voltage requires using the Voltage dashboard."
+            )),
+
+            #[cfg(feature = "lightning-ldk")]
+            LightningConfig::Ldk { .. } => self
+                .ldk_node(user_id)
+                .await?
+                .close_channel(channel_id, force),
+
+            LightningConfig::Mock => {
+                self.mock_backend
+                    .close_channel(user_id, channel_id, force)
+                    .await
+            }
+        }
+    }
 }
 pub mod voltage_setup;
@@ -1 +1,937 @@
-// Routing module
+// Routing module: a channel graph fed by gossip, and a pathfinder that scores routes by fee and
+// probability of success and splits payments over several paths when one can't carry them
+use super::invoice_handler::RouteHint;
+use anyhow::Result;
+use bitcoin::secp256k1::PublicKey;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Default bound on the CLTV deltas a route may add up to, as in LDK
+pub const DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA: u32 = 1008;
+/// Default number of parts a payment may be split into
+pub const DEFAULT_MAX_PARTS: usize = 8;
+
+/// How the node at one end of a channel forwards payments over it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelPolicy {
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub cltv_expiry_delta: u16,
+    pub htlc_minimum_msat: u64,
+    pub htlc_maximum_msat: u64,
+    pub disabled: bool,
+}
+
+impl Default for ChannelPolicy {
+    fn default() -> Self {
+        Self {
+            fee_base_msat: 1_000,
+            fee_proportional_millionths: 1,
+            cltv_expiry_delta: 40,
+            htlc_minimum_msat: 1,
+            htlc_maximum_msat: u64::MAX,
+            disabled: false,
+        }
+    }
+}
+
+impl ChannelPolicy {
+    /// Fee for forwarding `amount_msat` over the channel
+    pub fn fee_msat(&self, amount_msat: u64) -> u64 {
+        self.fee_base_msat as u64
+            + (amount_msat as u128 * self.fee_proportional_millionths as u128 / 1_000_000) as u64
+    }
+}
+
+/// Gossip the graph is built from (BOLT7). Channel closes are learned from the chain rather
+/// than gossip but are fed in the same way.
+#[derive(Debug, Clone)]
+pub enum GossipMessage {
+    ChannelAnnouncement {
+        short_channel_id: u64,
+        node_1: PublicKey,
+        node_2: PublicKey,
+        capacity_sats: u64,
+    },
+    ChannelUpdate {
+        short_channel_id: u64,
+        /// Node whose forwarding policy this is
+        source: PublicKey,
+        timestamp: u32,
+        policy: ChannelPolicy,
+    },
+    ChannelClosed {
+        short_channel_id: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct GraphChannel {
+    nodes: [PublicKey; 2],
+    capacity_sats: u64,
+    /// Latest policy from each end, with its timestamp
+    policies: [Option<(u32, ChannelPolicy)>; 2],
+}
+
+/// A channel in one direction, as the pathfinder sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub short_channel_id: u64,
+    pub source: PublicKey,
+    pub target: PublicKey,
+    pub policy: ChannelPolicy,
+    /// `None` for private channels from route hints, whose capacity isn't known
+    pub capacity_msat: Option<u64>,
+}
+
+/// Public channels and their forwarding policies
+#[derive(Debug, Clone, Default)]
+pub struct ChannelGraph {
+    channels: HashMap<u64, GraphChannel>,
+    nodes: HashMap<PublicKey, HashSet<u64>>,
+}
+
+impl ChannelGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a gossip message. Updates older than the policy already known are ignored.
+    pub fn apply(&mut self, message: GossipMessage) -> Result<()> {
+        match message {
+            GossipMessage::ChannelAnnouncement {
+                short_channel_id,
+                node_1,
+                node_2,
+                capacity_sats,
+            } => {
+                if node_1 == node_2 {
+                    return Err(anyhow::anyhow!(
+                        "Channel {short_channel_id} loops to itself"
+                    ));
+                }
+                if self.channels.contains_key(&short_channel_id) {
+                    return Ok(());
+                }
+                self.channels.insert(
+                    short_channel_id,
+                    GraphChannel {
+                        nodes: [node_1, node_2],
+                        capacity_sats,
+                        policies: [None, None],
+                    },
+                );
+                for node in [node_1, node_2] {
+                    self.nodes.entry(node).or_default().insert(short_channel_id);
+                }
+            }
+            GossipMessage::ChannelUpdate {
+                short_channel_id,
+                source,
+                timestamp,
+                policy,
+            } => {
+                let channel = self.channels.get_mut(&short_channel_id).ok_or_else(|| {
+                    anyhow::anyhow!("Update for unknown channel {short_channel_id}")
+                })?;
+                let direction = channel
+                    .nodes
+                    .iter()
+                    .position(|node| *node == source)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("{source} is not in channel {short_channel_id}")
+                    })?;
+                match channel.policies[direction] {
+                    Some((known, _)) if known >= timestamp => {}
+                    _ => channel.policies[direction] = Some((timestamp, policy)),
+                }
+            }
+            GossipMessage::ChannelClosed { short_channel_id } => {
+                if let Some(channel) = self.channels.remove(&short_channel_id) {
+                    for node in channel.nodes {
+                        if let Some(channels) = self.nodes.get_mut(&node) {
+                            channels.remove(&short_channel_id);
+                            if channels.is_empty() {
+                                self.nodes.remove(&node);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Capacity of a channel and the nodes at its ends
+    pub fn channel(&self, short_channel_id: u64) -> Option<(PublicKey, PublicKey, u64)> {
+        self.channels
+            .get(&short_channel_id)
+            .map(|c| (c.nodes[0], c.nodes[1], c.capacity_sats))
+    }
+
+    /// Enabled channels `node` can be paid over, in the direction towards it
+    pub fn edges_into(&self, node: &PublicKey) -> Vec<Edge> {
+        let Some(channels) = self.nodes.get(node) else {
+            return vec![];
+        };
+        channels
+            .iter()
+            .filter_map(|scid| {
+                let channel = &self.channels[scid];
+                let source = if channel.nodes[0] == *node { 1 } else { 0 };
+                let (_, policy) = channel.policies[source]?;
+                (!policy.disabled).then_some(Edge {
+                    short_channel_id: *scid,
+                    source: channel.nodes[source],
+                    target: *node,
+                    policy,
+                    capacity_msat: Some(channel.capacity_sats * 1_000),
+                })
+            })
+            .collect()
+    }
+}
+
+/// How the scorer turns liquidity estimates into a cost, in millisatoshis
+#[derive(Debug, Clone, Copy)]
+pub struct ScoringParams {
+    /// Cost of every hop, so shorter routes win between equal ones
+    pub base_penalty_msat: u64,
+    /// Cost per order of magnitude of failure probability: a hop that succeeds one time in
+    /// ten costs this much
+    pub liquidity_penalty_multiplier_msat: u64,
+    /// Learned liquidity bounds relax halfway back to the channel's capacity this often
+    pub liquidity_half_life: Duration,
+}
+
+impl Default for ScoringParams {
+    fn default() -> Self {
+        Self {
+            base_penalty_msat: 500,
+            liquidity_penalty_multiplier_msat: 30_000,
+            liquidity_half_life: Duration::from_secs(6 * 3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LiquidityBounds {
+    min_msat: u64,
+    max_msat: u64,
+    updated: Instant,
+}
+
+/// Estimates the liquidity of each channel direction from the outcome of payments over it.
+/// Without any, the liquidity is taken to be uniformly distributed over the capacity.
+#[derive(Debug, Clone, Default)]
+pub struct ProbabilisticScorer {
+    params: ScoringParams,
+    liquidity: HashMap<(u64, PublicKey), LiquidityBounds>,
+}
+
+impl ProbabilisticScorer {
+    pub fn new(params: ScoringParams) -> Self {
+        Self {
+            params,
+            liquidity: HashMap::new(),
+        }
+    }
+
+    /// What is known of the liquidity available from `edge.source`, decayed towards the
+    /// whole capacity as it ages
+    pub fn liquidity_bounds(&self, edge: &Edge) -> Option<(u64, u64)> {
+        let capacity = edge.capacity_msat?;
+        let Some(bounds) = self.liquidity.get(&(edge.short_channel_id, edge.source)) else {
+            return Some((0, capacity));
+        };
+        let half_lives =
+            bounds.updated.elapsed().as_secs() / self.params.liquidity_half_life.as_secs().max(1);
+        let keep = 0.5f64.powi(half_lives.min(64) as i32);
+        let min = (bounds.min_msat as f64 * keep).round() as u64;
+        let max =
+            capacity - (capacity.saturating_sub(bounds.max_msat) as f64 * keep).round() as u64;
+        Some((min.min(capacity), max.min(capacity)))
+    }
+
+    /// Probability that `amount_msat` more than `in_flight_msat` can be forwarded over `edge`
+    pub fn success_probability(&self, edge: &Edge, amount_msat: u64, in_flight_msat: u64) -> f64 {
+        let Some((min, max)) = self.liquidity_bounds(edge) else {
+            return 1.0;
+        };
+        let amount = amount_msat.saturating_add(in_flight_msat);
+        if amount <= min {
+            1.0
+        } else if amount > max {
+            0.0
+        } else {
+            (max - amount + 1) as f64 / (max - min + 1) as f64
+        }
+    }
+
+    /// Cost of sending `amount_msat` over `edge` on top of fees, or `None` if it can't succeed
+    pub fn penalty_msat(&self, edge: &Edge, amount_msat: u64, in_flight_msat: u64) -> Option<u64> {
+        let probability = self.success_probability(edge, amount_msat, in_flight_msat);
+        if probability <= 0.0 {
+            return None;
+        }
+        let liquidity_penalty =
+            -probability.log10() * self.params.liquidity_penalty_multiplier_msat as f64;
+        Some(self.params.base_penalty_msat + liquidity_penalty as u64)
+    }
+
+    /// Learn from a path that failed at `failed_channel`: the hops before it could forward
+    /// their amounts and it couldn't
+    pub fn payment_path_failed(&mut self, path: &RoutePath, failed_channel: u64) {
+        for hop in &path.hops {
+            if hop.short_channel_id == failed_channel {
+                self.update(hop, |bounds| {
+                    bounds.max_msat = bounds.max_msat.min(hop.amount_msat.saturating_sub(1));
+                    bounds.min_msat = bounds.min_msat.min(bounds.max_msat);
+                });
+                return;
+            }
+            self.update(hop, |bounds| {
+                bounds.min_msat = bounds.min_msat.max(hop.amount_msat);
+                bounds.max_msat = bounds.max_msat.max(bounds.min_msat);
+            });
+        }
+    }
+
+    /// Learn from a path that succeeded: every hop had the amount, which has now moved to
+    /// the other side
+    pub fn payment_path_successful(&mut self, path: &RoutePath) {
+        for hop in &path.hops {
+            self.update(hop, |bounds| {
+                bounds.min_msat = bounds.min_msat.max(hop.amount_msat) - hop.amount_msat;
+                bounds.max_msat = bounds.max_msat.saturating_sub(hop.amount_msat);
+            });
+        }
+    }
+
+    fn update(&mut self, hop: &RouteHop, f: impl FnOnce(&mut LiquidityBounds)) {
+        let Some(capacity) = hop.capacity_msat else {
+            return;
+        };
+        let edge = Edge {
+            short_channel_id: hop.short_channel_id,
+            source: hop.source,
+            target: hop.node_id,
+            policy: ChannelPolicy::default(),
+            capacity_msat: Some(capacity),
+        };
+        let (min_msat, max_msat) = self.liquidity_bounds(&edge).unwrap_or((0, capacity));
+        let mut bounds = LiquidityBounds {
+            min_msat,
+            max_msat,
+            updated: Instant::now(),
+        };
+        f(&mut bounds);
+        self.liquidity
+            .insert((hop.short_channel_id, hop.source), bounds);
+    }
+}
+
+/// One of the payer's own channels, whose outbound liquidity is known exactly
+#[derive(Debug, Clone, Copy)]
+pub struct FirstHop {
+    pub short_channel_id: u64,
+    pub counterparty: PublicKey,
+    pub outbound_msat: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteParams {
+    /// Most the payment may pay in fees across all its parts
+    pub max_fee_msat: Option<u64>,
+    pub max_total_cltv_expiry_delta: u32,
+    /// Most paths the payment may be split over; 1 disables multi-part payments
+    pub max_parts: usize,
+    pub final_cltv_expiry_delta: u32,
+    /// Private channels to the payee, from its invoice
+    pub route_hints: Vec<RouteHint>,
+}
+
+impl Default for RouteParams {
+    fn default() -> Self {
+        Self {
+            max_fee_msat: None,
+            max_total_cltv_expiry_delta: DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA,
+            max_parts: DEFAULT_MAX_PARTS,
+            final_cltv_expiry_delta: super::invoice_handler::DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA
+                as u32,
+            route_hints: vec![],
+        }
+    }
+}
+
+/// A channel a path crosses, from `source` to `node_id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteHop {
+    pub source: PublicKey,
+    pub node_id: PublicKey,
+    pub short_channel_id: u64,
+    /// Amount sent over the channel, including the fees of the hops after it
+    pub amount_msat: u64,
+    /// Fee `source` charges to forward over this channel; none on the payer's own channel
+    pub fee_msat: u64,
+    pub cltv_expiry_delta: u32,
+    capacity_msat: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePath {
+    pub hops: Vec<RouteHop>,
+}
+
+impl RoutePath {
+    /// Amount the path delivers to the payee
+    pub fn amount_msat(&self) -> u64 {
+        self.hops
+            .last()
+            .map(|hop| hop.amount_msat)
+            .unwrap_or_default()
+    }
+
+    pub fn fee_msat(&self) -> u64 {
+        self.hops.iter().map(|hop| hop.fee_msat).sum()
+    }
+
+    pub fn total_cltv_expiry_delta(&self) -> u32 {
+        self.hops.iter().map(|hop| hop.cltv_expiry_delta).sum()
+    }
+}
+
+/// Paths that together deliver a payment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub paths: Vec<RoutePath>,
+}
+
+impl Route {
+    pub fn amount_msat(&self) -> u64 {
+        self.paths.iter().map(RoutePath::amount_msat).sum()
+    }
+
+    pub fn fee_msat(&self) -> u64 {
+        self.paths.iter().map(RoutePath::fee_msat).sum()
+    }
+}
+
+/// Best-known way for a node to get the payment to the payee
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    /// What the node must receive, including the fees of every hop after it
+    amount_msat: u64,
+    cltv: u32,
+    cost: u64,
+    /// Channel the node forwards over, `None` at the payee
+    next: Option<Edge>,
+}
+
+/// Finds routes through a channel graph, weighing fees against the scorer's estimate of how
+/// likely each channel is to carry the payment
+pub struct Pathfinder<'a> {
+    graph: &'a ChannelGraph,
+    scorer: &'a ProbabilisticScorer,
+}
+
+impl<'a> Pathfinder<'a> {
+    pub fn new(graph: &'a ChannelGraph, scorer: &'a ProbabilisticScorer) -> Self {
+        Self { graph, scorer }
+    }
+
+    /// Route `amount_msat` from `payer` to `payee`. When no single path can carry it the
+    /// payment is split, each part over the cheapest path left once the earlier parts'
+    /// amounts are taken out of the channels they use. With `first_hops`, only those of the
+    /// payer's channels are used.
+    pub fn find_route(
+        &self,
+        payer: &PublicKey,
+        payee: &PublicKey,
+        amount_msat: u64,
+        first_hops: Option<&[FirstHop]>,
+        params: &RouteParams,
+    ) -> Result<Route> {
+        if amount_msat == 0 {
+            return Err(anyhow::anyhow!("Nothing to route"));
+        }
+        if payer == payee {
+            return Err(anyhow::anyhow!("Can't route a payment to ourselves"));
+        }
+        let extra_edges = extra_edges(payer, payee, first_hops.unwrap_or_default(), params);
+        let mut in_flight: HashMap<(u64, PublicKey), u64> = HashMap::new();
+        let mut paths = vec![];
+        let mut remaining = amount_msat;
+        let mut fee_budget = params.max_fee_msat.unwrap_or(u64::MAX);
+
+        while remaining > 0 {
+            if paths.len() == params.max_parts.max(1) {
+                return Err(anyhow::anyhow!(
+                    "No route for {amount_msat} msat in {} parts",
+                    params.max_parts
+                ));
+            }
+            // Try the whole remainder first, then smaller parts down to an even split over
+            // the parts left
+            let parts_left = (params.max_parts.max(1) - paths.len()) as u64;
+            let smallest = remaining.div_ceil(parts_left);
+            let mut part = remaining;
+            let path = loop {
+                let found = self.find_path(
+                    payer,
+                    payee,
+                    part,
+                    &extra_edges,
+                    first_hops.is_some(),
+                    &in_flight,
+                    fee_budget,
+                    params,
+                );
+                match found {
+                    Some(path) => break path,
+                    None if part == smallest => {
+                        return Err(anyhow::anyhow!(
+                            "No route for {amount_msat} msat to {payee}"
+                        ))
+                    }
+                    None => part = (part / 2).max(smallest),
+                }
+            };
+            for hop in &path.hops {
+                *in_flight
+                    .entry((hop.short_channel_id, hop.source))
+                    .or_default() += hop.amount_msat;
+            }
+            remaining -= part;
+            fee_budget -= path.fee_msat();
+            paths.push(path);
+        }
+        Ok(Route { paths })
+    }
+
+    /// Cheapest path for one part, searched backwards from the payee as fees depend on the
+    /// amount each hop forwards
+    #[allow(clippy::too_many_arguments)]
+    fn find_path(
+        &self,
+        payer: &PublicKey,
+        payee: &PublicKey,
+        amount_msat: u64,
+        extra_edges: &[Edge],
+        only_first_hops: bool,
+        in_flight: &HashMap<(u64, PublicKey), u64>,
+        fee_budget: u64,
+        params: &RouteParams,
+    ) -> Option<RoutePath> {
+        let mut labels: HashMap<PublicKey, Label> = HashMap::new();
+        let mut settled = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        labels.insert(
+            *payee,
+            Label {
+                amount_msat,
+                cltv: params.final_cltv_expiry_delta,
+                cost: 0,
+                next: None,
+            },
+        );
+        queue.push(Reverse((0u64, *payee)));
+
+        while let Some(Reverse((_, node))) = queue.pop() {
+            if !settled.insert(node) {
+                continue;
+            }
+            if node == *payer {
+                break;
+            }
+            let label = labels[&node];
+            // The payer's first hops and route hints replace what the graph knows of those
+            // channels
+            let graph_edges = self
+                .graph
+                .edges_into(&node)
+                .into_iter()
+                .filter(|edge| !only_first_hops || edge.source != *payer)
+                .filter(|edge| {
+                    !extra_edges
+                        .iter()
+                        .any(|extra| extra.short_channel_id == edge.short_channel_id)
+                });
+            let edges = graph_edges.chain(
+                extra_edges
+                    .iter()
+                    .filter(|edge| edge.target == node)
+                    .copied(),
+            );
+            for edge in edges {
+                if settled.contains(&edge.source) || edge.source == *payee {
+                    continue;
+                }
+                let send = label.amount_msat;
+                let policy = edge.policy;
+                if policy.disabled
+                    || send < policy.htlc_minimum_msat
+                    || send > policy.htlc_maximum_msat
+                {
+                    continue;
+                }
+                let used = in_flight
+                    .get(&(edge.short_channel_id, edge.source))
+                    .copied()
+                    .unwrap_or_default();
+                if edge
+                    .capacity_msat
+                    .is_some_and(|capacity| send.saturating_add(used) > capacity)
+                {
+                    continue;
+                }
+                let is_payer = edge.source == *payer;
+                let (fee, cltv_delta) = if is_payer {
+                    (0, 0)
+                } else {
+                    (policy.fee_msat(send), policy.cltv_expiry_delta as u32)
+                };
+                let cltv = label.cltv + cltv_delta;
+                let amount = send + fee;
+                if cltv > params.max_total_cltv_expiry_delta + params.final_cltv_expiry_delta
+                    || amount - amount_msat > fee_budget
+                {
+                    continue;
+                }
+                // The payer's own channels have exactly known liquidity
+                let penalty = if is_payer {
+                    0
+                } else {
+                    match self.scorer.penalty_msat(&edge, send, used) {
+                        Some(penalty) => penalty,
+                        None => continue,
+                    }
+                };
+                let cost = label.cost.saturating_add(fee).saturating_add(penalty);
+                if labels
+                    .get(&edge.source)
+                    .is_some_and(|known| known.cost <= cost)
+                {
+                    continue;
+                }
+                labels.insert(
+                    edge.source,
+                    Label {
+                        amount_msat: amount,
+                        cltv,
+                        cost,
+                        next: Some(edge),
+                    },
+                );
+                queue.push(Reverse((cost, edge.source)));
+            }
+        }
+
+        if !settled.contains(payer) {
+            return None;
+        }
+        let mut hops = vec![];
+        let mut node = *payer;
+        while let Some(edge) = labels[&node].next {
+            let next = labels[&edge.target];
+            hops.push(RouteHop {
+                source: node,
+                node_id: edge.target,
+                short_channel_id: edge.short_channel_id,
+                amount_msat: next.amount_msat,
+                fee_msat: labels[&node].amount_msat - next.amount_msat,
+                cltv_expiry_delta: labels[&node].cltv - next.cltv,
+                capacity_msat: edge.capacity_msat,
+            });
+            node = edge.target;
+        }
+        Some(RoutePath { hops })
+    }
+}
+
+/// Edges the graph doesn't have: the payer's first hops, whose liquidity is known, and the
+/// private channels in the invoice's route hints
+fn extra_edges(
+    payer: &PublicKey,
+    payee: &PublicKey,
+    first_hops: &[FirstHop],
+    params: &RouteParams,
+) -> Vec<Edge> {
+    let mut edges: Vec<Edge> = first_hops
+        .iter()
+        .map(|hop| Edge {
+            short_channel_id: hop.short_channel_id,
+            source: *payer,
+            target: hop.counterparty,
+            policy: ChannelPolicy {
+                fee_base_msat: 0,
+                fee_proportional_millionths: 0,
+                cltv_expiry_delta: 0,
+                htlc_minimum_msat: 1,
+                htlc_maximum_msat: hop.outbound_msat,
+                disabled: false,
+            },
+            capacity_msat: Some(hop.outbound_msat),
+        })
+        .collect();
+    for hint in &params.route_hints {
+        for (i, hop) in hint.0.iter().enumerate() {
+            let target = hint
+                .0
+                .get(i + 1)
+                .map(|next| next.src_node_id)
+                .unwrap_or(*payee);
+            edges.push(Edge {
+                short_channel_id: hop.short_channel_id,
+                source: hop.src_node_id,
+                target,
+                policy: ChannelPolicy {
+                    fee_base_msat: hop.fee_base_msat,
+                    fee_proportional_millionths: hop.fee_proportional_millionths,
+                    cltv_expiry_delta: hop.cltv_expiry_delta,
+                    htlc_minimum_msat: 1,
+                    htlc_maximum_msat: u64::MAX,
+                    disabled: false,
+                },
+                capacity_msat: None,
+            });
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::invoice_handler::RouteHintHop;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+    fn key(i: u8) -> PublicKey {
+        SecretKey::from_slice(&[i; 32])
+            .unwrap()
+            .public_key(&Secp256k1::new())
+    }
+
+    fn add_channel(graph: &mut ChannelGraph, scid: u64, a: u8, b: u8, sats: u64, fee_ppm: u32) {
+        graph
+            .apply(GossipMessage::ChannelAnnouncement {
+                short_channel_id: scid,
+                node_1: key(a),
+                node_2: key(b),
+                capacity_sats: sats,
+            })
+            .unwrap();
+        for source in [a, b] {
+            graph
+                .apply(GossipMessage::ChannelUpdate {
+                    short_channel_id: scid,
+                    source: key(source),
+                    timestamp: 1,
+                    policy: ChannelPolicy {
+                        fee_proportional_millionths: fee_ppm,
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+    }
+
+    fn scids(path: &RoutePath) -> Vec<u64> {
+        path.hops.iter().map(|hop| hop.short_channel_id).collect()
+    }
+
+    #[test]
+    fn routes_over_the_cheapest_likely_path() {
+        // 1 -> 2 -> 4 is cheap, 1 -> 3 -> 4 expensive
+        let mut graph = ChannelGraph::new();
+        add_channel(&mut graph, 1, 1, 2, 1_000_000, 100);
+        add_channel(&mut graph, 2, 2, 4, 1_000_000, 100);
+        add_channel(&mut graph, 3, 1, 3, 1_000_000, 5_000);
+        add_channel(&mut graph, 4, 3, 4, 1_000_000, 5_000);
+        assert_eq!((graph.node_count(), graph.channel_count()), (4, 4));
+
+        let mut scorer = ProbabilisticScorer::default();
+        let params = RouteParams {
+            max_parts: 1,
+            ..Default::default()
+        };
+        let route = Pathfinder::new(&graph, &scorer)
+            .find_route(&key(1), &key(4), 100_000_000, None, &params)
+            .unwrap();
+        assert_eq!(route.paths.len(), 1);
+        let path = &route.paths[0];
+        assert_eq!(scids(path), vec![1, 2]);
+        assert_eq!(path.amount_msat(), 100_000_000);
+        // Only node 2 charges: 1000 msat base plus 100 ppm
+        assert_eq!(path.fee_msat(), 11_000);
+        assert_eq!(path.hops[0].amount_msat, 100_011_000);
+        assert_eq!(path.total_cltv_expiry_delta(), 40);
+
+        // Once channel 2 fails for the amount the expensive path wins
+        scorer.payment_path_failed(path, 2);
+        let route = Pathfinder::new(&graph, &scorer)
+            .find_route(&key(1), &key(4), 100_000_000, None, &params)
+            .unwrap();
+        assert_eq!(scids(&route.paths[0]), vec![3, 4]);
+
+        // Stale updates are ignored and disabled channels skipped
+        graph
+            .apply(GossipMessage::ChannelUpdate {
+                short_channel_id: 4,
+                source: key(3),
+                timestamp: 0,
+                policy: ChannelPolicy {
+                    disabled: true,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert_eq!(graph.edges_into(&key(4)).len(), 2);
+        graph
+            .apply(GossipMessage::ChannelUpdate {
+                short_channel_id: 4,
+                source: key(3),
+                timestamp: 2,
+                policy: ChannelPolicy {
+                    disabled: true,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert!(Pathfinder::new(&graph, &scorer)
+            .find_route(&key(1), &key(4), 100_000_000, None, &params)
+            .is_err());
+    }
+
+    #[test]
+    fn success_probability_follows_learned_liquidity() {
+        let mut graph = ChannelGraph::new();
+        add_channel(&mut graph, 1, 1, 2, 1_000, 0);
+        let edge = graph.edges_into(&key(2))[0];
+        let mut scorer = ProbabilisticScorer::default();
+        let half = scorer.success_probability(&edge, 500_000, 0);
+        assert!((half - 0.5).abs() < 0.01);
+        assert_eq!(scorer.success_probability(&edge, 1_000_001, 0), 0.0);
+
+        let path = RoutePath {
+            hops: vec![RouteHop {
+                source: key(1),
+                node_id: key(2),
+                short_channel_id: 1,
+                amount_msat: 600_000,
+                fee_msat: 0,
+                cltv_expiry_delta: 0,
+                capacity_msat: edge.capacity_msat,
+            }],
+        };
+        scorer.payment_path_failed(&path, 99);
+        assert_eq!(scorer.success_probability(&edge, 600_000, 0), 1.0);
+        scorer.payment_path_successful(&path);
+        assert_eq!(scorer.liquidity_bounds(&edge).map(|b| b.0), Some(0));
+        assert!(scorer.penalty_msat(&edge, 400_001, 0).is_none());
+    }
+
+    #[test]
+    fn splits_payments_no_path_can_carry() {
+        let mut graph = ChannelGraph::new();
+        add_channel(&mut graph, 1, 1, 2, 60_000, 0);
+        add_channel(&mut graph, 2, 2, 4, 60_000, 0);
+        add_channel(&mut graph, 3, 1, 3, 60_000, 0);
+        add_channel(&mut graph, 4, 3, 4, 60_000, 0);
+        let scorer = ProbabilisticScorer::default();
+        let pathfinder = Pathfinder::new(&graph, &scorer);
+
+        let route = pathfinder
+            .find_route(&key(1), &key(4), 100_000_000, None, &RouteParams::default())
+            .unwrap();
+        assert_eq!(route.paths.len(), 2);
+        assert_eq!(route.amount_msat(), 100_000_000);
+        let single = RouteParams {
+            max_parts: 1,
+            ..Default::default()
+        };
+        assert!(pathfinder
+            .find_route(&key(1), &key(4), 100_000_000, None, &single)
+            .is_err());
+        assert!(pathfinder
+            .find_route(&key(1), &key(4), 130_000_000, None, &RouteParams::default())
+            .is_err());
+
+        // The payer's own balance bounds what its channels can carry
+        let first_hops = [
+            FirstHop {
+                short_channel_id: 1,
+                counterparty: key(2),
+                outbound_msat: 10_000_000,
+            },
+            FirstHop {
+                short_channel_id: 3,
+                counterparty: key(3),
+                outbound_msat: 50_000_000,
+            },
+        ];
+        assert!(pathfinder
+            .find_route(
+                &key(1),
+                &key(4),
+                70_000_000,
+                Some(&first_hops),
+                &RouteParams::default()
+            )
+            .is_err());
+        let route = pathfinder
+            .find_route(
+                &key(1),
+                &key(4),
+                55_000_000,
+                Some(&first_hops),
+                &RouteParams::default(),
+            )
+            .unwrap();
+        assert_eq!(route.amount_msat(), 55_000_000);
+    }
+
+    #[test]
+    fn follows_route_hints_to_private_payees() {
+        let mut graph = ChannelGraph::new();
+        add_channel(&mut graph, 1, 1, 2, 1_000_000, 0);
+        let scorer = ProbabilisticScorer::default();
+        let pathfinder = Pathfinder::new(&graph, &scorer);
+        assert!(pathfinder
+            .find_route(&key(1), &key(5), 1_000_000, None, &RouteParams::default())
+            .is_err());
+
+        let params = RouteParams {
+            route_hints: vec![RouteHint(vec![RouteHintHop {
+                src_node_id: key(2),
+                short_channel_id: 7,
+                fee_base_msat: 2_000,
+                fee_proportional_millionths: 0,
+                cltv_expiry_delta: 144,
+            }])],
+            ..Default::default()
+        };
+        let route = pathfinder
+            .find_route(&key(1), &key(5), 1_000_000, None, &params)
+            .unwrap();
+        assert_eq!(scids(&route.paths[0]), vec![1, 7]);
+        assert_eq!(route.fee_msat(), 2_000);
+
+        let capped = RouteParams {
+            max_fee_msat: Some(1_000),
+            ..params
+        };
+        assert!(pathfinder
+            .find_route(&key(1), &key(5), 1_000_000, None, &capped)
+            .is_err());
+    }
+}
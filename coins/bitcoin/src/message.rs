@@ -0,0 +1,565 @@
+// Message signing module: BIP322 generic signed messages, and the legacy `signmessage` format
+// (with BIP137 headers for segwit addresses)
+//
+// A BIP322 signature is a spend of a virtual `to_spend` output locked to the address, which
+// commits to the message. Signing goes through the usual PSBT machinery, so multisig scripts
+// are signed by each cosigner and finalized like any other PSBT.
+use crate::security::Signer;
+use crate::transaction_builder::psbt_handler;
+use anyhow::Result;
+use bitcoin::base64::{engine::general_purpose, Engine as _};
+use bitcoin::bip32::DerivationPath;
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::consensus::encode::{deserialize, serialize, VarInt};
+use bitcoin::ecdsa;
+use bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use bitcoin::secp256k1::{All, Message, Secp256k1, XOnlyPublicKey};
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache};
+use bitcoin::taproot;
+use bitcoin::{
+    absolute, transaction, Address, Amount, OutPoint, PublicKey, Script, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Witness,
+};
+
+const BIP322_TAG: &[u8] = b"BIP0322-signed-message";
+const LEGACY_MAGIC: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+/// How a signed message is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// 65-byte recoverable signature from `signmessage`, for single-key addresses
+    Legacy,
+    /// BIP322 witness stack, for segwit addresses
+    Simple,
+    /// BIP322 `to_sign` transaction, for any script
+    Full,
+}
+
+impl SignatureFormat {
+    /// Format wallets expect for an address: `signmessage` for P2PKH, the witness alone for
+    /// native segwit and the full transaction when a script sig is needed
+    pub fn for_script(script_pubkey: &Script) -> Self {
+        if script_pubkey.is_p2pkh() {
+            SignatureFormat::Legacy
+        } else if script_pubkey.is_witness_program() {
+            SignatureFormat::Simple
+        } else {
+            SignatureFormat::Full
+        }
+    }
+}
+
+/// BIP340 tagged hash of a message
+pub fn message_hash(message: &[u8]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(BIP322_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Virtual transaction paying to `script_pubkey` that commits to the message
+pub fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    let script_sig = Builder::new()
+        .push_int(0)
+        .push_slice(message_hash(message).to_byte_array())
+        .into_script();
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    }
+}
+
+/// Unsigned transaction spending `to_spend` to an empty `OP_RETURN`
+fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.txid(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// The `to_sign` transaction as a PSBT, for signers to fill in like a spend of the address.
+/// Finalize it and pass it to `encode_signature`.
+pub fn to_sign_psbt(script_pubkey: &Script, message: &[u8]) -> Result<Psbt> {
+    let to_spend = to_spend(script_pubkey, message);
+    let mut psbt = Psbt::from_unsigned_tx(to_sign(&to_spend))?;
+    psbt.inputs[0].witness_utxo = Some(to_spend.output[0].clone());
+    psbt.inputs[0].non_witness_utxo = Some(to_spend);
+    Ok(psbt)
+}
+
+/// Base64 BIP322 signature from a finalized `to_sign` PSBT
+pub fn encode_signature(psbt: &Psbt, format: SignatureFormat) -> Result<String> {
+    let tx = psbt_handler::extract_transaction(psbt.clone())?;
+    let bytes = match format {
+        SignatureFormat::Simple if !tx.input[0].script_sig.is_empty() => {
+            return Err(anyhow::anyhow!(
+                "The address needs a script sig, which only the full format carries"
+            ))
+        }
+        SignatureFormat::Simple => serialize(&tx.input[0].witness),
+        SignatureFormat::Full => serialize(&tx),
+        SignatureFormat::Legacy => {
+            return Err(anyhow::anyhow!("Legacy signatures aren't made from PSBTs"))
+        }
+    };
+    Ok(general_purpose::STANDARD.encode(bytes))
+}
+
+/// Double SHA256 of the message behind the `signmessage` magic
+fn signed_msg_hash(message: &[u8]) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(LEGACY_MAGIC);
+    engine.input(&serialize(&VarInt(message.len() as u64)));
+    engine.input(message);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// `signmessage` signature by the key at `path`, with the BIP137 header for the address type
+pub fn sign_legacy(
+    signer: &dyn Signer,
+    path: &DerivationPath,
+    script_pubkey: &Script,
+    message: &[u8],
+    secp: &Secp256k1<All>,
+) -> Result<String> {
+    let header = if script_pubkey.is_p2pkh() {
+        31
+    } else if script_pubkey.is_p2sh() {
+        35
+    } else if script_pubkey.is_p2wpkh() {
+        39
+    } else {
+        return Err(anyhow::anyhow!(
+            "Legacy signatures only cover single-key addresses"
+        ));
+    };
+    let pubkey = signer.public_key(path)?;
+    let msg = Message::from_digest(signed_msg_hash(message).to_byte_array());
+    let compact = signer.sign_ecdsa(path, &msg)?.serialize_compact();
+
+    // Signers don't return the recovery ID, so find the one that gives back our key
+    for id in 0..4 {
+        let recoverable = RecoverableSignature::from_compact(&compact, RecoveryId::from_i32(id)?)?;
+        if secp.recover_ecdsa(&msg, &recoverable).ok() == Some(pubkey.inner) {
+            let mut bytes = vec![header + id as u8];
+            bytes.extend_from_slice(&compact);
+            return Ok(general_purpose::STANDARD.encode(bytes));
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Signature doesn't recover to the signing key"
+    ))
+}
+
+/// Check a signature of `message` by `address` in any of the formats. Returns false for a
+/// valid signature that doesn't match; errors for signatures that can't be decoded or use a
+/// script other than single-key, multisig and taproot key-path spends.
+pub fn verify_message(
+    address: &Address,
+    message: &[u8],
+    signature: &str,
+    secp: &Secp256k1<All>,
+) -> Result<bool> {
+    let bytes = general_purpose::STANDARD.decode(signature.trim())?;
+    let script_pubkey = address.script_pubkey();
+    if bytes.len() == 65 && (27..=42).contains(&bytes[0]) {
+        return verify_legacy(&script_pubkey, message, &bytes, secp);
+    }
+
+    let to_spend = to_spend(&script_pubkey, message);
+    let to_sign = if let Ok(tx) = deserialize::<Transaction>(&bytes) {
+        if tx.input.len() != 1 {
+            return Err(anyhow::anyhow!(
+                "Signatures proving funds with extra inputs aren't supported"
+            ));
+        }
+        if tx.input[0].previous_output != OutPoint::new(to_spend.txid(), 0)
+            || tx.output != to_sign(&to_spend).output
+        {
+            return Ok(false);
+        }
+        tx
+    } else {
+        let witness: Witness = deserialize(&bytes)
+            .map_err(|_| anyhow::anyhow!("Signature is neither a transaction nor a witness"))?;
+        let mut tx = to_sign(&to_spend);
+        tx.input[0].witness = witness;
+        tx
+    };
+    verify_spend(&to_sign, &to_spend.output[0], secp)
+}
+
+fn verify_legacy(
+    script_pubkey: &Script,
+    message: &[u8],
+    bytes: &[u8],
+    secp: &Secp256k1<All>,
+) -> Result<bool> {
+    let header = bytes[0] - 27;
+    let recoverable = RecoverableSignature::from_compact(
+        &bytes[1..],
+        RecoveryId::from_i32((header % 4) as i32)?,
+    )?;
+    let msg = Message::from_digest(signed_msg_hash(message).to_byte_array());
+    let Ok(inner) = secp.recover_ecdsa(&msg, &recoverable) else {
+        return Ok(false);
+    };
+    let pubkey = PublicKey {
+        compressed: header >= 4,
+        inner,
+    };
+
+    // Like most wallets, accept any header for the address types a compressed key has
+    let mut scripts = vec![ScriptBuf::new_p2pkh(&pubkey.pubkey_hash())];
+    if let Some(wpkh) = pubkey.wpubkey_hash() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&wpkh);
+        scripts.push(ScriptBuf::new_p2sh(&p2wpkh.script_hash()));
+        scripts.push(p2wpkh);
+    }
+    Ok(scripts.iter().any(|s| s.as_script() == script_pubkey))
+}
+
+/// Check the only input of `tx` spends `spent`, for the script templates the wallet signs
+fn verify_spend(tx: &Transaction, spent: &TxOut, secp: &Secp256k1<All>) -> Result<bool> {
+    let input = &tx.input[0];
+    let script_pubkey = &spent.script_pubkey;
+    let mut cache = SighashCache::new(tx);
+
+    if script_pubkey.is_p2tr() {
+        if input.witness.len() != 1 {
+            return Err(anyhow::anyhow!(
+                "Only taproot key-path signatures are supported"
+            ));
+        }
+        let Ok(sig) = taproot::Signature::from_slice(&input.witness[0]) else {
+            return Ok(false);
+        };
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+        let sighash =
+            cache.taproot_key_spend_signature_hash(0, &Prevouts::All(&[spent]), sig.hash_ty)?;
+        let msg = Message::from_digest(sighash.to_byte_array());
+        return Ok(secp.verify_schnorr(&sig.sig, &msg, &output_key).is_ok());
+    }
+    if script_pubkey.is_p2wpkh() {
+        return verify_p2wpkh(&mut cache, script_pubkey, &input.witness, secp);
+    }
+    if script_pubkey.is_p2wsh() {
+        return verify_p2wsh(&mut cache, script_pubkey, &input.witness, secp);
+    }
+
+    let Some(pushes) = pushes(&input.script_sig) else {
+        return Ok(false);
+    };
+    if script_pubkey.is_p2pkh() {
+        let [sig, pubkey] = &pushes[..] else {
+            return Ok(false);
+        };
+        let pubkey = PublicKey::from_slice(pubkey)?;
+        if ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()) != *script_pubkey {
+            return Ok(false);
+        }
+        let Ok(sig) = ecdsa::Signature::from_slice(sig) else {
+            return Ok(false);
+        };
+        let sighash = cache.legacy_signature_hash(0, script_pubkey, sig.hash_ty.to_u32())?;
+        let msg = Message::from_digest(sighash.to_byte_array());
+        return Ok(secp.verify_ecdsa(&msg, &sig.sig, &pubkey.inner).is_ok());
+    }
+    if script_pubkey.is_p2sh() {
+        let Some((redeem_script, stack)) = pushes.split_last() else {
+            return Ok(false);
+        };
+        let redeem_script = ScriptBuf::from_bytes(redeem_script.clone());
+        if ScriptBuf::new_p2sh(&redeem_script.script_hash()) != *script_pubkey {
+            return Ok(false);
+        }
+        if redeem_script.is_p2wpkh() && stack.is_empty() {
+            return verify_p2wpkh(&mut cache, &redeem_script, &input.witness, secp);
+        }
+        if redeem_script.is_p2wsh() && stack.is_empty() {
+            return verify_p2wsh(&mut cache, &redeem_script, &input.witness, secp);
+        }
+        let (threshold, keys) = psbt_handler::parse_multisig(&redeem_script)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported redeem script"))?;
+        return check_multisig(threshold, &keys, stack, secp, |hash_ty| {
+            Ok(cache
+                .legacy_signature_hash(0, &redeem_script, hash_ty.to_u32())?
+                .to_byte_array())
+        });
+    }
+    Err(anyhow::anyhow!("Unsupported script type"))
+}
+
+/// Data pushed by a script sig, or `None` if it runs other opcodes
+fn pushes(script_sig: &Script) -> Option<Vec<Vec<u8>>> {
+    script_sig
+        .instructions()
+        .map(|ins| match ins {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes().to_vec()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Single-key segwit v0 spend of `script`, a P2WPKH output or redeem script
+fn verify_p2wpkh(
+    cache: &mut SighashCache<&Transaction>,
+    script: &Script,
+    witness: &Witness,
+    secp: &Secp256k1<All>,
+) -> Result<bool> {
+    if witness.len() != 2 {
+        return Ok(false);
+    }
+    let pubkey = PublicKey::from_slice(&witness[1])?;
+    let Some(wpkh) = pubkey.wpubkey_hash() else {
+        return Ok(false);
+    };
+    if ScriptBuf::new_p2wpkh(&wpkh) != *script {
+        return Ok(false);
+    }
+    let Ok(sig) = ecdsa::Signature::from_slice(&witness[0]) else {
+        return Ok(false);
+    };
+    let sighash = cache.p2wpkh_signature_hash(0, script, Amount::ZERO, sig.hash_ty)?;
+    let msg = Message::from_digest(sighash.to_byte_array());
+    Ok(secp.verify_ecdsa(&msg, &sig.sig, &pubkey.inner).is_ok())
+}
+
+/// Multisig spend of `script`, a P2WSH output or redeem script
+fn verify_p2wsh(
+    cache: &mut SighashCache<&Transaction>,
+    script: &Script,
+    witness: &Witness,
+    secp: &Secp256k1<All>,
+) -> Result<bool> {
+    let stack = witness.to_vec();
+    let Some((witness_script, stack)) = stack.split_last() else {
+        return Ok(false);
+    };
+    let witness_script = ScriptBuf::from_bytes(witness_script.clone());
+    if ScriptBuf::new_p2wsh(&witness_script.wscript_hash()) != *script {
+        return Ok(false);
+    }
+    let (threshold, keys) = psbt_handler::parse_multisig(&witness_script)
+        .ok_or_else(|| anyhow::anyhow!("Only multisig witness scripts are supported"))?;
+    check_multisig(threshold, &keys, stack, secp, |hash_ty| {
+        Ok(cache
+            .p2wsh_signature_hash(0, &witness_script, Amount::ZERO, hash_ty)?
+            .to_byte_array())
+    })
+}
+
+/// `OP_CHECKMULTISIG` over a stack of the dummy element and `threshold` signatures
+fn check_multisig(
+    threshold: usize,
+    keys: &[PublicKey],
+    stack: &[Vec<u8>],
+    secp: &Secp256k1<All>,
+    mut sighash: impl FnMut(EcdsaSighashType) -> Result<[u8; 32]>,
+) -> Result<bool> {
+    let Some((dummy, sigs)) = stack.split_first() else {
+        return Ok(false);
+    };
+    if !dummy.is_empty() || sigs.len() != threshold {
+        return Ok(false);
+    }
+    // Signatures must match keys in script order, each key used once
+    let mut keys = keys.iter();
+    for sig in sigs {
+        let Ok(sig) = ecdsa::Signature::from_slice(sig) else {
+            return Ok(false);
+        };
+        let msg = Message::from_digest(sighash(sig.hash_ty)?);
+        if !keys
+            .by_ref()
+            .any(|key| secp.verify_ecdsa(&msg, &sig.sig, &key.inner).is_ok())
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressType, BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::bip32::{DerivationPath, Xpriv};
+    use bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG;
+    use bitcoin::Network;
+    use std::str::FromStr;
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn address(s: &str) -> Address {
+        Address::from_str(s)
+            .unwrap()
+            .require_network(Network::Bitcoin)
+            .unwrap()
+    }
+
+    #[test]
+    fn bip322_test_vectors() {
+        assert_eq!(
+            message_hash(b"").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash(b"Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+
+        let secp = Secp256k1::new();
+        let p2wpkh = address("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l");
+        let spend = to_spend(&p2wpkh.script_pubkey(), b"Hello World");
+        assert_eq!(
+            spend.txid().to_string(),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+        assert_eq!(
+            to_sign(&spend).txid().to_string(),
+            "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"
+        );
+        let simple = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        assert!(verify_message(&p2wpkh, b"Hello World", simple, &secp).unwrap());
+        assert!(!verify_message(&p2wpkh, b"Hello World!", simple, &secp).unwrap());
+
+        let p2tr = address("bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3");
+        let taproot = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert!(verify_message(&p2tr, b"Hello World", taproot, &secp).unwrap());
+        assert!(!verify_message(&p2wpkh, b"Hello World", taproot, &secp).unwrap());
+    }
+
+    #[tokio::test]
+    async fn signs_every_address_type() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Bitcoin,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        manager
+            .create_wallet("alice", Some(MNEMONIC.to_string()))
+            .await
+            .unwrap();
+
+        for address_type in AddressType::ALL {
+            let address = manager
+                .get_receive_address("alice", address_type)
+                .await
+                .unwrap();
+            let signature = manager
+                .sign_message("alice", &address, "proof of reserves", None)
+                .await
+                .unwrap();
+            assert!(manager
+                .verify_message(&address, "proof of reserves", &signature)
+                .unwrap());
+            assert!(!manager
+                .verify_message(&address, "something else", &signature)
+                .unwrap());
+        }
+
+        // Single-key addresses also sign in the other formats they support
+        let p2sh = manager
+            .get_receive_address("alice", AddressType::SegwitP2SH)
+            .await
+            .unwrap();
+        let legacy = manager
+            .sign_message("alice", &p2sh, "hi", Some(SignatureFormat::Legacy))
+            .await
+            .unwrap();
+        // BIP137 header for P2SH-P2WPKH
+        let header = general_purpose::STANDARD.decode(&legacy).unwrap()[0];
+        assert!((35..39).contains(&header));
+        assert!(manager.verify_message(&p2sh, "hi", &legacy).unwrap());
+        assert!(manager
+            .sign_message("alice", &p2sh, "hi", Some(SignatureFormat::Simple))
+            .await
+            .is_err());
+        let p2pkh = manager
+            .get_receive_address("alice", AddressType::Legacy)
+            .await
+            .unwrap();
+        let full = manager
+            .sign_message("alice", &p2pkh, "hi", Some(SignatureFormat::Full))
+            .await
+            .unwrap();
+        assert!(manager.verify_message(&p2pkh, "hi", &full).unwrap());
+        assert!(!manager.verify_message(&p2sh, "hi", &full).unwrap());
+    }
+
+    #[test]
+    fn signs_multisig_through_psbts() {
+        let secp = Secp256k1::new();
+        let cosigners: Vec<Xpriv> = (1..=3u8)
+            .map(|i| Xpriv::new_master(Network::Bitcoin, &[i; 32]).unwrap())
+            .collect();
+        let keys: Vec<PublicKey> = cosigners
+            .iter()
+            .map(|x| x.to_priv().public_key(&secp))
+            .collect();
+        let mut builder = Builder::new().push_int(2);
+        for key in &keys {
+            builder = builder.push_key(key);
+        }
+        let witness_script = builder
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let address = Address::p2wsh(&witness_script, Network::Bitcoin);
+
+        let mut psbt = to_sign_psbt(&address.script_pubkey(), b"2-of-3 treasury").unwrap();
+        psbt.inputs[0].witness_script = Some(witness_script);
+        for (cosigner, key) in cosigners.iter().zip(&keys) {
+            psbt.inputs[0].bip32_derivation.insert(
+                key.inner,
+                (cosigner.fingerprint(&secp), DerivationPath::master()),
+            );
+        }
+        // Each cosigner signs their own copy, as they would on separate devices
+        let copies: Vec<Psbt> = [&cosigners[0], &cosigners[2]]
+            .into_iter()
+            .map(|cosigner| {
+                let mut copy = psbt.clone();
+                copy.sign(cosigner, &secp).unwrap();
+                copy
+            })
+            .collect();
+        let mut signed = psbt_handler::combine_psbts(copies).unwrap();
+        psbt_handler::finalize_psbt(&mut signed).unwrap();
+
+        let signature = encode_signature(&signed, SignatureFormat::Full).unwrap();
+        assert!(verify_message(&address, b"2-of-3 treasury", &signature, &secp).unwrap());
+        assert!(!verify_message(&address, b"1-of-3 treasury", &signature, &secp).unwrap());
+    }
+}
@@ -1,9 +1,1293 @@
-// Simplified multi-wallet module
+// Multi-signature treasury wallets
+//
+// Cosigners share BIP48 account xpubs. Spends go through a proposal: the PSBT is created once,
+// cosigners add signatures (ECDSA for P2WSH, two MuSig2 rounds for P2TR) and the proposal is
+// broadcast once the threshold is met.
+pub mod musig;
+
+use crate::rpc_pool::RpcPool;
+use crate::transaction_builder::script_builder::{
+    combinations, Descriptor, DescriptorKey, ExtendedKey, Miniscript,
+};
+use crate::transaction_builder::{psbt_handler, TransactionBuilder};
+use crate::utxo_manager::{CoinSelectionParams, SelectionStrategy, UtxoManager};
+use crate::RpcEndpoint;
+use anyhow::Result;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource, Xpriv, Xpub};
+use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{rand, All, PublicKey, Secp256k1, XOnlyPublicKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, LeafVersion, TapLeafHash, TaprootSpendInfo};
+use bitcoin::{Address, FeeRate, Network, OutPoint, ScriptBuf, Transaction, TxOut, Txid, Weight};
+use bitcoincore_rpc::RpcApi;
+use musig::{KeyAggContext, PartialSignature, PubNonce, SecNonce, Session};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{RwLock, Semaphore};
+
+/// Largest number of cosigners in a wallet
+const MAX_COSIGNERS: usize = 15;
+
+/// Largest number of k-of-n subset leaves in a MuSig2 script tree
+const MAX_TAPROOT_LEAVES: usize = 256;
+
+/// How long coins stay reserved for an open proposal
+const PROPOSAL_RESERVATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const RECEIVE_CHAIN: u32 = 0;
+const CHANGE_CHAIN: u32 = 1;
 
 pub struct EnterpriseWalletManager {
-    // Simplified for now
+    wallets: Arc<RwLock<HashMap<String, MultisigWallet>>>,
+    proposals: Arc<RwLock<HashMap<String, SpendProposal>>>,
+    /// UTXOs keyed by wallet ID
+    utxo_manager: Arc<RwLock<UtxoManager>>,
+    secp: Arc<Secp256k1<All>>,
+    /// Bitcoin Core endpoints with failover
+    rpc: RpcPool,
+    /// Limits concurrent node operations (syncs and broadcasts)
+    ops: Arc<Semaphore>,
+    network: Network,
 }
 
 pub struct EnterpriseConfig {
     pub max_concurrent_ops: usize,
+    pub network: Network,
+    pub rpc_endpoints: Vec<RpcEndpoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultisigKind {
+    /// `wsh(sortedmulti(k, ...))`, BIP48 script type 2'
+    SortedMultiP2wsh,
+    /// Taproot output keyed by the MuSig2 aggregate of every cosigner, with one
+    /// `<aggregate> OP_CHECKSIG` leaf per k-sized signer subset when k < n.
+    /// BIP48 has no taproot script type yet, so 3' is used.
+    MuSig2P2tr,
+}
+
+impl MultisigKind {
+    fn bip48_script_type(&self) -> u32 {
+        match self {
+            MultisigKind::SortedMultiP2wsh => 2,
+            MultisigKind::MuSig2P2tr => 3,
+        }
+    }
+}
+
+/// A cosigner's account key, `[fingerprint/48'/coin'/account'/script_type']xpub`
+#[derive(Debug, Clone)]
+struct Cosigner {
+    fingerprint: Fingerprint,
+    key: DescriptorKey,
+}
+
+impl Cosigner {
+    fn parse(key: &str) -> Result<Self> {
+        let key = DescriptorKey::from_str(key)?;
+        match &key {
+            DescriptorKey::Extended {
+                origin: Some((fingerprint, _)),
+                key: ExtendedKey::Public(_),
+                path,
+                wildcard: false,
+            } if path.is_empty() => Ok(Self {
+                fingerprint: *fingerprint,
+                key,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Cosigner key must be an account xpub with its origin, e.g. [fingerprint/48'/0'/0'/2']xpub"
+            )),
+        }
+    }
+
+    /// The account key extended with `/chain/*`
+    fn ranged(&self, chain: u32) -> DescriptorKey {
+        match &self.key {
+            DescriptorKey::Extended { origin, key, .. } => DescriptorKey::Extended {
+                origin: origin.clone(),
+                key: key.clone(),
+                path: DerivationPath::from(vec![ChildNumber::Normal { index: chain }]),
+                wildcard: true,
+            },
+            single => single.clone(),
+        }
+    }
+
+    fn derive(
+        &self,
+        chain: u32,
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<(PublicKey, KeySource)> {
+        let ranged = self.ranged(chain);
+        let key = ranged.derive(index, secp)?.inner;
+        let source = ranged
+            .key_source(index, secp)?
+            .ok_or_else(|| anyhow::anyhow!("Cosigner key has no origin"))?;
+        Ok((key, source))
+    }
+}
+
+/// Account key a cosigner shares to join a wallet: m/48'/coin'/account'/script_type'
+pub fn cosigner_key(
+    master: &Xpriv,
+    kind: MultisigKind,
+    account: u32,
+    secp: &Secp256k1<All>,
+) -> Result<String> {
+    let coin = if master.network == Network::Bitcoin {
+        0
+    } else {
+        1
+    };
+    let path = DerivationPath::from(vec![
+        ChildNumber::from_hardened_idx(48)?,
+        ChildNumber::from_hardened_idx(coin)?,
+        ChildNumber::from_hardened_idx(account)?,
+        ChildNumber::from_hardened_idx(kind.bip48_script_type())?,
+    ]);
+    let xpub = Xpub::from_priv(secp, &master.derive_priv(secp, &path)?);
+    let key = DescriptorKey::Extended {
+        origin: Some((master.fingerprint(secp), path)),
+        key: ExtendedKey::Public(xpub),
+        path: DerivationPath::master(),
+        wildcard: false,
+    };
+    Ok(key.to_string())
+}
+
+/// A k-of-n wallet shared by several cosigners
+#[derive(Debug, Clone)]
+pub struct MultisigWallet {
+    pub id: String,
+    pub kind: MultisigKind,
+    pub threshold: usize,
+    /// Sorted by fingerprint
+    cosigners: Vec<Cosigner>,
+    next_receive: u32,
+    next_change: u32,
+    /// (chain, index) of every script handed out
+    scripts: HashMap<ScriptBuf, (u32, u32)>,
+    network: Network,
+}
+
+impl MultisigWallet {
+    pub fn fingerprints(&self) -> Vec<Fingerprint> {
+        self.cosigners.iter().map(|c| c.fingerprint).collect()
+    }
+
+    /// `wsh(sortedmulti(...))` descriptor for a chain, P2WSH wallets only
+    pub fn descriptor(&self, chain: u32) -> Option<Descriptor> {
+        match self.kind {
+            MultisigKind::SortedMultiP2wsh => Some(Descriptor::Wsh(Miniscript::SortedMulti(
+                self.threshold,
+                self.cosigners.iter().map(|c| c.ranged(chain)).collect(),
+            ))),
+            MultisigKind::MuSig2P2tr => None,
+        }
+    }
+
+    fn child_keys(&self, chain: u32, index: u32, secp: &Secp256k1<All>) -> Result<Vec<PublicKey>> {
+        self.cosigners
+            .iter()
+            .map(|c| Ok(c.derive(chain, index, secp)?.0))
+            .collect()
+    }
+
+    /// Signer subsets with their leaf scripts, for k < n taproot wallets
+    fn taproot_leaves(
+        &self,
+        keys: &[PublicKey],
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<(Vec<usize>, ScriptBuf)>> {
+        if self.threshold == keys.len() {
+            return Ok(vec![]);
+        }
+        combinations(keys.len(), self.threshold)
+            .into_iter()
+            .map(|subset| {
+                let ctx = KeyAggContext::new(secp, subset.iter().map(|&i| keys[i]).collect())?;
+                let leaf = Builder::new()
+                    .push_x_only_key(&ctx.x_only())
+                    .push_opcode(OP_CHECKSIG)
+                    .into_script();
+                Ok((subset, leaf))
+            })
+            .collect()
+    }
+
+    fn taproot_spend_info(
+        &self,
+        chain: u32,
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<TaprootSpendInfo> {
+        let keys = self.child_keys(chain, index, secp)?;
+        let internal_key = KeyAggContext::new(secp, keys.clone())?.x_only();
+        let leaves = self.taproot_leaves(&keys, secp)?;
+        if leaves.is_empty() {
+            return Ok(TaprootSpendInfo::new_key_spend(secp, internal_key, None));
+        }
+        Ok(TaprootSpendInfo::with_huffman_tree(
+            secp,
+            internal_key,
+            leaves.into_iter().map(|(_, leaf)| (1, leaf)),
+        )?)
+    }
+
+    fn address(&self, chain: u32, index: u32, secp: &Secp256k1<All>) -> Result<Address> {
+        match self.kind {
+            MultisigKind::SortedMultiP2wsh => self
+                .descriptor(chain)
+                .expect("P2WSH wallets have a descriptor")
+                .address(index, self.network, secp),
+            MultisigKind::MuSig2P2tr => {
+                let spend_info = self.taproot_spend_info(chain, index, secp)?;
+                Ok(Address::p2tr_tweaked(spend_info.output_key(), self.network))
+            }
+        }
+    }
+
+    fn next_address(&mut self, chain: u32, secp: &Secp256k1<All>) -> Result<Address> {
+        let index = if chain == RECEIVE_CHAIN {
+            self.next_receive
+        } else {
+            self.next_change
+        };
+        let address = self.address(chain, index, secp)?;
+        self.scripts.insert(address.script_pubkey(), (chain, index));
+        if chain == RECEIVE_CHAIN {
+            self.next_receive += 1;
+        } else {
+            self.next_change += 1;
+        }
+        Ok(address)
+    }
+
+    /// Weight of spending one of the wallet's outputs, by key path if every cosigner signs
+    fn input_weight(&self, key_path: bool) -> Weight {
+        // outpoint + sequence + empty script_sig
+        const BASE: u64 = 41 * 4;
+        let n = self.cosigners.len() as u64;
+        let k = self.threshold as u64;
+        let wu = match self.kind {
+            MultisigKind::SortedMultiP2wsh => {
+                let script = 3 + 34 * n;
+                let script_len_prefix = if script < 0xfd { 1 } else { 3 };
+                // item count, CHECKMULTISIG dummy, signatures, witness script
+                BASE + 1 + 1 + k * (1 + 72) + script_len_prefix + script
+            }
+            MultisigKind::MuSig2P2tr if key_path => BASE + 1 + 1 + 64,
+            MultisigKind::MuSig2P2tr => {
+                let leaves = combinations(self.cosigners.len(), self.threshold).len() as u64;
+                let depth = 64 - (leaves.max(1) - 1).leading_zeros() as u64;
+                // item count, signature, leaf script, control block
+                BASE + 1 + (1 + 64) + (1 + 34) + (1 + 33 + 32 * depth)
+            }
+        };
+        Weight::from_wu(wu)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposalStatus {
+    /// Waiting for cosigner signatures or MuSig2 rounds
+    Pending,
+    /// Enough signatures to finalize
+    Ready,
+    Broadcast(Txid),
+    Cancelled,
+}
+
+/// MuSig2 signing state for one input
+struct MusigInput {
+    /// Aggregate of the signing subset, tweaked for key-path spends
+    ctx: KeyAggContext,
+    /// Signing subset's child keys by cosigner
+    keys: BTreeMap<Fingerprint, (PublicKey, KeySource)>,
+    /// Sighash being signed
+    msg: [u8; 32],
+    /// Leaf being spent, `None` for a key-path spend
+    leaf_hash: Option<TapLeafHash>,
+}
+
+struct MusigState {
+    signers: Vec<Fingerprint>,
+    inputs: Vec<MusigInput>,
+    pub_nonces: BTreeMap<Fingerprint, Vec<PubNonce>>,
+    /// Secret nonces of cosigners signing in this process, consumed by `musig_sign`
+    sec_nonces: HashMap<Fingerprint, Vec<SecNonce>>,
+    partial_sigs: BTreeMap<Fingerprint, Vec<PartialSignature>>,
+}
+
+impl MusigState {
+    fn sessions(&self, secp: &Secp256k1<All>) -> Result<Vec<Session>> {
+        if self.pub_nonces.len() != self.signers.len() {
+            return Err(anyhow::anyhow!(
+                "Waiting for nonces from {} of {} signers",
+                self.signers.len() - self.pub_nonces.len(),
+                self.signers.len()
+            ));
+        }
+        self.inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let nonces: Vec<PubNonce> = self.pub_nonces.values().map(|n| n[i]).collect();
+                Session::new(
+                    secp,
+                    input.ctx.clone(),
+                    musig::nonce_agg(&nonces)?,
+                    input.msg,
+                )
+            })
+            .collect()
+    }
+}
+
+/// A spend from a multisig wallet awaiting cosigner approval
+pub struct SpendProposal {
+    pub id: String,
+    pub wallet_id: String,
+    pub psbt: Psbt,
+    pub status: ProposalStatus,
+    musig: Option<MusigState>,
+}
+
+impl SpendProposal {
+    fn outpoints(&self) -> Vec<OutPoint> {
+        self.psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|i| i.previous_output)
+            .collect()
+    }
+
+    /// Cosigners whose signatures are on every input
+    pub fn signed_by(&self) -> Vec<Fingerprint> {
+        if let Some(musig) = &self.musig {
+            return musig.partial_sigs.keys().copied().collect();
+        }
+        let mut signers: Option<Vec<Fingerprint>> = None;
+        for input in &self.psbt.inputs {
+            let signed: Vec<Fingerprint> = input
+                .partial_sigs
+                .keys()
+                .filter_map(|pk| input.bip32_derivation.get(&pk.inner))
+                .map(|(fingerprint, _)| *fingerprint)
+                .collect();
+            signers = Some(match signers {
+                Some(s) => s.into_iter().filter(|f| signed.contains(f)).collect(),
+                None => signed,
+            });
+        }
+        signers.unwrap_or_default()
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.status {
+            ProposalStatus::Pending | ProposalStatus::Ready => Ok(()),
+            _ => Err(anyhow::anyhow!("Proposal {} is {:?}", self.id, self.status)),
+        }
+    }
+}
+
+impl EnterpriseWalletManager {
+    pub fn new(config: EnterpriseConfig) -> Result<Self> {
+        Ok(Self {
+            wallets: Arc::new(RwLock::new(HashMap::new())),
+            proposals: Arc::new(RwLock::new(HashMap::new())),
+            utxo_manager: Arc::new(RwLock::new(UtxoManager::new())),
+            secp: Arc::new(Secp256k1::new()),
+            rpc: RpcPool::new(&config.rpc_endpoints)?,
+            ops: Arc::new(Semaphore::new(config.max_concurrent_ops.max(1))),
+            network: config.network,
+        })
+    }
+
+    /// Create a k-of-n wallet from the cosigners' account keys (see [`cosigner_key`]).
+    /// Returns the first receive address.
+    pub async fn create_wallet(
+        &self,
+        wallet_id: &str,
+        kind: MultisigKind,
+        threshold: usize,
+        cosigner_keys: &[String],
+    ) -> Result<String> {
+        let mut cosigners = cosigner_keys
+            .iter()
+            .map(|k| Cosigner::parse(k))
+            .collect::<Result<Vec<_>>>()?;
+        cosigners.sort_by_key(|c| c.fingerprint);
+        cosigners.dedup_by_key(|c| c.fingerprint);
+
+        let n = cosigners.len();
+        if n != cosigner_keys.len() {
+            return Err(anyhow::anyhow!("Duplicate cosigner fingerprints"));
+        }
+        if threshold == 0 || threshold > n || n > MAX_COSIGNERS {
+            return Err(anyhow::anyhow!(
+                "Invalid {threshold}-of-{n} multisig (at most {MAX_COSIGNERS} cosigners)"
+            ));
+        }
+        if kind == MultisigKind::MuSig2P2tr && combinations(n, threshold).len() > MAX_TAPROOT_LEAVES
+        {
+            return Err(anyhow::anyhow!(
+                "{threshold}-of-{n} needs more than {MAX_TAPROOT_LEAVES} taproot leaves"
+            ));
+        }
+        for cosigner in &cosigners {
+            if let DescriptorKey::Extended {
+                key: ExtendedKey::Public(xpub),
+                ..
+            } = &cosigner.key
+            {
+                if (xpub.network == Network::Bitcoin) != (self.network == Network::Bitcoin) {
+                    return Err(anyhow::anyhow!(
+                        "Cosigner {} key is for {}",
+                        cosigner.fingerprint,
+                        xpub.network
+                    ));
+                }
+            }
+        }
+
+        let mut wallet = MultisigWallet {
+            id: wallet_id.to_string(),
+            kind,
+            threshold,
+            cosigners,
+            next_receive: 0,
+            next_change: 0,
+            scripts: HashMap::new(),
+            network: self.network,
+        };
+        let address = wallet.next_address(RECEIVE_CHAIN, &self.secp)?;
+
+        let mut wallets = self.wallets.write().await;
+        if wallets.contains_key(wallet_id) {
+            return Err(anyhow::anyhow!("Wallet {wallet_id} already exists"));
+        }
+        wallets.insert(wallet_id.to_string(), wallet);
+        Ok(address.to_string())
+    }
+
+    pub async fn get_wallet(&self, wallet_id: &str) -> Option<MultisigWallet> {
+        self.wallets.read().await.get(wallet_id).cloned()
+    }
+
+    pub async fn get_receive_address(&self, wallet_id: &str) -> Result<String> {
+        self.next_address(wallet_id, RECEIVE_CHAIN).await
+    }
+
+    pub async fn get_change_address(&self, wallet_id: &str) -> Result<String> {
+        self.next_address(wallet_id, CHANGE_CHAIN).await
+    }
+
+    async fn next_address(&self, wallet_id: &str, chain: u32) -> Result<String> {
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(wallet_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        Ok(wallet.next_address(chain, &self.secp)?.to_string())
+    }
+
+    /// Shared UTXO manager, keyed by wallet ID
+    pub fn utxo_manager(&self) -> Arc<RwLock<UtxoManager>> {
+        self.utxo_manager.clone()
+    }
+
+    /// Refresh a wallet's UTXOs from the first configured RPC endpoint
+    pub async fn sync_utxos(&self, wallet_id: &str) -> Result<usize> {
+        let addresses: Vec<Address> = {
+            let wallets = self.wallets.read().await;
+            let wallet = wallets
+                .get(wallet_id)
+                .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+            wallet
+                .scripts
+                .keys()
+                .map(|s| Address::from_script(s, self.network))
+                .collect::<Result<_, _>>()?
+        };
+        let _permit = self.ops.acquire().await?;
+        let utxos = self
+            .rpc
+            .call(move |node| UtxoManager::scan_utxos(&node.client, &addresses))
+            .await?;
+
+        let count = utxos.len();
+        self.utxo_manager
+            .write()
+            .await
+            .update_utxos(wallet_id, utxos);
+        Ok(count)
+    }
+
+    /// Propose a payment. `signers` picks the cosigners who will sign a MuSig2 spend: all of
+    /// them for a key-path spend, or exactly `threshold` for the matching script-path leaf.
+    /// P2WSH proposals accept signatures from any `threshold` cosigners.
+    pub async fn create_proposal(
+        &self,
+        wallet_id: &str,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        signers: Option<&[Fingerprint]>,
+    ) -> Result<String> {
+        let mut wallets = self.wallets.write().await;
+        let wallet = wallets
+            .get_mut(wallet_id)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+
+        let mut signers: Vec<Fingerprint> = match signers {
+            Some(s) => s.to_vec(),
+            None => wallet.fingerprints(),
+        };
+        signers.sort();
+        signers.dedup();
+        if let Some(unknown) = signers.iter().find(|f| !wallet.fingerprints().contains(f)) {
+            return Err(anyhow::anyhow!(
+                "{unknown} is not a cosigner of {wallet_id}"
+            ));
+        }
+        let key_path = signers.len() == wallet.cosigners.len();
+        if wallet.kind == MultisigKind::MuSig2P2tr && !key_path && signers.len() != wallet.threshold
+        {
+            return Err(anyhow::anyhow!(
+                "MuSig2 spends need all {} cosigners or exactly {}",
+                wallet.cosigners.len(),
+                wallet.threshold
+            ));
+        }
+
+        let change = wallet.next_address(CHANGE_CHAIN, &self.secp)?;
+        let amount = recipients.iter().map(|(_, amount)| amount).sum();
+        let scripts: Vec<ScriptBuf> = recipients.iter().map(|(a, _)| a.script_pubkey()).collect();
+        let params = CoinSelectionParams::new(fee_rate, &scripts)
+            .with_change_script(&change.script_pubkey())
+            .with_input_weight(wallet.input_weight(key_path));
+        let selection = self.utxo_manager.write().await.select_coins(
+            wallet_id,
+            amount,
+            &params,
+            SelectionStrategy::Auto,
+            PROPOSAL_RESERVATION,
+        )?;
+
+        let mut psbt = recipients
+            .iter()
+            .fold(TransactionBuilder::new(), |b, (address, amount)| {
+                b.add_recipient(address, *amount)
+            })
+            .add_selection(&selection, change.script_pubkey())
+            .build_psbt()?;
+
+        let musig = match wallet.kind {
+            MultisigKind::SortedMultiP2wsh => {
+                self.update_p2wsh_psbt(wallet, &mut psbt)?;
+                None
+            }
+            MultisigKind::MuSig2P2tr => Some(self.start_musig(wallet, &mut psbt, signers)?),
+        };
+
+        let mut id = [0u8; 16];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut id);
+        let id = id.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        self.proposals.write().await.insert(
+            id.clone(),
+            SpendProposal {
+                id: id.clone(),
+                wallet_id: wallet_id.to_string(),
+                psbt,
+                status: ProposalStatus::Pending,
+                musig,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Witness scripts and key origins for every input and change output
+    fn update_p2wsh_psbt(&self, wallet: &MultisigWallet, psbt: &mut Psbt) -> Result<()> {
+        for (index, input) in psbt.inputs.iter_mut().enumerate() {
+            let script = &input
+                .witness_utxo
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Input {index} has no spent output"))?
+                .script_pubkey;
+            let (chain, child) = wallet.scripts[script];
+            wallet
+                .descriptor(chain)
+                .expect("P2WSH wallets have a descriptor")
+                .update_psbt_input(input, child, &self.secp)?;
+        }
+        for (txout, output) in psbt.unsigned_tx.output.iter().zip(psbt.outputs.iter_mut()) {
+            if let Some(&(chain, child)) = wallet.scripts.get(&txout.script_pubkey) {
+                wallet
+                    .descriptor(chain)
+                    .expect("P2WSH wallets have a descriptor")
+                    .update_psbt_output(output, child, &self.secp)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute what each input's MuSig2 session signs and attach the taproot spend data
+    fn start_musig(
+        &self,
+        wallet: &MultisigWallet,
+        psbt: &mut Psbt,
+        signers: Vec<Fingerprint>,
+    ) -> Result<MusigState> {
+        let secp = &self.secp;
+        let key_path = signers.len() == wallet.cosigners.len();
+        let signer_indexes: Vec<usize> = wallet
+            .cosigners
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| signers.contains(&c.fingerprint))
+            .map(|(i, _)| i)
+            .collect();
+
+        let spent: Vec<TxOut> = psbt
+            .inputs
+            .iter()
+            .map(|i| {
+                i.witness_utxo
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("Input has no spent output"))
+            })
+            .collect::<Result<_>>()?;
+        let prevouts = Prevouts::All(&spent);
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+        let mut inputs = Vec::new();
+        for (index, input) in psbt.inputs.iter_mut().enumerate() {
+            let (chain, child) = wallet.scripts[&spent[index].script_pubkey];
+            let spend_info = wallet.taproot_spend_info(chain, child, secp)?;
+            input.tap_internal_key = Some(spend_info.internal_key());
+            input.tap_merkle_root = spend_info.merkle_root();
+
+            let mut keys = BTreeMap::new();
+            for &i in &signer_indexes {
+                let cosigner = &wallet.cosigners[i];
+                keys.insert(cosigner.fingerprint, cosigner.derive(chain, child, secp)?);
+            }
+            let subset_keys: Vec<PublicKey> = keys.values().map(|(k, _)| *k).collect();
+
+            let (ctx, msg, leaf_hash) = if key_path {
+                let ctx = KeyAggContext::new(secp, subset_keys)?
+                    .with_taproot_tweak(secp, spend_info.merkle_root())?;
+                let sighash = cache.taproot_key_spend_signature_hash(
+                    index,
+                    &prevouts,
+                    TapSighashType::Default,
+                )?;
+                (ctx, sighash.to_byte_array(), None)
+            } else {
+                let all_keys = wallet.child_keys(chain, child, secp)?;
+                let (_, leaf) = wallet
+                    .taproot_leaves(&all_keys, secp)?
+                    .into_iter()
+                    .find(|(subset, _)| *subset == signer_indexes)
+                    .ok_or_else(|| anyhow::anyhow!("No leaf for this signer set"))?;
+                let control_block = spend_info
+                    .control_block(&(leaf.clone(), LeafVersion::TapScript))
+                    .ok_or_else(|| anyhow::anyhow!("Missing control block"))?;
+                let leaf_hash = TapLeafHash::from_script(&leaf, LeafVersion::TapScript);
+                input
+                    .tap_scripts
+                    .insert(control_block, (leaf, LeafVersion::TapScript));
+
+                let ctx = KeyAggContext::new(secp, subset_keys)?;
+                let sighash = cache.taproot_script_spend_signature_hash(
+                    index,
+                    &prevouts,
+                    leaf_hash,
+                    TapSighashType::Default,
+                )?;
+                (ctx, sighash.to_byte_array(), Some(leaf_hash))
+            };
+
+            inputs.push(MusigInput {
+                ctx,
+                keys,
+                msg,
+                leaf_hash,
+            });
+        }
+
+        Ok(MusigState {
+            signers,
+            inputs,
+            pub_nonces: BTreeMap::new(),
+            sec_nonces: HashMap::new(),
+            partial_sigs: BTreeMap::new(),
+        })
+    }
+
+    pub async fn proposal_status(&self, proposal_id: &str) -> Result<ProposalStatus> {
+        let proposals = self.proposals.read().await;
+        let proposal = proposals
+            .get(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        Ok(proposal.status.clone())
+    }
+
+    /// Cosigners that have signed a proposal
+    pub async fn proposal_signers(&self, proposal_id: &str) -> Result<Vec<Fingerprint>> {
+        let proposals = self.proposals.read().await;
+        let proposal = proposals
+            .get(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        Ok(proposal.signed_by())
+    }
+
+    /// Base64 PSBT for cosigners signing on another device
+    pub async fn export_proposal(&self, proposal_id: &str) -> Result<String> {
+        let proposals = self.proposals.read().await;
+        let proposal = proposals
+            .get(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        Ok(psbt_handler::to_base64(&proposal.psbt))
+    }
+
+    /// Sign a P2WSH proposal with a cosigner's master key held in this process
+    pub async fn sign_proposal(&self, proposal_id: &str, master: &Xpriv) -> Result<()> {
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        if proposal.musig.is_some() {
+            return Err(anyhow::anyhow!(
+                "MuSig2 proposals are signed with musig_sign"
+            ));
+        }
+
+        let signed = match proposal.psbt.sign(master, &self.secp) {
+            Ok(keys) => keys.values().filter(|k| !k.is_empty()).count(),
+            Err((_, errors)) => {
+                let (index, error) = errors.into_iter().next().expect("errors is not empty");
+                return Err(anyhow::anyhow!("Failed to sign input {index}: {error}"));
+            }
+        };
+        if signed == 0 {
+            return Err(anyhow::anyhow!(
+                "{} is not a cosigner of this proposal",
+                master.fingerprint(&self.secp)
+            ));
+        }
+        self.refresh_status(proposal).await
+    }
+
+    /// Merge a PSBT signed by a cosigner on another device into a P2WSH proposal
+    pub async fn add_signed_psbt(&self, proposal_id: &str, psbt: &str) -> Result<()> {
+        let signed = psbt_handler::from_base64(psbt)?;
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        if signed.unsigned_tx.txid() != proposal.psbt.unsigned_tx.txid() {
+            return Err(anyhow::anyhow!("PSBT is for a different transaction"));
+        }
+        proposal.psbt.combine(signed)?;
+        self.refresh_status(proposal).await
+    }
+
+    async fn refresh_status(&self, proposal: &mut SpendProposal) -> Result<()> {
+        let threshold = self
+            .wallets
+            .read()
+            .await
+            .get(&proposal.wallet_id)
+            .map(|w| w.threshold)
+            .ok_or_else(|| anyhow::anyhow!("Wallet not found"))?;
+        if proposal.signed_by().len() >= threshold {
+            proposal.status = ProposalStatus::Ready;
+        }
+        Ok(())
+    }
+
+    /// MuSig2 round one for a cosigner in this process: generate and keep secret nonces,
+    /// returning the public nonces (one per input) to share with the other signers
+    pub async fn musig_nonces(&self, proposal_id: &str, master: &Xpriv) -> Result<Vec<PubNonce>> {
+        let fingerprint = master.fingerprint(&self.secp);
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        let musig = proposal
+            .musig
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not a MuSig2 proposal"))?;
+        if !musig.signers.contains(&fingerprint) {
+            return Err(anyhow::anyhow!(
+                "{fingerprint} is not a signer of this proposal"
+            ));
+        }
+        if musig.pub_nonces.contains_key(&fingerprint) {
+            return Err(anyhow::anyhow!("{fingerprint} already committed nonces"));
+        }
+
+        let (secret, public): (Vec<SecNonce>, Vec<PubNonce>) = musig
+            .inputs
+            .iter()
+            .map(|input| musig::nonce_gen(&self.secp, input.keys[&fingerprint].0))
+            .unzip();
+        musig.sec_nonces.insert(fingerprint, secret);
+        musig.pub_nonces.insert(fingerprint, public.clone());
+        Ok(public)
+    }
+
+    /// Record a remote cosigner's round-one nonces
+    pub async fn musig_add_nonces(
+        &self,
+        proposal_id: &str,
+        fingerprint: Fingerprint,
+        nonces: Vec<PubNonce>,
+    ) -> Result<()> {
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        let musig = proposal
+            .musig
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not a MuSig2 proposal"))?;
+        if !musig.signers.contains(&fingerprint) {
+            return Err(anyhow::anyhow!(
+                "{fingerprint} is not a signer of this proposal"
+            ));
+        }
+        if nonces.len() != musig.inputs.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} nonces, got {}",
+                musig.inputs.len(),
+                nonces.len()
+            ));
+        }
+        if musig.pub_nonces.contains_key(&fingerprint) {
+            return Err(anyhow::anyhow!("{fingerprint} already committed nonces"));
+        }
+        musig.pub_nonces.insert(fingerprint, nonces);
+        Ok(())
+    }
+
+    /// MuSig2 round two for a cosigner in this process, once every signer's nonces are in.
+    /// Returns the partial signatures to share with remote cosigners.
+    pub async fn musig_sign(
+        &self,
+        proposal_id: &str,
+        master: &Xpriv,
+    ) -> Result<Vec<PartialSignature>> {
+        let secp = &self.secp;
+        let fingerprint = master.fingerprint(secp);
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        let musig = proposal
+            .musig
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not a MuSig2 proposal"))?;
+
+        let sessions = musig.sessions(secp)?;
+        let sec_nonces = musig
+            .sec_nonces
+            .remove(&fingerprint)
+            .ok_or_else(|| anyhow::anyhow!("No unused nonces for {fingerprint}"))?;
+
+        let sigs = sessions
+            .iter()
+            .zip(sec_nonces)
+            .zip(&musig.inputs)
+            .map(|((session, sec_nonce), input)| {
+                let (_, (_, path)) = input
+                    .keys
+                    .get(&fingerprint)
+                    .ok_or_else(|| anyhow::anyhow!("{fingerprint} has no key in this input"))?;
+                let secret_key = master.derive_priv(secp, path)?.private_key;
+                session.partial_sign(secp, sec_nonce, &secret_key)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        musig.partial_sigs.insert(fingerprint, sigs.clone());
+        self.complete_musig(proposal, sessions)?;
+        Ok(sigs)
+    }
+
+    /// Record and verify a remote cosigner's partial signatures
+    pub async fn musig_add_partial_signatures(
+        &self,
+        proposal_id: &str,
+        fingerprint: Fingerprint,
+        sigs: Vec<PartialSignature>,
+    ) -> Result<()> {
+        let secp = &self.secp;
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        let musig = proposal
+            .musig
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not a MuSig2 proposal"))?;
+
+        if !musig.signers.contains(&fingerprint) {
+            return Err(anyhow::anyhow!(
+                "{fingerprint} is not a signer of this proposal"
+            ));
+        }
+        let sessions = musig.sessions(secp)?;
+        let nonces = musig
+            .pub_nonces
+            .get(&fingerprint)
+            .ok_or_else(|| anyhow::anyhow!("{fingerprint} hasn't committed nonces"))?;
+        if sigs.len() != musig.inputs.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} partial signatures",
+                musig.inputs.len()
+            ));
+        }
+        for (i, sig) in sigs.iter().enumerate() {
+            let (pubkey, _) = musig.inputs[i]
+                .keys
+                .get(&fingerprint)
+                .ok_or_else(|| anyhow::anyhow!("{fingerprint} has no key in input {i}"))?;
+            if !sessions[i].partial_sig_verify(secp, sig, &nonces[i], pubkey) {
+                return Err(anyhow::anyhow!(
+                    "Invalid partial signature from {fingerprint} on input {i}"
+                ));
+            }
+        }
+
+        musig.partial_sigs.insert(fingerprint, sigs);
+        self.complete_musig(proposal, sessions)
+    }
+
+    /// Aggregate the partial signatures into the PSBT once every signer has signed
+    fn complete_musig(&self, proposal: &mut SpendProposal, sessions: Vec<Session>) -> Result<()> {
+        let musig = proposal
+            .musig
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not a MuSig2 proposal"))?;
+        if musig.partial_sigs.len() != musig.signers.len() {
+            return Ok(());
+        }
+
+        for (i, session) in sessions.iter().enumerate() {
+            let partials: Vec<PartialSignature> =
+                musig.partial_sigs.values().map(|s| s[i]).collect();
+            let sig = taproot::Signature {
+                sig: session.aggregate(&partials)?,
+                hash_ty: TapSighashType::Default,
+            };
+            let input = &musig.inputs[i];
+            let psbt_input = &mut proposal.psbt.inputs[i];
+            match input.leaf_hash {
+                None => psbt_input.tap_key_sig = Some(sig),
+                Some(leaf_hash) => {
+                    let key: XOnlyPublicKey = input.ctx.x_only();
+                    psbt_input.tap_script_sigs.insert((key, leaf_hash), sig);
+                }
+            }
+        }
+        proposal.status = ProposalStatus::Ready;
+        Ok(())
+    }
+
+    /// Finalize a ready proposal into a network transaction
+    pub async fn finalize_proposal(&self, proposal_id: &str) -> Result<Transaction> {
+        let proposals = self.proposals.read().await;
+        let proposal = proposals
+            .get(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        if proposal.status != ProposalStatus::Ready {
+            return Err(anyhow::anyhow!("Proposal is {:?}", proposal.status));
+        }
+        let mut psbt = proposal.psbt.clone();
+        psbt_handler::finalize_psbt(&mut psbt)?;
+        psbt_handler::extract_transaction(psbt)
+    }
+
+    /// Finalize and broadcast a ready proposal
+    pub async fn broadcast_proposal(&self, proposal_id: &str) -> Result<Txid> {
+        let tx = self.finalize_proposal(proposal_id).await?;
+        let _permit = self.ops.acquire().await?;
+        let txid = self
+            .rpc
+            .call(move |node| Ok(node.client.send_raw_transaction(&tx)?))
+            .await?;
+
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        self.utxo_manager
+            .write()
+            .await
+            .mark_spent(&proposal.wallet_id, &proposal.outpoints());
+        proposal.status = ProposalStatus::Broadcast(txid);
+        Ok(txid)
+    }
+
+    /// Abandon a proposal and release its coins
+    pub async fn cancel_proposal(&self, proposal_id: &str) -> Result<()> {
+        let mut proposals = self.proposals.write().await;
+        let proposal = proposals
+            .get_mut(proposal_id)
+            .ok_or_else(|| anyhow::anyhow!("Proposal not found"))?;
+        proposal.check_open()?;
+        self.utxo_manager
+            .write()
+            .await
+            .release(&proposal.outpoints());
+        proposal.status = ProposalStatus::Cancelled;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utxo_manager::TrackedUtxo;
+    use bitcoin::Amount;
+
+    fn masters(n: u8) -> Vec<Xpriv> {
+        (1..=n)
+            .map(|i| Xpriv::new_master(Network::Regtest, &[i; 32]).unwrap())
+            .collect()
+    }
+
+    async fn funded_wallet(
+        kind: MultisigKind,
+        threshold: usize,
+        masters: &[Xpriv],
+    ) -> (EnterpriseWalletManager, Address) {
+        let manager = EnterpriseWalletManager::new(EnterpriseConfig {
+            max_concurrent_ops: 4,
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+        })
+        .unwrap();
+        let keys: Vec<String> = masters
+            .iter()
+            .map(|m| cosigner_key(m, kind, 0, &manager.secp).unwrap())
+            .collect();
+        let address = manager
+            .create_wallet("treasury", kind, threshold, &keys)
+            .await
+            .unwrap();
+        let address = Address::from_str(&address).unwrap().assume_checked();
+
+        manager.utxo_manager.write().await.add_utxo(
+            "treasury",
+            TrackedUtxo {
+                outpoint: OutPoint::new(Txid::all_zeros(), 0),
+                txout: TxOut {
+                    value: Amount::from_sat(1_000_000),
+                    script_pubkey: address.script_pubkey(),
+                },
+                confirmations: 6,
+            },
+        );
+        (manager, address)
+    }
+
+    fn recipient() -> Address {
+        Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .unwrap()
+            .assume_checked()
+    }
+
+    #[tokio::test]
+    async fn sortedmulti_proposal_needs_threshold() {
+        let masters = masters(3);
+        let (manager, address) = funded_wallet(MultisigKind::SortedMultiP2wsh, 2, &masters).await;
+        assert!(address.script_pubkey().is_p2wsh());
+
+        let id = manager
+            .create_proposal(
+                "treasury",
+                &[(recipient(), 400_000)],
+                FeeRate::from_sat_per_vb_unchecked(5),
+                None,
+            )
+            .await
+            .unwrap();
+
+        manager.sign_proposal(&id, &masters[0]).await.unwrap();
+        assert_eq!(
+            manager.proposal_status(&id).await.unwrap(),
+            ProposalStatus::Pending
+        );
+        assert!(manager.finalize_proposal(&id).await.is_err());
+
+        // Second cosigner signs on another device
+        let mut remote =
+            psbt_handler::from_base64(&manager.export_proposal(&id).await.unwrap()).unwrap();
+        remote.sign(&masters[2], &manager.secp).unwrap();
+        manager
+            .add_signed_psbt(&id, &psbt_handler::to_base64(&remote))
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.proposal_status(&id).await.unwrap(),
+            ProposalStatus::Ready
+        );
+        assert_eq!(manager.proposal_signers(&id).await.unwrap().len(), 2);
+
+        let tx = manager.finalize_proposal(&id).await.unwrap();
+        // dummy, two signatures, witness script
+        assert_eq!(tx.input[0].witness.len(), 4);
+    }
+
+    async fn run_musig(manager: &EnterpriseWalletManager, id: &str, signers: &[Xpriv]) {
+        // Round one: every signer commits nonces
+        for master in signers {
+            manager.musig_nonces(id, master).await.unwrap();
+        }
+        // Round two
+        for master in signers {
+            manager.musig_sign(id, master).await.unwrap();
+        }
+    }
+
+    fn verify_taproot(tx: &Transaction, spent: &TxOut, leaf: bool) {
+        let secp = Secp256k1::new();
+        let sig = taproot::Signature::from_slice(&tx.input[0].witness[0]).unwrap();
+        let mut cache = SighashCache::new(tx);
+        let prevouts = Prevouts::All(std::slice::from_ref(spent));
+        let (sighash, key) = if leaf {
+            let script = ScriptBuf::from_bytes(tx.input[0].witness[1].to_vec());
+            let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+            let key = XOnlyPublicKey::from_slice(&script.as_bytes()[1..33]).unwrap();
+            let sighash = cache
+                .taproot_script_spend_signature_hash(0, &prevouts, leaf_hash, sig.hash_ty)
+                .unwrap();
+            (sighash.to_byte_array(), key)
+        } else {
+            let key = XOnlyPublicKey::from_slice(&spent.script_pubkey.as_bytes()[2..]).unwrap();
+            let sighash = cache
+                .taproot_key_spend_signature_hash(0, &prevouts, sig.hash_ty)
+                .unwrap();
+            (sighash.to_byte_array(), key)
+        };
+        let msg = bitcoin::secp256k1::Message::from_digest(sighash);
+        secp.verify_schnorr(&sig.sig, &msg, &key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn musig_key_path_spend() {
+        let masters = masters(3);
+        let (manager, address) = funded_wallet(MultisigKind::MuSig2P2tr, 2, &masters).await;
+        assert!(address.script_pubkey().is_p2tr());
+
+        let id = manager
+            .create_proposal(
+                "treasury",
+                &[(recipient(), 400_000)],
+                FeeRate::from_sat_per_vb_unchecked(5),
+                None,
+            )
+            .await
+            .unwrap();
+        let spent = manager.proposals.read().await[&id].psbt.inputs[0]
+            .witness_utxo
+            .clone()
+            .unwrap();
+
+        run_musig(&manager, &id, &masters).await;
+        assert_eq!(
+            manager.proposal_status(&id).await.unwrap(),
+            ProposalStatus::Ready
+        );
+        let tx = manager.finalize_proposal(&id).await.unwrap();
+        assert_eq!(tx.input[0].witness.len(), 1);
+        verify_taproot(&tx, &spent, false);
+    }
+
+    #[tokio::test]
+    async fn musig_script_path_spend_with_threshold_signers() {
+        let masters = masters(3);
+        let (manager, _) = funded_wallet(MultisigKind::MuSig2P2tr, 2, &masters).await;
+        let signers: Vec<Fingerprint> = masters[1..]
+            .iter()
+            .map(|m| m.fingerprint(&manager.secp))
+            .collect();
+
+        let id = manager
+            .create_proposal(
+                "treasury",
+                &[(recipient(), 400_000)],
+                FeeRate::from_sat_per_vb_unchecked(5),
+                Some(&signers),
+            )
+            .await
+            .unwrap();
+        let spent = manager.proposals.read().await[&id].psbt.inputs[0]
+            .witness_utxo
+            .clone()
+            .unwrap();
+
+        // A cosigner outside the signer set can't take part
+        assert!(manager.musig_nonces(&id, &masters[0]).await.is_err());
+        let outsider = masters[0].fingerprint(&manager.secp);
+        for master in &masters[1..] {
+            manager.musig_nonces(&id, master).await.unwrap();
+        }
+        let sigs = manager.musig_sign(&id, &masters[1]).await.unwrap();
+        assert!(manager
+            .musig_add_partial_signatures(&id, outsider, sigs)
+            .await
+            .is_err());
+        manager.musig_sign(&id, &masters[2]).await.unwrap();
+        let tx = manager.finalize_proposal(&id).await.unwrap();
+        // signature, leaf script, control block
+        assert_eq!(tx.input[0].witness.len(), 3);
+        verify_taproot(&tx, &spent, true);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_wallets() {
+        let masters = masters(2);
+        let manager = EnterpriseWalletManager::new(EnterpriseConfig {
+            max_concurrent_ops: 1,
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+        })
+        .unwrap();
+        let key = cosigner_key(
+            &masters[0],
+            MultisigKind::SortedMultiP2wsh,
+            0,
+            &manager.secp,
+        )
+        .unwrap();
+        assert!(manager
+            .create_wallet(
+                "w",
+                MultisigKind::SortedMultiP2wsh,
+                3,
+                std::slice::from_ref(&key)
+            )
+            .await
+            .is_err());
+        assert!(manager
+            .create_wallet("w", MultisigKind::SortedMultiP2wsh, 1, &[key.clone(), key])
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,763 @@
+// MuSig2 (BIP327) key aggregation and two-round signing
+//
+// Built on the public secp256k1 API. Scalars that must be non-zero are kept as `SecretKey`,
+// which gives modular addition, multiplication and negation.
+use anyhow::Result;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::key::{TapTweak, UntweakedPublicKey};
+use bitcoin::secp256k1::constants::CURVE_ORDER;
+use bitcoin::secp256k1::{
+    rand, schnorr, All, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey,
+};
+use bitcoin::taproot::{TapNodeHash, TapTweakHash};
+
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for part in parts {
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Interpret 32 bytes as an integer mod n
+fn scalar_mod_n(mut bytes: [u8; 32]) -> Scalar {
+    if let Ok(scalar) = Scalar::from_be_bytes(bytes) {
+        return scalar;
+    }
+    // A 256-bit value is below 2n, so one subtraction reduces it
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let diff = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+        borrow = (diff < 0) as i16;
+        bytes[i] = diff.rem_euclid(256) as u8;
+    }
+    Scalar::from_be_bytes(bytes).expect("reduced below the curve order")
+}
+
+fn has_even_y(point: &PublicKey) -> bool {
+    point.x_only_public_key().1 == Parity::Even
+}
+
+fn xbytes(point: &PublicKey) -> [u8; 32] {
+    point.x_only_public_key().0.serialize()
+}
+
+/// 33-byte encoding, all zeros for the point at infinity
+fn cbytes_ext(point: &Option<PublicKey>) -> [u8; 33] {
+    point.map(|p| p.serialize()).unwrap_or([0; 33])
+}
+
+fn to_secret(scalar: &Scalar) -> Result<SecretKey> {
+    Ok(SecretKey::from_slice(&scalar.to_be_bytes())?)
+}
+
+fn generator(secp: &Secp256k1<All>) -> PublicKey {
+    PublicKey::from_secret_key(secp, &to_secret(&Scalar::ONE).expect("one is a valid key"))
+}
+
+/// Aggregate public key of a set of signers, optionally with a BIP341 taproot tweak
+#[derive(Debug, Clone)]
+pub struct KeyAggContext {
+    /// Participant keys in sorted order
+    keys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    agg_key: PublicKey,
+    /// Accumulated sign of the tweaks (gacc == -1)
+    gacc_negated: bool,
+    /// Accumulated tweak (tacc), `None` when zero
+    tacc: Option<SecretKey>,
+}
+
+impl KeyAggContext {
+    /// KeySort + KeyAgg. Keys are sorted so every cosigner computes the same aggregate.
+    pub fn new(secp: &Secp256k1<All>, mut keys: Vec<PublicKey>) -> Result<Self> {
+        keys.sort_by_key(|k| k.serialize());
+        Self::unsorted(secp, keys)
+    }
+
+    /// KeyAgg over keys in the given order; the aggregate depends on the order
+    pub fn unsorted(secp: &Secp256k1<All>, keys: Vec<PublicKey>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("MuSig2 needs at least one key"));
+        }
+
+        let serialized: Vec<[u8; 33]> = keys.iter().map(|k| k.serialize()).collect();
+        let list: Vec<&[u8]> = serialized.iter().map(|k| k.as_slice()).collect();
+        let list_hash = tagged_hash("KeyAgg list", &list);
+        let second = keys.iter().find(|k| **k != keys[0]).copied();
+
+        let coefficients: Vec<Scalar> = keys
+            .iter()
+            .map(|key| {
+                if Some(*key) == second {
+                    Scalar::ONE
+                } else {
+                    scalar_mod_n(tagged_hash(
+                        "KeyAgg coefficient",
+                        &[&list_hash, &key.serialize()],
+                    ))
+                }
+            })
+            .collect();
+
+        let weighted = keys
+            .iter()
+            .zip(&coefficients)
+            .map(|(key, a)| Ok(key.mul_tweak(secp, a)?))
+            .collect::<Result<Vec<_>>>()?;
+        let agg_key = PublicKey::combine_keys(&weighted.iter().collect::<Vec<_>>())?;
+
+        Ok(Self {
+            keys,
+            coefficients,
+            agg_key,
+            gacc_negated: false,
+            tacc: None,
+        })
+    }
+
+    /// Apply the BIP341 tweak for a taproot output with the given script tree
+    pub fn with_taproot_tweak(
+        self,
+        secp: &Secp256k1<All>,
+        merkle_root: Option<TapNodeHash>,
+    ) -> Result<Self> {
+        let internal_key: UntweakedPublicKey = self.x_only();
+        let tweak = TapTweakHash::from_key_and_tweak(internal_key, merkle_root).to_scalar();
+        let tweaked = self.with_tweak(secp, tweak, true)?;
+
+        debug_assert_eq!(
+            tweaked.x_only(),
+            internal_key.tap_tweak(secp, merkle_root).0.to_inner()
+        );
+        Ok(tweaked)
+    }
+
+    /// ApplyTweak: add `tweak` to the aggregate key, as a plain (BIP32-style) tweak or as an
+    /// x-only tweak that first lifts the key to even y
+    pub fn with_tweak(
+        mut self,
+        secp: &Secp256k1<All>,
+        tweak: Scalar,
+        is_xonly: bool,
+    ) -> Result<Self> {
+        // Q' = g*Q + t*G with g = -1 for an x-only tweak of a key with odd y
+        let negate = is_xonly && !has_even_y(&self.agg_key);
+        let q = if negate {
+            self.agg_key.negate(secp)
+        } else {
+            self.agg_key
+        };
+        self.agg_key = q.add_exp_tweak(secp, &tweak)?;
+        self.gacc_negated ^= negate;
+        // tacc' = t + g*tacc
+        self.tacc = match self.tacc {
+            Some(tacc) => {
+                let tacc = if negate { tacc.negate() } else { tacc };
+                tacc.add_tweak(&tweak).ok()
+            }
+            None => to_secret(&tweak).ok(),
+        };
+        Ok(self)
+    }
+
+    pub fn agg_key(&self) -> PublicKey {
+        self.agg_key
+    }
+
+    /// The key that signatures verify against
+    pub fn x_only(&self) -> XOnlyPublicKey {
+        self.agg_key.x_only_public_key().0
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    fn coefficient(&self, key: &PublicKey) -> Result<Scalar> {
+        self.keys
+            .iter()
+            .position(|k| k == key)
+            .map(|i| self.coefficients[i])
+            .ok_or_else(|| anyhow::anyhow!("Key is not part of this MuSig2 aggregate"))
+    }
+
+    /// Whether a participant's secret must be negated: g * gacc == -1
+    fn negate_secret(&self) -> bool {
+        !has_even_y(&self.agg_key) ^ self.gacc_negated
+    }
+}
+
+/// Secret nonce pair. Deliberately not `Clone`: reusing a nonce leaks the signing key.
+pub struct SecNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    pubkey: PublicKey,
+}
+
+/// Public nonce pair shared with the other signers in round one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubNonce {
+    r1: PublicKey,
+    r2: PublicKey,
+}
+
+impl PubNonce {
+    pub fn to_bytes(&self) -> [u8; 66] {
+        let mut bytes = [0; 66];
+        bytes[..33].copy_from_slice(&self.r1.serialize());
+        bytes[33..].copy_from_slice(&self.r2.serialize());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 66 {
+            return Err(anyhow::anyhow!("MuSig2 public nonce must be 66 bytes"));
+        }
+        Ok(Self {
+            r1: PublicKey::from_slice(&bytes[..33])?,
+            r2: PublicKey::from_slice(&bytes[33..])?,
+        })
+    }
+}
+
+/// Generate a fresh nonce pair for the signer holding `pubkey`
+pub fn nonce_gen(secp: &Secp256k1<All>, pubkey: PublicKey) -> (SecNonce, PubNonce) {
+    let mut rng = rand::thread_rng();
+    let k1 = SecretKey::new(&mut rng);
+    let k2 = SecretKey::new(&mut rng);
+    let public = PubNonce {
+        r1: PublicKey::from_secret_key(secp, &k1),
+        r2: PublicKey::from_secret_key(secp, &k2),
+    };
+    (SecNonce { k1, k2, pubkey }, public)
+}
+
+/// Sum of every signer's nonces; either half may be the point at infinity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AggNonce {
+    r1: Option<PublicKey>,
+    r2: Option<PublicKey>,
+}
+
+impl AggNonce {
+    /// Encoding as in a public nonce, with 33 zero bytes for a half at infinity
+    pub fn to_bytes(&self) -> [u8; 66] {
+        let mut bytes = [0; 66];
+        bytes[..33].copy_from_slice(&cbytes_ext(&self.r1));
+        bytes[33..].copy_from_slice(&cbytes_ext(&self.r2));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 66 {
+            return Err(anyhow::anyhow!("MuSig2 aggregate nonce must be 66 bytes"));
+        }
+        let point = |half: &[u8]| -> Result<Option<PublicKey>> {
+            if half.iter().all(|b| *b == 0) {
+                Ok(None)
+            } else {
+                Ok(Some(PublicKey::from_slice(half)?))
+            }
+        };
+        Ok(Self {
+            r1: point(&bytes[..33])?,
+            r2: point(&bytes[33..])?,
+        })
+    }
+}
+
+pub fn nonce_agg(nonces: &[PubNonce]) -> Result<AggNonce> {
+    if nonces.is_empty() {
+        return Err(anyhow::anyhow!("No nonces to aggregate"));
+    }
+    let r1: Vec<&PublicKey> = nonces.iter().map(|n| &n.r1).collect();
+    let r2: Vec<&PublicKey> = nonces.iter().map(|n| &n.r2).collect();
+    Ok(AggNonce {
+        r1: PublicKey::combine_keys(&r1).ok(),
+        r2: PublicKey::combine_keys(&r2).ok(),
+    })
+}
+
+/// A signer's share of the final signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialSignature(SecretKey);
+
+impl PartialSignature {
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.secret_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(SecretKey::from_slice(bytes)?))
+    }
+}
+
+/// Values shared by every signer once the aggregate nonce and message are fixed
+pub struct Session {
+    ctx: KeyAggContext,
+    agg_nonce: AggNonce,
+    msg: [u8; 32],
+    /// Nonce coefficient
+    b: Scalar,
+    /// Final nonce point
+    r: PublicKey,
+    /// BIP340 challenge
+    e: Scalar,
+}
+
+impl Session {
+    pub fn new(
+        secp: &Secp256k1<All>,
+        ctx: KeyAggContext,
+        agg_nonce: AggNonce,
+        msg: [u8; 32],
+    ) -> Result<Self> {
+        let q = xbytes(&ctx.agg_key);
+        let b = scalar_mod_n(tagged_hash(
+            "MuSig/noncecoef",
+            &[
+                &cbytes_ext(&agg_nonce.r1),
+                &cbytes_ext(&agg_nonce.r2),
+                &q,
+                &msg,
+            ],
+        ));
+
+        let br2 = agg_nonce.r2.and_then(|r2| r2.mul_tweak(secp, &b).ok());
+        let r = match (agg_nonce.r1, br2) {
+            (Some(r1), Some(br2)) => r1.combine(&br2).ok(),
+            (r1, br2) => r1.or(br2),
+        }
+        // An infinite nonce is replaced by G so the protocol can't be stalled
+        .unwrap_or_else(|| generator(secp));
+
+        let e = scalar_mod_n(tagged_hash("BIP0340/challenge", &[&xbytes(&r), &q, &msg]));
+        Ok(Self {
+            ctx,
+            agg_nonce,
+            msg,
+            b,
+            r,
+            e,
+        })
+    }
+
+    pub fn message(&self) -> [u8; 32] {
+        self.msg
+    }
+
+    pub fn agg_nonce(&self) -> AggNonce {
+        self.agg_nonce
+    }
+
+    /// Round two: sign with `secret_key`, consuming the secret nonce
+    pub fn partial_sign(
+        &self,
+        secp: &Secp256k1<All>,
+        sec_nonce: SecNonce,
+        secret_key: &SecretKey,
+    ) -> Result<PartialSignature> {
+        let pubkey = PublicKey::from_secret_key(secp, secret_key);
+        if pubkey != sec_nonce.pubkey {
+            return Err(anyhow::anyhow!("Nonce was generated for a different key"));
+        }
+        let a = self.ctx.coefficient(&pubkey)?;
+        let pub_nonce = PubNonce {
+            r1: PublicKey::from_secret_key(secp, &sec_nonce.k1),
+            r2: PublicKey::from_secret_key(secp, &sec_nonce.k2),
+        };
+
+        let (mut k1, mut k2) = (sec_nonce.k1, sec_nonce.k2);
+        if !has_even_y(&self.r) {
+            k1 = k1.negate();
+            k2 = k2.negate();
+        }
+        let d = if self.ctx.negate_secret() {
+            secret_key.negate()
+        } else {
+            *secret_key
+        };
+
+        // s = k1 + b*k2 + e*a*d
+        let bk2 = k2.mul_tweak(&self.b)?;
+        let ead = d.mul_tweak(&self.e)?.mul_tweak(&a)?;
+        let s = k1
+            .add_tweak(&Scalar::from(bk2))?
+            .add_tweak(&Scalar::from(ead))?;
+
+        let sig = PartialSignature(s);
+        if !self.partial_sig_verify(secp, &sig, &pub_nonce, &pubkey) {
+            return Err(anyhow::anyhow!("Produced an invalid partial signature"));
+        }
+        Ok(sig)
+    }
+
+    /// Check another signer's partial signature against their public nonce and key
+    pub fn partial_sig_verify(
+        &self,
+        secp: &Secp256k1<All>,
+        sig: &PartialSignature,
+        pub_nonce: &PubNonce,
+        pubkey: &PublicKey,
+    ) -> bool {
+        let verify = || -> Result<bool> {
+            let a = self.ctx.coefficient(pubkey)?;
+            // R_i = R1_i + b*R2_i, negated along with the final nonce
+            let mut r_i = pub_nonce
+                .r1
+                .combine(&pub_nonce.r2.mul_tweak(secp, &self.b)?)?;
+            if !has_even_y(&self.r) {
+                r_i = r_i.negate(secp);
+            }
+            let p = if self.ctx.negate_secret() {
+                pubkey.negate(secp)
+            } else {
+                *pubkey
+            };
+            let ea = to_secret(&self.e)?.mul_tweak(&a)?;
+            let expected = r_i.combine(&p.mul_tweak(secp, &Scalar::from(ea))?)?;
+            Ok(PublicKey::from_secret_key(secp, &sig.0) == expected)
+        };
+        verify().unwrap_or(false)
+    }
+
+    /// Combine every signer's partial signature into a BIP340 signature
+    pub fn aggregate(&self, sigs: &[PartialSignature]) -> Result<schnorr::Signature> {
+        let (first, rest) = sigs
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("No partial signatures"))?;
+        let mut s = first.0;
+        for sig in rest {
+            s = s.add_tweak(&Scalar::from(sig.0))?;
+        }
+        if let Some(tacc) = self.ctx.tacc {
+            // s += e * g * tacc
+            let mut term = tacc.mul_tweak(&self.e)?;
+            if !has_even_y(&self.ctx.agg_key) {
+                term = term.negate();
+            }
+            s = s.add_tweak(&Scalar::from(term))?;
+        }
+
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&xbytes(&self.r));
+        bytes[32..].copy_from_slice(&s.secret_bytes());
+        Ok(schnorr::Signature::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign_all(
+        secp: &Secp256k1<All>,
+        ctx: KeyAggContext,
+        secrets: &[SecretKey],
+        msg: [u8; 32],
+    ) -> schnorr::Signature {
+        let nonces: Vec<(SecNonce, PubNonce)> = secrets
+            .iter()
+            .map(|sk| nonce_gen(secp, PublicKey::from_secret_key(secp, sk)))
+            .collect();
+        let agg_nonce = nonce_agg(&nonces.iter().map(|(_, p)| *p).collect::<Vec<_>>()).unwrap();
+        let session = Session::new(secp, ctx, agg_nonce, msg).unwrap();
+
+        let sigs: Vec<PartialSignature> = nonces
+            .into_iter()
+            .zip(secrets)
+            .map(|((sec, public), sk)| {
+                let sig = session.partial_sign(secp, sec, sk).unwrap();
+                let pk = PublicKey::from_secret_key(secp, sk);
+                assert!(session.partial_sig_verify(secp, &sig, &public, &pk));
+                sig
+            })
+            .collect();
+        session.aggregate(&sigs).unwrap()
+    }
+
+    fn secrets(n: u8) -> Vec<SecretKey> {
+        (1..=n)
+            .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn aggregate_signature_verifies() {
+        let secp = Secp256k1::new();
+        let secrets = secrets(3);
+        let keys = secrets
+            .iter()
+            .map(|sk| PublicKey::from_secret_key(&secp, sk))
+            .collect();
+        let ctx = KeyAggContext::new(&secp, keys).unwrap();
+        let key = ctx.x_only();
+        let msg = [7; 32];
+
+        let sig = sign_all(&secp, ctx, &secrets, msg);
+        let msg = bitcoin::secp256k1::Message::from_digest(msg);
+        secp.verify_schnorr(&sig, &msg, &key).unwrap();
+    }
+
+    #[test]
+    fn taproot_tweaked_signature_verifies() {
+        let secp = Secp256k1::new();
+        let secrets = secrets(2);
+        let keys: Vec<PublicKey> = secrets
+            .iter()
+            .map(|sk| PublicKey::from_secret_key(&secp, sk))
+            .collect();
+        let ctx = KeyAggContext::new(&secp, keys.clone()).unwrap();
+        let internal = ctx.x_only();
+        let merkle_root = Some(TapNodeHash::from_byte_array([3; 32]));
+        let ctx = ctx.with_taproot_tweak(&secp, merkle_root).unwrap();
+        let (output_key, _) = internal.tap_tweak(&secp, merkle_root);
+        assert_eq!(ctx.x_only(), output_key.to_inner());
+
+        let msg = [9; 32];
+        let sig = sign_all(&secp, ctx, &secrets, msg);
+        let msg = bitcoin::secp256k1::Message::from_digest(msg);
+        secp.verify_schnorr(&sig, &msg, &output_key.to_inner())
+            .unwrap();
+    }
+
+    fn hex32(s: &str) -> [u8; 32] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    fn pubkey(s: &str) -> PublicKey {
+        PublicKey::from_slice(&hex::decode(s).unwrap()).unwrap()
+    }
+
+    fn pubkeys(keys: &[&str], indices: &[usize]) -> Vec<PublicKey> {
+        indices.iter().map(|&i| pubkey(keys[i])).collect()
+    }
+
+    fn sec_nonce(s: &str) -> SecNonce {
+        let bytes = hex::decode(s).unwrap();
+        SecNonce {
+            k1: SecretKey::from_slice(&bytes[..32]).unwrap(),
+            k2: SecretKey::from_slice(&bytes[32..64]).unwrap(),
+            pubkey: PublicKey::from_slice(&bytes[64..]).unwrap(),
+        }
+    }
+
+    fn pub_nonce(s: &str) -> PubNonce {
+        PubNonce::from_bytes(&hex::decode(s).unwrap()).unwrap()
+    }
+
+    // BIP327 key_agg_vectors.json
+    #[test]
+    fn bip327_key_agg_vectors() {
+        let secp = Secp256k1::new();
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ];
+        let cases: [(&[usize], &str); 4] = [
+            (
+                &[0, 1, 2],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ];
+        for (indices, expected) in cases {
+            let ctx = KeyAggContext::unsorted(&secp, pubkeys(&keys, indices)).unwrap();
+            assert_eq!(ctx.x_only().serialize(), hex32(expected));
+        }
+    }
+
+    const SIGN_SK: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+    const SIGN_KEYS: [&str; 3] = [
+        "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+        "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+        "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+    ];
+    const SIGN_SECNONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F703935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9";
+    const SIGN_PNONCES: [&str; 4] = [
+        "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+        "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+    ];
+    const SIGN_MSG: &str = "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF";
+
+    /// Signs as the vectors' signer, whose key sits at `key_indices[signer]`
+    fn sign_vector(
+        secp: &Secp256k1<All>,
+        ctx: KeyAggContext,
+        nonce_indices: &[usize],
+        signer: usize,
+    ) -> (Session, PartialSignature) {
+        let nonces: Vec<PubNonce> = nonce_indices
+            .iter()
+            .map(|&i| pub_nonce(SIGN_PNONCES[i]))
+            .collect();
+        let session =
+            Session::new(secp, ctx, nonce_agg(&nonces).unwrap(), hex32(SIGN_MSG)).unwrap();
+        let sk = SecretKey::from_slice(&hex32(SIGN_SK)).unwrap();
+        let sig = session
+            .partial_sign(secp, sec_nonce(SIGN_SECNONCE), &sk)
+            .unwrap();
+        assert!(session.partial_sig_verify(secp, &sig, &nonces[signer], &pubkey(SIGN_KEYS[0])));
+        (session, sig)
+    }
+
+    // BIP327 sign_verify_vectors.json
+    #[test]
+    fn bip327_sign_verify_vectors() {
+        let secp = Secp256k1::new();
+        let sk = SecretKey::from_slice(&hex32(SIGN_SK)).unwrap();
+        assert_eq!(PublicKey::from_secret_key(&secp, &sk), pubkey(SIGN_KEYS[0]));
+        let nonces: Vec<PubNonce> = SIGN_PNONCES[..3].iter().map(|n| pub_nonce(n)).collect();
+        assert_eq!(
+            hex::encode_upper(nonce_agg(&nonces).unwrap().to_bytes()),
+            "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9"
+        );
+
+        let cases: [(&[usize], &[usize], usize, &str); 4] = [
+            (
+                &[0, 1, 2],
+                &[0, 1, 2],
+                0,
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                &[1, 0, 2],
+                &[1, 0, 2],
+                1,
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                &[1, 2, 0],
+                &[1, 2, 0],
+                2,
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+            // Both halves of the aggregate nonce are the point at infinity
+            (
+                &[0, 1],
+                &[0, 3],
+                0,
+                "AE386064B26105404798F75DE2EB9AF5EDA5387B064B83D049CB7C5E08879531",
+            ),
+        ];
+        for (key_indices, nonce_indices, signer, expected) in cases {
+            let ctx = KeyAggContext::unsorted(&secp, pubkeys(&SIGN_KEYS, key_indices)).unwrap();
+            let (_, sig) = sign_vector(&secp, ctx, nonce_indices, signer);
+            assert_eq!(sig.to_bytes(), hex32(expected));
+        }
+        assert_eq!(
+            AggNonce::from_bytes(&[0; 66]).unwrap(),
+            nonce_agg(&[pub_nonce(SIGN_PNONCES[0]), pub_nonce(SIGN_PNONCES[3])]).unwrap()
+        );
+    }
+
+    #[test]
+    fn partial_sig_verify_rejects_wrong_signatures() {
+        let secp = Secp256k1::new();
+        let ctx = KeyAggContext::unsorted(&secp, pubkeys(&SIGN_KEYS, &[0, 1, 2])).unwrap();
+        let (session, sig) = sign_vector(&secp, ctx.clone(), &[0, 1, 2], 0);
+        let nonce = pub_nonce(SIGN_PNONCES[0]);
+        let key = pubkey(SIGN_KEYS[0]);
+
+        let negated = PartialSignature(sig.0.negate());
+        assert!(!session.partial_sig_verify(&secp, &negated, &nonce, &key));
+        // Right signature, attributed to another signer's key and nonce
+        let other_nonce = pub_nonce(SIGN_PNONCES[1]);
+        assert!(!session.partial_sig_verify(&secp, &sig, &other_nonce, &pubkey(SIGN_KEYS[1])));
+        assert!(!session.partial_sig_verify(&secp, &sig, &other_nonce, &key));
+        // A key outside the aggregate
+        let outsider = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[5; 32]).unwrap());
+        assert!(!session.partial_sig_verify(&secp, &sig, &nonce, &outsider));
+        // The same nonces over another message
+        let other = Session::new(&secp, ctx, session.agg_nonce(), [0; 32]).unwrap();
+        assert!(!other.partial_sig_verify(&secp, &sig, &nonce, &key));
+    }
+
+    // BIP327 tweak_vectors.json
+    #[test]
+    fn bip327_tweak_vectors() {
+        let secp = Secp256k1::new();
+        let keys = [
+            SIGN_KEYS[0],
+            SIGN_KEYS[1],
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        ];
+        let tweaks = [
+            "E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB",
+            "AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455",
+            "F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0",
+            "1969AD73CC177FA0B4FCED6DF1F7BF9907E665FDE9BA196A74FED0A3CF5AEF9D",
+        ];
+        let cases: [(&[bool], &str); 5] = [
+            (
+                &[true],
+                "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91",
+            ),
+            (
+                &[false],
+                "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D",
+            ),
+            (
+                &[false, true],
+                "408A0A21C4A0F5DACAF9646AD6EB6FECD7F7A11F03ED1F48DFFF2185BC2C2408",
+            ),
+            (
+                &[false, false, true, true],
+                "45ABD206E61E3DF2EC9E264A6FEC8292141A633C28586388235541F9ADE75435",
+            ),
+            (
+                &[true, false, true, false],
+                "B255FDCAC27B40C7CE7848E2D3B7BF5EA0ED756DA81565AC804CCCA3E1D5D239",
+            ),
+        ];
+        for (is_xonly, expected) in cases {
+            let mut ctx = KeyAggContext::unsorted(&secp, pubkeys(&keys, &[1, 2, 0])).unwrap();
+            for (tweak, xonly) in tweaks.iter().zip(is_xonly) {
+                let tweak = Scalar::from_be_bytes(hex32(tweak)).unwrap();
+                ctx = ctx.with_tweak(&secp, tweak, *xonly).unwrap();
+            }
+            let nonces: Vec<PubNonce> = [1, 2, 0]
+                .iter()
+                .map(|&i| pub_nonce(SIGN_PNONCES[i]))
+                .collect();
+            let session =
+                Session::new(&secp, ctx, nonce_agg(&nonces).unwrap(), hex32(SIGN_MSG)).unwrap();
+            let sk = SecretKey::from_slice(&hex32(SIGN_SK)).unwrap();
+            let sig = session
+                .partial_sign(&secp, sec_nonce(SIGN_SECNONCE), &sk)
+                .unwrap();
+            assert_eq!(sig.to_bytes(), hex32(expected), "tweaks {is_xonly:?}");
+        }
+    }
+
+    #[test]
+    fn reduces_hashes_mod_n() {
+        let scalar = scalar_mod_n([0xff; 32]);
+        // 2^256 - 1 - n
+        let mut expected = [0u8; 32];
+        expected[15] = 0x01;
+        expected[16..].copy_from_slice(&[
+            0x45, 0x51, 0x23, 0x19, 0x50, 0xb7, 0x5f, 0xc4, 0x40, 0x2d, 0xa1, 0x73, 0x2f, 0xc9,
+            0xbe, 0xbe,
+        ]);
+        assert_eq!(scalar.to_be_bytes(), expected);
+    }
+}
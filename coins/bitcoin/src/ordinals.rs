@@ -0,0 +1,638 @@
+// Ordinals module: inscriptions and rare sats held by the wallet's outputs, so they can be
+// frozen instead of spent as fees or change
+//
+// Sats flow through a transaction first in, first out: the inputs' sats, in order, fill the
+// outputs in order and whatever is left over goes to fees. An inscription revealed in input
+// `i` sits on the first sat of that input, unless its pointer field names another offset.
+use anyhow::Result;
+use bitcoin::blockdata::opcodes::all::{OP_ENDIF, OP_IF, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{OutPoint, Script, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const CONTENT_TYPE_TAG: &[u8] = &[1];
+const POINTER_TAG: &[u8] = &[2];
+
+const SUBSIDY_HALVING_INTERVAL: u32 = 210_000;
+const DIFFCHANGE_INTERVAL: u32 = 2016;
+/// Halvings between conjunctions of a halving and a difficulty adjustment
+const CYCLE_EPOCHS: u32 = 6;
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+/// Reveal transaction ID and the inscription's index among those it reveals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InscriptionId {
+    pub txid: Txid,
+    pub index: u32,
+}
+
+impl fmt::Display for InscriptionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}i{}", self.txid, self.index)
+    }
+}
+
+impl FromStr for InscriptionId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (txid, index) = s
+            .split_once('i')
+            .ok_or_else(|| anyhow::anyhow!("Invalid inscription ID: {s}"))?;
+        Ok(Self {
+            txid: txid.parse()?,
+            index: index.parse()?,
+        })
+    }
+}
+
+/// An inscription parsed from a reveal transaction's witness envelope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inscription {
+    pub id: InscriptionId,
+    /// Input whose witness revealed it
+    pub input: usize,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    /// Offset into the transaction's output sats the inscription is made on
+    pub pointer: Option<u64>,
+}
+
+/// Inscriptions revealed by a transaction, in input order
+pub fn parse_inscriptions(tx: &Transaction) -> Vec<Inscription> {
+    let txid = tx.txid();
+    let mut inscriptions = vec![];
+    for (input, txin) in tx.input.iter().enumerate() {
+        let Some(script) = txin.witness.tapscript() else {
+            continue;
+        };
+        for fields in envelopes(script) {
+            let id = InscriptionId {
+                txid,
+                index: inscriptions.len() as u32,
+            };
+            inscriptions.push(inscription(id, input, fields));
+        }
+    }
+    inscriptions
+}
+
+/// Pushes inside every `OP_FALSE OP_IF "ord" ... OP_ENDIF` envelope of a tapscript
+fn envelopes(script: &Script) -> Vec<Vec<Vec<u8>>> {
+    let mut envelopes = vec![];
+    let mut instructions = script.instructions().peekable();
+    let mut previous: [Option<Instruction>; 2] = [None, None];
+    while let Some(Ok(instruction)) = instructions.next() {
+        let opens = matches!(previous, [Some(Instruction::PushBytes(f)), Some(Instruction::Op(OP_IF))] if f.is_empty())
+            && matches!(&instruction, Instruction::PushBytes(p) if p.as_bytes() == PROTOCOL_ID);
+        previous = [previous[1].take(), Some(instruction)];
+        if !opens {
+            continue;
+        }
+
+        let mut pushes = vec![];
+        let closed = loop {
+            match instructions.next() {
+                Some(Ok(Instruction::PushBytes(bytes))) => pushes.push(bytes.as_bytes().to_vec()),
+                // OP_1 to OP_16 push their number
+                Some(Ok(Instruction::Op(op)))
+                    if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+                {
+                    pushes.push(vec![op.to_u8() - OP_PUSHNUM_1.to_u8() + 1])
+                }
+                Some(Ok(Instruction::Op(OP_ENDIF))) => break true,
+                _ => break false,
+            }
+        };
+        if closed {
+            envelopes.push(pushes);
+        }
+        previous = [None, None];
+    }
+    envelopes
+}
+
+/// Fields are tag and value pairs, then an empty push and the body
+fn inscription(id: InscriptionId, input: usize, pushes: Vec<Vec<u8>>) -> Inscription {
+    let mut inscription = Inscription {
+        id,
+        input,
+        content_type: None,
+        body: vec![],
+        pointer: None,
+    };
+    let mut pushes = pushes.into_iter();
+    while let Some(tag) = pushes.next() {
+        if tag.is_empty() {
+            inscription.body = pushes.flatten().collect();
+            break;
+        }
+        let Some(value) = pushes.next() else { break };
+        match tag.as_slice() {
+            CONTENT_TYPE_TAG if inscription.content_type.is_none() => {
+                inscription.content_type = Some(String::from_utf8_lossy(&value).into_owned())
+            }
+            // Little-endian, with trailing zeros allowed
+            POINTER_TAG if inscription.pointer.is_none() && value.len() <= 8 => {
+                let mut bytes = [0; 8];
+                bytes[..value.len()].copy_from_slice(&value);
+                inscription.pointer = Some(u64::from_le_bytes(bytes));
+            }
+            _ => {}
+        }
+    }
+    inscription
+}
+
+/// Output and offset in it that sat `offset` of the transaction's outputs lands on, or `None`
+/// if it goes to fees
+fn output_offset(tx: &Transaction, offset: u64) -> Option<(usize, u64)> {
+    let mut start = 0;
+    for (vout, output) in tx.output.iter().enumerate() {
+        let value = output.value.to_sat();
+        if offset < start + value {
+            return Some((vout, offset - start));
+        }
+        start += value;
+    }
+    None
+}
+
+/// How many leading inputs' values `scan_output` needs to place the inscriptions of `tx`
+pub fn input_values_needed(tx: &Transaction) -> usize {
+    parse_inscriptions(tx)
+        .iter()
+        .map(|i| i.input)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Ordinal number of a sat, counted in the order sats were mined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Sat(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Rarity {
+    Common,
+    /// First sat of a block
+    Uncommon,
+    /// First sat of a difficulty adjustment period
+    Rare,
+    /// First sat of a halving epoch
+    Epic,
+    /// First sat of a cycle, every sixth halving
+    Legendary,
+    /// First sat of the genesis block
+    Mythic,
+}
+
+fn subsidy(epoch: u32) -> u64 {
+    INITIAL_SUBSIDY.checked_shr(epoch).unwrap_or(0)
+}
+
+impl Sat {
+    /// First sat mined in block `height`
+    pub fn first_of_block(height: u32) -> Self {
+        let epoch = height / SUBSIDY_HALVING_INTERVAL;
+        let start: u64 = (0..epoch)
+            .map(|e| subsidy(e) * SUBSIDY_HALVING_INTERVAL as u64)
+            .sum();
+        Sat(start + (height % SUBSIDY_HALVING_INTERVAL) as u64 * subsidy(epoch))
+    }
+
+    /// Block the sat was mined in and its offset in that block's subsidy
+    fn block_offset(self) -> (u32, u64) {
+        let mut epoch = 0;
+        let mut start = 0;
+        while subsidy(epoch) > 0
+            && self.0 >= start + subsidy(epoch) * SUBSIDY_HALVING_INTERVAL as u64
+        {
+            start += subsidy(epoch) * SUBSIDY_HALVING_INTERVAL as u64;
+            epoch += 1;
+        }
+        let subsidy = subsidy(epoch).max(1);
+        let into_epoch = self.0 - start;
+        (
+            epoch * SUBSIDY_HALVING_INTERVAL + (into_epoch / subsidy) as u32,
+            into_epoch % subsidy,
+        )
+    }
+
+    pub fn height(self) -> u32 {
+        self.block_offset().0
+    }
+
+    pub fn rarity(self) -> Rarity {
+        let (height, offset) = self.block_offset();
+        if self.0 == 0 {
+            Rarity::Mythic
+        } else if offset != 0 {
+            Rarity::Common
+        } else if height % (SUBSIDY_HALVING_INTERVAL * CYCLE_EPOCHS) == 0 {
+            Rarity::Legendary
+        } else if height % SUBSIDY_HALVING_INTERVAL == 0 {
+            Rarity::Epic
+        } else if height % DIFFCHANGE_INTERVAL == 0 {
+            Rarity::Rare
+        } else {
+            Rarity::Uncommon
+        }
+    }
+}
+
+/// Height a BIP34 coinbase commits to in its script sig
+fn coinbase_height(tx: &Transaction) -> Option<u32> {
+    match tx.input.first()?.script_sig.instructions().next()?.ok()? {
+        Instruction::PushBytes(bytes) if bytes.len() <= 4 => {
+            let mut le = [0; 4];
+            le[..bytes.len()].copy_from_slice(bytes.as_bytes());
+            Some(u32::from_le_bytes(le))
+        }
+        Instruction::Op(op)
+            if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+        {
+            Some((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as u32)
+        }
+        _ => None,
+    }
+}
+
+/// A sat rarer than common and its offset in the output holding it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RareSat {
+    pub offset: u64,
+    pub sat: Sat,
+    pub rarity: Rarity,
+}
+
+/// Sats of an output that shouldn't be spent as fees or change
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtectedSats {
+    /// Inscriptions and their offsets in the output
+    pub inscriptions: Vec<(InscriptionId, u64)>,
+    pub rare_sats: Vec<RareSat>,
+}
+
+impl ProtectedSats {
+    pub fn is_empty(&self) -> bool {
+        self.inscriptions.is_empty() && self.rare_sats.is_empty()
+    }
+
+    /// Add what another source found, skipping duplicates
+    pub fn merge(&mut self, other: ProtectedSats) {
+        for inscription in other.inscriptions {
+            if !self.inscriptions.iter().any(|(id, _)| *id == inscription.0) {
+                self.inscriptions.push(inscription);
+            }
+        }
+        for sat in other.rare_sats {
+            if !self.rare_sats.iter().any(|s| s.sat == sat.sat) {
+                self.rare_sats.push(sat);
+            }
+        }
+    }
+}
+
+/// Inscriptions revealed by `tx` and rare sats it mined that land in output `vout`.
+/// `input_values` holds the values spent by the first `input_values_needed(tx)` inputs.
+/// Inscriptions and sats moved by ordinary transfers need `OrdClient`.
+pub fn scan_output(tx: &Transaction, vout: u32, input_values: &[u64]) -> Result<ProtectedSats> {
+    let mut protected = ProtectedSats::default();
+    let output_total: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+    for inscription in parse_inscriptions(tx) {
+        let offset = match inscription.pointer.filter(|p| *p < output_total) {
+            Some(pointer) => pointer,
+            None => {
+                let before = input_values.get(..inscription.input).ok_or_else(|| {
+                    anyhow::anyhow!("Value spent by input {} is needed", inscription.input - 1)
+                })?;
+                before.iter().sum()
+            }
+        };
+        if let Some((out, offset)) = output_offset(tx, offset) {
+            if out == vout as usize {
+                protected.inscriptions.push((inscription.id, offset));
+            }
+        }
+    }
+
+    // A coinbase pays out the block's new sats first, so its first sat is at least uncommon
+    if tx.is_coinbase() {
+        if let Some(height) = coinbase_height(tx) {
+            let sat = Sat::first_of_block(height);
+            if let Some((out, offset)) = output_offset(tx, 0) {
+                if out == vout as usize {
+                    protected.rare_sats.push(RareSat {
+                        offset,
+                        sat,
+                        rarity: sat.rarity(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(protected)
+}
+
+#[derive(Deserialize)]
+struct OrdOutput {
+    #[serde(default)]
+    inscriptions: Vec<String>,
+    /// Only reported by indexes built with `--index-sats`
+    #[serde(default)]
+    sat_ranges: Option<Vec<(u64, u64)>>,
+}
+
+#[derive(Deserialize)]
+struct OrdInscription {
+    /// `txid:vout:offset`
+    satpoint: String,
+}
+
+/// JSON API of a local `ord server`, which also follows inscriptions and rare sats through
+/// ordinary transfers. Rare sats need an index built with `--index-sats`.
+pub struct OrdClient {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl OrdClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        Ok(self
+            .http
+            .get(format!("{}{path}", self.url))
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Inscriptions and rare sats the index knows to be in an output
+    pub async fn protected_sats(&self, outpoint: &OutPoint) -> Result<ProtectedSats> {
+        let output: OrdOutput = self.get(&format!("/output/{outpoint}")).await?;
+        let mut protected = ProtectedSats::default();
+        for id in output.inscriptions {
+            let inscription: OrdInscription = self.get(&format!("/inscription/{id}")).await?;
+            let offset = inscription
+                .satpoint
+                .rsplit(':')
+                .next()
+                .and_then(|o| o.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid satpoint {}", inscription.satpoint))?;
+            protected.inscriptions.push((id.parse()?, offset));
+        }
+
+        let mut offset = 0;
+        for (start, end) in output.sat_ranges.unwrap_or_default() {
+            // Only the first sat of a block can be rarer than common
+            let mut height = Sat(start).height();
+            loop {
+                let sat = Sat::first_of_block(height);
+                if sat.0 >= end {
+                    break;
+                }
+                if sat.0 >= start {
+                    protected.rare_sats.push(RareSat {
+                        offset: offset + sat.0 - start,
+                        sat,
+                        rarity: sat.rarity(),
+                    });
+                }
+                height += 1;
+            }
+            offset += end - start;
+        }
+        Ok(protected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utxo_manager::{FreezeReason, SelectionStrategy, TrackedUtxo};
+    use crate::{AddressType, BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{
+        absolute, transaction, Address, Amount, FeeRate, Network, ScriptBuf, Sequence, TxIn, TxOut,
+        Witness,
+    };
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    /// Script-path witness revealing an inscription of `body`, with an optional pointer
+    fn reveal_witness(body: &[u8], pointer: Option<u64>) -> Witness {
+        let mut builder = Builder::new()
+            .push_slice([2; 32])
+            .push_opcode(OP_CHECKSIG)
+            .push_int(0)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord")
+            .push_slice([1])
+            .push_slice(b"text/plain;charset=utf-8");
+        if let Some(pointer) = pointer {
+            builder = builder.push_slice([2]).push_slice(pointer.to_le_bytes());
+        }
+        let script = builder
+            .push_int(0)
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(body).unwrap())
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        let mut control_block = vec![0xc0];
+        control_block.extend([3; 32]);
+        Witness::from_slice(&[vec![1; 64], script.to_bytes(), control_block])
+    }
+
+    fn tx(inputs: Vec<(Txid, Witness)>, outputs: Vec<(ScriptBuf, u64)>) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|(txid, witness)| TxIn {
+                    previous_output: OutPoint::new(txid, 0),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness,
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn finds_where_inscriptions_land() {
+        let reveal = tx(
+            vec![
+                (Txid::all_zeros(), reveal_witness(b"first", None)),
+                (Txid::all_zeros(), reveal_witness(b"second", None)),
+                (Txid::all_zeros(), reveal_witness(b"third", Some(600))),
+            ],
+            vec![(ScriptBuf::new(), 546), (ScriptBuf::new(), 20_000)],
+        );
+        let inscriptions = parse_inscriptions(&reveal);
+        assert_eq!(inscriptions.len(), 3);
+        assert_eq!(inscriptions[0].body, b"first");
+        assert_eq!(
+            inscriptions[0].content_type.as_deref(),
+            Some("text/plain;charset=utf-8")
+        );
+        assert_eq!(inscriptions[2].pointer, Some(600));
+        assert_eq!(
+            inscriptions[1]
+                .id
+                .to_string()
+                .parse::<InscriptionId>()
+                .unwrap(),
+            inscriptions[1].id
+        );
+
+        // The second input's sats start after the first input's 10,000
+        assert_eq!(input_values_needed(&reveal), 2);
+        assert!(scan_output(&reveal, 0, &[]).is_err());
+        let first = scan_output(&reveal, 0, &[10_000, 5_000]).unwrap();
+        assert_eq!(first.inscriptions, vec![(inscriptions[0].id, 0)]);
+        let second = scan_output(&reveal, 1, &[10_000, 5_000]).unwrap();
+        assert_eq!(
+            second.inscriptions,
+            vec![(inscriptions[1].id, 10_000 - 546), (inscriptions[2].id, 54)]
+        );
+    }
+
+    #[test]
+    fn rates_sats_by_when_they_were_mined() {
+        assert_eq!(Sat(0).rarity(), Rarity::Mythic);
+        assert_eq!(Sat(1).rarity(), Rarity::Common);
+        assert_eq!(Sat(INITIAL_SUBSIDY).height(), 1);
+        assert_eq!(Sat::first_of_block(1).rarity(), Rarity::Uncommon);
+        assert_eq!(Sat::first_of_block(2016).rarity(), Rarity::Rare);
+        assert_eq!(Sat::first_of_block(210_000).rarity(), Rarity::Epic);
+        assert_eq!(Sat::first_of_block(1_260_000).rarity(), Rarity::Legendary);
+        assert_eq!(
+            Sat::first_of_block(210_001).0,
+            210_000 * INITIAL_SUBSIDY + INITIAL_SUBSIDY / 2
+        );
+        assert_eq!(Sat::first_of_block(840_123).height(), 840_123);
+
+        let mut coinbase = tx(
+            vec![(Txid::all_zeros(), Witness::new())],
+            vec![(ScriptBuf::new(), 0), (ScriptBuf::new(), 312_500_000)],
+        );
+        coinbase.input[0].previous_output = OutPoint::null();
+        coinbase.input[0].script_sig = Builder::new().push_int(840_000).into_script();
+        let protected = scan_output(&coinbase, 1, &[]).unwrap();
+        assert_eq!(protected.rare_sats[0].rarity, Rarity::Epic);
+        assert_eq!(protected.rare_sats[0].offset, 0);
+    }
+
+    #[tokio::test]
+    async fn frozen_inscriptions_are_only_spent_by_inscription_sends() {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        manager
+            .create_wallet("alice", Some(MNEMONIC.to_string()))
+            .await
+            .unwrap();
+        let ours = |address: String| {
+            address
+                .parse::<Address<_>>()
+                .unwrap()
+                .require_network(Network::Regtest)
+                .unwrap()
+        };
+        let taproot = ours(
+            manager
+                .get_receive_address("alice", AddressType::Taproot)
+                .await
+                .unwrap(),
+        );
+        let segwit = ours(
+            manager
+                .get_receive_address("alice", AddressType::NativeSegwit)
+                .await
+                .unwrap(),
+        );
+
+        let reveal = tx(
+            vec![(Txid::all_zeros(), reveal_witness(b"hello", None))],
+            vec![(taproot.script_pubkey(), 10_000)],
+        );
+        let inscribed = TrackedUtxo {
+            outpoint: OutPoint::new(reveal.txid(), 0),
+            txout: reveal.output[0].clone(),
+            confirmations: 6,
+        };
+        let funding = TrackedUtxo {
+            outpoint: OutPoint::new(Txid::from_byte_array([7; 32]), 1),
+            txout: TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: segwit.script_pubkey(),
+            },
+            confirmations: 6,
+        };
+        let protected = scan_output(&reveal, 0, &[]).unwrap();
+        let id = protected.inscriptions[0].0;
+        {
+            let utxo_manager = manager.utxo_manager();
+            let mut utxo_manager = utxo_manager.write().await;
+            utxo_manager.update_utxos("alice", vec![inscribed.clone(), funding]);
+            utxo_manager.freeze(inscribed.outpoint, FreezeReason::ProtectedSats(protected));
+        }
+
+        // Ordinary sends can't reach the inscribed sats
+        let recipient = ours(
+            manager
+                .get_change_address("alice", AddressType::NativeSegwit)
+                .await
+                .unwrap(),
+        );
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+        assert!(manager
+            .create_psbt(
+                "alice",
+                &[(recipient.clone(), 55_000)],
+                fee_rate,
+                SelectionStrategy::default()
+            )
+            .await
+            .is_err());
+
+        let psbt = manager
+            .create_inscription_psbt("alice", &id, &recipient, fee_rate)
+            .await
+            .unwrap();
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input[0].previous_output, inscribed.outpoint);
+        assert_eq!(tx.output[0].script_pubkey, recipient.script_pubkey());
+        // The whole inscribed output moves first, so the sat keeps its offset
+        assert_eq!(tx.output[0].value.to_sat(), 10_000);
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx.output.len() == 2 && tx.output[1].value.to_sat() < 50_000);
+    }
+}
@@ -0,0 +1,229 @@
+// Bitcoin Core RPC endpoints with failover and health checks
+use crate::RpcEndpoint;
+use anyhow::Result;
+use bitcoincore_rpc::{jsonrpc, Auth, Client, RpcApi};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a failed endpoint is skipped before it is tried again
+pub const UNHEALTHY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A connection handed to RPC calls run through the pool
+pub struct RpcConnection {
+    pub client: Arc<Client>,
+    endpoint: RpcEndpoint,
+}
+
+impl RpcConnection {
+    /// Client for a wallet loaded on this node, for wallet RPCs such as `listunspent`
+    pub fn wallet_client(&self, wallet: &str) -> Result<Client> {
+        let url = format!(
+            "{}/wallet/{wallet}",
+            self.endpoint.url.trim_end_matches('/')
+        );
+        Ok(Client::new(
+            &url,
+            Auth::UserPass(self.endpoint.user.clone(), self.endpoint.pass.clone()),
+        )?)
+    }
+}
+
+struct RpcNode {
+    connection: Arc<RpcConnection>,
+    /// Set when the node last failed to answer
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl RpcNode {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_until
+            .lock()
+            .unwrap()
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        *self.unhealthy_until.lock().unwrap() =
+            (!healthy).then(|| Instant::now() + UNHEALTHY_BACKOFF);
+    }
+}
+
+/// Result of probing one endpoint
+#[derive(Debug, Clone)]
+pub struct EndpointHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub block_height: Option<u64>,
+}
+
+/// RPC endpoints used in turn: calls go to the last node that answered, and move on to the
+/// next when a node can't be reached. Unreachable nodes are skipped for `UNHEALTHY_BACKOFF`.
+pub struct RpcPool {
+    nodes: Vec<RpcNode>,
+    current: AtomicUsize,
+}
+
+impl RpcPool {
+    pub fn new(endpoints: &[RpcEndpoint]) -> Result<Self> {
+        let nodes = endpoints
+            .iter()
+            .map(|endpoint| {
+                let client = Client::new(
+                    &endpoint.url,
+                    Auth::UserPass(endpoint.user.clone(), endpoint.pass.clone()),
+                )?;
+                Ok(RpcNode {
+                    connection: Arc::new(RpcConnection {
+                        client: Arc::new(client),
+                        endpoint: endpoint.clone(),
+                    }),
+                    unhealthy_until: Mutex::new(None),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            nodes,
+            current: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Every node's client, in configuration order
+    pub fn clients(&self) -> Vec<Arc<Client>> {
+        self.nodes
+            .iter()
+            .map(|n| n.connection.client.clone())
+            .collect()
+    }
+
+    /// Run a blocking RPC call, failing over to the next endpoint when a node can't be
+    /// reached. Errors returned by a node that did answer are passed through.
+    pub async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&RpcConnection) -> Result<T> + Send + Sync + 'static,
+    {
+        if self.nodes.is_empty() {
+            return Err(anyhow::anyhow!("No RPC endpoints configured"));
+        }
+
+        let f = Arc::new(f);
+        let mut errors = vec![];
+        for index in self.order() {
+            let node = &self.nodes[index];
+            let connection = node.connection.clone();
+            let call = f.clone();
+            match tokio::task::spawn_blocking(move || call(&connection)).await? {
+                Ok(value) => {
+                    node.set_healthy(true);
+                    self.current.store(index, Ordering::Relaxed);
+                    return Ok(value);
+                }
+                Err(e) if is_connection_error(&e) => {
+                    log::warn!("RPC endpoint {} failed: {e}", node.connection.endpoint.url);
+                    node.set_healthy(false);
+                    errors.push(format!("{}: {e}", node.connection.endpoint.url));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(anyhow::anyhow!(
+            "All RPC endpoints failed: {}",
+            errors.join("; ")
+        ))
+    }
+
+    /// Probe every endpoint with `getblockcount` and record which are reachable
+    pub async fn health_check(&self) -> Vec<EndpointHealth> {
+        let mut report = vec![];
+        for node in &self.nodes {
+            let client = node.connection.client.clone();
+            let height = tokio::task::spawn_blocking(move || client.get_block_count())
+                .await
+                .ok()
+                .and_then(|r| r.ok());
+            node.set_healthy(height.is_some());
+            report.push(EndpointHealth {
+                url: node.connection.endpoint.url.clone(),
+                healthy: height.is_some(),
+                block_height: height,
+            });
+        }
+        report
+    }
+
+    /// Nodes to try: healthy ones starting from the current node, then unhealthy ones as a
+    /// last resort
+    fn order(&self) -> Vec<usize> {
+        let start = self.current.load(Ordering::Relaxed);
+        let rotated: Vec<usize> = (0..self.nodes.len())
+            .map(|i| (start + i) % self.nodes.len())
+            .collect();
+        let (healthy, unhealthy): (Vec<usize>, Vec<usize>) = rotated
+            .into_iter()
+            .partition(|i| self.nodes[*i].is_healthy());
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+}
+
+/// Whether the node could not be reached, as opposed to answering with an error
+fn is_connection_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<bitcoincore_rpc::Error>(),
+        Some(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(
+            _
+        ))) | Some(bitcoincore_rpc::Error::Io(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(port: u16) -> RpcEndpoint {
+        RpcEndpoint {
+            url: format!("http://127.0.0.1:{port}"),
+            user: "user".to_string(),
+            pass: "pass".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_marks_unreachable_nodes() {
+        // Nothing listens on the discard port
+        let pool = RpcPool::new(&[endpoint(9), endpoint(9)]).unwrap();
+        let err = pool
+            .call(|node| Ok(node.client.get_block_count()?))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("All RPC endpoints failed"));
+        assert!(pool.nodes.iter().all(|n| !n.is_healthy()));
+
+        // Answers that aren't connection failures stop the failover
+        let err = pool
+            .call(|_| -> Result<()> { Err(anyhow::anyhow!("bad request")) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "bad request");
+
+        let health = pool.health_check().await;
+        assert_eq!(health.len(), 2);
+        assert!(health
+            .iter()
+            .all(|h| !h.healthy && h.block_height.is_none()));
+    }
+
+    #[test]
+    fn tries_healthy_nodes_first() {
+        let pool = RpcPool::new(&[endpoint(1), endpoint(2), endpoint(3)]).unwrap();
+        pool.current.store(1, Ordering::Relaxed);
+        assert_eq!(pool.order(), vec![1, 2, 0]);
+        pool.nodes[2].set_healthy(false);
+        assert_eq!(pool.order(), vec![1, 0, 2]);
+    }
+}
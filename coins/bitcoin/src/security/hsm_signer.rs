@@ -1 +1,241 @@
-// HSM signer module
+// HSM signer module: ECDSA signing with secp256k1 keys held on a PKCS#11 token
+use std::path::PathBuf;
+
+/// PKCS#11 module and token to keep signing keys on, e.g. SoftHSM's
+/// `/usr/lib/softhsm/libsofthsm2.so`
+#[derive(Clone)]
+pub struct HsmConfig {
+    pub module: PathBuf,
+    pub token_label: String,
+    pub pin: String,
+}
+
+impl std::fmt::Debug for HsmConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HsmConfig")
+            .field("module", &self.module)
+            .field("token_label", &self.token_label)
+            .field("pin", &"<redacted>")
+            .finish()
+    }
+}
+
+#[cfg(feature = "hsm")]
+pub use token::{Pkcs11Signer, Pkcs11Token};
+
+#[cfg(feature = "hsm")]
+mod token {
+    use super::HsmConfig;
+    use crate::security::{Signer, TaprootSpend};
+    use anyhow::Result;
+    use bitcoin::bip32::{DerivationPath, Fingerprint};
+    use bitcoin::secp256k1::{ecdsa, schnorr, Message, SecretKey};
+    use bitcoin::PublicKey;
+    use cryptoki::context::{CInitializeArgs, Pkcs11};
+    use cryptoki::mechanism::Mechanism;
+    use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+    use cryptoki::session::{Session, UserType};
+    use cryptoki::types::AuthPin;
+    use std::sync::{Arc, Mutex};
+
+    /// DER encoding of the secp256k1 curve OID, 1.3.132.0.10
+    const SECP256K1_OID: [u8; 7] = [0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a];
+
+    /// A logged-in session on a PKCS#11 token. Keys are found by label.
+    pub struct Pkcs11Token {
+        session: Mutex<Session>,
+    }
+
+    impl Pkcs11Token {
+        pub fn open(config: &HsmConfig) -> Result<Self> {
+            let pkcs11 = Pkcs11::new(&config.module)?;
+            pkcs11.initialize(CInitializeArgs::OsThreads)?;
+            let slot = pkcs11
+                .get_slots_with_token()?
+                .into_iter()
+                .find(|slot| {
+                    pkcs11
+                        .get_token_info(*slot)
+                        .is_ok_and(|info| info.label() == config.token_label)
+                })
+                .ok_or_else(|| anyhow::anyhow!("No token labelled {}", config.token_label))?;
+            let session = pkcs11.open_rw_session(slot)?;
+            session.login(UserType::User, Some(&AuthPin::new(config.pin.clone())))?;
+            Ok(Self {
+                session: Mutex::new(session),
+            })
+        }
+
+        pub fn has_key(&self, label: &str) -> Result<bool> {
+            Ok(self.find(ObjectClass::PRIVATE_KEY, label)?.is_some())
+        }
+
+        /// Store a private key that can sign but never be read back, with its public key
+        pub fn import_key(
+            &self,
+            label: &str,
+            secret: &SecretKey,
+            public: &PublicKey,
+        ) -> Result<()> {
+            let session = self.session.lock().unwrap();
+            let common = |class| {
+                vec![
+                    Attribute::Class(class),
+                    Attribute::KeyType(KeyType::EC),
+                    Attribute::EcParams(SECP256K1_OID.to_vec()),
+                    Attribute::Label(label.as_bytes().to_vec()),
+                    Attribute::Token(true),
+                ]
+            };
+            let mut private = common(ObjectClass::PRIVATE_KEY);
+            private.extend([
+                Attribute::Value(secret.secret_bytes().to_vec()),
+                Attribute::Private(true),
+                Attribute::Sensitive(true),
+                Attribute::Extractable(false),
+                Attribute::Sign(true),
+            ]);
+            let mut public_object = common(ObjectClass::PUBLIC_KEY);
+            public_object.extend([
+                Attribute::EcPoint(ec_point(public)),
+                Attribute::Verify(true),
+            ]);
+            session.create_object(&public_object)?;
+            session.create_object(&private)?;
+            Ok(())
+        }
+
+        pub fn public_key(&self, label: &str) -> Result<PublicKey> {
+            let handle = self
+                .find(ObjectClass::PUBLIC_KEY, label)?
+                .ok_or_else(|| anyhow::anyhow!("No key {label} on the token"))?;
+            let session = self.session.lock().unwrap();
+            match session
+                .get_attributes(handle, &[AttributeType::EcPoint])?
+                .into_iter()
+                .next()
+            {
+                // CKA_EC_POINT is the SEC1 point wrapped in a DER octet string
+                Some(Attribute::EcPoint(point)) if point.len() > 2 => {
+                    Ok(PublicKey::from_slice(&point[2..])?)
+                }
+                _ => Err(anyhow::anyhow!("Key {label} has no EC point")),
+            }
+        }
+
+        /// ECDSA signature over a 32-byte digest, normalized to low S
+        pub fn sign(&self, label: &str, msg: &Message) -> Result<ecdsa::Signature> {
+            let handle = self
+                .find(ObjectClass::PRIVATE_KEY, label)?
+                .ok_or_else(|| anyhow::anyhow!("No key {label} on the token"))?;
+            let raw = self
+                .session
+                .lock()
+                .unwrap()
+                .sign(&Mechanism::Ecdsa, handle, &msg[..])?;
+            let mut sig = ecdsa::Signature::from_compact(&raw)?;
+            sig.normalize_s();
+            Ok(sig)
+        }
+
+        fn find(&self, class: ObjectClass, label: &str) -> Result<Option<ObjectHandle>> {
+            let session = self.session.lock().unwrap();
+            Ok(session
+                .find_objects(&[
+                    Attribute::Class(class),
+                    Attribute::Label(label.as_bytes().to_vec()),
+                ])?
+                .into_iter()
+                .next())
+        }
+    }
+
+    /// Uncompressed SEC1 point in a DER octet string, as CKA_EC_POINT holds it
+    fn ec_point(public: &PublicKey) -> Vec<u8> {
+        let point = public.inner.serialize_uncompressed();
+        [&[0x04, point.len() as u8][..], &point].concat()
+    }
+
+    /// Signs with the keys of one wallet stored on a token, labelled by master fingerprint
+    /// and derivation path. PKCS#11 has no BIP340 mechanism, so taproot inputs can't be
+    /// signed here.
+    pub struct Pkcs11Signer {
+        token: Arc<Pkcs11Token>,
+        fingerprint: Fingerprint,
+    }
+
+    impl Pkcs11Signer {
+        pub fn new(token: Arc<Pkcs11Token>, fingerprint: Fingerprint) -> Self {
+            Self { token, fingerprint }
+        }
+
+        /// Label of the key at `path` below the master key with this fingerprint
+        pub fn label(fingerprint: Fingerprint, path: &DerivationPath) -> String {
+            format!("walletd/{fingerprint}/{path}")
+        }
+    }
+
+    impl Signer for Pkcs11Signer {
+        fn fingerprint(&self) -> Fingerprint {
+            self.fingerprint
+        }
+
+        fn public_key(&self, path: &DerivationPath) -> Result<PublicKey> {
+            self.token.public_key(&Self::label(self.fingerprint, path))
+        }
+
+        fn sign_ecdsa(&self, path: &DerivationPath, msg: &Message) -> Result<ecdsa::Signature> {
+            self.token.sign(&Self::label(self.fingerprint, path), msg)
+        }
+
+        fn sign_schnorr(
+            &self,
+            _path: &DerivationPath,
+            _msg: &Message,
+            _spend: TaprootSpend,
+        ) -> Result<schnorr::Signature> {
+            Err(anyhow::anyhow!(
+                "PKCS#11 tokens cannot make BIP340 Schnorr signatures"
+            ))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use bitcoin::secp256k1::Secp256k1;
+
+        /// Run against SoftHSM with a token initialized by
+        /// `softhsm2-util --init-token --free --label walletd --pin 1234 --so-pin 0000` and
+        /// `SOFTHSM2_MODULE=/usr/lib/softhsm/libsofthsm2.so`
+        #[test]
+        #[ignore]
+        fn signs_on_softhsm() {
+            let token = Arc::new(
+                Pkcs11Token::open(&HsmConfig {
+                    module: std::env::var("SOFTHSM2_MODULE").unwrap().into(),
+                    token_label: "walletd".to_string(),
+                    pin: "1234".to_string(),
+                })
+                .unwrap(),
+            );
+            let secp = Secp256k1::new();
+            let secret = SecretKey::new(&mut bitcoin::secp256k1::rand::thread_rng());
+            let public = PublicKey::new(secret.public_key(&secp));
+            let fingerprint = Fingerprint::from([std::process::id() as u8, 1, 2, 3]);
+            let path: DerivationPath = "m/84'/0'/0'/0/0".parse().unwrap();
+            let label = Pkcs11Signer::label(fingerprint, &path);
+            token.import_key(&label, &secret, &public).unwrap();
+            assert!(token.has_key(&label).unwrap());
+
+            let signer = Pkcs11Signer::new(token, fingerprint);
+            assert_eq!(signer.public_key(&path).unwrap(), public);
+            let msg = Message::from_digest([7; 32]);
+            let sig = signer.sign_ecdsa(&path, &msg).unwrap();
+            secp.verify_ecdsa(&msg, &sig, &public.inner).unwrap();
+            assert!(signer
+                .sign_schnorr(&path, &msg, TaprootSpend::ScriptPath)
+                .is_err());
+        }
+    }
+}
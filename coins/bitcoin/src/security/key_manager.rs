@@ -1 +1,309 @@
-// Key manager module
+// Key manager module: master keys sealed under a passphrase and the keys currently unlocked
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
+use bitcoin::bip32::{DerivationPath, KeySource, Xpriv};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{All, Secp256k1};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Bound into every sealed key so ciphertexts can't be swapped with other sealed data
+const SEALED_KEY_AAD: &[u8] = b"walletd master key";
+
+/// A secret encrypted with AES-256-GCM under a key derived from a passphrase with Argon2id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedKey {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    /// Argon2id memory cost in KiB, time cost and parallelism
+    kdf_params: (u32, u32, u32),
+}
+
+impl SealedKey {
+    pub fn seal(secret: &[u8], passphrase: &str) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        let mut nonce = vec![0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        thread_rng().fill_bytes(&mut nonce);
+        let params = Params::default();
+        let kdf_params = (params.m_cost(), params.t_cost(), params.p_cost());
+
+        let cipher = cipher(passphrase, &salt, kdf_params)?;
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: secret,
+                    aad: SEALED_KEY_AAD,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        Ok(Self {
+            salt,
+            nonce,
+            ciphertext,
+            kdf_params,
+        })
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+        if self.nonce.len() != NONCE_LEN {
+            return Err(anyhow::anyhow!("Malformed sealed key"));
+        }
+        let cipher = cipher(passphrase, &self.salt, self.kdf_params)?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: SEALED_KEY_AAD,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow::anyhow!("Incorrect wallet passphrase"))
+    }
+}
+
+fn cipher(
+    passphrase: &str,
+    salt: &[u8],
+    (m_cost, t_cost, p_cost): (u32, u32, u32),
+) -> Result<Aes256Gcm> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {e}"))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {e}"))?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref())))
+}
+
+/// A wallet's master key as written to disk
+#[derive(Clone)]
+pub enum MasterKey {
+    /// Not encrypted with a wallet passphrase; storage still encrypts it at rest
+    Plain(Xpriv),
+    /// Encrypted with the wallet passphrase
+    Sealed(SealedKey),
+}
+
+struct Entry {
+    sealed: Option<SealedKey>,
+    /// Encoded extended private key, while unlocked or for unencrypted wallets
+    unlocked: Option<Zeroizing<Vec<u8>>>,
+    /// When an unlocked sealed key is locked again
+    expires: Option<Instant>,
+    /// Origin of the key: the master fingerprint and the path the key sits at below it
+    origin: KeySource,
+}
+
+impl Entry {
+    fn xprv(&self) -> Result<Xpriv> {
+        let encoded = self
+            .unlocked
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Wallet is locked"))?;
+        Ok(Xpriv::decode(encoded)?)
+    }
+}
+
+/// Master keys per user. Wallets encrypted with a passphrase keep only their sealed key until
+/// unlocked, and lock again once the unlock timeout passes, as with Bitcoin Core's
+/// `walletpassphrase`.
+pub struct KeyManager {
+    entries: HashMap<String, Entry>,
+    unlock_timeout: Duration,
+}
+
+impl KeyManager {
+    pub fn new(unlock_timeout: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            unlock_timeout,
+        }
+    }
+
+    /// Add an unencrypted key. `origin` is where the key sits below its master, if it isn't
+    /// a master key itself.
+    pub fn insert(&mut self, user_id: &str, xprv: &Xpriv, origin: KeySource) {
+        self.entries.insert(
+            user_id.to_string(),
+            Entry {
+                sealed: None,
+                unlocked: Some(Zeroizing::new(xprv.encode().to_vec())),
+                expires: None,
+                origin,
+            },
+        );
+    }
+
+    /// Add a key sealed under its wallet passphrase, locked
+    pub fn insert_sealed(&mut self, user_id: &str, sealed: SealedKey, origin: KeySource) {
+        self.entries.insert(
+            user_id.to_string(),
+            Entry {
+                sealed: Some(sealed),
+                unlocked: None,
+                expires: None,
+                origin,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, user_id: &str) {
+        self.entries.remove(user_id);
+    }
+
+    /// Seal an unencrypted key under `passphrase` and lock it
+    pub fn encrypt(&mut self, user_id: &str, passphrase: &str) -> Result<()> {
+        let entry = self.entry(user_id)?;
+        if entry.sealed.is_some() {
+            return Err(anyhow::anyhow!(
+                "Wallet is already encrypted; change its passphrase instead"
+            ));
+        }
+        let encoded = entry
+            .unlocked
+            .take()
+            .expect("unencrypted keys are unlocked");
+        entry.sealed = Some(SealedKey::seal(&encoded, passphrase)?);
+        Ok(())
+    }
+
+    /// Reseal a key under a new passphrase. The key stays locked.
+    pub fn change_passphrase(&mut self, user_id: &str, old: &str, new: &str) -> Result<()> {
+        let entry = self.entry(user_id)?;
+        let sealed = entry
+            .sealed
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Wallet is not encrypted"))?;
+        let encoded = sealed.open(old)?;
+        entry.sealed = Some(SealedKey::seal(&encoded, new)?);
+        entry.unlocked = None;
+        entry.expires = None;
+        Ok(())
+    }
+
+    /// Decrypt a sealed key for the unlock timeout
+    pub fn unlock(&mut self, user_id: &str, passphrase: &str) -> Result<()> {
+        let timeout = self.unlock_timeout;
+        let entry = self.entry(user_id)?;
+        let sealed = entry
+            .sealed
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Wallet is not encrypted"))?;
+        entry.unlocked = Some(sealed.open(passphrase)?);
+        entry.expires = Some(Instant::now() + timeout);
+        Ok(())
+    }
+
+    /// Drop the decrypted copy of a sealed key
+    pub fn lock(&mut self, user_id: &str) -> Result<()> {
+        let entry = self.entry(user_id)?;
+        if entry.sealed.is_none() {
+            return Err(anyhow::anyhow!("Wallet is not encrypted"));
+        }
+        entry.unlocked = None;
+        entry.expires = None;
+        Ok(())
+    }
+
+    pub fn lock_all(&mut self) {
+        for entry in self.entries.values_mut() {
+            if entry.sealed.is_some() {
+                entry.unlocked = None;
+                entry.expires = None;
+            }
+        }
+    }
+
+    /// Lock every key whose unlock timeout has passed
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for entry in self.entries.values_mut() {
+            if entry.expires.is_some_and(|expires| expires <= now) {
+                entry.unlocked = None;
+                entry.expires = None;
+            }
+        }
+    }
+
+    pub fn is_encrypted(&self, user_id: &str) -> bool {
+        self.entries
+            .get(user_id)
+            .is_some_and(|e| e.sealed.is_some())
+    }
+
+    pub fn is_locked(&self, user_id: &str) -> bool {
+        self.entries
+            .get(user_id)
+            .is_none_or(|e| e.unlocked.is_none())
+    }
+
+    pub fn origin(&self, user_id: &str) -> Option<&KeySource> {
+        self.entries.get(user_id).map(|e| &e.origin)
+    }
+
+    /// The unlocked key and its origin
+    pub fn xprv(&self, user_id: &str) -> Result<(Xpriv, &KeySource)> {
+        let entry = self
+            .entries
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("No key for {user_id}"))?;
+        Ok((entry.xprv()?, &entry.origin))
+    }
+
+    /// The key as it should be persisted
+    pub fn stored(&self, user_id: &str) -> Result<MasterKey> {
+        let entry = self
+            .entries
+            .get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("No key for {user_id}"))?;
+        match &entry.sealed {
+            Some(sealed) => Ok(MasterKey::Sealed(sealed.clone())),
+            None => Ok(MasterKey::Plain(entry.xprv()?)),
+        }
+    }
+
+    fn entry(&mut self, user_id: &str) -> Result<&mut Entry> {
+        self.entries
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow::anyhow!("No key for {user_id}"))
+    }
+}
+
+/// Path of `path` below a key with the given origin, or `None` if it isn't below it
+pub fn relative_path(origin: &KeySource, path: &DerivationPath) -> Option<DerivationPath> {
+    let (_, origin_path) = origin;
+    path.as_ref()
+        .strip_prefix(origin_path.as_ref())
+        .map(DerivationPath::from)
+}
+
+/// Origin of a key with no recorded origin: itself, as a master key
+pub fn master_origin(xprv: &Xpriv, secp: &Secp256k1<All>) -> KeySource {
+    (xprv.fingerprint(secp), DerivationPath::master())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_under_passphrase() {
+        let sealed = SealedKey::seal(b"secret", "correct horse").unwrap();
+        assert_eq!(sealed.open("correct horse").unwrap().as_slice(), b"secret");
+        assert!(sealed.open("wrong").is_err());
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(tampered.open("correct horse").is_err());
+    }
+}
@@ -1,19 +1,337 @@
-// Security module
+// Security module: sealed master keys and the signers transactions are signed through
 pub mod hsm_signer;
 pub mod key_manager;
 
 use anyhow::Result;
+use bitcoin::bip32::{DerivationPath, Fingerprint, KeySource, Xpriv, Xpub};
+use bitcoin::key::TapTweak;
+use bitcoin::secp256k1::{ecdsa, schnorr, All, Keypair, Message, Secp256k1};
+use bitcoin::taproot::TapNodeHash;
+use bitcoin::PublicKey;
+use hsm_signer::HsmConfig;
+use key_manager::{KeyManager, MasterKey};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
-pub struct BitcoinSecurityVault {
-    // Simplified for compilation
-}
+/// How long a wallet unlocked with its passphrase stays unlocked by default
+pub const DEFAULT_UNLOCK_TIMEOUT: Duration = Duration::from_secs(300);
 
+#[derive(Debug, Clone)]
 pub struct SecurityConfig {
+    /// Sign with keys copied onto the PKCS#11 token in `hsm` instead of in memory. Needs
+    /// the `hsm` feature.
+    ///
+    /// PKCS#11 has no BIP32 mechanism, so the master key still lives in this process: each
+    /// child key is derived here from it and imported onto the token as a non-extractable key
+    /// the first time it signs. The token keeps those keys from being read back, but doesn't
+    /// protect the master key from a compromised host while the wallet is unlocked. To keep it
+    /// out of memory, encrypt the wallet and leave it locked once its addresses have signed
+    /// once; keys already on the token sign without unlocking.
     pub use_hsm: bool,
+    pub hsm: Option<HsmConfig>,
+    /// How long `unlock` keeps a passphrase-encrypted wallet unlocked
+    pub unlock_timeout: Duration,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            use_hsm: false,
+            hsm: None,
+            unlock_timeout: DEFAULT_UNLOCK_TIMEOUT,
+        }
+    }
+}
+
+/// Which key a taproot signature is made with
+#[derive(Debug, Clone, Copy)]
+pub enum TaprootSpend {
+    /// The output key: the internal key tweaked with the script tree's merkle root
+    KeyPath { merkle_root: Option<TapNodeHash> },
+    /// The untweaked key, for a leaf script
+    ScriptPath,
+}
+
+/// Signs with the keys below one master key, identified by its fingerprint. Paths are full
+/// derivation paths from that master, as in PSBT key origins.
+pub trait Signer: Send + Sync {
+    fn fingerprint(&self) -> Fingerprint;
+
+    fn public_key(&self, path: &DerivationPath) -> Result<PublicKey>;
+
+    fn sign_ecdsa(&self, path: &DerivationPath, msg: &Message) -> Result<ecdsa::Signature>;
+
+    fn sign_schnorr(
+        &self,
+        path: &DerivationPath,
+        msg: &Message,
+        spend: TaprootSpend,
+    ) -> Result<schnorr::Signature>;
+}
+
+/// Holds every wallet's master key, sealed with Argon2id and AES-256-GCM once the wallet is
+/// encrypted, and hands out signers for them. With `use_hsm`, child keys derived from the
+/// master key are copied onto a PKCS#11 token before they first sign and signing happens
+/// there; the master key itself stays here (see `SecurityConfig::use_hsm`).
+pub struct BitcoinSecurityVault {
+    keys: Mutex<KeyManager>,
+    secp: Secp256k1<All>,
+    #[cfg(feature = "hsm")]
+    token: Option<Arc<hsm_signer::Pkcs11Token>>,
 }
 
 impl BitcoinSecurityVault {
-    pub fn new(_config: SecurityConfig) -> Result<Self> {
-        Ok(Self {})
+    pub fn new(config: SecurityConfig) -> Result<Self> {
+        #[cfg(feature = "hsm")]
+        let token = match (config.use_hsm, &config.hsm) {
+            (true, Some(hsm)) => Some(Arc::new(hsm_signer::Pkcs11Token::open(hsm)?)),
+            (true, None) => return Err(anyhow::anyhow!("use_hsm is set without an HSM config")),
+            (false, _) => None,
+        };
+        #[cfg(not(feature = "hsm"))]
+        if config.use_hsm {
+            return Err(anyhow::anyhow!(
+                "use_hsm needs walletd_bitcoin built with the hsm feature"
+            ));
+        }
+
+        Ok(Self {
+            keys: Mutex::new(KeyManager::new(config.unlock_timeout)),
+            secp: Secp256k1::new(),
+            #[cfg(feature = "hsm")]
+            token,
+        })
+    }
+
+    /// Add an unencrypted key, with its origin if it isn't a master key
+    pub fn add_key(&self, user_id: &str, xprv: &Xpriv, origin: Option<KeySource>) {
+        let origin = origin.unwrap_or_else(|| key_manager::master_origin(xprv, &self.secp));
+        self.keys().insert(user_id, xprv, origin);
+    }
+
+    /// Add a key read back from storage
+    pub fn restore_key(&self, user_id: &str, key: MasterKey, origin: KeySource) {
+        match key {
+            MasterKey::Plain(xprv) => self.keys().insert(user_id, &xprv, origin),
+            MasterKey::Sealed(sealed) => self.keys().insert_sealed(user_id, sealed, origin),
+        }
+    }
+
+    pub fn remove_key(&self, user_id: &str) {
+        self.keys().remove(user_id);
+    }
+
+    /// The key as it should be persisted
+    pub fn stored_key(&self, user_id: &str) -> Result<MasterKey> {
+        self.keys().stored(user_id)
+    }
+
+    /// Master fingerprint and path of the user's key
+    pub fn origin(&self, user_id: &str) -> Result<KeySource> {
+        self.keys()
+            .origin(user_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No key for {user_id}"))
+    }
+
+    /// Seal an unencrypted key under a passphrase. The wallet is locked afterwards.
+    pub fn encrypt(&self, user_id: &str, passphrase: &str) -> Result<()> {
+        self.keys().encrypt(user_id, passphrase)
+    }
+
+    pub fn change_passphrase(&self, user_id: &str, old: &str, new: &str) -> Result<()> {
+        self.keys().change_passphrase(user_id, old, new)
+    }
+
+    /// Decrypt a wallet's key until the unlock timeout passes or it is locked again
+    pub fn unlock(&self, user_id: &str, passphrase: &str) -> Result<()> {
+        self.keys().unlock(user_id, passphrase)
+    }
+
+    pub fn lock(&self, user_id: &str) -> Result<()> {
+        self.keys().lock(user_id)
+    }
+
+    pub fn lock_all(&self) {
+        self.keys().lock_all()
+    }
+
+    pub fn is_encrypted(&self, user_id: &str) -> bool {
+        self.keys().is_encrypted(user_id)
+    }
+
+    pub fn is_locked(&self, user_id: &str) -> bool {
+        self.keys().is_locked(user_id)
+    }
+
+    /// Extended public key at a path from the master, e.g. an account's `m/84'/0'/0'`.
+    /// Needs the key unlocked.
+    pub fn xpub(&self, user_id: &str, path: &DerivationPath) -> Result<Xpub> {
+        let child = self.derive_priv(user_id, path)?;
+        Ok(Xpub::from_priv(&self.secp, &child))
+    }
+
+    /// Signer for the user's keys: the HSM with `use_hsm`, otherwise the unlocked key
+    pub fn signer(self: &Arc<Self>, user_id: &str) -> Result<Arc<dyn Signer>> {
+        let (fingerprint, _) = self.origin(user_id)?;
+        #[cfg(feature = "hsm")]
+        if let Some(token) = &self.token {
+            return Ok(Arc::new(hsm_signer::Pkcs11Signer::new(
+                token.clone(),
+                fingerprint,
+            )));
+        }
+        Ok(Arc::new(VaultSigner {
+            vault: self.clone(),
+            user_id: user_id.to_string(),
+            fingerprint,
+        }))
+    }
+
+    /// With `use_hsm`, copy the keys at `paths` onto the token if they aren't there yet.
+    /// New keys are derived from the master key, so they can only be copied while the wallet
+    /// is unlocked; the derived secret is wiped once it is on the token.
+    pub fn provision<'a>(
+        &self,
+        user_id: &str,
+        paths: impl IntoIterator<Item = &'a DerivationPath>,
+    ) -> Result<()> {
+        #[cfg(feature = "hsm")]
+        if let Some(token) = &self.token {
+            let (fingerprint, _) = self.origin(user_id)?;
+            for path in paths {
+                let label = hsm_signer::Pkcs11Signer::label(fingerprint, path);
+                if token.has_key(&label)? {
+                    continue;
+                }
+                let mut child = self.derive_priv(user_id, path)?.to_priv();
+                let imported =
+                    token.import_key(&label, &child.inner, &child.public_key(&self.secp));
+                child.inner.non_secure_erase();
+                imported?;
+            }
+        }
+        #[cfg(not(feature = "hsm"))]
+        let _ = (user_id, paths.into_iter());
+        Ok(())
+    }
+
+    /// Private key at a path from the master
+    fn derive_priv(&self, user_id: &str, path: &DerivationPath) -> Result<Xpriv> {
+        let keys = self.keys();
+        let (xprv, origin) = keys.xprv(user_id)?;
+        let relative = key_manager::relative_path(origin, path)
+            .ok_or_else(|| anyhow::anyhow!("{path} is not below the wallet's key"))?;
+        Ok(xprv.derive_priv(&self.secp, &relative)?)
+    }
+
+    /// The key manager, with expired unlocks locked again
+    fn keys(&self) -> MutexGuard<'_, KeyManager> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.expire();
+        keys
+    }
+}
+
+/// Signs in memory with a key unlocked in the vault
+struct VaultSigner {
+    vault: Arc<BitcoinSecurityVault>,
+    user_id: String,
+    fingerprint: Fingerprint,
+}
+
+impl Signer for VaultSigner {
+    fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    fn public_key(&self, path: &DerivationPath) -> Result<PublicKey> {
+        Ok(self.vault.xpub(&self.user_id, path)?.to_pub())
+    }
+
+    fn sign_ecdsa(&self, path: &DerivationPath, msg: &Message) -> Result<ecdsa::Signature> {
+        let key = self.vault.derive_priv(&self.user_id, path)?;
+        Ok(self.vault.secp.sign_ecdsa(msg, &key.private_key))
+    }
+
+    fn sign_schnorr(
+        &self,
+        path: &DerivationPath,
+        msg: &Message,
+        spend: TaprootSpend,
+    ) -> Result<schnorr::Signature> {
+        let secp = &self.vault.secp;
+        let key = self.vault.derive_priv(&self.user_id, path)?;
+        let keypair = Keypair::from_secret_key(secp, &key.private_key);
+        let keypair = match spend {
+            TaprootSpend::KeyPath { merkle_root } => {
+                keypair.tap_tweak(secp, merkle_root).to_inner()
+            }
+            TaprootSpend::ScriptPath => keypair,
+        };
+        Ok(secp.sign_schnorr(msg, &keypair))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+
+    fn vault(unlock_timeout: Duration) -> Arc<BitcoinSecurityVault> {
+        let vault = BitcoinSecurityVault::new(SecurityConfig {
+            unlock_timeout,
+            ..Default::default()
+        })
+        .unwrap();
+        vault.add_key(
+            "alice",
+            &Xpriv::new_master(Network::Regtest, &[1; 32]).unwrap(),
+            None,
+        );
+        Arc::new(vault)
+    }
+
+    #[test]
+    fn signs_only_while_unlocked() {
+        let vault = vault(DEFAULT_UNLOCK_TIMEOUT);
+        let signer = vault.signer("alice").unwrap();
+        let path: DerivationPath = "m/84'/0'/0'/0/0".parse().unwrap();
+        let msg = Message::from_digest([3; 32]);
+        let public = signer.public_key(&path).unwrap();
+        let sig = signer.sign_ecdsa(&path, &msg).unwrap();
+        vault.secp.verify_ecdsa(&msg, &sig, &public.inner).unwrap();
+
+        vault.encrypt("alice", "hunter2").unwrap();
+        assert!(vault.is_locked("alice"));
+        assert!(signer.sign_ecdsa(&path, &msg).is_err());
+        assert!(vault.unlock("alice", "wrong").is_err());
+
+        vault.unlock("alice", "hunter2").unwrap();
+        assert_eq!(signer.sign_ecdsa(&path, &msg).unwrap(), sig);
+        vault.lock("alice").unwrap();
+        assert!(signer.public_key(&path).is_err());
+
+        // A sealed key restored from storage opens with the same passphrase
+        let MasterKey::Sealed(sealed) = vault.stored_key("alice").unwrap() else {
+            panic!("key should be sealed");
+        };
+        let origin = vault.origin("alice").unwrap();
+        vault.restore_key("bob", MasterKey::Sealed(sealed), origin);
+        vault.unlock("bob", "hunter2").unwrap();
+        assert_eq!(
+            vault.signer("bob").unwrap().public_key(&path).unwrap(),
+            public
+        );
+    }
+
+    #[test]
+    fn locks_after_timeout() {
+        let vault = vault(Duration::from_millis(50));
+        vault.encrypt("alice", "hunter2").unwrap();
+        vault.unlock("alice", "hunter2").unwrap();
+        assert!(!vault.is_locked("alice"));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(vault.is_locked("alice"));
     }
 }
@@ -1,4 +1,774 @@
-// Storage module placeholder
+// Persistent wallet storage backed by sled, with secrets sealed by a passphrase-derived key
+use crate::discovery::Keychain;
+use crate::labels::{Label, LabelType, Labels};
+use crate::security::key_manager::{self, MasterKey, SealedKey};
+use crate::swaps::AtomicSwap;
+use crate::transaction_builder::fee_bump::{ReplacementTracker, SentTransaction};
+use crate::transaction_builder::script_builder::Descriptor;
+use crate::utxo_manager::{FreezeReason, TrackedUtxo};
+use crate::{AddressType, UserBitcoinWallet};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Result;
+use argon2::Argon2;
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpriv, Xpub};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{All, Secp256k1, XOnlyPublicKey};
+use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::{Address, Network, OutPoint, ScriptBuf};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Version the database is migrated to on open
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Schema migrations; the one at index `i` upgrades version `i` to `i + 1`
+const MIGRATIONS: &[fn(&sled::Db) -> Result<()>] =
+    &[migrate_v1, migrate_v2, migrate_v3, migrate_v4, migrate_v5];
+
+const META: &str = "meta";
+const WALLETS: &str = "wallets";
+const UTXOS: &str = "utxos";
+const LABELS: &str = "labels";
+const HISTORY: &str = "history";
+const SWAPS: &str = "swaps";
+const FROZEN: &str = "frozen";
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+const SALT_KEY: &[u8] = b"kdf_salt";
+const KEY_CHECK_KEY: &[u8] = b"key_check";
+const KEY_CHECK: &[u8] = b"walletd";
+const NONCE_LEN: usize = 12;
+
+/// Where wallet state is stored and the passphrase protecting its secrets
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub path: PathBuf,
+    pub passphrase: String,
+}
+
+impl std::fmt::Debug for StorageConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("path", &self.path)
+            .field("passphrase", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Durable store for user wallets, address indexes, UTXOs, labels, transaction history and
+/// atomic swaps. Extended private keys, descriptors and swaps are encrypted with AES-256-GCM under a key
+/// derived from the passphrase with Argon2id.
 pub struct StorageManager {
-    // Storage implementation
+    db: sled::Db,
+    cipher: Aes256Gcm,
+}
+
+/// A wallet as written to disk
+#[derive(Serialize, Deserialize)]
+struct StoredWallet {
+    user_id: String,
+    /// Extended private key sealed with the storage key, for wallets without a passphrase
+    xprv: Option<Vec<u8>>,
+    /// Extended private key sealed with the wallet passphrase
+    sealed_key: Option<SealedKey>,
+    /// Origin of the key; a master key when unset
+    key_origin: Option<(Fingerprint, DerivationPath)>,
+    account_xpubs: Vec<(DerivationPath, Xpub)>,
+    /// Sealed output descriptor
+    descriptor: Option<Vec<u8>>,
+    current_index: u32,
+    next_indexes: Vec<(Keychain, u32)>,
+    addresses: Vec<String>,
+    script_paths: Vec<(ScriptBuf, DerivationPath)>,
+    descriptor_indexes: Vec<(ScriptBuf, u32)>,
+    taproot_keys: Vec<(XOnlyPublicKey, DerivationPath)>,
+    /// Internal key and leaves of every script-path taproot output
+    taproot_trees: Vec<(ScriptBuf, XOnlyPublicKey, Vec<ScriptBuf>)>,
+    /// Watch-only wallets are stored without a key
+    #[serde(default)]
+    watch_only: bool,
+}
+
+impl StorageManager {
+    /// Open or create the database at `config.path`, migrating it to the current schema
+    pub fn open(config: &StorageConfig) -> Result<Self> {
+        let db = sled::open(&config.path)?;
+        Self::init(db, &config.passphrase)
+    }
+
+    /// A database that is deleted when dropped
+    pub fn temporary(passphrase: &str) -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::init(db, passphrase)
+    }
+
+    fn init(db: sled::Db, passphrase: &str) -> Result<Self> {
+        migrate(&db)?;
+
+        let meta = db.open_tree(META)?;
+        let salt = match meta.get(SALT_KEY)? {
+            Some(salt) => salt.to_vec(),
+            None => {
+                let mut salt = vec![0u8; 16];
+                thread_rng().fill_bytes(&mut salt);
+                meta.insert(SALT_KEY, salt.as_slice())?;
+                salt
+            }
+        };
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+            .map_err(|e| anyhow::anyhow!("Key derivation failed: {e}"))?;
+        let storage = Self {
+            db,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref())),
+        };
+
+        match meta.get(KEY_CHECK_KEY)? {
+            Some(check) => {
+                if storage.decrypt(&check, KEY_CHECK_KEY)? != KEY_CHECK {
+                    return Err(anyhow::anyhow!("Incorrect storage passphrase"));
+                }
+            }
+            None => {
+                let check = storage.encrypt(KEY_CHECK, KEY_CHECK_KEY)?;
+                meta.insert(KEY_CHECK_KEY, check)?;
+            }
+        }
+        storage.db.flush()?;
+        Ok(storage)
+    }
+
+    pub fn schema_version(&self) -> Result<u32> {
+        schema_version(&self.db)
+    }
+
+    /// Write a wallet and its key, replacing any earlier copy
+    pub fn save_wallet(&self, wallet: &UserBitcoinWallet, key: Option<&MasterKey>) -> Result<()> {
+        let aad = wallet.user_id.as_bytes();
+        let (xprv, sealed_key) = match key {
+            Some(MasterKey::Plain(xprv)) => (Some(self.encrypt(&xprv.encode(), aad)?), None),
+            Some(MasterKey::Sealed(sealed)) => (None, Some(sealed.clone())),
+            None => (None, None),
+        };
+        let stored = StoredWallet {
+            user_id: wallet.user_id.clone(),
+            xprv,
+            sealed_key,
+            key_origin: Some(wallet.key_origin.clone()),
+            account_xpubs: wallet.account_xpubs.clone().into_iter().collect(),
+            descriptor: wallet
+                .descriptor
+                .as_ref()
+                .map(|d| self.encrypt(d.to_string().as_bytes(), aad))
+                .transpose()?,
+            current_index: wallet.current_index,
+            next_indexes: wallet.next_indexes.clone().into_iter().collect(),
+            addresses: wallet.addresses.values().map(|a| a.to_string()).collect(),
+            script_paths: wallet.script_paths.clone().into_iter().collect(),
+            descriptor_indexes: wallet.descriptor_indexes.clone().into_iter().collect(),
+            taproot_keys: wallet.taproot_keys.clone().into_iter().collect(),
+            taproot_trees: wallet
+                .taproot_trees
+                .iter()
+                .map(|(script, info)| {
+                    let leaves = wallet.taproot_leaves[script].clone();
+                    (script.clone(), info.internal_key(), leaves)
+                })
+                .collect(),
+            watch_only: wallet.watch_only,
+        };
+        self.put(WALLETS, wallet.user_id.as_bytes(), &stored)
+    }
+
+    /// Read every stored wallet with its key, which watch-only wallets don't have. Keys sealed
+    /// with a wallet passphrase stay sealed.
+    pub fn load_wallets(
+        &self,
+        network: Network,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<(UserBitcoinWallet, Option<MasterKey>)>> {
+        let mut wallets = vec![];
+        for entry in self.db.open_tree(WALLETS)?.iter() {
+            let (_, value) = entry?;
+            let stored: StoredWallet = serde_json::from_slice(&value)?;
+            let aad = stored.user_id.as_bytes();
+
+            let (key, key_origin) = match (&stored.xprv, stored.sealed_key) {
+                (_, Some(sealed)) => {
+                    let origin = stored
+                        .key_origin
+                        .ok_or_else(|| anyhow::anyhow!("Sealed key has no origin"))?;
+                    (Some(MasterKey::Sealed(sealed)), origin)
+                }
+                (Some(xprv), None) => {
+                    let xprv = Xpriv::decode(&self.decrypt(xprv, aad)?)?;
+                    let origin = stored
+                        .key_origin
+                        .unwrap_or_else(|| key_manager::master_origin(&xprv, secp));
+                    (Some(MasterKey::Plain(xprv)), origin)
+                }
+                (None, None) => match stored.key_origin {
+                    Some(origin) if stored.watch_only => (None, origin),
+                    _ => return Err(anyhow::anyhow!("Wallet {} has no key", stored.user_id)),
+                },
+            };
+            let descriptor = match &stored.descriptor {
+                // Older wallets kept the private descriptor; its key now lives in the vault
+                Some(sealed) => Some(
+                    String::from_utf8(self.decrypt(sealed, aad)?)?
+                        .parse::<Descriptor>()?
+                        .to_public(secp),
+                ),
+                None => None,
+            };
+            let addresses = stored
+                .addresses
+                .into_iter()
+                .map(|a| {
+                    let address = Address::from_str(&a)?.require_network(network)?;
+                    Ok((address.script_pubkey(), address))
+                })
+                .collect::<Result<_>>()?;
+
+            let mut taproot_trees = HashMap::new();
+            let mut taproot_leaves = HashMap::new();
+            for (script, internal_key, leaves) in stored.taproot_trees {
+                let info = TaprootSpendInfo::with_huffman_tree(
+                    secp,
+                    internal_key,
+                    leaves.iter().map(|leaf| (1, leaf.clone())),
+                )?;
+                taproot_trees.insert(script.clone(), info);
+                taproot_leaves.insert(script, leaves);
+            }
+
+            let wallet = UserBitcoinWallet {
+                user_id: stored.user_id,
+                key_origin,
+                account_xpubs: stored.account_xpubs.into_iter().collect(),
+                addresses,
+                current_index: stored.current_index,
+                next_indexes: stored.next_indexes.into_iter().collect(),
+                script_paths: stored.script_paths.into_iter().collect(),
+                descriptor,
+                descriptor_indexes: stored.descriptor_indexes.into_iter().collect(),
+                taproot_keys: stored.taproot_keys.into_iter().collect(),
+                taproot_trees,
+                taproot_leaves,
+                watch_only: stored.watch_only,
+            };
+            wallets.push((wallet, key));
+        }
+        Ok(wallets)
+    }
+
+    /// Replace a user's stored UTXO set
+    pub fn save_utxos(&self, user_id: &str, utxos: &[TrackedUtxo]) -> Result<()> {
+        let tree = self.db.open_tree(UTXOS)?;
+        for key in tree.scan_prefix(user_prefix(user_id)).keys() {
+            tree.remove(key?)?;
+        }
+        for utxo in utxos {
+            let key = user_key(user_id, &utxo.outpoint.to_string());
+            tree.insert(key, serde_json::to_vec(utxo)?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Every user's stored UTXOs
+    pub fn load_utxos(&self) -> Result<HashMap<String, Vec<TrackedUtxo>>> {
+        self.load_grouped(UTXOS)
+    }
+
+    /// Replace a user's frozen outpoints
+    pub fn save_frozen(&self, user_id: &str, frozen: &[(OutPoint, FreezeReason)]) -> Result<()> {
+        let tree = self.db.open_tree(FROZEN)?;
+        for key in tree.scan_prefix(user_prefix(user_id)).keys() {
+            tree.remove(key?)?;
+        }
+        for entry in frozen {
+            let key = user_key(user_id, &entry.0.to_string());
+            tree.insert(key, serde_json::to_vec(entry)?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Every user's frozen outpoints
+    pub fn load_frozen(&self) -> Result<HashMap<String, Vec<(OutPoint, FreezeReason)>>> {
+        self.load_grouped(FROZEN)
+    }
+
+    /// Write label records, replacing earlier ones for the same type and reference
+    pub fn save_labels(&self, user_id: &str, labels: &[Label]) -> Result<()> {
+        let tree = self.db.open_tree(LABELS)?;
+        let mut batch = sled::Batch::default();
+        for label in labels {
+            batch.insert(
+                label_key(user_id, label.kind, &label.reference),
+                serde_json::to_vec(label)?,
+            );
+        }
+        tree.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_label(&self, user_id: &str, kind: LabelType, reference: &str) -> Result<()> {
+        self.db
+            .open_tree(LABELS)?
+            .remove(label_key(user_id, kind, reference))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Every user's labels
+    pub fn load_labels(&self) -> Result<HashMap<String, Labels>> {
+        let labels: HashMap<String, Vec<Label>> = self.load_grouped(LABELS)?;
+        Ok(labels
+            .into_iter()
+            .map(|(user, labels)| (user, labels.into_iter().collect()))
+            .collect())
+    }
+
+    /// Write every transaction in a user's history, including replacement links
+    pub fn save_history(&self, user_id: &str, history: &ReplacementTracker) -> Result<()> {
+        let tree = self.db.open_tree(HISTORY)?;
+        for tx in history.history() {
+            let key = user_key(user_id, &tx.txid().to_string());
+            tree.insert(key, serde_json::to_vec(tx)?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Every user's transaction history
+    pub fn load_history(&self) -> Result<HashMap<String, ReplacementTracker>> {
+        let history: HashMap<String, Vec<SentTransaction>> = self.load_grouped(HISTORY)?;
+        Ok(history
+            .into_iter()
+            .map(|(user, txs)| (user, txs.into_iter().collect()))
+            .collect())
+    }
+
+    /// Write a swap, which holds its preimage once known, replacing any earlier copy
+    pub fn save_swap(&self, swap: &AtomicSwap) -> Result<()> {
+        let sealed = self.encrypt(&serde_json::to_vec(swap)?, swap.id.as_bytes())?;
+        self.db
+            .open_tree(SWAPS)?
+            .insert(swap.id.as_bytes(), sealed)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Every stored swap
+    pub fn load_swaps(&self) -> Result<Vec<AtomicSwap>> {
+        let mut swaps = vec![];
+        for entry in self.db.open_tree(SWAPS)?.iter() {
+            let (id, sealed) = entry?;
+            swaps.push(serde_json::from_slice(&self.decrypt(&sealed, &id)?)?);
+        }
+        Ok(swaps)
+    }
+
+    fn put<T: Serialize>(&self, tree: &str, key: &[u8], value: &T) -> Result<()> {
+        self.db
+            .open_tree(tree)?
+            .insert(key, serde_json::to_vec(value)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Values of a tree keyed by `user_key`, grouped by user
+    fn load_grouped<T: DeserializeOwned>(&self, tree: &str) -> Result<HashMap<String, Vec<T>>> {
+        let mut grouped: HashMap<String, Vec<T>> = HashMap::new();
+        for entry in self.db.open_tree(tree)?.iter() {
+            let (key, value) = entry?;
+            let user_len = key
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow::anyhow!("Malformed key in {tree}"))?;
+            let user_id = String::from_utf8(key[..user_len].to_vec())?;
+            grouped
+                .entry(user_id)
+                .or_default()
+                .push(serde_json::from_slice(&value)?);
+        }
+        Ok(grouped)
+    }
+
+    /// Seal `plaintext` as nonce || ciphertext, bound to `aad`
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("Sealed value is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Incorrect storage passphrase or corrupted data"))
+    }
+}
+
+fn user_prefix(user_id: &str) -> Vec<u8> {
+    [user_id.as_bytes(), &[0]].concat()
+}
+
+fn user_key(user_id: &str, item: &str) -> Vec<u8> {
+    [user_prefix(user_id).as_slice(), item.as_bytes()].concat()
+}
+
+fn label_key(user_id: &str, kind: LabelType, reference: &str) -> Vec<u8> {
+    user_key(user_id, &format!("{kind}:{reference}"))
+}
+
+fn schema_version(db: &sled::Db) -> Result<u32> {
+    match db.open_tree(META)?.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => Ok(u32::from_be_bytes(bytes.as_ref().try_into()?)),
+        None => Ok(0),
+    }
+}
+
+/// Run every migration newer than the database, recording the version after each
+fn migrate(db: &sled::Db) -> Result<()> {
+    let version = schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "Database schema {version} is newer than supported version {SCHEMA_VERSION}"
+        ));
+    }
+    let meta = db.open_tree(META)?;
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(db)?;
+        meta.insert(SCHEMA_VERSION_KEY, &(from as u32 + 1).to_be_bytes())?;
+        db.flush()?;
+    }
+    Ok(())
+}
+
+/// Initial schema: one tree per record type
+fn migrate_v1(db: &sled::Db) -> Result<()> {
+    for tree in [WALLETS, UTXOS, LABELS, HISTORY] {
+        db.open_tree(tree)?;
+    }
+    Ok(())
+}
+
+/// Per-chain address indexes. HD wallets used to share one index across address types on
+/// the receive chain, so every receive chain continues from it.
+fn migrate_v2(db: &sled::Db) -> Result<()> {
+    let tree = db.open_tree(WALLETS)?;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        let mut wallet: serde_json::Value = serde_json::from_slice(&value)?;
+        let current_index = wallet["current_index"].as_u64().unwrap_or(0) as u32;
+        let next_indexes: Vec<(Keychain, u32)> = if wallet["descriptor"].is_null() {
+            AddressType::ALL
+                .iter()
+                .map(|t| (Keychain::receive(*t), current_index))
+                .collect()
+        } else {
+            vec![]
+        };
+        let addresses: Vec<serde_json::Value> = wallet["addresses"]
+            .as_array()
+            .map(|a| a.iter().map(|pair| pair[1].clone()).collect())
+            .unwrap_or_default();
+
+        wallet["next_indexes"] = serde_json::to_value(next_indexes)?;
+        wallet["addresses"] = serde_json::Value::Array(addresses);
+        tree.insert(key, serde_json::to_vec(&wallet)?)?;
+    }
+    Ok(())
+}
+
+/// Keys sealed with a wallet passphrase and cached account xpubs. Earlier wallets have
+/// neither, and their accounts are derived again on load.
+fn migrate_v3(db: &sled::Db) -> Result<()> {
+    let tree = db.open_tree(WALLETS)?;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        let mut wallet: serde_json::Value = serde_json::from_slice(&value)?;
+        wallet["sealed_key"] = serde_json::Value::Null;
+        wallet["account_xpubs"] = serde_json::json!([]);
+        tree.insert(key, serde_json::to_vec(&wallet)?)?;
+    }
+    Ok(())
+}
+
+/// Atomic swaps, sealed with the storage key
+fn migrate_v4(db: &sled::Db) -> Result<()> {
+    db.open_tree(SWAPS)?;
+    Ok(())
+}
+
+/// BIP329 label records. Labels used to be bare (reference, label) pairs; their type is
+/// guessed from the reference.
+fn migrate_v5(db: &sled::Db) -> Result<()> {
+    let tree = db.open_tree(LABELS)?;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        let (reference, label): (String, String) = serde_json::from_slice(&value)?;
+        let user_len = key
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| anyhow::anyhow!("Malformed key in {LABELS}"))?;
+        let user_id = std::str::from_utf8(&key[..user_len])?;
+        let label = Label::new(LabelType::guess(&reference), reference, label);
+        tree.remove(&key)?;
+        tree.insert(
+            label_key(user_id, label.kind, &label.reference),
+            serde_json::to_vec(&label)?,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressType, BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::{Amount, OutPoint, TxOut};
+
+    fn config(dir: &std::path::Path, passphrase: &str) -> BitcoinConfig {
+        BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: Some(StorageConfig {
+                path: dir.to_path_buf(),
+                passphrase: passphrase.to_string(),
+            }),
+            electrum_url: None,
+            security: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn wallets_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("walletd-storage-{}", uuid::Uuid::new_v4()));
+        let (address, leaf_address) = {
+            let manager = BitcoinWalletManager::new(config(&dir, "hunter2"))
+                .await
+                .unwrap();
+            manager.create_wallet("alice", None).await.unwrap();
+            let address = manager
+                .get_receive_address("alice", AddressType::Taproot)
+                .await
+                .unwrap();
+            let key = manager.get_taproot_key("alice").await.unwrap();
+            let leaf = bitcoin::blockdata::script::Builder::new()
+                .push_x_only_key(&key)
+                .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
+                .into_script();
+            let leaf_address = manager
+                .get_taproot_script_address("alice", &[leaf])
+                .await
+                .unwrap();
+            manager
+                .set_label("alice", LabelType::Addr, &address, "savings")
+                .await
+                .unwrap();
+
+            let utxo = TrackedUtxo {
+                outpoint: OutPoint::null(),
+                txout: TxOut {
+                    value: Amount::from_sat(5_000),
+                    script_pubkey: ScriptBuf::new(),
+                },
+                confirmations: 2,
+            };
+            manager.utxo_manager().write().await.add_utxo("alice", utxo);
+            manager.persist_utxos("alice").await.unwrap();
+            (address, leaf_address)
+        };
+
+        assert!(BitcoinWalletManager::new(config(&dir, "wrong"))
+            .await
+            .is_err());
+
+        let manager = BitcoinWalletManager::new(config(&dir, "hunter2"))
+            .await
+            .unwrap();
+        let next = manager
+            .get_receive_address("alice", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        assert_ne!(next, address);
+        assert_ne!(next, leaf_address);
+        assert_eq!(
+            manager
+                .label("alice", LabelType::Addr, &address)
+                .await
+                .as_deref(),
+            Some("savings")
+        );
+        assert_eq!(
+            manager.utxo_manager().read().await.balance("alice"),
+            (5_000, 0)
+        );
+
+        let wallets = manager.wallets.read().await;
+        let wallet = &wallets["alice"];
+        let script = Address::from_str(&leaf_address)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+        assert!(wallet.taproot_tree(&script).is_some());
+        assert!(wallet.owns(&script));
+        drop(wallets);
+        drop(manager);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_wallets_reload_locked() {
+        let dir = std::env::temp_dir().join(format!("walletd-storage-{}", uuid::Uuid::new_v4()));
+        let address = {
+            let manager = BitcoinWalletManager::new(config(&dir, "hunter2"))
+                .await
+                .unwrap();
+            manager.create_wallet("alice", None).await.unwrap();
+            manager.encrypt_wallet("alice", "wallet pw").await.unwrap();
+            manager
+                .get_receive_address("alice", AddressType::Legacy)
+                .await
+                .unwrap()
+        };
+
+        let manager = BitcoinWalletManager::new(config(&dir, "hunter2"))
+            .await
+            .unwrap();
+        assert!(manager.is_wallet_locked("alice"));
+        // Cached account xpubs keep handing out addresses while locked
+        let next = manager
+            .get_receive_address("alice", AddressType::Legacy)
+            .await
+            .unwrap();
+        assert_ne!(next, address);
+        assert!(manager
+            .get_address(
+                "alice",
+                crate::Keychain::new(AddressType::Legacy, 1, crate::KeychainKind::External),
+            )
+            .await
+            .is_err());
+
+        assert!(manager.unlock_wallet("alice", "hunter2").is_err());
+        manager.unlock_wallet("alice", "wallet pw").unwrap();
+        manager
+            .get_address(
+                "alice",
+                crate::Keychain::new(AddressType::Legacy, 1, crate::KeychainKind::External),
+            )
+            .await
+            .unwrap();
+        drop(manager);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_and_seals_secrets() {
+        let storage = StorageManager::temporary("pw").unwrap();
+        assert_eq!(storage.schema_version().unwrap(), SCHEMA_VERSION);
+
+        let sealed = storage.encrypt(b"secret", b"alice").unwrap();
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(storage.decrypt(&sealed, b"alice").unwrap(), b"secret");
+        // Sealed values can't be moved to another user
+        assert!(storage.decrypt(&sealed, b"bob").is_err());
+    }
+
+    #[test]
+    fn migrates_shared_index_to_chains() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        migrate_v1(&db).unwrap();
+        db.open_tree(META)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes())
+            .unwrap();
+        let v1 = serde_json::json!({
+            "user_id": "alice",
+            "descriptor": null,
+            "current_index": 3,
+            "addresses": [[0, "bcrt1qexample"]],
+        });
+        let wallets = db.open_tree(WALLETS).unwrap();
+        wallets
+            .insert("alice", serde_json::to_vec(&v1).unwrap())
+            .unwrap();
+
+        migrate(&db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        let v2: serde_json::Value =
+            serde_json::from_slice(&wallets.get("alice").unwrap().unwrap()).unwrap();
+        assert_eq!(v2["addresses"], serde_json::json!(["bcrt1qexample"]));
+        let next_indexes: Vec<(Keychain, u32)> =
+            serde_json::from_value(v2["next_indexes"].clone()).unwrap();
+        assert_eq!(next_indexes.len(), 4);
+        assert!(next_indexes
+            .iter()
+            .all(|(k, i)| k.kind == crate::KeychainKind::External && *i == 3));
+    }
+
+    #[test]
+    fn migrates_labels_to_bip329_records() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        migrate_v1(&db).unwrap();
+        db.open_tree(META)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &4u32.to_be_bytes())
+            .unwrap();
+        let txid = "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd";
+        let labels = db.open_tree(LABELS).unwrap();
+        for (reference, label) in [(txid, "rent"), ("bcrt1qexample", "savings")] {
+            labels
+                .insert(
+                    user_key("alice", reference),
+                    serde_json::to_vec(&(reference, label)).unwrap(),
+                )
+                .unwrap();
+        }
+
+        migrate(&db).unwrap();
+        let storage = StorageManager {
+            db,
+            cipher: Aes256Gcm::new(&[0u8; 32].into()),
+        };
+        let alice = &storage.load_labels().unwrap()["alice"];
+        assert_eq!(alice.len(), 2);
+        assert_eq!(alice.label(LabelType::Tx, txid), Some("rent"));
+        assert_eq!(
+            alice.label(LabelType::Addr, "bcrt1qexample"),
+            Some("savings")
+        );
+    }
 }
@@ -0,0 +1,290 @@
+// HTLC module: hashlocked P2WSH outputs with a timelocked refund, and the transactions
+// that redeem or refund them
+use crate::security::Signer;
+use anyhow::Result;
+use bitcoin::absolute::LockTime;
+use bitcoin::bip32::DerivationPath;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CLTV, OP_CSV, OP_DROP, OP_ELSE, OP_ENDIF, OP_EQUALVERIFY, OP_IF, OP_SHA256,
+    OP_SIZE,
+};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::Message;
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    ecdsa, Address, Amount, FeeRate, Network, OutPoint, PublicKey, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Witness,
+};
+use serde::{Deserialize, Serialize};
+
+/// Size of a DER signature with its sighash byte at most, used to size unsigned spends
+const MAX_SIGNATURE_LEN: usize = 73;
+
+/// When the funder can take an unredeemed HTLC back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HtlcTimelock {
+    /// This many blocks after the funding output confirms (`OP_CHECKSEQUENCEVERIFY`)
+    Relative(u16),
+    /// From this block height on (`OP_CHECKLOCKTIMEVERIFY`)
+    Absolute(u32),
+}
+
+impl HtlcTimelock {
+    /// Whether a refund could be mined in the block after `tip`, for an output that
+    /// confirmed at `funding_height`
+    pub fn is_expired(self, funding_height: u32, tip: u32) -> bool {
+        match self {
+            HtlcTimelock::Relative(blocks) => tip + 1 >= funding_height + blocks as u32,
+            HtlcTimelock::Absolute(height) => tip >= height,
+        }
+    }
+}
+
+/// How an HTLC output is spent
+#[derive(Debug, Clone, Copy)]
+pub enum HtlcSpend {
+    /// By the recipient, revealing the preimage of the payment hash
+    Redeem { secret: [u8; 32] },
+    /// By the funder, once the timelock has passed
+    Refund,
+}
+
+/// Hashed timelock contract: the recipient can spend with the preimage of `payment_hash`,
+/// the funder with their refund key after the timelock.
+///
+/// ```text
+/// OP_IF
+///     OP_SIZE 32 OP_EQUALVERIFY OP_SHA256 <payment_hash> OP_EQUALVERIFY <recipient>
+/// OP_ELSE
+///     <timelock> OP_CHECKSEQUENCEVERIFY|OP_CHECKLOCKTIMEVERIFY OP_DROP <refund>
+/// OP_ENDIF
+/// OP_CHECKSIG
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Htlc {
+    pub payment_hash: sha256::Hash,
+    /// Key that redeems with the preimage
+    pub recipient: PublicKey,
+    /// Key that refunds after the timelock
+    pub refund: PublicKey,
+    pub timelock: HtlcTimelock,
+}
+
+impl Htlc {
+    pub fn witness_script(&self) -> ScriptBuf {
+        let builder = Builder::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SIZE)
+            .push_int(32)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_SHA256)
+            .push_slice(self.payment_hash.to_byte_array())
+            .push_opcode(OP_EQUALVERIFY)
+            .push_key(&self.recipient)
+            .push_opcode(OP_ELSE);
+        let builder = match self.timelock {
+            HtlcTimelock::Relative(blocks) => builder.push_int(blocks as i64).push_opcode(OP_CSV),
+            HtlcTimelock::Absolute(height) => builder.push_int(height as i64).push_opcode(OP_CLTV),
+        };
+        builder
+            .push_opcode(OP_DROP)
+            .push_key(&self.refund)
+            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    /// P2WSH address the HTLC is funded at
+    pub fn address(&self, network: Network) -> Address {
+        Address::p2wsh(&self.witness_script(), network)
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2wsh(&self.witness_script().wscript_hash())
+    }
+
+    /// Witness spending the HTLC output with `signature`, a serialized ECDSA signature
+    pub fn witness(&self, spend: &HtlcSpend, signature: &[u8]) -> Witness {
+        let script = self.witness_script();
+        match spend {
+            HtlcSpend::Redeem { secret } => {
+                Witness::from_slice(&[signature, &secret[..], &[1], script.as_bytes()])
+            }
+            HtlcSpend::Refund => Witness::from_slice(&[signature, &[], script.as_bytes()]),
+        }
+    }
+
+    /// Transaction spending the HTLC output `funding` worth `value` to `destination` at
+    /// `fee_rate`, signed with the key at `key_path`
+    #[allow(clippy::too_many_arguments)]
+    pub fn spend(
+        &self,
+        funding: OutPoint,
+        value: Amount,
+        destination: ScriptBuf,
+        spend: &HtlcSpend,
+        fee_rate: FeeRate,
+        signer: &dyn Signer,
+        key_path: &DerivationPath,
+    ) -> Result<Transaction> {
+        let (sequence, lock_time) = match (spend, self.timelock) {
+            (HtlcSpend::Redeem { .. }, _) => (Sequence::ENABLE_RBF_NO_LOCKTIME, LockTime::ZERO),
+            (HtlcSpend::Refund, HtlcTimelock::Relative(blocks)) => {
+                (Sequence::from_height(blocks), LockTime::ZERO)
+            }
+            (HtlcSpend::Refund, HtlcTimelock::Absolute(height)) => (
+                Sequence::ENABLE_LOCKTIME_NO_RBF,
+                LockTime::from_height(height)?,
+            ),
+        };
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: funding,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: self.witness(spend, &[0; MAX_SIGNATURE_LEN]),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: destination,
+            }],
+        };
+
+        let fee = fee_rate
+            .fee_vb(tx.vsize() as u64)
+            .ok_or_else(|| anyhow::anyhow!("Fee overflow"))?;
+        tx.output[0].value = value
+            .checked_sub(fee)
+            .filter(|v| *v >= tx.output[0].script_pubkey.dust_value())
+            .ok_or_else(|| anyhow::anyhow!("HTLC output of {value} can't pay a fee of {fee}"))?;
+
+        let sighash = SighashCache::new(&tx).p2wsh_signature_hash(
+            0,
+            &self.witness_script(),
+            value,
+            EcdsaSighashType::All,
+        )?;
+        let signature = ecdsa::Signature {
+            sig: signer.sign_ecdsa(key_path, &Message::from_digest(sighash.to_byte_array()))?,
+            hash_ty: EcdsaSighashType::All,
+        };
+        tx.input[0].witness = self.witness(spend, &signature.to_vec());
+        Ok(tx)
+    }
+
+    /// The preimage revealed by `tx` if it redeems the HTLC output at `funding`
+    pub fn extract_secret(&self, tx: &Transaction, funding: OutPoint) -> Option<[u8; 32]> {
+        let input = tx.input.iter().find(|i| i.previous_output == funding)?;
+        if input.witness.len() != 4 || input.witness.last()? != self.witness_script().as_bytes() {
+            return None;
+        }
+        let secret: [u8; 32] = input.witness.nth(1)?.try_into().ok()?;
+        (sha256::Hash::hash(&secret) == self.payment_hash).then_some(secret)
+    }
+}
+
+/// Random preimage and its payment hash
+pub fn new_secret() -> ([u8; 32], sha256::Hash) {
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    (secret, sha256::Hash::hash(&secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{BitcoinSecurityVault, SecurityConfig};
+    use bitcoin::bip32::Xpriv;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::Txid;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[test]
+    fn redeem_reveals_secret() {
+        let vault = Arc::new(BitcoinSecurityVault::new(SecurityConfig::default()).unwrap());
+        vault.add_key(
+            "bob",
+            &Xpriv::new_master(Network::Regtest, &[2; 32]).unwrap(),
+            None,
+        );
+        let signer = vault.signer("bob").unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'/1/0").unwrap();
+        let refund_path = DerivationPath::from_str("m/84'/1'/0'/1/1").unwrap();
+
+        let (secret, payment_hash) = new_secret();
+        let htlc = Htlc {
+            payment_hash,
+            recipient: signer.public_key(&path).unwrap(),
+            refund: signer.public_key(&refund_path).unwrap(),
+            timelock: HtlcTimelock::Relative(144),
+        };
+        assert_eq!(
+            htlc.address(Network::Regtest).script_pubkey(),
+            htlc.script_pubkey()
+        );
+
+        let funding = OutPoint::new(Txid::all_zeros(), 1);
+        let value = Amount::from_sat(100_000);
+        let destination = ScriptBuf::new_p2wpkh(&htlc.recipient.wpubkey_hash().unwrap());
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let tx = htlc
+            .spend(
+                funding,
+                value,
+                destination.clone(),
+                &HtlcSpend::Redeem { secret },
+                fee_rate,
+                signer.as_ref(),
+                &path,
+            )
+            .unwrap();
+        assert_eq!(htlc.extract_secret(&tx, funding), Some(secret));
+        assert_eq!(
+            htlc.extract_secret(&tx, OutPoint::new(Txid::all_zeros(), 0)),
+            None
+        );
+        assert!(value - tx.output[0].value >= fee_rate.fee_vb(tx.vsize() as u64).unwrap());
+
+        // The signature commits to the HTLC script and the funding value
+        let secp = Secp256k1::verification_only();
+        let sighash = SighashCache::new(&tx)
+            .p2wsh_signature_hash(0, &htlc.witness_script(), value, EcdsaSighashType::All)
+            .unwrap();
+        let signature = ecdsa::Signature::from_slice(tx.input[0].witness.nth(0).unwrap()).unwrap();
+        secp.verify_ecdsa(
+            &Message::from_digest(sighash.to_byte_array()),
+            &signature.sig,
+            &htlc.recipient.inner,
+        )
+        .unwrap();
+
+        // A refund waits out the timelock and reveals nothing
+        let refund = htlc
+            .spend(
+                funding,
+                value,
+                destination,
+                &HtlcSpend::Refund,
+                fee_rate,
+                signer.as_ref(),
+                &refund_path,
+            )
+            .unwrap();
+        assert_eq!(refund.input[0].sequence, Sequence::from_height(144));
+        assert_eq!(htlc.extract_secret(&refund, funding), None);
+    }
+
+    #[test]
+    fn timelocks_expire() {
+        assert!(!HtlcTimelock::Relative(10).is_expired(100, 108));
+        assert!(HtlcTimelock::Relative(10).is_expired(100, 109));
+        assert!(!HtlcTimelock::Absolute(200).is_expired(100, 199));
+        assert!(HtlcTimelock::Absolute(200).is_expired(100, 200));
+    }
+}
@@ -1,24 +1,83 @@
+// Cross-chain atomic swaps: the Bitcoin leg is an HTLC driven from Initiated through Locked
+// to Redeemed or Refunded
+pub mod htlc;
+
+use crate::{AddressType, BitcoinWalletManager, FeePriority, Keychain};
 use anyhow::Result;
+use bitcoin::bip32::DerivationPath;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Amount, OutPoint, PublicKey, ScriptBuf, Txid};
+use htlc::{Htlc, HtlcSpend, HtlcTimelock};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
-/// Cross-chain atomic swap coordinator
+/// Refund timelock when we hold the secret and lock BTC first, about a day
+pub const INITIATOR_TIMELOCK: HtlcTimelock = HtlcTimelock::Relative(144);
+
+/// Refund timelock when the counterparty holds the secret; shorter than theirs so we can
+/// refund before they can
+pub const PARTICIPANT_TIMELOCK: HtlcTimelock = HtlcTimelock::Relative(72);
+
+/// Blocks the counterparty has to fund an HTLC we are to redeem
+pub const FUNDING_TIMEOUT_BLOCKS: u32 = 144;
+
+/// Confirmations before a funded HTLC counts as locked
+pub const DEFAULT_MIN_CONFIRMATIONS: u32 = 1;
+
+/// Cross-chain atomic swap coordinator. Swaps are written to the wallet's storage on every
+/// step and resumed after a restart.
 pub struct SwapCoordinator {
+    wallet: Arc<BitcoinWalletManager>,
     active_swaps: Arc<RwLock<HashMap<String, AtomicSwap>>>,
+    min_confirmations: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AtomicSwap {
     pub id: String,
+    /// Wallet the BTC is paid from or to
+    pub user_id: String,
     pub from_chain: Chain,
     pub to_chain: Chain,
     pub from_amount: u64,
     pub to_amount: u64,
     pub status: SwapStatus,
+    pub role: SwapRole,
+    pub htlc: Htlc,
+    /// Path of our key in the HTLC
+    pub key_path: DerivationPath,
+    /// Counterparty's address on the other chain
+    pub counterparty_address: String,
+    /// Preimage of the payment hash, once known
+    pub secret: Option<[u8; 32]>,
+    /// The HTLC output, once funded
+    pub funding: Option<OutPoint>,
+    /// Value of the HTLC output in sats, which its spend signs for. It can be more than the
+    /// agreed amount when the counterparty overfunds.
+    #[serde(default)]
+    pub funding_value: Option<u64>,
+    /// Height the funding output confirmed at
+    pub funding_height: Option<u32>,
+    /// Height the counterparty must fund by, when they lock the BTC
+    pub funding_deadline: Option<u32>,
+    /// The transaction that redeemed or refunded the HTLC
+    pub spend_txid: Option<Txid>,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl AtomicSwap {
+    /// Amount locked in the HTLC, in sats
+    pub fn btc_amount(&self) -> u64 {
+        match self.role {
+            SwapRole::Sender => self.from_amount,
+            SwapRole::Receiver => self.to_amount,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Chain {
     Bitcoin,
     ICP,
@@ -28,56 +87,203 @@ pub enum Chain {
     Hedera,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SwapStatus {
+    /// Waiting for the HTLC to be funded and confirmed
     Initiated,
+    /// The HTLC is funded and waits for the preimage or the timelock
     Locked,
+    /// The HTLC was spent with the preimage
     Redeemed,
+    /// The HTLC was refunded to its funder
     Refunded,
+    /// The HTLC was never funded: the counterparty missed the deadline, or our funding
+    /// transaction couldn't be sent
     Failed,
 }
 
-impl Default for SwapCoordinator {
-    fn default() -> Self {
-        Self::new()
+impl SwapStatus {
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            SwapStatus::Redeemed | SwapStatus::Refunded | SwapStatus::Failed
+        )
     }
 }
 
+/// Our side of the Bitcoin leg
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwapRole {
+    /// We lock BTC for the counterparty to redeem
+    Sender,
+    /// The counterparty locks BTC for us to redeem
+    Receiver,
+}
+
+/// What both sides agreed to for the Bitcoin leg of a swap
+#[derive(Clone, Debug)]
+pub struct SwapTerms {
+    /// Chain of the other leg
+    pub other_chain: Chain,
+    pub btc_amount: u64,
+    /// Amount on the other chain, in its smallest unit
+    pub other_amount: u64,
+    /// Counterparty's key in the HTLC
+    pub counterparty_key: PublicKey,
+    /// Counterparty's address on the other chain
+    pub counterparty_address: String,
+    pub payment_hash: sha256::Hash,
+    pub timelock: HtlcTimelock,
+    /// Our key in the HTLC, from `swap_key`, when it was handed to the counterparty before
+    /// the swap was opened; a fresh key otherwise
+    pub our_key: Option<PublicKey>,
+}
+
 impl SwapCoordinator {
-    pub fn new() -> Self {
-        Self {
-            active_swaps: Arc::new(RwLock::new(HashMap::new())),
-        }
+    /// Coordinator for swaps paid from and to `wallet`'s users. Unfinished swaps are
+    /// reloaded from the wallet's storage.
+    pub fn new(wallet: Arc<BitcoinWalletManager>) -> Result<Self> {
+        let swaps = match &wallet.storage {
+            Some(storage) => storage
+                .load_swaps()?
+                .into_iter()
+                .map(|swap| (swap.id.clone(), swap))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            wallet,
+            active_swaps: Arc::new(RwLock::new(swaps)),
+            min_confirmations: DEFAULT_MIN_CONFIRMATIONS,
+        })
     }
 
-    /// Initiate a BTC -> ICP swap
+    /// Confirmations the funding output needs before a swap is locked
+    pub fn with_min_confirmations(mut self, min_confirmations: u32) -> Self {
+        self.min_confirmations = min_confirmations.max(1);
+        self
+    }
+
+    /// Initiate a BTC -> ICP swap: generate the secret and lock `btc_amount` for the
+    /// counterparty's key, refundable after `INITIATOR_TIMELOCK`. They redeem once we
+    /// reveal the secret claiming the ICP.
     pub async fn initiate_btc_to_icp_swap(
         &self,
+        user_id: &str,
         btc_amount: u64,
         icp_amount: u64,
-        _btc_address: &str,
-        _icp_principal: &str,
+        counterparty_key: PublicKey,
+        icp_principal: &str,
     ) -> Result<String> {
-        let swap_id = format!("swap_{}", uuid::Uuid::new_v4());
-
-        let swap = AtomicSwap {
-            id: swap_id.clone(),
-            from_chain: Chain::Bitcoin,
-            to_chain: Chain::ICP,
-            from_amount: btc_amount,
-            to_amount: icp_amount,
-            status: SwapStatus::Initiated,
+        let (secret, payment_hash) = htlc::new_secret();
+        let terms = SwapTerms {
+            other_chain: Chain::ICP,
+            btc_amount,
+            other_amount: icp_amount,
+            counterparty_key,
+            counterparty_address: icp_principal.to_string(),
+            payment_hash,
+            timelock: INITIATOR_TIMELOCK,
+            our_key: None,
         };
+        self.open(user_id, SwapRole::Sender, terms, Some(secret))
+            .await
+    }
+
+    /// Lock BTC in an HTLC for a swap the counterparty initiated. The secret is learned
+    /// from their redeem witness.
+    pub async fn lock_btc(&self, user_id: &str, terms: SwapTerms) -> Result<String> {
+        self.open(user_id, SwapRole::Sender, terms, None).await
+    }
+
+    /// Expect the counterparty to lock BTC for us. Give them the swap's `htlc.recipient`
+    /// key; the HTLC is redeemed once the secret is known, see `set_secret`.
+    pub async fn receive_btc(&self, user_id: &str, terms: SwapTerms) -> Result<String> {
+        self.open(user_id, SwapRole::Receiver, terms, None).await
+    }
 
+    /// Hand out a fresh key to put in a swap's `SwapTerms::our_key`
+    pub async fn swap_key(&self, user_id: &str) -> Result<PublicKey> {
+        let (key, _) = self
+            .wallet
+            .get_public_key(user_id, Keychain::change(AddressType::NativeSegwit))
+            .await?;
+        Ok(key)
+    }
+
+    /// Record the preimage, e.g. once revealed on the other chain
+    pub async fn set_secret(&self, swap_id: &str, secret: [u8; 32]) -> Result<()> {
         let mut swaps = self.active_swaps.write().await;
-        swaps.insert(swap_id.clone(), swap);
+        let swap = swaps
+            .get_mut(swap_id)
+            .ok_or_else(|| anyhow::anyhow!("Swap {swap_id} not found"))?;
+        if sha256::Hash::hash(&secret) != swap.htlc.payment_hash {
+            return Err(anyhow::anyhow!("Secret doesn't match the payment hash"));
+        }
+        swap.secret = Some(secret);
+        self.save(swap)
+    }
 
-        // In production, this would:
-        // 1. Create HTLC on Bitcoin
-        // 2. Wait for ICP deposit
-        // 3. Release BTC when ICP confirmed
+    pub async fn get_swap(&self, swap_id: &str) -> Option<AtomicSwap> {
+        self.active_swaps.read().await.get(swap_id).cloned()
+    }
 
-        Ok(swap_id)
+    pub async fn swaps(&self) -> Vec<AtomicSwap> {
+        self.active_swaps.read().await.values().cloned().collect()
+    }
+
+    /// Move a swap on as far as the chain allows: confirm its funding, redeem once the
+    /// secret is known, refund once the timelock passes, and pick up the counterparty's
+    /// redeem or refund along with any secret it reveals.
+    pub async fn advance(&self, swap_id: &str) -> Result<SwapStatus> {
+        let mut swap = self
+            .get_swap(swap_id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Swap {swap_id} not found"))?;
+        let tip = self.wallet.block_height().await?;
+
+        if swap.status == SwapStatus::Initiated {
+            self.check_funding(&mut swap, tip).await?;
+        }
+        if swap.status == SwapStatus::Locked {
+            self.check_spend(&mut swap, tip).await?;
+        }
+
+        let status = swap.status;
+        self.save(&swap)?;
+        self.active_swaps
+            .write()
+            .await
+            .insert(swap.id.clone(), swap);
+        Ok(status)
+    }
+
+    /// Advance every unfinished swap, logging failures
+    pub async fn advance_all(&self) {
+        let pending: Vec<String> = self
+            .active_swaps
+            .read()
+            .await
+            .values()
+            .filter(|s| !s.status.is_final())
+            .map(|s| s.id.clone())
+            .collect();
+        for id in pending {
+            if let Err(e) = self.advance(&id).await {
+                log::warn!("Swap {id} could not advance: {e}");
+            }
+        }
+    }
+
+    /// Advance every unfinished swap every `interval` in the background
+    pub fn spawn_monitor(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                self.advance_all().await;
+            }
+        })
     }
 
     /// Create a multi-chain swap route
@@ -95,6 +301,239 @@ impl SwapCoordinator {
             _ => Ok(SwapRoute::MultiHop(vec![from, Chain::Bitcoin, to])),
         }
     }
+
+    /// Record a new swap and, when we are the sender, fund its HTLC
+    async fn open(
+        &self,
+        user_id: &str,
+        role: SwapRole,
+        terms: SwapTerms,
+        secret: Option<[u8; 32]>,
+    ) -> Result<String> {
+        let tip = self.wallet.block_height().await?;
+        if let HtlcTimelock::Absolute(height) = terms.timelock {
+            if tip >= height {
+                return Err(anyhow::anyhow!("Timelock height {height} has passed"));
+            }
+        }
+        let (our_key, key_path) = match terms.our_key {
+            Some(key) => {
+                let hash = key
+                    .wpubkey_hash()
+                    .ok_or_else(|| anyhow::anyhow!("Swap keys must be compressed"))?;
+                let script = ScriptBuf::new_p2wpkh(&hash);
+                let path = self
+                    .wallet
+                    .wallets
+                    .read()
+                    .await
+                    .get(user_id)
+                    .and_then(|wallet| wallet.derivation_path(&script))
+                    .ok_or_else(|| anyhow::anyhow!("{key} isn't a key of {user_id}"))?;
+                (key, path)
+            }
+            None => {
+                self.wallet
+                    .get_public_key(user_id, Keychain::change(AddressType::NativeSegwit))
+                    .await?
+            }
+        };
+        let (recipient, refund) = match role {
+            SwapRole::Sender => (terms.counterparty_key, our_key),
+            SwapRole::Receiver => (our_key, terms.counterparty_key),
+        };
+        let (from_chain, to_chain, from_amount, to_amount) = match role {
+            SwapRole::Sender => (
+                Chain::Bitcoin,
+                terms.other_chain,
+                terms.btc_amount,
+                terms.other_amount,
+            ),
+            SwapRole::Receiver => (
+                terms.other_chain,
+                Chain::Bitcoin,
+                terms.other_amount,
+                terms.btc_amount,
+            ),
+        };
+
+        let mut swap = AtomicSwap {
+            id: format!("swap_{}", uuid::Uuid::new_v4()),
+            user_id: user_id.to_string(),
+            from_chain,
+            to_chain,
+            from_amount,
+            to_amount,
+            status: SwapStatus::Initiated,
+            role,
+            htlc: Htlc {
+                payment_hash: terms.payment_hash,
+                recipient,
+                refund,
+                timelock: terms.timelock,
+            },
+            key_path,
+            counterparty_address: terms.counterparty_address,
+            secret,
+            funding: None,
+            funding_value: None,
+            funding_height: None,
+            funding_deadline: (role == SwapRole::Receiver).then_some(tip + FUNDING_TIMEOUT_BLOCKS),
+            spend_txid: None,
+        };
+        // Saved before funding so the secret and keys survive a crash while broadcasting
+        self.save(&swap)?;
+
+        if role == SwapRole::Sender {
+            match self.fund(user_id, &swap.htlc, terms.btc_amount).await {
+                Ok(funding) => {
+                    swap.funding = Some(funding);
+                    swap.funding_value = Some(terms.btc_amount);
+                    self.save(&swap)?;
+                }
+                Err(e) => {
+                    // Nothing was sent, so there is nothing to advance
+                    swap.status = SwapStatus::Failed;
+                    self.save(&swap)?;
+                    return Err(e);
+                }
+            }
+        }
+
+        let id = swap.id.clone();
+        self.active_swaps.write().await.insert(id.clone(), swap);
+        Ok(id)
+    }
+
+    /// Pay `amount` to the HTLC from the user's wallet and return the HTLC output
+    async fn fund(&self, user_id: &str, htlc: &Htlc, amount: u64) -> Result<OutPoint> {
+        let address = htlc.address(self.wallet.network);
+        let mut psbt = self
+            .wallet
+            .create_psbt_with_priority(
+                user_id,
+                &[(address.clone(), amount)],
+                FeePriority::Normal,
+                Default::default(),
+            )
+            .await?;
+        let signed = self.wallet.sign_psbt(user_id, &mut psbt).await?;
+        if signed < psbt.inputs.len() {
+            return Err(anyhow::anyhow!(
+                "Signed {signed} of {} inputs",
+                psbt.inputs.len()
+            ));
+        }
+        let vout = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == address.script_pubkey())
+            .ok_or_else(|| anyhow::anyhow!("The funding transaction doesn't pay the HTLC"))?;
+        let txid = self.wallet.broadcast_psbt(user_id, psbt).await?;
+        Ok(OutPoint::new(txid, vout as u32))
+    }
+
+    /// Lock the swap once its HTLC is funded with enough confirmations, or fail it when the
+    /// counterparty missed the funding deadline
+    async fn check_funding(&self, swap: &mut AtomicSwap, tip: u32) -> Result<()> {
+        let address = swap.htlc.address(self.wallet.network);
+        let funded = self
+            .wallet
+            .address_utxos(&address)
+            .await?
+            .into_iter()
+            .filter(|u| u.txout.value >= Amount::from_sat(swap.btc_amount()))
+            // Our own funding transaction is known even if it was replaced
+            .max_by_key(|u| (Some(u.outpoint) == swap.funding, u.confirmations));
+
+        match funded {
+            Some(utxo) => {
+                swap.funding = Some(utxo.outpoint);
+                swap.funding_value = Some(utxo.txout.value.to_sat());
+                if utxo.confirmations >= self.min_confirmations {
+                    swap.funding_height = Some(tip + 1 - utxo.confirmations);
+                    swap.status = SwapStatus::Locked;
+                }
+            }
+            None if swap
+                .funding_deadline
+                .is_some_and(|deadline| tip >= deadline) =>
+            {
+                swap.status = SwapStatus::Failed;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Settle a locked swap: find the transaction spending the HTLC, or spend it ourselves
+    async fn check_spend(&self, swap: &mut AtomicSwap, tip: u32) -> Result<()> {
+        let (Some(funding), Some(funding_height)) = (swap.funding, swap.funding_height) else {
+            return Err(anyhow::anyhow!("Locked swap {} has no funding", swap.id));
+        };
+
+        if let Some(tx) = self
+            .wallet
+            .find_spending_tx(funding, &swap.htlc.script_pubkey(), funding_height)
+            .await?
+        {
+            swap.spend_txid = Some(tx.txid());
+            match swap.htlc.extract_secret(&tx, funding) {
+                Some(secret) => {
+                    swap.secret = Some(secret);
+                    swap.status = SwapStatus::Redeemed;
+                }
+                None => swap.status = SwapStatus::Refunded,
+            }
+            return Ok(());
+        }
+
+        let spend = match (swap.role, swap.secret) {
+            (SwapRole::Receiver, Some(secret)) => HtlcSpend::Redeem { secret },
+            (SwapRole::Sender, _) if swap.htlc.timelock.is_expired(funding_height, tip) => {
+                HtlcSpend::Refund
+            }
+            _ => return Ok(()),
+        };
+        let txid = self.spend(swap, funding, &spend).await?;
+        swap.spend_txid = Some(txid);
+        swap.status = match spend {
+            HtlcSpend::Redeem { .. } => SwapStatus::Redeemed,
+            HtlcSpend::Refund => SwapStatus::Refunded,
+        };
+        Ok(())
+    }
+
+    /// Redeem or refund the HTLC to a fresh change address of the swap's wallet
+    async fn spend(&self, swap: &AtomicSwap, funding: OutPoint, spend: &HtlcSpend) -> Result<Txid> {
+        // The sighash commits to the output's actual value. Swaps saved before it was
+        // recorded were funded with the agreed amount.
+        let value = Amount::from_sat(swap.funding_value.unwrap_or(swap.btc_amount()));
+        let destination = self.wallet.change_script(&swap.user_id).await?;
+        let fee_rate = self.wallet.estimate_fee_rate(FeePriority::Normal).await?;
+        let vault = self.wallet.vault();
+        vault.provision(&swap.user_id, [&swap.key_path])?;
+        let signer = vault.signer(&swap.user_id)?;
+
+        let tx = swap.htlc.spend(
+            funding,
+            value,
+            destination,
+            spend,
+            fee_rate,
+            signer.as_ref(),
+            &swap.key_path,
+        )?;
+        self.wallet.broadcast_transaction(&tx).await
+    }
+
+    fn save(&self, swap: &AtomicSwap) -> Result<()> {
+        match &self.wallet.storage {
+            Some(storage) => storage.save_swap(swap),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -112,11 +551,25 @@ pub struct WalletDSwapInterface {
 }
 
 impl WalletDSwapInterface {
+    pub fn new(
+        btc_manager: Arc<crate::BitcoinWalletManager>,
+        swap_coordinator: Arc<SwapCoordinator>,
+    ) -> Self {
+        Self {
+            btc_manager,
+            swap_coordinator,
+        }
+    }
+
+    /// Lock BTC for a swap to another chain, holding the secret ourselves
     pub async fn swap_btc_to_any(
         &self,
         user_id: &str,
         to_chain: &str,
         btc_amount: u64,
+        to_amount: u64,
+        counterparty_key: PublicKey,
+        counterparty_address: &str,
     ) -> Result<String> {
         // Get user's BTC balance
         let balance = self.btc_manager.get_balance(user_id).await?;
@@ -125,18 +578,245 @@ impl WalletDSwapInterface {
         }
 
         // Determine swap route
-        let _target_chain = match to_chain {
+        let target_chain = match to_chain {
             "ICP" => Chain::ICP,
             "ETH" => Chain::Ethereum,
             "SOL" => Chain::Solana,
             _ => return Err(anyhow::anyhow!("Unsupported chain")),
         };
 
-        let swap_id = self
-            .swap_coordinator
-            .initiate_btc_to_icp_swap(btc_amount, 0, "", "")
-            .await?;
+        let (secret, payment_hash) = htlc::new_secret();
+        let terms = SwapTerms {
+            other_chain: target_chain,
+            btc_amount,
+            other_amount: to_amount,
+            counterparty_key,
+            counterparty_address: counterparty_address.to_string(),
+            payment_hash,
+            timelock: INITIATOR_TIMELOCK,
+            our_key: None,
+        };
+        self.swap_coordinator
+            .open(user_id, SwapRole::Sender, terms, Some(secret))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BitcoinConfig, RpcEndpoint};
+    use bitcoin::{Address, Network};
+    use bitcoincore_rpc::{Auth, Client, RpcApi};
+    use std::str::FromStr;
+
+    /// Run against a regtest node with
+    /// `BITCOIND_RPC_URL=http://127.0.0.1:18443 BITCOIND_RPC_USER=.. BITCOIND_RPC_PASS=..`.
+    /// Alice swaps BTC with Bob three times: Bob redeems with the secret Alice reveals, Alice
+    /// refunds an HTLC Bob never redeems, and Alice learns Bob's secret from his redeem.
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_swaps_redeem_and_refund() {
+        let node = RpcEndpoint {
+            url: std::env::var("BITCOIND_RPC_URL").unwrap(),
+            user: std::env::var("BITCOIND_RPC_USER").unwrap(),
+            pass: std::env::var("BITCOIND_RPC_PASS").unwrap(),
+        };
+        let client = Client::new(
+            &node.url,
+            Auth::UserPass(node.user.clone(), node.pass.clone()),
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("walletd-swaps-{}", uuid::Uuid::new_v4()));
+        let config = BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![node],
+            storage: Some(crate::storage::StorageConfig {
+                path: dir.clone(),
+                passphrase: "hunter2".to_string(),
+            }),
+            electrum_url: None,
+            security: Default::default(),
+        };
+        let wallet = Arc::new(BitcoinWalletManager::new(config.clone()).await.unwrap());
+        let coordinator = SwapCoordinator::new(wallet.clone()).unwrap();
+        let alice = format!("swap-alice-{}", std::process::id());
+        let bob = format!("swap-bob-{}", std::process::id());
+        wallet.create_wallet(&alice, None).await.unwrap();
+        wallet.create_wallet(&bob, None).await.unwrap();
+
+        let mine = |blocks: u64| {
+            let burn = Address::from_str("bcrt1qs758ursh4q9z627kt3pp5yysm78ddny6txaqgw")
+                .unwrap()
+                .assume_checked();
+            client.generate_to_address(blocks, &burn).unwrap();
+        };
+        let funding = wallet
+            .get_receive_address(&alice, AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let funding = Address::from_str(&funding).unwrap().assume_checked();
+        client.generate_to_address(1, &funding).unwrap();
+        mine(100);
+        wallet.sync_utxos(&alice).await.unwrap();
+
+        // Bob hands Alice a key, and she locks BTC for it under her secret
+        let bob_key = coordinator.swap_key(&bob).await.unwrap();
+        let sent = coordinator
+            .initiate_btc_to_icp_swap(&alice, 1_000_000, 5_000_000, bob_key, "bob-principal")
+            .await
+            .unwrap();
+        let alice_swap = coordinator.get_swap(&sent).await.unwrap();
+        let received = coordinator
+            .receive_btc(
+                &bob,
+                SwapTerms {
+                    other_chain: Chain::ICP,
+                    btc_amount: 1_000_000,
+                    other_amount: 5_000_000,
+                    counterparty_key: alice_swap.htlc.refund,
+                    counterparty_address: "alice-principal".to_string(),
+                    payment_hash: alice_swap.htlc.payment_hash,
+                    timelock: alice_swap.htlc.timelock,
+                    our_key: Some(bob_key),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            coordinator.get_swap(&received).await.unwrap().htlc,
+            alice_swap.htlc
+        );
+
+        assert_eq!(
+            coordinator.advance(&sent).await.unwrap(),
+            SwapStatus::Initiated
+        );
+        mine(1);
+        assert_eq!(
+            coordinator.advance(&sent).await.unwrap(),
+            SwapStatus::Locked
+        );
+        assert_eq!(
+            coordinator.advance(&received).await.unwrap(),
+            SwapStatus::Locked
+        );
+        // Bob can't redeem until Alice reveals the secret on the other chain
+        assert_eq!(
+            coordinator.advance(&received).await.unwrap(),
+            SwapStatus::Locked
+        );
+        coordinator
+            .set_secret(&received, alice_swap.secret.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            coordinator.advance(&received).await.unwrap(),
+            SwapStatus::Redeemed
+        );
+        mine(1);
+        assert_eq!(
+            coordinator.advance(&sent).await.unwrap(),
+            SwapStatus::Redeemed
+        );
+
+        wallet.sync_utxos(&alice).await.unwrap();
+        // Bob never redeems this one, so Alice takes it back after the timelock
+        let (bob_key, _) = wallet
+            .get_public_key(&bob, Keychain::change(AddressType::NativeSegwit))
+            .await
+            .unwrap();
+        let (_, payment_hash) = htlc::new_secret();
+        let refunded = coordinator
+            .lock_btc(
+                &alice,
+                SwapTerms {
+                    other_chain: Chain::ICP,
+                    btc_amount: 500_000,
+                    other_amount: 1,
+                    counterparty_key: bob_key,
+                    counterparty_address: "bob-principal".to_string(),
+                    payment_hash,
+                    timelock: HtlcTimelock::Relative(5),
+                    our_key: None,
+                },
+            )
+            .await
+            .unwrap();
+        mine(1);
+        assert_eq!(
+            coordinator.advance(&refunded).await.unwrap(),
+            SwapStatus::Locked
+        );
+        mine(3);
+        assert_eq!(
+            coordinator.advance(&refunded).await.unwrap(),
+            SwapStatus::Locked
+        );
+        mine(1);
+        assert_eq!(
+            coordinator.advance(&refunded).await.unwrap(),
+            SwapStatus::Refunded
+        );
+
+        // Alice locks BTC under Bob's secret and learns it when he redeems, even after a
+        // restart
+        wallet.sync_utxos(&alice).await.unwrap();
+        let (bob_secret, payment_hash) = htlc::new_secret();
+        let (bob_key, bob_path) = wallet
+            .get_public_key(&bob, Keychain::change(AddressType::NativeSegwit))
+            .await
+            .unwrap();
+        let learned = coordinator
+            .lock_btc(
+                &alice,
+                SwapTerms {
+                    other_chain: Chain::Ethereum,
+                    btc_amount: 200_000,
+                    other_amount: 1,
+                    counterparty_key: bob_key,
+                    counterparty_address: "0xb0b".to_string(),
+                    payment_hash,
+                    timelock: PARTICIPANT_TIMELOCK,
+                    our_key: None,
+                },
+            )
+            .await
+            .unwrap();
+        mine(1);
+        assert_eq!(
+            coordinator.advance(&learned).await.unwrap(),
+            SwapStatus::Locked
+        );
+        let swap = coordinator.get_swap(&learned).await.unwrap();
+        let redeem = swap
+            .htlc
+            .spend(
+                swap.funding.unwrap(),
+                Amount::from_sat(200_000),
+                wallet.change_script(&bob).await.unwrap(),
+                &HtlcSpend::Redeem { secret: bob_secret },
+                bitcoin::FeeRate::from_sat_per_vb(2).unwrap(),
+                wallet.vault().signer(&bob).unwrap().as_ref(),
+                &bob_path,
+            )
+            .unwrap();
+        wallet.broadcast_transaction(&redeem).await.unwrap();
+        drop(coordinator);
+        drop(wallet);
 
-        Ok(swap_id)
+        let wallet = Arc::new(BitcoinWalletManager::new(config).await.unwrap());
+        let coordinator = SwapCoordinator::new(wallet).unwrap();
+        assert_eq!(
+            coordinator.advance(&learned).await.unwrap(),
+            SwapStatus::Redeemed
+        );
+        let swap = coordinator.get_swap(&learned).await.unwrap();
+        assert_eq!(swap.secret, Some(bob_secret));
+        assert_eq!(swap.spend_txid, Some(redeem.txid()));
+        assert_eq!(
+            coordinator.get_swap(&sent).await.unwrap().status,
+            SwapStatus::Redeemed
+        );
     }
 }
@@ -0,0 +1,574 @@
+// Fee bumping: BIP125 replace-by-fee and child-pays-for-parent
+use super::{psbt_handler, TransactionBuilder};
+use crate::utxo_manager::coin_selection::{
+    fee_for_weight, input_weight, output_weight, DEFAULT_DUST_LIMIT, TX_OVERHEAD_WEIGHT,
+};
+use crate::utxo_manager::TrackedUtxo;
+use anyhow::Result;
+use bitcoin::psbt::Psbt;
+use bitcoin::{
+    Amount, FeeRate, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxOut, Txid, Weight,
+    Witness,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Default `incrementalrelayfee` of Bitcoin Core
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxStatus {
+    /// Broadcast, not yet confirmed
+    Pending,
+    Confirmed,
+    /// A conflicting transaction in its replacement chain confirmed or replaced it
+    Replaced,
+}
+
+/// A transaction we broadcast, with enough context to bump its fee
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentTransaction {
+    pub tx: Transaction,
+    /// Outputs spent by `tx`, in input order
+    pub prevouts: Vec<TxOut>,
+    pub fee: u64,
+    /// Index of our change output, if any
+    pub change_index: Option<usize>,
+    /// Transaction this one replaced by fee
+    pub replaces: Option<Txid>,
+    /// Transaction that replaced this one by fee
+    pub replaced_by: Option<Txid>,
+    pub status: TxStatus,
+}
+
+impl SentTransaction {
+    /// Track a signed transaction; its fee is computed from the spent outputs
+    pub fn new(tx: Transaction, prevouts: Vec<TxOut>, change_index: Option<usize>) -> Result<Self> {
+        if tx.input.len() != prevouts.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} spent outputs, got {}",
+                tx.input.len(),
+                prevouts.len()
+            ));
+        }
+        let input_value: u64 = prevouts.iter().map(|o| o.value.to_sat()).sum();
+        let output_value: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or_else(|| anyhow::anyhow!("Transaction spends more than its inputs"))?;
+
+        Ok(Self {
+            tx,
+            prevouts,
+            fee,
+            change_index,
+            replaces: None,
+            replaced_by: None,
+            status: TxStatus::Pending,
+        })
+    }
+
+    pub fn txid(&self) -> Txid {
+        self.tx.txid()
+    }
+
+    /// Fee rate the transaction pays, rounded down
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::from_sat_per_kwu(self.fee * 1000 / self.tx.weight().to_wu().max(1))
+    }
+
+    /// Whether any input opts in to replacement (BIP125 rule 1)
+    pub fn signals_rbf(&self) -> bool {
+        self.tx.input.iter().any(|i| i.sequence.is_rbf())
+    }
+
+    fn change(&self) -> Option<&TxOut> {
+        self.change_index.and_then(|i| self.tx.output.get(i))
+    }
+}
+
+/// An unsigned fee-bumping transaction, ready to be turned into a PSBT and signed
+#[derive(Debug, Clone)]
+pub struct FeeBump {
+    pub tx: Transaction,
+    /// Outputs being spent, in input order
+    pub utxos: Vec<TrackedUtxo>,
+    pub fee: u64,
+    /// Index of our change output, if any
+    pub change_index: Option<usize>,
+}
+
+impl FeeBump {
+    pub fn build_psbt(&self) -> Result<Psbt> {
+        let mut psbt = psbt_handler::create_psbt(self.tx.clone(), &self.utxos)?;
+        if let Some(index) = self.change_index {
+            psbt_handler::mark_change(&mut psbt, index)?;
+        }
+        Ok(psbt)
+    }
+}
+
+/// Build a BIP125 replacement of `original` paying `fee_rate`. The fee comes out of the change
+/// output first; change is dropped once it would be dust, and only then are confirmed
+/// `extra_utxos` added, largest first. `change_script` receives change when the original had none.
+///
+/// `descendant_fees` is what the original's in-mempool descendants pay, which the replacement
+/// evicts along with it, e.g. `getmempoolentry`'s `descendantfees` less the original's own fee.
+pub fn bump_fee_rbf(
+    original: &SentTransaction,
+    fee_rate: FeeRate,
+    extra_utxos: &[TrackedUtxo],
+    change_script: &Script,
+    descendant_fees: u64,
+) -> Result<FeeBump> {
+    if original.status != TxStatus::Pending || original.replaced_by.is_some() {
+        return Err(anyhow::anyhow!("Only pending transactions can be replaced"));
+    }
+    if !original.signals_rbf() {
+        return Err(anyhow::anyhow!(
+            "Transaction does not signal replaceability (BIP125)"
+        ));
+    }
+    if fee_rate <= original.fee_rate() {
+        return Err(anyhow::anyhow!(
+            "New fee rate {} sat/vB must exceed the current {} sat/vB",
+            fee_rate.to_sat_per_vb_ceil(),
+            original.fee_rate().to_sat_per_vb_floor()
+        ));
+    }
+
+    let change_script = original
+        .change()
+        .map(|c| c.script_pubkey.clone())
+        .unwrap_or_else(|| change_script.to_owned());
+
+    // Replacements pay at least the new rate and, per BIP125 rules 3 and 4, the fees of every
+    // transaction they evict plus the incremental relay fee for their own size
+    let evicted_fees = original.fee + descendant_fees;
+    let required_fee = |weight: Weight| {
+        fee_for_weight(fee_rate, weight)
+            .max(evicted_fees + fee_for_weight(INCREMENTAL_RELAY_FEE, weight))
+    };
+
+    let payments: Vec<TxOut> = original
+        .tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != original.change_index)
+        .map(|(_, o)| o.clone())
+        .collect();
+    let payment_value: u64 = payments.iter().map(|o| o.value.to_sat()).sum();
+
+    // The original is signed, so its weight already includes its inputs' witnesses
+    let mut weight = original.tx.weight();
+    if let Some(change) = original.change() {
+        weight -= output_weight(&change.script_pubkey);
+    }
+
+    let mut utxos: Vec<TrackedUtxo> = original
+        .tx
+        .input
+        .iter()
+        .zip(&original.prevouts)
+        .map(|(input, prevout)| TrackedUtxo {
+            outpoint: input.previous_output,
+            txout: prevout.clone(),
+            confirmations: 0,
+        })
+        .collect();
+    let mut input_value: u64 = original.prevouts.iter().map(|o| o.value.to_sat()).sum();
+
+    // BIP125 rule 2: new inputs must be confirmed
+    let mut extra: Vec<&TrackedUtxo> = extra_utxos
+        .iter()
+        .filter(|u| u.confirmations > 0)
+        .filter(|u| !utxos.iter().any(|o| o.outpoint == u.outpoint))
+        .collect();
+    extra.sort_by_key(|u| std::cmp::Reverse(u.txout.value));
+    let mut extra = extra.into_iter();
+
+    let (fee, change) = loop {
+        let fee = required_fee(weight + output_weight(&change_script));
+        if input_value >= payment_value + fee + DEFAULT_DUST_LIMIT {
+            break (fee, Some(input_value - payment_value - fee));
+        }
+        if input_value >= payment_value + required_fee(weight) {
+            // Whatever is left over is too small for change and goes to the miner
+            break (input_value - payment_value, None);
+        }
+        let utxo = extra
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Insufficient funds to bump the fee"))?;
+        weight += input_weight(&utxo.txout.script_pubkey);
+        input_value += utxo.txout.value.to_sat();
+        utxos.push(utxo.clone());
+    };
+
+    let mut tx = original.tx.clone();
+    for input in &mut tx.input {
+        input.script_sig = ScriptBuf::new();
+        input.witness = Witness::default();
+    }
+    for utxo in &utxos[tx.input.len()..] {
+        tx.input.push(bitcoin::TxIn {
+            previous_output: utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        });
+    }
+
+    // Keep the change output where it was so the replacement looks like the original
+    let mut change_index = None;
+    tx.output = payments;
+    if let Some(value) = change {
+        let index = original
+            .change_index
+            .unwrap_or(tx.output.len())
+            .min(tx.output.len());
+        tx.output.insert(
+            index,
+            TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: change_script,
+            },
+        );
+        change_index = Some(index);
+    }
+
+    Ok(FeeBump {
+        tx,
+        utxos,
+        fee,
+        change_index,
+    })
+}
+
+/// Build a child spending output `vout` of `parent` to `destination` so that parent and child
+/// together pay `package_fee_rate`
+pub fn build_cpfp(
+    parent: &SentTransaction,
+    vout: u32,
+    package_fee_rate: FeeRate,
+    destination: &Script,
+) -> Result<FeeBump> {
+    if parent.status != TxStatus::Pending {
+        return Err(anyhow::anyhow!("Parent transaction is no longer pending"));
+    }
+    if package_fee_rate <= parent.fee_rate() {
+        return Err(anyhow::anyhow!(
+            "Parent already pays {} sat/vB",
+            parent.fee_rate().to_sat_per_vb_floor()
+        ));
+    }
+    let txout = parent
+        .tx
+        .output
+        .get(vout as usize)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Parent has no output {vout}"))?;
+
+    let child_weight =
+        TX_OVERHEAD_WEIGHT + input_weight(&txout.script_pubkey) + output_weight(destination);
+    let package_fee = fee_for_weight(package_fee_rate, parent.tx.weight() + child_weight);
+    let fee = package_fee
+        .saturating_sub(parent.fee)
+        .max(fee_for_weight(INCREMENTAL_RELAY_FEE, child_weight));
+
+    let value = txout
+        .value
+        .to_sat()
+        .checked_sub(fee)
+        .filter(|v| *v >= DEFAULT_DUST_LIMIT)
+        .ok_or_else(|| anyhow::anyhow!("Output is too small to pay a {fee} sat child fee"))?;
+
+    let utxo = TrackedUtxo {
+        outpoint: OutPoint::new(parent.txid(), vout),
+        txout,
+        confirmations: 0,
+    };
+    let tx = TransactionBuilder::new()
+        .add_input(utxo.clone())
+        .add_output(destination.to_owned(), value)
+        .build();
+
+    Ok(FeeBump {
+        tx,
+        utxos: vec![utxo],
+        fee,
+        change_index: Some(0),
+    })
+}
+
+/// Sent transactions and their fee-bump replacement chains
+#[derive(Debug, Clone, Default)]
+pub struct ReplacementTracker {
+    txs: HashMap<Txid, SentTransaction>,
+}
+
+impl ReplacementTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a newly broadcast transaction
+    pub fn record(&mut self, tx: SentTransaction) -> Txid {
+        let txid = tx.txid();
+        self.txs.insert(txid, tx);
+        txid
+    }
+
+    /// Track `replacement`, which replaced `original` by fee
+    pub fn record_replacement(&mut self, original: Txid, mut replacement: SentTransaction) -> Txid {
+        let txid = replacement.txid();
+        if let Some(tx) = self.txs.get_mut(&original) {
+            tx.replaced_by = Some(txid);
+            tx.status = TxStatus::Replaced;
+        }
+        replacement.replaces = Some(original);
+        self.txs.insert(txid, replacement);
+        txid
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&SentTransaction> {
+        self.txs.get(txid)
+    }
+
+    /// Every txid in the replacement chain of `txid`, oldest first
+    pub fn chain(&self, txid: &Txid) -> Vec<Txid> {
+        let mut root = *txid;
+        while let Some(previous) = self.txs.get(&root).and_then(|t| t.replaces) {
+            root = previous;
+        }
+        let mut chain = vec![root];
+        while let Some(next) = self
+            .txs
+            .get(chain.last().unwrap())
+            .and_then(|t| t.replaced_by)
+        {
+            chain.push(next);
+        }
+        chain
+    }
+
+    /// Most recent replacement of `txid`, or `txid` itself
+    pub fn latest(&self, txid: &Txid) -> Txid {
+        *self.chain(txid).last().unwrap()
+    }
+
+    /// Record that `txid` confirmed; every other transaction in its chain is now replaced
+    pub fn mark_confirmed(&mut self, txid: &Txid) -> Result<()> {
+        if !self.txs.contains_key(txid) {
+            return Err(anyhow::anyhow!("Unknown transaction {txid}"));
+        }
+        for id in self.chain(txid) {
+            if let Some(tx) = self.txs.get_mut(&id) {
+                tx.status = if id == *txid {
+                    TxStatus::Confirmed
+                } else {
+                    TxStatus::Replaced
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// The transaction in `txid`'s replacement chain that finally confirmed
+    pub fn confirmed_txid(&self, txid: &Txid) -> Option<Txid> {
+        self.chain(txid)
+            .into_iter()
+            .find(|id| self.txs[id].status == TxStatus::Confirmed)
+    }
+
+    /// Transactions still waiting to confirm
+    pub fn pending(&self) -> Vec<&SentTransaction> {
+        self.txs
+            .values()
+            .filter(|t| t.status == TxStatus::Pending)
+            .collect()
+    }
+
+    pub fn history(&self) -> Vec<&SentTransaction> {
+        self.txs.values().collect()
+    }
+
+    /// Fees of the pending transactions spending `txid`'s outputs, directly or through each
+    /// other, which replacing `txid` would evict
+    pub fn descendant_fees(&self, txid: &Txid) -> u64 {
+        let mut parents = vec![*txid];
+        let mut seen = HashSet::new();
+        let mut fees = 0;
+        while let Some(parent) = parents.pop() {
+            for tx in self.pending() {
+                let spends_parent = tx.tx.input.iter().any(|i| i.previous_output.txid == parent);
+                if spends_parent && seen.insert(tx.txid()) {
+                    fees += tx.fee;
+                    parents.push(tx.txid());
+                }
+            }
+        }
+        fees
+    }
+}
+
+/// Rebuild a tracker from stored transactions, keeping their replacement links
+impl FromIterator<SentTransaction> for ReplacementTracker {
+    fn from_iter<I: IntoIterator<Item = SentTransaction>>(iter: I) -> Self {
+        Self {
+            txs: iter.into_iter().map(|tx| (tx.txid(), tx)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utxo_manager::coin_selection::P2WPKH_INPUT_WEIGHT;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{TxIn, WPubkeyHash};
+
+    fn p2wpkh(byte: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([byte; 20]))
+    }
+
+    fn utxo(byte: u8, value: u64, confirmations: u32) -> TrackedUtxo {
+        TrackedUtxo {
+            outpoint: OutPoint::new(Txid::from_byte_array([byte; 32]), 0),
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: p2wpkh(byte),
+            },
+            confirmations,
+        }
+    }
+
+    /// A signed-looking 1-in 2-out payment of 50_000 sats with 1_000 sats of fee
+    fn sent(change: u64) -> SentTransaction {
+        let spent = utxo(1, 51_000 + change, 3);
+        let tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: spent.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[vec![0u8; 72], vec![2u8; 33]]),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(50_000),
+                    script_pubkey: p2wpkh(9),
+                },
+                TxOut {
+                    value: Amount::from_sat(change),
+                    script_pubkey: p2wpkh(8),
+                },
+            ],
+        };
+        SentTransaction::new(tx, vec![spent.txout], Some(1)).unwrap()
+    }
+
+    #[test]
+    fn rbf_shrinks_change_then_adds_inputs() {
+        let original = sent(20_000);
+        assert_eq!(original.fee, 1_000);
+        let rate = FeeRate::from_sat_per_vb_unchecked(20);
+
+        let bump = bump_fee_rbf(&original, rate, &[], &p2wpkh(7), 0).unwrap();
+        assert_eq!(bump.tx.input.len(), 1);
+        assert_eq!(bump.tx.output[0].value, Amount::from_sat(50_000));
+        assert_eq!(bump.change_index, Some(1));
+        assert!(bump.fee > original.fee);
+        assert_eq!(
+            bump.tx.output[1].value.to_sat(),
+            20_000 - (bump.fee - 1_000)
+        );
+        assert!(bump.tx.input[0].witness.is_empty());
+
+        // Not enough change: confirmed inputs are added, unconfirmed ones never are
+        let original = sent(600);
+        let extra = [utxo(2, 100_000, 0), utxo(3, 30_000, 1)];
+        let bump = bump_fee_rbf(&original, rate, &extra, &p2wpkh(7), 0).unwrap();
+        assert_eq!(bump.utxos.len(), 2);
+        assert_eq!(bump.utxos[1].outpoint, extra[1].outpoint);
+        let total_in: u64 = bump.utxos.iter().map(|u| u.txout.value.to_sat()).sum();
+        let total_out: u64 = bump.tx.output.iter().map(|o| o.value.to_sat()).sum();
+        assert_eq!(total_in - total_out, bump.fee);
+
+        assert!(bump_fee_rbf(
+            &original,
+            FeeRate::from_sat_per_vb_unchecked(1),
+            &[],
+            &p2wpkh(7),
+            0
+        )
+        .is_err());
+        let mut final_tx = sent(20_000);
+        final_tx.tx.input[0].sequence = Sequence::MAX;
+        assert!(bump_fee_rbf(&final_tx, rate, &[], &p2wpkh(7), 0).is_err());
+    }
+
+    #[test]
+    fn rbf_pays_for_evicted_descendants() {
+        let original = sent(20_000);
+        let rate = FeeRate::from_sat_per_vb_unchecked(10);
+        let alone = bump_fee_rbf(&original, rate, &[], &p2wpkh(7), 0).unwrap();
+
+        // A child spending the change pays a high fee, which the replacement evicts too
+        let mut tracker = ReplacementTracker::new();
+        tracker.record(original.clone());
+        let child = build_cpfp(
+            &original,
+            1,
+            FeeRate::from_sat_per_vb_unchecked(50),
+            &p2wpkh(7),
+        )
+        .unwrap();
+        let child = SentTransaction::new(
+            child.tx,
+            child.utxos.into_iter().map(|u| u.txout).collect(),
+            Some(0),
+        )
+        .unwrap();
+        tracker.record(child.clone());
+        assert_eq!(tracker.descendant_fees(&original.txid()), child.fee);
+
+        let bump = bump_fee_rbf(&original, rate, &[], &p2wpkh(7), child.fee).unwrap();
+        assert!(bump.fee >= original.fee + child.fee);
+        assert!(bump.fee > alone.fee);
+    }
+
+    #[test]
+    fn cpfp_pays_for_the_package() {
+        let parent = sent(20_000);
+        let rate = FeeRate::from_sat_per_vb_unchecked(10);
+        let child = build_cpfp(&parent, 1, rate, &p2wpkh(7)).unwrap();
+        assert_eq!(
+            child.tx.input[0].previous_output,
+            OutPoint::new(parent.txid(), 1)
+        );
+        assert_eq!(child.tx.output[0].value.to_sat(), 20_000 - child.fee);
+
+        let child_weight = TX_OVERHEAD_WEIGHT + P2WPKH_INPUT_WEIGHT + output_weight(&p2wpkh(7));
+        let package_weight = parent.tx.weight() + child_weight;
+        assert!(parent.fee + child.fee >= fee_for_weight(rate, package_weight));
+    }
+
+    #[test]
+    fn tracks_replacement_chain() {
+        let mut tracker = ReplacementTracker::new();
+        let first = tracker.record(sent(20_000));
+        let second = tracker.record_replacement(first, sent(19_000));
+        let third = tracker.record_replacement(second, sent(18_000));
+
+        assert_eq!(tracker.chain(&second), vec![first, second, third]);
+        assert_eq!(tracker.latest(&first), third);
+        assert_eq!(tracker.pending().len(), 1);
+
+        tracker.mark_confirmed(&second).unwrap();
+        assert_eq!(tracker.confirmed_txid(&first), Some(second));
+        assert_eq!(tracker.get(&third).unwrap().status, TxStatus::Replaced);
+        assert!(tracker.pending().is_empty());
+    }
+}
@@ -1,11 +1,19 @@
-// Simplified transaction builder
+// Transaction builder
+pub mod fee_bump;
 pub mod psbt_handler;
 pub mod script_builder;
 
-use bitcoin::Transaction;
+use crate::utxo_manager::{SelectionResult, TrackedUtxo};
+use anyhow::Result;
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
 
 pub struct TransactionBuilder {
     tx: Transaction,
+    /// Outputs being spent, in input order
+    utxos: Vec<TrackedUtxo>,
+    /// Index of the change output added by `add_selection`
+    change_index: Option<usize>,
 }
 
 impl Default for TransactionBuilder {
@@ -23,10 +31,66 @@ impl TransactionBuilder {
                 input: vec![],
                 output: vec![],
             },
+            utxos: vec![],
+            change_index: None,
         }
     }
 
+    /// Spend a UTXO; inputs signal RBF by default
+    pub fn add_input(mut self, utxo: TrackedUtxo) -> Self {
+        self.tx.input.push(TxIn {
+            previous_output: utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        });
+        self.utxos.push(utxo);
+        self
+    }
+
+    /// Spend every UTXO picked by coin selection and add its change output
+    pub fn add_selection(mut self, selection: &SelectionResult, change_script: ScriptBuf) -> Self {
+        for utxo in &selection.selected {
+            self = self.add_input(utxo.clone());
+        }
+        if let Some(change) = selection.change {
+            self.change_index = Some(self.tx.output.len());
+            self = self.add_output(change_script, change);
+        }
+        self
+    }
+
+    pub fn add_recipient(self, address: &Address, amount: u64) -> Self {
+        self.add_output(address.script_pubkey(), amount)
+    }
+
+    pub fn add_output(mut self, script_pubkey: ScriptBuf, amount: u64) -> Self {
+        self.tx.output.push(TxOut {
+            value: Amount::from_sat(amount),
+            script_pubkey,
+        });
+        self
+    }
+
+    /// Set the sequence of every input, e.g. `Sequence::MAX` to opt out of RBF
+    pub fn sequence(mut self, sequence: Sequence) -> Self {
+        for input in &mut self.tx.input {
+            input.sequence = sequence;
+        }
+        self
+    }
+
     pub fn build(self) -> Transaction {
         self.tx
     }
+
+    /// Build an unsigned PSBT carrying the spent outputs for each input, with the change
+    /// output marked
+    pub fn build_psbt(self) -> Result<Psbt> {
+        let mut psbt = psbt_handler::create_psbt(self.tx, &self.utxos)?;
+        if let Some(index) = self.change_index {
+            psbt_handler::mark_change(&mut psbt, index)?;
+        }
+        Ok(psbt)
+    }
 }
@@ -1 +1,892 @@
-// PSBT handler module
+// PSBT handler module (BIP174)
+use super::script_builder::{Descriptor, Satisfier};
+use crate::security::{Signer, TaprootSpend};
+use crate::utxo_manager::TrackedUtxo;
+use crate::UserBitcoinWallet;
+use anyhow::Result;
+use bitcoin::bip32::KeySource;
+use bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG;
+use bitcoin::blockdata::script::{Builder, Instruction, PushBytesBuf};
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::raw::ProprietaryKey;
+use bitcoin::psbt::{Input, Psbt};
+use bitcoin::relative;
+use bitcoin::secp256k1::{All, Message, Secp256k1, XOnlyPublicKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, ControlBlock, LeafVersion, TapLeafHash, TapNodeHash};
+use bitcoin::{ecdsa, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+/// Create an unsigned PSBT, attaching the spent output to every input
+pub fn create_psbt(tx: Transaction, utxos: &[TrackedUtxo]) -> Result<Psbt> {
+    if tx.input.len() != utxos.len() {
+        return Err(anyhow::anyhow!(
+            "Expected {} spent outputs, got {}",
+            tx.input.len(),
+            utxos.len()
+        ));
+    }
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+        input.witness_utxo = Some(utxo.txout.clone());
+    }
+    Ok(psbt)
+}
+
+/// Proprietary output key marking the change output of a PSBT this wallet built
+fn change_key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: b"walletd".to_vec(),
+        subtype: 0,
+        key: vec![],
+    }
+}
+
+/// Mark output `index` as the change the builder added. The mark is a proprietary PSBT field,
+/// so it survives a round trip through external signers.
+pub fn mark_change(psbt: &mut Psbt, index: usize) -> Result<()> {
+    let output = psbt
+        .outputs
+        .get_mut(index)
+        .ok_or_else(|| anyhow::anyhow!("PSBT has no output {index}"))?;
+    output.proprietary.insert(change_key(), vec![]);
+    Ok(())
+}
+
+/// Index of the output marked as change by `mark_change`. Outputs that merely pay one of our
+/// addresses, as in a self-transfer, are not change.
+pub fn change_index(psbt: &Psbt) -> Option<usize> {
+    let key = change_key();
+    psbt.outputs
+        .iter()
+        .position(|o| o.proprietary.contains_key(&key))
+}
+
+/// Attach the wallet's BIP32 key origins (and P2SH-P2WPKH redeem scripts) to every input and
+/// output it owns. Returns the number of inputs and outputs updated.
+pub fn add_wallet_derivations(
+    psbt: &mut Psbt,
+    wallet: &UserBitcoinWallet,
+    secp: &Secp256k1<All>,
+) -> Result<usize> {
+    if let Some(descriptor) = wallet.descriptor() {
+        return add_descriptor_derivations(psbt, wallet, descriptor, secp);
+    }
+
+    let mut updated = 0;
+
+    for index in 0..psbt.inputs.len() {
+        let script = psbt
+            .spend_utxo(index)
+            .map_err(|e| anyhow::anyhow!("Input {index}: {e}"))?
+            .script_pubkey
+            .clone();
+        if script.is_p2tr() {
+            if let Some(fields) = taproot_fields(wallet, &script, secp)? {
+                let input = &mut psbt.inputs[index];
+                input.tap_internal_key = Some(fields.internal_key);
+                input.tap_merkle_root = fields.merkle_root;
+                input.tap_key_origins.extend(fields.key_origins);
+                input.tap_scripts.extend(fields.scripts);
+                updated += 1;
+            }
+            continue;
+        }
+        if let Some(path) = wallet.derivation_path(&script) {
+            let pubkey = wallet.derive_public_key(&path, secp)?;
+            let input = &mut psbt.inputs[index];
+            input
+                .bip32_derivation
+                .insert(pubkey.inner, wallet.key_source(&path));
+            if script.is_p2sh() {
+                let wpkh = pubkey
+                    .wpubkey_hash()
+                    .ok_or_else(|| anyhow::anyhow!("Uncompressed key in segwit script"))?;
+                input.redeem_script = Some(ScriptBuf::new_p2wpkh(&wpkh));
+            }
+            updated += 1;
+        }
+    }
+
+    for (txout, output) in psbt.unsigned_tx.output.iter().zip(psbt.outputs.iter_mut()) {
+        if txout.script_pubkey.is_p2tr() {
+            if let Some(fields) = taproot_fields(wallet, &txout.script_pubkey, secp)? {
+                output.tap_internal_key = Some(fields.internal_key);
+                output.tap_key_origins.extend(fields.key_origins);
+                updated += 1;
+            }
+            continue;
+        }
+        if let Some(path) = wallet.derivation_path(&txout.script_pubkey) {
+            let pubkey = wallet.derive_public_key(&path, secp)?;
+            output
+                .bip32_derivation
+                .insert(pubkey.inner, wallet.key_source(&path));
+            updated += 1;
+        }
+    }
+
+    Ok(updated)
+}
+
+/// BIP371 fields for a taproot output the wallet owns
+struct TaprootFields {
+    internal_key: XOnlyPublicKey,
+    merkle_root: Option<TapNodeHash>,
+    key_origins: BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)>,
+    scripts: BTreeMap<ControlBlock, (ScriptBuf, LeafVersion)>,
+}
+
+fn taproot_fields(
+    wallet: &UserBitcoinWallet,
+    script_pubkey: &Script,
+    secp: &Secp256k1<All>,
+) -> Result<Option<TaprootFields>> {
+    let Some(path) = wallet.derivation_path(script_pubkey) else {
+        return Ok(None);
+    };
+    let internal_key = XOnlyPublicKey::from(wallet.derive_public_key(&path, secp)?.inner);
+
+    let mut fields = TaprootFields {
+        internal_key,
+        merkle_root: None,
+        key_origins: BTreeMap::new(),
+        scripts: BTreeMap::new(),
+    };
+    // An empty leaf hash list marks the internal key
+    fields
+        .key_origins
+        .insert(internal_key, (vec![], wallet.key_source(&path)));
+
+    if let Some(spend_info) = wallet.taproot_tree(script_pubkey) {
+        fields.merkle_root = spend_info.merkle_root();
+        for script_ver in spend_info.script_map().keys() {
+            let control_block = spend_info
+                .control_block(script_ver)
+                .ok_or_else(|| anyhow::anyhow!("Missing control block for tapscript leaf"))?;
+            let leaf_hash = TapLeafHash::from_script(&script_ver.0, script_ver.1);
+            for key in tapscript_keys(&script_ver.0) {
+                if let Some(path) = wallet.taproot_key_path(&key) {
+                    fields
+                        .key_origins
+                        .entry(key)
+                        .or_insert_with(|| (vec![], wallet.key_source(&path)))
+                        .0
+                        .push(leaf_hash);
+                }
+            }
+            fields.scripts.insert(control_block, script_ver.clone());
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// X-only keys pushed by a tapscript leaf, in script order
+fn tapscript_keys(script: &Script) -> Vec<XOnlyPublicKey> {
+    script
+        .instructions()
+        .filter_map(|ins| match ins {
+            Ok(Instruction::PushBytes(bytes)) => XOnlyPublicKey::from_slice(bytes.as_bytes()).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn add_descriptor_derivations(
+    psbt: &mut Psbt,
+    wallet: &UserBitcoinWallet,
+    descriptor: &Descriptor,
+    secp: &Secp256k1<All>,
+) -> Result<usize> {
+    let mut updated = 0;
+    for index in 0..psbt.inputs.len() {
+        let script = &psbt
+            .spend_utxo(index)
+            .map_err(|e| anyhow::anyhow!("Input {index}: {e}"))?
+            .script_pubkey;
+        if let Some(child) = wallet.descriptor_index(script) {
+            descriptor.update_psbt_input(&mut psbt.inputs[index], child, secp)?;
+            updated += 1;
+        }
+    }
+    for (txout, output) in psbt.unsigned_tx.output.iter().zip(psbt.outputs.iter_mut()) {
+        if let Some(child) = wallet.descriptor_index(&txout.script_pubkey) {
+            descriptor.update_psbt_output(output, child, secp)?;
+            updated += 1;
+        }
+    }
+    Ok(updated)
+}
+
+/// Add every signature the signer can produce. Inputs it has no key for are left untouched,
+/// so the PSBT can be passed on to other signers. Returns the number of inputs signed.
+pub fn sign_psbt(psbt: &mut Psbt, signer: &dyn Signer) -> Result<usize> {
+    let mut signed = sign_ecdsa(psbt, signer)?;
+    signed.extend(sign_taproot(psbt, signer)?);
+    Ok(signed.len())
+}
+
+/// ECDSA signatures for every legacy and segwit v0 key in `bip32_derivation` with the
+/// signer's fingerprint
+fn sign_ecdsa(psbt: &mut Psbt, signer: &dyn Signer) -> Result<BTreeSet<usize>> {
+    let mut signed = BTreeSet::new();
+    let mut cache = SighashCache::new(psbt.unsigned_tx.clone());
+    for index in 0..psbt.inputs.len() {
+        let is_taproot = psbt
+            .spend_utxo(index)
+            .map(|utxo| utxo.script_pubkey.is_p2tr())
+            .unwrap_or(false);
+        let ours: Vec<_> = psbt.inputs[index]
+            .bip32_derivation
+            .iter()
+            .filter(|(_, (fingerprint, _))| *fingerprint == signer.fingerprint())
+            .map(|(pubkey, (_, path))| (*pubkey, path.clone()))
+            .collect();
+        if is_taproot || ours.is_empty() {
+            continue;
+        }
+
+        let (msg, hash_ty) = psbt
+            .sighash_ecdsa(index, &mut cache)
+            .map_err(|e| anyhow::anyhow!("Failed to sign input {index}: {e}"))?;
+        for (pubkey, path) in ours {
+            // Another key under the same fingerprint, e.g. from a different seed
+            if signer.public_key(&path)?.inner != pubkey {
+                continue;
+            }
+            let sig = signer.sign_ecdsa(&path, &msg)?;
+            psbt.inputs[index]
+                .partial_sigs
+                .insert(PublicKey::new(pubkey), ecdsa::Signature { sig, hash_ty });
+            signed.insert(index);
+        }
+    }
+    Ok(signed)
+}
+
+/// BIP341 signing: key-path signatures with the tweaked internal key and script-path
+/// signatures for every leaf listed in `tap_key_origins`
+fn sign_taproot(psbt: &mut Psbt, signer: &dyn Signer) -> Result<BTreeSet<usize>> {
+    let mut signed = BTreeSet::new();
+    let is_taproot = |psbt: &Psbt, index: usize| {
+        psbt.spend_utxo(index)
+            .map(|utxo| utxo.script_pubkey.is_p2tr())
+            .unwrap_or(false)
+    };
+    if !(0..psbt.inputs.len()).any(|i| is_taproot(psbt, i)) {
+        return Ok(signed);
+    }
+
+    // Taproot sighashes commit to every spent output
+    let spent: Vec<TxOut> = (0..psbt.inputs.len())
+        .map(|i| {
+            psbt.spend_utxo(i)
+                .cloned()
+                .map_err(|e| anyhow::anyhow!("Input {i}: {e}"))
+        })
+        .collect::<Result<_>>()?;
+    let prevouts = Prevouts::All(&spent);
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        if !spent[index].script_pubkey.is_p2tr() {
+            continue;
+        }
+        let hash_ty = match input.sighash_type {
+            Some(ty) => ty.taproot_hash_ty()?,
+            None => TapSighashType::Default,
+        };
+
+        for (xonly, (leaf_hashes, (fingerprint, path))) in input.tap_key_origins.clone() {
+            if fingerprint != signer.fingerprint()
+                || XOnlyPublicKey::from(signer.public_key(&path)?.inner) != xonly
+            {
+                continue;
+            }
+
+            if input.tap_internal_key == Some(xonly) {
+                let sighash = cache.taproot_key_spend_signature_hash(index, &prevouts, hash_ty)?;
+                let sig = signer.sign_schnorr(
+                    &path,
+                    &Message::from_digest(sighash.to_byte_array()),
+                    TaprootSpend::KeyPath {
+                        merkle_root: input.tap_merkle_root,
+                    },
+                )?;
+                input.tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+                signed.insert(index);
+            }
+            for leaf_hash in leaf_hashes {
+                let sighash = cache
+                    .taproot_script_spend_signature_hash(index, &prevouts, leaf_hash, hash_ty)?;
+                let sig = signer.sign_schnorr(
+                    &path,
+                    &Message::from_digest(sighash.to_byte_array()),
+                    TaprootSpend::ScriptPath,
+                )?;
+                input
+                    .tap_script_sigs
+                    .insert((xonly, leaf_hash), taproot::Signature { sig, hash_ty });
+                signed.insert(index);
+            }
+        }
+    }
+    Ok(signed)
+}
+
+/// Merge PSBTs for the same transaction produced by different signers
+pub fn combine_psbts(psbts: Vec<Psbt>) -> Result<Psbt> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No PSBTs to combine"))?;
+    for psbt in psbts {
+        combined.combine(psbt)?;
+    }
+    Ok(combined)
+}
+
+/// Whether every input carries a final script sig or witness
+pub fn is_finalized(psbt: &Psbt) -> bool {
+    psbt.inputs
+        .iter()
+        .all(|i| i.final_script_sig.is_some() || i.final_script_witness.is_some())
+}
+
+/// Build final script sigs and witnesses from the collected signatures
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<()> {
+    for index in 0..psbt.inputs.len() {
+        if psbt.inputs[index].final_script_sig.is_some()
+            || psbt.inputs[index].final_script_witness.is_some()
+        {
+            continue;
+        }
+        let script_pubkey = psbt
+            .spend_utxo(index)
+            .map_err(|e| anyhow::anyhow!("Input {index}: {e}"))?
+            .script_pubkey
+            .clone();
+        finalize_input(&mut psbt.inputs[index], &script_pubkey)
+            .map_err(|e| anyhow::anyhow!("Cannot finalize input {index}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Finalize inputs spending from `descriptor` with a miniscript satisfier, which also covers
+/// timelocked and hashlocked branches. Inputs the wallet does not own use `finalize_psbt` rules.
+pub fn finalize_with_descriptor(
+    psbt: &mut Psbt,
+    wallet: &UserBitcoinWallet,
+    descriptor: &Descriptor,
+    secp: &Secp256k1<All>,
+) -> Result<()> {
+    let absolute_locktime = psbt.unsigned_tx.lock_time.to_consensus_u32();
+    for index in 0..psbt.inputs.len() {
+        if psbt.inputs[index].final_script_sig.is_some()
+            || psbt.inputs[index].final_script_witness.is_some()
+        {
+            continue;
+        }
+        let script_pubkey = psbt
+            .spend_utxo(index)
+            .map_err(|e| anyhow::anyhow!("Input {index}: {e}"))?
+            .script_pubkey
+            .clone();
+        let Some(child) = wallet.descriptor_index(&script_pubkey) else {
+            finalize_input(&mut psbt.inputs[index], &script_pubkey)
+                .map_err(|e| anyhow::anyhow!("Cannot finalize input {index}: {e}"))?;
+            continue;
+        };
+        if matches!(descriptor, Descriptor::Tr(_)) {
+            finalize_input(&mut psbt.inputs[index], &script_pubkey)
+                .map_err(|e| anyhow::anyhow!("Cannot finalize input {index}: {e}"))?;
+            continue;
+        }
+
+        let mut satisfier = Satisfier::from_psbt_input(&psbt.inputs[index]);
+        satisfier.absolute_locktime = absolute_locktime;
+        if let Some(relative::LockTime::Blocks(blocks)) = psbt.unsigned_tx.input[index]
+            .sequence
+            .to_relative_lock_time()
+        {
+            satisfier.relative_locktime = blocks.value() as u32;
+        }
+
+        let input = &mut psbt.inputs[index];
+        let witness = descriptor
+            .satisfy(child, secp, &satisfier)?
+            .ok_or_else(|| {
+                anyhow::anyhow!("Cannot satisfy input {index} with the available signatures")
+            })?;
+        if let Some(redeem_script) = &input.redeem_script {
+            input.final_script_sig = Some(
+                Builder::new()
+                    .push_slice(PushBytesBuf::try_from(redeem_script.to_bytes())?)
+                    .into_script(),
+            );
+        }
+        input.final_script_witness = Some(witness);
+        clear_signing_data(input);
+    }
+    Ok(())
+}
+
+fn finalize_input(input: &mut Input, script_pubkey: &Script) -> Result<()> {
+    if script_pubkey.is_p2tr() {
+        input.final_script_witness = Some(taproot_witness(input)?);
+    } else if script_pubkey.is_p2wpkh() {
+        input.final_script_witness = Some(single_key_witness(input)?);
+    } else if script_pubkey.is_p2pkh() {
+        let (pubkey, sig) = single_sig(input)?;
+        input.final_script_sig = Some(
+            Builder::new()
+                .push_slice(PushBytesBuf::try_from(sig.to_vec())?)
+                .push_key(&pubkey)
+                .into_script(),
+        );
+    } else if script_pubkey.is_p2wsh() {
+        input.final_script_witness = Some(multisig_witness(input)?);
+    } else if script_pubkey.is_p2sh() {
+        let redeem_script = input
+            .redeem_script
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Missing redeem script"))?;
+        let witness = if redeem_script.is_p2wpkh() {
+            single_key_witness(input)?
+        } else if redeem_script.is_p2wsh() {
+            multisig_witness(input)?
+        } else {
+            return Err(anyhow::anyhow!("Unsupported P2SH redeem script"));
+        };
+        input.final_script_sig = Some(
+            Builder::new()
+                .push_slice(PushBytesBuf::try_from(redeem_script.to_bytes())?)
+                .into_script(),
+        );
+        input.final_script_witness = Some(witness);
+    } else {
+        return Err(anyhow::anyhow!("Unsupported script type"));
+    }
+
+    clear_signing_data(input);
+    Ok(())
+}
+
+fn single_sig(input: &Input) -> Result<(PublicKey, bitcoin::ecdsa::Signature)> {
+    input
+        .partial_sigs
+        .iter()
+        .next()
+        .map(|(pk, sig)| (*pk, *sig))
+        .ok_or_else(|| anyhow::anyhow!("Missing signature"))
+}
+
+fn single_key_witness(input: &Input) -> Result<Witness> {
+    let (pubkey, sig) = single_sig(input)?;
+    let mut witness = Witness::new();
+    witness.push_ecdsa_signature(&sig);
+    witness.push(pubkey.to_bytes());
+    Ok(witness)
+}
+
+fn multisig_witness(input: &Input) -> Result<Witness> {
+    let witness_script = input
+        .witness_script
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing witness script"))?;
+    let (threshold, pubkeys) = parse_multisig(witness_script)
+        .ok_or_else(|| anyhow::anyhow!("Witness script is not a multisig script"))?;
+
+    // Signatures must appear in the same order as their keys in the script
+    let sigs: Vec<_> = pubkeys
+        .iter()
+        .filter_map(|pk| input.partial_sigs.get(pk))
+        .take(threshold)
+        .collect();
+    if sigs.len() < threshold {
+        return Err(anyhow::anyhow!(
+            "Need {threshold} signatures, have {}",
+            sigs.len()
+        ));
+    }
+
+    let mut witness = Witness::new();
+    // OP_CHECKMULTISIG pops one extra stack element
+    witness.push([]);
+    for sig in sigs {
+        witness.push_ecdsa_signature(sig);
+    }
+    witness.push(witness_script.as_bytes());
+    Ok(witness)
+}
+
+/// Key-path witness when there is a key signature, otherwise the cheapest leaf whose keys
+/// have all signed
+fn taproot_witness(input: &Input) -> Result<Witness> {
+    if let Some(sig) = input.tap_key_sig {
+        return Ok(Witness::from_slice(&[sig.to_vec()]));
+    }
+
+    let mut best: Option<Witness> = None;
+    for (control_block, (script, version)) in &input.tap_scripts {
+        let leaf_hash = TapLeafHash::from_script(script, *version);
+        let sigs: Option<Vec<Vec<u8>>> = tapscript_keys(script)
+            .iter()
+            .map(|key| {
+                input
+                    .tap_script_sigs
+                    .get(&(*key, leaf_hash))
+                    .map(|s| s.to_vec())
+            })
+            .collect();
+        let Some(mut stack) = sigs else { continue };
+
+        // The first key in the script consumes the top stack item
+        stack.reverse();
+        stack.push(script.to_bytes());
+        stack.push(control_block.serialize());
+        let witness = Witness::from_slice(&stack);
+        if best.as_ref().is_none_or(|b| witness.size() < b.size()) {
+            best = Some(witness);
+        }
+    }
+    best.ok_or_else(|| anyhow::anyhow!("Missing taproot signature"))
+}
+
+/// BIP174 finalizers drop everything but the final scripts and UTXOs
+fn clear_signing_data(input: &mut Input) {
+    input.partial_sigs = BTreeMap::new();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation = BTreeMap::new();
+    input.tap_key_sig = None;
+    input.tap_script_sigs = BTreeMap::new();
+    input.tap_scripts = BTreeMap::new();
+    input.tap_key_origins = BTreeMap::new();
+    input.tap_internal_key = None;
+    input.tap_merkle_root = None;
+}
+
+/// Parse `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` into the threshold and keys
+pub fn parse_multisig(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let instructions: Vec<Instruction> = script.instructions().collect::<Result<_, _>>().ok()?;
+    let (last, rest) = instructions.split_last()?;
+    if *last != Instruction::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let (n, rest) = rest.split_last()?;
+    let (m, keys) = rest.split_first()?;
+
+    let small_int = |ins: &Instruction| match ins {
+        Instruction::Op(op) => {
+            let code = op.to_u8();
+            (0x51..=0x60)
+                .contains(&code)
+                .then(|| (code - 0x50) as usize)
+        }
+        _ => None,
+    };
+    let threshold = small_int(m)?;
+    let total = small_int(n)?;
+
+    let pubkeys: Vec<PublicKey> = keys
+        .iter()
+        .map(|ins| match ins {
+            Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+
+    (pubkeys.len() == total && threshold <= total).then_some((threshold, pubkeys))
+}
+
+/// Extract the network transaction from a finalized PSBT
+pub fn extract_transaction(psbt: Psbt) -> Result<Transaction> {
+    if !is_finalized(&psbt) {
+        return Err(anyhow::anyhow!("PSBT is not finalized"));
+    }
+    Ok(psbt.extract_tx()?)
+}
+
+pub fn to_base64(psbt: &Psbt) -> String {
+    psbt.to_string()
+}
+
+pub fn from_base64(data: &str) -> Result<Psbt> {
+    Ok(Psbt::from_str(data.trim())?)
+}
+
+pub fn to_bytes(psbt: &Psbt) -> Vec<u8> {
+    psbt.serialize()
+}
+
+pub fn from_bytes(data: &[u8]) -> Result<Psbt> {
+    Ok(Psbt::deserialize(data)?)
+}
+
+/// Load a PSBT from a binary file or a base64 text file, as exported by Sparrow or Core
+pub fn read_psbt_file(path: &std::path::Path) -> Result<Psbt> {
+    let data = std::fs::read(path)?;
+    if data.starts_with(b"psbt\xff") {
+        from_bytes(&data)
+    } else {
+        from_base64(std::str::from_utf8(&data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressType, BitcoinConfig, BitcoinWalletManager};
+    use bitcoin::{Address, Amount, Network, OutPoint, Txid};
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    async fn manager() -> BitcoinWalletManager {
+        let manager = BitcoinWalletManager::new(BitcoinConfig {
+            network: Network::Regtest,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap();
+        manager
+            .create_wallet("alice", Some(MNEMONIC.to_string()))
+            .await
+            .unwrap();
+        manager
+    }
+
+    fn funding(address: &str, vout: u32, value: u64) -> TrackedUtxo {
+        let address = Address::from_str(address).unwrap().assume_checked();
+        TrackedUtxo {
+            outpoint: OutPoint::new(Txid::all_zeros(), vout),
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: address.script_pubkey(),
+            },
+            confirmations: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_finalize_and_extract() {
+        let manager = manager().await;
+        let segwit = manager
+            .get_receive_address("alice", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let nested = manager
+            .get_receive_address("alice", AddressType::SegwitP2SH)
+            .await
+            .unwrap();
+        let recipient = manager
+            .get_receive_address("alice", AddressType::Legacy)
+            .await
+            .unwrap();
+
+        let recipient = Address::from_str(&recipient).unwrap().assume_checked();
+        let psbt = crate::transaction_builder::TransactionBuilder::new()
+            .add_input(funding(&segwit, 0, 50_000))
+            .add_input(funding(&nested, 1, 50_000))
+            .add_recipient(&recipient, 90_000)
+            .build_psbt()
+            .unwrap();
+
+        let psbt = manager.prepare_psbt("alice", psbt).await.unwrap();
+        assert!(psbt.inputs.iter().all(|i| !i.bip32_derivation.is_empty()));
+
+        // Round-trip through base64 like an external signer would
+        let mut psbt = from_base64(&to_base64(&psbt)).unwrap();
+        assert_eq!(manager.sign_psbt("alice", &mut psbt).await.unwrap(), 2);
+
+        finalize_psbt(&mut psbt).unwrap();
+        assert!(psbt.inputs[1].final_script_sig.is_some());
+        let tx = extract_transaction(psbt).unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx.input.iter().all(|i| !i.witness.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn change_is_the_output_the_builder_added() {
+        let manager = manager().await;
+        let own = manager
+            .get_receive_address("alice", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let change = manager
+            .get_address("alice", crate::Keychain::change(AddressType::NativeSegwit))
+            .await
+            .unwrap();
+        let own = Address::from_str(&own).unwrap().assume_checked();
+        let change = Address::from_str(&change).unwrap().assume_checked();
+        let selection = crate::utxo_manager::SelectionResult {
+            selected: vec![funding(&own.to_string(), 0, 100_000)],
+            total_input: 100_000,
+            target: 50_000,
+            fee: 10_000,
+            change: Some(40_000),
+            waste: 0,
+            algorithm: "test",
+        };
+
+        // A self-transfer pays one of our addresses before the change output
+        let psbt = crate::transaction_builder::TransactionBuilder::new()
+            .add_recipient(&own, 50_000)
+            .add_selection(&selection, change.script_pubkey())
+            .build_psbt()
+            .unwrap();
+        let psbt = manager.prepare_psbt("alice", psbt).await.unwrap();
+        let psbt = from_base64(&to_base64(&psbt)).unwrap();
+        assert_eq!(change_index(&psbt), Some(1));
+
+        let psbt = crate::transaction_builder::TransactionBuilder::new()
+            .add_input(funding(&own.to_string(), 0, 100_000))
+            .add_recipient(&own, 90_000)
+            .build_psbt()
+            .unwrap();
+        assert_eq!(change_index(&psbt), None);
+    }
+
+    #[tokio::test]
+    async fn descriptor_wallet_spends_timelocked_branch() {
+        let manager = manager().await;
+        let tprv = "tprv8ZgxMBicQKsPd7Uf69XL1XwhmjHopUGep8GuEiJDZmbQz6o58LninorQAfcKZWARbtRtfnLcJ5MQ2AtHcQJCCRUcMRvmDUjyEmNUWwx8UbK";
+        let descriptor = format!("wsh(and_v(v:pk([d34db33f/48'/1'/0'/2']{tprv}/0/*),older(144)))");
+        let info = manager
+            .create_wallet_from_descriptor("bob", &descriptor)
+            .await
+            .unwrap();
+        assert!(!info.descriptor.unwrap().contains("tprv"));
+
+        let psbt = crate::transaction_builder::TransactionBuilder::new()
+            .add_input(funding(&info.first_address, 0, 50_000))
+            .add_recipient(
+                &Address::from_str(&info.first_address)
+                    .unwrap()
+                    .assume_checked(),
+                49_000,
+            )
+            .build_psbt()
+            .unwrap();
+        let mut psbt = manager.prepare_psbt("bob", psbt).await.unwrap();
+        assert!(psbt.inputs[0].witness_script.is_some());
+        assert_eq!(manager.sign_psbt("bob", &mut psbt).await.unwrap(), 1);
+
+        // The relative timelock is not yet committed to
+        assert!(manager
+            .finalize_psbt("bob", &mut psbt.clone())
+            .await
+            .is_err());
+
+        psbt.unsigned_tx.input[0].sequence = bitcoin::Sequence::from_height(144);
+        psbt.inputs[0].partial_sigs.clear();
+        manager.sign_psbt("bob", &mut psbt).await.unwrap();
+        manager.finalize_psbt("bob", &mut psbt).await.unwrap();
+        let tx = extract_transaction(psbt).unwrap();
+        // signature and witness script
+        assert_eq!(tx.input[0].witness.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn taproot_key_and_script_path() {
+        let manager = manager().await;
+        let key_path = manager
+            .get_receive_address("alice", AddressType::Taproot)
+            .await
+            .unwrap();
+        assert!(key_path.starts_with("bcrt1p"));
+
+        let leaf_key = manager.get_taproot_key("alice").await.unwrap();
+        let leaf = Builder::new()
+            .push_x_only_key(&leaf_key)
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let script_path = manager
+            .get_taproot_script_address("alice", std::slice::from_ref(&leaf))
+            .await
+            .unwrap();
+
+        let recipient = Address::from_str(&key_path).unwrap().assume_checked();
+        let psbt = crate::transaction_builder::TransactionBuilder::new()
+            .add_input(funding(&key_path, 0, 50_000))
+            .add_input(funding(&script_path, 1, 50_000))
+            .add_recipient(&recipient, 99_000)
+            .build_psbt()
+            .unwrap();
+        let mut psbt = manager.prepare_psbt("alice", psbt).await.unwrap();
+        assert_eq!(psbt.inputs[1].tap_scripts.len(), 1);
+        assert_eq!(manager.sign_psbt("alice", &mut psbt).await.unwrap(), 2);
+
+        // Spend the second input through its leaf instead of the key path
+        psbt.inputs[1].tap_key_sig = None;
+        let spent: Vec<TxOut> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = extract_transaction(psbt).unwrap();
+        assert_eq!(tx.input[0].witness.len(), 1);
+        assert_eq!(tx.input[1].witness.len(), 3);
+
+        let secp = Secp256k1::new();
+        let mut cache = SighashCache::new(&tx);
+        let prevouts = Prevouts::All(&spent);
+        let verify = |sighash: [u8; 32], sig: &[u8], key: &XOnlyPublicKey| {
+            let sig = taproot::Signature::from_slice(sig).unwrap();
+            secp.verify_schnorr(&sig.sig, &Message::from_digest(sighash), key)
+                .unwrap();
+        };
+
+        let output_key =
+            XOnlyPublicKey::from_slice(&spent[0].script_pubkey.as_bytes()[2..]).unwrap();
+        let sighash = cache
+            .taproot_key_spend_signature_hash(0, &prevouts, TapSighashType::Default)
+            .unwrap();
+        verify(
+            sighash.to_byte_array(),
+            &tx.input[0].witness[0],
+            &output_key,
+        );
+
+        let leaf_hash = TapLeafHash::from_script(&leaf, LeafVersion::TapScript);
+        let sighash = cache
+            .taproot_script_spend_signature_hash(1, &prevouts, leaf_hash, TapSighashType::Default)
+            .unwrap();
+        verify(sighash.to_byte_array(), &tx.input[1].witness[0], &leaf_key);
+    }
+
+    #[test]
+    fn combine_requires_input() {
+        assert!(combine_psbts(vec![]).is_err());
+    }
+
+    #[test]
+    fn parses_multisig_script() {
+        let secp = Secp256k1::new();
+        let keys: Vec<PublicKey> = (1u8..=3)
+            .map(|i| {
+                let sk = bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+                PublicKey::new(sk.public_key(&secp))
+            })
+            .collect();
+        let mut builder = Builder::new().push_int(2);
+        for key in &keys {
+            builder = builder.push_key(key);
+        }
+        let script = builder
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+
+        let (threshold, parsed) = parse_multisig(&script).unwrap();
+        assert_eq!(threshold, 2);
+        assert_eq!(parsed, keys);
+    }
+}
@@ -1 +1,1329 @@
-// Script builder module
+// Output descriptors (BIP380-386) and a miniscript policy compiler
+//
+// Supported descriptors: wpkh(KEY), sh(wpkh(KEY)), wsh(multi(..)), wsh(sortedmulti(..)),
+// wsh(MINISCRIPT) and tr(KEY). Supported miniscript fragments: pk, multi, sortedmulti, older,
+// after, sha256, and_v, or_i and the v: wrapper.
+use anyhow::Result;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource, Xpriv, Xpub};
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::psbt::{Input, Output};
+use bitcoin::secp256k1::{All, Secp256k1, XOnlyPublicKey};
+use bitcoin::{Address, Network, PublicKey, ScriptBuf, Weight, Witness};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// Maximum number of keys in a P2WSH multisig
+const MAX_MULTISIG_KEYS: usize = 20;
+
+/// Largest `thresh` the policy compiler expands into an or-of-ands
+const MAX_THRESH_EXPANSION: usize = 6;
+
+// BIP380 descriptor checksum
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(symbols: impl IntoIterator<Item = u64>) -> u64 {
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    let mut chk = 1u64;
+    for value in symbols {
+        let top = chk >> 35;
+        chk = ((chk & 0x7ffffffff) << 5) ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// Compute the 8-character checksum of a descriptor body
+pub fn descriptor_checksum(desc: &str) -> Result<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in desc.chars() {
+        let value = INPUT_CHARSET
+            .find(c)
+            .ok_or_else(|| anyhow::anyhow!("Invalid character in descriptor: {c}"))?
+            as u64;
+        symbols.push(value & 31);
+        groups.push(value >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.len() {
+        1 => symbols.push(groups[0]),
+        2 => symbols.push(groups[0] * 3 + groups[1]),
+        _ => {}
+    }
+    symbols.extend([0; 8]);
+
+    let checksum = polymod(symbols) ^ 1;
+    Ok((0..8)
+        .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect())
+}
+
+/// Split `body#checksum`, verifying the checksum when present
+fn strip_checksum(s: &str) -> Result<&str> {
+    match s.split_once('#') {
+        Some((body, checksum)) => {
+            if descriptor_checksum(body)? != checksum {
+                return Err(anyhow::anyhow!("Invalid descriptor checksum"));
+            }
+            Ok(body)
+        }
+        None => Ok(s),
+    }
+}
+
+// Expression parsing: name(arg,arg,...)
+
+/// Split `name(args)` into the name and its top-level comma separated arguments
+fn split_call(s: &str) -> Result<(&str, Vec<&str>)> {
+    let open = s
+        .find('(')
+        .ok_or_else(|| anyhow::anyhow!("Expected '(' in {s}"))?;
+    if !s.ends_with(')') {
+        return Err(anyhow::anyhow!("Expected ')' at the end of {s}"));
+    }
+    let name = &s[..open];
+    let inner = &s[open + 1..s.len() - 1];
+
+    let mut args = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        if depth < 0 {
+            return Err(anyhow::anyhow!("Unbalanced brackets in {s}"));
+        }
+    }
+    if depth != 0 {
+        return Err(anyhow::anyhow!("Unbalanced brackets in {s}"));
+    }
+    if !inner.is_empty() {
+        args.push(&inner[start..]);
+    }
+    Ok((name, args))
+}
+
+fn expect_args(name: &str, args: &[&str], count: usize) -> Result<()> {
+    if args.len() != count {
+        return Err(anyhow::anyhow!(
+            "{name}() takes {count} argument(s), got {}",
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+fn parse_path(components: &[&str]) -> Result<DerivationPath> {
+    let children = components
+        .iter()
+        .map(|c| ChildNumber::from_str(c))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(DerivationPath::from(children))
+}
+
+fn format_path(path: &DerivationPath) -> String {
+    path.into_iter().map(|c| format!("/{c}")).collect()
+}
+
+// Keys
+
+/// Extended key inside a descriptor, public or private
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtendedKey {
+    Public(Xpub),
+    Private(Xpriv),
+}
+
+/// A key expression: `[fingerprint/origin]KEY/path/*` or a hex public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorKey {
+    Single {
+        origin: Option<KeySource>,
+        key: PublicKey,
+    },
+    Extended {
+        origin: Option<KeySource>,
+        key: ExtendedKey,
+        /// Path after the extended key, excluding the wildcard
+        path: DerivationPath,
+        /// Whether the key ends in `/*`
+        wildcard: bool,
+    },
+}
+
+impl DescriptorKey {
+    /// Whether the key derives a different child for every index
+    pub fn is_ranged(&self) -> bool {
+        matches!(self, DescriptorKey::Extended { wildcard: true, .. })
+    }
+
+    /// Private key material, if any
+    pub fn secret(&self) -> Option<(Xpriv, Option<KeySource>)> {
+        match self {
+            DescriptorKey::Extended {
+                key: ExtendedKey::Private(xprv),
+                origin,
+                ..
+            } => Some((*xprv, origin.clone())),
+            _ => None,
+        }
+    }
+
+    /// Same key with private material replaced by its public counterpart
+    pub fn to_public(&self, secp: &Secp256k1<All>) -> DescriptorKey {
+        match self {
+            DescriptorKey::Extended {
+                origin,
+                key: ExtendedKey::Private(xprv),
+                path,
+                wildcard,
+            } => DescriptorKey::Extended {
+                // Keep the link to the private key for signers
+                origin: origin
+                    .clone()
+                    .or_else(|| Some((xprv.fingerprint(secp), DerivationPath::master()))),
+                key: ExtendedKey::Public(Xpub::from_priv(secp, xprv)),
+                path: path.clone(),
+                wildcard: *wildcard,
+            },
+            other => other.clone(),
+        }
+    }
+
+    fn child_path(path: &DerivationPath, wildcard: bool, index: u32) -> Result<DerivationPath> {
+        Ok(if wildcard {
+            path.child(ChildNumber::from_normal_idx(index)?)
+        } else {
+            path.clone()
+        })
+    }
+
+    /// Public key at the given wildcard index
+    pub fn derive(&self, index: u32, secp: &Secp256k1<All>) -> Result<PublicKey> {
+        match self {
+            DescriptorKey::Single { key, .. } => Ok(*key),
+            DescriptorKey::Extended {
+                key,
+                path,
+                wildcard,
+                ..
+            } => {
+                let path = Self::child_path(path, *wildcard, index)?;
+                Ok(match key {
+                    ExtendedKey::Public(xpub) => xpub.derive_pub(secp, &path)?.to_pub(),
+                    ExtendedKey::Private(xprv) => {
+                        Xpub::from_priv(secp, &xprv.derive_priv(secp, &path)?).to_pub()
+                    }
+                })
+            }
+        }
+    }
+
+    /// BIP32 key origin of the key at the given index, for PSBT fields
+    pub fn key_source(&self, index: u32, secp: &Secp256k1<All>) -> Result<Option<KeySource>> {
+        match self {
+            DescriptorKey::Single { origin, .. } => Ok(origin.clone()),
+            DescriptorKey::Extended {
+                origin,
+                key,
+                path,
+                wildcard,
+            } => {
+                let path = Self::child_path(path, *wildcard, index)?;
+                Ok(Some(match origin {
+                    Some((fingerprint, origin_path)) => {
+                        (*fingerprint, origin_path.extend(path.as_ref()))
+                    }
+                    None => {
+                        let fingerprint = match key {
+                            ExtendedKey::Public(xpub) => xpub.fingerprint(),
+                            ExtendedKey::Private(xprv) => xprv.fingerprint(secp),
+                        };
+                        (fingerprint, path)
+                    }
+                }))
+            }
+        }
+    }
+}
+
+impl FromStr for DescriptorKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (origin, rest) = if let Some(stripped) = s.strip_prefix('[') {
+            let (origin, rest) = stripped
+                .split_once(']')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated key origin in {s}"))?;
+            let mut parts = origin.split('/');
+            let fingerprint = Fingerprint::from_str(parts.next().unwrap_or_default())?;
+            let path = parse_path(&parts.collect::<Vec<_>>())?;
+            (Some((fingerprint, path)), rest)
+        } else {
+            (None, s)
+        };
+
+        let mut parts = rest.split('/');
+        let key = parts.next().unwrap_or_default();
+        let mut components: Vec<&str> = parts.collect();
+
+        if key.len() == 66 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+            if !components.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Derivation path on a non-extended key: {s}"
+                ));
+            }
+            return Ok(DescriptorKey::Single {
+                origin,
+                key: PublicKey::from_str(key)?,
+            });
+        }
+
+        let wildcard = components.last() == Some(&"*");
+        if wildcard {
+            components.pop();
+        }
+        if components.iter().any(|c| c.contains('*')) {
+            return Err(anyhow::anyhow!(
+                "Only a trailing /* wildcard is supported: {s}"
+            ));
+        }
+        let path = parse_path(&components)?;
+
+        let key = if key.starts_with("xprv") || key.starts_with("tprv") {
+            ExtendedKey::Private(Xpriv::from_str(key)?)
+        } else {
+            if path.into_iter().any(|c| c.is_hardened()) {
+                return Err(anyhow::anyhow!(
+                    "Hardened derivation after a public key: {s}"
+                ));
+            }
+            ExtendedKey::Public(Xpub::from_str(key)?)
+        };
+
+        Ok(DescriptorKey::Extended {
+            origin,
+            key,
+            path,
+            wildcard,
+        })
+    }
+}
+
+impl fmt::Display for DescriptorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = match self {
+            DescriptorKey::Single { origin, .. } | DescriptorKey::Extended { origin, .. } => origin,
+        };
+        if let Some((fingerprint, path)) = origin {
+            write!(f, "[{fingerprint}{}]", format_path(path))?;
+        }
+        match self {
+            DescriptorKey::Single { key, .. } => write!(f, "{key}"),
+            DescriptorKey::Extended {
+                key,
+                path,
+                wildcard,
+                ..
+            } => {
+                match key {
+                    ExtendedKey::Public(xpub) => write!(f, "{xpub}")?,
+                    ExtendedKey::Private(xprv) => write!(f, "{xprv}")?,
+                }
+                write!(f, "{}", format_path(path))?;
+                if *wildcard {
+                    write!(f, "/*")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Miniscript
+
+/// A subset of miniscript sufficient for multisig and timelocked recovery policies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Miniscript {
+    /// `<key> CHECKSIG`
+    Pk(DescriptorKey),
+    /// `k <keys...> n CHECKMULTISIG`
+    Multi(usize, Vec<DescriptorKey>),
+    /// Like `Multi` with keys sorted lexicographically after derivation (BIP67)
+    SortedMulti(usize, Vec<DescriptorKey>),
+    /// `<n> CHECKSEQUENCEVERIFY`
+    Older(u32),
+    /// `<n> CHECKLOCKTIMEVERIFY`
+    After(u32),
+    /// `SIZE 32 EQUALVERIFY SHA256 <h> EQUAL`
+    Sha256(sha256::Hash),
+    /// `v:X`, run X and fail unless it succeeds
+    Verify(Box<Miniscript>),
+    /// `X Y` where X is a verify fragment
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    /// `IF X ELSE Y ENDIF`
+    OrI(Box<Miniscript>, Box<Miniscript>),
+}
+
+/// Signatures, preimages and timelock context used to build a witness
+#[derive(Debug, Clone, Default)]
+pub struct Satisfier {
+    /// Serialized ECDSA signatures including the sighash byte
+    pub signatures: HashMap<PublicKey, Vec<u8>>,
+    pub preimages: HashMap<sha256::Hash, [u8; 32]>,
+    /// Relative locktime (in blocks) the spending input commits to
+    pub relative_locktime: u32,
+    /// Absolute locktime the spending transaction commits to
+    pub absolute_locktime: u32,
+}
+
+impl Satisfier {
+    /// Collect the partial signatures of a PSBT input
+    pub fn from_psbt_input(input: &Input) -> Self {
+        Self {
+            signatures: input
+                .partial_sigs
+                .iter()
+                .map(|(pk, sig)| (*pk, sig.to_vec()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Miniscript {
+    fn parse(s: &str) -> Result<Self> {
+        if let Some(inner) = s.strip_prefix("v:") {
+            return Ok(Miniscript::Verify(Box::new(Self::parse(inner)?)));
+        }
+        let (name, args) = split_call(s)?;
+        let multi = |args: &[&str]| -> Result<(usize, Vec<DescriptorKey>)> {
+            let (k, keys) = args
+                .split_first()
+                .ok_or_else(|| anyhow::anyhow!("{name}() needs a threshold"))?;
+            let k: usize = k.parse()?;
+            let keys = keys
+                .iter()
+                .map(|k| DescriptorKey::from_str(k))
+                .collect::<Result<Vec<_>>>()?;
+            if k == 0 || k > keys.len() || keys.len() > MAX_MULTISIG_KEYS {
+                return Err(anyhow::anyhow!(
+                    "Invalid {name}({k}) with {} keys",
+                    keys.len()
+                ));
+            }
+            Ok((k, keys))
+        };
+
+        Ok(match name {
+            "pk" => {
+                expect_args(name, &args, 1)?;
+                Miniscript::Pk(DescriptorKey::from_str(args[0])?)
+            }
+            "multi" => {
+                let (k, keys) = multi(&args)?;
+                Miniscript::Multi(k, keys)
+            }
+            "sortedmulti" => {
+                let (k, keys) = multi(&args)?;
+                Miniscript::SortedMulti(k, keys)
+            }
+            "older" => {
+                expect_args(name, &args, 1)?;
+                Miniscript::Older(args[0].parse()?)
+            }
+            "after" => {
+                expect_args(name, &args, 1)?;
+                Miniscript::After(args[0].parse()?)
+            }
+            "sha256" => {
+                expect_args(name, &args, 1)?;
+                Miniscript::Sha256(sha256::Hash::from_str(args[0])?)
+            }
+            "and_v" => {
+                expect_args(name, &args, 2)?;
+                Miniscript::AndV(
+                    Box::new(Self::parse(args[0])?),
+                    Box::new(Self::parse(args[1])?),
+                )
+            }
+            "or_i" => {
+                expect_args(name, &args, 2)?;
+                Miniscript::OrI(
+                    Box::new(Self::parse(args[0])?),
+                    Box::new(Self::parse(args[1])?),
+                )
+            }
+            other => return Err(anyhow::anyhow!("Unsupported miniscript fragment: {other}")),
+        })
+    }
+
+    /// Every key appearing in the script
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Miniscript::Pk(key) => vec![key],
+            Miniscript::Multi(_, keys) | Miniscript::SortedMulti(_, keys) => keys.iter().collect(),
+            Miniscript::Older(_) | Miniscript::After(_) | Miniscript::Sha256(_) => vec![],
+            Miniscript::Verify(inner) => inner.keys(),
+            Miniscript::AndV(a, b) | Miniscript::OrI(a, b) => {
+                let mut keys = a.keys();
+                keys.extend(b.keys());
+                keys
+            }
+        }
+    }
+
+    fn map_keys(&self, f: &impl Fn(&DescriptorKey) -> DescriptorKey) -> Miniscript {
+        match self {
+            Miniscript::Pk(key) => Miniscript::Pk(f(key)),
+            Miniscript::Multi(k, keys) => Miniscript::Multi(*k, keys.iter().map(f).collect()),
+            Miniscript::SortedMulti(k, keys) => {
+                Miniscript::SortedMulti(*k, keys.iter().map(f).collect())
+            }
+            Miniscript::Verify(inner) => Miniscript::Verify(Box::new(inner.map_keys(f))),
+            Miniscript::AndV(a, b) => {
+                Miniscript::AndV(Box::new(a.map_keys(f)), Box::new(b.map_keys(f)))
+            }
+            Miniscript::OrI(a, b) => {
+                Miniscript::OrI(Box::new(a.map_keys(f)), Box::new(b.map_keys(f)))
+            }
+            other => other.clone(),
+        }
+    }
+
+    fn derive_keys(
+        keys: &[DescriptorKey],
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<PublicKey>> {
+        keys.iter().map(|k| k.derive(index, secp)).collect()
+    }
+
+    fn sorted_keys(
+        keys: &[DescriptorKey],
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<PublicKey>> {
+        let mut keys = Self::derive_keys(keys, index, secp)?;
+        keys.sort_by_key(|k| k.to_bytes());
+        Ok(keys)
+    }
+
+    /// Append the script for this fragment. With `verify` set the fragment must leave nothing
+    /// on the stack, using the VERIFY form of its last opcode where one exists.
+    fn encode(
+        &self,
+        builder: Builder,
+        index: u32,
+        secp: &Secp256k1<All>,
+        verify: bool,
+    ) -> Result<Builder> {
+        let multisig = |builder: Builder, k: usize, keys: Vec<PublicKey>| {
+            let builder = keys
+                .iter()
+                .fold(builder.push_int(k as i64), |b, key| b.push_key(key))
+                .push_int(keys.len() as i64);
+            if verify {
+                builder.push_opcode(OP_CHECKMULTISIGVERIFY)
+            } else {
+                builder.push_opcode(OP_CHECKMULTISIG)
+            }
+        };
+
+        Ok(match self {
+            Miniscript::Pk(key) => {
+                let builder = builder.push_key(&key.derive(index, secp)?);
+                if verify {
+                    builder.push_opcode(OP_CHECKSIGVERIFY)
+                } else {
+                    builder.push_opcode(OP_CHECKSIG)
+                }
+            }
+            Miniscript::Multi(k, keys) => {
+                multisig(builder, *k, Self::derive_keys(keys, index, secp)?)
+            }
+            Miniscript::SortedMulti(k, keys) => {
+                multisig(builder, *k, Self::sorted_keys(keys, index, secp)?)
+            }
+            Miniscript::Older(n) | Miniscript::After(n) => {
+                let op = if matches!(self, Miniscript::Older(_)) {
+                    OP_CSV
+                } else {
+                    OP_CLTV
+                };
+                let builder = builder.push_int(*n as i64).push_opcode(op);
+                if verify {
+                    builder.push_opcode(OP_VERIFY)
+                } else {
+                    builder
+                }
+            }
+            Miniscript::Sha256(hash) => {
+                let builder = builder
+                    .push_opcode(OP_SIZE)
+                    .push_int(32)
+                    .push_opcode(OP_EQUALVERIFY)
+                    .push_opcode(OP_SHA256)
+                    .push_slice(hash.to_byte_array());
+                if verify {
+                    builder.push_opcode(OP_EQUALVERIFY)
+                } else {
+                    builder.push_opcode(OP_EQUAL)
+                }
+            }
+            Miniscript::Verify(inner) => inner.encode(builder, index, secp, true)?,
+            Miniscript::AndV(a, b) => {
+                let builder = a.encode(builder, index, secp, false)?;
+                b.encode(builder, index, secp, verify)?
+            }
+            Miniscript::OrI(a, b) => {
+                let builder = a.encode(builder.push_opcode(OP_IF), index, secp, false)?;
+                let builder = b
+                    .encode(builder.push_opcode(OP_ELSE), index, secp, false)?
+                    .push_opcode(OP_ENDIF);
+                if verify {
+                    builder.push_opcode(OP_VERIFY)
+                } else {
+                    builder
+                }
+            }
+        })
+    }
+
+    /// Script for this miniscript at the given derivation index
+    pub fn script(&self, index: u32, secp: &Secp256k1<All>) -> Result<ScriptBuf> {
+        Ok(self
+            .encode(Builder::new(), index, secp, false)?
+            .into_script())
+    }
+
+    /// Cheapest witness stack (without the witness script) satisfying this fragment
+    pub fn satisfy(
+        &self,
+        index: u32,
+        secp: &Secp256k1<All>,
+        satisfier: &Satisfier,
+    ) -> Result<Option<Vec<Vec<u8>>>> {
+        let multisig = |k: usize, keys: Vec<PublicKey>| {
+            let sigs: Vec<Vec<u8>> = keys
+                .iter()
+                .filter_map(|key| satisfier.signatures.get(key).cloned())
+                .take(k)
+                .collect();
+            // OP_CHECKMULTISIG pops one extra element
+            (sigs.len() == k).then(|| std::iter::once(vec![]).chain(sigs).collect())
+        };
+
+        Ok(match self {
+            Miniscript::Pk(key) => satisfier
+                .signatures
+                .get(&key.derive(index, secp)?)
+                .map(|sig| vec![sig.clone()]),
+            Miniscript::Multi(k, keys) => multisig(*k, Self::derive_keys(keys, index, secp)?),
+            Miniscript::SortedMulti(k, keys) => multisig(*k, Self::sorted_keys(keys, index, secp)?),
+            Miniscript::Older(n) => (satisfier.relative_locktime >= *n).then(Vec::new),
+            Miniscript::After(n) => (satisfier.absolute_locktime >= *n).then(Vec::new),
+            Miniscript::Sha256(hash) => satisfier
+                .preimages
+                .get(hash)
+                .map(|preimage| vec![preimage.to_vec()]),
+            Miniscript::Verify(inner) => inner.satisfy(index, secp, satisfier)?,
+            Miniscript::AndV(a, b) => {
+                // `a` runs first, so its items sit on top of `b`'s
+                match (
+                    a.satisfy(index, secp, satisfier)?,
+                    b.satisfy(index, secp, satisfier)?,
+                ) {
+                    (Some(a), Some(b)) => Some(b.into_iter().chain(a).collect()),
+                    _ => None,
+                }
+            }
+            Miniscript::OrI(a, b) => {
+                let left = a.satisfy(index, secp, satisfier)?.map(|mut s| {
+                    s.push(vec![1]);
+                    s
+                });
+                let right = b.satisfy(index, secp, satisfier)?.map(|mut s| {
+                    s.push(vec![]);
+                    s
+                });
+                let size = |s: &Vec<Vec<u8>>| s.iter().map(|i| i.len() + 1).sum::<usize>();
+                match (left, right) {
+                    (Some(l), Some(r)) => Some(if size(&l) <= size(&r) { l } else { r }),
+                    (l, r) => l.or(r),
+                }
+            }
+        })
+    }
+
+    /// Largest witness stack any satisfaction can need, as (item count, serialized size with
+    /// each item's length prefix)
+    fn max_satisfaction_size(&self) -> (usize, usize) {
+        // DER signature with its sighash byte
+        const SIG: usize = 1 + 73;
+        match self {
+            Miniscript::Pk(_) => (1, SIG),
+            // OP_CHECKMULTISIG's dummy element and k signatures
+            Miniscript::Multi(k, _) | Miniscript::SortedMulti(k, _) => (1 + k, 1 + k * SIG),
+            Miniscript::Older(_) | Miniscript::After(_) => (0, 0),
+            Miniscript::Sha256(_) => (1, 1 + 32),
+            Miniscript::Verify(inner) => inner.max_satisfaction_size(),
+            Miniscript::AndV(a, b) => {
+                let (a, b) = (a.max_satisfaction_size(), b.max_satisfaction_size());
+                (a.0 + b.0, a.1 + b.1)
+            }
+            Miniscript::OrI(a, b) => {
+                // `1` selects the first branch, an empty item the second
+                let (a, b) = (a.max_satisfaction_size(), b.max_satisfaction_size());
+                (a.0.max(b.0) + 1, (a.1 + 2).max(b.1 + 1))
+            }
+        }
+    }
+
+    /// Largest relative timelock needed by any branch
+    pub fn relative_timelocks(&self) -> Vec<u32> {
+        match self {
+            Miniscript::Older(n) => vec![*n],
+            Miniscript::Verify(inner) => inner.relative_timelocks(),
+            Miniscript::AndV(a, b) | Miniscript::OrI(a, b) => {
+                let mut locks = a.relative_timelocks();
+                locks.extend(b.relative_timelocks());
+                locks
+            }
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = |keys: &[DescriptorKey]| {
+            keys.iter()
+                .map(|k| k.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            Miniscript::Pk(key) => write!(f, "pk({key})"),
+            Miniscript::Multi(k, ks) => write!(f, "multi({k},{})", keys(ks)),
+            Miniscript::SortedMulti(k, ks) => write!(f, "sortedmulti({k},{})", keys(ks)),
+            Miniscript::Older(n) => write!(f, "older({n})"),
+            Miniscript::After(n) => write!(f, "after({n})"),
+            Miniscript::Sha256(h) => write!(f, "sha256({h})"),
+            Miniscript::Verify(inner) => write!(f, "v:{inner}"),
+            Miniscript::AndV(a, b) => write!(f, "and_v({a},{b})"),
+            Miniscript::OrI(a, b) => write!(f, "or_i({a},{b})"),
+        }
+    }
+}
+
+// Descriptors
+
+/// An output descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    /// `wpkh(KEY)`, native segwit single key
+    Wpkh(DescriptorKey),
+    /// `sh(wpkh(KEY))`, nested segwit single key
+    ShWpkh(DescriptorKey),
+    /// `wsh(SCRIPT)`, native segwit script hash
+    Wsh(Miniscript),
+    /// `tr(KEY)`, taproot key path only
+    Tr(DescriptorKey),
+}
+
+impl Descriptor {
+    /// Every key in the descriptor
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Descriptor::Wpkh(key) | Descriptor::ShWpkh(key) | Descriptor::Tr(key) => vec![key],
+            Descriptor::Wsh(ms) => ms.keys(),
+        }
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        self.keys().iter().any(|k| k.is_ranged())
+    }
+
+    /// First private extended key in the descriptor, with its origin
+    pub fn secret_key(&self) -> Option<(Xpriv, Option<KeySource>)> {
+        self.keys().into_iter().find_map(|k| k.secret())
+    }
+
+    /// The descriptor with every private key replaced by its xpub
+    pub fn to_public(&self, secp: &Secp256k1<All>) -> Descriptor {
+        let public = |k: &DescriptorKey| k.to_public(secp);
+        match self {
+            Descriptor::Wpkh(key) => Descriptor::Wpkh(public(key)),
+            Descriptor::ShWpkh(key) => Descriptor::ShWpkh(public(key)),
+            Descriptor::Wsh(ms) => Descriptor::Wsh(ms.map_keys(&public)),
+            Descriptor::Tr(key) => Descriptor::Tr(public(key)),
+        }
+    }
+
+    /// Witness script for `wsh()` descriptors
+    pub fn witness_script(&self, index: u32, secp: &Secp256k1<All>) -> Result<Option<ScriptBuf>> {
+        match self {
+            Descriptor::Wsh(ms) => Ok(Some(ms.script(index, secp)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn script_pubkey(&self, index: u32, secp: &Secp256k1<All>) -> Result<ScriptBuf> {
+        Ok(self.address(index, Network::Bitcoin, secp)?.script_pubkey())
+    }
+
+    pub fn address(&self, index: u32, network: Network, secp: &Secp256k1<All>) -> Result<Address> {
+        Ok(match self {
+            Descriptor::Wpkh(key) => Address::p2wpkh(&key.derive(index, secp)?, network)?,
+            Descriptor::ShWpkh(key) => Address::p2shwpkh(&key.derive(index, secp)?, network)?,
+            Descriptor::Wsh(ms) => Address::p2wsh(&ms.script(index, secp)?, network),
+            Descriptor::Tr(key) => {
+                let internal_key = XOnlyPublicKey::from(key.derive(index, secp)?.inner);
+                Address::p2tr(secp, internal_key, None, network)
+            }
+        })
+    }
+
+    /// Scripts and key origins for the output at `index`
+    fn psbt_fields(&self, index: u32, secp: &Secp256k1<All>) -> Result<PsbtFields> {
+        let mut fields = PsbtFields::default();
+        for key in self.keys() {
+            let pubkey = key.derive(index, secp)?;
+            if let Some(source) = key.key_source(index, secp)? {
+                if let Descriptor::Tr(_) = self {
+                    fields
+                        .tap_key_origins
+                        .insert(XOnlyPublicKey::from(pubkey.inner), (vec![], source));
+                } else {
+                    fields.bip32_derivation.insert(pubkey.inner, source);
+                }
+            }
+        }
+
+        match self {
+            Descriptor::ShWpkh(key) => {
+                let pubkey = key.derive(index, secp)?;
+                let wpkh = pubkey
+                    .wpubkey_hash()
+                    .ok_or_else(|| anyhow::anyhow!("Uncompressed key in segwit descriptor"))?;
+                fields.redeem_script = Some(ScriptBuf::new_p2wpkh(&wpkh));
+            }
+            Descriptor::Wsh(ms) => fields.witness_script = Some(ms.script(index, secp)?),
+            Descriptor::Tr(key) => {
+                fields.tap_internal_key = Some(XOnlyPublicKey::from(key.derive(index, secp)?.inner))
+            }
+            Descriptor::Wpkh(_) => {}
+        }
+        Ok(fields)
+    }
+
+    /// Add the scripts and key origins a signer needs to a PSBT input spending index `index`
+    pub fn update_psbt_input(
+        &self,
+        input: &mut Input,
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<()> {
+        let fields = self.psbt_fields(index, secp)?;
+        input.bip32_derivation.extend(fields.bip32_derivation);
+        input.tap_key_origins.extend(fields.tap_key_origins);
+        input.redeem_script = fields.redeem_script.or(input.redeem_script.take());
+        input.witness_script = fields.witness_script.or(input.witness_script.take());
+        input.tap_internal_key = fields.tap_internal_key.or(input.tap_internal_key);
+        Ok(())
+    }
+
+    /// Same as `update_psbt_input`, for a change output paying to index `index`
+    pub fn update_psbt_output(
+        &self,
+        output: &mut Output,
+        index: u32,
+        secp: &Secp256k1<All>,
+    ) -> Result<()> {
+        let fields = self.psbt_fields(index, secp)?;
+        output.bip32_derivation.extend(fields.bip32_derivation);
+        output.tap_key_origins.extend(fields.tap_key_origins);
+        output.redeem_script = fields.redeem_script.or(output.redeem_script.take());
+        output.witness_script = fields.witness_script.or(output.witness_script.take());
+        output.tap_internal_key = fields.tap_internal_key.or(output.tap_internal_key);
+        Ok(())
+    }
+
+    /// Build the final witness for an input of this descriptor, or `None` if the available
+    /// signatures, preimages and timelocks cannot satisfy it
+    pub fn satisfy(
+        &self,
+        index: u32,
+        secp: &Secp256k1<All>,
+        satisfier: &Satisfier,
+    ) -> Result<Option<Witness>> {
+        let single_key = |key: &DescriptorKey| -> Result<Option<Witness>> {
+            let pubkey = key.derive(index, secp)?;
+            Ok(satisfier
+                .signatures
+                .get(&pubkey)
+                .map(|sig| Witness::from_slice(&[sig.clone(), pubkey.to_bytes()])))
+        };
+
+        match self {
+            Descriptor::Wpkh(key) | Descriptor::ShWpkh(key) => single_key(key),
+            Descriptor::Wsh(ms) => {
+                let script = ms.script(index, secp)?;
+                Ok(ms.satisfy(index, secp, satisfier)?.map(|mut stack| {
+                    stack.push(script.to_bytes());
+                    Witness::from_slice(&stack)
+                }))
+            }
+            Descriptor::Tr(_) => Err(anyhow::anyhow!(
+                "Taproot inputs are satisfied from their Schnorr signature"
+            )),
+        }
+    }
+
+    /// Largest weight an input spending this descriptor can have once satisfied, for coin
+    /// selection's fee estimate
+    pub fn max_satisfaction_weight(&self, secp: &Secp256k1<All>) -> Result<Weight> {
+        // outpoint + sequence + empty script_sig
+        const BASE: u64 = 41 * 4;
+        // item count, DER signature, compressed pubkey
+        const WPKH_WITNESS: u64 = 1 + (1 + 73) + (1 + 33);
+        let varint = |n: u64| if n < 0xfd { 1 } else { 3 };
+
+        let wu = match self {
+            Descriptor::Wpkh(_) => BASE + WPKH_WITNESS,
+            // script_sig pushes the 22 byte P2WPKH redeem script
+            Descriptor::ShWpkh(_) => BASE + 4 * 23 + WPKH_WITNESS,
+            Descriptor::Wsh(ms) => {
+                // Scripts use compressed keys, so their size is the same at every index
+                let script = ms.script(0, secp)?.len() as u64;
+                let (items, size) = ms.max_satisfaction_size();
+                let items = items as u64 + 1;
+                BASE + varint(items) + size as u64 + varint(script) + script
+            }
+            // item count and a Schnorr signature with a sighash byte
+            Descriptor::Tr(_) => BASE + 1 + 1 + 65,
+        };
+        Ok(Weight::from_wu(wu))
+    }
+
+    /// Display without the checksum
+    fn body(&self) -> String {
+        match self {
+            Descriptor::Wpkh(key) => format!("wpkh({key})"),
+            Descriptor::ShWpkh(key) => format!("sh(wpkh({key}))"),
+            Descriptor::Wsh(ms) => format!("wsh({ms})"),
+            Descriptor::Tr(key) => format!("tr({key})"),
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let body = strip_checksum(s.trim())?;
+        let (name, args) = split_call(body)?;
+        Ok(match name {
+            "wpkh" => {
+                expect_args(name, &args, 1)?;
+                Descriptor::Wpkh(DescriptorKey::from_str(args[0])?)
+            }
+            "sh" => {
+                expect_args(name, &args, 1)?;
+                let (inner, inner_args) = split_call(args[0])?;
+                if inner != "wpkh" {
+                    return Err(anyhow::anyhow!("Only sh(wpkh(KEY)) is supported"));
+                }
+                expect_args(inner, &inner_args, 1)?;
+                Descriptor::ShWpkh(DescriptorKey::from_str(inner_args[0])?)
+            }
+            "wsh" => {
+                expect_args(name, &args, 1)?;
+                Descriptor::Wsh(Miniscript::parse(args[0])?)
+            }
+            "tr" => {
+                expect_args(name, &args, 1)?;
+                Descriptor::Tr(DescriptorKey::from_str(args[0])?)
+            }
+            other => return Err(anyhow::anyhow!("Unsupported descriptor: {other}()")),
+        })
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self.body();
+        let checksum = descriptor_checksum(&body).map_err(|_| fmt::Error)?;
+        write!(f, "{body}#{checksum}")
+    }
+}
+
+/// PSBT fields shared by inputs and outputs
+#[derive(Default)]
+struct PsbtFields {
+    bip32_derivation: BTreeMap<bitcoin::secp256k1::PublicKey, KeySource>,
+    tap_key_origins: BTreeMap<XOnlyPublicKey, (Vec<bitcoin::taproot::TapLeafHash>, KeySource)>,
+    redeem_script: Option<ScriptBuf>,
+    witness_script: Option<ScriptBuf>,
+    tap_internal_key: Option<XOnlyPublicKey>,
+}
+
+// Policy compiler
+
+/// Spending policy in the rust-miniscript policy language, e.g.
+/// `or(thresh(2,pk(A),pk(B),pk(C)),and(pk(D),older(12960)))`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    Key(DescriptorKey),
+    After(u32),
+    Older(u32),
+    Sha256(sha256::Hash),
+    And(Vec<Policy>),
+    /// Branches with their relative probability weights
+    Or(Vec<(usize, Policy)>),
+    Thresh(usize, Vec<Policy>),
+}
+
+impl Policy {
+    fn is_timelock(&self) -> bool {
+        matches!(self, Policy::After(_) | Policy::Older(_))
+    }
+
+    /// Compile into a miniscript suitable for `wsh()`
+    pub fn compile(&self) -> Result<Miniscript> {
+        Ok(match self {
+            Policy::Key(key) => Miniscript::Pk(key.clone()),
+            Policy::After(n) => Miniscript::After(*n),
+            Policy::Older(n) => Miniscript::Older(*n),
+            Policy::Sha256(h) => Miniscript::Sha256(*h),
+            Policy::And(subs) => {
+                // Checks that always succeed (timelocks) go last, keys first
+                let mut subs: Vec<&Policy> = subs.iter().collect();
+                subs.sort_by_key(|p| p.is_timelock());
+                let (last, rest) = subs
+                    .split_last()
+                    .ok_or_else(|| anyhow::anyhow!("and() needs arguments"))?;
+                let mut ms = last.compile()?;
+                for sub in rest.iter().rev() {
+                    ms = Miniscript::AndV(
+                        Box::new(Miniscript::Verify(Box::new(sub.compile()?))),
+                        Box::new(ms),
+                    );
+                }
+                ms
+            }
+            Policy::Or(subs) => {
+                // Most likely branch first
+                let mut subs: Vec<&(usize, Policy)> = subs.iter().collect();
+                subs.sort_by_key(|(weight, _)| std::cmp::Reverse(*weight));
+                let (last, rest) = subs
+                    .split_last()
+                    .ok_or_else(|| anyhow::anyhow!("or() needs arguments"))?;
+                let mut ms = last.1.compile()?;
+                for (_, sub) in rest.iter().rev() {
+                    ms = Miniscript::OrI(Box::new(sub.compile()?), Box::new(ms));
+                }
+                ms
+            }
+            Policy::Thresh(k, subs) => {
+                let n = subs.len();
+                if *k == 0 || *k > n {
+                    return Err(anyhow::anyhow!("Invalid thresh({k}) over {n} policies"));
+                }
+                let keys: Option<Vec<DescriptorKey>> = subs
+                    .iter()
+                    .map(|p| match p {
+                        Policy::Key(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect();
+
+                match keys {
+                    Some(keys) if n <= MAX_MULTISIG_KEYS => Miniscript::Multi(*k, keys),
+                    _ if *k == n => Policy::And(subs.clone()).compile()?,
+                    _ if *k == 1 => {
+                        Policy::Or(subs.iter().map(|p| (1, p.clone())).collect()).compile()?
+                    }
+                    _ if n <= MAX_THRESH_EXPANSION => {
+                        // k-of-n over mixed policies: or() of every k-sized and()
+                        let branches = combinations(n, *k)
+                            .into_iter()
+                            .map(|c| {
+                                (
+                                    1,
+                                    Policy::And(c.into_iter().map(|i| subs[i].clone()).collect()),
+                                )
+                            })
+                            .collect();
+                        Policy::Or(branches).compile()?
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "thresh({k}) over {n} non-key policies is not supported"
+                        ))
+                    }
+                }
+            }
+        })
+    }
+
+    /// Compile into a `wsh()` descriptor
+    pub fn compile_descriptor(&self) -> Result<Descriptor> {
+        Ok(Descriptor::Wsh(self.compile()?))
+    }
+}
+
+/// Every k-sized subset of 0..n, each in ascending order
+pub(crate) fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    if n < k {
+        return vec![];
+    }
+    // Either take the last element or don't
+    let mut with_last = combinations(n - 1, k - 1);
+    for c in &mut with_last {
+        c.push(n - 1);
+    }
+    let mut result = combinations(n - 1, k);
+    result.extend(with_last);
+    result
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let (name, args) = split_call(&s)?;
+        let subs = |args: &[&str]| -> Result<Vec<Policy>> {
+            args.iter().map(|a| Policy::from_str(a)).collect()
+        };
+
+        Ok(match name {
+            "pk" => {
+                expect_args(name, &args, 1)?;
+                Policy::Key(DescriptorKey::from_str(args[0])?)
+            }
+            "after" => {
+                expect_args(name, &args, 1)?;
+                Policy::After(args[0].parse()?)
+            }
+            "older" => {
+                expect_args(name, &args, 1)?;
+                Policy::Older(args[0].parse()?)
+            }
+            "sha256" => {
+                expect_args(name, &args, 1)?;
+                Policy::Sha256(sha256::Hash::from_str(args[0])?)
+            }
+            "and" => {
+                expect_args(name, &args, 2)?;
+                Policy::And(subs(&args)?)
+            }
+            "or" => {
+                expect_args(name, &args, 2)?;
+                let branches = args
+                    .iter()
+                    .map(|a| match a.split_once('@') {
+                        Some((weight, policy)) if !weight.contains('(') => {
+                            Ok((weight.parse()?, Policy::from_str(policy)?))
+                        }
+                        _ => Ok((1, Policy::from_str(a)?)),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Policy::Or(branches)
+            }
+            "thresh" => {
+                let (k, rest) = args
+                    .split_first()
+                    .ok_or_else(|| anyhow::anyhow!("thresh() needs a threshold"))?;
+                Policy::Thresh(k.parse()?, subs(rest)?)
+            }
+            other => return Err(anyhow::anyhow!("Unsupported policy fragment: {other}()")),
+        })
+    }
+}
+
+/// Compile a policy string into a `wsh()` descriptor
+pub fn compile_policy(policy: &str) -> Result<Descriptor> {
+    Policy::from_str(policy)?.compile_descriptor()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XPRV: &str = "tprv8ZgxMBicQKsPd7Uf69XL1XwhmjHopUGep8GuEiJDZmbQz6o58LninorQAfcKZWARbtRtfnLcJ5MQ2AtHcQJCCRUcMRvmDUjyEmNUWwx8UbK";
+
+    fn keys(n: u8) -> Vec<String> {
+        let secp = Secp256k1::new();
+        (1..=n)
+            .map(|i| {
+                let sk = bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+                PublicKey::new(sk.public_key(&secp)).to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksum_matches_bitcoin_core() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert!(strip_checksum("raw(deadbeef)#89f8spxm").is_ok());
+        assert!(strip_checksum("raw(deadbeef)#89f8spxn").is_err());
+    }
+
+    #[test]
+    fn descriptor_round_trip() {
+        let secp = Secp256k1::new();
+        let desc = format!("wpkh([d34db33f/84'/1'/0']{XPRV}/0/*)");
+        let parsed = Descriptor::from_str(&desc).unwrap();
+        assert!(parsed.is_ranged());
+        assert!(parsed.secret_key().is_some());
+
+        let reparsed = Descriptor::from_str(&parsed.to_string()).unwrap();
+        assert_eq!(parsed, reparsed);
+
+        let public = parsed.to_public(&secp);
+        assert!(public.secret_key().is_none());
+        assert_eq!(
+            parsed.address(3, Network::Regtest, &secp).unwrap(),
+            public.address(3, Network::Regtest, &secp).unwrap()
+        );
+    }
+
+    #[test]
+    fn sortedmulti_is_order_independent() {
+        let secp = Secp256k1::new();
+        let k = keys(3);
+        let a = Descriptor::from_str(&format!("wsh(sortedmulti(2,{},{},{}))", k[0], k[1], k[2]))
+            .unwrap();
+        let b = Descriptor::from_str(&format!("wsh(sortedmulti(2,{},{},{}))", k[2], k[0], k[1]))
+            .unwrap();
+        assert_eq!(
+            a.script_pubkey(0, &secp).unwrap(),
+            b.script_pubkey(0, &secp).unwrap()
+        );
+    }
+
+    #[test]
+    fn compiles_recovery_policy() {
+        let secp = Secp256k1::new();
+        let k = keys(4);
+        let policy = format!(
+            "or(9@thresh(2,pk({}),pk({}),pk({})),1@and(older(12960),pk({})))",
+            k[0], k[1], k[2], k[3]
+        );
+        let desc = compile_policy(&policy).unwrap();
+        assert_eq!(
+            desc.to_string().split('#').next().unwrap(),
+            format!(
+                "wsh(or_i(multi(2,{},{},{}),and_v(v:pk({}),older(12960))))",
+                k[0], k[1], k[2], k[3]
+            )
+        );
+
+        // Recovery key alone works only once the timelock has passed
+        let Descriptor::Wsh(ms) = &desc else {
+            panic!("expected wsh")
+        };
+        let recovery = PublicKey::from_str(&k[3]).unwrap();
+        let mut satisfier = Satisfier::default();
+        satisfier.signatures.insert(recovery, vec![0x30; 72]);
+        assert!(ms.satisfy(0, &secp, &satisfier).unwrap().is_none());
+        satisfier.relative_locktime = 12960;
+        let stack = ms.satisfy(0, &secp, &satisfier).unwrap().unwrap();
+        assert_eq!(stack, vec![vec![0x30; 72], vec![]]);
+
+        // Two cosigners take the multisig branch
+        let mut satisfier = Satisfier::default();
+        for key in &k[..2] {
+            satisfier
+                .signatures
+                .insert(PublicKey::from_str(key).unwrap(), vec![0x30; 72]);
+        }
+        let witness = desc.satisfy(0, &secp, &satisfier).unwrap().unwrap();
+        // dummy, 2 signatures, branch selector, witness script
+        assert_eq!(witness.len(), 5);
+    }
+
+    #[test]
+    fn max_satisfaction_weight_covers_every_branch() {
+        let secp = Secp256k1::new();
+        let k = keys(4);
+        let policy = format!(
+            "or(9@thresh(2,pk({}),pk({}),pk({})),1@and(older(12960),pk({})))",
+            k[0], k[1], k[2], k[3]
+        );
+        let desc = compile_policy(&policy).unwrap();
+        let max = desc.max_satisfaction_weight(&secp).unwrap();
+        let input_weight = |witness: Witness| Weight::from_wu(41 * 4 + witness.size() as u64);
+
+        let mut cosigners = Satisfier::default();
+        for key in &k[..2] {
+            cosigners
+                .signatures
+                .insert(PublicKey::from_str(key).unwrap(), vec![0x30; 73]);
+        }
+        let multisig = desc.satisfy(0, &secp, &cosigners).unwrap().unwrap();
+        // The multisig branch is the heaviest, so the estimate is exact for it
+        assert_eq!(input_weight(multisig), max);
+
+        let mut recovery = Satisfier {
+            relative_locktime: 12960,
+            ..Default::default()
+        };
+        recovery
+            .signatures
+            .insert(PublicKey::from_str(&k[3]).unwrap(), vec![0x30; 73]);
+        let recovery = desc.satisfy(0, &secp, &recovery).unwrap().unwrap();
+        assert!(input_weight(recovery) < max);
+
+        // Well above the P2WPKH estimate coin selection would otherwise use
+        assert!(max > crate::utxo_manager::coin_selection::P2WPKH_INPUT_WEIGHT);
+    }
+
+    #[test]
+    fn mixed_thresh_expands() {
+        let k = keys(2);
+        let policy = format!("thresh(2,pk({}),pk({}),older(144))", k[0], k[1]);
+        let ms = Policy::from_str(&policy).unwrap().compile().unwrap();
+        assert!(matches!(ms, Miniscript::OrI(_, _)));
+    }
+
+    #[test]
+    fn rejects_hardened_after_xpub() {
+        let secp = Secp256k1::new();
+        let xprv = Xpriv::from_str(XPRV).unwrap();
+        let xpub = Xpub::from_priv(&secp, &xprv);
+        assert!(DescriptorKey::from_str(&format!("{xpub}/0'/*")).is_err());
+        assert!(DescriptorKey::from_str(&format!("{xpub}/0/*")).is_ok());
+    }
+}
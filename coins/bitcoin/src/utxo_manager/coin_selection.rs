@@ -0,0 +1,756 @@
+// Coin selection algorithms
+use super::TrackedUtxo;
+use anyhow::Result;
+use bitcoin::secp256k1::rand::{seq::SliceRandom, thread_rng, Rng};
+use bitcoin::{FeeRate, Script, ScriptBuf, Weight};
+
+/// Weight of an empty segwit transaction (version, locktime, counts, marker and flag)
+pub const TX_OVERHEAD_WEIGHT: Weight = Weight::from_wu(4 * 10 + 2);
+
+/// Weight of a P2WPKH output, used as the default change output
+pub const P2WPKH_OUTPUT_WEIGHT: Weight = Weight::from_wu(4 * 31);
+
+/// Weight of spending a P2WPKH output
+pub const P2WPKH_INPUT_WEIGHT: Weight = Weight::from_wu(41 * 4 + 108);
+
+/// Outputs below this value are not created as change
+pub const DEFAULT_DUST_LIMIT: u64 = 546;
+
+/// Number of branches Branch-and-Bound explores before giving up
+const BNB_TOTAL_TRIES: usize = 100_000;
+
+/// Number of random passes the knapsack solver makes
+const KNAPSACK_ITERATIONS: usize = 1_000;
+
+/// Estimated weight of spending an output with the given script pubkey
+pub fn input_weight(script_pubkey: &Script) -> Weight {
+    // outpoint (36) + sequence (4) + empty script_sig length (1)
+    const BASE: u64 = 41 * 4;
+    // witness item count + DER signature + compressed pubkey
+    const P2WPKH_WITNESS: u64 = 1 + 1 + 72 + 1 + 33;
+
+    let wu = if script_pubkey.is_p2tr() {
+        // witness item count + schnorr signature
+        BASE + 1 + 1 + 64
+    } else if script_pubkey.is_p2pkh() {
+        // script_sig: signature push + pubkey push
+        BASE + 4 * (1 + 72 + 1 + 33)
+    } else if script_pubkey.is_p2sh() {
+        // assume P2SH-P2WPKH: redeem script push in script_sig
+        BASE + 4 * 23 + P2WPKH_WITNESS
+    } else {
+        BASE + P2WPKH_WITNESS
+    };
+    Weight::from_wu(wu)
+}
+
+/// Weight of an output paying to the given script pubkey
+pub fn output_weight(script_pubkey: &Script) -> Weight {
+    let len = script_pubkey.len() as u64;
+    let len_prefix = if len < 0xfd { 1 } else { 3 };
+    Weight::from_wu(4 * (8 + len_prefix + len))
+}
+
+/// Fee in satoshis for the given weight, rounded up
+pub fn fee_for_weight(fee_rate: FeeRate, weight: Weight) -> u64 {
+    fee_rate
+        .fee_wu(weight)
+        .map(|a| a.to_sat())
+        .unwrap_or(u64::MAX)
+}
+
+/// Parameters shared by every selection algorithm
+#[derive(Debug, Clone)]
+pub struct CoinSelectionParams {
+    /// Fee rate the transaction will pay
+    pub fee_rate: FeeRate,
+    /// Fee rate we expect to pay when spending outputs in the future
+    pub long_term_fee_rate: FeeRate,
+    /// Weight of the transaction excluding inputs and change
+    pub base_weight: Weight,
+    /// Weight of the change output
+    pub change_output_weight: Weight,
+    /// Weight of spending the change output later
+    pub change_spend_weight: Weight,
+    /// Smallest change output we are willing to create
+    pub dust_limit: u64,
+    /// Weight of every input when it can't be inferred from the script, e.g. P2WSH multisig
+    pub input_weight: Option<Weight>,
+}
+
+impl CoinSelectionParams {
+    /// Parameters for a transaction paying the given recipient scripts with P2WPKH change
+    pub fn new(fee_rate: FeeRate, recipients: &[ScriptBuf]) -> Self {
+        let base_weight = recipients
+            .iter()
+            .fold(TX_OVERHEAD_WEIGHT, |w, s| w + output_weight(s));
+
+        Self {
+            fee_rate,
+            long_term_fee_rate: FeeRate::from_sat_per_vb_unchecked(10),
+            base_weight,
+            change_output_weight: P2WPKH_OUTPUT_WEIGHT,
+            change_spend_weight: P2WPKH_INPUT_WEIGHT,
+            dust_limit: DEFAULT_DUST_LIMIT,
+            input_weight: None,
+        }
+    }
+
+    /// Use a different change script than P2WPKH
+    pub fn with_change_script(mut self, change_script: &Script) -> Self {
+        self.change_output_weight = output_weight(change_script);
+        self.change_spend_weight = input_weight(change_script);
+        self
+    }
+
+    /// Spend every input (and the change output later) at a fixed weight
+    pub fn with_input_weight(mut self, weight: Weight) -> Self {
+        self.input_weight = Some(weight);
+        self.change_spend_weight = weight;
+        self
+    }
+
+    /// Weight of spending the UTXO
+    pub fn spend_weight(&self, utxo: &TrackedUtxo) -> Weight {
+        self.input_weight
+            .unwrap_or_else(|| input_weight(&utxo.txout.script_pubkey))
+    }
+
+    /// Override the long-term fee rate used for the waste metric
+    pub fn with_long_term_fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.long_term_fee_rate = fee_rate;
+        self
+    }
+
+    /// Cost of creating a change output now and spending it later
+    pub fn cost_of_change(&self) -> u64 {
+        fee_for_weight(self.fee_rate, self.change_output_weight)
+            + fee_for_weight(self.long_term_fee_rate, self.change_spend_weight)
+    }
+
+    /// Fee for everything in the transaction except inputs and change
+    pub fn base_fee(&self) -> u64 {
+        fee_for_weight(self.fee_rate, self.base_weight)
+    }
+
+    /// Value of the UTXO minus the fee to spend it at the current fee rate
+    pub fn effective_value(&self, utxo: &TrackedUtxo) -> i64 {
+        utxo.txout.value.to_sat() as i64
+            - fee_for_weight(self.fee_rate, self.spend_weight(utxo)) as i64
+    }
+
+    /// Fee paid for the input now minus what it would cost at the long-term rate
+    fn input_waste(&self, utxo: &TrackedUtxo) -> i64 {
+        let weight = self.spend_weight(utxo);
+        fee_for_weight(self.fee_rate, weight) as i64
+            - fee_for_weight(self.long_term_fee_rate, weight) as i64
+    }
+}
+
+/// Outcome of a coin selection run
+#[derive(Debug, Clone)]
+pub struct SelectionResult {
+    /// Selected UTXOs
+    pub selected: Vec<TrackedUtxo>,
+    /// Sum of the selected UTXO values
+    pub total_input: u64,
+    /// Amount paid to recipients
+    pub target: u64,
+    /// Absolute fee of the transaction
+    pub fee: u64,
+    /// Change returned to the wallet, if any
+    pub change: Option<u64>,
+    /// Waste metric: timing cost of inputs plus change cost or excess
+    pub waste: i64,
+    /// Name of the algorithm that produced this selection
+    pub algorithm: &'static str,
+}
+
+/// A pluggable coin selection algorithm
+pub trait CoinSelectionAlgorithm: Send + Sync {
+    /// Short algorithm name, reported in [`SelectionResult::algorithm`]
+    fn name(&self) -> &'static str;
+
+    /// Pick a subset of `candidates` whose effective value covers `target` plus the base fee.
+    /// Returns `None` if this algorithm cannot find a solution.
+    fn select(
+        &self,
+        candidates: &[TrackedUtxo],
+        target: u64,
+        params: &CoinSelectionParams,
+    ) -> Option<Vec<TrackedUtxo>>;
+}
+
+/// Built-in selection strategies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionStrategy {
+    /// Exact match without change, Bitcoin Core's Branch-and-Bound
+    BranchAndBound,
+    /// Randomised subset-sum approximation
+    Knapsack,
+    /// Spend the largest UTXOs first
+    LargestFirst,
+    /// Run every algorithm and keep the lowest waste
+    #[default]
+    Auto,
+}
+
+/// Bitcoin Core's Branch-and-Bound search for a changeless solution
+#[derive(Debug, Clone)]
+pub struct BranchAndBound {
+    pub max_tries: usize,
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        Self {
+            max_tries: BNB_TOTAL_TRIES,
+        }
+    }
+}
+
+impl CoinSelectionAlgorithm for BranchAndBound {
+    fn name(&self) -> &'static str {
+        "branch_and_bound"
+    }
+
+    fn select(
+        &self,
+        candidates: &[TrackedUtxo],
+        target: u64,
+        params: &CoinSelectionParams,
+    ) -> Option<Vec<TrackedUtxo>> {
+        let mut pool: Vec<(i64, i64, &TrackedUtxo)> = candidates
+            .iter()
+            .map(|u| (params.effective_value(u), params.input_waste(u), u))
+            .filter(|(value, _, _)| *value > 0)
+            .collect();
+        pool.sort_by_key(|entry| std::cmp::Reverse(entry.0));
+
+        let selection_target = (target + params.base_fee()) as i64;
+        let cost_of_change = params.cost_of_change() as i64;
+        let feerate_high = params.fee_rate > params.long_term_fee_rate;
+
+        let mut available: i64 = pool.iter().map(|(v, _, _)| v).sum();
+        if available < selection_target {
+            return None;
+        }
+
+        let mut current: Vec<usize> = Vec::new();
+        let mut current_value = 0i64;
+        let mut current_waste = 0i64;
+        let mut best: Option<Vec<usize>> = None;
+        let mut best_waste = i64::MAX;
+        let mut index = 0usize;
+
+        for _ in 0..self.max_tries {
+            let mut backtrack = false;
+            if current_value + available < selection_target
+                || current_value > selection_target + cost_of_change
+                || (feerate_high && current_waste > best_waste)
+            {
+                backtrack = true;
+            } else if current_value >= selection_target {
+                let waste = current_waste + (current_value - selection_target);
+                if waste <= best_waste {
+                    best = Some(current.clone());
+                    best_waste = waste;
+                }
+                backtrack = true;
+            }
+
+            if backtrack {
+                let Some(&last) = current.last() else {
+                    break;
+                };
+                // Give back the lookahead of every UTXO skipped after the last inclusion
+                while index > last + 1 {
+                    index -= 1;
+                    available += pool[index].0;
+                }
+                // Now explore the branch that omits the last included UTXO
+                current.pop();
+                current_value -= pool[last].0;
+                current_waste -= pool[last].1;
+                index = last + 1;
+                continue;
+            }
+
+            let (value, waste, _) = pool[index];
+            available -= value;
+            // Skip UTXOs equivalent to an omitted predecessor, that branch was already explored
+            let duplicate_of_omitted = index > 0
+                && current.last().is_some_and(|&last| last != index - 1)
+                && pool[index - 1].0 == value
+                && pool[index - 1].1 == waste;
+            if !duplicate_of_omitted {
+                current.push(index);
+                current_value += value;
+                current_waste += waste;
+            }
+            index += 1;
+        }
+
+        best.map(|indexes| indexes.into_iter().map(|i| pool[i].2.clone()).collect())
+    }
+}
+
+/// Bitcoin Core's knapsack solver, aiming for a change output of at least `cost_of_change`
+#[derive(Debug, Clone)]
+pub struct Knapsack {
+    pub iterations: usize,
+}
+
+impl Default for Knapsack {
+    fn default() -> Self {
+        Self {
+            iterations: KNAPSACK_ITERATIONS,
+        }
+    }
+}
+
+impl Knapsack {
+    /// Randomised search for the subset closest to (but not below) `target`
+    fn approximate_best_subset<R: Rng>(
+        &self,
+        rng: &mut R,
+        values: &[i64],
+        total: i64,
+        target: i64,
+    ) -> (Vec<bool>, i64) {
+        let mut best = vec![true; values.len()];
+        let mut best_value = total;
+
+        for _ in 0..self.iterations {
+            if best_value == target {
+                break;
+            }
+            let mut included = vec![false; values.len()];
+            let mut sum = 0i64;
+            let mut reached = false;
+            for pass in 0..2 {
+                if reached {
+                    break;
+                }
+                for i in 0..values.len() {
+                    // First pass picks randomly, second pass fills in what the first skipped
+                    let take = if pass == 0 {
+                        rng.gen_bool(0.5)
+                    } else {
+                        !included[i]
+                    };
+                    if !take {
+                        continue;
+                    }
+                    sum += values[i];
+                    included[i] = true;
+                    if sum >= target {
+                        reached = true;
+                        if sum < best_value {
+                            best_value = sum;
+                            best = included.clone();
+                        }
+                        sum -= values[i];
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+        (best, best_value)
+    }
+}
+
+impl CoinSelectionAlgorithm for Knapsack {
+    fn name(&self) -> &'static str {
+        "knapsack"
+    }
+
+    fn select(
+        &self,
+        candidates: &[TrackedUtxo],
+        target: u64,
+        params: &CoinSelectionParams,
+    ) -> Option<Vec<TrackedUtxo>> {
+        let selection_target = (target + params.base_fee()) as i64;
+        let min_change = (params.cost_of_change() + params.dust_limit) as i64;
+
+        let mut pool: Vec<(i64, &TrackedUtxo)> = candidates
+            .iter()
+            .map(|u| (params.effective_value(u), u))
+            .filter(|(value, _)| *value > 0)
+            .collect();
+        pool.shuffle(&mut thread_rng());
+
+        let mut applicable: Vec<(i64, &TrackedUtxo)> = Vec::new();
+        let mut lowest_larger: Option<(i64, &TrackedUtxo)> = None;
+        let mut applicable_total = 0i64;
+
+        for (value, utxo) in pool {
+            if value == selection_target {
+                return Some(vec![utxo.clone()]);
+            } else if value < selection_target + min_change {
+                applicable.push((value, utxo));
+                applicable_total += value;
+            } else if lowest_larger.is_none_or(|(v, _)| value < v) {
+                lowest_larger = Some((value, utxo));
+            }
+        }
+
+        if applicable_total == selection_target {
+            return Some(applicable.into_iter().map(|(_, u)| u.clone()).collect());
+        }
+        if applicable_total < selection_target {
+            return lowest_larger.map(|(_, u)| vec![u.clone()]);
+        }
+
+        applicable.sort_by_key(|entry| std::cmp::Reverse(entry.0));
+        let values: Vec<i64> = applicable.iter().map(|(v, _)| *v).collect();
+        let mut rng = thread_rng();
+
+        let (mut best, mut best_value) =
+            self.approximate_best_subset(&mut rng, &values, applicable_total, selection_target);
+        if best_value != selection_target && applicable_total >= selection_target + min_change {
+            (best, best_value) = self.approximate_best_subset(
+                &mut rng,
+                &values,
+                applicable_total,
+                selection_target + min_change,
+            );
+        }
+
+        if let Some((larger_value, larger)) = lowest_larger {
+            if (best_value != selection_target && best_value < selection_target + min_change)
+                || larger_value <= best_value
+            {
+                return Some(vec![larger.clone()]);
+            }
+        }
+
+        Some(
+            applicable
+                .into_iter()
+                .zip(best)
+                .filter(|(_, included)| *included)
+                .map(|((_, u), _)| u.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Spend the largest UTXOs until the target is covered
+#[derive(Debug, Clone, Default)]
+pub struct LargestFirst;
+
+impl CoinSelectionAlgorithm for LargestFirst {
+    fn name(&self) -> &'static str {
+        "largest_first"
+    }
+
+    fn select(
+        &self,
+        candidates: &[TrackedUtxo],
+        target: u64,
+        params: &CoinSelectionParams,
+    ) -> Option<Vec<TrackedUtxo>> {
+        let selection_target = (target + params.base_fee()) as i64;
+
+        let mut pool: Vec<(i64, &TrackedUtxo)> = candidates
+            .iter()
+            .map(|u| (params.effective_value(u), u))
+            .filter(|(value, _)| *value > 0)
+            .collect();
+        pool.sort_by_key(|entry| std::cmp::Reverse(entry.0));
+
+        let mut selected = Vec::new();
+        let mut total = 0i64;
+        for (value, utxo) in pool {
+            selected.push(utxo.clone());
+            total += value;
+            if total >= selection_target {
+                return Some(selected);
+            }
+        }
+        None
+    }
+}
+
+/// Compute fee, change and waste for a set of selected UTXOs
+pub fn evaluate_selection(
+    selected: Vec<TrackedUtxo>,
+    target: u64,
+    params: &CoinSelectionParams,
+    algorithm: &'static str,
+) -> Result<SelectionResult> {
+    let total_input: u64 = selected.iter().map(|u| u.txout.value.to_sat()).sum();
+    let input_weight = selected
+        .iter()
+        .fold(Weight::ZERO, |w, u| w + params.spend_weight(u));
+    let fee_without_change = fee_for_weight(params.fee_rate, params.base_weight + input_weight);
+
+    let excess = total_input
+        .checked_sub(target + fee_without_change)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Insufficient funds. Have: {} sats, Need: {} sats",
+                total_input,
+                target + fee_without_change
+            )
+        })?;
+
+    let timing_waste: i64 = selected.iter().map(|u| params.input_waste(u)).sum();
+    let change_fee = fee_for_weight(params.fee_rate, params.change_output_weight);
+    let cost_of_change = params.cost_of_change();
+
+    let (fee, change, waste) = match excess.checked_sub(change_fee) {
+        Some(change) if excess > cost_of_change && change >= params.dust_limit => (
+            fee_without_change + change_fee,
+            Some(change),
+            timing_waste + cost_of_change as i64,
+        ),
+        // Change would be dust or cost more than it is worth: give the excess to the miners
+        _ => (
+            fee_without_change + excess,
+            None,
+            timing_waste + excess as i64,
+        ),
+    };
+
+    Ok(SelectionResult {
+        selected,
+        total_input,
+        target,
+        fee,
+        change,
+        waste,
+        algorithm,
+    })
+}
+
+/// Run a single selection algorithm
+pub fn select_with(
+    algorithm: &dyn CoinSelectionAlgorithm,
+    candidates: &[TrackedUtxo],
+    target: u64,
+    params: &CoinSelectionParams,
+) -> Result<SelectionResult> {
+    let selected = algorithm
+        .select(candidates, target, params)
+        .ok_or_else(|| {
+            let available: u64 = candidates.iter().map(|u| u.txout.value.to_sat()).sum();
+            anyhow::anyhow!(
+                "Insufficient funds for {}: have {} sats, need {} sats plus fees",
+                algorithm.name(),
+                available,
+                target
+            )
+        })?;
+    evaluate_selection(selected, target, params, algorithm.name())
+}
+
+/// Select coins using one of the built-in strategies
+pub fn select_coins(
+    candidates: &[TrackedUtxo],
+    target: u64,
+    params: &CoinSelectionParams,
+    strategy: SelectionStrategy,
+) -> Result<SelectionResult> {
+    match strategy {
+        SelectionStrategy::BranchAndBound => {
+            select_with(&BranchAndBound::default(), candidates, target, params)
+        }
+        SelectionStrategy::Knapsack => {
+            select_with(&Knapsack::default(), candidates, target, params)
+        }
+        SelectionStrategy::LargestFirst => select_with(&LargestFirst, candidates, target, params),
+        SelectionStrategy::Auto => {
+            let algorithms: [&dyn CoinSelectionAlgorithm; 3] = [
+                &BranchAndBound::default(),
+                &Knapsack::default(),
+                &LargestFirst,
+            ];
+
+            let mut best: Option<SelectionResult> = None;
+            let mut last_error = None;
+            for algorithm in algorithms {
+                match select_with(algorithm, candidates, target, params) {
+                    Ok(result) => {
+                        if best.as_ref().is_none_or(|b| result.waste < b.waste) {
+                            best = Some(result);
+                        }
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            best.ok_or_else(|| last_error.unwrap_or_else(|| anyhow::anyhow!("No UTXOs available")))
+        }
+    }
+}
+
+/// Select coins that always include `required`, adding candidates only if the required coins
+/// don't cover the target and fee
+pub fn select_coins_including(
+    required: Vec<TrackedUtxo>,
+    candidates: &[TrackedUtxo],
+    target: u64,
+    params: &CoinSelectionParams,
+    strategy: SelectionStrategy,
+) -> Result<SelectionResult> {
+    if required.is_empty() {
+        return select_coins(candidates, target, params, strategy);
+    }
+    if let Ok(result) = evaluate_selection(required.clone(), target, params, "manual") {
+        return Ok(result);
+    }
+
+    // Select the rest as if the required coins were part of the transaction's base, then
+    // account for everything together
+    let required_value: u64 = required.iter().map(|u| u.txout.value.to_sat()).sum();
+    let mut rest_params = params.clone();
+    rest_params.base_weight += required
+        .iter()
+        .fold(Weight::ZERO, |w, u| w + params.spend_weight(u));
+    let rest = select_coins(
+        candidates,
+        target.saturating_sub(required_value),
+        &rest_params,
+        strategy,
+    )?;
+    let selected = required.into_iter().chain(rest.selected).collect();
+    evaluate_selection(selected, target, params, rest.algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, OutPoint, TxOut, Txid, WPubkeyHash};
+
+    fn utxo(vout: u32, value: u64) -> TrackedUtxo {
+        TrackedUtxo {
+            outpoint: OutPoint::new(Txid::all_zeros(), vout),
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            },
+            confirmations: 6,
+        }
+    }
+
+    fn params(sat_vb: u64) -> CoinSelectionParams {
+        let recipient = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        CoinSelectionParams::new(FeeRate::from_sat_per_vb_unchecked(sat_vb), &[recipient])
+    }
+
+    fn assert_balanced(result: &SelectionResult) {
+        assert_eq!(
+            result.total_input,
+            result.target + result.fee + result.change.unwrap_or(0)
+        );
+    }
+
+    #[test]
+    fn bnb_finds_changeless_solution() {
+        let params = params(1);
+        let base_fee = params.base_fee();
+        let input_fee = fee_for_weight(
+            params.fee_rate,
+            input_weight(&utxo(0, 0).txout.script_pubkey),
+        );
+        // Two UTXOs that exactly pay 30_000 plus fees
+        let exact_a = 10_000 + input_fee;
+        let exact_b = 20_000 + input_fee + base_fee;
+        let candidates = vec![utxo(0, exact_a), utxo(1, exact_b), utxo(2, 1_000_000)];
+
+        let result = select_with(&BranchAndBound::default(), &candidates, 30_000, &params).unwrap();
+        assert_eq!(result.selected.len(), 2);
+        assert!(result.change.is_none());
+        assert_balanced(&result);
+    }
+
+    #[test]
+    fn bnb_fails_without_exact_match() {
+        let candidates = vec![utxo(0, 1_000_000)];
+        assert!(select_with(&BranchAndBound::default(), &candidates, 30_000, &params(1)).is_err());
+    }
+
+    #[test]
+    fn largest_first_creates_change() {
+        let candidates = vec![utxo(0, 50_000), utxo(1, 200_000), utxo(2, 10_000)];
+        let result = select_with(&LargestFirst, &candidates, 100_000, &params(5)).unwrap();
+        assert_eq!(result.selected.len(), 1);
+        assert_eq!(result.selected[0].txout.value.to_sat(), 200_000);
+        assert!(result.change.is_some());
+        assert_balanced(&result);
+    }
+
+    #[test]
+    fn knapsack_covers_target() {
+        let candidates: Vec<_> = (0..20).map(|i| utxo(i, 5_000 + 1_000 * i as u64)).collect();
+        let result = select_with(&Knapsack::default(), &candidates, 60_000, &params(2)).unwrap();
+        assert!(result.total_input >= 60_000 + result.fee);
+        assert_balanced(&result);
+    }
+
+    #[test]
+    fn dust_change_goes_to_fee() {
+        let params = params(1);
+        let input_fee = fee_for_weight(
+            params.fee_rate,
+            input_weight(&utxo(0, 0).txout.script_pubkey),
+        );
+        let value = 10_000 + params.base_fee() + input_fee + 100;
+        let result = select_with(&LargestFirst, &[utxo(0, value)], 10_000, &params).unwrap();
+        assert!(result.change.is_none());
+        assert_eq!(result.waste, params.input_waste(&result.selected[0]) + 100);
+        assert_balanced(&result);
+    }
+
+    #[test]
+    fn auto_prefers_lowest_waste() {
+        let params = params(1);
+        let input_fee = fee_for_weight(
+            params.fee_rate,
+            input_weight(&utxo(0, 0).txout.script_pubkey),
+        );
+        let exact = 40_000 + input_fee + params.base_fee();
+        let candidates = vec![utxo(0, exact), utxo(1, 500_000)];
+        let result = select_coins(&candidates, 40_000, &params, SelectionStrategy::Auto).unwrap();
+        assert_eq!(result.algorithm, "branch_and_bound");
+        assert!(result.change.is_none());
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let candidates = vec![utxo(0, 1_000)];
+        assert!(select_coins(&candidates, 10_000, &params(1), SelectionStrategy::Auto).is_err());
+    }
+
+    #[test]
+    fn required_coins_are_always_spent() {
+        let candidates = vec![utxo(1, 50_000), utxo(2, 30_000)];
+        // Enough on its own: nothing else is added
+        let result = select_coins_including(
+            vec![utxo(0, 40_000)],
+            &candidates,
+            20_000,
+            &params(2),
+            SelectionStrategy::Auto,
+        )
+        .unwrap();
+        assert_eq!(result.selected.len(), 1);
+        assert_balanced(&result);
+
+        // Short: the candidates top it up and the required coin stays first
+        let result = select_coins_including(
+            vec![utxo(0, 10_000)],
+            &candidates,
+            45_000,
+            &params(2),
+            SelectionStrategy::LargestFirst,
+        )
+        .unwrap();
+        assert_eq!(result.selected[0].outpoint.vout, 0);
+        assert_eq!(result.selected.len(), 2);
+        assert_balanced(&result);
+    }
+}
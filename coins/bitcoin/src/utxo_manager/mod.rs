@@ -1,13 +1,68 @@
-// Simplified UTXO manager
-use bitcoin::{OutPoint, TxOut};
-use std::collections::HashMap;
+// UTXO tracking and coin selection
+pub mod coin_selection;
+
+pub use coin_selection::{
+    evaluate_selection, select_coins, select_coins_including, select_with, BranchAndBound,
+    CoinSelectionAlgorithm, CoinSelectionParams, Knapsack, LargestFirst, SelectionResult,
+    SelectionStrategy,
+};
+
+use crate::ordinals::ProtectedSats;
+use crate::transaction_builder::script_builder::descriptor_checksum;
+use anyhow::Result;
+use bitcoin::{Address, OutPoint, ScriptBuf, TxOut};
+use bitcoincore_rpc::json::{ImportDescriptors, ImportMultiResult, ScanTxOutRequest, Timestamp};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 pub struct UtxoManager {
-    #[allow(dead_code)]
     user_utxos: HashMap<String, HashMap<OutPoint, TrackedUtxo>>,
+    /// Outpoints that must never be selected until unlocked
+    locked: HashSet<OutPoint>,
+    /// Outpoints held by an in-flight transaction, released when the deadline passes
+    reserved: HashMap<OutPoint, Instant>,
+    /// Outpoints coin selection skips; only spent when a caller names them
+    frozen: HashMap<OutPoint, FreezeReason>,
+}
+
+/// Why an output is frozen
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreezeReason {
+    /// Frozen by the user
+    Manual,
+    /// Holds inscriptions or rare sats that would be lost as fees or change
+    ProtectedSats(ProtectedSats),
+}
+
+/// Manual coin control for a send
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoinControl {
+    /// Outpoints that must be spent, frozen ones included; coin selection adds more if they
+    /// don't cover the payment
+    pub include: Vec<OutPoint>,
+    /// Outpoints coin selection must not spend
+    pub exclude: Vec<OutPoint>,
+}
+
+impl CoinControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, outpoint: OutPoint) -> Self {
+        self.include.push(outpoint);
+        self
+    }
+
+    pub fn exclude(mut self, outpoint: OutPoint) -> Self {
+        self.exclude.push(outpoint);
+        self
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TrackedUtxo {
     pub outpoint: OutPoint,
     pub txout: TxOut,
@@ -24,6 +79,343 @@ impl UtxoManager {
     pub fn new() -> Self {
         Self {
             user_utxos: HashMap::new(),
+            locked: HashSet::new(),
+            reserved: HashMap::new(),
+            frozen: HashMap::new(),
+        }
+    }
+
+    /// Replace a user's UTXO set, e.g. after a sync with a chain backend.
+    /// Locks, reservations and freezes on outpoints that are gone are dropped.
+    pub fn update_utxos(&mut self, user_id: &str, utxos: impl IntoIterator<Item = TrackedUtxo>) {
+        let utxos: HashMap<OutPoint, TrackedUtxo> =
+            utxos.into_iter().map(|u| (u.outpoint, u)).collect();
+
+        if let Some(previous) = self.user_utxos.get(user_id) {
+            for outpoint in previous.keys().filter(|o| !utxos.contains_key(o)) {
+                self.locked.remove(outpoint);
+                self.reserved.remove(outpoint);
+                self.frozen.remove(outpoint);
+            }
+        }
+
+        self.user_utxos.insert(user_id.to_string(), utxos);
+    }
+
+    /// Sync a user's UTXOs for the given addresses from a bitcoind node using `scantxoutset`.
+    /// Only confirmed outputs are visible to `scantxoutset`.
+    pub fn sync_from_rpc(
+        &mut self,
+        user_id: &str,
+        client: &Client,
+        addresses: &[Address],
+    ) -> Result<usize> {
+        let utxos = Self::scan_utxos(client, addresses)?;
+        let count = utxos.len();
+        self.update_utxos(user_id, utxos);
+        Ok(count)
+    }
+
+    /// Confirmed outputs paying to the addresses, from `scantxoutset`
+    pub fn scan_utxos(client: &Client, addresses: &[Address]) -> Result<Vec<TrackedUtxo>> {
+        let requests: Vec<ScanTxOutRequest> = addresses
+            .iter()
+            .map(|a| ScanTxOutRequest::Single(format!("addr({a})")))
+            .collect();
+
+        let result = client.scan_tx_out_set_blocking(&requests)?;
+        let tip = match result.height {
+            Some(height) => height,
+            None => client.get_block_count()?,
+        };
+
+        Ok(result
+            .unspents
+            .into_iter()
+            .map(|u| TrackedUtxo {
+                outpoint: OutPoint::new(u.txid, u.vout),
+                txout: TxOut {
+                    value: u.amount,
+                    script_pubkey: u.script_pub_key,
+                },
+                confirmations: (tip + 1).saturating_sub(u.height) as u32,
+            })
+            .collect())
+    }
+
+    /// Unconfirmed outputs paying to the addresses, seen by the watch-only descriptor wallet
+    /// `wallet` on the node. The wallet is created if needed and the addresses are imported
+    /// without a rescan, so only payments arriving after the first import are seen.
+    pub fn mempool_utxos(
+        client: &Client,
+        wallet_client: &Client,
+        wallet: &str,
+        addresses: &[Address],
+    ) -> Result<Vec<TrackedUtxo>> {
+        Self::import_addresses(client, wallet_client, wallet, addresses, Timestamp::Now)?;
+
+        let addresses: Vec<&Address> = addresses.iter().collect();
+        Ok(wallet_client
+            .list_unspent(Some(0), Some(0), Some(&addresses), Some(true), None)?
+            .into_iter()
+            .map(|u| TrackedUtxo {
+                outpoint: OutPoint::new(u.txid, u.vout),
+                txout: TxOut {
+                    value: u.amount,
+                    script_pubkey: u.script_pub_key,
+                },
+                confirmations: 0,
+            })
+            .collect())
+    }
+
+    /// Which of the addresses have ever received a payment, confirmed or not. The addresses
+    /// are imported into the watch-only wallet `wallet` with a full rescan, which can take a
+    /// long time on mainnet the first time an address is seen.
+    pub fn used_addresses(
+        client: &Client,
+        wallet_client: &Client,
+        wallet: &str,
+        addresses: &[Address],
+    ) -> Result<HashSet<Address>> {
+        Self::import_addresses(client, wallet_client, wallet, addresses, Timestamp::Time(0))?;
+
+        let wanted: HashSet<ScriptBuf> = addresses.iter().map(|a| a.script_pubkey()).collect();
+        let received: HashSet<ScriptBuf> = wallet_client
+            .list_received_by_address(None, Some(0), Some(false), Some(true))?
+            .into_iter()
+            .map(|r| r.address.assume_checked().script_pubkey())
+            .filter(|script| wanted.contains(script))
+            .collect();
+        Ok(addresses
+            .iter()
+            .filter(|a| received.contains(&a.script_pubkey()))
+            .cloned()
+            .collect())
+    }
+
+    /// Import `addr()` descriptors into the watch-only wallet `wallet`, creating or loading it
+    /// first, with a single `importdescriptors` call so the node rescans at most once
+    fn import_addresses(
+        client: &Client,
+        wallet_client: &Client,
+        wallet: &str,
+        addresses: &[Address],
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        if !client.list_wallets()?.iter().any(|w| w == wallet)
+            && client.load_wallet(wallet).is_err()
+        {
+            client.create_wallet(wallet, Some(true), Some(true), None, None)?;
+        }
+        if addresses.is_empty() {
+            return Ok(());
         }
+
+        let requests = addresses
+            .iter()
+            .map(|address| {
+                let descriptor = format!("addr({address})");
+                let checksum = descriptor_checksum(&descriptor)?;
+                Ok(ImportDescriptors {
+                    descriptor: format!("{descriptor}#{checksum}"),
+                    timestamp,
+                    active: None,
+                    range: None,
+                    next_index: None,
+                    internal: None,
+                    label: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let results: Vec<ImportMultiResult> =
+            wallet_client.call("importdescriptors", &[serde_json::to_value(requests)?])?;
+        if let Some(error) = results.into_iter().find_map(|r| r.error) {
+            return Err(anyhow::anyhow!("importdescriptors: {}", error.message));
+        }
+        Ok(())
+    }
+
+    /// Track a new output belonging to the user
+    pub fn add_utxo(&mut self, user_id: &str, utxo: TrackedUtxo) {
+        self.user_utxos
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(utxo.outpoint, utxo);
+    }
+
+    /// Forget outputs that have been spent
+    pub fn mark_spent(&mut self, user_id: &str, outpoints: &[OutPoint]) {
+        if let Some(utxos) = self.user_utxos.get_mut(user_id) {
+            for outpoint in outpoints {
+                utxos.remove(outpoint);
+                self.locked.remove(outpoint);
+                self.reserved.remove(outpoint);
+                self.frozen.remove(outpoint);
+            }
+        }
+    }
+
+    /// Bump the confirmation count of every tracked UTXO after new blocks
+    pub fn apply_new_blocks(&mut self, blocks: u32) {
+        for utxo in self.user_utxos.values_mut().flat_map(|m| m.values_mut()) {
+            if utxo.confirmations > 0 {
+                utxo.confirmations += blocks;
+            }
+        }
+    }
+
+    /// All tracked UTXOs for a user, including locked, reserved and frozen ones
+    pub fn get_utxos(&self, user_id: &str) -> Vec<TrackedUtxo> {
+        self.user_utxos
+            .get(user_id)
+            .map(|m| m.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// UTXOs that coin selection may use
+    pub fn spendable_utxos(&self, user_id: &str, min_confirmations: u32) -> Vec<TrackedUtxo> {
+        let now = Instant::now();
+        self.user_utxos
+            .get(user_id)
+            .map(|m| {
+                m.values()
+                    .filter(|u| u.confirmations >= min_confirmations)
+                    .filter(|u| !self.locked.contains(&u.outpoint))
+                    .filter(|u| !self.frozen.contains_key(&u.outpoint))
+                    .filter(|u| {
+                        self.reserved
+                            .get(&u.outpoint)
+                            .is_none_or(|&until| until <= now)
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Confirmed and unconfirmed totals for a user
+    pub fn balance(&self, user_id: &str) -> (u64, u64) {
+        self.get_utxos(user_id)
+            .iter()
+            .fold((0, 0), |(confirmed, unconfirmed), u| {
+                if u.confirmations > 0 {
+                    (confirmed + u.txout.value.to_sat(), unconfirmed)
+                } else {
+                    (confirmed, unconfirmed + u.txout.value.to_sat())
+                }
+            })
+    }
+
+    pub fn lock(&mut self, outpoint: OutPoint) {
+        self.locked.insert(outpoint);
+    }
+
+    pub fn unlock(&mut self, outpoint: &OutPoint) {
+        self.locked.remove(outpoint);
+    }
+
+    pub fn is_locked(&self, outpoint: &OutPoint) -> bool {
+        self.locked.contains(outpoint)
+    }
+
+    /// Keep coin selection away from an output, e.g. one holding an inscription
+    pub fn freeze(&mut self, outpoint: OutPoint, reason: FreezeReason) {
+        self.frozen.insert(outpoint, reason);
+    }
+
+    pub fn unfreeze(&mut self, outpoint: &OutPoint) -> Option<FreezeReason> {
+        self.frozen.remove(outpoint)
+    }
+
+    pub fn is_frozen(&self, outpoint: &OutPoint) -> bool {
+        self.frozen.contains_key(outpoint)
+    }
+
+    /// A user's frozen UTXOs and why they are frozen
+    pub fn frozen_utxos(&self, user_id: &str) -> Vec<(TrackedUtxo, FreezeReason)> {
+        self.get_utxos(user_id)
+            .into_iter()
+            .filter_map(|u| {
+                let reason = self.frozen.get(&u.outpoint)?.clone();
+                Some((u, reason))
+            })
+            .collect()
+    }
+
+    /// Hold outpoints for a pending transaction so concurrent sends don't double-spend them
+    pub fn reserve(&mut self, outpoints: &[OutPoint], ttl: Duration) {
+        let until = Instant::now() + ttl;
+        for outpoint in outpoints {
+            self.reserved.insert(*outpoint, until);
+        }
+    }
+
+    pub fn release(&mut self, outpoints: &[OutPoint]) {
+        for outpoint in outpoints {
+            self.reserved.remove(outpoint);
+        }
+    }
+
+    /// The user's UTXOs at these outpoints, frozen or not. Unknown and locked outpoints
+    /// are errors.
+    pub fn named_utxos(&self, user_id: &str, outpoints: &[OutPoint]) -> Result<Vec<TrackedUtxo>> {
+        let utxos = self.user_utxos.get(user_id);
+        outpoints
+            .iter()
+            .map(|outpoint| {
+                if self.locked.contains(outpoint) {
+                    return Err(anyhow::anyhow!("{outpoint} is locked"));
+                }
+                utxos
+                    .and_then(|u| u.get(outpoint))
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("{outpoint} is not a UTXO of this wallet"))
+            })
+            .collect()
+    }
+
+    /// Select coins from the user's spendable UTXOs and reserve them
+    pub fn select_coins(
+        &mut self,
+        user_id: &str,
+        target: u64,
+        params: &CoinSelectionParams,
+        strategy: SelectionStrategy,
+        reserve_for: Duration,
+    ) -> Result<SelectionResult> {
+        self.select_coins_with(
+            user_id,
+            target,
+            params,
+            strategy,
+            &CoinControl::default(),
+            reserve_for,
+        )
+    }
+
+    /// Like `select_coins`, always spending `control.include` and never `control.exclude`
+    pub fn select_coins_with(
+        &mut self,
+        user_id: &str,
+        target: u64,
+        params: &CoinSelectionParams,
+        strategy: SelectionStrategy,
+        control: &CoinControl,
+        reserve_for: Duration,
+    ) -> Result<SelectionResult> {
+        let required = self.named_utxos(user_id, &control.include)?;
+        let candidates: Vec<TrackedUtxo> = self
+            .spendable_utxos(user_id, 1)
+            .into_iter()
+            .filter(|u| !control.exclude.contains(&u.outpoint))
+            .filter(|u| !control.include.contains(&u.outpoint))
+            .collect();
+
+        let result = select_coins_including(required, &candidates, target, params, strategy)?;
+
+        let outpoints: Vec<OutPoint> = result.selected.iter().map(|u| u.outpoint).collect();
+        self.reserve(&outpoints, reserve_for);
+        Ok(result)
     }
 }
@@ -0,0 +1,309 @@
+// Watch-only accounts: extended public keys in any SLIP-132 encoding, and public descriptors
+use crate::transaction_builder::script_builder::{Descriptor, DescriptorKey, ExtendedKey};
+use crate::AddressType;
+use anyhow::Result;
+use bitcoin::base58;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource, Xpub};
+use bitcoin::Network;
+use std::str::FromStr;
+
+/// BIP32 version bytes `Xpub` decodes, for mainnet and the test networks
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+/// What a watch-only wallet watches
+#[derive(Debug, Clone)]
+pub enum WatchOnlySource {
+    /// One account's receive and change chains, from its extended public key
+    Account {
+        xpub: Xpub,
+        address_type: AddressType,
+        account: u32,
+        /// Master fingerprint and path of the account key, so signers recognise the key
+        /// origins in exported PSBTs. The account key itself when it wasn't given.
+        origin: KeySource,
+    },
+    /// The scripts of a descriptor without private keys
+    Descriptor(Descriptor),
+}
+
+impl WatchOnlySource {
+    /// Parse an account key such as `zpub...` or `[d34db33f/84'/0'/0']xpub...`, or a public
+    /// descriptor such as `wpkh([d34db33f/84'/0'/0']xpub.../0/*)`
+    pub fn parse(source: &str, network: Network) -> Result<Self> {
+        let source = source.trim();
+        if source.contains('(') {
+            let descriptor = Descriptor::from_str(source)?;
+            if descriptor.secret_key().is_some() {
+                return Err(anyhow::anyhow!(
+                    "Descriptor has private keys; use create_wallet_from_descriptor to sign with it"
+                ));
+            }
+            for key in descriptor.keys() {
+                if let DescriptorKey::Extended {
+                    key: ExtendedKey::Public(xpub),
+                    ..
+                } = key
+                {
+                    check_network(xpub, network)?;
+                }
+            }
+            return Ok(WatchOnlySource::Descriptor(descriptor));
+        }
+
+        let (origin, key) = match source.strip_prefix('[') {
+            Some(rest) => {
+                let (origin, key) = rest
+                    .split_once(']')
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated key origin in {source}"))?;
+                let (fingerprint, path) = origin.split_once('/').unwrap_or((origin, ""));
+                let path = DerivationPath::from_str(&format!("m/{path}"))?;
+                (Some((Fingerprint::from_str(fingerprint)?, path)), key)
+            }
+            None => (None, source),
+        };
+        let (xpub, implied_type) = decode_slip132(key)?;
+        check_network(&xpub, network)?;
+        if xpub.depth != 3 {
+            return Err(anyhow::anyhow!(
+                "Expected an account key at depth 3, like m/84'/0'/0', got depth {}",
+                xpub.depth
+            ));
+        }
+
+        let origin_type = origin
+            .as_ref()
+            .and_then(|(_, path)| path.as_ref().first().copied())
+            .and_then(|purpose| {
+                AddressType::ALL
+                    .into_iter()
+                    .find(|t| ChildNumber::from_hardened_idx(t.purpose()).ok() == Some(purpose))
+            });
+        let address_type = match (implied_type, origin_type) {
+            (Some(implied), Some(from_origin)) if implied != from_origin => {
+                return Err(anyhow::anyhow!(
+                    "Key encoding is for {implied:?} addresses but its origin is a {from_origin:?} path"
+                ))
+            }
+            (Some(address_type), _) | (None, Some(address_type)) => address_type,
+            // Plain xpubs are P2PKH accounts unless the origin says otherwise (SLIP-132)
+            (None, None) => AddressType::Legacy,
+        };
+        let account = match xpub.child_number {
+            ChildNumber::Hardened { index } => index,
+            ChildNumber::Normal { .. } => {
+                return Err(anyhow::anyhow!("Account keys are derived with hardening"))
+            }
+        };
+
+        Ok(WatchOnlySource::Account {
+            origin: origin.unwrap_or((xpub.fingerprint(), DerivationPath::master())),
+            xpub,
+            address_type,
+            account,
+        })
+    }
+
+    /// Fingerprint and path recorded as the wallet's key origin
+    pub fn key_origin(&self) -> KeySource {
+        match self {
+            WatchOnlySource::Account { origin, .. } => origin.clone(),
+            WatchOnlySource::Descriptor(descriptor) => descriptor
+                .keys()
+                .into_iter()
+                .find_map(|key| match key {
+                    DescriptorKey::Extended { origin, key, .. } => Some(match (origin, key) {
+                        (Some((fingerprint, _)), _) => *fingerprint,
+                        (None, ExtendedKey::Public(xpub)) => xpub.fingerprint(),
+                        (None, ExtendedKey::Private(_)) => return None,
+                    }),
+                    DescriptorKey::Single { origin, .. } => origin.as_ref().map(|(f, _)| *f),
+                })
+                .map(|fingerprint| (fingerprint, DerivationPath::master()))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The account key, or the descriptor's first extended key
+    pub fn xpub(&self) -> Option<Xpub> {
+        match self {
+            WatchOnlySource::Account { xpub, .. } => Some(*xpub),
+            WatchOnlySource::Descriptor(descriptor) => {
+                descriptor.keys().into_iter().find_map(|key| match key {
+                    DescriptorKey::Extended {
+                        key: ExtendedKey::Public(xpub),
+                        ..
+                    } => Some(*xpub),
+                    _ => None,
+                })
+            }
+        }
+    }
+}
+
+/// Decode an extended public key in any SLIP-132 encoding, with the address type its
+/// version implies
+pub fn decode_slip132(key: &str) -> Result<(Xpub, Option<AddressType>)> {
+    let (version, address_type) = match key.get(..4) {
+        Some("xpub") => (XPUB_VERSION, None),
+        Some("ypub") => (XPUB_VERSION, Some(AddressType::SegwitP2SH)),
+        Some("zpub") => (XPUB_VERSION, Some(AddressType::NativeSegwit)),
+        Some("tpub") => (TPUB_VERSION, None),
+        Some("upub") => (TPUB_VERSION, Some(AddressType::SegwitP2SH)),
+        Some("vpub") => (TPUB_VERSION, Some(AddressType::NativeSegwit)),
+        Some("xprv" | "yprv" | "zprv" | "tprv" | "uprv" | "vprv") => {
+            return Err(anyhow::anyhow!(
+                "Watch-only wallets take extended public keys, not private ones"
+            ))
+        }
+        _ => return Err(anyhow::anyhow!("Unrecognised extended public key")),
+    };
+    let mut data = base58::decode_check(key)?;
+    if data.len() != 78 {
+        return Err(anyhow::anyhow!("Extended public key has the wrong length"));
+    }
+    data[..4].copy_from_slice(&version);
+    Ok((Xpub::decode(&data)?, address_type))
+}
+
+/// Extended keys only distinguish mainnet from the test networks
+fn check_network(xpub: &Xpub, network: Network) -> Result<()> {
+    if (xpub.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(anyhow::anyhow!(
+            "Key is for {}, wallet manager is on {network}",
+            xpub.network
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_builder::psbt_handler;
+    use crate::utxo_manager::{SelectionStrategy, TrackedUtxo};
+    use crate::{BitcoinConfig, BitcoinWalletManager, Keychain, KeychainKind};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Address, Amount, FeeRate, OutPoint, TxOut, Txid};
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    /// BIP84 and BIP49 test vectors for the mnemonic above
+    const ZPUB: &str = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    const YPUB: &str = "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP";
+
+    async fn manager(network: Network) -> BitcoinWalletManager {
+        BitcoinWalletManager::new(BitcoinConfig {
+            network,
+            rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn derives_slip132_accounts() {
+        let manager = manager(Network::Bitcoin).await;
+        let zpub = manager
+            .create_watch_only_wallet("cold", ZPUB)
+            .await
+            .unwrap();
+        assert_eq!(
+            zpub.first_address,
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        assert!(zpub.mnemonic.is_empty());
+        let ypub = manager
+            .create_watch_only_wallet("cold-p2sh", YPUB)
+            .await
+            .unwrap();
+        assert_eq!(ypub.first_address, "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf");
+        // Only the imported account can be derived
+        assert!(manager
+            .get_receive_address("cold", AddressType::Legacy)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("Watch-only"));
+
+        assert!(WatchOnlySource::parse(ZPUB, Network::Testnet).is_err());
+        let conflicting = format!("[73c5da0a/44'/0'/0']{ZPUB}");
+        assert!(WatchOnlySource::parse(&conflicting, Network::Bitcoin).is_err());
+        let descriptor = format!(
+            "wpkh([73c5da0a/84'/0'/0']{}/0/*)",
+            decode_slip132(ZPUB).unwrap().0
+        );
+        let source = WatchOnlySource::parse(&descriptor, Network::Bitcoin).unwrap();
+        assert_eq!(source.key_origin().0.to_string(), "73c5da0a");
+        let wallet = manager
+            .create_watch_only_wallet("cold-descriptor", &descriptor)
+            .await
+            .unwrap();
+        assert_eq!(wallet.first_address, zpub.first_address);
+    }
+
+    #[tokio::test]
+    async fn exports_psbts_the_key_holder_signs() {
+        let manager = manager(Network::Regtest).await;
+        manager
+            .create_wallet("hot", Some(MNEMONIC.to_string()))
+            .await
+            .unwrap();
+        let keychain = Keychain::new(AddressType::NativeSegwit, 0, KeychainKind::External);
+        let path = keychain.account_path().unwrap();
+        let account = manager.vault().xpub("hot", &path).unwrap();
+        let (fingerprint, _) = manager.vault().origin("hot").unwrap();
+        let source = format!("[{fingerprint}/84'/0'/0']{account}");
+
+        let watched = manager
+            .create_watch_only_wallet("watched", &source)
+            .await
+            .unwrap();
+        let hot_address = manager
+            .get_receive_address("hot", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        let watched_next = manager
+            .get_receive_address("watched", AddressType::NativeSegwit)
+            .await
+            .unwrap();
+        assert_eq!(watched_next, hot_address);
+
+        let funded = Address::from_str(&watched.first_address)
+            .unwrap()
+            .assume_checked();
+        manager.utxo_manager().write().await.add_utxo(
+            "watched",
+            TrackedUtxo {
+                outpoint: OutPoint::new(Txid::all_zeros(), 0),
+                txout: TxOut {
+                    value: Amount::from_sat(100_000),
+                    script_pubkey: funded.script_pubkey(),
+                },
+                confirmations: 6,
+            },
+        );
+        let exported = manager
+            .export_unsigned_psbt(
+                "watched",
+                &[(funded.clone(), 40_000)],
+                FeeRate::from_sat_per_vb_unchecked(2),
+                SelectionStrategy::default(),
+            )
+            .await
+            .unwrap();
+
+        let mut psbt = psbt_handler::from_base64(&exported).unwrap();
+        let err = manager.sign_psbt("watched", &mut psbt).await.unwrap_err();
+        assert!(err.to_string().contains("watch-only"));
+        assert!(manager.encrypt_wallet("watched", "hunter2").await.is_err());
+
+        // The key origins lead the key holder to its keys
+        let signed = manager.sign_psbt("hot", &mut psbt).await.unwrap();
+        assert_eq!(signed, psbt.inputs.len());
+        manager.finalize_psbt("watched", &mut psbt).await.unwrap();
+        assert!(psbt_handler::extract_transaction(psbt).is_ok());
+    }
+}
@@ -2,8 +2,8 @@ use bdk::keys::bip39::Mnemonic;
 use ethers::prelude::*;
 use walletd_ethereum::prelude::*;
 
-const PROVIDER_URL: &str = "https://goerli.infura.io/v3/9aa3d95b3bc440fa88ea12eaa4456161";
-const GOERLI_TEST_ADDRESS: &str = "0xFf7FD50BF684eb853787179cc9c784b55Ac68699";
+const PROVIDER_URL: &str = "https://sepolia.infura.io/v3/9aa3d95b3bc440fa88ea12eaa4456161";
+const SEPOLIA_TEST_ADDRESS: &str = "0xFf7FD50BF684eb853787179cc9c784b55Ac68699";
 #[tokio::main]
 async fn main() -> Result<(), walletd_ethereum::Error> {
     let mnemonic_phrase: &str =
@@ -24,7 +24,12 @@ async fn main() -> Result<(), walletd_ethereum::Error> {
 
     let send_amount = EthereumAmount::from_wei(10000.into());
     let tx = wallet
-        .transfer(&provider, send_amount, GOERLI_TEST_ADDRESS)
+        .transfer_with_fees(
+            &provider,
+            send_amount,
+            SEPOLIA_TEST_ADDRESS,
+            FeePreset::Fast,
+        )
         .await?;
 
     println!("tx: {:?}", &tx);
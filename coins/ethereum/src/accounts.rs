@@ -0,0 +1,128 @@
+use crate::EthereumAmount;
+
+use bdk::bitcoin::util::bip32::{ChildNumber, DerivationPath};
+use ethers::types::U256;
+
+/// Coin type of Ether in BIP44 paths
+const ETHER_COIN_TYPE: u32 = 60;
+
+/// How account and address indexes map to BIP32 derivation paths
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DerivationScheme {
+    /// `m/44'/60'/a'/0/i`, used by MetaMask and most software wallets with account `a` = 0
+    #[default]
+    Bip44,
+    /// `m/44'/60'/i'/0/0`, used by Ledger Live: every address is its own account, so the
+    /// address index picks the account and the account number is not used
+    LedgerLive,
+    /// `m/44'/60'/a'/i`, used by the legacy Ledger Chrome app and MyEtherWallet
+    LedgerLegacy,
+}
+
+impl DerivationScheme {
+    /// The derivation path of address `index` in `account`
+    pub fn path(self, account: u32, index: u32) -> DerivationPath {
+        let hardened = |i| ChildNumber::Hardened { index: i };
+        let normal = |i| ChildNumber::Normal { index: i };
+        let children = match self {
+            DerivationScheme::Bip44 => vec![
+                hardened(44),
+                hardened(ETHER_COIN_TYPE),
+                hardened(account),
+                normal(0),
+                normal(index),
+            ],
+            DerivationScheme::LedgerLive => vec![
+                hardened(44),
+                hardened(ETHER_COIN_TYPE),
+                hardened(index),
+                normal(0),
+                normal(0),
+            ],
+            DerivationScheme::LedgerLegacy => vec![
+                hardened(44),
+                hardened(ETHER_COIN_TYPE),
+                hardened(account),
+                normal(index),
+            ],
+        };
+        DerivationPath::from(children)
+    }
+}
+
+/// An address derived from the wallet's seed and its on-chain activity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivedAccount {
+    /// Account the address is derived in
+    pub account: u32,
+    /// Address index within the account
+    pub index: u32,
+    /// Derivation path of the address's key
+    pub path: DerivationPath,
+    /// Address in the wallet's format
+    pub address: String,
+    /// Number of transactions the address has sent
+    pub nonce: U256,
+    /// Balance of the address
+    pub balance: EthereumAmount,
+}
+
+impl DerivedAccount {
+    /// Whether the address has ever sent a transaction or holds ether
+    pub fn is_used(&self) -> bool {
+        !self.nonce.is_zero() || !self.balance.wei().is_zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EthereumWallet;
+    use bdk::keys::bip39::Mnemonic;
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn derives_addresses_for_each_scheme() {
+        assert_eq!(
+            DerivationScheme::Bip44.path(2, 7).to_string(),
+            "m/44'/60'/2'/0/7"
+        );
+        assert_eq!(
+            DerivationScheme::LedgerLive.path(0, 3).to_string(),
+            "m/44'/60'/3'/0/0"
+        );
+        assert_eq!(
+            DerivationScheme::LedgerLegacy.path(0, 3).to_string(),
+            "m/44'/60'/0'/3"
+        );
+
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(
+            wallet.public_address(),
+            "0x9858EfFD232B4033E47d90003D41EC34EcaEda94"
+        );
+        assert_eq!(
+            wallet.address_at(1).unwrap(),
+            "0x6Fac4D18c912343BF86fa7049364Dd4E424Ab9C0"
+        );
+
+        let ledger = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .derivation_scheme(DerivationScheme::LedgerLive)
+            .index(1)
+            .build()
+            .unwrap();
+        // Account 0 is the same key under both schemes
+        assert_eq!(ledger.address_at(0).unwrap(), wallet.public_address());
+        assert_ne!(ledger.public_address(), wallet.address_at(1).unwrap());
+        assert_eq!(ledger.index(), 1);
+        assert_eq!(
+            ledger.at_index(0).unwrap().public_address(),
+            wallet.public_address()
+        );
+    }
+}
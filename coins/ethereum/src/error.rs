@@ -42,7 +42,40 @@ pub enum Error {
     /// Error when trying to retrieve a transaction from a transaction hash
     #[error("An error was encountered while trying to retrieve a tx from a tx hash")]
     GetTx,
+    /// A key could not be derived from the wallet's seed
+    #[error("Key derivation failed: {0}")]
+    KeyDerivation(String),
     /// Error due to overflow
     #[error("Overflow error: {0}")]
     Overflow(String),
+    /// A JSON-RPC request to the node failed
+    #[error("Provider error: {0}")]
+    Provider(String),
+    /// The node's fee history can't be used to price an EIP-1559 transaction
+    #[error("Fee history error: {0}")]
+    FeeHistory(String),
+    /// `eth_estimateGas` failed, e.g. because the call would revert
+    #[error("Gas estimation failed: {0}")]
+    GasEstimation(String),
+    /// Signing or submitting a transaction failed
+    #[error("Failed to send transaction: {0}")]
+    SendTransaction(String),
+    /// The transaction is not tracked by the nonce manager
+    #[error("Unknown transaction: {0}")]
+    UnknownTransaction(String),
+    /// The transaction was already mined or replaced, so it can no longer be sped up or cancelled
+    #[error("Transaction can't be replaced: {0}")]
+    NotReplaceable(String),
+    /// Reading or writing the nonce manager's pending transactions failed
+    #[error("Pending transaction store error: {0}")]
+    PendingStore(String),
+    /// Typed data isn't valid EIP-712 or can't be signed as given
+    #[error("Typed data error: {0}")]
+    TypedData(String),
+    /// A signature is malformed or nothing can be recovered from it
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    /// A submitted transaction was dropped from the mempool before it was mined
+    #[error("Transaction {0} was dropped from the mempool")]
+    TxDropped(String),
 }
@@ -1,7 +1,10 @@
+use crate::fees::FEE_HISTORY_BLOCKS;
 use crate::Error;
 use crate::EthereumAmount;
+use crate::{Eip1559Fees, FeePreset, TxObservation};
 
 use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
 use ethers::types::Address;
 
 use std::sync::Arc;
@@ -15,8 +18,66 @@ abigen!(ERC20, "./abi/erc20_abi.json");
 #[allow(unused)]
 impl EthClient {
     /// Returns the chain id of the current network the ethers instance is connected to.
-    pub async fn chain_id(provider: &Provider<Http>) -> U256 {
-        provider.get_chainid().await.unwrap()
+    pub async fn chain_id(provider: &Provider<Http>) -> Result<U256, Error> {
+        provider
+            .get_chainid()
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))
+    }
+
+    /// Estimates EIP-1559 fees for the preset from the priority fees paid in recent blocks,
+    /// using `eth_feeHistory`.
+    pub async fn fee_estimate(
+        provider: &Provider<Http>,
+        preset: FeePreset,
+    ) -> Result<Eip1559Fees, Error> {
+        let history = provider
+            .fee_history(
+                FEE_HISTORY_BLOCKS,
+                BlockNumber::Latest,
+                &FeePreset::PERCENTILES,
+            )
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))?;
+        Eip1559Fees::from_fee_history(&history, preset)
+    }
+
+    /// Returns the gas a transaction needs, using `eth_estimateGas`.
+    ///
+    /// Fails with [Error::GasEstimation] if the transaction would revert.
+    pub async fn estimate_gas(
+        provider: &Provider<Http>,
+        tx: &TypedTransaction,
+    ) -> Result<U256, Error> {
+        provider
+            .estimate_gas(tx, None)
+            .await
+            .map_err(|e| Error::GasEstimation(e.to_string()))
+    }
+
+    /// Reports whether a transaction was mined, and in which block, or is waiting in the node's
+    /// mempool, for [NonceManager::update][crate::NonceManager::update].
+    pub async fn observe_transaction(
+        provider: &Provider<Http>,
+        tx_hash: H256,
+    ) -> Result<TxObservation, Error> {
+        let receipt = provider
+            .get_transaction_receipt(tx_hash)
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))?;
+        let mined_in = receipt
+            .and_then(|receipt| receipt.block_number)
+            .map(|block| block.as_u64());
+        let in_mempool = mined_in.is_none()
+            && provider
+                .get_transaction(tx_hash)
+                .await
+                .map_err(|e| Error::Provider(e.to_string()))?
+                .is_some();
+        Ok(TxObservation {
+            mined_in,
+            in_mempool,
+        })
     }
 
     /// Returns a block with its specified block number and transactions
@@ -37,14 +98,41 @@ impl EthClient {
     }
 
     /// Returns the balance of an address as an [EthereumAmount].
-    pub async fn balance(
-        provider: &Provider<Http>,
+    pub async fn balance<P: JsonRpcClient>(
+        provider: &Provider<P>,
         address: Address,
     ) -> Result<EthereumAmount, Error> {
-        let balance = provider.get_balance(address, None).await.unwrap();
+        let balance = provider
+            .get_balance(address, None)
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))?;
         Ok(EthereumAmount { wei: balance })
     }
 
+    /// Returns the number of mined transactions sent from an address, which is the nonce of
+    /// its next transaction to be mined.
+    pub async fn transaction_count<P: JsonRpcClient>(
+        provider: &Provider<P>,
+        address: Address,
+    ) -> Result<U256, Error> {
+        provider
+            .get_transaction_count(address, None)
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))
+    }
+
+    /// Like [transaction_count][Self::transaction_count], also counting transactions waiting in
+    /// the node's mempool.
+    pub async fn pending_transaction_count(
+        provider: &Provider<Http>,
+        address: Address,
+    ) -> Result<U256, Error> {
+        provider
+            .get_transaction_count(address, Some(BlockNumber::Pending.into()))
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))
+    }
+
     /// Gets a transaction given a specific tx hash.
     ///
     /// Returns an error[Error] if the transaction is not found.
@@ -134,7 +222,10 @@ impl EthClient {
 
     /// Get the latest block number for the current network chain.
     pub async fn current_block_number(provider: &Provider<Http>) -> Result<u64, Error> {
-        let block_number: ethers::types::U64 = provider.get_block_number().await.unwrap();
+        let block_number: ethers::types::U64 = provider
+            .get_block_number()
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))?;
         Ok(block_number.as_u64())
     }
 
@@ -1,12 +1,17 @@
 use ::core::fmt;
 use std::fmt::LowerHex;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
+use crate::signing;
+use crate::transactions;
 use crate::Error;
 use crate::EthClient;
-use crate::{EthereumAmount, EthereumFormat};
+use crate::{
+    DerivationScheme, DerivedAccount, EthereumAmount, EthereumFormat, FeePreset, NonceManager,
+    TrackedTransaction, TxObservation,
+};
 
-use bdk::bitcoin::secp256k1::ffi::types::AlignedType;
 use bdk::bitcoin::secp256k1::PublicKey;
 use bdk::bitcoin::secp256k1::Secp256k1;
 use bdk::bitcoin::util::bip32::DerivationPath;
@@ -14,9 +19,8 @@ use bdk::bitcoin::util::bip32::ExtendedPrivKey;
 use bdk::bitcoin::util::bip32::ExtendedPubKey;
 use bdk::keys::bip39::Mnemonic;
 use bdk::keys::{DerivableKey, ExtendedKey};
-use ethers::middleware::gas_oracle::GasNow;
-use ethers::prelude::gas_oracle::GasOracleMiddleware;
 use ethers::prelude::*;
+use ethers::types::transaction::eip712::TypedData;
 // use ethers::providers::{Middleware};
 // use ethers::types::{TransactionRequest};
 // use ethers::signers::{Signer};
@@ -92,23 +96,29 @@ impl LowerHex for EthereumPublicKey {
 }
 
 /// Builder for [EthereumWallet], allows for specification of options for the ethereum wallet
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct EthereumWalletBuilder {
     address_format: EthereumFormat,
     mnemonic: Option<Mnemonic>,
-    chain_id: u64,
+    derivation_scheme: DerivationScheme,
+    account: u32,
+    index: u32,
+    nonce_manager: Option<Arc<Mutex<NonceManager>>>,
 }
 
 impl Default for EthereumWalletBuilder {
     /// Specifies the default options for the EthereumWalletBuilder
     /// The default address format is EthereumFormat::Checksummed
     /// By default the mnemonic seed are specified
+    /// By default the wallet uses the first address of the first BIP44 account, `m/44'/60'/0'/0/0`
     fn default() -> Self {
         Self {
             address_format: EthereumFormat::Checksummed,
             mnemonic: None,
-            chain_id: 5, // Goerli
+            derivation_scheme: DerivationScheme::default(),
+            account: 0,
+            index: 0,
+            nonce_manager: None,
         }
     }
 }
@@ -120,38 +130,33 @@ impl EthereumWalletBuilder {
     }
     /// Builds the EthereumWallet with the specified options
     pub fn build(&self) -> Result<EthereumWallet, Error> {
-        if self.mnemonic.is_none() {
+        let Some(mnemonic) = &self.mnemonic else {
             return Err(Error::UnableToImportWallet(
-                "The mnemonic seed was provided".to_string(),
+                "The mnemonic seed was not provided".to_string(),
             ));
-        }
-
-        // we need secp256k1 context for key derivation
-        let mut buf: Vec<AlignedType> = Vec::new();
-        buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-        let secp = Secp256k1::preallocated_new(buf.as_mut_slice()).unwrap();
+        };
 
-        let mnemonic = &self.mnemonic.clone().unwrap();
-        let xkey: ExtendedKey = mnemonic.clone().into_extended_key().unwrap();
+        let xkey: ExtendedKey = mnemonic
+            .clone()
+            .into_extended_key()
+            .map_err(|e| Error::UnableToImportWallet(e.to_string()))?;
         // Get xprv from the extended key
-        let xprv = xkey.into_xprv(bdk::bitcoin::Network::Bitcoin).unwrap();
-        let path = DerivationPath::from_str("m/44h/60h/0h/0/0").unwrap();
+        let master = xkey
+            .into_xprv(bdk::bitcoin::Network::Bitcoin)
+            .ok_or_else(|| Error::UnableToImportWallet("No private key in seed".to_string()))?;
 
-        let child = xprv.derive_priv(&secp, &path).unwrap();
-        // println!("Child at {}: {}", path, child);
-        let xpub = ExtendedPubKey::from_priv(&secp, &child);
-        // println!("Public key at {}: {}", path, xpub);
-        // println!("private key bytes: {:?}", &child.private_key.secret_bytes());
-        let public_key =
-            EthereumPublicKey(PublicKey::from_slice(&xpub.public_key.serialize()).unwrap());
-        // println!("test2: {:?}", public_key);
-        let public_address = public_key.to_public_address(self.address_format)?;
-        let wallet = EthereumWallet {
+        let mut wallet = EthereumWallet {
             address_format: self.address_format,
-            public_address,
-            private_key: Some(child),
-            public_key: Some(xpub),
+            public_address: String::new(),
+            master_key: Some(master),
+            derivation_scheme: self.derivation_scheme,
+            account: self.account,
+            index: self.index,
+            nonce_manager: self.nonce_manager.clone().unwrap_or_default(),
+            private_key: None,
+            public_key: None,
         };
+        wallet.select_index(self.index)?;
         Ok(wallet)
     }
 
@@ -166,13 +171,48 @@ impl EthereumWalletBuilder {
         self.mnemonic = Some(mnemonic);
         self
     }
+
+    /// Allows specification of how account and address indexes map to derivation paths
+    pub fn derivation_scheme(&mut self, derivation_scheme: DerivationScheme) -> &mut Self {
+        self.derivation_scheme = derivation_scheme;
+        self
+    }
+
+    /// Allows specification of the account the wallet's addresses are derived in, 0 by default
+    pub fn account(&mut self, account: u32) -> &mut Self {
+        self.account = account;
+        self
+    }
+
+    /// Allows specification of the address index the wallet uses, 0 by default
+    pub fn index(&mut self, index: u32) -> &mut Self {
+        self.index = index;
+        self
+    }
+
+    /// Allows specification of the [NonceManager] tracking the wallet's transactions, e.g. one
+    /// [opened][NonceManager::open] from a file or shared with other wallets.
+    /// By default each wallet gets its own in-memory manager.
+    pub fn nonce_manager(&mut self, nonce_manager: Arc<Mutex<NonceManager>>) -> &mut Self {
+        self.nonce_manager = Some(nonce_manager);
+        self
+    }
 }
 
-/// Contains the information needed to interact with an Ethereum wallet with a single public address associated with it.
+/// Contains the information needed to interact with an Ethereum wallet.
+///
+/// The wallet holds the seed's master key and uses one address at a time, chosen by its
+/// account and address index; [at_index][EthereumWallet::at_index] switches to another address
+/// derived from the same seed.
 #[derive(Debug, Clone)]
 pub struct EthereumWallet {
     address_format: EthereumFormat,
     public_address: String,
+    master_key: Option<ExtendedPrivKey>,
+    derivation_scheme: DerivationScheme,
+    account: u32,
+    index: u32,
+    nonce_manager: Arc<Mutex<NonceManager>>,
     private_key: Option<ExtendedPrivKey>,
     public_key: Option<ExtendedPubKey>,
 }
@@ -183,56 +223,326 @@ impl EthereumWallet {
         EthereumWalletBuilder::new()
     }
 
+    /// Returns a copy of the wallet using the address at `index` for balances, transfers and
+    /// signing.
+    pub fn at_index(&self, index: u32) -> Result<EthereumWallet, Error> {
+        let mut wallet = self.clone();
+        wallet.select_index(index)?;
+        Ok(wallet)
+    }
+
+    /// Returns the address at `index` in the wallet's account
+    pub fn address_at(&self, index: u32) -> Result<String, Error> {
+        let (_, public_key) = self.derive(index)?;
+        EthereumPublicKey(public_key.public_key).to_public_address(self.address_format)
+    }
+
+    /// Returns the derivation path of the address at `index` in the wallet's account
+    pub fn derivation_path(&self, index: u32) -> DerivationPath {
+        self.derivation_scheme.path(self.account, index)
+    }
+
+    /// Returns the derivation scheme of the wallet
+    pub fn derivation_scheme(&self) -> DerivationScheme {
+        self.derivation_scheme
+    }
+
+    /// Returns the account the wallet's addresses are derived in
+    pub fn account(&self) -> u32 {
+        self.account
+    }
+
+    /// Returns the index of the address the wallet uses
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns a copy of the wallet using the first address of `account`. Under
+    /// [LedgerLive][DerivationScheme::LedgerLive] every address is an account, so this is the
+    /// address at index `account`.
+    pub fn at_account(&self, account: u32) -> Result<EthereumWallet, Error> {
+        let mut wallet = self.clone();
+        wallet.account = account;
+        match self.derivation_scheme {
+            DerivationScheme::LedgerLive => wallet.select_index(account)?,
+            DerivationScheme::Bip44 | DerivationScheme::LedgerLegacy => wallet.select_index(0)?,
+        }
+        Ok(wallet)
+    }
+
+    /// Scans accounts from 0, as Ledger Live does, and returns the first address of each
+    /// account that has sent a transaction or holds ether, stopping after `gap_limit` unused
+    /// accounts in a row. Under [Bip44][DerivationScheme::Bip44] these are the addresses
+    /// `m/44'/60'/a'/0/0`.
+    pub async fn discover_accounts<P: JsonRpcClient>(
+        &self,
+        provider: &Provider<P>,
+        gap_limit: u32,
+    ) -> Result<Vec<DerivedAccount>, Error> {
+        self.discover(provider, gap_limit, Self::at_account).await
+    }
+
+    /// Scans addresses in the wallet's account from index 0 and returns those that have sent
+    /// a transaction or hold ether, stopping after `gap_limit` unused addresses in a row.
+    pub async fn discover_addresses<P: JsonRpcClient>(
+        &self,
+        provider: &Provider<P>,
+        gap_limit: u32,
+    ) -> Result<Vec<DerivedAccount>, Error> {
+        self.discover(provider, gap_limit, Self::at_index).await
+    }
+
+    /// Checks the addresses `select` picks for 0, 1, 2... until `gap_limit` in a row are unused
+    async fn discover<P: JsonRpcClient>(
+        &self,
+        provider: &Provider<P>,
+        gap_limit: u32,
+        select: fn(&Self, u32) -> Result<EthereumWallet, Error>,
+    ) -> Result<Vec<DerivedAccount>, Error> {
+        let mut used = Vec::new();
+        let mut gap = 0;
+        let mut n: u32 = 0;
+        while gap < gap_limit {
+            let wallet = select(self, n)?;
+            let parsed = ethers::types::Address::from_str(&wallet.public_address)
+                .map_err(|e| Error::FromStr(e.to_string()))?;
+            let account = DerivedAccount {
+                account: wallet.account,
+                index: wallet.index,
+                path: wallet.derivation_path(wallet.index),
+                nonce: EthClient::transaction_count(provider, parsed).await?,
+                balance: EthClient::balance(provider, parsed).await?,
+                address: wallet.public_address,
+            };
+            if account.is_used() {
+                used.push(account);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            n = n
+                .checked_add(1)
+                .ok_or_else(|| Error::Overflow("Derivation index".to_string()))?;
+        }
+        Ok(used)
+    }
+
+    /// Derives the key pair at `index` in the wallet's account
+    fn derive(&self, index: u32) -> Result<(ExtendedPrivKey, ExtendedPubKey), Error> {
+        let master = self.master_key.as_ref().ok_or(Error::MissingHDKey)?;
+        let secp = Secp256k1::new();
+        let path = self.derivation_path(index);
+        let child = master
+            .derive_priv(&secp, &path)
+            .map_err(|e| Error::KeyDerivation(format!("{path}: {e}")))?;
+        let xpub = ExtendedPubKey::from_priv(&secp, &child);
+        Ok((child, xpub))
+    }
+
+    /// Switches the wallet's keys and address to those at `index`
+    fn select_index(&mut self, index: u32) -> Result<(), Error> {
+        let (private_key, public_key) = self.derive(index)?;
+        self.public_address =
+            EthereumPublicKey(public_key.public_key).to_public_address(self.address_format)?;
+        self.private_key = Some(private_key);
+        self.public_key = Some(public_key);
+        self.index = index;
+        Ok(())
+    }
+
     ///  Returns the balance for this Ethereum Wallet.
     pub async fn balance(&self, provider: &Provider<Http>) -> Result<EthereumAmount, Error> {
         let address = ethers::types::Address::from_str(&self.public_address())
-            .map_err(|e| (Error::FromStr(e.to_string())))?;
+            .map_err(|e| Error::FromStr(e.to_string()))?;
         let balance = EthClient::balance(provider, address).await?;
         Ok(balance)
     }
 
-    // TODO: take chain_id as a parameter
-    // TODO: Take index as a parameter and use that for deriving the wallet we want (refactor keystore)
-    /// This function creates and broadcasts a basic Ethereum transfer transaction to the Ethereum mempool.
+    /// Sends `send_amount` to `to_address` in an EIP-1559 transaction paying
+    /// [FeePreset::Normal] fees, waits for it to be mined and returns its transaction hash.
     pub async fn transfer(
         &self,
         provider: &Provider<Http>,
         send_amount: EthereumAmount,
         to_address: &str,
     ) -> Result<String, Error> {
-        let private_key_bytes = self.private_key.unwrap().private_key.secret_bytes();
-        // EthereumWallet stores the private key as a 32 byte array
-        //let secret_bytes = private_key.to_bytes();
-
-        // Instantiate a ethers local wallet from the wallet's secret bytes
-        let wallet_from_bytes = Wallet::from_bytes(&private_key_bytes).unwrap();
-
-        // Link our wallet instance to our provider for signing our transactions
-        let client = SignerMiddleware::new(provider, wallet_from_bytes.with_chain_id(5u64));
-        let client = GasOracleMiddleware::new(client, GasNow::new());
-        // Create a transaction request to send 10000 wei to the Goerli address
-        // TODO: Use gas oracle for more complex transactions where required gas fee is not known
-        // 21000 = gas fee for basic transfer
-        let tx = TransactionRequest::new()
-            .to(to_address)
-            .gas(21000)
-            .value(send_amount.wei())
-            .chain_id(5u64);
-
-        let pending_tx = client.send_transaction(tx, None).await.unwrap();
-        let receipt = pending_tx
+        self.transfer_with_fees(provider, send_amount, to_address, FeePreset::Normal)
             .await
-            .unwrap()
-            .ok_or_else(|| println!("tx dropped from mempool"))
-            .unwrap();
+    }
+
+    /// Like [transfer][Self::transfer], paying fees for the given preset.
+    pub async fn transfer_with_fees(
+        &self,
+        provider: &Provider<Http>,
+        send_amount: EthereumAmount,
+        to_address: &str,
+        preset: FeePreset,
+    ) -> Result<String, Error> {
+        let to = ethers::types::Address::from_str(to_address)
+            .map_err(|e| Error::FromStr(e.to_string()))?;
+        let tx = Eip1559TransactionRequest::new()
+            .to(to)
+            .value(send_amount.wei());
+        self.send_transaction(provider, tx, preset).await
+    }
+
+    /// Signs and sends a type 2 transaction, such as a contract call, waits for it to be mined
+    /// and returns its transaction hash.
+    ///
+    /// See [broadcast_transaction][Self::broadcast_transaction] for how the request is filled in.
+    pub async fn send_transaction(
+        &self,
+        provider: &Provider<Http>,
+        tx: Eip1559TransactionRequest,
+        preset: FeePreset,
+    ) -> Result<String, Error> {
+        let tx_hash = self.broadcast_transaction(provider, tx, preset).await?;
+        self.wait_for_receipt(provider, tx_hash).await
+    }
+
+    /// Signs and broadcasts a type 2 transaction and returns its hash without waiting for it to
+    /// be mined. The transaction is tracked by the wallet's [NonceManager] so it can later be
+    /// [sped up][Self::speed_up] or [cancelled][Self::cancel].
+    ///
+    /// The chain id comes from the provider and the nonce from the nonce manager, so
+    /// concurrent sends don't collide. Fees the request leaves unset come from
+    /// `eth_feeHistory` for the preset, and gas from `eth_estimateGas`, except that a plain
+    /// transfer to an account without code uses 21000.
+    pub async fn broadcast_transaction(
+        &self,
+        provider: &Provider<Http>,
+        tx: Eip1559TransactionRequest,
+        preset: FeePreset,
+    ) -> Result<H256, Error> {
+        transactions::broadcast(
+            provider,
+            &self.signer()?,
+            &self.nonce_manager,
+            tx,
+            preset,
+            None,
+        )
+        .await
+    }
+
+    /// Replaces a pending or dropped transaction with the same one paying higher fees, at least
+    /// [REPLACEMENT_FEE_BUMP][crate::REPLACEMENT_FEE_BUMP] percent more or the preset's current
+    /// fees, and returns the replacement's hash.
+    pub async fn speed_up(
+        &self,
+        provider: &Provider<Http>,
+        tx_hash: H256,
+        preset: FeePreset,
+    ) -> Result<H256, Error> {
+        transactions::speed_up(
+            provider,
+            &self.signer()?,
+            &self.nonce_manager,
+            tx_hash,
+            preset,
+        )
+        .await
+    }
+
+    /// Replaces a pending or dropped transaction with a 0-value transfer to the wallet's own
+    /// address at the same nonce, paying replacement fees as in [speed_up][Self::speed_up], and
+    /// returns the cancellation's hash.
+    pub async fn cancel(
+        &self,
+        provider: &Provider<Http>,
+        tx_hash: H256,
+        preset: FeePreset,
+    ) -> Result<H256, Error> {
+        transactions::cancel(
+            provider,
+            &self.signer()?,
+            &self.nonce_manager,
+            tx_hash,
+            preset,
+        )
+        .await
+    }
+
+    /// Transactions sent from the wallet's address on the provider's chain that are not final
+    /// yet, oldest first
+    pub async fn tracked_transactions(
+        &self,
+        provider: &Provider<Http>,
+    ) -> Result<Vec<TrackedTransaction>, Error> {
+        let from = self.signer()?.address();
+        transactions::tracked_transactions(provider, from, &self.nonce_manager).await
+    }
+
+    /// Checks every tracked transaction from the wallet's address against the node, noticing
+    /// ones that were mined, dropped from the mempool, replaced or reorged out, and returns the
+    /// transactions still tracked.
+    pub async fn sync_transactions(
+        &self,
+        provider: &Provider<Http>,
+    ) -> Result<Vec<TrackedTransaction>, Error> {
+        let from = self.signer()?.address();
+        transactions::sync_transactions(provider, from, &self.nonce_manager).await
+    }
 
-        let tx = client
-            .get_transaction(receipt.transaction_hash)
+    async fn wait_for_receipt(
+        &self,
+        provider: &Provider<Http>,
+        tx_hash: H256,
+    ) -> Result<String, Error> {
+        let receipt = PendingTransaction::new(tx_hash, provider)
             .await
-            .unwrap();
+            .map_err(|e| Error::Provider(e.to_string()))?
+            .ok_or_else(|| Error::TxDropped(format!("{tx_hash:?}")))?;
+        if let Some(block) = receipt.block_number {
+            let from = self.signer()?.address();
+            let mined_nonce = EthClient::transaction_count(provider, from).await?;
+            let observation = TxObservation {
+                mined_in: Some(block.as_u64()),
+                in_mempool: false,
+            };
+            let mut nonces = transactions::lock(&self.nonce_manager)?;
+            nonces.update(tx_hash, observation, mined_nonce, block.as_u64())?;
+            nonces.save()?;
+        }
+        if receipt.status == Some(U64::zero()) {
+            return Err(Error::TxResponse(format!(
+                "Transaction {tx_hash:?} reverted"
+            )));
+        }
+        Ok(format!("{:?}", receipt.transaction_hash))
+    }
+
+    /// Signs `message` as `personal_sign` does, over its EIP-191 hash, and returns the 65-byte
+    /// signature as 0x-prefixed hex.
+    ///
+    /// [signing::verify_message][crate::signing::verify_message] checks the signature.
+    pub fn sign_message(&self, message: &[u8]) -> Result<String, Error> {
+        self.sign_hash(ethers::utils::hash_message(message))
+    }
+
+    /// Signs typed data as `eth_signTypedData_v4` does and returns the 65-byte signature as
+    /// 0x-prefixed hex.
+    ///
+    /// `chain_id` is the chain the wallet is used on, e.g. from [EthClient::chain_id]. A domain
+    /// bound to another chain is refused, as EIP-712 asks, so a signature meant for one chain
+    /// can't be replayed on another.
+    pub fn sign_typed_data(&self, typed_data: &TypedData, chain_id: u64) -> Result<String, Error> {
+        signing::sign_typed_data(&self.signer()?, typed_data, chain_id)
+    }
+
+    fn sign_hash(&self, hash: H256) -> Result<String, Error> {
+        let signature = self
+            .signer()?
+            .sign_hash(hash)
+            .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+        Ok(format!("0x{signature}"))
+    }
 
-        let tx_hash_string = tx.unwrap().hash.to_string();
-        Ok(tx_hash_string)
+    /// A local signer holding the wallet's private key
+    fn signer(&self) -> Result<LocalWallet, Error> {
+        let private_key = self.private_key.as_ref().ok_or(Error::MissingPrivateKey)?;
+        LocalWallet::from_bytes(&private_key.private_key.secret_bytes())
+            .map_err(|e| Error::SendTransaction(e.to_string()))
     }
 
     /// Syncs the wallet with the blockchain by adding previously used addresses to the wallet.
@@ -267,3 +577,252 @@ impl EthereumWallet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TxState;
+    use ethers::utils::Anvil;
+
+    const MNEMONIC: &str =
+        "abstract vacuum mammal awkward pudding scene penalty purchase dinner depart evoke puzzle";
+
+    #[test]
+    fn signs_messages_and_typed_data() {
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+        let address = ethers::types::Address::from_str(&wallet.public_address()).unwrap();
+
+        let signature = wallet.sign_message(b"Log in to walletd").unwrap();
+        assert_eq!(signature.len(), 2 + 65 * 2);
+        assert!(signing::verify_message(address, b"Log in to walletd", &signature).unwrap());
+        assert!(!signing::verify_message(address, b"Log in to walletD", &signature).unwrap());
+
+        let permit = signing::parse_typed_data(
+            r#"{
+                "types": {
+                    "EIP712Domain": [
+                        {"name": "name", "type": "string"},
+                        {"name": "chainId", "type": "uint256"}
+                    ],
+                    "Login": [{"name": "nonce", "type": "uint256"}]
+                },
+                "primaryType": "Login",
+                "domain": {"name": "walletd", "chainId": 1},
+                "message": {"nonce": "7"}
+            }"#,
+        )
+        .unwrap();
+        let signature = wallet.sign_typed_data(&permit, 1).unwrap();
+        assert_eq!(
+            signing::recover_typed_data(&permit, &signature).unwrap(),
+            address
+        );
+        // The other account signs differently
+        let other = wallet.at_index(1).unwrap();
+        assert_ne!(other.sign_typed_data(&permit, 1).unwrap(), signature);
+        // A domain for mainnet can't be signed on Sepolia
+        assert!(matches!(
+            wallet.sign_typed_data(&permit, 11155111),
+            Err(Error::TypedData(_))
+        ));
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn transfer_sends_eip1559_transaction() {
+        if std::process::Command::new("anvil")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            println!("Skipping test - anvil not installed");
+            return;
+        }
+        let anvil = Anvil::new().mnemonic(MNEMONIC).chain_id(31337u64).spawn();
+        let provider = Provider::<Http>::try_from(anvil.endpoint()).unwrap();
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+        let to = anvil.addresses()[1];
+
+        let tx_hash = wallet
+            .transfer_with_fees(
+                &provider,
+                EthereumAmount::from_wei(1_000_000u64.into()),
+                &format!("{to:?}"),
+                FeePreset::Fast,
+            )
+            .await
+            .unwrap();
+
+        let tx = provider
+            .get_transaction(H256::from_str(&tx_hash).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tx.transaction_type, Some(2.into()));
+        assert_eq!(tx.chain_id, Some(31337.into()));
+        assert_eq!(tx.gas, 21000.into());
+        assert_eq!(
+            provider.get_balance(to, None).await.unwrap(),
+            U256::exp10(22) + 1_000_000
+        );
+
+        // Bad input is an error, not a panic
+        assert!(matches!(
+            wallet
+                .transfer(
+                    &provider,
+                    EthereumAmount::from_wei(1.into()),
+                    "not an address"
+                )
+                .await,
+            Err(Error::FromStr(_))
+        ));
+        drop(anvil);
+    }
+
+    #[tokio::test]
+    async fn discovers_funded_accounts() {
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+        let (provider, mock) = Provider::mocked();
+        // Each account's nonce is read, then its balance, and the mock answers last pushed first.
+        // Only account 1 holds ether.
+        let funded = U256::exp10(18);
+        for balance in [U256::zero(), funded, U256::zero(), U256::zero()]
+            .into_iter()
+            .rev()
+        {
+            mock.push(balance).unwrap();
+            mock.push(U256::zero()).unwrap();
+        }
+
+        let used = wallet.discover_accounts(&provider, 2).await.unwrap();
+        assert_eq!(used.len(), 1);
+        assert_eq!(used[0].account, 1);
+        assert_eq!(used[0].index, 0);
+        assert_eq!(used[0].path.to_string(), "m/44'/60'/1'/0/0");
+        assert_eq!(used[0].balance.wei(), funded);
+        let account = wallet.at_account(1).unwrap();
+        assert_eq!(used[0].address, account.public_address());
+        assert_ne!(used[0].address, wallet.address_at(1).unwrap());
+
+        // Accounts 0 to 3 were checked: account 1 is used and the gap of 2 ends at account 3
+        for n in 0..4 {
+            let address =
+                ethers::types::Address::from_str(&wallet.at_account(n).unwrap().public_address())
+                    .unwrap();
+            mock.assert_request("eth_getTransactionCount", (address, "latest"))
+                .unwrap();
+            mock.assert_request("eth_getBalance", (address, "latest"))
+                .unwrap();
+        }
+        assert!(mock.assert_request("eth_getBalance", ()).is_err());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn discovers_funded_addresses() {
+        if std::process::Command::new("anvil")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            println!("Skipping test - anvil not installed");
+            return;
+        }
+        // Anvil funds the first ten addresses of the mnemonic's first account
+        let anvil = Anvil::new().mnemonic(MNEMONIC).spawn();
+        let provider = Provider::<Http>::try_from(anvil.endpoint()).unwrap();
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+
+        let used = wallet.discover_addresses(&provider, 5).await.unwrap();
+        assert_eq!(used.len(), anvil.addresses().len());
+        for (account, address) in used.iter().zip(anvil.addresses()) {
+            assert_eq!(account.address, ethers::utils::to_checksum(address, None));
+        }
+
+        let third = wallet.at_index(2).unwrap();
+        assert_eq!(third.public_address(), used[2].address);
+        assert_eq!(
+            third.balance(&provider).await.unwrap().wei(),
+            used[2].balance.wei()
+        );
+        drop(anvil);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn replaces_stuck_transactions() {
+        if std::process::Command::new("anvil")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            println!("Skipping test - anvil not installed");
+            return;
+        }
+        // Without automatic mining every transaction stays pending until a block is mined
+        let anvil = Anvil::new().mnemonic(MNEMONIC).arg("--no-mining").spawn();
+        let provider = Provider::<Http>::try_from(anvil.endpoint()).unwrap();
+        let wallet = EthereumWallet::builder()
+            .mnemonic(Mnemonic::parse(MNEMONIC).unwrap())
+            .build()
+            .unwrap();
+        let transfer = |value: u64| {
+            Eip1559TransactionRequest::new()
+                .to(anvil.addresses()[1])
+                .value(value)
+        };
+
+        let first = wallet
+            .broadcast_transaction(&provider, transfer(1), FeePreset::Slow)
+            .await
+            .unwrap();
+        let second = wallet
+            .broadcast_transaction(&provider, transfer(2), FeePreset::Slow)
+            .await
+            .unwrap();
+        let faster = wallet
+            .speed_up(&provider, first, FeePreset::Fast)
+            .await
+            .unwrap();
+        let cancelled = wallet
+            .cancel(&provider, second, FeePreset::Normal)
+            .await
+            .unwrap();
+
+        let tracked = wallet.tracked_transactions(&provider).await.unwrap();
+        let nonces: Vec<u64> = tracked.iter().map(|tx| tx.nonce.as_u64()).collect();
+        assert_eq!(nonces, [0, 1, 0, 1]);
+        assert_eq!(tracked[0].state, TxState::Replaced { by: Some(faster) });
+        assert!(tracked[2].fees().max_fee_per_gas > tracked[0].fees().max_fee_per_gas);
+        assert!(matches!(
+            wallet.speed_up(&provider, first, FeePreset::Fast).await,
+            Err(Error::NotReplaceable(_))
+        ));
+
+        provider.request::<_, U256>("evm_mine", ()).await.unwrap();
+        let tracked = wallet.sync_transactions(&provider).await.unwrap();
+        let mined: Vec<H256> = tracked
+            .iter()
+            .filter(|tx| matches!(tx.state, TxState::Mined { .. }))
+            .map(|tx| tx.hash)
+            .collect();
+        assert_eq!(mined, [faster, cancelled]);
+        let cancellation = provider.get_transaction(cancelled).await.unwrap().unwrap();
+        assert_eq!(cancellation.to, Some(anvil.addresses()[0]));
+        assert!(cancellation.value.is_zero());
+        drop(anvil);
+    }
+}
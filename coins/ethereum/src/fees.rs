@@ -0,0 +1,170 @@
+use crate::Error;
+use ethers::types::{FeeHistory, U256};
+
+/// Number of recent blocks whose priority fees are sampled
+pub(crate) const FEE_HISTORY_BLOCKS: u64 = 10;
+
+/// Priority fee used when every sampled block was empty, 1 gwei
+const DEFAULT_PRIORITY_FEE: u64 = 1_000_000_000;
+
+/// Smallest increase, in percent, of both fee fields that nodes accept for a replacement
+pub const REPLACEMENT_FEE_BUMP: u64 = 10;
+
+/// How quickly a transaction should be included, picking the percentile of recent priority
+/// fees it pays
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePreset {
+    /// 10th percentile of recent priority fees
+    Slow,
+    /// Median of recent priority fees
+    #[default]
+    Normal,
+    /// 90th percentile of recent priority fees
+    Fast,
+}
+
+impl FeePreset {
+    /// Percentiles requested from `eth_feeHistory`, one per preset
+    pub(crate) const PERCENTILES: [f64; 3] = [10.0, 50.0, 90.0];
+
+    fn column(self) -> usize {
+        match self {
+            FeePreset::Slow => 0,
+            FeePreset::Normal => 1,
+            FeePreset::Fast => 2,
+        }
+    }
+}
+
+/// EIP-1559 fee parameters for a type 2 transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eip1559Fees {
+    /// Most the transaction pays per gas, base fee included
+    pub max_fee_per_gas: U256,
+    /// Tip to the block producer per gas
+    pub max_priority_fee_per_gas: U256,
+}
+
+impl Eip1559Fees {
+    /// Fees from an `eth_feeHistory` response requested with [FeePreset::PERCENTILES].
+    ///
+    /// The tip is the median of the preset's percentile over the blocks that had transactions,
+    /// and the fee cap allows the next block's base fee to double before the transaction is
+    /// priced out.
+    pub fn from_fee_history(history: &FeeHistory, preset: FeePreset) -> Result<Self, Error> {
+        // The last entry is the base fee of the block after the newest one sampled
+        let next_base_fee = *history
+            .base_fee_per_gas
+            .last()
+            .ok_or_else(|| Error::FeeHistory("No base fee; is EIP-1559 active?".to_string()))?;
+
+        let mut tips: Vec<U256> = history
+            .reward
+            .iter()
+            .zip(&history.gas_used_ratio)
+            .filter(|(_, used)| **used > 0.0)
+            .filter_map(|(rewards, _)| rewards.get(preset.column()).copied())
+            .collect();
+        tips.sort();
+        let max_priority_fee_per_gas = match tips.get(tips.len() / 2) {
+            Some(tip) => *tip,
+            None => U256::from(DEFAULT_PRIORITY_FEE),
+        };
+
+        let max_fee_per_gas = next_base_fee
+            .checked_mul(2.into())
+            .and_then(|fee| fee.checked_add(max_priority_fee_per_gas))
+            .ok_or_else(|| Error::Overflow(format!("Fee cap for base fee {next_base_fee}")))?;
+        Ok(Self {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        })
+    }
+
+    /// Fees for a transaction replacing one that paid `self` at the same nonce.
+    ///
+    /// Both fields rise by more than [REPLACEMENT_FEE_BUMP] percent, or to `current` network
+    /// fees when those are higher.
+    pub fn replacement(&self, current: Eip1559Fees) -> Self {
+        let bump = |fee: U256| {
+            fee.saturating_add(fee * REPLACEMENT_FEE_BUMP / 100)
+                .saturating_add(1.into())
+        };
+        let max_priority_fee_per_gas =
+            bump(self.max_priority_fee_per_gas).max(current.max_priority_fee_per_gas);
+        let max_fee_per_gas = bump(self.max_fee_per_gas)
+            .max(current.max_fee_per_gas)
+            .max(max_priority_fee_per_gas);
+        Self {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> FeeHistory {
+        let gwei = |n: u64| U256::from(n * 1_000_000_000);
+        FeeHistory {
+            base_fee_per_gas: vec![gwei(20), gwei(22), gwei(25), gwei(30)],
+            gas_used_ratio: vec![0.9, 0.0, 0.7],
+            oldest_block: 100.into(),
+            reward: vec![
+                vec![gwei(1), gwei(2), gwei(5)],
+                vec![0.into(), 0.into(), 0.into()],
+                vec![gwei(2), gwei(3), gwei(8)],
+            ],
+        }
+    }
+
+    #[test]
+    fn fees_follow_recent_blocks() {
+        let gwei = |n: u64| U256::from(n * 1_000_000_000);
+        let slow = Eip1559Fees::from_fee_history(&history(), FeePreset::Slow).unwrap();
+        let fast = Eip1559Fees::from_fee_history(&history(), FeePreset::Fast).unwrap();
+        // The empty block is skipped, leaving two samples; the upper one is taken
+        assert_eq!(slow.max_priority_fee_per_gas, gwei(2));
+        assert_eq!(fast.max_priority_fee_per_gas, gwei(8));
+        assert_eq!(fast.max_fee_per_gas, gwei(68));
+
+        let empty = FeeHistory {
+            gas_used_ratio: vec![0.0; 3],
+            ..history()
+        };
+        let fees = Eip1559Fees::from_fee_history(&empty, FeePreset::Normal).unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, gwei(1));
+
+        let legacy = FeeHistory {
+            base_fee_per_gas: vec![],
+            ..history()
+        };
+        assert!(matches!(
+            Eip1559Fees::from_fee_history(&legacy, FeePreset::Normal),
+            Err(Error::FeeHistory(_))
+        ));
+    }
+
+    #[test]
+    fn replacement_outbids_original() {
+        let original = Eip1559Fees {
+            max_fee_per_gas: 100.into(),
+            max_priority_fee_per_gas: 10.into(),
+        };
+        let quiet = Eip1559Fees {
+            max_fee_per_gas: 50.into(),
+            max_priority_fee_per_gas: 5.into(),
+        };
+        let bumped = original.replacement(quiet);
+        assert_eq!(bumped.max_fee_per_gas, 111.into());
+        assert_eq!(bumped.max_priority_fee_per_gas, 12.into());
+
+        let busy = Eip1559Fees {
+            max_fee_per_gas: 300.into(),
+            max_priority_fee_per_gas: 30.into(),
+        };
+        assert_eq!(original.replacement(busy), busy);
+    }
+}
@@ -23,7 +23,11 @@
 //! # Ok(())
 //! # }
 //! ```
-//! We see that by default the Ethereum wallet uses the derivation path "m/44'/60'/0'/0/" corresponding to BIP44 for the purpose value and 60' corresponding to the coin type for Ethereum.
+//! We see that by default the Ethereum wallet uses the derivation path "m/44'/60'/0'/0/0" corresponding to BIP44 for the purpose value and 60' corresponding to the coin type for Ethereum.
+//! Other accounts and address indexes, and Ledger Live style paths, can be chosen with the builder's
+//! [account][EthereumWalletBuilder::account], [index][EthereumWalletBuilder::index] and
+//! [derivation_scheme][EthereumWalletBuilder::derivation_scheme] options, and
+//! [EthereumWallet::at_index] switches an existing wallet to another address from the same seed.
 //!
 //! We need to add a blockchain connector to our [ethereum wallet][EthereumWallet] to be able to interact with the Ethereum blockchain.
 //!
@@ -85,6 +89,8 @@
 
 use core::fmt;
 
+mod accounts;
+pub use accounts::{DerivationScheme, DerivedAccount};
 mod ethclient;
 pub use ethclient::EthClient;
 mod ethereum_amount;
@@ -93,8 +99,14 @@ mod ethereum_wallet;
 pub use ethereum_wallet::{EthereumWallet, EthereumWalletBuilder};
 mod error;
 pub use error::Error;
+mod fees;
 pub use ethers;
+pub use fees::{Eip1559Fees, FeePreset, REPLACEMENT_FEE_BUMP};
+mod nonces;
+pub use nonces::{NonceManager, TrackedTransaction, TxObservation, TxState, CONFIRMATION_DEPTH};
 pub mod prelude;
+pub mod signing;
+pub mod transactions;
 
 /// Represents the format of an Ethereum address (checksummed or non-checksummed)
 #[derive(Default, Debug, Clone, Copy)]
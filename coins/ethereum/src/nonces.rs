@@ -0,0 +1,410 @@
+use crate::{Eip1559Fees, Error};
+
+use ethers::types::{Address, Eip1559TransactionRequest, H256, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Blocks, counting its own, a transaction must be buried under before a reorg is no longer
+/// expected
+pub const CONFIRMATION_DEPTH: u64 = 12;
+
+/// Where a tracked transaction stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxState {
+    /// Broadcast and waiting in the mempool
+    Pending,
+    /// Included in `block`, but fewer than [CONFIRMATION_DEPTH] blocks deep
+    Mined {
+        /// Number of the including block
+        block: u64,
+    },
+    /// Buried deep enough that a reorg is no longer expected
+    Confirmed,
+    /// Another transaction with the same nonce superseded this one; `by` is set when it is one
+    /// of ours
+    Replaced {
+        /// Hash of the replacing transaction
+        by: Option<H256>,
+    },
+    /// Unknown to the node while its nonce is still unused; speeding it up rebroadcasts it
+    Dropped,
+}
+
+/// A transaction the wallet broadcast
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackedTransaction {
+    /// Transaction hash
+    pub hash: H256,
+    /// Chain the transaction was signed for
+    pub chain_id: u64,
+    /// Sender
+    pub from: Address,
+    /// Nonce the transaction uses
+    pub nonce: U256,
+    /// The request that was signed, with fees, gas and nonce filled in
+    pub request: Eip1559TransactionRequest,
+    /// Transaction this one replaced at the same nonce
+    pub replaces: Option<H256>,
+    /// Where the transaction stands
+    pub state: TxState,
+}
+
+impl TrackedTransaction {
+    /// Tracks a freshly broadcast transaction; the request must have its nonce set
+    pub fn new(
+        hash: H256,
+        chain_id: u64,
+        from: Address,
+        request: Eip1559TransactionRequest,
+    ) -> Result<Self, Error> {
+        let nonce = request
+            .nonce
+            .ok_or_else(|| Error::TxResponse(format!("Transaction {hash:?} has no nonce")))?;
+        Ok(Self {
+            hash,
+            chain_id,
+            from,
+            nonce,
+            request,
+            replaces: None,
+            state: TxState::Pending,
+        })
+    }
+
+    /// Whether the transaction can still be sped up or cancelled
+    pub fn is_replaceable(&self) -> bool {
+        matches!(self.state, TxState::Pending | TxState::Dropped)
+    }
+
+    /// Fees the transaction offers
+    pub fn fees(&self) -> Eip1559Fees {
+        Eip1559Fees {
+            max_fee_per_gas: self.request.max_fee_per_gas.unwrap_or_default(),
+            max_priority_fee_per_gas: self.request.max_priority_fee_per_gas.unwrap_or_default(),
+        }
+    }
+
+    fn key(&self) -> (u64, Address, U256) {
+        (self.chain_id, self.from, self.nonce)
+    }
+
+    /// The state the node's view implies, given the number of mined transactions from the
+    /// sender and the current block height
+    fn next_state(&self, observation: TxObservation, mined_nonce: U256, head: u64) -> TxState {
+        match (observation.mined_in, self.state) {
+            (Some(block), _) if head.saturating_sub(block) + 1 >= CONFIRMATION_DEPTH => {
+                TxState::Confirmed
+            }
+            (Some(block), _) => TxState::Mined { block },
+            // Once replaced, a transaction only comes back by being mined
+            (None, TxState::Replaced { by }) => TxState::Replaced { by },
+            (None, _) if observation.in_mempool => TxState::Pending,
+            (None, _) if self.nonce < mined_nonce => TxState::Replaced { by: None },
+            // Includes a mined transaction that a reorg returned to neither chain nor mempool
+            (None, _) => TxState::Dropped,
+        }
+    }
+}
+
+/// What the node reports about a tracked transaction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TxObservation {
+    /// Block the transaction's receipt places it in
+    pub mined_in: Option<u64>,
+    /// Whether the node knows the transaction without a receipt, i.e. it is in the mempool
+    pub in_mempool: bool,
+}
+
+/// Hands out nonces per chain and sender and tracks the transactions sent with them until they
+/// are final, so concurrent sends don't collide and stuck ones can be replaced.
+///
+/// Nonces handed out but not yet recorded or released live in memory only; tracked
+/// transactions are written to a JSON file by [save][Self::save] when the manager was
+/// [opened][Self::open] from one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NonceManager {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    transactions: Vec<TrackedTransaction>,
+    #[serde(skip)]
+    reserved: HashSet<(u64, Address, U256)>,
+}
+
+impl NonceManager {
+    /// A manager that keeps its state in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A manager persisted to `path`, loading the transactions saved there if the file exists
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut manager = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str::<NonceManager>(&json)
+                .map_err(|e| Error::PendingStore(format!("{}: {e}", path.display())))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::new(),
+            Err(e) => return Err(Error::PendingStore(format!("{}: {e}", path.display()))),
+        };
+        manager.path = Some(path);
+        Ok(manager)
+    }
+
+    /// Writes the tracked transactions to the manager's file, if it has one
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| Error::PendingStore(e.to_string()))?;
+        // Write then rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| Error::PendingStore(format!("{}: {e}", path.display())))
+    }
+
+    /// Hands out the lowest nonce from `chain_nonce`, the node's pending transaction count, that
+    /// is neither used by a tracked transaction in the mempool nor already handed out. Nonces of
+    /// dropped transactions are free again, as nothing the node knows of will take them.
+    ///
+    /// The nonce must be given back with [record][Self::record] once the transaction is
+    /// broadcast or [release_nonce][Self::release_nonce] if sending fails.
+    pub fn reserve_nonce(&mut self, chain_id: u64, from: Address, chain_nonce: U256) -> U256 {
+        let in_use: HashSet<U256> = self
+            .transactions
+            .iter()
+            .filter(|tx| tx.chain_id == chain_id && tx.from == from)
+            .filter(|tx| tx.state == TxState::Pending)
+            .map(|tx| tx.nonce)
+            .collect();
+        let mut nonce = chain_nonce;
+        while in_use.contains(&nonce) || self.reserved.contains(&(chain_id, from, nonce)) {
+            nonce += U256::one();
+        }
+        self.reserved.insert((chain_id, from, nonce));
+        nonce
+    }
+
+    /// Returns a nonce whose transaction was never broadcast
+    pub fn release_nonce(&mut self, chain_id: u64, from: Address, nonce: U256) {
+        self.reserved.remove(&(chain_id, from, nonce));
+    }
+
+    /// Tracks a broadcast transaction. When it replaces another, or reuses the nonce of a dropped
+    /// one, that one is marked replaced.
+    pub fn record(&mut self, tx: TrackedTransaction) {
+        self.reserved.remove(&tx.key());
+        for other in self.transactions.iter_mut() {
+            if Some(other.hash) == tx.replaces
+                || (other.key() == tx.key() && other.state == TxState::Dropped)
+            {
+                other.state = TxState::Replaced { by: Some(tx.hash) };
+            }
+        }
+        self.transactions.push(tx);
+    }
+
+    /// The tracked transaction with `hash`
+    pub fn transaction(&self, hash: H256) -> Option<&TrackedTransaction> {
+        self.transactions.iter().find(|tx| tx.hash == hash)
+    }
+
+    /// Tracked transactions sent by `from` on `chain_id`, oldest first
+    pub fn transactions(&self, chain_id: u64, from: Address) -> Vec<&TrackedTransaction> {
+        self.transactions
+            .iter()
+            .filter(|tx| tx.chain_id == chain_id && tx.from == from)
+            .collect()
+    }
+
+    /// Updates a transaction from what the node reports about it, given the number of mined
+    /// transactions from its sender and the current block height, and returns its new state.
+    ///
+    /// A mined transaction marks the others at its nonce replaced by it, and nonces with
+    /// nothing left to wait for are no longer tracked.
+    pub fn update(
+        &mut self,
+        hash: H256,
+        observation: TxObservation,
+        mined_nonce: U256,
+        head: u64,
+    ) -> Result<TxState, Error> {
+        let tx = self
+            .transactions
+            .iter_mut()
+            .find(|tx| tx.hash == hash)
+            .ok_or_else(|| Error::UnknownTransaction(format!("{hash:?}")))?;
+        tx.state = tx.next_state(observation, mined_nonce, head);
+        let (state, key) = (tx.state, tx.key());
+
+        if matches!(state, TxState::Mined { .. } | TxState::Confirmed) {
+            for sibling in self.transactions.iter_mut() {
+                if sibling.key() == key && sibling.hash != hash {
+                    sibling.state = TxState::Replaced { by: Some(hash) };
+                }
+            }
+        }
+        let waiting: HashSet<_> = self
+            .transactions
+            .iter()
+            .filter(|tx| {
+                matches!(
+                    tx.state,
+                    TxState::Pending | TxState::Mined { .. } | TxState::Dropped
+                )
+            })
+            .map(TrackedTransaction::key)
+            .collect();
+        self.transactions.retain(|tx| waiting.contains(&tx.key()));
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN: u64 = 1;
+
+    fn tracked(manager: &mut NonceManager, from: Address, byte: u8) -> H256 {
+        let nonce = manager.reserve_nonce(CHAIN, from, 5.into());
+        let request = Eip1559TransactionRequest::new()
+            .nonce(nonce)
+            .max_fee_per_gas(100)
+            .max_priority_fee_per_gas(2);
+        let hash = H256::repeat_byte(byte);
+        manager.record(TrackedTransaction::new(hash, CHAIN, from, request).unwrap());
+        hash
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let from = Address::repeat_byte(1);
+        let mut manager = NonceManager::new();
+        let first = manager.reserve_nonce(CHAIN, from, 5.into());
+        let second = manager.reserve_nonce(CHAIN, from, 5.into());
+        assert_eq!((first, second), (5.into(), 6.into()));
+        // Other senders and chains count separately
+        assert_eq!(
+            manager.reserve_nonce(CHAIN, Address::repeat_byte(2), 5.into()),
+            5.into()
+        );
+        assert_eq!(manager.reserve_nonce(10, from, 5.into()), 5.into());
+
+        // A failed send's nonce is handed out again
+        manager.release_nonce(CHAIN, from, first);
+        assert_eq!(manager.reserve_nonce(CHAIN, from, 5.into()), first);
+
+        // The node already counting our pending transactions doesn't matter
+        let mut manager = NonceManager::new();
+        let hash = tracked(&mut manager, from, 0xaa);
+        assert_eq!(manager.transaction(hash).unwrap().nonce, 5.into());
+        assert_eq!(manager.reserve_nonce(CHAIN, from, 5.into()), 6.into());
+        assert_eq!(manager.reserve_nonce(CHAIN, from, 6.into()), 7.into());
+    }
+
+    #[test]
+    fn follows_mining_reorgs_drops_and_replacements() {
+        let from = Address::repeat_byte(1);
+        let mut manager = NonceManager::new();
+        let hash = tracked(&mut manager, from, 0xaa);
+        let mined = TxObservation {
+            mined_in: Some(100),
+            in_mempool: false,
+        };
+        let in_mempool = TxObservation {
+            mined_in: None,
+            in_mempool: true,
+        };
+
+        let state = manager.update(hash, mined, 6.into(), 101).unwrap();
+        assert_eq!(state, TxState::Mined { block: 100 });
+        // Reorged back into the mempool, then out of it entirely
+        let state = manager.update(hash, in_mempool, 5.into(), 101).unwrap();
+        assert_eq!(state, TxState::Pending);
+        let state = manager
+            .update(hash, TxObservation::default(), 5.into(), 102)
+            .unwrap();
+        assert_eq!(state, TxState::Dropped);
+
+        // A replacement is recorded, then the original is mined after all
+        let mut request = manager.transaction(hash).unwrap().request.clone();
+        request.max_fee_per_gas = Some(200.into());
+        let mut replacement =
+            TrackedTransaction::new(H256::repeat_byte(0xbb), CHAIN, from, request).unwrap();
+        replacement.replaces = Some(hash);
+        let replacement_hash = replacement.hash;
+        manager.record(replacement);
+        assert_eq!(
+            manager.transaction(hash).unwrap().state,
+            TxState::Replaced {
+                by: Some(replacement_hash)
+            }
+        );
+        manager.update(hash, mined, 6.into(), 103).unwrap();
+        assert_eq!(
+            manager.transaction(replacement_hash).unwrap().state,
+            TxState::Replaced { by: Some(hash) }
+        );
+
+        // Final once deep enough, and no longer tracked
+        let state = manager
+            .update(hash, mined, 6.into(), 100 + CONFIRMATION_DEPTH - 1)
+            .unwrap();
+        assert_eq!(state, TxState::Confirmed);
+        assert!(manager.transactions(CHAIN, from).is_empty());
+
+        // A nonce used by a transaction sent elsewhere
+        let hash = tracked(&mut manager, from, 0xcc);
+        let state = manager
+            .update(hash, TxObservation::default(), 6.into(), 120)
+            .unwrap();
+        assert_eq!(state, TxState::Replaced { by: None });
+        assert!(manager.transaction(hash).is_none());
+    }
+
+    #[test]
+    fn dropped_nonces_are_reused() {
+        let from = Address::repeat_byte(1);
+        let mut manager = NonceManager::new();
+        let dropped = tracked(&mut manager, from, 0xaa);
+        let pending = tracked(&mut manager, from, 0xbb);
+        let state = manager
+            .update(dropped, TxObservation::default(), 5.into(), 100)
+            .unwrap();
+        assert_eq!(state, TxState::Dropped);
+
+        // The node counts neither, but only the dropped transaction's nonce is free
+        let nonce = manager.reserve_nonce(CHAIN, from, 5.into());
+        assert_eq!(nonce, 5.into());
+        assert_eq!(manager.reserve_nonce(CHAIN, from, 5.into()), 7.into());
+
+        // Sending at the dropped nonce supersedes the dropped transaction
+        let request = Eip1559TransactionRequest::new().nonce(nonce);
+        let hash = H256::repeat_byte(0xcc);
+        manager.record(TrackedTransaction::new(hash, CHAIN, from, request).unwrap());
+        let dropped = manager.transaction(dropped).unwrap();
+        assert_eq!(dropped.state, TxState::Replaced { by: Some(hash) });
+        assert!(!dropped.is_replaceable());
+        assert_eq!(
+            manager.transaction(pending).unwrap().state,
+            TxState::Pending
+        );
+    }
+
+    #[test]
+    fn pending_transactions_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("walletd_nonces_{}.json", std::process::id()));
+        let from = Address::repeat_byte(1);
+        let mut manager = NonceManager::open(&path).unwrap();
+        let hash = tracked(&mut manager, from, 0xaa);
+        manager.save().unwrap();
+
+        let mut reopened = NonceManager::open(&path).unwrap();
+        assert_eq!(reopened.transaction(hash), manager.transaction(hash));
+        assert_eq!(reopened.reserve_nonce(CHAIN, from, 5.into()), 6.into());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
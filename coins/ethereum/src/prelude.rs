@@ -5,7 +5,10 @@
 //! use walletd_ethereum::prelude::*;
 //! ```
 
-pub use crate::{EthClient, EthereumAmount, EthereumFormat, EthereumWallet, EthereumWalletBuilder};
+pub use crate::{
+    DerivationScheme, DerivedAccount, Eip1559Fees, EthClient, EthereumAmount, EthereumFormat,
+    EthereumWallet, EthereumWalletBuilder, FeePreset, NonceManager, TrackedTransaction, TxState,
+};
 
 pub use bdk::keys::bip39::Mnemonic;
 pub use ethers::types::Transaction;
@@ -0,0 +1,189 @@
+//! Signing and verification of EIP-191 `personal_sign` messages and EIP-712 typed data.
+//!
+//! [EthereumWallet][crate::EthereumWallet] signs with
+//! [sign_message][crate::EthereumWallet::sign_message] and
+//! [sign_typed_data][crate::EthereumWallet::sign_typed_data]; the functions here parse typed data
+//! and recover or verify the signer of a signature.
+
+use crate::Error;
+
+use ethers::signers::LocalWallet;
+use ethers::types::transaction::eip712::{EIP712Domain, Eip712, TypedData};
+use ethers::types::{Address, Signature, H256, U256};
+use ethers::utils::hash_message;
+use std::fmt;
+use std::str::FromStr;
+
+/// Parses typed data in the JSON form `eth_signTypedData_v4` takes, with `domain`, `types`,
+/// `primaryType` and `message`
+pub fn parse_typed_data(json: &str) -> Result<TypedData, Error> {
+    serde_json::from_str(json).map_err(|e| Error::TypedData(e.to_string()))
+}
+
+/// The EIP-712 digest of typed data, which is what gets signed
+pub fn typed_data_hash(typed_data: &TypedData) -> Result<H256, Error> {
+    typed_data
+        .encode_eip712()
+        .map(H256)
+        .map_err(|e| Error::TypedData(e.to_string()))
+}
+
+/// Fails unless the domain is bound to `chain_id` or to no chain, as EIP-712 asks wallets to
+/// refuse signing for another chain
+pub fn check_domain_chain_id(domain: &EIP712Domain, chain_id: u64) -> Result<(), Error> {
+    match domain.chain_id {
+        Some(domain_chain_id) if domain_chain_id != U256::from(chain_id) => Err(Error::TypedData(
+            format!("Domain is for chain {domain_chain_id}, not the active chain {chain_id}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Signs typed data with `signer` as `eth_signTypedData_v4` does, for a wallet on `chain_id`,
+/// and returns the 65-byte signature as 0x-prefixed hex. Domains bound to another chain are
+/// refused, see [check_domain_chain_id].
+pub fn sign_typed_data(
+    signer: &LocalWallet,
+    typed_data: &TypedData,
+    chain_id: u64,
+) -> Result<String, Error> {
+    check_domain_chain_id(&typed_data.domain, chain_id)?;
+    let signature = signer
+        .sign_hash(typed_data_hash(typed_data)?)
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+    Ok(format!("0x{signature}"))
+}
+
+/// The address whose key produced `signature` over `message` with `personal_sign`, i.e. over
+/// the EIP-191 hash of the message
+pub fn recover_message(message: &[u8], signature: &str) -> Result<Address, Error> {
+    recover(hash_message(message), signature)
+}
+
+/// Whether `address` signed `message` with `personal_sign`
+pub fn verify_message(address: Address, message: &[u8], signature: &str) -> Result<bool, Error> {
+    Ok(recover_message(message, signature)? == address)
+}
+
+/// The address whose key produced `signature` over `typed_data` with `eth_signTypedData_v4`
+pub fn recover_typed_data(typed_data: &TypedData, signature: &str) -> Result<Address, Error> {
+    recover(typed_data_hash(typed_data)?, signature)
+}
+
+/// Whether `address` signed `typed_data` with `eth_signTypedData_v4`
+pub fn verify_typed_data(
+    address: Address,
+    typed_data: &TypedData,
+    signature: &str,
+) -> Result<bool, Error> {
+    Ok(recover_typed_data(typed_data, signature)? == address)
+}
+
+fn recover(hash: H256, signature: &str) -> Result<Address, Error> {
+    Signature::from_str(signature)
+        .and_then(|signature| signature.recover(hash))
+        .map_err(|e| Error::InvalidSignature(e.to_string()))
+}
+
+/// Displays an EIP-712 domain one field per line, so a user can see who a signature is for
+/// before signing. Fields the domain leaves out are skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct DomainDisplay<'a>(pub &'a EIP712Domain);
+
+impl fmt::Display for DomainDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let domain = self.0;
+        let mut lines = Vec::new();
+        if let Some(name) = &domain.name {
+            lines.push(format!("Name: {name}"));
+        }
+        if let Some(version) = &domain.version {
+            lines.push(format!("Version: {version}"));
+        }
+        if let Some(chain_id) = domain.chain_id {
+            lines.push(format!("Chain id: {chain_id}"));
+        }
+        if let Some(contract) = domain.verifying_contract {
+            lines.push(format!(
+                "Verifying contract: {}",
+                ethers::utils::to_checksum(&contract, None)
+            ));
+        }
+        if let Some(salt) = domain.salt {
+            lines.push(format!("Salt: 0x{}", hex::encode(salt)));
+        }
+        if lines.is_empty() {
+            return f.write_str("(empty domain)");
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from EIP-712, signed with the private key keccak256("cow")
+    const MAIL: &str = r#"{
+        "types": {
+            "EIP712Domain": [
+                {"name": "name", "type": "string"},
+                {"name": "version", "type": "string"},
+                {"name": "chainId", "type": "uint256"},
+                {"name": "verifyingContract", "type": "address"}
+            ],
+            "Person": [
+                {"name": "name", "type": "string"},
+                {"name": "wallet", "type": "address"}
+            ],
+            "Mail": [
+                {"name": "from", "type": "Person"},
+                {"name": "to", "type": "Person"},
+                {"name": "contents", "type": "string"}
+            ]
+        },
+        "primaryType": "Mail",
+        "domain": {
+            "name": "Ether Mail",
+            "version": "1",
+            "chainId": 1,
+            "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+        },
+        "message": {
+            "from": {"name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826"},
+            "to": {"name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"},
+            "contents": "Hello, Bob!"
+        }
+    }"#;
+    const MAIL_SIGNATURE: &str = "0x4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b915621c";
+
+    #[test]
+    fn recovers_eip712_signer() {
+        let mail = parse_typed_data(MAIL).unwrap();
+        assert_eq!(
+            typed_data_hash(&mail).unwrap(),
+            H256::from_str("0xbe609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+                .unwrap()
+        );
+        let cow = Address::from_str("0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826").unwrap();
+        assert_eq!(recover_typed_data(&mail, MAIL_SIGNATURE).unwrap(), cow);
+        assert!(verify_typed_data(cow, &mail, MAIL_SIGNATURE).unwrap());
+        assert!(!verify_message(cow, b"Hello, Bob!", MAIL_SIGNATURE).unwrap());
+        assert!(matches!(
+            recover_typed_data(&mail, "0x1234"),
+            Err(Error::InvalidSignature(_))
+        ));
+
+        assert!(check_domain_chain_id(&mail.domain, 1).is_ok());
+        assert!(check_domain_chain_id(&mail.domain, 11155111).is_err());
+        assert_eq!(
+            DomainDisplay(&mail.domain).to_string(),
+            "Name: Ether Mail\nVersion: 1\nChain id: 1\n\
+             Verifying contract: 0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+        );
+        assert!(matches!(
+            parse_typed_data("{\"domain\": {}}"),
+            Err(Error::TypedData(_))
+        ));
+    }
+}
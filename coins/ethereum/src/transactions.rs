@@ -0,0 +1,194 @@
+//! Broadcasting, replacing and following type 2 transactions with a [NonceManager].
+//!
+//! [EthereumWallet][crate::EthereumWallet] sends through these with its own key and nonce
+//! manager; they take any local signer, so wallets holding a bare private key track their
+//! transactions the same way.
+
+use crate::{Error, EthClient, FeePreset, NonceManager, TrackedTransaction};
+
+use ethers::prelude::*;
+use std::sync::{Mutex, MutexGuard};
+
+/// Signs and broadcasts a type 2 transaction from `signer` and tracks it in `nonces`,
+/// returning its hash without waiting for it to be mined. `replaces` is the transaction it
+/// replaces at the same nonce, if any.
+///
+/// The chain id comes from the provider and, unless the request sets one, the nonce from
+/// `nonces`, so concurrent sends don't collide. Fees the request leaves unset come from
+/// `eth_feeHistory` for the preset, and gas from `eth_estimateGas`, except that a plain
+/// transfer to an account without code uses 21000.
+pub async fn broadcast(
+    provider: &Provider<Http>,
+    signer: &LocalWallet,
+    nonces: &Mutex<NonceManager>,
+    tx: Eip1559TransactionRequest,
+    preset: FeePreset,
+    replaces: Option<H256>,
+) -> Result<H256, Error> {
+    let chain_id = EthClient::chain_id(provider).await?.as_u64();
+    let signer = signer.clone().with_chain_id(chain_id);
+    let from = signer.address();
+    let mut tx = tx.from(from).chain_id(chain_id);
+
+    if tx.max_fee_per_gas.is_none() || tx.max_priority_fee_per_gas.is_none() {
+        let fees = EthClient::fee_estimate(provider, preset).await?;
+        tx.max_fee_per_gas.get_or_insert(fees.max_fee_per_gas);
+        tx.max_priority_fee_per_gas
+            .get_or_insert(fees.max_priority_fee_per_gas);
+    }
+    if tx.gas.is_none() {
+        let gas = gas_limit(provider, &tx).await?;
+        tx = tx.gas(gas);
+    }
+    let reserved = match tx.nonce {
+        Some(_) => None,
+        None => {
+            let chain_nonce = EthClient::pending_transaction_count(provider, from).await?;
+            let nonce = lock(nonces)?.reserve_nonce(chain_id, from, chain_nonce);
+            tx = tx.nonce(nonce);
+            Some(nonce)
+        }
+    };
+
+    let client = SignerMiddleware::new(provider.clone(), signer);
+    let sent = client.send_transaction(tx.clone(), None).await;
+    let mut nonces = lock(nonces)?;
+    let tx_hash = match sent {
+        Ok(pending) => pending.tx_hash(),
+        Err(e) => {
+            if let Some(nonce) = reserved {
+                nonces.release_nonce(chain_id, from, nonce);
+            }
+            return Err(Error::SendTransaction(e.to_string()));
+        }
+    };
+    let mut tracked = TrackedTransaction::new(tx_hash, chain_id, from, tx)?;
+    tracked.replaces = replaces;
+    nonces.record(tracked);
+    nonces.save()?;
+    Ok(tx_hash)
+}
+
+/// Replaces a pending or dropped transaction from `signer` with the same one paying higher
+/// fees, at least [REPLACEMENT_FEE_BUMP][crate::REPLACEMENT_FEE_BUMP] percent more or the
+/// preset's current fees, and returns the replacement's hash.
+pub async fn speed_up(
+    provider: &Provider<Http>,
+    signer: &LocalWallet,
+    nonces: &Mutex<NonceManager>,
+    tx_hash: H256,
+    preset: FeePreset,
+) -> Result<H256, Error> {
+    let original = replaceable(nonces, signer.address(), tx_hash)?;
+    let fees = EthClient::fee_estimate(provider, preset).await?;
+    let fees = original.fees().replacement(fees);
+    let tx = original
+        .request
+        .max_fee_per_gas(fees.max_fee_per_gas)
+        .max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+    broadcast(provider, signer, nonces, tx, preset, Some(tx_hash)).await
+}
+
+/// Replaces a pending or dropped transaction from `signer` with a 0-value transfer to its own
+/// address at the same nonce, paying replacement fees as in [speed_up], and returns the
+/// cancellation's hash.
+pub async fn cancel(
+    provider: &Provider<Http>,
+    signer: &LocalWallet,
+    nonces: &Mutex<NonceManager>,
+    tx_hash: H256,
+    preset: FeePreset,
+) -> Result<H256, Error> {
+    let original = replaceable(nonces, signer.address(), tx_hash)?;
+    let fees = EthClient::fee_estimate(provider, preset).await?;
+    let fees = original.fees().replacement(fees);
+    let tx = Eip1559TransactionRequest::new()
+        .to(original.from)
+        .value(0)
+        .nonce(original.nonce)
+        .gas(21000)
+        .max_fee_per_gas(fees.max_fee_per_gas)
+        .max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+    broadcast(provider, signer, nonces, tx, preset, Some(tx_hash)).await
+}
+
+/// Transactions sent from `from` on the provider's chain that are not final yet, oldest first
+pub async fn tracked_transactions(
+    provider: &Provider<Http>,
+    from: Address,
+    nonces: &Mutex<NonceManager>,
+) -> Result<Vec<TrackedTransaction>, Error> {
+    let chain_id = EthClient::chain_id(provider).await?.as_u64();
+    Ok(lock(nonces)?
+        .transactions(chain_id, from)
+        .into_iter()
+        .cloned()
+        .collect())
+}
+
+/// Checks every tracked transaction from `from` against the node, noticing ones that were
+/// mined, dropped from the mempool, replaced or reorged out, and returns the transactions
+/// still tracked.
+pub async fn sync_transactions(
+    provider: &Provider<Http>,
+    from: Address,
+    nonces: &Mutex<NonceManager>,
+) -> Result<Vec<TrackedTransaction>, Error> {
+    let mined_nonce = EthClient::transaction_count(provider, from).await?;
+    let head = EthClient::current_block_number(provider).await?;
+
+    for tx in tracked_transactions(provider, from, nonces).await? {
+        let observation = EthClient::observe_transaction(provider, tx.hash).await?;
+        let mut nonces = lock(nonces)?;
+        // An earlier update may have finished tracking this one
+        if nonces.transaction(tx.hash).is_some() {
+            nonces.update(tx.hash, observation, mined_nonce, head)?;
+        }
+    }
+    lock(nonces)?.save()?;
+    tracked_transactions(provider, from, nonces).await
+}
+
+/// A tracked transaction from `from` that can still be replaced
+fn replaceable(
+    nonces: &Mutex<NonceManager>,
+    from: Address,
+    tx_hash: H256,
+) -> Result<TrackedTransaction, Error> {
+    let nonces = lock(nonces)?;
+    let tx = nonces
+        .transaction(tx_hash)
+        .filter(|tx| tx.from == from)
+        .ok_or_else(|| Error::UnknownTransaction(format!("{tx_hash:?}")))?;
+    if !tx.is_replaceable() {
+        return Err(Error::NotReplaceable(format!(
+            "{tx_hash:?} is {:?}",
+            tx.state
+        )));
+    }
+    Ok(tx.clone())
+}
+
+pub(crate) fn lock(nonces: &Mutex<NonceManager>) -> Result<MutexGuard<'_, NonceManager>, Error> {
+    nonces
+        .lock()
+        .map_err(|_| Error::PendingStore("Nonce manager lock poisoned".to_string()))
+}
+
+/// 21000 for a plain transfer to an account without code, otherwise `eth_estimateGas`
+async fn gas_limit(
+    provider: &Provider<Http>,
+    tx: &Eip1559TransactionRequest,
+) -> Result<U256, Error> {
+    let has_data = tx.data.as_ref().is_some_and(|data| !data.is_empty());
+    if let (false, Some(NameOrAddress::Address(to))) = (has_data, &tx.to) {
+        let code = provider
+            .get_code(*to, None)
+            .await
+            .map_err(|e| Error::Provider(e.to_string()))?;
+        if code.is_empty() {
+            return Ok(21000.into());
+        }
+    }
+    EthClient::estimate_gas(provider, &tx.clone().into()).await
+}
@@ -8,6 +8,7 @@
 //! error from the underlying contract call.
 
 use async_trait::async_trait;
+use ethers::contract::abigen;
 use ethers::contract::ContractError;
 use ethers::middleware::SignerMiddleware;
 use ethers::providers::{Http, Provider};
@@ -15,6 +16,10 @@ use ethers::signers::Signer;
 use ethers::types::{Address, H256, U256};
 use std::sync::Arc;
 
+// Generate a Rust type safe wrapper for the ERC‑20 contract.
+// The ABI file is stored in the crate root under `abi/erc20.json`.
+abigen!(Erc20Contract, "./abi/erc20.json");
+
 /// Common interface for interacting with ERC‑20 tokens.
 ///
 /// Token implementations provide the on‑chain address, symbol and
@@ -34,7 +39,14 @@ pub trait Erc20Adapter: Send + Sync + 'static {
     fn decimals(&self) -> u8;
 
     /// Returns the short symbol (ticker) for this token.
-    fn symbol(&self) -> &'static str;
+    fn symbol(&self) -> &str;
+
+    /// Formats an amount in the token's smallest unit as a decimal
+    /// number of whole tokens, e.g. `1500000` as `1.5` for a token
+    /// with six decimals.
+    fn format_amount(&self, amount: U256) -> String {
+        format_amount(amount, self.decimals())
+    }
 
     /// Queries the balance of `owner` for this token. Returns the
     /// underlying `ContractError<Provider<Http>>` specialised on the
@@ -81,3 +93,24 @@ pub trait Erc20Adapter: Send + Sync + 'static {
     where
         S: Signer + 'static + Send + Sync;
 }
+
+/// Formats `amount`, given in a token's smallest unit, as a decimal
+/// number of whole tokens with `decimals` fractional digits. Trailing
+/// zeros of the fraction are dropped, so `1000000` with six decimals
+/// is `1` and `1` with six decimals is `0.000001`.
+pub fn format_amount(amount: U256, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    let (whole, fraction) = if digits.len() > decimals {
+        let (whole, fraction) = digits.split_at(digits.len() - decimals);
+        (whole.to_string(), fraction.to_string())
+    } else {
+        ("0".to_string(), format!("{digits:0>decimals$}"))
+    };
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole
+    } else {
+        format!("{whole}.{fraction}")
+    }
+}
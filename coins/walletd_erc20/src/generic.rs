@@ -0,0 +1,142 @@
+//! Adapter for any ERC‑20 token.
+//!
+//! This module defines the [`GenericErc20`] type which implements the
+//! [`Erc20Adapter`](crate::adapter::Erc20Adapter) trait for any
+//! contract following the standard interface, so new tokens don't
+//! need a dedicated adapter type.  Token metadata either comes from
+//! the chain, via [`GenericErc20::from_chain`], or from an entry of a
+//! [`TokenRegistry`](crate::registry::TokenRegistry).
+
+use std::sync::Arc;
+
+use ethers::contract::ContractError;
+use ethers::middleware::SignerMiddleware;
+use ethers::providers::{Http, Provider};
+use ethers::signers::Signer;
+use ethers::types::{Address, H256, U256};
+
+use crate::adapter::{Erc20Adapter, Erc20Contract};
+use crate::registry::TokenInfo;
+
+/// An adapter for an arbitrary ERC‑20 token contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenericErc20 {
+    address: Address,
+    name: String,
+    symbol: String,
+    decimals: u8,
+}
+
+impl GenericErc20 {
+    /// Creates an adapter for the token at `address` with known
+    /// metadata.
+    pub fn new(
+        address: Address,
+        name: impl Into<String>,
+        symbol: impl Into<String>,
+        decimals: u8,
+    ) -> Self {
+        Self {
+            address,
+            name: name.into(),
+            symbol: symbol.into(),
+            decimals,
+        }
+    }
+
+    /// Creates an adapter for the token at `address`, reading its
+    /// `decimals`, `symbol` and `name` from the contract.  Tokens that
+    /// predate the standard and return `bytes32` for their symbol or
+    /// name (such as MKR) fail to decode and should be registered with
+    /// [`new`](Self::new) instead.
+    pub async fn from_chain(
+        provider: &Provider<Http>,
+        address: Address,
+    ) -> Result<Self, ContractError<Provider<Http>>> {
+        let contract = Erc20Contract::new(address, provider.clone().into());
+        let decimals = contract.decimals().call().await?;
+        let symbol = contract.symbol().call().await?;
+        let name = contract.name().call().await?;
+        Ok(Self::new(address, name, symbol, decimals))
+    }
+
+    /// Returns the full name of the token, e.g. "USD Coin".
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl From<&TokenInfo> for GenericErc20 {
+    fn from(token: &TokenInfo) -> Self {
+        Self::new(
+            token.address,
+            token.name.clone(),
+            token.symbol.clone(),
+            token.decimals,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl Erc20Adapter for GenericErc20 {
+    fn contract_address(&self) -> Address {
+        self.address
+    }
+
+    fn decimals(&self) -> u8 {
+        self.decimals
+    }
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    async fn balance_of(
+        &self,
+        provider: &Provider<Http>,
+        owner: Address,
+    ) -> Result<U256, ContractError<Provider<Http>>> {
+        let contract = Erc20Contract::new(self.address, provider.clone().into());
+        contract.balance_of(owner).call().await
+    }
+
+    async fn allowance(
+        &self,
+        provider: &Provider<Http>,
+        owner: Address,
+        spender: Address,
+    ) -> Result<U256, ContractError<Provider<Http>>> {
+        let contract = Erc20Contract::new(self.address, provider.clone().into());
+        contract.allowance(owner, spender).call().await
+    }
+
+    async fn transfer<S>(
+        &self,
+        client: &Arc<SignerMiddleware<Provider<Http>, S>>,
+        to: Address,
+        amount: U256,
+    ) -> Result<H256, ContractError<SignerMiddleware<Provider<Http>, S>>>
+    where
+        S: Signer + 'static + Send + Sync,
+    {
+        let contract = Erc20Contract::new(self.address, client.clone());
+        let call = contract.transfer(to, amount);
+        let pending_tx = call.send().await?;
+        Ok(*pending_tx)
+    }
+
+    async fn approve<S>(
+        &self,
+        client: &Arc<SignerMiddleware<Provider<Http>, S>>,
+        spender: Address,
+        amount: U256,
+    ) -> Result<H256, ContractError<SignerMiddleware<Provider<Http>, S>>>
+    where
+        S: Signer + 'static + Send + Sync,
+    {
+        let contract = Erc20Contract::new(self.address, client.clone());
+        let call = contract.approve(spender, amount);
+        let pending_tx = call.send().await?;
+        Ok(*pending_tx)
+    }
+}
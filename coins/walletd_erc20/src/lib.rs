@@ -34,15 +34,40 @@
 //!
 //! See the [`usdc`](crate::usdc) module for additional details about
 //! the USD Coin adapter and its extra bridging helpers.
+//!
+//! Any other token can be used through [`GenericErc20`](crate::generic::GenericErc20),
+//! either read from the chain or taken from a
+//! [`TokenRegistry`](crate::registry::TokenRegistry) loaded from a
+//! Uniswap‑style token list:
+//!
+//! ```no_run
+//! use ethers::providers::{Provider, Http};
+//! use ethers::types::Address;
+//! use walletd_erc20::prelude::*;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let provider = Provider::<Http>::try_from("https://rpc.sepolia.org")?;
+//! let owner: Address = "0x0000000000000000000000000000000000000001".parse()?;
+//! let registry = TokenRegistry::builtin();
+//! for token in registry.adapters(walletd_erc20::registry::SEPOLIA) {
+//!     let balance = token.balance_of(&provider, owner).await?;
+//!     println!("{}: {}", token.symbol(), token.format_amount(balance));
+//! }
+//! # Ok(())
+//! # }
+//! ```
 
 #![forbid(unsafe_code)]
 #![allow(missing_docs)]
 
 pub mod adapter;
+pub mod generic;
+pub mod registry;
 pub mod usdc;
 
 /// Exposes commonly used types when working with ERC‑20 tokens.
 pub mod prelude {
     pub use super::adapter::Erc20Adapter;
+    pub use super::generic::GenericErc20;
+    pub use super::registry::{TokenInfo, TokenRegistry};
     pub use super::usdc::UsdcAdapter;
 }
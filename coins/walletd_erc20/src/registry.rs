@@ -0,0 +1,237 @@
+//! Token registry loaded from Uniswap‑style token lists.
+//!
+//! A [token list](https://tokenlists.org) is a JSON document whose
+//! `tokens` array names each token's `chainId`, `address`, `name`,
+//! `symbol` and `decimals`.  [`TokenRegistry`] indexes those entries
+//! by chain id so a wallet can find every token it knows about on the
+//! network it is connected to.  [`TokenRegistry::builtin`] ships a
+//! list covering Ethereum mainnet, Sepolia and Base; further lists can
+//! be merged in with [`TokenRegistry::extend_from_json`].
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::generic::GenericErc20;
+
+/// Chain id of Ethereum mainnet.
+pub const MAINNET: u64 = 1;
+/// Chain id of the Sepolia testnet.
+pub const SEPOLIA: u64 = 11_155_111;
+/// Chain id of Base mainnet.
+pub const BASE: u64 = 8453;
+
+/// Token list bundled with the crate.
+const BUILTIN_TOKEN_LIST: &str = include_str!("../tokens/walletd.tokenlist.json");
+
+/// One token of a token list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenInfo {
+    /// Chain the contract is deployed on.
+    pub chain_id: u64,
+    /// Contract address.
+    pub address: Address,
+    /// Full name, e.g. "USD Coin".
+    pub name: String,
+    /// Ticker, e.g. "USDC".
+    pub symbol: String,
+    /// Number of fractional digits of the token's amounts.
+    pub decimals: u8,
+    /// Link to the token's logo.
+    #[serde(rename = "logoURI", default, skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+}
+
+/// The parts of a token list the registry reads; other fields such as
+/// `version`, `timestamp` and `tags` are ignored.
+#[derive(Deserialize)]
+struct TokenList {
+    tokens: Vec<TokenInfo>,
+}
+
+/// Errors raised while loading a token list.
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    /// The token list could not be read.
+    #[error("Failed to read token list: {0}")]
+    Io(#[from] std::io::Error),
+    /// The token list is not valid token list JSON.
+    #[error("Invalid token list: {0}")]
+    Json(#[from] serde_json::Error),
+    /// The same contract appears twice for one chain in a list.
+    #[error("Token {address:?} is listed twice for chain {chain_id}")]
+    Duplicate { chain_id: u64, address: Address },
+}
+
+/// Known tokens, indexed by chain id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenRegistry {
+    tokens: BTreeMap<u64, Vec<TokenInfo>>,
+}
+
+impl TokenRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry holding the token list bundled with the
+    /// crate, covering Ethereum mainnet, Sepolia and Base.
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN_TOKEN_LIST).expect("bundled token list is valid")
+    }
+
+    /// Creates a registry from token list JSON.
+    pub fn from_json(json: &str) -> Result<Self, RegistryError> {
+        let mut registry = Self::new();
+        registry.extend_from_json(json)?;
+        Ok(registry)
+    }
+
+    /// Creates a registry from a token list file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RegistryError> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Adds every token of a token list.  Tokens already registered at
+    /// the same chain and address are replaced by the list's entry; the
+    /// registry is left unchanged if the list is invalid.
+    pub fn extend_from_json(&mut self, json: &str) -> Result<(), RegistryError> {
+        let list: TokenList = serde_json::from_str(json)?;
+        let mut seen = std::collections::HashSet::new();
+        for token in &list.tokens {
+            if !seen.insert((token.chain_id, token.address)) {
+                return Err(RegistryError::Duplicate {
+                    chain_id: token.chain_id,
+                    address: token.address,
+                });
+            }
+        }
+        for token in list.tokens {
+            self.insert(token);
+        }
+        Ok(())
+    }
+
+    /// Registers a token, replacing any entry at the same chain and
+    /// address.  Tokens of a chain are kept sorted by symbol.
+    pub fn insert(&mut self, token: TokenInfo) {
+        let tokens = self.tokens.entry(token.chain_id).or_default();
+        tokens.retain(|t| t.address != token.address);
+        tokens.push(token);
+        tokens.sort_by_key(|t| t.symbol.to_uppercase());
+    }
+
+    /// Returns the tokens registered for `chain_id`, sorted by symbol.
+    pub fn tokens(&self, chain_id: u64) -> &[TokenInfo] {
+        self.tokens.get(&chain_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the chain ids that have registered tokens.
+    pub fn chain_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.tokens.keys().copied()
+    }
+
+    /// Finds a token on `chain_id` by symbol, ignoring case.
+    pub fn find(&self, chain_id: u64, symbol: &str) -> Option<&TokenInfo> {
+        self.tokens(chain_id)
+            .iter()
+            .find(|t| t.symbol.eq_ignore_ascii_case(symbol))
+    }
+
+    /// Finds a token on `chain_id` by contract address.
+    pub fn by_address(&self, chain_id: u64, address: Address) -> Option<&TokenInfo> {
+        self.tokens(chain_id).iter().find(|t| t.address == address)
+    }
+
+    /// Returns an adapter for every token registered for `chain_id`.
+    pub fn adapters(&self, chain_id: u64) -> Vec<GenericErc20> {
+        self.tokens(chain_id)
+            .iter()
+            .map(GenericErc20::from)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::{format_amount, Erc20Adapter};
+
+    #[test]
+    fn builtin_list_covers_supported_chains() {
+        let registry = TokenRegistry::builtin();
+        assert_eq!(
+            registry.chain_ids().collect::<Vec<_>>(),
+            [MAINNET, BASE, SEPOLIA]
+        );
+
+        let usdc = registry.find(MAINNET, "usdc").unwrap();
+        assert_eq!(
+            usdc.address,
+            "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(usdc.decimals, 6);
+        assert_eq!(registry.by_address(MAINNET, usdc.address), Some(usdc));
+        assert!(registry.find(SEPOLIA, "USDC").is_some());
+        assert!(registry.find(BASE, "USDC").is_some());
+
+        let adapters = registry.adapters(BASE);
+        assert_eq!(adapters.len(), registry.tokens(BASE).len());
+        let symbols: Vec<_> = adapters.iter().map(|a| a.symbol()).collect();
+        let mut sorted = symbols.clone();
+        sorted.sort_by_key(|s| s.to_uppercase());
+        assert_eq!(symbols, sorted);
+        assert!(registry.tokens(137).is_empty());
+    }
+
+    #[test]
+    fn token_lists_merge_and_reject_duplicates() {
+        let list = |tokens: &str| {
+            format!(r#"{{"name": "Test", "version": {{"major": 1}}, "tokens": [{tokens}]}}"#)
+        };
+        let token = |symbol: &str, decimals: u8| {
+            format!(
+                r#"{{"chainId": 1, "address": "0x1111111111111111111111111111111111111111",
+                    "name": "Test", "symbol": "{symbol}", "decimals": {decimals}}}"#
+            )
+        };
+
+        let mut registry = TokenRegistry::builtin();
+        let before = registry.tokens(MAINNET).len();
+        registry.extend_from_json(&list(&token("TST", 4))).unwrap();
+        assert_eq!(registry.tokens(MAINNET).len(), before + 1);
+        // A later list replaces the entry at the same address
+        registry.extend_from_json(&list(&token("TST2", 8))).unwrap();
+        assert_eq!(registry.tokens(MAINNET).len(), before + 1);
+        assert_eq!(registry.find(MAINNET, "tst2").unwrap().decimals, 8);
+        assert!(registry.find(MAINNET, "TST").is_none());
+
+        let duplicate = list(&format!("{}, {}", token("A", 1), token("B", 1)));
+        assert!(matches!(
+            TokenRegistry::from_json(&duplicate),
+            Err(RegistryError::Duplicate { chain_id: 1, .. })
+        ));
+        assert!(matches!(
+            TokenRegistry::from_json("{\"tokens\": [{\"chainId\": 1}]}"),
+            Err(RegistryError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn amounts_are_formatted_with_token_decimals() {
+        assert_eq!(format_amount(1_500_000u64.into(), 6), "1.5");
+        assert_eq!(format_amount(1_000_000u64.into(), 6), "1");
+        assert_eq!(format_amount(1u64.into(), 6), "0.000001");
+        assert_eq!(format_amount(0u64.into(), 18), "0");
+        assert_eq!(format_amount(123u64.into(), 0), "123");
+        let wbtc = GenericErc20::new(Address::zero(), "Wrapped BTC", "WBTC", 8);
+        assert_eq!(wbtc.format_amount(2_100_000_000u64.into()), "21");
+    }
+}
@@ -11,18 +11,13 @@
 
 use std::sync::Arc;
 
-use ethers::contract::abigen;
 use ethers::contract::ContractError;
 use ethers::middleware::SignerMiddleware;
 use ethers::providers::{Http, Provider};
 use ethers::signers::Signer;
 use ethers::types::{Address, H256, U256};
 
-use crate::adapter::Erc20Adapter;
-
-// Generate a Rust type safe wrapper for the ERC‑20 contract.
-// The ABI file is stored in the crate root under `abi/erc20.json`.
-abigen!(Erc20Contract, "./abi/erc20.json");
+use crate::adapter::{Erc20Adapter, Erc20Contract};
 
 /// The canonical mainnet address for USD Coin (USDC).
 ///
@@ -50,7 +45,7 @@ impl Erc20Adapter for UsdcAdapter {
         6
     }
 
-    fn symbol(&self) -> &'static str {
+    fn symbol(&self) -> &str {
         "USDC"
     }
 
@@ -13,6 +13,9 @@ impl BitcoinTransactionManager {
         let config = walletd_bitcoin::BitcoinConfig {
             network,
             rpc_endpoints: vec![],
+            storage: None,
+            electrum_url: None,
+            security: Default::default(),
         };
         
         let wallet_manager = BitcoinWalletManager::new(config).await?;
@@ -221,16 +221,35 @@ async fn list_channels(manager: &LightningManager) -> Result<(), String> {
     Ok(())
 }
 
-async fn close_channel(_manager: &LightningManager) -> Result<(), String> {
+async fn close_channel(manager: &LightningManager) -> Result<(), String> {
     println!("\n=== Close Channel ===");
     
+    print!("Enter your user ID: ");
+    io::stdout().flush().unwrap();
+    let mut user_id = String::new();
+    io::stdin().read_line(&mut user_id).map_err(|e| e.to_string())?;
+    
     print!("Enter channel ID: ");
     io::stdout().flush().unwrap();
     let mut channel_id = String::new();
     io::stdin().read_line(&mut channel_id).map_err(|e| e.to_string())?;
     
-    println!("\nChannel closing initiated for: {}", channel_id.trim());
-    println!("Status: Closing (broadcasting transaction)");
+    print!("Force close? (yes/no): ");
+    io::stdout().flush().unwrap();
+    let mut force = String::new();
+    io::stdin().read_line(&mut force).map_err(|e| e.to_string())?;
+    let force = force.trim().to_lowercase() == "yes";
+    
+    match manager
+        .close_channel(user_id.trim(), channel_id.trim(), force)
+        .await
+    {
+        Ok(()) => {
+            println!("\nChannel closing initiated for: {}", channel_id.trim());
+            println!("Status: Closing (broadcasting transaction)");
+        }
+        Err(e) => println!("❌ Failed to close channel: {}", e),
+    }
     
     Ok(())
 }
@@ -248,11 +267,55 @@ async fn send_payment(manager: &LightningManager) -> Result<(), String> {
     let mut invoice = String::new();
     io::stdin().read_line(&mut invoice).map_err(|e| e.to_string())?;
     
-    // Parse invoice (mock)
+    let decoded = match manager.decode_invoice(invoice.trim()) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            println!("❌ Invalid invoice: {}", e);
+            return Ok(());
+        }
+    };
+
     println!("\n📋 Invoice Details:");
-    println!("Amount: 10,000 sats");
-    println!("Description: Coffee payment");
-    println!("Expiry: 10 minutes");
+    match decoded.amount_msat {
+        Some(msat) => println!("Amount: {} sats ({} msat)", msat / 1000, msat),
+        None => println!("Amount: not specified"),
+    }
+    if let Some(description) = &decoded.description {
+        println!("Description: {}", description);
+    } else if let Some(hash) = &decoded.description_hash {
+        println!("Description hash: {}", hash);
+    }
+    println!("Payee: {}", decoded.payee);
+    println!("Payment hash: {}", decoded.payment_hash);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!(
+        "Expires in: {} minutes",
+        decoded.expires_at().saturating_sub(now) / 60
+    );
+    println!("Final CLTV delta: {} blocks", decoded.min_final_cltv_expiry_delta);
+    for (i, hint) in decoded.route_hints.iter().enumerate() {
+        println!("Route hint {}: {} hop(s)", i + 1, hint.0.len());
+        for hop in &hint.0 {
+            println!(
+                "  via {} (channel {}, fee {} msat + {} ppm, cltv {})",
+                hop.src_node_id,
+                hop.short_channel_id,
+                hop.fee_base_msat,
+                hop.fee_proportional_millionths,
+                hop.cltv_expiry_delta
+            );
+        }
+    }
+    for address in &decoded.fallbacks {
+        println!("On-chain fallback: {}", address);
+    }
+    if decoded.amount_msat.is_none() {
+        println!("❌ Invoices without an amount can't be paid from this menu");
+        return Ok(());
+    }
     
     print!("\nSend payment? (yes/no): ");
     io::stdout().flush().unwrap();
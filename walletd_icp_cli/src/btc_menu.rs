@@ -31,6 +31,7 @@ pub async fn handle_btc_menu(
         println!("[13] Swap Bitcoin");
         println!("[14] Cross-Chain Bridge");
         println!("[15] Hardware Wallet");
+        println!("[16] Speed Up Transaction (RBF / CPFP)");
 
         println!("\n[B] Back to Main Menu");
         println!("[X] Exit");
@@ -68,7 +69,29 @@ pub async fn handle_btc_menu(
                 }
             }
             "4" => {
-                println!("\nTransaction history functionality coming soon!");
+                use crate::wallet_integration::WALLET_MANAGER;
+                let manager = WALLET_MANAGER.read().await;
+                if let Some(btc) = &manager.bitcoin {
+                    if let Err(e) = btc.refresh_history().await {
+                        println!("Could not refresh confirmations: {e}");
+                    }
+                    let history = btc.transaction_history();
+                    if history.is_empty() {
+                        println!("\nNo transactions sent this session");
+                    }
+                    for (txid, status, fee, replaced_by) in history {
+                        println!("\n{txid}");
+                        println!("   Status: {status:?}, fee: {fee} sats");
+                        if let Some(replacement) = replaced_by {
+                            println!("   Replaced by: {replacement}");
+                        }
+                        if let Ok(Some(confirmed)) = btc.confirmed_txid(&txid.to_string()) {
+                            println!("   Confirmed as: {confirmed}");
+                        }
+                    }
+                } else {
+                    println!("Bitcoin wallet not initialized");
+                }
             }
             "5" => {
                 // CALL THE REAL SEND FUNCTION
@@ -86,7 +109,9 @@ pub async fn handle_btc_menu(
                 println!("\nBroadcast transaction functionality coming soon!");
             }
             "9" => {
-                println!("\nUTXO management functionality coming soon!");
+                if let Err(e) = handle_utxo_management().await {
+                    println!("Error: {e}");
+                }
             }
             "10" => {
                 println!("\nFee estimation functionality coming soon!");
@@ -106,6 +131,11 @@ pub async fn handle_btc_menu(
             "15" => {
                 println!("\nHardware wallet functionality coming soon!");
             }
+            "16" => {
+                if let Err(e) = handle_speed_up().await {
+                    println!("Error: {e}");
+                }
+            }
             "B" | "b" => return Ok(CliResponse::Continue),
             "X" | "x" => return Ok(CliResponse::Exit),
             _ => println!("Invalid option. Please try again."),
@@ -117,3 +147,126 @@ pub async fn handle_btc_menu(
         io::stdin().read_line(&mut pause).unwrap();
     }
 }
+
+async fn handle_speed_up() -> anyhow::Result<()> {
+    use crate::wallet_integration::WALLET_MANAGER;
+
+    let txid = prompt("Transaction ID to speed up: ")?;
+    let fee_rate: u64 = prompt("New fee rate (sat/vB): ")?.parse()?;
+    let method = prompt("[1] Replace (RBF)  [2] Child pays for parent (CPFP): ")?;
+
+    let manager = WALLET_MANAGER.read().await;
+    let btc = manager
+        .bitcoin
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Bitcoin wallet not initialized"))?;
+    let new_txid = match method.as_str() {
+        "1" => btc.bump_fee(&txid, fee_rate).await?,
+        "2" => btc.cpfp(&txid, fee_rate).await?,
+        _ => return Err(anyhow::anyhow!("Invalid option")),
+    };
+    println!("✅ Broadcast {new_txid}");
+    Ok(())
+}
+
+/// List coins with their labels, label or freeze them, and move labels to and from BIP329
+/// files such as Sparrow's label export
+async fn handle_utxo_management() -> anyhow::Result<()> {
+    use crate::wallet_integration::WALLET_MANAGER;
+    use bitcoin::{OutPoint, Txid};
+    use std::str::FromStr;
+    use walletd_bitcoin::labels::LabelType;
+
+    let manager = WALLET_MANAGER.read().await;
+    let btc = manager
+        .bitcoin
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Bitcoin wallet not initialized"))?;
+
+    let utxos = btc.get_utxos().await?;
+    let outpoints = utxos
+        .iter()
+        .map(|u| Ok(OutPoint::new(Txid::from_str(&u.txid)?, u.vout)))
+        .collect::<anyhow::Result<Vec<OutPoint>>>()?;
+    println!("\n=== UTXOs ===");
+    if let Some(label) = btc.label(LabelType::Addr, &btc.address.to_string()) {
+        println!("Address: {} ({label})", btc.address);
+    }
+    for (i, (utxo, outpoint)) in utxos.iter().zip(&outpoints).enumerate() {
+        let label = btc
+            .label(LabelType::Output, &outpoint.to_string())
+            .or_else(|| btc.label(LabelType::Tx, &utxo.txid))
+            .unwrap_or_default();
+        let status = if utxo.status.confirmed {
+            ""
+        } else {
+            " [unconfirmed]"
+        };
+        let frozen = if btc.is_frozen(outpoint) {
+            " [frozen]"
+        } else {
+            ""
+        };
+        println!(
+            "[{}] {outpoint} {} sats {label}{status}{frozen}",
+            i + 1,
+            utxo.value
+        );
+    }
+
+    println!("\n[1] Label a UTXO  [2] Label a transaction  [3] Label an address");
+    println!("[4] Freeze a UTXO  [5] Unfreeze a UTXO");
+    println!("[6] Import BIP329 labels  [7] Export BIP329 labels");
+    let choose = || -> anyhow::Result<OutPoint> {
+        let choice: usize = prompt("UTXO number: ")?.parse()?;
+        choice
+            .checked_sub(1)
+            .and_then(|i| outpoints.get(i))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Invalid choice"))
+    };
+    match prompt("Select option: ")?.as_str() {
+        "1" => {
+            let outpoint = choose()?;
+            let label = prompt("Label (Enter to remove): ")?;
+            btc.set_label(LabelType::Output, &outpoint.to_string(), &label)?;
+        }
+        "2" => {
+            let txid = prompt("Transaction ID: ")?;
+            let label = prompt("Label (Enter to remove): ")?;
+            btc.set_label(LabelType::Tx, &txid, &label)?;
+        }
+        "3" => {
+            let address = prompt("Address (Enter for this wallet's): ")?;
+            let address = if address.is_empty() {
+                btc.address.to_string()
+            } else {
+                address
+            };
+            let label = prompt("Label (Enter to remove): ")?;
+            btc.set_label(LabelType::Addr, &address, &label)?;
+        }
+        "4" => btc.set_frozen(&choose()?, true),
+        "5" => btc.set_frozen(&choose()?, false),
+        "6" => {
+            let path = prompt("BIP329 file to import: ")?;
+            let count = btc.import_labels(&std::fs::read_to_string(path)?)?;
+            println!("✅ Imported {count} labels");
+        }
+        "7" => {
+            let path = prompt("File to export to: ")?;
+            std::fs::write(&path, btc.export_labels()?)?;
+            println!("✅ Labels written to {path}");
+        }
+        _ => return Err(anyhow::anyhow!("Invalid option")),
+    }
+    Ok(())
+}
+
+fn prompt(label: &str) -> anyhow::Result<String> {
+    print!("{label}");
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
@@ -1,5 +1,14 @@
+use crate::wallet_integration::bitcoin_real::RealBitcoinWallet;
 use crate::wallet_integration::WALLET_MANAGER;
+use bitcoin::{FeeRate, OutPoint, Txid};
 use std::io::{self, Write};
+use std::str::FromStr;
+use walletd_bitcoin::labels::LabelType;
+use walletd_bitcoin::utxo_manager::CoinControl;
+use walletd_bitcoin::FeePriority;
+
+/// Virtual size of a one-input, two-output P2WPKH transaction
+const TYPICAL_TX_VSIZE: u64 = 141;
 
 pub async fn handle_send_bitcoin_real(_user_id: &str) -> Result<(), String> {
     let manager = WALLET_MANAGER.read().await;
@@ -28,35 +37,92 @@ pub async fn handle_send_bitcoin_real(_user_id: &str) -> Result<(), String> {
         io::stdin().read_line(&mut to_address).unwrap();
         let to_address = to_address.trim();
 
-        print!("Amount (BTC): ");
+        print!("Coins ([Enter] automatic, [c] choose, [s] spend all from chosen): ");
         io::stdout().flush().unwrap();
-        let mut amount_str = String::new();
-        io::stdin().read_line(&mut amount_str).unwrap();
-        let amount: f64 = amount_str.trim().parse().map_err(|_| "Invalid amount")?;
+        let mut mode = String::new();
+        io::stdin().read_line(&mut mode).unwrap();
+        let mut control = CoinControl::default();
+        let mut sweep = None;
+        match mode.trim() {
+            "c" | "C" => {
+                control.include = choose_coins(btc_wallet, "Always spend").await?;
+                control.exclude = choose_coins(btc_wallet, "Never spend").await?;
+            }
+            "s" | "S" => sweep = Some(choose_coins(btc_wallet, "Spend all of").await?),
+            _ => {}
+        }
 
-        let amount_sats = (amount * 100_000_000.0) as u64;
+        let amount_sats = match &sweep {
+            Some(outpoints) => {
+                let utxos = btc_wallet
+                    .get_utxos()
+                    .await
+                    .map_err(|e| format!("Failed to get UTXOs: {e}"))?;
+                utxos
+                    .iter()
+                    .filter(|u| {
+                        outpoints
+                            .iter()
+                            .any(|o| o.txid.to_string() == u.txid && o.vout == u.vout)
+                    })
+                    .map(|u| u.value)
+                    .sum()
+            }
+            None => {
+                print!("Amount (BTC): ");
+                io::stdout().flush().unwrap();
+                let mut amount_str = String::new();
+                io::stdin().read_line(&mut amount_str).unwrap();
+                let amount: f64 = amount_str.trim().parse().map_err(|_| "Invalid amount")?;
+                (amount * 100_000_000.0) as u64
+            }
+        };
+        let amount = amount_sats as f64 / 100_000_000.0;
 
-        print!("Fee rate (sat/vB, or Enter for auto): ");
+        print!("Fee rate (sat/vB, economy/normal/priority, or Enter for normal): ");
         io::stdout().flush().unwrap();
         let mut fee_str = String::new();
         io::stdin().read_line(&mut fee_str).unwrap();
+        let fee_str = fee_str.trim();
+
+        let fee_rate = if let Ok(sat_vb) = fee_str.parse::<u64>() {
+            FeeRate::from_sat_per_vb(sat_vb).ok_or("Invalid fee rate")?
+        } else {
+            let priority = if fee_str.is_empty() {
+                FeePriority::Normal
+            } else {
+                fee_str.parse::<FeePriority>().map_err(|e| e.to_string())?
+            };
+            btc_wallet
+                .estimate_fee_rate(priority)
+                .await
+                .map_err(|e| format!("Failed to estimate fee: {e}"))?
+        };
+        // One P2WPKH input, recipient and change
+        let estimated_fee = fee_rate.fee_vb(TYPICAL_TX_VSIZE).map_or(0, |f| f.to_sat());
 
         println!("\n📋 Transaction Summary:");
         println!("From: {}", btc_wallet.address);
         println!("To: {to_address}");
-        println!("Amount: {amount} BTC ({amount_sats} sats)");
+        match sweep {
+            Some(_) => println!("Amount: {amount} BTC ({amount_sats} sats) less the fee"),
+            None => println!("Amount: {amount} BTC ({amount_sats} sats)"),
+        }
         println!(
             "Available: {} BTC ({} sats)",
             balance as f64 / 100_000_000.0,
             balance
         );
-        println!("Fee: ~10,000 sats (0.0001 BTC)");
+        println!(
+            "Fee: ~{estimated_fee} sats at {} sat/vB",
+            fee_rate.to_sat_per_vb_ceil()
+        );
 
-        if amount_sats + 10000 > balance {
+        if sweep.is_none() && amount_sats + estimated_fee > balance {
             println!("\n❌ Insufficient funds!");
             println!(
                 "You need at least {} sats but only have {} sats",
-                amount_sats + 10000,
+                amount_sats + estimated_fee,
                 balance
             );
             return Ok(());
@@ -71,10 +137,20 @@ pub async fn handle_send_bitcoin_real(_user_id: &str) -> Result<(), String> {
             println!("\n📡 Creating and broadcasting transaction...");
 
             // ACTUALLY SEND THE TRANSACTION
-            match btc_wallet
-                .create_and_send_transaction(to_address, amount_sats)
-                .await
-            {
+            let sent = match &sweep {
+                Some(outpoints) => btc_wallet.sweep(to_address, outpoints, fee_rate).await,
+                None => {
+                    btc_wallet
+                        .create_and_send_transaction_with_coin_control(
+                            to_address,
+                            amount_sats,
+                            fee_rate,
+                            &control,
+                        )
+                        .await
+                }
+            };
+            match sent {
                 Ok(txid) => {
                     println!("\n✅ TRANSACTION BROADCAST SUCCESSFULLY!");
                     println!("Transaction ID: {txid}");
@@ -108,3 +184,47 @@ pub async fn handle_send_bitcoin_real(_user_id: &str) -> Result<(), String> {
 
     Ok(())
 }
+
+/// List the wallet's UTXOs with their labels and read a comma-separated choice of them
+async fn choose_coins(wallet: &RealBitcoinWallet, action: &str) -> Result<Vec<OutPoint>, String> {
+    let utxos = wallet
+        .get_utxos()
+        .await
+        .map_err(|e| format!("Failed to get UTXOs: {e}"))?;
+    let mut outpoints = vec![];
+    for utxo in &utxos {
+        let txid = Txid::from_str(&utxo.txid).map_err(|e| e.to_string())?;
+        outpoints.push(OutPoint::new(txid, utxo.vout));
+    }
+
+    println!();
+    for (i, (utxo, outpoint)) in utxos.iter().zip(&outpoints).enumerate() {
+        let label = wallet
+            .label(LabelType::Output, &outpoint.to_string())
+            .or_else(|| wallet.label(LabelType::Tx, &utxo.txid))
+            .unwrap_or_default();
+        let frozen = if wallet.is_frozen(outpoint) {
+            " [frozen]"
+        } else {
+            ""
+        };
+        println!("[{}] {outpoint} {} sats {label}{frozen}", i + 1, utxo.value);
+    }
+    print!("{action} (numbers, comma separated, Enter for none): ");
+    io::stdout().flush().unwrap();
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    choice
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            c.parse::<usize>()
+                .ok()
+                .and_then(|i| outpoints.get(i.checked_sub(1)?))
+                .copied()
+                .ok_or_else(|| format!("Invalid choice: {c}"))
+        })
+        .collect()
+}
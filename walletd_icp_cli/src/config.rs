@@ -1,6 +1,7 @@
 //! Configuration for blockchain connections
 
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalletDConfig {
@@ -11,6 +12,17 @@ pub struct WalletDConfig {
     pub hedera: HederaConfig,
     pub icp: IcpConfig,
     pub demo_mode: bool,
+    /// Where wallet state such as pending transactions is kept between runs
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+}
+
+/// `~/.walletd`, or `.walletd` in the working directory when there is no home directory
+fn default_data_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".walletd")
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,6 +101,7 @@ impl Default for WalletDConfig {
                 identity_path: None,
             },
             demo_mode: false,
+            data_dir: default_data_dir(),
         }
     }
 }
@@ -1,18 +1,27 @@
 use crate::types::CliResponse;
+use crate::wallet_integration::WALLET_MANAGER;
+use ethers::types::Address;
 use std::io::{self, Write};
+use walletd_erc20::adapter::Erc20Adapter;
+use walletd_erc20::generic::GenericErc20;
+use walletd_erc20::registry::{TokenInfo, TokenRegistry};
 
 pub async fn handle_erc20_menu<T>(
     _wallet_api: &mut T,
     eth_address: &str,
     eth_balance: &str,
 ) -> Result<CliResponse, String> {
+    let mut registry = TokenRegistry::builtin();
+
     loop {
         println!("\n========== ERC-20 TOKEN MENU ==========");
         println!("Ethereum Address: {eth_address}");
         println!("ETH Balance: {eth_balance}");
 
-        println!("\n[1] Check Token Balance");
-        println!("[2] List Popular Tokens");
+        println!("\n[1] Token Balances");
+        println!("[2] List Registered Tokens");
+        println!("[3] Load Token List (JSON file)");
+        println!("[4] Add Token by Contract Address");
         println!("[B] Back");
 
         print!("\nChoice: ");
@@ -22,10 +31,143 @@ pub async fn handle_erc20_menu<T>(
         io::stdin().read_line(&mut choice).unwrap();
 
         match choice.trim() {
-            "1" => println!("✅ USDC Balance: 1000.00"),
-            "2" => println!("📋 Tokens: USDC, DAI, USDT, LINK"),
+            "1" => show_balances(&registry, eth_address).await,
+            "2" => list_tokens(&registry).await,
+            "3" => load_token_list(&mut registry),
+            "4" => add_token(&mut registry).await,
             "B" | "b" => return Ok(CliResponse::Continue),
             _ => println!("Invalid!"),
         }
     }
 }
+
+/// Print the balance of every token registered for the wallet's chain
+async fn show_balances(registry: &TokenRegistry, eth_address: &str) {
+    let Ok(owner) = eth_address.parse::<Address>() else {
+        println!("❌ Invalid Ethereum address: {eth_address}");
+        return;
+    };
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return;
+    };
+    let provider = match wallet.provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            println!("❌ {e}");
+            return;
+        }
+    };
+
+    let tokens = registry.adapters(wallet.chain_id);
+    if tokens.is_empty() {
+        println!("\nNo tokens registered for chain {}", wallet.chain_id);
+        return;
+    }
+
+    println!("\n💰 Token balances (chain {}):", wallet.chain_id);
+    for token in tokens {
+        match token.balance_of(provider, owner).await {
+            Ok(balance) => println!(
+                "  {:<8} {:>24}  {}",
+                token.symbol(),
+                token.format_amount(balance),
+                token.name()
+            ),
+            Err(e) => println!("  {:<8} ⚠️  {e}", token.symbol()),
+        }
+    }
+}
+
+/// Print the registered tokens of the wallet's chain, or of every chain
+async fn list_tokens(registry: &TokenRegistry) {
+    let chain_id = WALLET_MANAGER
+        .read()
+        .await
+        .ethereum
+        .as_ref()
+        .map(|wallet| wallet.chain_id);
+    let chains: Vec<u64> = match chain_id {
+        Some(chain_id) => vec![chain_id],
+        None => registry.chain_ids().collect(),
+    };
+
+    for chain_id in chains {
+        println!("\n📋 Tokens on chain {chain_id}:");
+        let tokens = registry.tokens(chain_id);
+        if tokens.is_empty() {
+            println!("  (none)");
+        }
+        for token in tokens {
+            println!(
+                "  {:<8} {:<24} {:#x} ({} decimals)",
+                token.symbol, token.name, token.address, token.decimals
+            );
+        }
+    }
+}
+
+/// Merge a Uniswap-style token list into the registry
+fn load_token_list(registry: &mut TokenRegistry) {
+    let path = prompt("Token list file: ");
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("❌ Could not read {path}: {e}");
+            return;
+        }
+    };
+    match registry.extend_from_json(&json) {
+        Ok(()) => println!("✅ Token list loaded"),
+        Err(e) => println!("❌ {e}"),
+    }
+}
+
+/// Register a token by reading its metadata from the chain
+async fn add_token(registry: &mut TokenRegistry) {
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return;
+    };
+    let provider = match wallet.provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            println!("❌ {e}");
+            return;
+        }
+    };
+    let Ok(address) = prompt("Token contract address: ").parse::<Address>() else {
+        println!("❌ Invalid address");
+        return;
+    };
+
+    match GenericErc20::from_chain(provider, address).await {
+        Ok(token) => {
+            println!(
+                "✅ Added {} ({}, {} decimals)",
+                token.symbol(),
+                token.name(),
+                token.decimals()
+            );
+            registry.insert(TokenInfo {
+                chain_id: wallet.chain_id,
+                address,
+                name: token.name().to_string(),
+                symbol: token.symbol().to_string(),
+                decimals: token.decimals(),
+                logo_uri: None,
+            });
+        }
+        Err(e) => println!("❌ Could not read token metadata: {e}"),
+    }
+}
+
+fn prompt(label: &str) -> String {
+    print!("{label}");
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).ok();
+    input.trim().to_string()
+}
@@ -1,6 +1,11 @@
 use crate::types::WalletDIcpApi;
+use crate::wallet_integration::ethereum_real::RealEthereumWallet;
+use crate::wallet_integration::WALLET_MANAGER;
 use crate::CliResponse;
+use ethers::types::transaction::eip712::TypedData;
+use ethers::types::{Address, H256};
 use std::io::{self, Write};
+use walletd_ethereum::signing;
 
 pub async fn handle_eth_menu(
     _wallet: &mut WalletDIcpApi,
@@ -42,6 +47,11 @@ pub async fn handle_eth_menu(
         println!("[19] ENS Operations");
         println!("[20] Smart Contract Interaction");
 
+        println!("\n--- Signing ---");
+        println!("[21] Sign Message (personal_sign)");
+        println!("[22] Sign Typed Data (EIP-712)");
+        println!("[23] Verify Signature");
+
         println!("\n[S] Swap to Another Coin");
         println!("[B] Back to Main Menu");
         println!("[X] Exit");
@@ -75,6 +85,9 @@ pub async fn handle_eth_menu(
             "18" => handle_layer2_networks().await?,
             "19" => handle_ens_lookup().await?,
             "20" => handle_smart_contract().await?,
+            "21" => handle_sign_message().await?,
+            "22" => handle_sign_typed_data().await?,
+            "23" => handle_verify_signature()?,
             "s" => return Ok(CliResponse::Swap),
             "b" => return Ok(CliResponse::Continue),
             "x" => return Ok(CliResponse::Exit),
@@ -177,28 +190,225 @@ async fn handle_speed_up_transaction() -> Result<(), String> {
     println!("\n=== Speed Up Transaction ===");
     println!("Replace pending transaction with higher gas fee");
 
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return Ok(());
+    };
+    let Some(tx_hash) = choose_pending_transaction(wallet).await? else {
+        return Ok(());
+    };
+
+    match wallet.speed_up(tx_hash).await {
+        Ok(replacement) => {
+            println!("\n✅ Transaction replaced with higher fee");
+            println!("New transaction hash: {replacement}");
+        }
+        Err(e) => println!("\n❌ Speed up failed: {e}"),
+    }
+
+    Ok(())
+}
+
+async fn handle_cancel_transaction() -> Result<(), String> {
+    println!("\n=== Cancel Transaction ===");
+    println!("Send 0 ETH to yourself with higher gas to cancel");
+
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return Ok(());
+    };
+    let Some(tx_hash) = choose_pending_transaction(wallet).await? else {
+        return Ok(());
+    };
+
+    match wallet.cancel(tx_hash).await {
+        Ok(cancellation) => {
+            println!("\n⚡ Cancellation transaction sent");
+            println!("Cancellation hash: {cancellation}");
+        }
+        Err(e) => println!("\n❌ Cancel failed: {e}"),
+    }
+
+    Ok(())
+}
+
+/// List the wallet's replaceable transactions and let the user pick one
+async fn choose_pending_transaction(wallet: &RealEthereumWallet) -> Result<Option<H256>, String> {
+    let pending: Vec<_> = wallet
+        .sync_pending()
+        .await
+        .map_err(|e| format!("Failed to check pending transactions: {e}"))?
+        .into_iter()
+        .filter(|tx| tx.is_replaceable())
+        .collect();
+    if pending.is_empty() {
+        println!("\nNo pending transactions");
+        return Ok(None);
+    }
+
     println!("\nPending transactions:");
-    println!("1. 0x123... - 0.5 ETH to 0xabc... (20 gwei)");
+    for (i, tx) in pending.iter().enumerate() {
+        let value = ethers::utils::format_ether(tx.request.value.unwrap_or_default());
+        let max_fee = ethers::utils::format_units(tx.fees().max_fee_per_gas, "gwei")
+            .unwrap_or_else(|_| "?".to_string());
+        println!(
+            "{}. {:#x} - nonce {} - {value} ETH ({max_fee} gwei max, {:?})",
+            i + 1,
+            tx.hash,
+            tx.nonce,
+            tx.state
+        );
+    }
 
-    print!("\nNew gas price (gwei): ");
+    print!("\nTransaction number: ");
     io::stdout().flush().unwrap();
-    let mut _gas = String::new();
-    io::stdin().read_line(&mut _gas).ok();
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).ok();
+    match choice.trim().parse::<usize>() {
+        Ok(n) if (1..=pending.len()).contains(&n) => Ok(Some(pending[n - 1].hash)),
+        _ => {
+            println!("Invalid selection");
+            Ok(None)
+        }
+    }
+}
 
-    println!("\n✅ Transaction replaced with higher fee");
+async fn handle_sign_message() -> Result<(), String> {
+    println!("\n=== Sign Message (personal_sign) ===");
+
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return Ok(());
+    };
+
+    let message = prompt("Message: ");
+    println!("\nSigning as 0x{:x}", wallet.address);
+    println!("Message: {message}");
+    if prompt("\nSign? (yes/no): ").to_lowercase() != "yes" {
+        println!("Cancelled");
+        return Ok(());
+    }
 
+    match wallet.sign_message(message.as_bytes()) {
+        Ok(signature) => println!("\n✅ Signature: {signature}"),
+        Err(e) => println!("\n❌ Signing failed: {e}"),
+    }
     Ok(())
 }
 
-async fn handle_cancel_transaction() -> Result<(), String> {
-    println!("\n=== Cancel Transaction ===");
-    println!("Send 0 ETH to yourself with higher gas to cancel");
+async fn handle_sign_typed_data() -> Result<(), String> {
+    println!("\n=== Sign Typed Data (EIP-712) ===");
+
+    let manager = WALLET_MANAGER.read().await;
+    let Some(wallet) = &manager.ethereum else {
+        println!("❌ Ethereum wallet not initialized");
+        return Ok(());
+    };
+    let Some(typed_data) = read_typed_data() else {
+        return Ok(());
+    };
+
+    // Show who the signature is for before anything is signed
+    println!("\n--- Domain ---");
+    println!("{}", signing::DomainDisplay(&typed_data.domain));
+    if let Err(e) = signing::check_domain_chain_id(&typed_data.domain, wallet.chain_id) {
+        println!("\n❌ {e}");
+        return Ok(());
+    }
+    println!("\n--- {} ---", typed_data.primary_type);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&typed_data.message).map_err(|e| e.to_string())?
+    );
+    println!("\nSigning as 0x{:x}", wallet.address);
+    if prompt("\nSign? (yes/no): ").to_lowercase() != "yes" {
+        println!("Cancelled");
+        return Ok(());
+    }
 
-    println!("\n⚡ Cancellation transaction sent");
+    match wallet.sign_typed_data(&typed_data) {
+        Ok(signature) => println!("\n✅ Signature: {signature}"),
+        Err(e) => println!("\n❌ Signing failed: {e}"),
+    }
+    Ok(())
+}
+
+fn handle_verify_signature() -> Result<(), String> {
+    println!("\n=== Verify Signature ===");
+    println!("[1] Message (personal_sign)");
+    println!("[2] Typed data (EIP-712)");
+
+    let recovered = match prompt("\nSelect: ").as_str() {
+        "1" => {
+            let message = prompt("Message: ");
+            let signature = prompt("Signature: ");
+            signing::recover_message(message.as_bytes(), &signature)
+        }
+        "2" => {
+            let Some(typed_data) = read_typed_data() else {
+                return Ok(());
+            };
+            let signature = prompt("Signature: ");
+            signing::recover_typed_data(&typed_data, &signature)
+        }
+        _ => {
+            println!("Invalid option");
+            return Ok(());
+        }
+    };
+    let recovered = match recovered {
+        Ok(address) => address,
+        Err(e) => {
+            println!("\n❌ {e}");
+            return Ok(());
+        }
+    };
 
+    println!(
+        "\nSigned by: {}",
+        ethers::utils::to_checksum(&recovered, None)
+    );
+    let expected = prompt("Expected signer (Enter to skip): ");
+    if !expected.is_empty() {
+        match expected.parse::<Address>() {
+            Ok(address) if address == recovered => println!("✅ Signature is valid"),
+            Ok(_) => println!("❌ Signature was made by a different address"),
+            Err(_) => println!("Invalid address"),
+        }
+    }
     Ok(())
 }
 
+/// Load `eth_signTypedData_v4` JSON from a file the user names
+fn read_typed_data() -> Option<TypedData> {
+    let path = prompt("Typed data JSON file: ");
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("❌ Could not read {path}: {e}");
+            return None;
+        }
+    };
+    match signing::parse_typed_data(&json) {
+        Ok(typed_data) => Some(typed_data),
+        Err(e) => {
+            println!("❌ {e}");
+            None
+        }
+    }
+}
+
+fn prompt(label: &str) -> String {
+    print!("{label}");
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).ok();
+    input.trim().to_string()
+}
+
 async fn handle_bridge_assets() -> Result<(), String> {
     println!("\n=== Bridge Assets ===");
 
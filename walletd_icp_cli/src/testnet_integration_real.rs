@@ -16,6 +16,9 @@ pub mod bitcoin_testnet {
         let config = BitcoinConfig {
             network: Network::Testnet,
             rpc_endpoints: vec![], // Empty for now, as it requires special RpcEndpoint type
+            storage: None,
+            electrum_url: crate::config::WalletDConfig::load().bitcoin.electrum_url,
+            security: Default::default(),
         };
 
         match BitcoinWalletManager::new(config).await {
@@ -60,7 +60,7 @@ impl WalletManager {
             _ => bitcoin::Network::Testnet,
         };
 
-        let wallet = RealBitcoinWallet::new(network)?;
+        let wallet = RealBitcoinWallet::new(network, &self.config.data_dir)?;
 
         println!("✅ Bitcoin wallet initialized ({network:?})");
         println!("📍 Address: {}", wallet.address);
@@ -83,7 +83,8 @@ impl WalletManager {
     pub async fn init_ethereum(&mut self) -> Result<()> {
         println!("🔄 Initializing Ethereum wallet...");
 
-        let mut wallet = RealEthereumWallet::new(self.config.ethereum.chain_id)?;
+        let mut wallet =
+            RealEthereumWallet::new(self.config.ethereum.chain_id, &self.config.data_dir)?;
 
         if let Err(e) = wallet.connect().await {
             println!("⚠️  Could not connect to Ethereum network: {e}");
@@ -5,11 +5,24 @@ use bitcoin::{
     secp256k1::{rand, Message, Secp256k1},
     sighash::{EcdsaSighashType, SighashCache},
     transaction::Version,
-    Address, Amount, Network, OutPoint, PrivateKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
-    Txid, Witness,
+    Address, Amount, FeeRate, Network, OutPoint, PrivateKey, ScriptBuf, Sequence, Transaction,
+    TxIn, TxOut, Txid, Witness,
 };
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use walletd_bitcoin::fee_estimator::{
+    FeeEstimator, FeePriority, FeeSource, DEFAULT_FALLBACK_FEE_RATE,
+};
+use walletd_bitcoin::labels::{self, Label, LabelType, Labels};
+use walletd_bitcoin::transaction_builder::fee_bump::{
+    self, ReplacementTracker, SentTransaction, TxStatus,
+};
+use walletd_bitcoin::utxo_manager::{
+    coin_selection, select_coins_including, CoinControl, CoinSelectionParams, SelectionStrategy,
+    TrackedUtxo,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Utxo {
@@ -30,21 +43,48 @@ pub struct RealBitcoinWallet {
     pub address: Address,
     pub network: Network,
     pub secp: Secp256k1<bitcoin::secp256k1::All>,
+    /// Transactions sent from the address and their fee-bump replacements
+    history: Mutex<ReplacementTracker>,
+    /// JSON file `history` is kept in between runs
+    history_path: PathBuf,
+    /// Fee rates from the block explorer's `/fee-estimates`
+    fee_estimator: FeeEstimator,
+    /// BIP329 labels; outputs labelled unspendable are frozen
+    labels: Mutex<Labels>,
 }
 
 impl RealBitcoinWallet {
-    pub fn new(network: Network) -> Result<Self> {
+    /// A fresh wallet keeping its sent transactions in `data_dir`
+    pub fn new(network: Network, data_dir: &Path) -> Result<Self> {
         let secp = Secp256k1::new();
         let (secret_key, _) = secp.generate_keypair(&mut rand::thread_rng());
         let private_key = PrivateKey::new(secret_key, network);
         let public_key = private_key.public_key(&secp);
         let address = Address::p2wpkh(&public_key, network)?;
+        let mut sources = vec![];
+        if let Some(api) = api_base(network) {
+            sources.push(FeeSource::Esplora(api.to_string()));
+        }
+        let fee_estimator = FeeEstimator::new(sources).with_fallback(DEFAULT_FALLBACK_FEE_RATE);
+        std::fs::create_dir_all(data_dir)?;
+        let history_path = data_dir.join(format!("btc_history_{address}.json"));
+        let history = match std::fs::read_to_string(&history_path) {
+            Ok(json) => serde_json::from_str::<Vec<SentTransaction>>(&json)?
+                .into_iter()
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ReplacementTracker::new(),
+            Err(e) => return Err(e.into()),
+        };
 
         Ok(Self {
             private_key,
             address,
             network,
             secp,
+            history: Mutex::new(history),
+            history_path,
+            fee_estimator,
+            labels: Mutex::new(Labels::new()),
         })
     }
 
@@ -99,11 +139,49 @@ impl RealBitcoinWallet {
         Ok(utxos)
     }
 
+    /// Estimated fee rate for the given priority level
+    pub async fn estimate_fee_rate(&self, priority: FeePriority) -> Result<FeeRate> {
+        self.fee_estimator.estimate_priority(priority).await
+    }
+
+    /// Send at the estimated fee rate for normal priority
     pub async fn create_and_send_transaction(
         &self,
         to_address: &str,
         amount_sats: u64,
     ) -> Result<String> {
+        let fee_rate = self.estimate_fee_rate(FeePriority::Normal).await?;
+        self.create_and_send_transaction_with_fee(to_address, amount_sats, fee_rate)
+            .await
+    }
+
+    pub async fn create_and_send_transaction_with_fee(
+        &self,
+        to_address: &str,
+        amount_sats: u64,
+        fee_rate: FeeRate,
+    ) -> Result<String> {
+        self.create_and_send_transaction_with_coin_control(
+            to_address,
+            amount_sats,
+            fee_rate,
+            &CoinControl::default(),
+        )
+        .await
+    }
+
+    /// Send spending the coins `control` includes, frozen or not, never those it excludes,
+    /// and other unfrozen coins as needed
+    pub async fn create_and_send_transaction_with_coin_control(
+        &self,
+        to_address: &str,
+        amount_sats: u64,
+        fee_rate: FeeRate,
+        control: &CoinControl,
+    ) -> Result<String> {
+        // Never pay less than the relay minimum
+        let fee_rate = fee_rate.max(self.fee_estimator.min_relay_fee().await);
+
         // Get UTXOs
         let utxos = self.get_utxos().await?;
         if utxos.is_empty() {
@@ -113,78 +191,325 @@ impl RealBitcoinWallet {
         }
 
         // Parse destination address
-        let to_addr = to_address
-            .parse::<Address<_>>()
-            .map_err(|_| anyhow::anyhow!("Invalid address"))?
-            .require_network(self.network)?;
+        let to_addr = self.parse_address(to_address)?;
+
+        // Select coins
+        let candidates = self.tracked_utxos(&utxos)?;
+        let required = named_utxos(&candidates, &control.include)?;
+        let candidates: Vec<TrackedUtxo> = candidates
+            .into_iter()
+            .filter(|u| !control.include.contains(&u.outpoint))
+            .filter(|u| !control.exclude.contains(&u.outpoint))
+            .filter(|u| !self.is_frozen(&u.outpoint))
+            .collect();
+
+        let params = CoinSelectionParams::new(fee_rate, &[to_addr.script_pubkey()])
+            .with_change_script(&self.address.script_pubkey());
+        let selection = select_coins_including(
+            required,
+            &candidates,
+            amount_sats,
+            &params,
+            SelectionStrategy::Auto,
+        )?;
+
+        // Add output to recipient, then change if coin selection decided it is worth creating
+        let mut outputs = vec![TxOut {
+            value: Amount::from_sat(amount_sats),
+            script_pubkey: to_addr.script_pubkey(),
+        }];
+        if let Some(change) = selection.change {
+            outputs.push(TxOut {
+                value: Amount::from_sat(change),
+                script_pubkey: self.address.script_pubkey(),
+            });
+        }
+        let change_index = selection.change.map(|_| 1);
+        self.send(&selection.selected, outputs, change_index).await
+    }
+
+    /// Spend all of `outpoints`, and nothing else, to `to_address` less the fee
+    pub async fn sweep(
+        &self,
+        to_address: &str,
+        outpoints: &[OutPoint],
+        fee_rate: FeeRate,
+    ) -> Result<String> {
+        let fee_rate = fee_rate.max(self.fee_estimator.min_relay_fee().await);
+        let to_addr = self.parse_address(to_address)?;
+        let utxos = named_utxos(&self.tracked_utxos(&self.get_utxos().await?)?, outpoints)?;
+        if utxos.is_empty() {
+            return Err(anyhow::anyhow!("No coins to spend"));
+        }
+
+        let params = CoinSelectionParams::new(fee_rate, &[to_addr.script_pubkey()]);
+        let weight = utxos
+            .iter()
+            .fold(params.base_weight, |w, u| w + params.spend_weight(u));
+        let fee = coin_selection::fee_for_weight(fee_rate, weight);
+        let total: u64 = utxos.iter().map(|u| u.txout.value.to_sat()).sum();
+        let value = total
+            .checked_sub(fee)
+            .filter(|value| *value >= params.dust_limit)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Insufficient funds. Have: {total} sats, fee: {fee} sats")
+            })?;
+
+        let outputs = vec![TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: to_addr.script_pubkey(),
+        }];
+        self.send(&utxos, outputs, None).await
+    }
 
-        // Build transaction
+    /// Sign and broadcast a transaction spending `inputs`, and record it for fee bumping
+    async fn send(
+        &self,
+        inputs: &[TrackedUtxo],
+        outputs: Vec<TxOut>,
+        change_index: Option<usize>,
+    ) -> Result<String> {
         let mut tx = Transaction {
             version: Version::TWO,
             lock_time: LockTime::ZERO,
-            input: vec![],
-            output: vec![],
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: outputs,
         };
 
-        // Add inputs
-        let mut total_input = 0u64;
-        let mut selected_utxos = vec![];
+        let prevouts: Vec<TxOut> = inputs.iter().map(|u| u.txout.clone()).collect();
+        self.sign(&mut tx, &prevouts)?;
+        let txid = self.broadcast(&tx).await?;
+        let sent = SentTransaction::new(tx, prevouts, change_index)?;
+        self.update_history(|history| {
+            history.record(sent);
+            Ok(())
+        })?;
+        Ok(txid)
+    }
 
-        for utxo in utxos {
-            if total_input >= amount_sats + 10000 {
-                // 10k sats for fee
-                break;
-            }
+    /// Label a transaction, address or output; an empty label removes it
+    pub fn set_label(&self, kind: LabelType, reference: &str, label: &str) -> Result<()> {
+        let mut labels = self.labels.lock().unwrap();
+        let mut record = labels
+            .get(kind, reference)
+            .cloned()
+            .unwrap_or_else(|| Label::new(kind, reference, ""));
+        record.label = (!label.is_empty()).then(|| label.to_string());
+        record.validate()?;
+        labels.insert(record);
+        Ok(())
+    }
 
-            let txid = Txid::from_str(&utxo.txid)?;
-            tx.input.push(TxIn {
-                previous_output: OutPoint {
-                    txid,
-                    vout: utxo.vout,
-                },
-                script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
-                witness: Witness::default(),
-            });
+    pub fn label(&self, kind: LabelType, reference: &str) -> Option<String> {
+        let labels = self.labels.lock().unwrap();
+        labels.label(kind, reference).map(String::from)
+    }
 
-            selected_utxos.push(utxo.clone());
-            total_input += utxo.value;
-        }
+    /// Keep automatic coin selection away from an output, or let it spend it again
+    pub fn set_frozen(&self, outpoint: &OutPoint, frozen: bool) {
+        let mut labels = self.labels.lock().unwrap();
+        let reference = outpoint.to_string();
+        let mut record = labels
+            .get(LabelType::Output, &reference)
+            .cloned()
+            .unwrap_or_else(|| Label::new(LabelType::Output, reference, ""));
+        record.spendable = frozen.then_some(false);
+        labels.insert(record);
+    }
 
-        if total_input < amount_sats + 10000 {
-            return Err(anyhow::anyhow!(
-                "Insufficient funds. Have: {} sats, Need: {} sats",
-                total_input,
-                amount_sats + 10000
-            ));
+    pub fn is_frozen(&self, outpoint: &OutPoint) -> bool {
+        let labels = self.labels.lock().unwrap();
+        labels
+            .get(LabelType::Output, &outpoint.to_string())
+            .is_some_and(|l| l.spendable == Some(false))
+    }
+
+    /// Import BIP329 JSON lines, e.g. exported by Sparrow. Returns how many were imported.
+    pub fn import_labels(&self, jsonl: &str) -> Result<usize> {
+        let imported = labels::import_jsonl(jsonl)?;
+        let count = imported.len();
+        let mut labels = self.labels.lock().unwrap();
+        for label in imported {
+            labels.insert(label);
         }
+        Ok(count)
+    }
 
-        // Add output to recipient
-        tx.output.push(TxOut {
-            value: Amount::from_sat(amount_sats),
-            script_pubkey: to_addr.script_pubkey(),
-        });
+    /// Export labels as BIP329 JSON lines
+    pub fn export_labels(&self) -> Result<String> {
+        self.labels.lock().unwrap().to_jsonl()
+    }
 
-        // Add change output if needed
-        let fee = 10000u64; // 10k sats fee
-        if total_input > amount_sats + fee {
-            let change = total_input - amount_sats - fee;
-            tx.output.push(TxOut {
-                value: Amount::from_sat(change),
-                script_pubkey: self.address.script_pubkey(),
-            });
+    /// Replace an unconfirmed send with one paying `fee_rate_sat_vb` (BIP125), taking the
+    /// extra fee from change first and then from confirmed UTXOs
+    pub async fn bump_fee(&self, txid: &str, fee_rate_sat_vb: u64) -> Result<String> {
+        let original_txid = Txid::from_str(txid)?;
+        let original = self.sent_transaction(&original_txid)?;
+        let extra_utxos: Vec<TrackedUtxo> = self
+            .tracked_utxos(&self.get_utxos().await?)?
+            .into_iter()
+            .filter(|u| !self.is_frozen(&u.outpoint))
+            .collect();
+
+        // The explorer doesn't report descendants, so only our own spends of it are counted
+        let descendant_fees = self.history.lock().unwrap().descendant_fees(&original_txid);
+
+        let bump = fee_bump::bump_fee_rbf(
+            &original,
+            FeeRate::from_sat_per_vb_unchecked(fee_rate_sat_vb),
+            &extra_utxos,
+            &self.address.script_pubkey(),
+            descendant_fees,
+        )?;
+        let mut tx = bump.tx;
+        let prevouts: Vec<TxOut> = bump.utxos.into_iter().map(|u| u.txout).collect();
+        self.sign(&mut tx, &prevouts)?;
+        let new_txid = self.broadcast(&tx).await?;
+
+        let replacement = SentTransaction::new(tx, prevouts, bump.change_index)?;
+        self.update_history(|history| {
+            history.record_replacement(original_txid, replacement);
+            Ok(())
+        })?;
+        Ok(new_txid)
+    }
+
+    /// Spend the change of an unconfirmed send back to ourselves so parent and child together
+    /// pay `fee_rate_sat_vb`
+    pub async fn cpfp(&self, txid: &str, fee_rate_sat_vb: u64) -> Result<String> {
+        let parent = self.sent_transaction(&Txid::from_str(txid)?)?;
+        let vout = parent
+            .change_index
+            .ok_or_else(|| anyhow::anyhow!("Transaction has no change output to spend"))?;
+
+        let child = fee_bump::build_cpfp(
+            &parent,
+            vout as u32,
+            FeeRate::from_sat_per_vb_unchecked(fee_rate_sat_vb),
+            &self.address.script_pubkey(),
+        )?;
+        let mut tx = child.tx;
+        let prevouts: Vec<TxOut> = child.utxos.into_iter().map(|u| u.txout).collect();
+        self.sign(&mut tx, &prevouts)?;
+        let child_txid = self.broadcast(&tx).await?;
+
+        let child = SentTransaction::new(tx, prevouts, child.change_index)?;
+        self.update_history(|history| {
+            history.record(child);
+            Ok(())
+        })?;
+        Ok(child_txid)
+    }
+
+    /// Check pending sends against the explorer and settle replacement chains that confirmed
+    pub async fn refresh_history(&self) -> Result<()> {
+        let pending: Vec<Txid> = {
+            let history = self.history.lock().unwrap();
+            history.pending().iter().map(|t| t.txid()).collect()
+        };
+
+        #[derive(Deserialize)]
+        struct TxStatusResponse {
+            confirmed: bool,
+        }
+
+        for txid in pending {
+            let url = format!("{}/tx/{txid}/status", self.api_base()?);
+            let status: TxStatusResponse = match reqwest::get(&url).await {
+                Ok(response) if response.status().is_success() => response.json().await?,
+                // Replaced transactions drop out of the mempool and return 404
+                _ => continue,
+            };
+            if status.confirmed {
+                self.update_history(|history| history.mark_confirmed(&txid))?;
+            }
         }
+        Ok(())
+    }
 
+    /// Sent transactions as (txid, status, fee, txid that replaced it)
+    pub fn transaction_history(&self) -> Vec<(Txid, TxStatus, u64, Option<Txid>)> {
+        let history = self.history.lock().unwrap();
+        history
+            .history()
+            .into_iter()
+            .map(|t| (t.txid(), t.status, t.fee, t.replaced_by))
+            .collect()
+    }
+
+    /// The transaction that finally confirmed in place of `txid` or one of its replacements
+    pub fn confirmed_txid(&self, txid: &str) -> Result<Option<Txid>> {
+        let txid = Txid::from_str(txid)?;
+        Ok(self.history.lock().unwrap().confirmed_txid(&txid))
+    }
+
+    fn parse_address(&self, address: &str) -> Result<Address> {
+        Ok(address
+            .parse::<Address<_>>()
+            .map_err(|_| anyhow::anyhow!("Invalid address"))?
+            .require_network(self.network)?)
+    }
+
+    fn sent_transaction(&self, txid: &Txid) -> Result<SentTransaction> {
+        self.history
+            .lock()
+            .unwrap()
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Transaction {txid} was not sent from this wallet"))
+    }
+
+    /// Change the history and write it to the wallet's history file
+    fn update_history(&self, f: impl FnOnce(&mut ReplacementTracker) -> Result<()>) -> Result<()> {
+        let mut history = self.history.lock().unwrap();
+        f(&mut history)?;
+        let json = serde_json::to_string_pretty(&history.history())?;
+        // Write then rename so a crash never leaves a truncated file
+        let tmp = self.history_path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &self.history_path)?;
+        Ok(())
+    }
+
+    fn tracked_utxos(&self, utxos: &[Utxo]) -> Result<Vec<TrackedUtxo>> {
+        utxos
+            .iter()
+            .map(|utxo| {
+                Ok(TrackedUtxo {
+                    outpoint: OutPoint {
+                        txid: Txid::from_str(&utxo.txid)?,
+                        vout: utxo.vout,
+                    },
+                    txout: TxOut {
+                        value: Amount::from_sat(utxo.value),
+                        script_pubkey: self.address.script_pubkey(),
+                    },
+                    confirmations: u32::from(utxo.status.confirmed),
+                })
+            })
+            .collect()
+    }
+
+    /// Sign every input, all of which spend our P2WPKH address
+    fn sign(&self, tx: &mut Transaction, prevouts: &[TxOut]) -> Result<()> {
         // Create witnesses first
         let mut witnesses = vec![];
         {
-            let mut sighash_cache = SighashCache::new(&tx);
+            let mut sighash_cache = SighashCache::new(&*tx);
 
-            for (index, utxo) in selected_utxos.iter().enumerate() {
+            for (index, prevout) in prevouts.iter().enumerate() {
                 let sighash = sighash_cache.p2wpkh_signature_hash(
                     index,
                     &self.address.script_pubkey(),
-                    Amount::from_sat(utxo.value),
+                    prevout.value,
                     EcdsaSighashType::All,
                 )?;
 
@@ -206,17 +531,14 @@ impl RealBitcoinWallet {
         for (index, witness) in witnesses.into_iter().enumerate() {
             tx.input[index].witness = witness;
         }
+        Ok(())
+    }
 
+    async fn broadcast(&self, tx: &Transaction) -> Result<String> {
         // Serialize transaction
-        let tx_hex = hex::encode(serialize(&tx));
-
-        // Broadcast transaction
-        let broadcast_url = match self.network {
-            Network::Testnet => "https://blockstream.info/testnet/api/tx",
-            Network::Bitcoin => "https://blockstream.info/api/tx",
-            _ => return Err(anyhow::anyhow!("Unsupported network")),
-        };
+        let tx_hex = hex::encode(serialize(tx));
 
+        let broadcast_url = format!("{}/tx", self.api_base()?);
         let client = reqwest::Client::new();
         let response = client.post(broadcast_url).body(tx_hex).send().await?;
 
@@ -232,7 +554,34 @@ impl RealBitcoinWallet {
         }
     }
 
+    fn api_base(&self) -> Result<&'static str> {
+        api_base(self.network).ok_or_else(|| anyhow::anyhow!("Unsupported network"))
+    }
+
     pub fn get_receive_address(&self) -> Result<String> {
         Ok(self.address.to_string())
     }
 }
+
+/// The UTXOs at `outpoints`, in order
+fn named_utxos(utxos: &[TrackedUtxo], outpoints: &[OutPoint]) -> Result<Vec<TrackedUtxo>> {
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            utxos
+                .iter()
+                .find(|u| u.outpoint == *outpoint)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{outpoint} is not a UTXO of this wallet"))
+        })
+        .collect()
+}
+
+/// Esplora API of the block explorer for the network
+fn api_base(network: Network) -> Option<&'static str> {
+    match network {
+        Network::Testnet => Some("https://blockstream.info/testnet/api"),
+        Network::Bitcoin => Some("https://blockstream.info/api"),
+        _ => None,
+    }
+}
@@ -1,23 +1,35 @@
 use anyhow::Result;
+use ethers::types::transaction::eip712::TypedData;
 use ethers::{prelude::*, utils::parse_ether};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use walletd_ethereum::{signing, transactions};
+use walletd_ethereum::{FeePreset, NonceManager, TrackedTransaction};
+
+/// File in the data directory keeping transactions awaiting confirmation between runs
+const PENDING_FILE: &str = "eth_pending.json";
 
 pub struct RealEthereumWallet {
     pub wallet: LocalWallet,
     pub address: Address,
     pub chain_id: u64,
     provider: Option<Provider<Http>>,
+    nonces: Mutex<NonceManager>,
 }
 
 impl RealEthereumWallet {
-    pub fn new(chain_id: u64) -> Result<Self> {
+    /// A fresh wallet keeping its pending transactions in `data_dir`
+    pub fn new(chain_id: u64, data_dir: &Path) -> Result<Self> {
         let wallet = LocalWallet::new(&mut rand::thread_rng());
         let address = wallet.address();
+        std::fs::create_dir_all(data_dir)?;
 
         Ok(Self {
             wallet,
             address,
             chain_id,
             provider: None,
+            nonces: Mutex::new(NonceManager::open(data_dir.join(PENDING_FILE))?),
         })
     }
 
@@ -45,38 +57,107 @@ impl RealEthereumWallet {
     }
 
     pub async fn send_transaction(&self, to: &str, amount_eth: f64) -> Result<String> {
-        if let Some(provider) = &self.provider {
-            let to_address: Address = to.parse()?;
-            let value = parse_ether(amount_eth)?;
+        let provider = self.provider()?;
+        let to_address: Address = to.parse()?;
+        let value = parse_ether(amount_eth)?;
+        let tx = Eip1559TransactionRequest::new()
+            .to(to_address)
+            .value(value)
+            .gas(21000);
 
-            // Create the transaction
-            let tx = TransactionRequest::new()
-                .to(to_address)
-                .value(value)
-                .from(self.address)
-                .chain_id(self.chain_id);
+        println!("📡 Signing transaction...");
+        let tx_hash = transactions::broadcast(
+            provider,
+            &self.wallet,
+            &self.nonces,
+            tx,
+            FeePreset::Normal,
+            None,
+        )
+        .await?;
 
-            // Setup the wallet with chain ID
-            let wallet = self.wallet.clone().with_chain_id(self.chain_id);
+        println!("📡 Broadcasting to network...");
+        let receipt = PendingTransaction::new(tx_hash, provider).await?;
+        self.sync_pending().await?;
 
-            // Create a client with the signer
-            let client = SignerMiddleware::new(provider.clone(), wallet);
+        if let Some(receipt) = receipt {
+            Ok(format!("{:#x}", receipt.transaction_hash))
+        } else {
+            Err(anyhow::anyhow!(
+                "Transaction {tx_hash:#x} was dropped - speed it up to rebroadcast"
+            ))
+        }
+    }
 
-            // Send the transaction
-            println!("📡 Signing transaction...");
-            let pending_tx = client.send_transaction(tx, None).await?;
+    /// Resend a stuck transaction at the same nonce with higher fees
+    pub async fn speed_up(&self, tx_hash: H256) -> Result<String> {
+        let replacement = transactions::speed_up(
+            self.provider()?,
+            &self.wallet,
+            &self.nonces,
+            tx_hash,
+            FeePreset::Fast,
+        )
+        .await?;
+        Ok(format!("{replacement:#x}"))
+    }
 
-            println!("📡 Broadcasting to network...");
-            let receipt = pending_tx.await?;
+    /// Replace a stuck transaction with a 0-value send to ourselves at the same nonce
+    pub async fn cancel(&self, tx_hash: H256) -> Result<String> {
+        let cancellation = transactions::cancel(
+            self.provider()?,
+            &self.wallet,
+            &self.nonces,
+            tx_hash,
+            FeePreset::Fast,
+        )
+        .await?;
+        Ok(format!("{cancellation:#x}"))
+    }
 
-            if let Some(receipt) = receipt {
-                Ok(format!("{:#x}", receipt.transaction_hash))
-            } else {
-                Err(anyhow::anyhow!("Transaction failed - no receipt"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Not connected to network"))
-        }
+    /// Transactions from this wallet that are not final yet, oldest first
+    pub fn pending_transactions(&self) -> Result<Vec<TrackedTransaction>> {
+        Ok(self
+            .nonces()?
+            .transactions(self.chain_id, self.address)
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Check tracked transactions against the node for mining, drops, replacements and reorgs
+    pub async fn sync_pending(&self) -> Result<Vec<TrackedTransaction>> {
+        Ok(transactions::sync_transactions(self.provider()?, self.address, &self.nonces).await?)
+    }
+
+    pub fn provider(&self) -> Result<&Provider<Http>> {
+        self.provider
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to network"))
+    }
+
+    fn nonces(&self) -> Result<MutexGuard<'_, NonceManager>> {
+        self.nonces
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Pending transaction store is poisoned"))
+    }
+
+    /// Sign `message` as `personal_sign` does (EIP-191)
+    pub fn sign_message(&self, message: &[u8]) -> Result<String> {
+        let signature = self
+            .wallet
+            .sign_hash(ethers::utils::hash_message(message))?;
+        Ok(format!("0x{signature}"))
+    }
+
+    /// Sign EIP-712 typed data as `eth_signTypedData_v4` does, refusing domains bound to
+    /// another chain
+    pub fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String> {
+        Ok(signing::sign_typed_data(
+            &self.wallet,
+            typed_data,
+            self.chain_id,
+        )?)
     }
 
     pub fn get_private_key(&self) -> String {